@@ -1,6 +1,7 @@
 #include "emulibc.h"
 #include <stdio.h>
 #include <sys/mman.h>
+#include <unistd.h>
 
 // Keep this in sync with "syscall.h"!!
 struct __AddressRange {
@@ -84,6 +85,116 @@ void _debug_puts(const char *s)
 	fprintf(stderr, "%s\n", s);
 }
 
+// Keep this in sync with "syscall_defs.rs"!!
+#define __NR_WBX_REGISTER_MEMORY_DOMAIN 0x10000
+#define __NR_WBX_GET_CONFIG 0x10001
+#define __NR_WBX_ENTROPY 0x10002
+#define __NR_WBX_CREATE_ARENA 0x10003
+#define __NR_WBX_DESTROY_ARENA 0x10004
+#define __NR_WBX_SET_DOMAIN_TRANSLATOR 0x10005
+#define __NR_WBX_CREATE_FIBER 0x10006
+#define __NR_WBX_SWITCH_FIBER 0x10007
+#define __NR_WBX_DESTROY_FIBER 0x10008
+#define __NR_WBX_TRANSFER 0x10009
+
+// Keep this in sync with "imports.rs"!!
+struct __WbxSysImports {
+	unsigned long long version;
+	unsigned long long imports;
+	// written by the host
+	unsigned long long host_version;
+	unsigned long long host_imports;
+};
+ECL_EXPORT struct __WbxSysImports __wbximports = { 1, WBX_IMPORT_MEMORY_DOMAINS | WBX_IMPORT_CONFIG, 0, 0 };
+
+int wbx_host_has(unsigned long long imports)
+{
+	// hosts from before the handshake don't say, so everything is worth a try
+	return __wbximports.host_version == 0 || (__wbximports.host_imports & imports) == imports;
+}
+
+int wbx_register_memory_domain(const char *name, void *start, size_t size, size_t word_size, unsigned flags)
+{
+	if (!wbx_host_has(WBX_IMPORT_MEMORY_DOMAINS))
+		return -1;
+	return syscall(__NR_WBX_REGISTER_MEMORY_DOMAIN, name, start, size, word_size, flags) == 0 ? 0 : -1;
+}
+
+long __wbx_get_config(const char *key, char *buf, size_t len)
+{
+	if (!wbx_host_has(WBX_IMPORT_CONFIG))
+		return -1;
+	return syscall(__NR_WBX_GET_CONFIG, key, buf, len);
+}
+
+int __wbx_entropy(void *buf, size_t len)
+{
+	if (!wbx_host_has(WBX_IMPORT_ENTROPY))
+		return -1;
+	return syscall(__NR_WBX_ENTROPY, buf, len) < 0 ? -1 : 0;
+}
+
+int wbx_create_arena(const char *name, void *start, size_t size)
+{
+	if (!wbx_host_has(WBX_IMPORT_ARENAS))
+		return -1;
+	return syscall(__NR_WBX_CREATE_ARENA, name, start, size) == 0 ? 0 : -1;
+}
+
+int wbx_destroy_arena(const char *name)
+{
+	if (!wbx_host_has(WBX_IMPORT_ARENAS))
+		return -1;
+	return syscall(__NR_WBX_DESTROY_ARENA, name) == 0 ? 0 : -1;
+}
+
+int wbx_set_domain_translator(const char *name, void *(*translate)(size_t offset))
+{
+	if (!wbx_host_has(WBX_IMPORT_DOMAIN_TRANSLATORS))
+		return -1;
+	return syscall(__NR_WBX_SET_DOMAIN_TRANSLATOR, name, translate) == 0 ? 0 : -1;
+}
+
+// where new fibers start, with the host having put func in rbx and arg in r12 and the stack 16 byte aligned
+void __wbx_fiber_start(void);
+__asm__(
+	".text\n"
+	".globl __wbx_fiber_start\n"
+	"__wbx_fiber_start:\n"
+	"mov %r12, %rdi\n"
+	"call *%rbx\n"
+	"ud2\n"
+);
+
+int wbx_create_fiber(void (*func)(void *arg), void *arg, size_t stack_size)
+{
+	if (!wbx_host_has(WBX_IMPORT_FIBERS))
+		return -1;
+	long id = syscall(__NR_WBX_CREATE_FIBER, __wbx_fiber_start, func, arg, stack_size);
+	return id < 0 ? -1 : (int)id;
+}
+
+int wbx_switch_fiber(int id)
+{
+	if (!wbx_host_has(WBX_IMPORT_FIBERS))
+		return -1;
+	return syscall(__NR_WBX_SWITCH_FIBER, id) == 0 ? 0 : -1;
+}
+
+int wbx_destroy_fiber(int id)
+{
+	if (!wbx_host_has(WBX_IMPORT_FIBERS))
+		return -1;
+	return syscall(__NR_WBX_DESTROY_FIBER, id) == 0 ? 0 : -1;
+}
+
+int wbx_transfer(const char *name, const void *src, size_t len, size_t offset)
+{
+	if (!wbx_host_has(WBX_IMPORT_TRANSFERS))
+		return -1;
+	return syscall(__NR_WBX_TRANSFER, name, src, len, offset) == 0 ? 0 : -1;
+}
+
 ECL_EXPORT void ecl_seal()
 {
 	if (__sealed_current)
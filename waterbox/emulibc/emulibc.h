@@ -35,6 +35,66 @@ void *alloc_plain(size_t size);
 // send a debug string somewhere, bypassing stdio
 void _debug_puts(const char *);
 
+// waterbox calls the host may or may not have, for wbx_host_has
+#define WBX_IMPORT_MEMORY_DOMAINS 1ull
+#define WBX_IMPORT_CONFIG 2ull
+#define WBX_IMPORT_SAVERAM 4ull
+#define WBX_IMPORT_ENTROPY 8ull
+#define WBX_IMPORT_ARENAS 16ull
+#define WBX_IMPORT_DOMAIN_TRANSLATORS 32ull
+#define WBX_IMPORT_FIBERS 64ull
+#define WBX_IMPORT_TRANSFERS 128ull
+
+// whether the host has all of the calls in imports.  the ones below that it doesn't have fail without doing anything
+int wbx_host_has(unsigned long long imports);
+
+// flags for wbx_register_memory_domain
+#define WBX_DOMAIN_BIG_ENDIAN 1
+#define WBX_DOMAIN_WRITABLE 2
+// battery backed, like cartridge SRAM.  the frontend saves and restores these with wbx_get_saveram and wbx_put_saveram.
+// check wbx_host_has(WBX_IMPORT_SAVERAM) first, as older hosts refuse the whole domain
+#define WBX_DOMAIN_SAVERAM 4
+
+// make a region of memory known to the frontend by name, for its hex editor, RAM search and the like.
+// word_size is 1, 2, 4 or 8.  registering the same name again replaces the old one.  returns 0 on success
+int wbx_register_memory_domain(const char *name, void *start, size_t size, size_t word_size, unsigned flags);
+// for banked or mirrored memory:  have the frontend's typed reads and writes of a domain find each byte by calling
+// translate with its offset in the domain, which returns where that byte is now, or NULL if it isn't mapped.  it runs
+// from the frontend, between frames, so it mustn't change anything.  NULL clears it; registering the domain again does
+// too.  returns 0 on success, or -1 on failure or if the host doesn't have them
+int wbx_set_domain_translator(const char *name, void *(*translate)(size_t offset));
+
+// look up a setting the frontend made with wbx_set_config.  copies as much of the value as fits in len bytes to buf,
+// always null terminated, and returns its full length, not counting the terminator; or -1 if there's no such setting
+long __wbx_get_config(const char *key, char *buf, size_t len);
+
+// fill buf with len bytes of randomness that only this core draws from, seeded by the frontend and kept in savestates,
+// so the same seed always gives the same bytes.  not for secrets.  returns 0 on success, or -1 if the host doesn't have it
+int __wbx_entropy(void *buf, size_t len);
+
+// tell the frontend that size bytes at start are used for one thing, like VRAM or a decode cache, so it can show how
+// guest memory is used.  nothing is allocated; the host only keeps the list, which is kept in savestates.  names must
+// be unique.  returns 0 on success, or -1 on failure or if the host doesn't have them
+int wbx_create_arena(const char *name, void *start, size_t size);
+// drop an arena made with wbx_create_arena.  returns 0 on success
+int wbx_destroy_arena(const char *name);
+
+// make a fiber, which runs func(arg) on a stack of its own of stack_size bytes once something switches to it, for
+// interpreters that recurse deeper than the main stack allows.  func must never return; switch away instead.  returns
+// the fiber's id, or -1 on failure or if the host doesn't have them
+int wbx_create_fiber(void (*func)(void *arg), void *arg, size_t stack_size);
+// park the current fiber and go on with fiber id, or with the thread's own stack for 0.  returns 0 once something
+// switches back, or -1 if id doesn't exist or is running on another thread.  fibers and where each one is are kept in
+// savestates
+int wbx_switch_fiber(int id);
+// drop a fiber that isn't running, and its stack.  returns 0 on success
+int wbx_destroy_fiber(int id);
+
+// copy len bytes from src into the frontend's buffer called name, at offset, for handing over a video frame or an audio
+// batch without the frontend copying it out afterwards.  returns 0 on success, or -1 if there's no such buffer, it
+// doesn't fit, or the host doesn't have them
+int wbx_transfer(const char *name, const void *src, size_t len, size_t offset);
+
 // put data in a section that will have similar behavior characteristics to alloc_sealed
 #define ECL_SEALED __attribute__((section(".sealed")))
 
@@ -0,0 +1,334 @@
+// The data model a guest was built for.  Guests are normally built for the host's own, but on x86_64 they can also be
+// x32 style ILP32 builds:  64-bit code with 32-bit pointers and longs, for older cores that don't survive having their
+// pointers widened.  An ILP32 guest has to fit entirely below 4GiB, since none of its pointers can reach any higher, and
+// anything the host reads or writes in guest memory that holds a pointer or a long has to be done at the guest's width.
+// Guests interpreted from wasm have the same constraints, though they make syscalls with the host's own numbers.
+use crate::*;
+use crate::memory_block::{ActivatedMemoryBlock, Protection};
+use crate::syscall_defs::*;
+use goblin::elf::Elf;
+
+/// The machine guests have to be built for, which is the host's
+#[cfg(target_arch = "x86_64")]
+const MACHINE: u16 = goblin::elf::header::EM_X86_64;
+#[cfg(target_arch = "aarch64")]
+const MACHINE: u16 = goblin::elf::header::EM_AARCH64;
+
+/// If this host can run x32 code
+const ILP32_SUPPORTED: bool = cfg!(target_arch = "x86_64");
+
+/// x32's syscall numbers have this bit set
+const X32_SYSCALL_BIT: usize = 0x40000000;
+
+/// If native guests make syscalls with the generic table's numbers, as aarch64 ones do
+const GENERIC_SYSCALLS: bool = cfg!(target_arch = "aarch64");
+/// Given to generic syscalls there's no x86_64 one for, so that they fail with ENOSYS instead of being taken for
+/// whatever x86_64 has at that number
+const UNTRANSLATED_SYSCALL_BIT: usize = 0x20000000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuestAbi {
+	/// The host's own:  64-bit pointers and longs
+	Lp64,
+	/// 32-bit pointers and longs in 64-bit code
+	Ilp32,
+	/// wasm32, which the host interprets
+	Wasm32,
+}
+impl GuestAbi {
+	/// What `wbx` was built for.  Guest code runs natively, so it has to be for the host's architecture.
+	pub fn of(wbx: &Elf, module_name: &str) -> anyhow::Result<GuestAbi> {
+		if wbx.header.e_machine != MACHINE {
+			return Err(anyhow!("Module `{}` is for machine {}, but this host needs {}", module_name, wbx.header.e_machine, MACHINE))
+		}
+		match (wbx.is_64, ILP32_SUPPORTED) {
+			(true, _) => Ok(GuestAbi::Lp64),
+			(false, true) => Ok(GuestAbi::Ilp32),
+			(false, false) => Err(anyhow!("Module `{}` is a 32-bit build, which this host can't run", module_name)),
+		}
+	}
+	pub fn pointer_size(self) -> usize {
+		match self {
+			GuestAbi::Lp64 => 8,
+			GuestAbi::Ilp32 | GuestAbi::Wasm32 => 4,
+		}
+	}
+	/// Check that all of a guest's memory is somewhere its pointers can reach
+	pub fn check_layout(self, addr: AddressRange, module_name: &str) -> anyhow::Result<()> {
+		if self != GuestAbi::Lp64 && addr.end() > 1 << 32 {
+			return Err(anyhow!("Module `{}` has 32-bit pointers, but its memory would end at {:#x}, above 4GiB", module_name, addr.end()))
+		}
+		Ok(())
+	}
+	/// unsafe: `addr` must be readable host memory
+	pub unsafe fn read_pointer(self, addr: usize) -> usize {
+		match self {
+			GuestAbi::Lp64 => *(addr as *const usize),
+			GuestAbi::Ilp32 | GuestAbi::Wasm32 => *(addr as *const u32) as usize,
+		}
+	}
+	/// unsafe: `addr` must be writable host memory
+	pub unsafe fn write_pointer(self, addr: usize, value: usize) {
+		match self {
+			GuestAbi::Lp64 => *(addr as *mut usize) = value,
+			GuestAbi::Ilp32 | GuestAbi::Wasm32 => {
+				assert!(value <= u32::MAX as usize, "{:#x} doesn't fit in a guest pointer", value);
+				*(addr as *mut u32) = value as u32
+			},
+		}
+	}
+	/// The iovecs of a readv or writev
+	/// unsafe: `addr` must be `count` readable guest iovecs
+	pub unsafe fn iovecs(self, addr: usize, count: usize) -> Vec<Iovec> {
+		let size = self.pointer_size();
+		(0..count)
+			.map(|i| Iovec {
+				iov_base: self.read_pointer(addr + i * size * 2),
+				iov_len: self.read_pointer(addr + i * size * 2 + size),
+			})
+			.collect()
+	}
+	/// Turn the number a guest made a syscall with into the x86_64 one.  x32 has its own numbers for the syscalls
+	/// whose arguments hold pointers or longs, which the host handles at the guest's width anyway.
+	pub fn syscall_number(self, nr: usize) -> SyscallNumber {
+		if GENERIC_SYSCALLS && self == GuestAbi::Lp64 {
+			return generic_syscall_number(nr)
+		}
+		if self != GuestAbi::Ilp32 {
+			return SyscallNumber(nr)
+		}
+		let nr = nr & !X32_SYSCALL_BIT;
+		SyscallNumber(match nr {
+			512 => NR_RT_SIGACTION.0,
+			513 => NR_RT_SIGRETURN.0,
+			514 => NR_IOCTL.0,
+			515 => NR_READV.0,
+			516 => NR_WRITEV.0,
+			517 => NR_RECVFROM.0,
+			525 => NR_SIGALTSTACK.0,
+			530 => NR_SET_ROBUST_LIST.0,
+			531 => NR_GET_ROBUST_LIST.0,
+			534 => NR_PREADV.0,
+			535 => NR_PWRITEV.0,
+			546 => NR_PREADV2.0,
+			547 => NR_PWRITEV2.0,
+			_ => nr,
+		})
+	}
+	/// Put the arguments of `nr`, which syscall_number() has turned into the x86_64 one, where x86_64 has them.  Of the
+	/// syscalls the host has, only clone's differ in the generic table, which has the TLS and child tid swapped.
+	pub fn syscall_args(self, nr: &SyscallNumber, args: &mut [usize; 6]) {
+		if GENERIC_SYSCALLS && self == GuestAbi::Lp64 && *nr == NR_CLONE {
+			args.swap(3, 4);
+		}
+	}
+	/// How big the guest's __wbxsysarea is
+	pub fn sys_area_size(self) -> usize {
+		// every field is a pointer or a long
+		std::mem::size_of::<WbxSysArea>() / 8 * self.pointer_size()
+	}
+	/// Write `sys` out at the guest's width.  A 32-bit guest gets `thunk` in place of the syscall pointer, and no ud.
+	/// unsafe: `addr` must be sys_area_size() writable bytes
+	pub unsafe fn write_sys_area(self, addr: usize, sys: &WbxSysArea, thunk: usize) {
+		match self {
+			GuestAbi::Lp64 => *(addr as *mut WbxSysArea) = *sys,
+			GuestAbi::Ilp32 | GuestAbi::Wasm32 => {
+				let count = std::mem::size_of::<WbxSysLayout>() / 8;
+				let words = std::slice::from_raw_parts(&sys.layout as *const WbxSysLayout as *const usize, count);
+				for (i, &w) in words.iter().enumerate() {
+					self.write_pointer(addr + i * 4, w);
+				}
+				let syscall = addr + count * 4;
+				self.write_pointer(syscall, 0);
+				self.write_pointer(syscall + 4, thunk);
+			},
+		}
+	}
+}
+
+/// The x86_64 syscall for a number in the generic table, which is what aarch64 has.  Only the syscalls the host has,
+/// or that an x86_64 guest makes and the host answers with ENOSYS, are here.  The generic table leaves out the old
+/// ones like open(2) and stat(2), whose *at(2) versions the host has too.
+fn generic_syscall_number(nr: usize) -> SyscallNumber {
+	match nr {
+		17 => NR_GETCWD,
+		20 => NR_EPOLL_CREATE1,
+		21 => NR_EPOLL_CTL,
+		22 => NR_EPOLL_PWAIT,
+		23 => NR_DUP,
+		24 => NR_DUP3,
+		25 => NR_FCNTL,
+		29 => NR_IOCTL,
+		35 => NR_UNLINKAT,
+		38 => NR_RENAMEAT,
+		45 => NR_TRUNCATE,
+		46 => NR_FTRUNCATE,
+		56 => NR_OPENAT,
+		57 => NR_CLOSE,
+		62 => NR_LSEEK,
+		63 => NR_READ,
+		64 => NR_WRITE,
+		65 => NR_READV,
+		66 => NR_WRITEV,
+		67 => NR_PREAD64,
+		68 => NR_PWRITE64,
+		69 => NR_PREADV,
+		70 => NR_PWRITEV,
+		73 => NR_PPOLL,
+		79 => NR_NEWFSTATAT,
+		80 => NR_FSTAT,
+		93 => NR_EXIT,
+		94 => NR_EXIT_GROUP,
+		96 => NR_SET_TID_ADDRESS,
+		98 => NR_FUTEX,
+		99 => NR_SET_ROBUST_LIST,
+		101 => NR_NANOSLEEP,
+		112 => NR_CLOCK_SETTIME,
+		113 => NR_CLOCK_GETTIME,
+		114 => NR_CLOCK_GETRES,
+		115 => NR_CLOCK_NANOSLEEP,
+		122 => NR_SCHED_SETAFFINITY,
+		123 => NR_SCHED_GETAFFINITY,
+		124 => NR_SCHED_YIELD,
+		129 => NR_KILL,
+		130 => NR_TKILL,
+		131 => NR_TGKILL,
+		132 => NR_SIGALTSTACK,
+		134 => NR_RT_SIGACTION,
+		135 => NR_RT_SIGPROCMASK,
+		165 => NR_GETRUSAGE,
+		169 => NR_GETTIMEOFDAY,
+		170 => NR_SETTIMEOFDAY,
+		172 => NR_GETPID,
+		178 => NR_GETTID,
+		179 => NR_SYSINFO,
+		198 => NR_SOCKET,
+		200 => NR_BIND,
+		201 => NR_LISTEN,
+		202 => NR_ACCEPT,
+		203 => NR_CONNECT,
+		206 => NR_SENDTO,
+		207 => NR_RECVFROM,
+		208 => NR_SETSOCKOPT,
+		209 => NR_GETSOCKOPT,
+		210 => NR_SHUTDOWN,
+		214 => NR_BRK,
+		215 => NR_MUNMAP,
+		216 => NR_MREMAP,
+		220 => NR_CLONE,
+		222 => NR_MMAP,
+		226 => NR_MPROTECT,
+		233 => NR_MADVISE,
+		242 => NR_ACCEPT4,
+		276 => NR_RENAMEAT2,
+		278 => NR_GETRANDOM,
+		286 => NR_PREADV2,
+		287 => NR_PWRITEV2,
+		291 => NR_STATX,
+		// waterbox's own are the same everywhere
+		nr if nr >= NR_WBX_REGISTER_MEMORY_DOMAIN.0 => SyscallNumber(nr),
+		nr => SyscallNumber(nr | UNTRANSLATED_SYSCALL_BIT),
+	}
+}
+
+/// Code an ILP32 guest's syscall pointer can reach, since the host's stub and `ud` are too far up for it to hold.  It
+/// puts the real ud in the second argument and goes on to the entry stub, finding both in the page after it.
+const SYSCALL_THUNK: [u8; 13] = [
+	// mov rsi, [rip + 0xff9]
+	0x48, 0x8b, 0x35, 0xf9, 0x0f, 0x00, 0x00,
+	// jmp [rip + 0xffb]
+	0xff, 0x25, 0xfb, 0x0f, 0x00, 0x00,
+];
+
+/// The syscall thunk and the invisible page after it, which holds ud and the entry stub's address
+pub struct SyscallThunk {
+	addr: usize,
+}
+impl SyscallThunk {
+	/// Map the thunk somewhere in `arena`
+	pub fn new(b: &mut ActivatedMemoryBlock, arena: AddressRange) -> anyhow::Result<SyscallThunk> {
+		let addr = b.mmap(AddressRange { start: 0, size: PAGESIZE * 2 }, Protection::RW, arena, false)?;
+		unsafe {
+			AddressRange { start: addr, size: SYSCALL_THUNK.len() }.slice_mut().copy_from_slice(&SYSCALL_THUNK);
+		}
+		b.mprotect(AddressRange { start: addr, size: PAGESIZE }, Protection::RX)?;
+		// a different host pointer on every activation, so it can't be in states
+		b.mark_invisible(AddressRange { start: addr + PAGESIZE, size: PAGESIZE })?;
+		Ok(SyscallThunk { addr })
+	}
+	pub fn addr(&self) -> usize {
+		self.addr
+	}
+	/// unsafe: The thunk's memory block must be active
+	pub unsafe fn connect(&self, ud: usize, entry: usize) {
+		*((self.addr + PAGESIZE) as *mut usize) = ud;
+		*((self.addr + PAGESIZE + 8) as *mut usize) = entry;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::memory_block::MemoryBlock;
+
+	#[test]
+	fn test_pointers() {
+		let mut buf = [0xffu8; 16];
+		let p = buf.as_mut_ptr() as usize;
+		unsafe {
+			GuestAbi::Ilp32.write_pointer(p, 0x12345678);
+			assert_eq!(GuestAbi::Ilp32.read_pointer(p), 0x12345678);
+			assert_eq!(GuestAbi::Lp64.read_pointer(p), 0xffffffff12345678);
+			GuestAbi::Ilp32.write_pointer(p + 4, 0x10);
+			GuestAbi::Ilp32.write_pointer(p + 8, 0x2000);
+			GuestAbi::Ilp32.write_pointer(p + 12, 0x20);
+			let iov = GuestAbi::Ilp32.iovecs(p, 2);
+			assert_eq!((iov[0].iov_base, iov[0].iov_len, iov[1].iov_base, iov[1].iov_len), (0x12345678, 0x10, 0x2000, 0x20));
+		}
+		assert_eq!(GuestAbi::Ilp32.sys_area_size(), std::mem::size_of::<WbxSysArea>() / 2);
+		assert!(GuestAbi::Ilp32.check_layout(AddressRange { start: 0x10000000, size: 0xf0000000 }, "x").is_ok());
+		assert!(GuestAbi::Ilp32.check_layout(AddressRange { start: 0x10000000, size: 0xf0001000 }, "x").is_err());
+		assert!(GuestAbi::Lp64.check_layout(AddressRange { start: 0x36f00000000, size: 0x1000 }, "x").is_ok());
+	}
+
+	#[test]
+	fn test_syscall_number() {
+		assert_eq!(GuestAbi::Ilp32.syscall_number(X32_SYSCALL_BIT | 9), NR_MMAP);
+		assert_eq!(GuestAbi::Ilp32.syscall_number(X32_SYSCALL_BIT | 515), NR_READV);
+		assert_eq!(GuestAbi::Ilp32.syscall_number(20), NR_WRITEV);
+		assert_eq!(GuestAbi::Lp64.syscall_number(515), SyscallNumber(515));
+		assert_eq!(generic_syscall_number(63), NR_READ);
+		assert_eq!(generic_syscall_number(56), NR_OPENAT);
+		assert_eq!(generic_syscall_number(NR_WBX_TRANSFER.0), NR_WBX_TRANSFER);
+		// not x86_64's uname, which is 63 there
+		assert_eq!(generic_syscall_number(160), SyscallNumber(160 | UNTRANSLATED_SYSCALL_BIT));
+		let mut args = [1, 2, 3, 4, 5, 6];
+		GuestAbi::Lp64.syscall_args(&NR_CLONE, &mut args);
+		assert_eq!(args, if GENERIC_SYSCALLS { [1, 2, 3, 5, 4, 6] } else { [1, 2, 3, 4, 5, 6] });
+	}
+
+	#[repr(C)]
+	struct FakeHost {
+		tag: u64,
+		value: usize,
+	}
+	guest_abi! { fn fake_entry(nr: usize, ud: usize) -> usize {
+		nr + unsafe { (*(ud as *const FakeHost)).value }
+	}}
+
+	#[test]
+	#[cfg(target_arch = "x86_64")]
+	fn test_syscall_thunk() -> anyhow::Result<()> {
+		let addr = AddressRange { start: 0x38500000000, size: 0x10000 };
+		let mut b = MemoryBlock::new(addr);
+		let mut g = b.enter();
+		let thunk = SyscallThunk::new(&mut g, addr)?;
+		let host = FakeHost { tag: 0, value: 100 };
+		unsafe { thunk.connect(&host as *const FakeHost as usize, fake_entry as guest_abi!(fn(usize, usize) -> usize) as usize); }
+		let call: guest_abi!(fn(usize, usize) -> usize) = unsafe { std::mem::transmute(thunk.addr()) };
+		// whatever the guest passes as ud is replaced
+		assert_eq!(call(5, 0), 105);
+		assert_eq!(call(7, 0xdead), 107);
+		Ok(())
+	}
+}
@@ -0,0 +1,342 @@
+// The host as a Rust library, for frontends and test tools written in Rust that would rather not go through the C
+// interface.  It's the same host underneath, with the C interface's rules made into types:  A Builder makes a
+// Waterbox, which has to be activated before it can do anything, and the Activation borrows it, so it can't be
+// activated twice or destroyed while active, and is deactivated when the Activation is dropped.  Errors are an Error
+// with the same ErrorCode the C interface would have reported.  Anything not here is still only in the C interface.
+use crate::*;
+use host::{ActivatedWaterboxHost, WaterboxHost};
+pub use config::WaterboxConfig;
+pub use history::HistorySample;
+pub use transfers::TransferInfo;
+pub use leaks::LeakAudit;
+pub use host::WxPolicy;
+pub use memory_block::DirtyTracking;
+pub use state_format::StateCheck;
+use std::fmt;
+use std::time::Duration;
+
+/// What went wrong, with the kind of failure it was
+#[derive(Debug)]
+pub struct Error {
+	code: ErrorCode,
+	message: String,
+}
+impl Error {
+	pub fn code(&self) -> ErrorCode {
+		self.code
+	}
+	pub fn message(&self) -> &str {
+		&self.message
+	}
+}
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.message)
+	}
+}
+impl std::error::Error for Error {}
+impl From<anyhow::Error> for Error {
+	fn from(e: anyhow::Error) -> Error {
+		Error { code: ErrorCode::of(&e), message: format!("{:#}", e) }
+	}
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+fn error(code: ErrorCode, message: impl Into<String>) -> Error {
+	Error { code, message: message.into() }
+}
+
+/// A domain of guest memory the core registered, for debuggers and the like
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryDomain {
+	pub name: String,
+	pub addr: AddressRange,
+	/// 1, 2, 4 or 8
+	pub word_size: usize,
+	/// DOMAIN_*
+	pub flags: usize,
+}
+
+/// Part of guest memory the core uses for one thing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Arena {
+	pub name: String,
+	pub addr: AddressRange,
+	/// Of the pages it's on
+	pub mapped_bytes: usize,
+	pub state_bytes: usize,
+}
+
+/// Sets up a Waterbox, with a WaterboxConfig for everything but the executable
+pub struct Builder {
+	module_name: String,
+	config: WaterboxConfig,
+}
+impl Builder {
+	/// `module_name` is what the executable is called, in savestates and backtraces
+	pub fn new(module_name: &str) -> Builder {
+		Builder { module_name: module_name.to_string(), config: WaterboxConfig::new() }
+	}
+	pub fn config(mut self, config: WaterboxConfig) -> Builder {
+		self.config = config;
+		self
+	}
+	/// For brk(2)
+	pub fn sbrk_size(mut self, size: usize) -> Builder {
+		self.config = self.config.sbrk_size(size);
+		self
+	}
+	/// For alloc_sealed(3)
+	pub fn sealed_size(mut self, size: usize) -> Builder {
+		self.config = self.config.sealed_size(size);
+		self
+	}
+	/// For alloc_invisible(3)
+	pub fn invis_size(mut self, size: usize) -> Builder {
+		self.config = self.config.invis_size(size);
+		self
+	}
+	/// For alloc_plain(3)
+	pub fn plain_size(mut self, size: usize) -> Builder {
+		self.config = self.config.plain_size(size);
+		self
+	}
+	/// For mmap(2) and friends
+	pub fn mmap_size(mut self, size: usize) -> Builder {
+		self.config = self.config.mmap_size(size);
+		self
+	}
+	/// Load the guest executable `image`
+	pub fn build(self, image: Vec<u8>) -> Result<Waterbox> {
+		Ok(Waterbox { host: WaterboxHost::with_config(image, &self.module_name, &self.config)? })
+	}
+	/// Load the guest executable from `reader`, which is read to the end
+	pub fn build_from(self, reader: &mut impl Read) -> Result<Waterbox> {
+		self.config.validate()?;
+		let mut image = Vec::new();
+		reader.read_to_end(&mut image).map_err(anyhow::Error::from)?;
+		self.build(image)
+	}
+}
+
+/// A guest and everything the host keeps for it
+pub struct Waterbox {
+	host: Box<WaterboxHost>,
+}
+impl Waterbox {
+	pub fn builder(module_name: &str) -> Builder {
+		Builder::new(module_name)
+	}
+	/// Swap this guest into memory, waiting for any other host in the same 4GiB region to be deactivated first
+	pub fn activate(&mut self) -> Result<Activation<'_>> {
+		if self.host.shut_down() {
+			return Err(error(ErrorCode::BadState, "WaterboxHost was shut down!"))
+		}
+		if !self.host.claim() {
+			return Err(error(ErrorCode::BadState, "WaterboxHost is already active!"))
+		}
+		Ok(Activation { a: self.host.activate() })
+	}
+	/// Give back everything the guest holds, without dropping the Waterbox yet.  It can't be activated afterwards.
+	pub fn shutdown(&mut self) -> Result<()> {
+		Ok(self.host.shutdown()?)
+	}
+}
+
+/// What every host in the process still holds, for finding leaks
+#[cfg(debug_assertions)]
+pub fn leak_audit() -> LeakAudit {
+	leaks::audit()
+}
+
+/// A Waterbox swapped into memory, until this is dropped.  Addresses inside the guest are only good until then.
+pub struct Activation<'a> {
+	a: Box<ActivatedWaterboxHost<'a>>,
+}
+impl<'a> Activation<'a> {
+	/// The address of something the guest exports
+	pub fn proc_addr(&self, name: &str) -> Option<usize> {
+		match self.a.get_proc_addr(name) {
+			0 => None,
+			addr => Some(addr),
+		}
+	}
+	/// Call the function the guest exports as `name`, with up to 6 arguments
+	pub fn call(&mut self, name: &str, args: &[usize]) -> Result<usize> {
+		let func = self.proc_addr(name).ok_or_else(|| error(ErrorCode::NotFound, format!("The guest exports no {}", name)))?;
+		unsafe { self.call_addr(func, args) }
+	}
+	/// Call the guest function at `func`, with up to 6 arguments
+	/// # Safety
+	/// `func` must be from proc_addr(), or otherwise be a function in guest memory
+	pub unsafe fn call_addr(&mut self, func: usize, args: &[usize]) -> Result<usize> {
+		if args.len() > 6 {
+			return Err(error(ErrorCode::InvalidArgument, "Guest calls take at most 6 arguments"))
+		}
+		let mut all = [0; 6];
+		all[..args.len()].copy_from_slice(args);
+		Ok(self.a.call_guest(func, &all)?)
+	}
+	/// After setup, so states can be made
+	pub fn seal(&mut self) -> Result<()> {
+		Ok(self.a.seal()?)
+	}
+	/// Open a window for more setup after seal(), until the next one
+	pub fn unseal(&mut self) -> Result<()> {
+		Ok(self.a.unseal()?)
+	}
+	pub fn mount_file(&mut self, name: &str, data: &[u8], writable: bool) -> Result<()> {
+		let data = fs::ChunkedData::from_reader(&mut &data[..]).map_err(anyhow::Error::from)?;
+		Ok(self.a.mount_file(name.to_string(), data, writable)?)
+	}
+	/// A readonly file the guest can write to anyway, with its changes kept in savestates if `persist`
+	pub fn mount_overlay_file(&mut self, name: &str, data: Vec<u8>, persist: bool) -> Result<()> {
+		Ok(self.a.mount_overlay_file(name.to_string(), data, persist)?)
+	}
+	/// Returns what the file has in it now
+	pub fn unmount_file(&mut self, name: &str) -> Result<Vec<u8>> {
+		Ok(self.a.unmount_file(name)?)
+	}
+	/// Has the same restrictions as wbx_save_state
+	pub fn save_state(&mut self, stream: &mut impl Write) -> Result<()> {
+		Ok(self.a.save_state(stream)?)
+	}
+	/// Has the same restrictions as wbx_load_state
+	pub fn load_state(&mut self, stream: &mut impl Read) -> Result<()> {
+		Ok(self.a.load_state(stream)?)
+	}
+	/// Check that `state` would load, without loading it
+	pub fn validate_state(&mut self, state: &[u8]) -> Result<StateCheck> {
+		Ok(self.a.validate_state(&mut &state[..])?)
+	}
+	pub fn state_hash(&mut self) -> Result<u64> {
+		Ok(self.a.state_hash()?)
+	}
+	/// Copy guest memory at `addr` into `dest`, as much as is readable, and return how much that was
+	pub fn read_memory(&mut self, addr: usize, dest: &mut [u8]) -> usize {
+		self.a.read_memory(addr, dest)
+	}
+	/// Copy `src` into guest memory at `addr`, as much as is writable, and return how much that was
+	pub fn write_memory(&mut self, addr: usize, src: &[u8]) -> usize {
+		self.a.write_memory(addr, src)
+	}
+	/// Set what the guest gets for `key` from NR_WBX_GET_CONFIG, or clear it with None
+	pub fn set_config(&mut self, key: &str, value: Option<&str>) {
+		self.a.set_config(key, value)
+	}
+	pub fn memory_domains(&self) -> Vec<MemoryDomain> {
+		(0..self.a.memory_domain_count())
+			.filter_map(|i| self.a.memory_domain(i).ok())
+			.map(|d| MemoryDomain {
+				name: unsafe { std::ffi::CStr::from_ptr(d.name) }.to_string_lossy().into_owned(),
+				addr: AddressRange { start: d.start, size: d.size },
+				word_size: d.word_size,
+				flags: d.flags,
+			})
+			.collect()
+	}
+	/// Read a value of type `ty` (memory_domains::VALUE_*) at `offset` in memory domain `domain`, or in all of guest
+	/// memory if None
+	pub fn read_value(&mut self, domain: Option<usize>, offset: usize, ty: u32) -> Result<u64> {
+		Ok(self.a.read_value(domain, offset, ty)?)
+	}
+	pub fn write_value(&mut self, domain: Option<usize>, offset: usize, ty: u32, value: u64) -> Result<()> {
+		Ok(self.a.write_value(domain, offset, ty, value)?)
+	}
+	/// Keep the last `capacity` changes to a value read_value() reads, for record_history() to add to
+	pub fn add_history_watch(&mut self, domain: Option<usize>, offset: usize, ty: u32, capacity: usize) -> Result<u32> {
+		Ok(self.a.add_history_watch(domain, offset, ty, capacity)?)
+	}
+	pub fn remove_history_watch(&mut self, id: u32) -> Result<()> {
+		Ok(self.a.remove_history_watch(id)?)
+	}
+	/// Once a frame
+	pub fn record_history(&mut self, frame: u64) {
+		self.a.record_history(frame)
+	}
+	/// Every change history watch `id` has from frame `since` on, oldest first
+	pub fn history(&self, id: u32, since: u64) -> Result<Vec<HistorySample>> {
+		let mut res = vec![HistorySample::default(); 64];
+		let mut n = 0;
+		loop {
+			let from = if n == 0 { since } else { res[n - 1].frame + 1 };
+			let got = self.a.history(id, from, &mut res[n..])?;
+			n += got;
+			if n < res.len() {
+				break
+			}
+			res.resize(n * 2, HistorySample::default());
+		}
+		res.truncate(n);
+		Ok(res)
+	}
+	pub fn arenas(&mut self) -> Vec<Arena> {
+		(0..self.a.arena_count())
+			.filter_map(|i| self.a.arena(i).ok())
+			.map(|a| Arena {
+				name: unsafe { std::ffi::CStr::from_ptr(a.name) }.to_string_lossy().into_owned(),
+				addr: AddressRange { start: a.start, size: a.size },
+				mapped_bytes: a.mapped_bytes,
+				state_bytes: a.state_bytes,
+			})
+			.collect()
+	}
+	/// Every battery backed memory domain, one after another
+	pub fn saveram(&mut self) -> Result<Vec<u8>> {
+		Ok(self.a.get_saveram()?)
+	}
+	pub fn put_saveram(&mut self, data: &[u8]) -> Result<()> {
+		Ok(self.a.put_saveram(data)?)
+	}
+	/// Give up on guest calls that run longer than `budget`, or never with None
+	pub fn set_watchdog(&mut self, budget: Option<Duration>) {
+		self.a.set_watchdog(budget)
+	}
+	pub fn set_random_seed(&mut self, seed: u64) {
+		self.a.set_random_seed(seed)
+	}
+	/// Start the stream the core gets from __wbx_entropy() over from `seed`
+	pub fn seed_entropy(&mut self, seed: &[u8]) {
+		self.a.seed_entropy(seed)
+	}
+	/// Have readonly guest memory at `addr` passed over by everything to do with states, for good.  Before seal().
+	pub fn mark_immutable(&mut self, addr: AddressRange) -> Result<()> {
+		Ok(self.a.mark_immutable(addr)?)
+	}
+	/// Make the ROM the core loaded at `addr` readonly for good, to be checked against `expected`, a SHA-256, or the
+	/// hash it has now
+	pub fn register_rom(&mut self, addr: AddressRange, expected: Option<&[u8]>) -> Result<()> {
+		Ok(self.a.register_rom(addr, expected)?)
+	}
+	pub fn unregister_rom(&mut self, start: usize) -> Result<()> {
+		Ok(self.a.unregister_rom(start)?)
+	}
+	pub fn verify_roms(&mut self) -> Result<()> {
+		Ok(self.a.verify_roms()?)
+	}
+	/// Have the guest's wbx_transfer()s to `name` go to `data`
+	/// # Safety
+	/// `data` must stay valid for writes of `size` bytes until the buffer is unregistered or replaced
+	pub unsafe fn register_transfer_buffer(&mut self, name: &str, data: *mut u8, size: usize) -> Result<()> {
+		Ok(self.a.register_transfer_buffer(name, data, size)?)
+	}
+	pub fn unregister_transfer_buffer(&mut self, name: &str) -> Result<()> {
+		Ok(self.a.unregister_transfer_buffer(name)?)
+	}
+	pub fn transfer_info(&self, name: &str) -> Result<TransferInfo> {
+		Ok(self.a.transfer_info(name)?)
+	}
+	/// Start or stop gathering metrics, which are kept either way
+	pub fn set_metrics(&mut self, enabled: bool) {
+		self.a.set_metrics(enabled)
+	}
+	/// What's been gathered, in the Prometheus text format
+	pub fn metrics(&mut self) -> String {
+		self.a.metrics()
+	}
+	/// Move the guest's clocks forward by `ns`
+	pub fn advance_clock(&mut self, ns: u64) {
+		self.a.advance_clock(ns)
+	}
+}
@@ -0,0 +1,124 @@
+// Named parts of guest memory that a core hands out for one purpose each, like VRAM, audio buffers or a decode cache,
+// made and dropped with NR_WBX_CREATE_ARENA and NR_WBX_DESTROY_ARENA.  The host doesn't allocate anything for them;
+// it only keeps the list, so the frontend can show how much of guest memory goes where instead of one opaque heap.
+// Cores make and drop arenas as they run, so the list is in savestates.
+use crate::*;
+use syscall_defs::*;
+use std::{os::raw::c_char, ffi::CString};
+use std::io::{Read, Write};
+
+/// Longer names are turned down
+const MAX_NAME: usize = 255;
+
+struct Arena {
+	name: CString,
+	addr: AddressRange,
+}
+
+/// An arena, as the frontend sees it
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ArenaInfo {
+	/// Valid until the guest makes or drops an arena, a state is loaded, or the host is destroyed
+	pub name: *const c_char,
+	pub start: usize,
+	pub size: usize,
+	/// How much of the pages the arena is on are mapped
+	pub mapped_bytes: usize,
+	/// How much of them would be in a savestate now
+	pub state_bytes: usize,
+}
+
+/// Every arena the guest has, in the order it made them
+#[derive(Default)]
+pub struct Arenas {
+	arenas: Vec<Arena>,
+}
+impl Arenas {
+	/// Add an arena, which can't have the same name as another.  `guest` is all of guest memory.
+	pub fn create(&mut self, name: &str, addr: AddressRange, guest: AddressRange) -> SyscallResult {
+		if name.is_empty() || name.len() > MAX_NAME || addr.size == 0 {
+			return Err(EINVAL)
+		}
+		if addr.start < guest.start || addr.start > guest.end() || addr.size > guest.end() - addr.start {
+			return Err(EINVAL)
+		}
+		let name = CString::new(name).map_err(|_| EINVAL)?;
+		if self.arenas.iter().any(|a| a.name == name) {
+			return Err(EEXIST)
+		}
+		self.arenas.push(Arena { name, addr });
+		Ok(())
+	}
+	pub fn destroy(&mut self, name: &str) -> SyscallResult {
+		let before = self.arenas.len();
+		self.arenas.retain(|a| a.name.as_bytes() != name.as_bytes());
+		if self.arenas.len() == before {
+			return Err(ENOENT)
+		}
+		Ok(())
+	}
+	pub fn count(&self) -> usize {
+		self.arenas.len()
+	}
+	/// The arena's name and where it is
+	pub fn get(&self, index: usize) -> Option<(*const c_char, AddressRange)> {
+		self.arenas.get(index).map(|a| (a.name.as_ptr(), a.addr))
+	}
+}
+impl IStateable for Arenas {
+	fn save_state(&mut self, stream: &mut dyn Write) -> anyhow::Result<()> {
+		bin::write_magic(stream, "Arenas")?;
+		bin::writeval(stream, self.arenas.len() as u64)?;
+		for a in self.arenas.iter_mut() {
+			bin::writeval(stream, a.name.as_bytes().len() as u64)?;
+			stream.write_all(a.name.as_bytes())?;
+			a.addr.save_state(stream)?;
+		}
+		Ok(())
+	}
+	fn load_state(&mut self, stream: &mut dyn Read) -> anyhow::Result<()> {
+		bin::verify_magic(stream, "Arenas")?;
+		self.arenas.clear();
+		for _ in 0..bin::readval::<u64>(stream)? {
+			let len = bin::readval::<u64>(stream)? as usize;
+			if len > MAX_NAME {
+				return Err(coded(ErrorCode::BadStateData, "Arena name in state is too long"))
+			}
+			let mut name = vec![0u8; len];
+			stream.read_exact(&mut name[..])?;
+			let name = CString::new(name).map_err(|_| coded(ErrorCode::BadStateData, "Bad arena name in state"))?;
+			let mut addr = AddressRange { start: 0, size: 0 };
+			addr.load_state(stream)?;
+			self.arenas.push(Arena { name, addr });
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_arenas() -> anyhow::Result<()> {
+		let guest = AddressRange { start: 0x10000, size: 0x10000 };
+		let mut arenas = Arenas::default();
+		arenas.create("vram", AddressRange { start: 0x10800, size: 0x2000 }, guest)?;
+		arenas.create("audio", AddressRange { start: 0x13000, size: 0x100 }, guest)?;
+		assert_eq!(arenas.create("vram", AddressRange { start: 0x14000, size: 0x100 }, guest), Err(EEXIST));
+		assert_eq!(arenas.create("big", AddressRange { start: 0x18000, size: 0x9000 }, guest), Err(EINVAL));
+		assert_eq!(arenas.create("", AddressRange { start: 0x18000, size: 0x100 }, guest), Err(EINVAL));
+		let mut state = Vec::new();
+		arenas.save_state(&mut state)?;
+		arenas.destroy("vram")?;
+		assert_eq!(arenas.destroy("vram"), Err(ENOENT));
+		assert_eq!(arenas.count(), 1);
+		arenas.load_state(&mut &state[..])?;
+		assert_eq!(arenas.count(), 2);
+		let (name, addr) = arenas.get(0).unwrap();
+		assert_eq!(unsafe { std::ffi::CStr::from_ptr(name) }.to_str()?, "vram");
+		assert_eq!(addr, AddressRange { start: 0x10800, size: 0x2000 });
+		Ok(())
+	}
+}
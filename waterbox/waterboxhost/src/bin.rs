@@ -1,6 +1,6 @@
 use std::io::*;
 use std::mem::{transmute, size_of, zeroed};
-use anyhow::anyhow;
+use crate::{ErrorCode, coded};
 use sha2::{Sha256, Digest};
 
 pub fn write<T>(stream: &mut dyn Write, val: &T) -> Result<()> {
@@ -32,7 +32,7 @@ pub fn verify_magic(stream: &mut dyn Read, magic: &str) -> anyhow::Result<()> {
 	stream.read_exact(&mut read_tag[..])?;
 	match std::str::from_utf8(&read_tag[..]) {
 		Ok(s) if s == magic => Ok(()),
-		_ => Err(anyhow!("Bad magic for {} state", magic))
+		_ => Err(coded(ErrorCode::BadStateData, format!("Bad magic for {} state", magic)))
 	}
 }
 pub fn write_hash(stream: &mut dyn Write, hash: &[u8]) -> anyhow::Result<()> {
@@ -45,9 +45,96 @@ pub fn verify_hash(stream: &mut dyn Read, hash: &[u8]) -> anyhow::Result<()> {
 	if read_hash == hash {
 		Ok(())
 	} else {
-		Err(anyhow!("Bad hash for state"))
+		Err(coded(ErrorCode::BadStateData, "Bad hash for state"))
 	}
 }
+/// How much has to go by between progress reports
+const PROGRESS_INTERVAL: u64 = 1 << 20;
+
+/// Counts bytes as they go by, and every so often tells a callback how many that is out of the total expected
+pub struct Progress<'a> {
+	done: u64,
+	reported: u64,
+	total: u64,
+	callback: &'a mut dyn FnMut(u64, u64),
+}
+impl<'a> Progress<'a> {
+	pub fn new(total: u64, callback: &'a mut dyn FnMut(u64, u64)) -> Progress<'a> {
+		Progress { done: 0, reported: 0, total, callback }
+	}
+	fn advance(&mut self, n: usize) {
+		self.done += n as u64;
+		if self.done - self.reported >= PROGRESS_INTERVAL {
+			self.reported = self.done;
+			(self.callback)(self.done, self.total);
+		}
+	}
+	/// Make the last report, once everything's done
+	pub fn finish(&mut self) {
+		(self.callback)(self.done, self.total);
+	}
+}
+
+/// Writes through to another stream, reporting progress
+pub struct ProgressWriter<'a> {
+	inner: &'a mut dyn Write,
+	pub progress: Progress<'a>,
+}
+impl<'a> ProgressWriter<'a> {
+	pub fn new(inner: &'a mut dyn Write, progress: Progress<'a>) -> ProgressWriter<'a> {
+		ProgressWriter { inner, progress }
+	}
+}
+impl<'a> Write for ProgressWriter<'a> {
+	fn write(&mut self, buf: &[u8]) -> Result<usize> {
+		let n = self.inner.write(buf)?;
+		self.progress.advance(n);
+		Ok(n)
+	}
+	fn flush(&mut self) -> Result<()> {
+		self.inner.flush()
+	}
+}
+
+/// Writes through to another stream, counting how much
+pub struct CountingWriter<'a> {
+	inner: &'a mut dyn Write,
+	pub count: u64,
+}
+impl<'a> CountingWriter<'a> {
+	pub fn new(inner: &'a mut dyn Write) -> CountingWriter<'a> {
+		CountingWriter { inner, count: 0 }
+	}
+}
+impl<'a> Write for CountingWriter<'a> {
+	fn write(&mut self, buf: &[u8]) -> Result<usize> {
+		let n = self.inner.write(buf)?;
+		self.count += n as u64;
+		Ok(n)
+	}
+	fn flush(&mut self) -> Result<()> {
+		self.inner.flush()
+	}
+}
+
+/// Reads through from another stream, reporting progress
+pub struct ProgressReader<'a> {
+	inner: &'a mut dyn Read,
+	pub progress: Progress<'a>,
+}
+impl<'a> ProgressReader<'a> {
+	pub fn new(inner: &'a mut dyn Read, progress: Progress<'a>) -> ProgressReader<'a> {
+		ProgressReader { inner, progress }
+	}
+}
+impl<'a> Read for ProgressReader<'a> {
+	fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+		let n = self.inner.read(buf)?;
+		self.progress.advance(n);
+		Ok(n)
+	}
+}
+
 pub fn hash(data: &[u8]) -> Vec<u8> {
 	let mut hasher = Sha256::new();
 	hasher.update(data);
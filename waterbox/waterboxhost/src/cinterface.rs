@@ -1,11 +1,27 @@
 use crate::*;
-use host::{ActivatedWaterboxHost, WaterboxHost};
-use std::{os::raw::c_char, io, ffi::{/*CString, */CStr}};
+use host::{ActivatedWaterboxHost, PendingState, WaterboxHost, WxPolicy};
+use memory_block::{AuditEntry, DirtyTracking, HeatMapInfo, IntegrityCallback, IntegrityInfo, MemoryStats, PageDiff, PageHeat, WatchCallback, WATCH_READ, WATCH_WRITE};
+use memory_domains::MemoryDomainInfo;
+use arenas::ArenaInfo;
+use history::HistorySample;
+use transfers::TransferInfo;
+use rewind::RewindInfo;
+use imports::ImportInfo;
+use coredump::UserRegs;
+use counters::PerfCounters;
+use config::WaterboxConfig;
+use profile::EntryProfile;
+use state_diff::StateDiffInfo;
+use state_format::StateCheck;
+use syscall_policy::SyscallAction;
+use syscall_defs::{SyscallError, EINVAL};
+use std::{os::raw::c_char, io, ffi::{CString, CStr}};
 
 /// The memory template for a WaterboxHost.  Don't worry about
 /// making every size as small as possible, since the savestater handles sparse regions
 /// well enough.  All values should be PAGESIZE aligned.
 #[repr(C)]
+#[derive(Debug, Clone, Copy)]
 pub struct MemoryLayoutTemplate {
 	/// Memory space to serve brk(2)
 	pub sbrk_size: usize,
@@ -51,20 +67,103 @@ impl MemoryLayoutTemplate {
 			size: mmap_size
 		};
 		if res.elf.start >> 32 != (res.mmap.end() - 1) >> 32 {
-			Err(anyhow!("HostMemoryLayout must fit into a single 4GiB region!"))
+			Err(coded(ErrorCode::InvalidArgument, "HostMemoryLayout must fit into a single 4GiB region!"))
 		} else {
 			Ok(res)
 		}
 	}
 }
 
+/// Everything about a new host, for wbx_create_host_with_config.  Fields are only ever added at the end, and `size` says
+/// how many bytes of it the frontend knows about; the rest are as the wbx_set_* calls have them.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HostConfig {
+	/// sizeof(HostConfig) as the frontend has it
+	pub size: usize,
+	pub layout: MemoryLayoutTemplate,
+	/// As in wbx_set_dirty_tracking
+	pub dirty_tracking: u32,
+	/// As in wbx_set_wx_policy
+	pub wx_policy: u32,
+	/// HOST_CONFIG_*
+	pub flags: u64,
+	/// For HOST_CONFIG_MMAP_RANDOMIZATION, as in wbx_set_mmap_randomization
+	pub mmap_seed: u64,
+}
+pub const HOST_CONFIG_SANITIZER_MODE: u64 = 1;
+pub const HOST_CONFIG_HUGE_PAGES: u64 = 2;
+pub const HOST_CONFIG_SCRIBBLE: u64 = 4;
+pub const HOST_CONFIG_COMMIT_UPFRONT: u64 = 8;
+pub const HOST_CONFIG_WASM_NO_EXEC: u64 = 16;
+pub const HOST_CONFIG_MMAP_RANDOMIZATION: u64 = 32;
+const HOST_CONFIG_ALL: u64 = 63;
+impl HostConfig {
+	/// As the wbx_set_* calls have it
+	fn current() -> HostConfig {
+		let c = WaterboxConfig::new();
+		let flag = |val: bool, f: u64| if val { f } else { 0 };
+		HostConfig {
+			size: std::mem::size_of::<HostConfig>(),
+			layout: c.layout,
+			dirty_tracking: match c.dirty_tracking {
+				DirtyTracking::Signal => 0,
+				DirtyTracking::Userfaultfd => 1,
+				DirtyTracking::Eager => 2,
+			},
+			wx_policy: 0,
+			flags: flag(c.sanitizer_mode, HOST_CONFIG_SANITIZER_MODE) | flag(c.huge_pages, HOST_CONFIG_HUGE_PAGES)
+				| flag(c.scribble, HOST_CONFIG_SCRIBBLE) | flag(c.commit_upfront, HOST_CONFIG_COMMIT_UPFRONT)
+				| flag(c.wasm_no_exec, HOST_CONFIG_WASM_NO_EXEC) | flag(c.mmap_seed.is_some(), HOST_CONFIG_MMAP_RANDOMIZATION),
+			mmap_seed: c.mmap_seed.unwrap_or(0),
+		}
+	}
+	/// Read one that might be from a frontend that knows of more or fewer fields than this host
+	/// unsafe: `config` must point to at least its `size` bytes
+	pub unsafe fn read(config: *const HostConfig) -> anyhow::Result<HostConfig> {
+		let size = *(config as *const usize);
+		let ours = std::mem::size_of::<HostConfig>();
+		if size < std::mem::size_of::<usize>() + std::mem::size_of::<MemoryLayoutTemplate>() {
+			return Err(coded(ErrorCode::InvalidArgument, format!("HostConfig of {} bytes is too small", size)))
+		}
+		let theirs = std::slice::from_raw_parts(config as *const u8, size);
+		if theirs[std::cmp::min(size, ours)..].iter().any(|&b| b != 0) {
+			return Err(coded(ErrorCode::Unsupported, "HostConfig sets options this host doesn't know about"))
+		}
+		let mut res = HostConfig::current();
+		let n = std::cmp::min(size, ours);
+		std::ptr::copy_nonoverlapping(theirs.as_ptr(), &mut res as *mut HostConfig as *mut u8, n);
+		res.size = ours;
+		Ok(res)
+	}
+	pub fn to_config(self) -> anyhow::Result<WaterboxConfig> {
+		if self.flags & !HOST_CONFIG_ALL != 0 {
+			return Err(coded(ErrorCode::Unsupported, format!("Unknown HostConfig flags {:#x}", self.flags & !HOST_CONFIG_ALL)))
+		}
+		let flag = |f: u64| self.flags & f != 0;
+		Ok(WaterboxConfig::new()
+			.layout(self.layout)
+			.dirty_tracking(DirtyTracking::from_raw(self.dirty_tracking)?)
+			.wx_policy(WxPolicy::from_raw(self.wx_policy)?)
+			.sanitizer_mode(flag(HOST_CONFIG_SANITIZER_MODE))
+			.huge_pages(flag(HOST_CONFIG_HUGE_PAGES))
+			.scribble(flag(HOST_CONFIG_SCRIBBLE))
+			.commit_upfront(flag(HOST_CONFIG_COMMIT_UPFRONT))
+			.wasm_no_exec(flag(HOST_CONFIG_WASM_NO_EXEC))
+			.mmap_seed(if flag(HOST_CONFIG_MMAP_RANDOMIZATION) { Some(self.mmap_seed) } else { None }))
+	}
+}
+
 /// "return" struct.  On successful funtion call, error_message[0] will be 0 and data will be the return value.
-/// On failed call, error_message will contain a string describing the error, and data will be unspecified.
+/// On failed call, error_message will contain a string describing the error, error_code will say what kind of error it
+/// was (see ErrorCode; the numbers are stable), and data will be unspecified.
 /// Any function that takes this object as an argument can fail and should be checked for failure, even if
 /// it does not return data.
 #[repr(C)]
 pub struct Return<T> {
-	pub error_message: [u8; 1024],
+	pub error_message: [u8; 1020],
+	/// Right before data, which stays at offset 1024
+	pub error_code: ErrorCode,
 	pub data: T,
 }
 impl<T> Return<T> {
@@ -72,12 +171,14 @@ impl<T> Return<T> {
 		match result {
 			Err(e) => {
 				let s = format!("Waterbox Error: {:?}", e);
-				let len = std::cmp::min(s.len(), 1023);
+				let len = std::cmp::min(s.len(), self.error_message.len() - 1);
 				self.error_message[0..len].copy_from_slice(&s.as_bytes()[0..len]);
 				self.error_message[len] = 0;
+				self.error_code = ErrorCode::of(&e);
 			},
 			Ok(t) => {
 				self.error_message[0] = 0;
+				self.error_code = ErrorCode::None;
 				self.data = t;
 			}
 		}
@@ -145,7 +246,7 @@ fn arg_to_str(arg: *const c_char) -> anyhow::Result<String> {
 	let cs = unsafe { CStr::from_ptr(arg as *const c_char) };
 	match cs.to_str() {
 		Ok(s) => Ok(s.to_string()),
-		Err(_) => Err(anyhow!("Bad UTF-8 string")),
+		Err(_) => Err(coded(ErrorCode::InvalidArgument, "Bad UTF-8 string")),
 	}
 }
 
@@ -156,7 +257,7 @@ fn read_whole_file(reader: &mut CReader) -> anyhow::Result<Vec<u8>> {
 }
 
 /// Given a guest executable and a memory layout, create a new host environment.  All data will be immediately consumed from the reader,
-/// which will not be used after this call.
+/// which will not be used after this call.  Everything else about the host is as the wbx_set_* calls have it.
 #[no_mangle]
 pub extern fn wbx_create_host(layout: &MemoryLayoutTemplate, module_name: *const c_char, callback: ReadCallback, userdata: usize, ret: &mut Return<*mut WaterboxHost>) {
 	let mut reader = CReader {
@@ -170,13 +271,30 @@ pub extern fn wbx_create_host(layout: &MemoryLayoutTemplate, module_name: *const
 	ret.put(res.map(|boxed| Box::into_raw(boxed)));
 }
 
+/// wbx_create_host, with everything about the host in `config` instead of in the wbx_set_* calls.  Combinations that
+/// can't work fail before the executable is read.
+#[no_mangle]
+pub extern fn wbx_create_host_with_config(config: *const HostConfig, module_name: *const c_char, callback: ReadCallback, userdata: usize, ret: &mut Return<*mut WaterboxHost>) {
+	let mut reader = CReader {
+		userdata,
+		callback
+	};
+	let res = (|| {
+		let config = unsafe { HostConfig::read(config)? }.to_config()?;
+		config.validate()?;
+		let data = read_whole_file(&mut reader)?;
+		WaterboxHost::with_config(data, &arg_to_str(module_name)?[..], &config)
+	})();
+	ret.put(res.map(Box::into_raw));
+}
+
 /// Tear down a host environment.  May not be called while the environment is active.
 #[no_mangle]
 pub extern fn wbx_destroy_host(obj: *mut WaterboxHost, ret: &mut Return<()>) {
 	let res = (|| {
 		unsafe {
 			if (*obj).active() {
-				return Err(anyhow!("WaterboxHost is still active!"))
+				return Err(coded(ErrorCode::BadState, "WaterboxHost is still active!"))
 			}
 			Box::from_raw(obj);
 			Ok(())
@@ -185,16 +303,38 @@ pub extern fn wbx_destroy_host(obj: *mut WaterboxHost, ret: &mut Return<()>) {
 	ret.put(res);
 }
 
+/// Give back everything a host holds outside of itself, the way wbx_destroy_host would, but without freeing the host:
+/// Guest memory and its snapshots, the files the guest has open, the rewind buffer and journal, and transfer buffers.
+/// It can't be activated again afterwards, only destroyed.  May not be called while the host is active.  For frontends
+/// that swap cores, to check with wbx_get_leak_audit that nothing is left before letting go of the host.
+#[no_mangle]
+pub extern fn wbx_shutdown_host(obj: &mut WaterboxHost, ret: &mut Return<()>) {
+	ret.put(obj.shutdown());
+}
+
+/// Count what every host in the process still holds from the OS:  Hosts, guest memory blocks and how many are mapped in,
+/// host views, descriptors, gdb registrations and snapshot pages.  With every host destroyed or shut down, anything but
+/// pooled pages is a leak.  Only in debug builds.
+#[cfg(debug_assertions)]
+#[no_mangle]
+pub extern fn wbx_get_leak_audit(ret: &mut Return<leaks::LeakAudit>) {
+	ret.put(Ok(leaks::audit()));
+}
+
 /// Activate a host environment.  This swaps it into memory and makes it available for use.
-/// Pointers to inside the environment are only valid while active.  Uses a mutex internally
-/// so as to not stomp over other host environments in the same 4GiB slice.
+/// Pointers to inside the environment are only valid while active.  Uses a lock internally
+/// so as to not stomp over other host environments in the same 4GiB slice.  Any thread can activate
+/// a host, and it can then be used and deactivated from any other thread, one at a time.
 /// Returns a pointer to the activated object, used to do most other functions.
 #[no_mangle]
 pub extern fn wbx_activate_host(obj: *mut WaterboxHost, ret: &mut Return<*mut ActivatedWaterboxHost>) {
 	let res = (|| {
 		unsafe {
-			if (*obj).active() {
-				return Err(anyhow!("WaterboxHost is already active!"))
+			if (*obj).shut_down() {
+				return Err(coded(ErrorCode::BadState, "WaterboxHost was shut down!"))
+			}
+			if !(*obj).claim() {
+				return Err(coded(ErrorCode::BadState, "WaterboxHost is already active!"))
 			}
 			Ok((&mut (*obj)).activate())
 		}
@@ -202,11 +342,19 @@ pub extern fn wbx_activate_host(obj: *mut WaterboxHost, ret: &mut Return<*mut Ac
 	ret.put(res.map(|boxed| Box::into_raw(boxed)));
 }
 
-/// Deactivates a host environment, and releases the mutex.
+/// Deactivates a host environment, and releases the lock.  Fails if a guest call is still running on it.
 #[no_mangle]
 pub extern fn wbx_deactivate_host(obj: *mut ActivatedWaterboxHost, ret: &mut Return<()>) {
-	unsafe { Box::from_raw(obj); }
-	ret.put(Ok(()));
+	let res = (|| {
+		unsafe {
+			if (*obj).calling() {
+				return Err(coded(ErrorCode::BadState, "A guest call is still running!"))
+			}
+			drop(Box::from_raw(obj));
+			Ok(())
+		}
+	})();
+	ret.put(res);
 }
 
 /// Returns the address of an exported function from the guest executable.  This pointer is only valid
@@ -223,12 +371,38 @@ pub extern fn wbx_get_proc_addr(obj: &mut ActivatedWaterboxHost, name: *const c_
 	}
 }
 
-/// Calls the seal operation, which is a one time action that prepares the host to save states.
+/// Calls the seal operation, which prepares the host to save states.  If the host was unsealed since it was last
+/// sealed, this seals it again, against guest memory as it is now, and states from before can't be loaded anymore.
 #[no_mangle]
 pub extern fn wbx_seal(obj: &mut ActivatedWaterboxHost, ret: &mut Return<()>) {
 	ret.put(obj.seal());
 }
 
+/// Opens a window for more setup after wbx_seal, such as an init the core only does after its first frame, so that
+/// what it writes becomes part of the baseline at the next wbx_seal instead of being in every state.  States can't be
+/// saved or loaded until then.
+#[no_mangle]
+pub extern fn wbx_unseal(obj: &mut ActivatedWaterboxHost, ret: &mut Return<()>) {
+	ret.put(obj.unseal());
+}
+
+/// Like wbx_unseal, and also makes what sealing made read only, like the sealed heap, writable again, so that a core's
+/// developer can poke at its setup with wbx_write_memory.  Only in builds with the dev-unseal feature.
+#[cfg(feature = "dev-unseal")]
+#[no_mangle]
+pub extern fn wbx_unseal_for_development(obj: &mut ActivatedWaterboxHost, ret: &mut Return<()>) {
+	ret.put(obj.unseal_for_development());
+}
+
+/// Restrict the calling thread, and threads it starts from now on, to the syscalls the host needs, plus the `count`
+/// syscall numbers at `extra` for the frontend's own code on that thread.  Anything else fails with EPERM.  This is a
+/// seccomp filter, so it can't be undone, and is only available on Linux.  Must be called after wbx_seal.
+#[no_mangle]
+pub extern fn wbx_restrict_syscalls(obj: &mut ActivatedWaterboxHost, extra: *const u32, count: usize, ret: &mut Return<()>) {
+	let extra = if count == 0 { &[][..] } else { unsafe { std::slice::from_raw_parts(extra, count) } };
+	ret.put(obj.restrict_syscalls(extra));
+}
+
 /// Mounts a file in the environment.  All data will be immediately consumed from the reader, which will not be used after this call.
 /// To prevent nondeterminism, adding and removing files is very limited WRT savestates.  If a file is writable, it must never exist
 /// when save_state is called, and can only be used for transient operations.  If a file is readable, it can appear in savestates,
@@ -240,8 +414,143 @@ pub extern fn wbx_mount_file(obj: &mut ActivatedWaterboxHost, name: *const c_cha
 		callback
 	};
 	let res: anyhow::Result<()> = (|| {
-		obj.mount_file(arg_to_str(name)?, read_whole_file(&mut reader)?, writable)?;
+		obj.mount_file(arg_to_str(name)?, fs::ChunkedData::from_reader(&mut reader)?, writable)?;
+		Ok(())
+	})();
+	ret.put(res);
+}
+
+/// Mounts a file the guest can write to without the original content ever changing.  Writes go to an in-memory copy
+/// that is part of savestates, so like a readonly file, it must be mounted the same way before every savestate is loaded.
+/// If `persist` is set, wbx_flush_files will hand back its content once the guest has changed it.
+#[no_mangle]
+pub extern fn wbx_mount_overlay_file(obj: &mut ActivatedWaterboxHost, name: *const c_char, callback: ReadCallback, userdata: usize, persist: bool, ret: &mut Return<()>) {
+	let mut reader = CReader {
+		userdata,
+		callback
+	};
+	let res: anyhow::Result<()> = (|| {
+		obj.mount_overlay_file(arg_to_str(name)?, read_whole_file(&mut reader)?, persist)?;
+		Ok(())
+	})();
+	ret.put(res);
+}
+
+/// Mounts every file in a directory of the host, and its subdirectories, under `guest_path`, so that the guest can open
+/// them by path.  The files are read in immediately and mounted in name order.  If `readonly`, they have the same rules
+/// as readonly files from wbx_mount_file; otherwise the guest can write to them as with wbx_mount_overlay_file, but they
+/// don't persist.  They can be removed one at a time with wbx_unmount_file.  Returns how many files were mounted.
+#[no_mangle]
+pub extern fn wbx_mount_host_dir(obj: &mut ActivatedWaterboxHost, guest_path: *const c_char, host_path: *const c_char, readonly: bool, ret: &mut Return<usize>) {
+	let res = (|| {
+		obj.mount_host_dir(&arg_to_str(guest_path)?, &arg_to_str(host_path)?, readonly)
+	})();
+	ret.put(res);
+}
+
+/// Allow host files under the directory `path` to be read in by wbx_mount_host_dir, and written out, as by
+/// wbx_set_core_dump_path, if `writable`.  Until the first call every path is allowed; after it, only paths under an
+/// allowed directory are, even through symlinks.
+#[no_mangle]
+pub extern fn wbx_allow_host_path(obj: &mut ActivatedWaterboxHost, path: *const c_char, writable: bool, ret: &mut Return<()>) {
+	let res = (|| {
+		obj.allow_host_path(&arg_to_str(path)?, writable)
+	})();
+	ret.put(res);
+}
+
+/// Have the kernel enforce the paths allowed with wbx_allow_host_path, with Landlock, on the calling thread and any
+/// threads it starts from now on, so that no file outside them can be touched by anything on that thread.  This can't
+/// be undone, and fails if the kernel doesn't support Landlock.  Linux only.
+#[no_mangle]
+pub extern fn wbx_restrict_host_paths(obj: &mut ActivatedWaterboxHost, ret: &mut Return<()>) {
+	ret.put(obj.restrict_host_paths());
+}
+
+/// Mounts the files in a zip archive, read from the reader, as readonly files under `mount_point`, so `roms/a.bin` in
+/// the archive becomes `<mount_point>/roms/a.bin`.  Each file is only decompressed while the guest has it open.  Only
+/// stored and deflated files are supported, and 7z archives aren't at all; the frontend has to extract those itself.
+/// The same savestate rules as readonly files from wbx_mount_file apply.  Returns how many files were mounted.
+#[no_mangle]
+pub extern fn wbx_mount_zip(obj: &mut ActivatedWaterboxHost, mount_point: *const c_char, callback: ReadCallback, userdata: usize, ret: &mut Return<usize>) {
+	let mut reader = CReader {
+		userdata,
+		callback
+	};
+	let res = (|| {
+		obj.mount_zip(&arg_to_str(mount_point)?, read_whole_file(&mut reader)?)
+	})();
+	ret.put(res);
+}
+
+/// Says whether the stream has anything to read right now, for the guest's poll() and epoll_wait()
+pub type StreamReadyCallback = extern fn(userdata: usize) -> bool;
+
+/// Mounts a readonly pipe that the guest can open by name, where every read the guest makes calls the reader for more.
+/// Unlike wbx_mount_file, nothing is read ahead of time, so the reader and userdata must stay valid until the stream is
+/// removed with wbx_unmount_file.  If `ready` is null, the guest is always told there's something to read.
+/// Streams are transient, and can't exist when save_state or load_state is called.
+#[no_mangle]
+pub extern fn wbx_mount_stream(obj: &mut ActivatedWaterboxHost, name: *const c_char, callback: ReadCallback, ready: Option<StreamReadyCallback>, userdata: usize, ret: &mut Return<()>) {
+	let reader = CReader {
+		userdata,
+		callback
+	};
+	let ready = ready.map(|r| Box::new(move || r(userdata)) as Box<dyn FnMut() -> bool>);
+	let res: anyhow::Result<()> = (|| {
+		obj.mount_stream(arg_to_str(name)?, Box::new(reader), ready)?;
+		Ok(())
+	})();
+	ret.put(res);
+}
+
+/// Receives the name and content of one changed persistent file.  Return 0 on success, or < 0 on failure.
+/// Both pointers are only valid during the callback.
+pub type FlushCallback = extern fn(userdata: usize, name: *const c_char, data: *const u8, size: usize) -> i32;
+
+/// Call `callback` once for each file mounted with wbx_mount_overlay_file and `persist` that the guest has changed,
+/// so the frontend can write saves and configs back to disk when it wants to.
+#[no_mangle]
+pub extern fn wbx_flush_files(obj: &mut ActivatedWaterboxHost, callback: FlushCallback, userdata: usize, ret: &mut Return<()>) {
+	let res = obj.flush_files(|name, data| {
+		let name = CString::new(name)?;
+		if callback(userdata, name.as_ptr(), data.as_ptr(), data.len()) < 0 {
+			return Err(anyhow!("Callback signaled abnormal failure"))
+		}
 		Ok(())
+	});
+	ret.put(res);
+}
+
+/// Load a secondary guest library, such as a filter plugin, from the reader.  Its undefined symbols are linked against
+/// the main executable and any libraries loaded before it, and its exports can then be found with wbx_get_proc_addr.
+/// Must be called before wbx_seal.  Returns the address the library was loaded at.
+#[no_mangle]
+pub extern fn wbx_load_module(obj: &mut ActivatedWaterboxHost, name: *const c_char, callback: ReadCallback, userdata: usize, ret: &mut Return<usize>) {
+	let mut reader = CReader {
+		userdata,
+		callback
+	};
+	let res = (|| {
+		obj.load_module(&arg_to_str(name)?, read_whole_file(&mut reader)?)
+	})();
+	ret.put(res);
+}
+
+/// Experimental, for core development:  Replace the guest's code and read only data with a rebuilt executable from the
+/// reader, keeping all of its memory, so that changes to a core can be tried without restarting the game.  The rebuild
+/// has to have all of its variables in the same places, which a change that only touches code usually does.  Function
+/// pointers the guest has kept, like return addresses on its stacks, still point to where code was in the old build.
+/// Not for wasm guests, position independent executables, or guests with modules loaded.  Savestates made before the
+/// reload can't be loaded after it.
+#[no_mangle]
+pub extern fn wbx_reload_elf(obj: &mut ActivatedWaterboxHost, callback: ReadCallback, userdata: usize, ret: &mut Return<()>) {
+	let mut reader = CReader {
+		userdata,
+		callback
+	};
+	let res = (|| {
+		obj.reload_elf(read_whole_file(&mut reader)?)
 	})();
 	ret.put(res);
 }
@@ -311,6 +620,73 @@ pub extern fn wbx_save_state(obj: &mut ActivatedWaterboxHost, callback: WriteCal
 	})();
 	ret.put(res);
 }
+/// Capture a state to be written out later with wbx_finish_save_state, which can be called from another thread while
+/// this host keeps running.  Has the same restrictions as wbx_save_state.  Beginning another state before this one
+/// is finished makes this one copy out everything it still needs at that point.
+#[no_mangle]
+pub extern fn wbx_begin_save_state(obj: &mut ActivatedWaterboxHost, ret: &mut Return<*mut PendingState>) {
+	ret.put(obj.begin_save_state().map(|s| Box::into_raw(Box::new(s))));
+}
+/// Write out and free a state from wbx_begin_save_state.  The produced state is identical to what wbx_save_state would
+/// have produced at the time of wbx_begin_save_state.  `state` is freed even if this fails.
+#[no_mangle]
+pub extern fn wbx_finish_save_state(state: *mut PendingState, callback: WriteCallback, userdata: usize, ret: &mut Return<()>) {
+	let mut writer = CWriter {
+		userdata,
+		callback
+	};
+	let state = unsafe { Box::from_raw(state) };
+	ret.put(state.save_state(&mut writer));
+}
+/// Hash everything that would be in a savestate, much faster than making one, for netplay and movie sync checks.
+/// Two hosts running the same core in the same state give the same hash.
+#[no_mangle]
+pub extern fn wbx_get_state_hash(obj: &mut ActivatedWaterboxHost, ret: &mut Return<u64>) {
+	ret.put(obj.state_hash());
+}
+
+/// Told when a wbx_save_state_async() is done, on the thread that wrote the state out.  On success, `data` and `size`
+/// are the state, valid only during the callback, and `error` is null.  On failure, `error` says why.
+pub type StateCompleteCallback = extern fn(userdata: usize, data: *const u8, size: usize, error: *const c_char);
+
+/// Capture a state and return right away, while a worker thread writes it out and hands it to `callback`.  The
+/// state is identical to what wbx_save_state would have produced now, and it has the same restrictions.
+#[no_mangle]
+pub extern fn wbx_save_state_async(obj: &mut ActivatedWaterboxHost, callback: StateCompleteCallback, userdata: usize, ret: &mut Return<()>) {
+	ret.put(obj.save_state_async(Vec::new(), move |res| {
+		match res {
+			Ok(data) => callback(userdata, data.as_ptr(), data.len(), std::ptr::null()),
+			Err(e) => {
+				let text = CString::new(format!("Waterbox Error: {:?}", e)).unwrap_or_default();
+				callback(userdata, std::ptr::null(), 0, text.as_ptr());
+			},
+		}
+	}));
+}
+
+/// Told when a wbx_save_state_async_stream() is done, on the thread that wrote the state out.  `error` is null on
+/// success, or says why it failed.
+pub type StreamCompleteCallback = extern fn(userdata: usize, error: *const c_char);
+
+/// wbx_save_state_async, but the worker thread writes the state to `callback` as it goes, instead of handing it over
+/// all at once, so it can go straight to a file or a socket without ever being in memory in full.  `done` is called
+/// after the last write.  Both callbacks get `userdata`, and are called on the worker thread.
+#[no_mangle]
+pub extern fn wbx_save_state_async_stream(obj: &mut ActivatedWaterboxHost, callback: WriteCallback, done: StreamCompleteCallback, userdata: usize, ret: &mut Return<()>) {
+	let writer = CWriter {
+		userdata,
+		callback
+	};
+	ret.put(obj.save_state_async(writer, move |res| {
+		match res {
+			Ok(_) => done(userdata, std::ptr::null()),
+			Err(e) => {
+				let text = CString::new(format!("Waterbox Error: {:?}", e)).unwrap_or_default();
+				done(userdata, text.as_ptr());
+			},
+		}
+	}));
+}
 /// Load state.  Must not be called before seal.  Must not be called with any writable files mounted.
 /// Must always be called with the same sequence and contents of readonly files that were in the save state.
 /// Must be called with the same wbx executable and memory layout as in the savestate.
@@ -324,13 +700,1248 @@ pub extern fn wbx_load_state(obj: &mut ActivatedWaterboxHost, callback: ReadCall
 	ret.put(obj.load_state(&mut reader));
 }
 
-/// Control whether the host automatically evicts blocks from memory when they are not active.  For the best performance,
-/// this should be set to false.  Set to true to help catch dangling pointer issues.  Will be ignored (and forced to true)
-/// if waterboxhost was built in debug mode.  This is a single global setting.
+/// Read a state from `callback` all the way through, and check everything wbx_load_state would, without loading it or
+/// touching guest memory, so that states this host can't load can be greyed out.  Fails with the error loading it
+/// would have, such as StateMismatch with which area of memory is somewhere else.  A state that changed a ROM region
+/// passes, and fails when it's loaded.  Has the same restrictions as wbx_load_state.
 #[no_mangle]
-pub extern fn wbx_set_always_evict_blocks(_val: bool) {
-	#[cfg(not(debug_assertions))]
-	{
-		unsafe { ALWAYS_EVICT_BLOCKS = _val; }
-	}
+pub extern fn wbx_validate_state(obj: &mut ActivatedWaterboxHost, callback: ReadCallback, userdata: usize, ret: &mut Return<StateCheck>) {
+	let mut reader = CReader {
+		userdata,
+		callback
+	};
+	ret.put(obj.validate_state(&mut reader));
+}
+
+/// Compare two states made by this host, read from `a` and `b`, without loading either, for tracking down desyncs.
+/// Copies up to `len` of the pages they differ on into `dest`, in address order, and returns how many bytes differ in
+/// each area of guest memory, and in the host's own part of the states.  Has the same restrictions as wbx_load_state.
+#[no_mangle]
+pub extern fn wbx_compare_states(obj: &mut ActivatedWaterboxHost, a: ReadCallback, a_userdata: usize, b: ReadCallback, b_userdata: usize,
+	dest: *mut PageDiff, len: usize, ret: &mut Return<StateDiffInfo>) {
+	let mut reader_a = CReader {
+		userdata: a_userdata,
+		callback: a
+	};
+	let mut reader_b = CReader {
+		userdata: b_userdata,
+		callback: b
+	};
+	let res = obj.compare_states(&mut reader_a, &mut reader_b).map(|(info, pages)| {
+		let n = std::cmp::min(len, pages.len());
+		unsafe { std::slice::from_raw_parts_mut(dest, n) }.copy_from_slice(&pages[..n]);
+		info
+	});
+	ret.put(res);
+}
+
+/// Told how far a state operation has gotten, every megabyte or so and once at the end
+pub type ProgressCallback = extern fn(userdata: usize, done: u64, total: u64);
+
+/// wbx_save_state, reporting progress to `progress` in bytes of the uncompressed state.  Both callbacks get `userdata`.
+#[no_mangle]
+pub extern fn wbx_save_state_with_progress(obj: &mut ActivatedWaterboxHost, callback: WriteCallback, userdata: usize, progress: ProgressCallback, ret: &mut Return<()>) {
+	let mut writer = CWriter {
+		userdata,
+		callback
+	};
+	ret.put(obj.save_state_with_progress(&mut writer, &mut |done, total| progress(userdata, done, total)));
+}
+/// wbx_load_state, from the file at `path`.  Guest memory in the state is mapped from the file where it can be, instead
+/// of being copied in, so that loading even very large states is quick, and pages are only copied once something needs
+/// them to be.  That only works with uncompressed states, 4K host pages, signal based dirty tracking, and before any
+/// host view or memory handle is handed out.  Otherwise, or on Windows, the state is just loaded from the file.  The
+/// file must not change until another state is loaded.
+#[no_mangle]
+pub extern fn wbx_load_state_file(obj: &mut ActivatedWaterboxHost, path: *const c_char, ret: &mut Return<()>) {
+	ret.put(arg_to_str(path).and_then(|p| obj.load_state_file(&p[..])));
+}
+
+/// wbx_load_state, reporting progress to `progress` in bytes read from `callback`, out of `size`, which is how big the
+/// state is as it's stored.  Both callbacks get `userdata`.
+#[no_mangle]
+pub extern fn wbx_load_state_with_progress(obj: &mut ActivatedWaterboxHost, callback: ReadCallback, userdata: usize, size: u64, progress: ProgressCallback, ret: &mut Return<()>) {
+	let mut reader = CReader {
+		userdata,
+		callback
+	};
+	ret.put(obj.load_state_with_progress(&mut reader, size, &mut |done, total| progress(userdata, done, total)));
+}
+
+/// Control whether save_state produces compressed states for this host.  Defaults to false.  Load state
+/// detects compressed states automatically, so this does not need to match what was used when the state was made.
+#[no_mangle]
+pub extern fn wbx_set_compress_states(obj: &mut ActivatedWaterboxHost, val: bool, ret: &mut Return<()>) {
+	obj.set_compress_states(val);
+	ret.put(Ok(()));
+}
+
+/// Control whether save_state checks modified memory against the baseline taken at seal time, so that pages which
+/// were written to but have since returned to their original content are not included in the state.  This costs a
+/// comparison for each dirty page on every save, but can make states much smaller.  Defaults to false.
+/// States made either way are loadable regardless of this setting.
+#[no_mangle]
+pub extern fn wbx_set_delta_states(obj: &mut ActivatedWaterboxHost, val: bool, ret: &mut Return<()>) {
+	obj.set_delta_states(val);
+	ret.put(Ok(()));
+}
+
+/// Set the size of the host's internal rewind buffer, in bytes.  Any frames already captured are discarded.  Pass 0 to disable rewind,
+/// which is the default.  When the buffer fills up, the oldest frames are evicted.  Every 30th frame is a keyframe, as
+/// wbx_set_rewind() has it.
+#[no_mangle]
+pub extern fn wbx_set_rewind_capacity(obj: &mut ActivatedWaterboxHost, capacity: usize, ret: &mut Return<()>) {
+	obj.set_rewind_capacity(capacity);
+	ret.put(Ok(()));
+}
+
+/// Set up the host's rewind buffer with at most `capacity` bytes, in which every `keyframe_interval`th frame is a whole
+/// state and the ones between only have what changed since it.  Everything is compressed, and the buffer's own working
+/// copy of the newest keyframe counts against `capacity` too.  When it fills up, the oldest keyframe and everything made
+/// against it are evicted together.  Any frames already captured are discarded; pass 0 for `capacity` to disable rewind.
+#[no_mangle]
+pub extern fn wbx_set_rewind(obj: &mut ActivatedWaterboxHost, capacity: usize, keyframe_interval: usize, ret: &mut Return<()>) {
+	obj.set_rewind(capacity, keyframe_interval);
+	ret.put(Ok(()));
+}
+
+/// How big the rewind buffer is, how much of it is used, and how many frames and keyframes are in it.  All 0 if rewind
+/// is disabled.
+#[no_mangle]
+pub extern fn wbx_get_rewind_info(obj: &mut ActivatedWaterboxHost, ret: &mut Return<RewindInfo>) {
+	ret.put(Ok(obj.rewind_info()));
+}
+
+/// Capture the current state into the rewind buffer.  Has the same restrictions as wbx_save_state.
+#[no_mangle]
+pub extern fn wbx_capture_rewind_frame(obj: &mut ActivatedWaterboxHost, ret: &mut Return<()>) {
+	ret.put(obj.capture_rewind_frame());
+}
+
+/// Go back to the frame captured `frames` captures ago; 1 is the most recent capture.  That frame and all of the newer ones
+/// are removed from the rewind buffer.  Fails without changing anything if there are not enough frames available.
+/// Returns the number of frames remaining in the buffer.
+#[no_mangle]
+pub extern fn wbx_rewind(obj: &mut ActivatedWaterboxHost, frames: usize, ret: &mut Return<usize>) {
+	let res = obj.rewind(frames).map(|_| obj.rewind_frame_count());
+	ret.put(res);
+}
+
+/// Start a journal at `path`, which wbx_journal_frame appends a state to every `interval` frames, with all of guest
+/// memory in every `full_interval`th one and only what changed in the others.  An existing journal is added to, so
+/// after a crash, this and wbx_load_journal_frame(u64::MAX) pick up from its last entry.  Must be called after wbx_seal.
+#[no_mangle]
+pub extern fn wbx_start_journal(obj: &mut ActivatedWaterboxHost, path: *const c_char, interval: u64, full_interval: u32, ret: &mut Return<()>) {
+	ret.put(arg_to_str(path).and_then(|p| obj.start_journal(&p[..], interval, full_interval)));
+}
+
+/// Stop appending to the journal, and close it
+#[no_mangle]
+pub extern fn wbx_stop_journal(obj: &mut ActivatedWaterboxHost, ret: &mut Return<()>) {
+	obj.stop_journal();
+	ret.put(Ok(()));
+}
+
+/// Tell the journal that the frontend is at `frame`, after which it writes an entry if one is due.  Returns whether it
+/// did.  Loading a state, from anywhere, makes the next frame start a new timeline in the journal.
+#[no_mangle]
+pub extern fn wbx_journal_frame(obj: &mut ActivatedWaterboxHost, frame: u64, ret: &mut Return<bool>) {
+	ret.put(obj.journal_frame(frame));
+}
+
+/// Load the state from the last journal entry written at or before `frame`.  Returns the frame it was made at.
+#[no_mangle]
+pub extern fn wbx_load_journal_frame(obj: &mut ActivatedWaterboxHost, frame: u64, ret: &mut Return<u64>) {
+	ret.put(obj.load_journal_frame(frame));
+}
+
+/// Say which version of the core this is, like "1.60".  States record it along with the core's name and hash, so a
+/// state from a different core can be turned away with a message that says which made it.
+#[no_mangle]
+pub extern fn wbx_set_core_version(obj: &mut ActivatedWaterboxHost, version: *const c_char, ret: &mut Return<()>) {
+	let res = (|| {
+		obj.set_core_version(&arg_to_str(version)?);
+		Ok(())
+	})();
+	ret.put(res);
+}
+
+/// Set a setting for the guest to look up by name with __wbx_get_config() in emulibc, or remove it if `value` is null.
+/// Settings aren't in savestates, so cores should only read ones that can't affect emulation, or read them during init.
+#[no_mangle]
+pub extern fn wbx_set_config(obj: &mut ActivatedWaterboxHost, key: *const c_char, value: *const c_char, ret: &mut Return<()>) {
+	let res = (|| {
+		let value = if value.is_null() { None } else { Some(arg_to_str(value)?) };
+		obj.set_config(&arg_to_str(key)?, value.as_deref());
+		Ok(())
+	})();
+	ret.put(res);
+}
+
+/// Get the versions of the imports table the host and the guest have, and the bitmaps of waterbox calls each has or
+/// would use:  1 for memory domains, 2 for settings.  The guest's version is 0 if it doesn't take part, and any it would
+/// use that the host doesn't have, it does without.
+#[no_mangle]
+pub extern fn wbx_get_imports(obj: &mut ActivatedWaterboxHost, ret: &mut Return<ImportInfo>) {
+	ret.put(Ok(obj.imports()));
+}
+
+/// Get how many memory domains the guest has registered.  Domains are named regions of guest memory, like main RAM,
+/// that the core makes known with the NR_WBX_REGISTER_MEMORY_DOMAIN syscall, so they can be found without knowing
+/// the core.
+#[no_mangle]
+pub extern fn wbx_get_memory_domain_count(obj: &mut ActivatedWaterboxHost, ret: &mut Return<usize>) {
+	ret.put(Ok(obj.memory_domain_count()));
+}
+
+/// Get the name, location, word size and flags of memory domain `index`, counting from 0 in the order they were first
+/// registered.  Flags are 1 for big endian, 2 for writable and 4 for battery backed.  Access the memory itself with
+/// wbx_map_host_view.
+#[no_mangle]
+pub extern fn wbx_get_memory_domain(obj: &mut ActivatedWaterboxHost, index: usize, ret: &mut Return<MemoryDomainInfo>) {
+	ret.put(obj.memory_domain(index));
+}
+
+/// Get how many arenas the guest has.  Arenas are parts of guest memory the core uses for one thing each, like VRAM,
+/// audio buffers or a decode cache, that it makes known with NR_WBX_CREATE_ARENA, for breaking down the memory it uses.
+#[no_mangle]
+pub extern fn wbx_get_arena_count(obj: &mut ActivatedWaterboxHost, ret: &mut Return<usize>) {
+	ret.put(Ok(obj.arena_count()));
+}
+
+/// Get the name and location of arena `index`, counting from 0 in the order they were made, and how much of it is
+/// mapped, and in savestates, right now.  Those count whole pages, so arenas that share a page both count all of it.
+#[no_mangle]
+pub extern fn wbx_get_arena(obj: &mut ActivatedWaterboxHost, index: usize, ret: &mut Return<ArenaInfo>) {
+	ret.put(obj.arena(index));
+}
+
+/// Get how big the core's SaveRAM is:  every battery backed memory domain, one after another.  0 if it has none.
+#[no_mangle]
+pub extern fn wbx_get_saveram_size(obj: &mut ActivatedWaterboxHost, ret: &mut Return<usize>) {
+	ret.put(Ok(obj.saveram_size()));
+}
+
+/// Write the core's SaveRAM to `callback`, which is every battery backed memory domain, one after another in the order
+/// they were first registered.  This is apart from savestates, for the frontend to keep as the game's save file.
+#[no_mangle]
+pub extern fn wbx_get_saveram(obj: &mut ActivatedWaterboxHost, callback: WriteCallback, userdata: usize, ret: &mut Return<()>) {
+	let mut writer = CWriter {
+		userdata,
+		callback
+	};
+	let res = (|| {
+		writer.write_all(&obj.get_saveram()?[..])?;
+		Ok(())
+	})();
+	ret.put(res);
+}
+
+/// Put back SaveRAM that wbx_get_saveram wrote, all of which is consumed from the reader.  It has to be exactly as big
+/// as wbx_get_saveram_size says.
+#[no_mangle]
+pub extern fn wbx_put_saveram(obj: &mut ActivatedWaterboxHost, callback: ReadCallback, userdata: usize, ret: &mut Return<()>) {
+	let mut reader = CReader {
+		userdata,
+		callback
+	};
+	let res = (|| {
+		obj.put_saveram(&read_whole_file(&mut reader)?[..])
+	})();
+	ret.put(res);
+}
+
+/// Search readable guest memory for the `len` bytes at `pattern`, and write where it was found to `results`, in
+/// increasing order, until `max_results` are found.  Returns how many were written.  `domain` limits the search to one
+/// memory domain (see wbx_get_memory_domain), or is -1 for all of guest memory.  Only addresses that are multiples of
+/// `alignment` are looked at.  `mask` is null, or `len` bytes that say which bits of each byte have to match.
+#[no_mangle]
+pub extern fn wbx_search(obj: &mut ActivatedWaterboxHost, domain: isize, pattern: *const u8, mask: *const u8, len: usize, alignment: usize, results: *mut usize, max_results: usize, ret: &mut Return<usize>) {
+	let pattern = unsafe { std::slice::from_raw_parts(pattern, len) };
+	let mask = if mask.is_null() { None } else { Some(unsafe { std::slice::from_raw_parts(mask, len) }) };
+	let domain = if domain < 0 { None } else { Some(domain as usize) };
+	ret.put(obj.search_memory(domain, pattern, mask, alignment, max_results).map(|found| {
+		let dest = unsafe { std::slice::from_raw_parts_mut(results, max_results) };
+		dest[..found.len()].copy_from_slice(&found[..]);
+		found.len()
+	}));
+}
+
+/// Read a value of type `ty`, one of the VALUE_*s and maybe VALUE_BIG_ENDIAN, at `offset` in memory domain `domain`
+/// (see wbx_get_memory_domain), or at the address `offset` in guest memory if `domain` is -1.  Floats come back as the
+/// bits of an f64.  A domain with a translator the core set with wbx_set_domain_translator() is read where the
+/// translator says each byte is, which runs guest code, so that isn't allowed while recording or replaying.
+#[no_mangle]
+pub extern fn wbx_read_value(obj: &mut ActivatedWaterboxHost, domain: isize, offset: usize, ty: u32, ret: &mut Return<u64>) {
+	let domain = if domain < 0 { None } else { Some(domain as usize) };
+	ret.put(obj.read_value(domain, offset, ty));
+}
+
+/// Write `value` as type `ty` the way wbx_read_value() reads it.  The domain has to be writable.  Fails with
+/// UnmappedAddress, having written what it could, if any of it isn't writable.
+#[no_mangle]
+pub extern fn wbx_write_value(obj: &mut ActivatedWaterboxHost, domain: isize, offset: usize, ty: u32, value: u64, ret: &mut Return<()>) {
+	let domain = if domain < 0 { None } else { Some(domain as usize) };
+	ret.put(obj.write_value(domain, offset, ty, value));
+}
+
+/// Keep a history of the value wbx_read_value() would read, for finding when it changed:  Each wbx_record_history
+/// that reads something different from last time adds the frame and the new value to it, and only the last `capacity`
+/// changes are kept.  Returns an id for the other wbx_*_history calls.  Fails if the value can't be read now.
+#[no_mangle]
+pub extern fn wbx_add_history_watch(obj: &mut ActivatedWaterboxHost, domain: isize, offset: usize, ty: u32, capacity: usize, ret: &mut Return<u32>) {
+	let domain = if domain < 0 { None } else { Some(domain as usize) };
+	ret.put(obj.add_history_watch(domain, offset, ty, capacity));
+}
+
+/// Stop keeping a history added with wbx_add_history_watch, and drop what it has.
+#[no_mangle]
+pub extern fn wbx_remove_history_watch(obj: &mut ActivatedWaterboxHost, id: u32, ret: &mut Return<()>) {
+	ret.put(obj.remove_history_watch(id));
+}
+
+/// Read every history watch at the end of `frame`, which frontends do once a frame.  A frame that isn't after the last
+/// one recorded, as after loading a state, drops every change from that frame on before recording it.
+#[no_mangle]
+pub extern fn wbx_record_history(obj: &mut ActivatedWaterboxHost, frame: u64, ret: &mut Return<()>) {
+	obj.record_history(frame);
+	ret.put(Ok(()));
+}
+
+/// Copy up to `len` of the changes history watch `id` has from frame `since` on into `dest`, oldest first, and return
+/// how many that was.  Histories aren't saved in states.
+#[no_mangle]
+pub extern fn wbx_get_history(obj: &mut ActivatedWaterboxHost, id: u32, since: u64, dest: *mut HistorySample, len: usize, ret: &mut Return<usize>) {
+	let dest = unsafe { std::slice::from_raw_parts_mut(dest, len) };
+	ret.put(obj.history(id, since, dest));
+}
+
+/// Hold the `width` (1, 2, 4 or 8) bytes of guest memory at `addr` at `value`, stored little endian.  If `has_compare`,
+/// the cheat only applies while memory holds `compare`.  Cheats are applied after every wbx_call_guest, and by
+/// wbx_apply_cheats; if `on_write`, they're also put back right after every guest write to them.  Returns an id for
+/// wbx_remove_cheat.  Cheats aren't saved in states.
+#[no_mangle]
+pub extern fn wbx_add_cheat(obj: &mut ActivatedWaterboxHost, addr: usize, width: usize, value: u64, has_compare: bool, compare: u64, on_write: bool, ret: &mut Return<u32>) {
+	let compare = if has_compare { Some(compare) } else { None };
+	ret.put(obj.add_cheat(addr, width, value, compare, on_write));
+}
+
+/// Remove a cheat added by wbx_add_cheat.  The memory it held keeps whatever value it has.
+#[no_mangle]
+pub extern fn wbx_remove_cheat(obj: &mut ActivatedWaterboxHost, id: u32, ret: &mut Return<()>) {
+	ret.put(obj.remove_cheat(id));
+}
+
+/// Log every guest write to `size` bytes at `start`, with the instruction that wrote, and the up to 8 bytes from the
+/// written address that were there before and after, as far as the range goes.  Writes are caught by the same
+/// protection faults as watchpoints, so only on x86_64, and not in sanitizer mode.  Returns an id for
+/// wbx_remove_audit.  Audits aren't saved in states.
+#[no_mangle]
+pub extern fn wbx_add_audit(obj: &mut ActivatedWaterboxHost, start: usize, size: usize, ret: &mut Return<u32>) {
+	ret.put(obj.add_audit(AddressRange { start, size }));
+}
+
+/// Stop logging writes to a range added with wbx_add_audit.  What's already logged stays.
+#[no_mangle]
+pub extern fn wbx_remove_audit(obj: &mut ActivatedWaterboxHost, id: u32, ret: &mut Return<()>) {
+	ret.put(obj.remove_audit(id));
+}
+
+/// Keep at most `capacity` writes in the audit log, which drops the oldest to make room.  The default is 4096.
+#[no_mangle]
+pub extern fn wbx_set_audit_capacity(obj: &mut ActivatedWaterboxHost, capacity: usize, ret: &mut Return<()>) {
+	obj.set_audit_capacity(capacity);
+	ret.put(Ok(()));
+}
+
+/// Move up to `len` of the oldest writes in the audit log into `dest`, and return how many that was.
+#[no_mangle]
+pub extern fn wbx_read_audit_log(obj: &mut ActivatedWaterboxHost, dest: *mut AuditEntry, len: usize, ret: &mut Return<usize>) {
+	let dest = unsafe { std::slice::from_raw_parts_mut(dest, len) };
+	ret.put(Ok(obj.read_audit_log(dest)));
+}
+
+/// Get how many writes the audit log has dropped to make room since this was last called.
+#[no_mangle]
+pub extern fn wbx_get_audit_dropped(obj: &mut ActivatedWaterboxHost, ret: &mut Return<u64>) {
+	ret.put(Ok(obj.take_audit_dropped()));
+}
+
+/// Apply all cheats now.  Frontends that call guest frame functions directly, instead of with wbx_call_guest, call this
+/// after each frame.
+#[no_mangle]
+pub extern fn wbx_apply_cheats(obj: &mut ActivatedWaterboxHost, ret: &mut Return<()>) {
+	obj.apply_cheats();
+	ret.put(Ok(()));
+}
+
+/// Start or stop profiling calls made with wbx_call_guest, and savestates.  Each guest function called gets totals of
+/// the time spent in it, the part of that spent in syscalls, how many syscalls it made, and how many pages it dirtied.
+/// Profiling is off to start with.
+#[no_mangle]
+pub extern fn wbx_set_profiling(obj: &mut ActivatedWaterboxHost, enabled: bool, ret: &mut Return<()>) {
+	obj.set_profiling(enabled);
+	ret.put(Ok(()));
+}
+
+/// Let the guest copy straight into `size` bytes of host memory at `data` under `name`, with wbx_transfer() in emulibc,
+/// for the video frames and audio batches it hands over every frame.  The guest can't write past `size`.  `data` has to
+/// stay put and valid until the buffer is unregistered, or registered again under the same name, which replaces it.
+/// Transfer buffers aren't in states.
+#[no_mangle]
+pub extern fn wbx_register_transfer_buffer(obj: &mut ActivatedWaterboxHost, name: *const c_char, data: *mut u8, size: usize, ret: &mut Return<()>) {
+	ret.put(arg_to_str(name).and_then(|name| unsafe { obj.register_transfer_buffer(&name, data, size) }));
+}
+
+#[no_mangle]
+pub extern fn wbx_unregister_transfer_buffer(obj: &mut ActivatedWaterboxHost, name: *const c_char, ret: &mut Return<()>) {
+	ret.put(arg_to_str(name).and_then(|name| obj.unregister_transfer_buffer(&name)));
+}
+
+/// How big a transfer buffer is, where the guest's last transfer into it ended, and how many it's had, for telling a
+/// buffer that was just filled from one that's left over from before.
+#[no_mangle]
+pub extern fn wbx_get_transfer_info(obj: &mut ActivatedWaterboxHost, name: *const c_char, ret: &mut Return<TransferInfo>) {
+	ret.put(arg_to_str(name).and_then(|name| obj.transfer_info(&name)));
+}
+
+/// Start or stop gathering this host's metrics, for farms of headless hosts that need to watch all of them:  Fault
+/// counts, memory use, the guest's syscalls, how long guest calls take, and state sizes and timings.  Metrics are off
+/// to start with, and turning them off keeps what's been gathered.
+#[no_mangle]
+pub extern fn wbx_set_metrics(obj: &mut ActivatedWaterboxHost, enabled: bool, ret: &mut Return<()>) {
+	obj.set_metrics(enabled);
+	ret.put(Ok(()));
+}
+
+/// Write out the metrics gathered so far in the Prometheus text format, ready to serve from a /metrics endpoint, with a
+/// `core` label of the module name.  Copies as much as fits in `len` bytes to `dest`, null terminated, and returns the
+/// whole length, not counting the terminator.
+#[no_mangle]
+pub extern fn wbx_get_metrics(obj: &mut ActivatedWaterboxHost, dest: *mut u8, len: usize, ret: &mut Return<usize>) {
+	let text = obj.metrics();
+	if len > 0 {
+		let n = std::cmp::min(text.len(), len - 1);
+		let dest = unsafe { std::slice::from_raw_parts_mut(dest, n + 1) };
+		dest[..n].copy_from_slice(&text.as_bytes()[..n]);
+		dest[n] = 0;
+	}
+	ret.put(Ok(text.len()));
+}
+
+/// Copy up to `len` entry point profiles into `dest`, and return how many there are in total.  Savestates have entry 1,
+/// and loadstates 2; everything else is the address of a guest function.
+#[no_mangle]
+pub extern fn wbx_get_profile(obj: &mut ActivatedWaterboxHost, dest: *mut EntryProfile, len: usize, ret: &mut Return<usize>) {
+	let entries = obj.profile();
+	let n = std::cmp::min(len, entries.len());
+	unsafe { std::slice::from_raw_parts_mut(dest, n) }.copy_from_slice(&entries[..n]);
+	ret.put(Ok(entries.len()));
+}
+
+/// Throw away the profiles gathered so far
+#[no_mangle]
+pub extern fn wbx_reset_profile(obj: &mut ActivatedWaterboxHost, ret: &mut Return<()>) {
+	obj.reset_profile();
+	ret.put(Ok(()));
+}
+
+/// Start or stop counting how often each page of guest memory is used, to find hot data to pack together and cold data
+/// to keep out of states.  Every wbx_call_guest is a sampling period, in which each page is counted at most once as
+/// accessed and once as written.  Catching the first access to each page costs a fault, so this slows the guest down
+/// a lot.  Stopping forgets the counts.  Off to start with, and can't be used in sanitizer mode.
+#[no_mangle]
+pub extern fn wbx_set_heat_map(obj: &mut ActivatedWaterboxHost, enabled: bool, ret: &mut Return<()>) {
+	ret.put(obj.set_heat_map(enabled));
+}
+
+/// Copy the counts for up to `len` pages into `dest`, starting with the first page of guest memory, and return where
+/// that is, how many pages there are in total, and how many sampling periods there have been.  Fails if the heat map
+/// is off.
+#[no_mangle]
+pub extern fn wbx_get_heat_map(obj: &mut ActivatedWaterboxHost, dest: *mut PageHeat, len: usize, ret: &mut Return<HeatMapInfo>) {
+	let res = match obj.heat_map() {
+		Some((info, pages)) => {
+			let n = std::cmp::min(len, pages.len());
+			unsafe { std::slice::from_raw_parts_mut(dest, n) }.copy_from_slice(&pages[..n]);
+			Ok(info)
+		},
+		None => Err(coded(ErrorCode::BadState, "The heat map is off")),
+	};
+	ret.put(res);
+}
+
+/// Zero the heat map's counts, keeping it on
+#[no_mangle]
+pub extern fn wbx_reset_heat_map(obj: &mut ActivatedWaterboxHost, ret: &mut Return<()>) {
+	obj.reset_heat_map();
+	ret.put(Ok(()));
+}
+
+/// Start checking guest memory for corruption in the background, for machines with bad RAM or an unstable overclock.
+/// A low priority thread hashes up to `pages_per_second` pages a second of the ones the guest can only read, and
+/// compares them with what they held when they became readonly; after each full pass, the page table is checked too,
+/// the next time the host is activated.  `callback` gets 1 and the page for a page that changed, or 2 and the page for
+/// bookkeeping that's wrong.  Page reports come from the checking thread, so the callback must not call any wbx_
+/// functions.  Starting again starts over.  Off to start with.
+#[no_mangle]
+pub extern fn wbx_start_integrity_check(obj: &mut ActivatedWaterboxHost, pages_per_second: usize, callback: IntegrityCallback, userdata: usize, ret: &mut Return<()>) {
+	ret.put(obj.start_integrity_check(pages_per_second, callback, userdata));
+}
+
+/// Stop the checker wbx_start_integrity_check started, waiting for its thread to finish
+#[no_mangle]
+pub extern fn wbx_stop_integrity_check(obj: &mut ActivatedWaterboxHost, ret: &mut Return<()>) {
+	obj.stop_integrity_check();
+	ret.put(Ok(()));
+}
+
+/// How many pages the checker is watching, how many passes it's made over them, and how many problems it's found
+#[no_mangle]
+pub extern fn wbx_get_integrity_info(obj: &mut ActivatedWaterboxHost, ret: &mut Return<IntegrityInfo>) {
+	ret.put(obj.integrity_info());
+}
+
+/// Place a marker that counts the pages of guest memory written from now on, for working out how much changes each
+/// frame without saving states.  Until it's removed, every page the guest writes for the first time after the marker
+/// costs a fault; pages written by the frontend, or by loading a state, aren't counted.  Up to 8 markers can be placed
+/// at once.  Returns the marker's id.  Can't be used in sanitizer mode.
+#[no_mangle]
+pub extern fn wbx_add_dirty_marker(obj: &mut ActivatedWaterboxHost, ret: &mut Return<u32>) {
+	ret.put(obj.add_dirty_marker());
+}
+
+/// Start a marker counting from zero again, as if it had just been placed
+#[no_mangle]
+pub extern fn wbx_reset_dirty_marker(obj: &mut ActivatedWaterboxHost, marker: u32, ret: &mut Return<()>) {
+	ret.put(obj.reset_dirty_marker(marker));
+}
+
+/// Stop a marker counting, and free up its id
+#[no_mangle]
+pub extern fn wbx_remove_dirty_marker(obj: &mut ActivatedWaterboxHost, marker: u32, ret: &mut Return<()>) {
+	ret.put(obj.remove_dirty_marker(marker));
+}
+
+/// Return how many pages have been written since a marker was placed or last reset
+#[no_mangle]
+pub extern fn wbx_get_dirty_page_count(obj: &mut ActivatedWaterboxHost, marker: u32, ret: &mut Return<usize>) {
+	ret.put(obj.dirty_page_count_since(marker));
+}
+
+/// Receives a text report
+pub type ReportCallback = extern fn(userdata: usize, text: *const c_char);
+
+/// Find the address of a function or data in the guest by name.  Unlike wbx_get_proc_addr, this finds any symbol the
+/// guest was built with, exported or not, and gives its address in guest memory, not something to call.  Only
+/// exported globals can be found in wasm guests.
+#[no_mangle]
+pub extern fn wbx_resolve_symbol(obj: &mut ActivatedWaterboxHost, name: *const c_char, ret: &mut Return<usize>) {
+	ret.put(arg_to_str(name).and_then(|name| obj.resolve_symbol(&name)));
+}
+
+/// Name the guest function or data at `addr`, as `symbol+offset`, followed by the module name if it's in one loaded
+/// with wbx_load_module.  Copies as much of the name as fits in `len` bytes to `dest`, null terminated, and returns the
+/// whole name's length, not counting the terminator.
+#[no_mangle]
+pub extern fn wbx_get_symbol_name(obj: &mut ActivatedWaterboxHost, addr: usize, dest: *mut u8, len: usize, ret: &mut Return<usize>) {
+	ret.put(obj.symbolize(addr).map(|name| {
+		if len > 0 {
+			let n = std::cmp::min(name.len(), len - 1);
+			let dest = unsafe { std::slice::from_raw_parts_mut(dest, n + 1) };
+			dest[..n].copy_from_slice(&name.as_bytes()[..n]);
+			dest[n] = 0;
+		}
+		name.len()
+	}));
+}
+
+/// Start or stop heap profiling, which charges every mapping the guest makes with brk, mmap and mremap to the guest
+/// backtrace that made it.  Stopping forgets everything recorded so far.
+#[no_mangle]
+pub extern fn wbx_set_heap_profiling(obj: &mut ActivatedWaterboxHost, enabled: bool, ret: &mut Return<()>) {
+	obj.set_heap_profiling(enabled);
+	ret.put(Ok(()));
+}
+
+/// Get a report of the `top` guest backtraces with the most memory still mapped, symbolized where possible.  The text
+/// is only valid during the callback.
+#[no_mangle]
+pub extern fn wbx_get_heap_report(obj: &mut ActivatedWaterboxHost, top: usize, callback: ReportCallback, userdata: usize, ret: &mut Return<()>) {
+	let text = CString::new(obj.heap_report(top)).unwrap_or_default();
+	callback(userdata, text.as_ptr());
+	ret.put(Ok(()));
+}
+
+/// Remember which guest memory is mapped, and where brk is, for wbx_report_heap_delta.  For checking that a core
+/// doesn't keep allocating from frame to frame.
+#[no_mangle]
+pub extern fn wbx_mark_heap_baseline(obj: &mut ActivatedWaterboxHost, ret: &mut Return<()>) {
+	obj.mark_heap_baseline();
+	ret.put(Ok(()));
+}
+
+/// Describe every region the guest mapped or unmapped since wbx_mark_heap_baseline, and how far brk moved, to
+/// `callback`, which may be null.  Returns how many bytes were newly mapped, not counting brk.
+#[no_mangle]
+pub extern fn wbx_report_heap_delta(obj: &mut ActivatedWaterboxHost, callback: Option<ReportCallback>, userdata: usize, ret: &mut Return<usize>) {
+	ret.put(obj.report_heap_delta().map(|delta| {
+		if let Some(callback) = callback {
+			let text = CString::new(delta.describe()).unwrap_or_default();
+			callback(userdata, text.as_ptr());
+		}
+		delta.mapped_bytes()
+	}));
+}
+
+/// Start recording every brk, mmap, mremap and munmap the guest makes, with what it asked for and what it got, so that
+/// another run of the same movie can check that its guest lays out memory the same way.  Loading an earlier state
+/// while recording drops what was recorded after it.
+#[no_mangle]
+pub extern fn wbx_start_heap_layout_recording(obj: &mut ActivatedWaterboxHost, ret: &mut Return<()>) {
+	obj.start_heap_layout_recording();
+	ret.put(Ok(()));
+}
+
+/// Stop recording the heap layout, and write what was recorded to `callback`
+#[no_mangle]
+pub extern fn wbx_stop_heap_layout_recording(obj: &mut ActivatedWaterboxHost, callback: WriteCallback, userdata: usize, ret: &mut Return<()>) {
+	let mut writer = CWriter {
+		userdata,
+		callback
+	};
+	ret.put(obj.stop_heap_layout_recording().and_then(|log| log.save(&mut writer)));
+}
+
+/// Start checking the guest's memory calls against a recording from wbx_stop_heap_layout_recording(), read from
+/// `callback`.  Calls are numbered from the start of the guest, and the count is in savestates, so checking can start
+/// from the beginning or from any state of the recorded run.
+#[no_mangle]
+pub extern fn wbx_start_heap_layout_check(obj: &mut ActivatedWaterboxHost, callback: ReadCallback, userdata: usize, ret: &mut Return<()>) {
+	let mut reader = CReader {
+		userdata,
+		callback
+	};
+	ret.put(heap_layout::HeapLog::load(&mut reader).and_then(|log| obj.start_heap_layout_check(log)));
+}
+
+/// Get how many of the guest's memory calls have been checked against the recording and matched.  Once one hasn't, this
+/// fails with Desync, and a message saying which call it was and how it differed.
+#[no_mangle]
+pub extern fn wbx_check_heap_layout(obj: &mut ActivatedWaterboxHost, ret: &mut Return<u64>) {
+	ret.put(obj.check_heap_layout());
+}
+
+/// Stop checking the heap layout, returning what wbx_check_heap_layout() would have
+#[no_mangle]
+pub extern fn wbx_stop_heap_layout_check(obj: &mut ActivatedWaterboxHost, ret: &mut Return<u64>) {
+	ret.put(obj.stop_heap_layout_check());
+}
+
+/// Get memory usage information for a host's guest memory.  See MemoryStats for what's reported.
+#[no_mangle]
+pub extern fn wbx_get_memory_stats(obj: &mut ActivatedWaterboxHost, ret: &mut Return<MemoryStats>) {
+	ret.put(Ok(obj.memory_stats()));
+}
+
+/// Get counters of what the memory system has done, across every host in the process, like how many faults it has taken
+/// and how many savestate bytes it has written.  They only go up, so compare two reads to see what happened in between.
+#[no_mangle]
+pub extern fn wbx_get_perf_counters(ret: &mut Return<PerfCounters>) {
+	ret.put(Ok(counters::read()));
+}
+
+/// Give back the host memory behind a host's guest pages that only hold zeroes, like malloc_trim() does for freed memory.
+/// Allocators often zero what they free, or leave it untouched, without unmapping it, so with the heap's high water
+/// mark behind them, long sessions can hold on to a lot of memory the guest isn't using.  Guest memory reads the same
+/// afterwards, and states are unaffected.  Returns how many bytes were given back.  Does nothing if the host's memory
+/// was committed up front, or off Linux.
+#[no_mangle]
+pub extern fn wbx_trim_memory(obj: &mut ActivatedWaterboxHost, ret: &mut Return<usize>) {
+	ret.put(Ok(obj.trim_memory()));
+}
+
+/// Give the guest a /tmp to make scratch files in, with room for `bytes` of them, or take it away with 0.  Scratch files
+/// are in savestates, but the quota isn't; files the guest already has are kept when it's lowered, but can't grow.
+#[no_mangle]
+pub extern fn wbx_set_tmp_quota(obj: &mut ActivatedWaterboxHost, bytes: usize, ret: &mut Return<()>) {
+	obj.set_tmp_quota(bytes);
+	ret.put(Ok(()));
+}
+
+/// How many bytes the guest's scratch files in /tmp take up
+#[no_mangle]
+pub extern fn wbx_get_tmp_used(obj: &mut ActivatedWaterboxHost, ret: &mut Return<usize>) {
+	ret.put(Ok(obj.tmp_used()));
+}
+
+/// Limit how many bytes of guest memory a host can have allocated at once, or remove the limit with 0.  Guest mmap,
+/// mremap and brk calls that would go over it fail with ENOMEM, as they would when out of memory for real, instead of
+/// taking the process down.  What's already allocated is kept.  The limit isn't part of savestates.
+#[no_mangle]
+pub extern fn wbx_set_memory_limit(obj: &mut ActivatedWaterboxHost, bytes: usize, ret: &mut Return<()>) {
+	obj.set_memory_limit(if bytes == 0 { None } else { Some(bytes) });
+	ret.put(Ok(()));
+}
+
+/// Keep all of a host's guest memory on NUMA node `node`, moving what's already elsewhere, for frontends that pin each
+/// host's thread to one node.  Pass a negative node to go back to the OS's usual placement.  The binding lasts for the
+/// life of the host.  Linux only.
+#[no_mangle]
+pub extern fn wbx_set_numa_node(obj: &mut ActivatedWaterboxHost, node: i32, ret: &mut Return<()>) {
+	ret.put(obj.set_numa_node(if node < 0 { None } else { Some(node as u32) }));
+}
+
+/// Report how many bytes of a host's guest memory are on each NUMA node:  `counts[n]` is set for every node `n` below
+/// `len`.  Returns one more than the highest node with any of it, which can be more than `len`.  Linux only.
+#[no_mangle]
+pub extern fn wbx_get_numa_usage(obj: &mut ActivatedWaterboxHost, counts: *mut usize, len: usize, ret: &mut Return<usize>) {
+	let res = (|| {
+		let usage = obj.numa_usage()?;
+		let dest = unsafe { std::slice::from_raw_parts_mut(counts, len) };
+		for (i, d) in dest.iter_mut().enumerate() {
+			*d = usage.get(i).cloned().unwrap_or(0);
+		}
+		Ok(usage.len())
+	})();
+	ret.put(res);
+}
+
+/// Control whether the host automatically evicts blocks from memory when they are not active.  For the best performance,
+/// this should be set to false.  Set to true to help catch dangling pointer issues.  Will be ignored (and forced to true)
+/// if waterboxhost was built in debug mode.  This is a single global setting.
+#[no_mangle]
+pub extern fn wbx_set_always_evict_blocks(_val: bool) {
+	#[cfg(not(debug_assertions))]
+	{
+		unsafe { ALWAYS_EVICT_BLOCKS = _val; }
+	}
+}
+
+/// Select the dirty tracking backend used by hosts created after this call.  This is a single global setting.
+/// 0: Write protection with a signal handler / vectored exception handler.  The default, works everywhere.
+/// 1: userfaultfd write protection, serviced on a dedicated thread.  Linux only; fails if the running kernel does not support it.
+/// 2: No write protection.  Every writable page is taken to be dirty as soon as it can be written, so states are bigger
+/// and snapshots take more memory, but guest writes never fault.
+#[no_mangle]
+pub extern fn wbx_set_dirty_tracking(backend: u32, ret: &mut Return<()>) {
+	let res = (|| {
+		let tracking = DirtyTracking::from_raw(backend)?;
+		if !tracking.available() {
+			return Err(coded(ErrorCode::Unsupported, format!("Dirty tracking backend {:?} is not available on this system", tracking)))
+		}
+		unsafe { DIRTY_TRACKING = tracking; }
+		Ok(())
+	})();
+	ret.put(res);
+}
+
+/// Control whether hosts created after this call ask for 2MiB huge pages to back large, fully allocated parts of guest
+/// memory.  Defaults to false.  This is only a hint:  On Linux, it needs transparent huge pages for shared memory to be
+/// enabled (/sys/kernel/mm/transparent_hugepage/shmem_enabled), and it does nothing on Windows, where large pages can't
+/// be write protected in pieces.  This is a single global setting.
+#[no_mangle]
+pub extern fn wbx_set_huge_pages(val: bool, ret: &mut Return<()>) {
+	unsafe { HUGE_PAGES = val; }
+	ret.put(Ok(()));
+}
+
+/// Control whether hosts created after this call are set up so that the host can run under AddressSanitizer or
+/// Valgrind, for hunting memory bugs in cores and in the host.  Defaults to false.  This uses dirty tracking 2 from
+/// wbx_set_dirty_tracking() no matter what that's set to, installs no fault handlers, refuses to map guest memory
+/// over anything that's already there (like ASan's shadow memory, which guests linked at the usual addresses collide
+/// with), and tells ASan that guest memory is fine to access after every guest call.  Without fault handlers, guest
+/// crashes go straight to the sanitizer, and watchpoints, breakpoints, stack overflow reports and abandoning aborted
+/// calls don't work.  This is a single global setting.
+#[no_mangle]
+pub extern fn wbx_set_sanitizer_mode(val: bool, ret: &mut Return<()>) {
+	unsafe { SANITIZER_MODE = val; }
+	ret.put(Ok(()));
+}
+
+/// Control whether hosts created after this call fill memory the guest maps with 0xCD, and memory it discards with
+/// MADV_DONTNEED with 0xDD, instead of zeros.  Defaults to false.  This is for shaking out guest code that reads memory
+/// it never wrote, and isn't for real use:  A libc whose calloc() trusts fresh mappings to be clear hands out junk with
+/// this on, and states and replays made with it on don't match ones made without it.  Memory the host maps for the
+/// guest, like the ELF's .bss, is still zeroed, and unmapped pages can't be read at all, so they're left alone.
+/// This is a single global setting.
+#[no_mangle]
+pub extern fn wbx_set_scribble_memory(val: bool, ret: &mut Return<()>) {
+	unsafe { SCRIBBLE = val; }
+	ret.put(Ok(()));
+}
+
+/// Control whether hosts created after this call commit memory for their whole address space up front.  Defaults to
+/// false, in which case the address space is only reserved, and memory is committed a page at a time as the guest uses
+/// it; a guest that uses more than the machine can give it then faults partway through.  With this, creating the host
+/// fails instead, and the memory stays committed until the host is freed.  Linux and Windows only; on macOS, host
+/// creation fails with this set.  This is a single global setting.
+#[no_mangle]
+pub extern fn wbx_set_commit_upfront(val: bool, ret: &mut Return<()>) {
+	unsafe { COMMIT_UPFRONT = val; }
+	ret.put(Ok(()));
+}
+
+/// Control whether hosts created after this call are wasm-only, with no executable memory, for environments that don't
+/// allow executable memory to be created.  Defaults to false.  Only wasm cores, which the host interprets, can be loaded
+/// then, and all guest memory is mapped without execute permission.  Native cores are refused; running them without
+/// executable memory would take an x86_64 interpreter, which the host doesn't have.  Guest requests for executable memory
+/// fail with EPERM.  This is a single global setting.
+#[no_mangle]
+pub extern fn wbx_set_wasm_no_exec(val: bool, ret: &mut Return<()>) {
+	unsafe { WASM_NO_EXEC = val; }
+	ret.put(Ok(()));
+}
+
+/// Set the environment variables that guests of hosts created after this call start with, as `count` strings of the form
+/// `NAME=value`.  Native guests find them in the block their entry point is passed (see startup.rs), where the waterbox
+/// crt picks them up as envp, and WASI guests get them from environ_get.  Defaults to none.  This is a single global setting.
+#[no_mangle]
+pub extern fn wbx_set_guest_environment(vars: *const *const c_char, count: usize, ret: &mut Return<()>) {
+	let vars = if count == 0 { &[][..] } else { unsafe { std::slice::from_raw_parts(vars, count) } };
+	let env = vars.iter().map(|&v| unsafe { CStr::from_ptr(v) }.to_owned()).collect::<Vec<_>>();
+	ret.put(startup::set_environment(env));
+}
+
+/// Set the arguments that guests of hosts created after this call start with, as `count` strings, argv[0] included.  Native
+/// guests find them in the block their entry point is passed (see startup.rs), where the waterbox crt picks them up as
+/// argc and argv for main(), and WASI guests get them from args_get.  Defaults to none.  This is a single global setting.
+#[no_mangle]
+pub extern fn wbx_set_guest_arguments(args: *const *const c_char, count: usize, ret: &mut Return<()>) {
+	let args = if count == 0 { &[][..] } else { unsafe { std::slice::from_raw_parts(args, count) } };
+	startup::set_arguments(args.iter().map(|&v| unsafe { CStr::from_ptr(v) }.to_owned()).collect());
+	ret.put(Ok(()));
+}
+
+/// Released snapshot pages are kept around to be reused, since allocating them one at a time is slow.  This returns
+/// all of the ones not currently in use to the OS.  Returns the number of bytes released.  The pool is shared by all hosts.
+#[no_mangle]
+pub extern fn wbx_trim_page_pool(ret: &mut Return<usize>) {
+	ret.put(Ok(memory_block::trim_page_pool()));
+}
+
+/// Control whether hosts created after this call place mmap allocations at random, instead of packing them together.
+/// Meant for shaking out hidden pointer assumptions in cores.  Placement is entirely determined by `seed`, so runs stay
+/// reproducible, and savestates only work with hosts that were created with the same setting.  This is a single global setting.
+#[no_mangle]
+pub extern fn wbx_set_mmap_randomization(enabled: bool, seed: u64, ret: &mut Return<()>) {
+	unsafe { MMAP_SEED = if enabled { Some(seed) } else { None }; }
+	ret.put(Ok(()));
+}
+
+/// Reseed the generator that serves the guest's getrandom() calls and reads of /dev/random and /dev/urandom.  Guests
+/// get the same bytes for the same seed, so runs stay deterministic.  The generator's state is included in savestates.
+/// The default seed is 0.
+#[no_mangle]
+pub extern fn wbx_set_random_seed(obj: &mut ActivatedWaterboxHost, seed: u64, ret: &mut Return<()>) {
+	obj.set_random_seed(seed);
+	ret.put(Ok(()));
+}
+
+/// Start the stream of randomness the core gets from __wbx_entropy() over from the `len` bytes at `seed`, which can be
+/// none.  The same seed always gives the same bytes, and where the stream is is included in savestates.  Frontends can
+/// reseed it every frame, from the movie's seed and the frame number, say, so that nothing before can make it drift.
+#[no_mangle]
+pub extern fn wbx_seed_entropy(obj: &mut ActivatedWaterboxHost, seed: *const u8, len: usize, ret: &mut Return<()>) {
+	let seed = if len == 0 { &[][..] } else { unsafe { std::slice::from_raw_parts(seed, len) } };
+	obj.seed_entropy(seed);
+	ret.put(Ok(()));
+}
+
+/// Make the guest memory from `start` to `start + size`, which has to be whole pages and mapped readonly, immutable:
+/// The guest can never change, remap or unmap it again, and it's passed over entirely by dirty tracking and everything
+/// that saves, loads or compares states, which saves time for cores whose guest memory is mostly ROM.  Can't be undone.
+/// Must be done before wbx_seal, and the same way every time, since states only load into hosts that made the same
+/// memory immutable.
+#[no_mangle]
+pub extern fn wbx_mark_immutable(obj: &mut ActivatedWaterboxHost, start: usize, size: usize, ret: &mut Return<()>) {
+	ret.put(obj.mark_immutable(AddressRange { start, size }));
+}
+
+/// Guard the ROM image the core has loaded into the guest memory from `start` to `start + size`, which has to be whole
+/// pages:  It's made readonly, and the guest can't change that, remap it or unmap it.  `hash` is the SHA-256 (32 bytes)
+/// it's meant to have, or null to take the one it has now; with one it doesn't have, this fails with Corrupted.  The
+/// hash is checked again on every activation, where a mismatch is logged, and after every state load, which fails with
+/// Corrupted.  ROM regions aren't in savestates.
+#[no_mangle]
+pub extern fn wbx_register_rom(obj: &mut ActivatedWaterboxHost, start: usize, size: usize, hash: *const u8, ret: &mut Return<()>) {
+	let expected = if hash.is_null() { None } else { Some(unsafe { std::slice::from_raw_parts(hash, 32) }) };
+	ret.put(obj.register_rom(AddressRange { start, size }, expected));
+}
+
+/// Stop guarding the ROM region registered at `start`, which the guest can then write to again
+#[no_mangle]
+pub extern fn wbx_unregister_rom(obj: &mut ActivatedWaterboxHost, start: usize, ret: &mut Return<()>) {
+	ret.put(obj.unregister_rom(start));
+}
+
+/// Check every ROM region against its hash now, failing with Corrupted if one has changed
+#[no_mangle]
+pub extern fn wbx_verify_roms(obj: &mut ActivatedWaterboxHost, ret: &mut Return<()>) {
+	ret.put(obj.verify_roms());
+}
+
+/// How guest sockets reach the frontend.  Every callback gets `userdata`, and any that returns a number can return
+/// -errno to fail, such as -EAGAIN (-11) from `recv` when there's nothing to receive yet; the guest can't wait for it.
+/// `connect` is given an address like "inet:127.0.0.1:8080", "inet6:[::1]:80", "unix:/path", or "unix:@abstract", and
+/// returns a handle for the connection, which the other callbacks are given.  `ready` says whether `recv` would have
+/// something, for the guest's poll(); if null, sockets are only ever ready to send.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct SocketCallbacks {
+	pub userdata: usize,
+	pub connect: extern fn(userdata: usize, addr: *const c_char) -> i64,
+	pub send: extern fn(userdata: usize, handle: u64, data: *const u8, size: usize) -> isize,
+	pub recv: extern fn(userdata: usize, handle: u64, data: *mut u8, size: usize) -> isize,
+	pub ready: Option<extern fn(userdata: usize, handle: u64) -> bool>,
+	pub close: extern fn(userdata: usize, handle: u64),
+}
+struct CSocketHost {
+	c: SocketCallbacks,
+}
+fn socket_ret(res: isize) -> Result<usize, SyscallError> {
+	if res < 0 {
+		Err(SyscallError(-res as i32))
+	} else {
+		Ok(res as usize)
+	}
+}
+impl fs::SocketHost for CSocketHost {
+	fn connect(&mut self, addr: &str) -> Result<u64, SyscallError> {
+		let addr = CString::new(addr).map_err(|_| EINVAL)?;
+		socket_ret((self.c.connect)(self.c.userdata, addr.as_ptr()) as isize).map(|h| h as u64)
+	}
+	fn send(&mut self, handle: u64, buf: &[u8]) -> Result<usize, SyscallError> {
+		socket_ret((self.c.send)(self.c.userdata, handle, buf.as_ptr(), buf.len()))
+	}
+	fn recv(&mut self, handle: u64, buf: &mut [u8]) -> Result<usize, SyscallError> {
+		socket_ret((self.c.recv)(self.c.userdata, handle, buf.as_mut_ptr(), buf.len()))
+	}
+	fn ready(&mut self, handle: u64) -> bool {
+		match self.c.ready {
+			Some(ready) => ready(self.c.userdata, handle),
+			None => false,
+		}
+	}
+	fn close(&mut self, handle: u64) {
+		(self.c.close)(self.c.userdata, handle)
+	}
+}
+
+/// Let the guest make sockets, which connect through `callbacks` instead of any real network.  Pass null to stop new
+/// sockets from being made; ones that are already open keep their callbacks.  Sockets can't be open in savestates.
+#[no_mangle]
+pub extern fn wbx_set_socket_callbacks(obj: &mut ActivatedWaterboxHost, callbacks: Option<&SocketCallbacks>, ret: &mut Return<()>) {
+	obj.set_socket_host(callbacks.map(|&c| Box::new(CSocketHost { c }) as Box<dyn fs::SocketHost>));
+	ret.put(Ok(()));
+}
+
+/// Move the guest's clocks forward by `nanoseconds`.  The guest's time only passes when this is called, typically once per
+/// frame, so clock_gettime() and friends are deterministic.  The clocks are included in savestates.
+#[no_mangle]
+pub extern fn wbx_advance_clock(obj: &mut ActivatedWaterboxHost, nanoseconds: u64, ret: &mut Return<()>) {
+	obj.advance_clock(nanoseconds);
+	ret.put(Ok(()));
+}
+
+/// Set what the guest's wall clock says, in seconds since the epoch, such as for a core's RTC.  Monotonic clocks are
+/// unaffected.  The default is a fixed time in 2017.
+#[no_mangle]
+pub extern fn wbx_set_clock_realtime(obj: &mut ActivatedWaterboxHost, seconds: i64, ret: &mut Return<()>) {
+	obj.set_clock_realtime(seconds);
+	ret.put(Ok(()));
+}
+
+/// Get what the guest's wall clock says, in seconds since the epoch.  The guest can set it too, like a game setting its
+/// RTC, so a frontend that keeps SaveRAM should keep this with it and set it back with wbx_set_clock_realtime(), after
+/// advancing by however long it's been if the RTC should keep running while the game's off.
+#[no_mangle]
+pub extern fn wbx_get_clock_realtime(obj: &mut ActivatedWaterboxHost, ret: &mut Return<i64>) {
+	ret.put(Ok(obj.clock_realtime()));
+}
+
+/// Limit how long wbx_call_guest() lets a call run, in milliseconds, or 0 for no limit.  A call that runs over is
+/// abandoned, leaving the guest in whatever state it was in, so the frontend should load a savestate or throw it away.
+#[no_mangle]
+pub extern fn wbx_set_watchdog(obj: &mut ActivatedWaterboxHost, milliseconds: u64, ret: &mut Return<()>) {
+	obj.set_watchdog(if milliseconds == 0 { None } else { Some(std::time::Duration::from_millis(milliseconds)) });
+	ret.put(Ok(()));
+}
+
+/// Call the guest function at `func`, such as one from wbx_get_proc_addr(), with the six integer arguments in `args`, and
+/// return what it returns.  Unlike calling it directly, this fails instead of hanging if the watchdog runs out.
+#[no_mangle]
+pub extern fn wbx_call_guest(obj: &mut ActivatedWaterboxHost, func: usize, args: &[usize; 6], ret: &mut Return<usize>) {
+	ret.put(obj.call_guest(func, args));
+}
+
+/// Cancel the wbx_call_guest() in progress on `obj`, or the next one if none is, when the guest next makes a syscall.
+/// That call fails, and like after the watchdog runs out, the guest's state has to be thrown away.  This can be
+/// called from any thread.
+#[no_mangle]
+pub extern fn wbx_request_cancel(obj: &WaterboxHost, ret: &mut Return<()>) {
+	obj.request_cancel();
+	ret.put(Ok(()));
+}
+
+/// Start recording everything that goes into the guest, from the state it's in now:  wbx_call_guest() calls and what
+/// they return, wbx_write_memory(), clock, seed and config changes, and what the socket callbacks answer.  Files can't
+/// be mounted or unmounted, and states can't be loaded, until the recording stops.  Must be sealed.
+#[no_mangle]
+pub extern fn wbx_start_recording(obj: &mut ActivatedWaterboxHost, ret: &mut Return<()>) {
+	ret.put(obj.start_recording());
+}
+
+/// Record the end of a frame, with a hash of guest memory for wbx_replay() to check
+#[no_mangle]
+pub extern fn wbx_mark_frame(obj: &mut ActivatedWaterboxHost, ret: &mut Return<()>) {
+	ret.put(obj.mark_frame());
+}
+
+/// Stop recording, and write what was recorded to `callback`
+#[no_mangle]
+pub extern fn wbx_stop_recording(obj: &mut ActivatedWaterboxHost, callback: WriteCallback, userdata: usize, ret: &mut Return<()>) {
+	let mut writer = CWriter {
+		userdata,
+		callback
+	};
+	ret.put(obj.stop_recording().and_then(|log| log.save(&mut writer)));
+}
+
+/// Play back a recording from wbx_stop_recording(), read from `callback`, on a host already loaded to the state it was
+/// recorded from.  Returns the number of frames replayed.  If the guest does anything differently, this fails with
+/// Desync and a message saying where, with the guest left there to be looked at.
+#[no_mangle]
+pub extern fn wbx_replay(obj: &mut ActivatedWaterboxHost, callback: ReadCallback, userdata: usize, ret: &mut Return<u64>) {
+	let mut reader = CReader {
+		userdata,
+		callback
+	};
+	ret.put(replay::Log::load(&mut reader).and_then(|log| obj.replay(log)));
+}
+
+/// Told about a guest syscall after it completes.  `args` points to all six argument registers, whether the syscall
+/// uses them or not, and `ret` is the raw return value, with errors as -errno.  `text` is a description of the call in
+/// the style of strace.  Both pointers are only valid during the callback.
+pub type SyscallTraceCallback = extern fn(userdata: usize, nr: usize, args: *const usize, ret: usize, text: *const c_char);
+
+/// Call `callback` after every syscall the guest makes, for debugging.  Pass a null callback to stop.
+#[no_mangle]
+pub extern fn wbx_set_syscall_trace(obj: &mut ActivatedWaterboxHost, callback: Option<SyscallTraceCallback>, userdata: usize, ret: &mut Return<()>) {
+	obj.set_syscall_trace(callback.map(|c| (c, userdata)));
+	ret.put(Ok(()));
+}
+
+/// Told that the guest called sched_yield(), with `ns` 0, or slept for `ns`, likely because it's spinning while it waits
+/// for something.  The guest's clock doesn't move and the sleep returns right away either way, so a frontend can sleep or
+/// yield its own thread here instead of letting the guest spin on a host CPU.  `others_runnable` is whether another
+/// guest thread can run next, which it does right after, in which case there's not much to wait for.
+pub type YieldCallback = extern fn(userdata: usize, ns: u64, others_runnable: bool);
+
+/// Call `callback` whenever the guest yields or sleeps.  Pass a null callback to stop.
+#[no_mangle]
+pub extern fn wbx_set_yield_callback(obj: &mut ActivatedWaterboxHost, callback: Option<YieldCallback>, userdata: usize, ret: &mut Return<()>) {
+	obj.set_yield_callback(callback.map(|c| (c, userdata)));
+	ret.put(Ok(()));
+}
+
+/// Answers a guest syscall that the policy traps, with what it returns:  the raw value, with errors as -errno.  `args`
+/// points to all six argument registers, and is only valid during the callback.  Guest pointers in them haven't been
+/// checked.
+pub type SyscallTrapCallback = extern fn(userdata: usize, nr: usize, args: *const usize) -> usize;
+
+/// Set what happens when the guest makes syscall `nr`, numbered as on x86_64 whatever the guest's ABI.
+/// 0: Allow, so the host handles it as usual.  The default.
+/// 1: Deny, failing it with `errno`.
+/// 2: Trap, having the trap callback answer it, or failing it with ENOSYS if there is none.
+/// Nothing about the call is looked at first, not even its arguments.
+#[no_mangle]
+pub extern fn wbx_set_syscall_action(obj: &mut ActivatedWaterboxHost, nr: usize, action: u32, errno: i32, ret: &mut Return<()>) {
+	let res = (|| {
+		obj.set_syscall_action(nr, SyscallAction::from_raw(action, errno)?);
+		Ok(())
+	})();
+	ret.put(res);
+}
+
+/// Set what answers syscalls that wbx_set_syscall_action() traps.  Pass a null callback to stop.
+#[no_mangle]
+pub extern fn wbx_set_syscall_trap_callback(obj: &mut ActivatedWaterboxHost, callback: Option<SyscallTrapCallback>, userdata: usize, ret: &mut Return<()>) {
+	obj.set_syscall_trap_callback(callback.map(|c| (c, userdata)));
+	ret.put(Ok(()));
+}
+
+/// Gets each message the host logs, at a logging::Level:  1 error, 2 warning, 3 info, 4 debug.  Called from whichever
+/// thread logged, so it must be thread safe, and it must not call back into waterbox.
+pub type LogCallback = extern fn(userdata: usize, level: logging::Level, message: *const c_char);
+
+/// Send the host's log messages, like warnings about what guests do and which modules are loaded, to a callback instead
+/// of the console, or back to the console with a null callback.  Only messages at `max_level` and more important ones
+/// are sent; the default is 3, info.  0 silences everything.  These are single global settings for all hosts.
+#[no_mangle]
+pub extern fn wbx_set_log_callback(callback: Option<LogCallback>, userdata: usize, max_level: i32, ret: &mut Return<()>) {
+	logging::set_callback(callback.map(|cb| (cb, userdata)));
+	logging::set_max_level(max_level);
+	ret.put(Ok(()));
+}
+
+/// Gets a line the guest wrote to stdout (`fd` 1) or stderr (2), without its newline, and with the core's name in
+/// brackets in front.  It must not call back into waterbox.
+pub type GuestOutputCallback = extern fn(userdata: usize, fd: i32, line: *const c_char);
+
+/// Send what the guest writes to stdout and stderr, like a core's debug logging, to a callback a line at a time,
+/// instead of the host's own console, or back to the console with a null callback.  A line that hasn't ended yet goes
+/// out when the host is deactivated, which frontends do every frame, or with wbx_flush_guest_output.
+#[no_mangle]
+pub extern fn wbx_set_guest_output_callback(obj: &mut ActivatedWaterboxHost, callback: Option<GuestOutputCallback>, userdata: usize, ret: &mut Return<()>) {
+	obj.set_output_callback(callback.map(|cb| (cb, userdata)));
+	ret.put(Ok(()));
+}
+
+/// Pass on any guest output that's waiting for the end of its line
+#[no_mangle]
+pub extern fn wbx_flush_guest_output(obj: &mut ActivatedWaterboxHost, ret: &mut Return<()>) {
+	obj.flush_output();
+	ret.put(Ok(()));
+}
+
+/// Gets a description of an unrecoverable fault in guest code, with a symbolized backtrace.  `addr` is the address the
+/// faulting instruction was accessing.
+pub type CrashCallback = extern fn(userdata: usize, rip: usize, addr: usize, text: *const c_char);
+
+/// Kinds of GuestTrapCallback traps:  int3, and ud2 or any other instruction the CPU won't run
+pub const GUEST_TRAP_BREAKPOINT: u32 = 1;
+pub const GUEST_TRAP_ILLEGAL: u32 = 2;
+/// What a GuestTrapCallback can have happen next:  Go on from the registers as they are now, which with rip left
+/// alone runs the trapping instruction again; go on after the trapping instruction, which only works for int3 and ud2;
+/// or abandon the call, as if there were no callback
+pub const GUEST_TRAP_RESUME: u32 = 0;
+pub const GUEST_TRAP_SKIP: u32 = 1;
+pub const GUEST_TRAP_ABORT: u32 = 2;
+
+/// Gets a trap in guest code at `addr`, with the trapping thread's registers, which it can change.  rip starts out as
+/// `addr`.  Returns GUEST_TRAP_RESUME, GUEST_TRAP_SKIP or GUEST_TRAP_ABORT.
+pub type GuestTrapCallback = extern fn(userdata: usize, kind: u32, addr: usize, regs: &mut UserRegs) -> u32;
+
+/// Have `callback` decide what happens when guest code hits int3 or an illegal instruction, for cores with debuggers
+/// of their own, instead of the call it's in being abandoned.  The callback runs inside the trap handler:  It can look
+/// at and change guest memory directly, as the host is active, but must not call back into waterbox.  gdb, when it's
+/// attached, gets traps first.  Pass a null callback to stop.  Only on x86_64.
+#[no_mangle]
+pub extern fn wbx_set_guest_trap_callback(obj: &mut ActivatedWaterboxHost, callback: Option<GuestTrapCallback>, userdata: usize, ret: &mut Return<()>) {
+	obj.set_guest_trap_callback(callback.map(|c| (c, userdata)));
+	ret.put(Ok(()));
+}
+
+/// Call `callback` when guest code faults and nothing can handle it, just before the fault goes on to crash the process
+/// (or to whatever else wants it.)  The callback runs inside the fault handler, so it should do little more than log
+/// the report, and must not call back into waterbox.  Pass a null callback to stop.
+#[no_mangle]
+pub extern fn wbx_set_crash_callback(obj: &mut ActivatedWaterboxHost, callback: Option<CrashCallback>, userdata: usize, ret: &mut Return<()>) {
+	obj.set_crash_callback(callback.map(|c| (c, userdata)));
+	ret.put(Ok(()));
+}
+
+/// Write an ELF core file of the guest, with its memory, the faulting registers, and where everything was loaded, to
+/// `path` whenever guest code faults and nothing can handle it.  Load the core in gdb along with the guest executable.
+/// Pass a null path to stop.
+#[no_mangle]
+pub extern fn wbx_set_core_dump_path(obj: &mut ActivatedWaterboxHost, path: *const c_char, ret: &mut Return<()>) {
+	let res = (|| {
+		let path = if path.is_null() { None } else { Some(arg_to_str(path)?) };
+		obj.set_core_dump_path(path)
+	})();
+	ret.put(res);
+}
+
+/// Map a read only view of a range of guest memory, such as a framebuffer, somewhere in host memory.  The view always
+/// reflects the guest's memory without any copying, and stays valid until wbx_unmap_host_view, even if the host is
+/// deactivated.  Returns the host address of `start`.
+#[no_mangle]
+pub extern fn wbx_map_host_view(obj: &mut ActivatedWaterboxHost, start: usize, size: usize, ret: &mut Return<usize>) {
+	ret.put(obj.map_host_view(AddressRange { start, size }));
+}
+
+/// Copy `len` bytes of guest memory starting at `addr` into `buf`.  Unlike reading through a pointer, a bad address
+/// can't crash the process:  copying stops at the first page the guest can't read, and the number of bytes that were
+/// copied is returned.  Watchpoints aren't tripped.
+#[no_mangle]
+pub extern fn wbx_read_memory(obj: &mut ActivatedWaterboxHost, addr: usize, buf: *mut u8, len: usize, ret: &mut Return<usize>) {
+	let dest = unsafe { std::slice::from_raw_parts_mut(buf, len) };
+	ret.put(Ok(obj.read_memory(addr, dest)));
+}
+
+/// Copy `len` bytes from `buf` into guest memory starting at `addr`.  Copying stops at the first page the guest can't
+/// write, and the number of bytes that were copied is returned.  Written memory goes in savestates like anything else
+/// the guest changed.
+#[no_mangle]
+pub extern fn wbx_write_memory(obj: &mut ActivatedWaterboxHost, addr: usize, buf: *const u8, len: usize, ret: &mut Return<usize>) {
+	let src = unsafe { std::slice::from_raw_parts(buf, len) };
+	ret.put(Ok(obj.write_memory(addr, src)));
+}
+
+/// Free a view made by wbx_map_host_view.  `ptr` and `size` are what was passed to and returned from it.
+#[no_mangle]
+pub extern fn wbx_unmap_host_view(ptr: usize, size: usize, ret: &mut Return<()>) {
+	ret.put(unsafe { memory_block::unmap_host_view(AddressRange { start: ptr, size }.align_expand()) });
+}
+
+/// Leave the guest memory from `start` to `start + size` out of savestates, or put it back in if `transient` is false.
+/// For things the core rebuilds by itself, like framebuffers and decode caches; after a state is loaded, the memory
+/// reads as zero.  Can be done at any time.
+#[no_mangle]
+pub extern fn wbx_set_transient(obj: &mut ActivatedWaterboxHost, start: usize, size: usize, transient: bool, ret: &mut Return<()>) {
+	ret.put(obj.set_transient(AddressRange { start, size }, transient));
+}
+
+/// Get the OS handle (a file descriptor on Linux) of the shared memory object that backs all of this host's guest
+/// memory, so that other processes can map views of it.  Offset 0 of the object is the start of the guest's address
+/// space.  Nothing may write to it except the guest.
+#[no_mangle]
+pub extern fn wbx_get_memory_handle(obj: &mut ActivatedWaterboxHost, ret: &mut Return<usize>) {
+	ret.put(Ok(obj.memory_handle()));
+}
+
+/// Start a gdb remote protocol server on localhost:`port`, for debugging guest code at the instruction level.  gdb can
+/// read and write registers and guest memory, set breakpoints inside guest memory, and single step.  When gdb connects
+/// or interrupts, the guest stops at its next syscall, inside the host.  Breakpoints don't change guest memory, so
+/// they don't show up in savestates.  Only available on Linux, and only one server per process.
+#[no_mangle]
+pub extern fn wbx_start_gdb_server(port: u16, ret: &mut Return<()>) {
+	ret.put(gdbstub::start(port));
+}
+
+/// Told about a guest request for writable and executable memory.  `addr` is 0 for mmap requests with no address hint.
+/// `rip` is the guest code that made the syscall.
+pub type WxViolationCallback = extern fn(userdata: usize, addr: usize, size: usize, rip: usize);
+
+/// Set the policy for guest mmap and mprotect requests for memory that is both writable and executable.
+/// 0: Allow.  The default.
+/// 1: Deny, failing the request with EPERM.
+/// 2: Allow, but report it.
+/// If a callback is given, it is called for every such request under policies 1 and 2.  Memory mapped by the loader
+/// is not affected.
+#[no_mangle]
+pub extern fn wbx_set_wx_policy(obj: &mut ActivatedWaterboxHost, policy: u32, callback: Option<WxViolationCallback>, userdata: usize, ret: &mut Return<()>) {
+	let res = (|| {
+		obj.set_wx_policy(WxPolicy::from_raw(policy)?, callback.map(|c| (c, userdata)));
+		Ok(())
+	})();
+	ret.put(res);
+}
+
+/// Watch a range of guest memory.  `kind` is 1 for reads, 2 for writes, or 3 for both.  Every access of that kind
+/// that touches the range calls `callback` before the access completes, with the watchpoint's id, the accessed address,
+/// whether it was a write, and the address of the accessing instruction.  The callback runs inside the host's fault
+/// handler on the accessing thread, so it must not call any wbx_ functions.  Returns the watchpoint's id.
+#[no_mangle]
+pub extern fn wbx_add_watchpoint(obj: &mut ActivatedWaterboxHost, start: usize, size: usize, kind: u32, callback: WatchCallback, userdata: usize, ret: &mut Return<u32>) {
+	let kind = (if kind & 1 != 0 { WATCH_READ } else { 0 }) | (if kind & 2 != 0 { WATCH_WRITE } else { 0 });
+	ret.put(obj.add_watchpoint(AddressRange { start, size }, kind, callback, userdata));
+}
+
+/// Remove a watchpoint added with wbx_add_watchpoint
+#[no_mangle]
+pub extern fn wbx_remove_watchpoint(obj: &mut ActivatedWaterboxHost, id: u32, ret: &mut Return<()>) {
+	ret.put(obj.remove_watchpoint(id));
 }
@@ -0,0 +1,133 @@
+// The guest's idea of time.  Nothing about it comes from the real clock:  it only moves when the frontend advances it,
+// usually once a frame, so time based code in the guest stays deterministic and its state goes in savestates.  The wall
+// clock is also what a core's RTC chip should read:  the guest can set it, like a game setting its clock, and the
+// frontend can read it back to keep with SaveRAM, so the RTC picks up where it was the next time the game starts.
+use crate::*;
+use crate::syscall_defs::*;
+
+const CLOCK_REALTIME: usize = 0;
+const CLOCK_MONOTONIC: usize = 1;
+const CLOCK_PROCESS_CPUTIME_ID: usize = 2;
+const CLOCK_THREAD_CPUTIME_ID: usize = 3;
+const CLOCK_MONOTONIC_RAW: usize = 4;
+const CLOCK_REALTIME_COARSE: usize = 5;
+const CLOCK_MONOTONIC_COARSE: usize = 6;
+const CLOCK_BOOTTIME: usize = 7;
+const CLOCK_TAI: usize = 11;
+
+const NS_PER_SEC: u64 = 1000000000;
+
+/// What the guest's wall clock says before the frontend sets it
+const DEFAULT_REALTIME: i64 = 1495889068;
+
+pub struct Clock {
+	/// Wall clock time when elapsed was 0, in seconds since the epoch
+	realtime_base: i64,
+	/// How long the guest has been running, according to the frontend
+	elapsed_ns: u64,
+}
+impl Clock {
+	pub fn new() -> Clock {
+		Clock {
+			realtime_base: DEFAULT_REALTIME,
+			elapsed_ns: 0,
+		}
+	}
+	pub fn advance(&mut self, ns: u64) {
+		self.elapsed_ns = self.elapsed_ns.wrapping_add(ns);
+	}
+	/// Set the wall clock to `secs` since the epoch, without changing the monotonic clocks
+	pub fn set_realtime(&mut self, secs: i64) {
+		self.realtime_base = secs - (self.elapsed_ns / NS_PER_SEC) as i64;
+	}
+	/// What the wall clock says, in seconds since the epoch
+	pub fn realtime(&self) -> i64 {
+		self.realtime_base + (self.elapsed_ns / NS_PER_SEC) as i64
+	}
+	/// Implements clock_settime(2), to the second, as it's the same clock the frontend sets.  Only the wall clock can be
+	/// set.
+	pub fn set(&mut self, clock: usize, time: TimeSpec) -> Result<(), SyscallError> {
+		match clock {
+			CLOCK_REALTIME if time.tv_nsec >= 0 && (time.tv_nsec as u64) < NS_PER_SEC && time.tv_sec >= 0 => {
+				self.set_realtime(time.tv_sec);
+				Ok(())
+			},
+			_ => Err(EINVAL)
+		}
+	}
+	/// How long nanosleep(2) or clock_nanosleep(2) asked to wait on `clock`, given until when if `absolute`.  Time
+	/// doesn't pass in a syscall, so the wait is only ever a hint.
+	pub fn sleep_ns(&self, clock: usize, absolute: bool, time: &TimeSpec) -> Result<u64, SyscallError> {
+		if time.tv_nsec < 0 || time.tv_nsec as u64 >= NS_PER_SEC || time.tv_sec < 0 {
+			return Err(EINVAL)
+		}
+		let ns = |t: &TimeSpec| (t.tv_sec as i128) * NS_PER_SEC as i128 + t.tv_nsec as i128;
+		let now = self.now(clock)?;
+		let wait = if absolute { ns(time) - ns(&now) } else { ns(time) };
+		Ok(wait.max(0).min(u64::MAX as i128) as u64)
+	}
+	/// Implements clock_gettime(2)
+	pub fn now(&self, clock: usize) -> Result<TimeSpec, SyscallError> {
+		let sec = (self.elapsed_ns / NS_PER_SEC) as i64;
+		let nsec = (self.elapsed_ns % NS_PER_SEC) as i64;
+		match clock {
+			CLOCK_REALTIME | CLOCK_REALTIME_COARSE | CLOCK_TAI => Ok(TimeSpec { tv_sec: self.realtime_base + sec, tv_nsec: nsec }),
+			CLOCK_MONOTONIC | CLOCK_MONOTONIC_RAW | CLOCK_MONOTONIC_COARSE | CLOCK_BOOTTIME
+				// the guest is always running, as far as it can tell
+				| CLOCK_PROCESS_CPUTIME_ID | CLOCK_THREAD_CPUTIME_ID => Ok(TimeSpec { tv_sec: sec, tv_nsec: nsec }),
+			_ => Err(EINVAL)
+		}
+	}
+}
+impl IStateable for Clock {
+	fn save_state(&mut self, stream: &mut dyn Write) -> anyhow::Result<()> {
+		bin::write_magic(stream, "Clock")?;
+		bin::write(stream, &self.realtime_base)?;
+		bin::write(stream, &self.elapsed_ns)?;
+		Ok(())
+	}
+	fn load_state(&mut self, stream: &mut dyn Read) -> anyhow::Result<()> {
+		bin::verify_magic(stream, "Clock")?;
+		bin::read(stream, &mut self.realtime_base)?;
+		bin::read(stream, &mut self.elapsed_ns)?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_clock() -> anyhow::Result<()> {
+		let mut c = Clock::new();
+		assert_eq!(c.now(CLOCK_MONOTONIC)?.tv_sec, 0);
+		assert_eq!(c.now(CLOCK_REALTIME)?.tv_sec, DEFAULT_REALTIME);
+		c.advance(NS_PER_SEC * 3 / 2);
+		let t = c.now(CLOCK_MONOTONIC)?;
+		assert_eq!((t.tv_sec, t.tv_nsec), (1, 500000000));
+		c.set_realtime(1000);
+		let t = c.now(CLOCK_REALTIME)?;
+		assert_eq!((t.tv_sec, t.tv_nsec), (1000, 500000000));
+		assert_eq!(c.now(CLOCK_MONOTONIC)?.tv_sec, 1);
+		assert!(c.now(99).is_err());
+		// a game setting its RTC
+		c.set(CLOCK_REALTIME, TimeSpec { tv_sec: 2000, tv_nsec: 0 })?;
+		c.advance(NS_PER_SEC);
+		assert_eq!(c.realtime(), 2001);
+		assert_eq!(c.set(CLOCK_MONOTONIC, TimeSpec { tv_sec: 0, tv_nsec: 0 }), Err(EINVAL));
+		assert_eq!(c.set(CLOCK_REALTIME, TimeSpec { tv_sec: 0, tv_nsec: -1 }), Err(EINVAL));
+		c.set_realtime(1001);
+		assert_eq!(c.sleep_ns(CLOCK_MONOTONIC, false, &TimeSpec { tv_sec: 0, tv_nsec: 500 })?, 500);
+		assert_eq!(c.sleep_ns(CLOCK_MONOTONIC, true, &TimeSpec { tv_sec: 3, tv_nsec: 0 })?, NS_PER_SEC / 2);
+		assert_eq!(c.sleep_ns(CLOCK_REALTIME, true, &TimeSpec { tv_sec: 5, tv_nsec: 0 })?, 0);
+		assert!(c.sleep_ns(CLOCK_MONOTONIC, false, &TimeSpec { tv_sec: 0, tv_nsec: NS_PER_SEC as i64 }).is_err());
+
+		let mut state = Vec::new();
+		c.save_state(&mut state)?;
+		c.advance(NS_PER_SEC * 10);
+		c.load_state(&mut &state[..])?;
+		assert_eq!(c.now(CLOCK_REALTIME)?.tv_sec, 1001);
+		Ok(())
+	}
+}
@@ -0,0 +1,354 @@
+// LZ4 block compression for savestates.  Only the LZ4 block format is implemented; the framing around it is our own,
+// since these states never leave the waterbox host.
+use std::io::{self, Read, Write};
+use anyhow::anyhow;
+use crate::{bin, workers};
+
+/// Marks the start of a compressed stream.  Uncompressed states never start with this.
+pub const MAGIC: &[u8; 8] = b"WbxLZ4\x00\x01";
+/// Amount of uncompressed data that goes into each independently compressed chunk
+const CHUNK_SIZE: usize = 0x40000;
+
+const MIN_MATCH: usize = 4;
+/// The last 5 bytes of a block are always literals
+const LAST_LITERALS: usize = 5;
+/// The last match must start at least 12 bytes before the end of a block
+const MF_LIMIT: usize = 12;
+const HASH_LOG: u32 = 16;
+const MAX_OFFSET: usize = 0xffff;
+
+fn read_u32(src: &[u8], i: usize) -> u32 {
+	u32::from_le_bytes([src[i], src[i + 1], src[i + 2], src[i + 3]])
+}
+fn hash(seq: u32) -> usize {
+	(seq.wrapping_mul(2654435761) >> (32 - HASH_LOG)) as usize
+}
+fn write_len(dst: &mut Vec<u8>, mut n: usize) {
+	while n >= 255 {
+		dst.push(255);
+		n -= 255;
+	}
+	dst.push(n as u8);
+}
+fn emit_literals(dst: &mut Vec<u8>, literals: &[u8], match_nibble: u8) {
+	let lit = literals.len();
+	dst.push((std::cmp::min(lit, 15) as u8) << 4 | match_nibble);
+	if lit >= 15 {
+		write_len(dst, lit - 15);
+	}
+	dst.extend_from_slice(literals);
+}
+
+/// Compress `src` as a single LZ4 block, appending the result to `dst`
+pub fn compress_block(src: &[u8], dst: &mut Vec<u8>) {
+	let mut anchor = 0;
+	if src.len() >= MF_LIMIT {
+		let mut table = vec![0usize; 1 << HASH_LOG];
+		let limit = src.len() - MF_LIMIT;
+		let match_limit = src.len() - LAST_LITERALS;
+		let mut i = 0;
+		while i <= limit {
+			let seq = read_u32(src, i);
+			let h = hash(seq);
+			// table stores position + 1, so that 0 can mean empty
+			let candidate = table[h];
+			table[h] = i + 1;
+			if candidate != 0 {
+				let c = candidate - 1;
+				if i - c <= MAX_OFFSET && read_u32(src, c) == seq {
+					let mut len = MIN_MATCH;
+					while i + len < match_limit && src[c + len] == src[i + len] {
+						len += 1;
+					}
+					let ml = len - MIN_MATCH;
+					emit_literals(dst, &src[anchor..i], std::cmp::min(ml, 15) as u8);
+					dst.extend_from_slice(&((i - c) as u16).to_le_bytes());
+					if ml >= 15 {
+						write_len(dst, ml - 15);
+					}
+					i += len;
+					anchor = i;
+					continue
+				}
+			}
+			i += 1;
+		}
+	}
+	emit_literals(dst, &src[anchor..], 0);
+}
+
+/// Decompress a single LZ4 block from `src`, appending the result to `dst`.  `expected_size` is the exact number of bytes
+/// the block must decompress to.
+pub fn decompress_block(src: &[u8], dst: &mut Vec<u8>, expected_size: usize) -> anyhow::Result<()> {
+	let bad = || anyhow!("Corrupt compressed state data");
+	let base = dst.len();
+	let end = base + expected_size;
+	let mut i = 0;
+	let read_len = |i: &mut usize| -> anyhow::Result<usize> {
+		let mut n = 0usize;
+		loop {
+			let b = *src.get(*i).ok_or_else(bad)?;
+			*i += 1;
+			n += b as usize;
+			if b != 255 {
+				break Ok(n)
+			}
+		}
+	};
+	loop {
+		let token = *src.get(i).ok_or_else(bad)?;
+		i += 1;
+		let mut lit = (token >> 4) as usize;
+		if lit == 15 {
+			lit += read_len(&mut i)?;
+		}
+		if i + lit > src.len() || dst.len() + lit > end {
+			return Err(bad())
+		}
+		dst.extend_from_slice(&src[i..i + lit]);
+		i += lit;
+		if i == src.len() {
+			break
+		}
+		if i + 2 > src.len() {
+			return Err(bad())
+		}
+		let offset = u16::from_le_bytes([src[i], src[i + 1]]) as usize;
+		i += 2;
+		let mut ml = (token & 15) as usize;
+		if ml == 15 {
+			ml += read_len(&mut i)?;
+		}
+		ml += MIN_MATCH;
+		if offset == 0 || offset > dst.len() - base || dst.len() + ml > end {
+			return Err(bad())
+		}
+		// matches may overlap their own output, so this has to go forwards in small pieces
+		let start = dst.len() - offset;
+		for j in 0..ml {
+			let b = dst[start + j];
+			dst.push(b);
+		}
+	}
+	if dst.len() != end {
+		return Err(bad())
+	}
+	Ok(())
+}
+
+/// Wraps a stream, compressing everything written to it.  Must be finished with `finish()`.  Chunks are compressed a
+/// batch at a time, with one chunk per worker thread.
+pub struct CompressedWriter<'a> {
+	stream: &'a mut dyn Write,
+	buf: Vec<u8>,
+	/// Full chunks waiting to be compressed
+	pending: Vec<Vec<u8>>,
+}
+impl<'a> CompressedWriter<'a> {
+	pub fn new(stream: &'a mut dyn Write) -> anyhow::Result<CompressedWriter<'a>> {
+		stream.write_all(MAGIC)?;
+		Ok(CompressedWriter {
+			stream,
+			buf: Vec::with_capacity(CHUNK_SIZE),
+			pending: Vec::new(),
+		})
+	}
+	fn write_chunks(&mut self) -> io::Result<()> {
+		let compressed = workers::map(&self.pending[..], |chunk| {
+			let mut out = Vec::new();
+			compress_block(&chunk[..], &mut out);
+			out
+		});
+		for (chunk, out) in self.pending.iter().zip(compressed) {
+			bin::writeval(self.stream, chunk.len() as u32)?;
+			// a chunk which did not compress is stored, and marked by having its compressed size equal its uncompressed size
+			if out.len() >= chunk.len() {
+				bin::writeval(self.stream, chunk.len() as u32)?;
+				self.stream.write_all(&chunk[..])?;
+			} else {
+				bin::writeval(self.stream, out.len() as u32)?;
+				self.stream.write_all(&out[..])?;
+			}
+		}
+		self.pending.clear();
+		Ok(())
+	}
+	/// Flush any remaining data and write the end marker
+	pub fn finish(mut self) -> anyhow::Result<()> {
+		if !self.buf.is_empty() {
+			let buf = std::mem::take(&mut self.buf);
+			self.pending.push(buf);
+		}
+		self.write_chunks()?;
+		bin::writeval(self.stream, 0u32)?;
+		Ok(())
+	}
+}
+impl<'a> Write for CompressedWriter<'a> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let n = std::cmp::min(buf.len(), CHUNK_SIZE - self.buf.len());
+		self.buf.extend_from_slice(&buf[0..n]);
+		if self.buf.len() == CHUNK_SIZE {
+			let full = std::mem::replace(&mut self.buf, Vec::with_capacity(CHUNK_SIZE));
+			self.pending.push(full);
+			if self.pending.len() == workers::threads() {
+				self.write_chunks()?;
+			}
+		}
+		Ok(n)
+	}
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+/// Reads from a stream made by CompressedWriter
+pub struct CompressedReader<'a> {
+	stream: &'a mut dyn Read,
+	buf: Vec<u8>,
+	compressed: Vec<u8>,
+	pos: usize,
+	done: bool,
+}
+impl<'a> CompressedReader<'a> {
+	fn read_chunk(&mut self) -> anyhow::Result<()> {
+		let size = bin::readval::<u32>(self.stream)? as usize;
+		self.buf.clear();
+		self.pos = 0;
+		if size == 0 {
+			self.done = true;
+			return Ok(())
+		}
+		if size > CHUNK_SIZE {
+			return Err(anyhow!("Corrupt compressed state data"))
+		}
+		let csize = bin::readval::<u32>(self.stream)? as usize;
+		if csize > size {
+			return Err(anyhow!("Corrupt compressed state data"))
+		}
+		self.compressed.resize(csize, 0);
+		self.stream.read_exact(&mut self.compressed[..])?;
+		if csize == size {
+			self.buf.extend_from_slice(&self.compressed[..]);
+		} else {
+			decompress_block(&self.compressed[..], &mut self.buf, size)?;
+		}
+		Ok(())
+	}
+}
+impl<'a> Read for CompressedReader<'a> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		while self.pos == self.buf.len() {
+			if self.done {
+				return Ok(0)
+			}
+			if let Err(e) = self.read_chunk() {
+				return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+			}
+		}
+		let n = std::cmp::min(buf.len(), self.buf.len() - self.pos);
+		buf[0..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+		self.pos += n;
+		Ok(n)
+	}
+}
+
+/// Whether a stream that starts with `prefix` is compressed.  `prefix` might not be long enough to tell.
+pub fn is_compressed(prefix: &[u8]) -> bool {
+	prefix.starts_with(MAGIC)
+}
+
+/// Sniffs the start of a stream, and returns a reader that will decompress it if needed
+pub fn maybe_decompress<'a>(stream: &'a mut dyn Read) -> anyhow::Result<Box<dyn Read + 'a>> {
+	let mut prefix = vec![0u8; MAGIC.len()];
+	stream.read_exact(&mut prefix[..])?;
+	if &prefix[..] == MAGIC {
+		Ok(Box::new(CompressedReader {
+			stream,
+			buf: Vec::new(),
+			compressed: Vec::new(),
+			pos: 0,
+			done: false,
+		}))
+	} else {
+		Ok(Box::new(io::Cursor::new(prefix).chain(stream)))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	type TestResult = anyhow::Result<()>;
+
+	fn roundtrip(data: &[u8]) -> TestResult {
+		let mut compressed = Vec::new();
+		compress_block(data, &mut compressed);
+		let mut res = Vec::new();
+		decompress_block(&compressed[..], &mut res, data.len())?;
+		assert_eq!(res, data);
+		Ok(())
+	}
+
+	#[test]
+	fn test_blocks() -> TestResult {
+		roundtrip(&[])?;
+		roundtrip(b"a")?;
+		roundtrip(b"The quick brown fox jumps over the lazy dog.")?;
+		roundtrip(&vec![0u8; 0x10000][..])?;
+		let mut mixed = Vec::new();
+		let mut x = 0x12345678u32;
+		for i in 0..100000u32 {
+			x ^= x << 13;
+			x ^= x >> 17;
+			x ^= x << 5;
+			mixed.push(if i % 1000 < 300 { x as u8 } else { (i / 7) as u8 });
+		}
+		roundtrip(&mixed[..])?;
+		Ok(())
+	}
+
+	#[test]
+	fn test_corrupt() {
+		let data = vec![7u8; 1000];
+		let mut compressed = Vec::new();
+		compress_block(&data[..], &mut compressed);
+		let mut res = Vec::new();
+		assert!(decompress_block(&compressed[..compressed.len() - 1], &mut res, data.len()).is_err());
+		res.clear();
+		assert!(decompress_block(&compressed[..], &mut res, data.len() - 1).is_err());
+	}
+
+	#[test]
+	fn test_stream() -> TestResult {
+		let data: Vec<u8> = (0..CHUNK_SIZE * 2 + 1234).map(|i| (i / 3000) as u8).collect();
+		let mut state = Vec::new();
+		{
+			let mut writer = CompressedWriter::new(&mut state)?;
+			writer.write_all(&data[..])?;
+			writer.finish()?;
+		}
+		assert!(state.len() < data.len() / 10);
+		let mut res = Vec::new();
+		maybe_decompress(&mut &state[..])?.read_to_end(&mut res)?;
+		assert_eq!(res, data);
+
+		// the same as compressing one chunk at a time
+		let mut serial = MAGIC.to_vec();
+		for chunk in data.chunks(CHUNK_SIZE) {
+			let mut out = Vec::new();
+			compress_block(chunk, &mut out);
+			bin::writeval(&mut serial, chunk.len() as u32)?;
+			bin::writeval(&mut serial, out.len() as u32)?;
+			serial.extend_from_slice(&out[..]);
+		}
+		bin::writeval(&mut serial, 0u32)?;
+		assert_eq!(state, serial);
+
+		// uncompressed data passes through untouched
+		res.clear();
+		maybe_decompress(&mut &data[..])?.read_to_end(&mut res)?;
+		assert_eq!(res, data);
+		Ok(())
+	}
+}
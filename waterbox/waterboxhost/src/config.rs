@@ -0,0 +1,186 @@
+// Everything about a host that's fixed once it's made:  The sizes of the parts of guest memory, and the optional
+// features that change how guest memory works.  WaterboxConfig::new() starts from the process wide wbx_set_* settings,
+// so a frontend only has to set what it cares about, and validate() turns down combinations that can't work before
+// any memory is reserved.  The C interface has it as HostConfig, which starts with its own size, so that fields can be
+// added at the end without breaking frontends built against an older one.
+use crate::*;
+use cinterface::MemoryLayoutTemplate;
+use host::WxPolicy;
+use memory_block::DirtyTracking;
+
+/// All of guest memory, including the executable, has to fit in this
+const REGION_SIZE: usize = 1 << 32;
+
+#[derive(Debug, Clone, Copy)]
+pub struct WaterboxConfig {
+	pub(crate) layout: MemoryLayoutTemplate,
+	pub(crate) dirty_tracking: DirtyTracking,
+	pub(crate) wx_policy: WxPolicy,
+	pub(crate) sanitizer_mode: bool,
+	pub(crate) huge_pages: bool,
+	pub(crate) scribble: bool,
+	pub(crate) commit_upfront: bool,
+	pub(crate) wasm_no_exec: bool,
+	pub(crate) mmap_seed: Option<u64>,
+}
+impl WaterboxConfig {
+	/// With no memory past the executable, and everything else as the wbx_set_* calls have it
+	pub fn new() -> WaterboxConfig {
+		unsafe {
+			WaterboxConfig {
+				layout: MemoryLayoutTemplate { sbrk_size: 0, sealed_size: 0, invis_size: 0, plain_size: 0, mmap_size: 0 },
+				dirty_tracking: if SANITIZER_MODE { DirtyTracking::Eager } else { DIRTY_TRACKING },
+				wx_policy: WxPolicy::Allow,
+				sanitizer_mode: SANITIZER_MODE,
+				huge_pages: HUGE_PAGES,
+				scribble: SCRIBBLE,
+				commit_upfront: COMMIT_UPFRONT,
+				wasm_no_exec: WASM_NO_EXEC,
+				mmap_seed: MMAP_SEED,
+			}
+		}
+	}
+	pub fn layout(mut self, layout: MemoryLayoutTemplate) -> WaterboxConfig {
+		self.layout = layout;
+		self
+	}
+	/// For brk(2)
+	pub fn sbrk_size(mut self, size: usize) -> WaterboxConfig {
+		self.layout.sbrk_size = size;
+		self
+	}
+	/// For alloc_sealed(3)
+	pub fn sealed_size(mut self, size: usize) -> WaterboxConfig {
+		self.layout.sealed_size = size;
+		self
+	}
+	/// For alloc_invisible(3)
+	pub fn invis_size(mut self, size: usize) -> WaterboxConfig {
+		self.layout.invis_size = size;
+		self
+	}
+	/// For alloc_plain(3)
+	pub fn plain_size(mut self, size: usize) -> WaterboxConfig {
+		self.layout.plain_size = size;
+		self
+	}
+	/// For mmap(2) and friends
+	pub fn mmap_size(mut self, size: usize) -> WaterboxConfig {
+		self.layout.mmap_size = size;
+		self
+	}
+	/// As in wbx_set_dirty_tracking
+	pub fn dirty_tracking(mut self, tracking: DirtyTracking) -> WaterboxConfig {
+		self.dirty_tracking = tracking;
+		self
+	}
+	/// As in wbx_set_wx_policy, from the start, so it covers what the guest does before the frontend gets a chance
+	pub fn wx_policy(mut self, policy: WxPolicy) -> WaterboxConfig {
+		self.wx_policy = policy;
+		self
+	}
+	/// As in wbx_set_sanitizer_mode.  Turning it on also picks the dirty tracking it needs.
+	pub fn sanitizer_mode(mut self, val: bool) -> WaterboxConfig {
+		self.sanitizer_mode = val;
+		if val {
+			self.dirty_tracking = DirtyTracking::Eager;
+		}
+		self
+	}
+	/// As in wbx_set_huge_pages
+	pub fn huge_pages(mut self, val: bool) -> WaterboxConfig {
+		self.huge_pages = val;
+		self
+	}
+	/// As in wbx_set_scribble_memory
+	pub fn scribble(mut self, val: bool) -> WaterboxConfig {
+		self.scribble = val;
+		self
+	}
+	/// As in wbx_set_commit_upfront
+	pub fn commit_upfront(mut self, val: bool) -> WaterboxConfig {
+		self.commit_upfront = val;
+		self
+	}
+	/// As in wbx_set_wasm_no_exec
+	pub fn wasm_no_exec(mut self, val: bool) -> WaterboxConfig {
+		self.wasm_no_exec = val;
+		self
+	}
+	/// As in wbx_set_mmap_randomization, with None for off
+	pub fn mmap_seed(mut self, seed: Option<u64>) -> WaterboxConfig {
+		self.mmap_seed = seed;
+		self
+	}
+
+	/// Check everything that can be checked without the executable
+	pub fn validate(&self) -> anyhow::Result<()> {
+		let l = &self.layout;
+		let total = [l.sbrk_size, l.sealed_size, l.invis_size, l.plain_size, l.mmap_size].iter()
+			.try_fold(0usize, |acc, &size| acc.checked_add(size.checked_add(PAGEMASK)? & !PAGEMASK));
+		if !matches!(total, Some(t) if t < REGION_SIZE) {
+			return Err(coded(ErrorCode::InvalidArgument, "HostMemoryLayout must fit into a single 4GiB region!"))
+		}
+		if self.sanitizer_mode && self.dirty_tracking != DirtyTracking::Eager {
+			return Err(coded(ErrorCode::InvalidArgument, "Sanitizer mode can't write protect guest memory, so it needs Eager dirty tracking"))
+		}
+		if !self.dirty_tracking.available() {
+			return Err(coded(ErrorCode::Unsupported, format!("Dirty tracking backend {:?} is not available on this system", self.dirty_tracking)))
+		}
+		if cfg!(target_os = "macos") && self.commit_upfront {
+			return Err(coded(ErrorCode::Unsupported, "Memory can't be committed up front on macOS"))
+		}
+		Ok(())
+	}
+}
+impl Default for WaterboxConfig {
+	fn default() -> WaterboxConfig {
+		WaterboxConfig::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_validate() {
+		let config = WaterboxConfig::new().sbrk_size(0x1000).mmap_size(0x10000);
+		assert!(config.validate().is_ok());
+		let fail = |c: WaterboxConfig| ErrorCode::of(&c.validate().unwrap_err());
+		assert_eq!(fail(config.mmap_size(0x80000000).plain_size(0x80000000)), ErrorCode::InvalidArgument);
+		assert_eq!(fail(config.mmap_size(usize::MAX)), ErrorCode::InvalidArgument);
+		assert_eq!(fail(config.sanitizer_mode(true).dirty_tracking(DirtyTracking::Signal)), ErrorCode::InvalidArgument);
+		assert!(config.dirty_tracking(DirtyTracking::Signal).sanitizer_mode(true).validate().is_ok());
+	}
+
+	#[test]
+	fn test_host_config() -> anyhow::Result<()> {
+		use cinterface::*;
+		let size = std::mem::size_of::<HostConfig>();
+		let mut words = vec![0u64; size / 8 + 2];
+		// a frontend that only knows about the layout
+		words[0] = 48;
+		words[1] = 0x1000;
+		words[5] = 0x7000;
+		let c = unsafe { HostConfig::read(words.as_ptr() as *const HostConfig)? }.to_config()?;
+		assert_eq!((c.layout.sbrk_size, c.layout.mmap_size), (0x1000, 0x7000));
+		assert_eq!(c.wx_policy, WxPolicy::Allow);
+		// and one that knows about more than this host, but doesn't use it
+		words[0] = size as u64 + 16;
+		words[6] = 2 | 1 << 32;
+		let read = |w: &[u64]| unsafe { HostConfig::read(w.as_ptr() as *const HostConfig) };
+		words[7] = HOST_CONFIG_MMAP_RANDOMIZATION;
+		words[8] = 99;
+		let c = read(&words)?.to_config()?;
+		assert_eq!((c.dirty_tracking, c.wx_policy, c.mmap_seed), (DirtyTracking::Eager, WxPolicy::Deny, Some(99)));
+		words[size / 8 + 1] = 1;
+		assert_eq!(ErrorCode::of(&read(&words).unwrap_err()), ErrorCode::Unsupported);
+		words[size / 8 + 1] = 0;
+		words[7] = 1 << 40;
+		assert_eq!(ErrorCode::of(&read(&words)?.to_config().unwrap_err()), ErrorCode::Unsupported);
+		words[0] = 16;
+		assert_eq!(ErrorCode::of(&read(&words).unwrap_err()), ErrorCode::InvalidArgument);
+		Ok(())
+	}
+}
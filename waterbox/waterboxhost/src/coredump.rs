@@ -0,0 +1,199 @@
+// ELF core files of a crashed guest, for looking at with gdb afterwards.  These hold the guest's memory, the faulting
+// thread's registers, and which guest images were loaded where, in the same format Linux uses for x86_64 processes.
+use crate::*;
+use memory_block::Protection;
+use std::mem::size_of;
+
+/// Registers in the order of the kernel's `user_regs_struct`, which is what NT_PRSTATUS holds
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UserRegs {
+	pub r15: u64,
+	pub r14: u64,
+	pub r13: u64,
+	pub r12: u64,
+	pub rbp: u64,
+	pub rbx: u64,
+	pub r11: u64,
+	pub r10: u64,
+	pub r9: u64,
+	pub r8: u64,
+	pub rax: u64,
+	pub rcx: u64,
+	pub rdx: u64,
+	pub rsi: u64,
+	pub rdi: u64,
+	pub orig_rax: u64,
+	pub rip: u64,
+	pub cs: u64,
+	pub eflags: u64,
+	pub rsp: u64,
+	pub ss: u64,
+	pub fs_base: u64,
+	pub gs_base: u64,
+	pub ds: u64,
+	pub es: u64,
+	pub fs: u64,
+	pub gs: u64,
+}
+
+const ET_CORE: u16 = 4;
+const EM_X86_64: u16 = 62;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+const NT_PRSTATUS: u32 = 1;
+const NT_FILE: u32 = 0x46494c45;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+/// Size of the kernel's `elf_prstatus`
+const PRSTATUS_SIZE: usize = 336;
+/// Where pr_reg is in `elf_prstatus`
+const PRSTATUS_REGS: usize = 112;
+
+/// A guest image that was loaded, for NT_FILE
+pub struct MappedFile {
+	pub name: String,
+	pub addr: AddressRange,
+}
+
+fn note(name: &str, kind: u32, desc: &[u8]) -> Vec<u8> {
+	let mut res = Vec::new();
+	res.extend_from_slice(&(name.len() as u32 + 1).to_le_bytes());
+	res.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+	res.extend_from_slice(&kind.to_le_bytes());
+	res.extend_from_slice(name.as_bytes());
+	res.push(0);
+	while res.len() % 4 != 0 {
+		res.push(0);
+	}
+	res.extend_from_slice(desc);
+	while res.len() % 4 != 0 {
+		res.push(0);
+	}
+	res
+}
+
+fn prstatus(signal: i32, regs: &UserRegs) -> Vec<u8> {
+	let mut res = vec![0u8; PRSTATUS_SIZE];
+	res[0..4].copy_from_slice(&signal.to_le_bytes());
+	// pr_cursig
+	res[12..14].copy_from_slice(&(signal as i16).to_le_bytes());
+	// pr_pid; the guest doesn't have a real one
+	res[32..36].copy_from_slice(&1i32.to_le_bytes());
+	let regs = unsafe { std::slice::from_raw_parts(regs as *const UserRegs as *const u8, size_of::<UserRegs>()) };
+	res[PRSTATUS_REGS..PRSTATUS_REGS + regs.len()].copy_from_slice(regs);
+	res
+}
+
+fn file_note(files: &[MappedFile]) -> Vec<u8> {
+	let mut res = Vec::new();
+	res.extend_from_slice(&(files.len() as u64).to_le_bytes());
+	res.extend_from_slice(&(PAGESIZE as u64).to_le_bytes());
+	for f in files.iter() {
+		res.extend_from_slice(&(f.addr.start as u64).to_le_bytes());
+		res.extend_from_slice(&(f.addr.end() as u64).to_le_bytes());
+		res.extend_from_slice(&0u64.to_le_bytes());
+	}
+	for f in files.iter() {
+		res.extend_from_slice(f.name.as_bytes());
+		res.push(0);
+	}
+	res
+}
+
+/// Write a core file.  `regions` are the guest memory to include, which must all be readable.
+pub fn write_core(stream: &mut dyn Write, signal: i32, regs: &UserRegs, regions: &[(AddressRange, Protection)], files: &[MappedFile]) -> anyhow::Result<()> {
+	let mut notes = note("CORE", NT_PRSTATUS, &prstatus(signal, regs));
+	notes.extend(note("CORE", NT_FILE, &file_note(files)));
+
+	let phnum = regions.len() + 1;
+	let notes_offset = EHDR_SIZE + PHDR_SIZE * phnum;
+	let data_offset = align_up(notes_offset + notes.len());
+
+	let mut head = Vec::new();
+	head.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2 /* 64 bit */, 1 /* little endian */, 1 /* version */]);
+	head.resize(16, 0);
+	head.extend_from_slice(&ET_CORE.to_le_bytes());
+	head.extend_from_slice(&EM_X86_64.to_le_bytes());
+	head.extend_from_slice(&1u32.to_le_bytes());
+	// entry, phoff, shoff
+	head.extend_from_slice(&0u64.to_le_bytes());
+	head.extend_from_slice(&(EHDR_SIZE as u64).to_le_bytes());
+	head.extend_from_slice(&0u64.to_le_bytes());
+	// flags, ehsize, phentsize, phnum, shentsize, shnum, shstrndx
+	head.extend_from_slice(&0u32.to_le_bytes());
+	head.extend_from_slice(&(EHDR_SIZE as u16).to_le_bytes());
+	head.extend_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+	head.extend_from_slice(&(phnum as u16).to_le_bytes());
+	head.extend_from_slice(&[0u8; 6]);
+
+	let mut phdr = |kind: u32, flags: u32, offset: usize, vaddr: usize, size: usize, align: usize| {
+		head.extend_from_slice(&kind.to_le_bytes());
+		head.extend_from_slice(&flags.to_le_bytes());
+		head.extend_from_slice(&(offset as u64).to_le_bytes());
+		head.extend_from_slice(&(vaddr as u64).to_le_bytes());
+		head.extend_from_slice(&0u64.to_le_bytes());
+		head.extend_from_slice(&(size as u64).to_le_bytes());
+		head.extend_from_slice(&(size as u64).to_le_bytes());
+		head.extend_from_slice(&(align as u64).to_le_bytes());
+	};
+	phdr(PT_NOTE, 0, notes_offset, 0, notes.len(), 4);
+	let mut offset = data_offset;
+	for (addr, prot) in regions.iter() {
+		let flags = match prot {
+			Protection::None | Protection::StackGuard => 0,
+			Protection::R => PF_R,
+			Protection::RX => PF_R | PF_X,
+			Protection::RW | Protection::RWStack => PF_R | PF_W,
+			Protection::RWX => PF_R | PF_W | PF_X,
+		};
+		phdr(PT_LOAD, flags, offset, addr.start, addr.size, PAGESIZE);
+		offset += addr.size;
+	}
+
+	head.extend(notes);
+	head.resize(data_offset, 0);
+	stream.write_all(&head)?;
+	for (addr, _) in regions.iter() {
+		stream.write_all(unsafe { addr.slice() })?;
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use goblin::elf::Elf;
+
+	#[test]
+	fn test_write_core() -> anyhow::Result<()> {
+		let data = vec![0x5au8; 0x3000];
+		let start = align_up(data.as_ptr() as usize);
+		let regions = [
+			(AddressRange { start, size: 0x1000 }, Protection::RX),
+			(AddressRange { start: start + 0x1000, size: 0x1000 }, Protection::RW),
+		];
+		let regs = UserRegs { rip: start as u64 + 0x10, rsp: 0x1234, ..Default::default() };
+		let files = [MappedFile { name: "core.wbx".to_string(), addr: regions[0].0 }];
+		let mut core = Vec::new();
+		write_core(&mut core, 11, &regs, &regions, &files)?;
+
+		let elf = Elf::parse(&core)?;
+		assert_eq!(elf.header.e_type, ET_CORE);
+		assert_eq!(elf.program_headers.len(), 3);
+		let note = &elf.program_headers[0];
+		assert_eq!(note.p_type, PT_NOTE);
+		let code = &elf.program_headers[1];
+		assert_eq!((code.p_type, code.p_vaddr as usize, code.p_flags), (PT_LOAD, start, PF_R | PF_X));
+		assert!(core[code.file_range()].iter().all(|&b| b == 0x5a));
+		// the registers are 20 bytes of note header and "CORE" in
+		let regs_at = note.p_offset as usize + 20 + PRSTATUS_REGS;
+		let rip = u64::from_le_bytes(std::convert::TryInto::try_into(&core[regs_at + 16 * 8..regs_at + 17 * 8]).unwrap());
+		assert_eq!(rip, regs.rip);
+		Ok(())
+	}
+}
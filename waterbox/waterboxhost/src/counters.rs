@@ -0,0 +1,37 @@
+// Counters of what the memory system has done, across every host in the process, for finding out where the time goes,
+// whether in a benchmark or from a frontend in the field.  They only ever go up, so what something cost is the
+// difference between reads before and after it.
+use crate::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Uncompressed savestate bytes written, by every host, ever
+static STATE_BYTES: AtomicU64 = AtomicU64::new(0);
+
+pub fn add_state_bytes(n: u64) {
+	STATE_BYTES.fetch_add(n, Ordering::Relaxed);
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PerfCounters {
+	/// Faults taken on guest memory, by the signal or exception handler or the userfaultfd thread
+	pub faults: u64,
+	/// Of those, the ones that were first writes to a page since it was last clean
+	pub dirty_faults: u64,
+	/// OS calls made to change guest memory protections
+	pub protect_calls: u64,
+	/// Pages of snapshots and copies of guest memory made, whether new to the process or reused
+	pub snapshot_pages: u64,
+	/// Uncompressed bytes of savestates written
+	pub state_bytes: u64,
+}
+
+pub fn read() -> PerfCounters {
+	PerfCounters {
+		faults: memory_block::fault_count(),
+		dirty_faults: memory_block::dirty_fault_count(),
+		protect_calls: memory_block::protect_call_count(),
+		snapshot_pages: memory_block::page_block_count(),
+		state_bytes: STATE_BYTES.load(Ordering::Relaxed),
+	}
+}
@@ -0,0 +1,108 @@
+// Crash reports for guest faults that nothing can handle.  Before the fault goes on to whoever would have seen it
+// anyway, the host that owns the faulting code describes it, with a backtrace walked along the guest's frame pointers
+// and symbolized from the guest's symbol tables.
+use crate::*;
+use memory_block::debug_read;
+use coredump::UserRegs;
+use threading::ThreadId;
+
+/// How deep a backtrace can go
+const MAX_FRAMES: usize = 64;
+
+/// The state of the guest when it faulted
+pub struct Fault {
+	/// What the fault would be as a signal on Linux
+	pub signal: i32,
+	pub regs: UserRegs,
+	/// The address the faulting instruction was accessing
+	pub addr: usize,
+	pub access: &'static str,
+	/// If the fault was the stack overflowing, how much of the stack was in use
+	pub stack_depth: Option<usize>,
+}
+
+fn read_word(addr: usize) -> Option<usize> {
+	let mut buf = [0u8; 8];
+	if unsafe { debug_read(addr, &mut buf) } {
+		Some(usize::from_le_bytes(buf))
+	} else {
+		None
+	}
+}
+
+/// Return addresses found by following the frame pointer chain up from rbp, most recent first.  The walk stops at the
+/// first frame that doesn't look right:  unreadable, not further up the stack, or not returning into guest code.
+pub fn backtrace(mut rbp: usize, is_code: impl Fn(usize) -> bool) -> Vec<usize> {
+	let mut res = Vec::new();
+	while res.len() < MAX_FRAMES {
+		let (next, ret) = match (read_word(rbp), read_word(rbp + 8)) {
+			(Some(next), Some(ret)) => (next, ret),
+			_ => break,
+		};
+		if !is_code(ret) {
+			break
+		}
+		res.push(ret);
+		if next <= rbp {
+			break
+		}
+		rbp = next;
+	}
+	res
+}
+
+/// Describe a fault in guest thread `thread`, and the backtrace leading to it, using `symbolize` to name guest code
+/// addresses
+pub fn describe(fault: &Fault, thread: ThreadId, frames: &[usize], symbolize: impl Fn(usize) -> Option<String>) -> String {
+	let name = |addr: usize| symbolize(addr).map(|s| format!(" {}", s)).unwrap_or_default();
+	let rip = fault.regs.rip as usize;
+	let mut res = match fault.stack_depth {
+		Some(depth) => format!("Guest stack overflow in thread {} at depth {:#x}, at {:#x}{}\n", thread, depth, rip, name(rip)),
+		None => format!("Guest fault: {} of {:#x} at {:#x}{}\n", fault.access, fault.addr, rip, name(rip)),
+	};
+	res.push_str(&format!("  rsp={:#x} rbp={:#x}\n", fault.regs.rsp, fault.regs.rbp));
+	for (i, &ret) in frames.iter().enumerate() {
+		// the call instruction is what's interesting, not the one after it
+		res.push_str(&format!("  #{} {:#x}{}\n", i + 1, ret, name(ret - 1)));
+	}
+	res
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use memory_block::{MemoryBlock, Protection};
+
+	#[test]
+	fn test_backtrace() -> anyhow::Result<()> {
+		let addr = AddressRange { start: 0x37c00000000, size: 0x10000 };
+		let mut b = MemoryBlock::new(addr);
+		let mut g = b.enter();
+		g.mmap_fixed(addr, Protection::RW, true)?;
+		let stack = addr.start + 0x8000;
+		let frames = [
+			(stack, stack + 0x40, 0x37c00001234),
+			(stack + 0x40, stack + 0x100, 0x37c00001500),
+			// returning somewhere that isn't guest code ends it
+			(stack + 0x100, stack + 0x200, 0x1234),
+		];
+		unsafe {
+			for &(rbp, next, ret) in frames.iter() {
+				*(rbp as *mut usize) = next;
+				*((rbp + 8) as *mut usize) = ret;
+			}
+		}
+		let is_code = |a: usize| addr.contains(a);
+		assert_eq!(backtrace(stack, is_code), vec![0x37c00001234, 0x37c00001500]);
+		assert_eq!(backtrace(addr.start + 0x20000, is_code), vec![]);
+
+		let regs = UserRegs { rip: 0x37c00001000, rsp: stack as u64 - 0x10, rbp: stack as u64, ..Default::default() };
+		let fault = Fault { signal: 11, regs, addr: 0, access: "write", stack_depth: None };
+		let text = describe(&fault, threading::MAIN_TID, &[0x37c00001234], |a| if a < 0x37c00001200 { Some("foo+0x0".to_string()) } else { None });
+		assert_eq!(text, format!("Guest fault: write of 0x0 at 0x37c00001000 foo+0x0\n  rsp={:#x} rbp={:#x}\n  #1 0x37c00001234\n",
+			stack - 0x10, stack));
+		let overflow = Fault { stack_depth: Some(0x8010), ..fault };
+		assert!(describe(&overflow, 5, &[], |_| None).starts_with("Guest stack overflow in thread 5 at depth 0x8010, at 0x37c00001000\n"));
+		Ok(())
+	}
+}
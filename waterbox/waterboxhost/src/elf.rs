@@ -1,12 +1,17 @@
 use goblin;
-use goblin::{elf::Elf, elf64::{sym::*, section_header::*}};
+use goblin::{elf::{Elf, ProgramHeader, header::ET_DYN, reloc::*}, elf64::{sym::*, section_header::*, program_header::{PT_LOAD, PT_TLS}}};
 use crate::*;
 use crate::memory_block::ActivatedMemoryBlock;
 use crate::memory_block::Protection;
+use crate::abi::{GuestAbi, SyscallThunk};
 use std::collections::HashMap;
+use crate::startup::StartInfo;
+use crate::imports::{self, ImportInfo};
+use crate::fpenv::{FpEnv, GuestFp};
 
 /// Special system import area
 const IMPORTS_OBJECT_NAME: &str = "__wbxsysarea";
+const IMPORTS_TABLE_NAME: &str = "__wbximports";
 
 /// Section names that are not marked as readonly, but we'll make them readonly anyway
 fn section_name_is_readonly(name: &str) -> bool {
@@ -18,91 +23,337 @@ fn section_name_is_readonly(name: &str) -> bool {
 		|| name == ".sealed"
 }
 
+/// Room for the TCB that the thread pointer points to.  Guest libcs may keep more than the self pointer there.
+const TCB_SIZE: usize = 0x100;
+
+/// How to lay out a static TLS block for a PT_TLS of the given size and alignment.  Returns the size to allocate, and
+/// the offset of the thread pointer from the aligned start of the allocation.  This is TLS variant II:  The thread
+/// pointer points just past the TLS data, at the TCB.
+fn tls_layout(size: usize, align: usize) -> (usize, usize) {
+	let align = std::cmp::max(align, 16);
+	let data_size = (size + align - 1) & !(align - 1);
+	// allocations are only page aligned
+	let slack = align.saturating_sub(PAGESIZE);
+	(align_up(slack + data_size + TCB_SIZE), data_size)
+}
+
+fn segment_prot(segment: &ProgramHeader) -> Protection {
+	match (segment.is_read(), segment.is_write(), segment.is_executable()) {
+		(false, false, false) => Protection::None,
+		(true, false, false) => Protection::R,
+		(_, false, true) => Protection::RX,
+		(_, true, false) => Protection::RW,
+		(_, true, true) => Protection::RWX
+	}
+}
+
+/// What a dynamic relocation of a secondary module resolves to.  `sym` is the address of its symbol, if it has one.
+#[cfg(target_arch = "x86_64")]
+fn relocation_value(r_type: u32, base: usize, sym: usize, addend: i64) -> anyhow::Result<Option<usize>> {
+	Ok(Some(match r_type {
+		R_X86_64_NONE => return Ok(None),
+		R_X86_64_RELATIVE => base.wrapping_add(addend as usize),
+		R_X86_64_RELATIVE64 => base.wrapping_add(addend as usize),
+		R_X86_64_64 | R_X86_64_32 => sym.wrapping_add(addend as usize),
+		R_X86_64_GLOB_DAT | R_X86_64_JUMP_SLOT => sym,
+		_ => return Err(anyhow!("Unsupported relocation type {}", r_type))
+	}))
+}
+/// How many bytes a dynamic relocation writes
+#[cfg(target_arch = "x86_64")]
+fn relocation_size(r_type: u32, abi: GuestAbi) -> usize {
+	match r_type {
+		R_X86_64_32 => 4,
+		R_X86_64_64 | R_X86_64_RELATIVE64 => 8,
+		_ => abi.pointer_size(),
+	}
+}
+/// What a dynamic relocation of a secondary module resolves to.  `sym` is the address of its symbol, if it has one.
+#[cfg(target_arch = "aarch64")]
+fn relocation_value(r_type: u32, base: usize, sym: usize, addend: i64) -> anyhow::Result<Option<usize>> {
+	Ok(Some(match r_type {
+		R_AARCH64_NONE => return Ok(None),
+		R_AARCH64_RELATIVE => base.wrapping_add(addend as usize),
+		R_AARCH64_ABS64 | R_AARCH64_GLOB_DAT => sym.wrapping_add(addend as usize),
+		R_AARCH64_JUMP_SLOT => sym,
+		_ => return Err(anyhow!("Unsupported relocation type {}", r_type))
+	}))
+}
+/// How many bytes a dynamic relocation writes
+#[cfg(target_arch = "aarch64")]
+fn relocation_size(_r_type: u32, abi: GuestAbi) -> usize {
+	abi.pointer_size()
+}
+
+/// Where a position independent main executable is loaded:  Where linkscript.T puts fixed address ones, or for 32-bit
+/// guests, low enough for their pointers to reach
+fn pie_base(abi: GuestAbi) -> usize {
+	match abi {
+		GuestAbi::Lp64 => 0x36f00000000,
+		GuestAbi::Ilp32 | GuestAbi::Wasm32 => 0x10000000,
+	}
+}
+
+/// How far the main executable's addresses are from the ones it was linked at
+fn load_bias(wbx: &Elf, abi: GuestAbi) -> usize {
+	if wbx.header.e_type == ET_DYN { pie_base(abi) } else { 0 }
+}
+
+/// The main executable's segments that get loaded
+fn loaded_segments<'a>(wbx: &'a Elf) -> impl Iterator<Item = &'a ProgramHeader> {
+	let pie = wbx.header.e_type == ET_DYN;
+	// the first segment of a position independent one is at 0
+	wbx.program_headers.iter().filter(move |x| if pie { x.p_type == PT_LOAD } else { x.p_vaddr != 0 && x.p_type != PT_TLS })
+}
+
+/// Apply the dynamic relocations of an ELF that was loaded at `base`.  Symbols it needs but doesn't define come from
+/// `resolve`.
+fn relocate(wbx: &Elf, base: usize, abi: GuestAbi, module_name: &str, resolve: impl Fn(&str) -> Option<usize>) -> anyhow::Result<()> {
+	let sym_name = |index: usize| -> Option<&str> {
+		wbx.dynsyms.get(index).and_then(|sym| match wbx.dynstrtab.get(sym.st_name) {
+			Some(Ok(s)) => Some(s),
+			_ => None
+		})
+	};
+	if !wbx.dynrels.is_empty() {
+		return Err(anyhow!("Module `{}` has REL relocations, which x86_64 and aarch64 shouldn't", module_name))
+	}
+	for reloc in wbx.dynrelas.iter().chain(wbx.pltrelocs.iter()) {
+		let sym = match wbx.dynsyms.get(reloc.r_sym) {
+			Some(sym) if reloc.r_sym != 0 && sym.st_shndx as u32 != SHN_UNDEF => base + sym.st_value as usize,
+			Some(sym) if reloc.r_sym != 0 => {
+				let name = sym_name(reloc.r_sym).unwrap_or("<anon>");
+				match resolve(name) {
+					Some(addr) => addr,
+					None if sym.st_bind() == STB_WEAK => 0,
+					None => return Err(anyhow!("Module `{}` needs `{}`, which nothing exports", module_name, name))
+				}
+			},
+			_ => 0,
+		};
+		if let Some(value) = relocation_value(reloc.r_type, base, sym, reloc.r_addend.unwrap_or(0))? {
+			let addr = base + reloc.r_offset as usize;
+			unsafe {
+				match relocation_size(reloc.r_type, abi) {
+					4 => *(addr as *mut u32) = value as u32,
+					_ => *(addr as *mut usize) = value,
+				}
+			}
+		}
+	}
+	Ok(())
+}
+
+/// The main executable's sections that have data, moved by `bias`
+fn read_sections(wbx: &Elf, bias: usize) -> Vec<SectionInfo> {
+	log!(Debug, "  Sections:");
+
+	let mut sections = Vec::new();	
+
+	for section in wbx.section_headers.iter() {
+		let name = match wbx.shdr_strtab.get(section.sh_name) {
+			Some(Ok(s)) => s,
+			_ => "<anon>"
+		};
+		log!(Debug, "    @{:x}:{:x} {}{}{} `{}` {} bytes",
+			section.sh_addr,
+			section.sh_addr + section.sh_size,
+			if section.sh_flags & (SHF_ALLOC as u64) != 0 { "R" } else { " " },
+			if section.sh_flags & (SHF_WRITE as u64) != 0 { "W" } else { " " },
+			if section.sh_flags & (SHF_EXECINSTR as u64) != 0 { "X" } else { " " },
+			name,
+			section.sh_size
+		);
+		if section.sh_type != SHT_NOBITS
+			&& name != "<anon>"
+			&& section.sh_addr != 0 {
+			let si = SectionInfo {
+				name: name.to_string(),
+				addr: AddressRange {
+					start: bias + section.sh_addr as usize,
+					size: section.sh_size as usize
+				}
+			};
+			sections.push(si);
+		}
+	}
+	sections
+}
+
+/// The guest's half of the imports handshake, from `table`, which has been loaded
+fn read_imports(table: AddressRange, module_name: &str) -> ImportInfo {
+	let res = unsafe { imports::read_guest(table) };
+	if res.missing() != 0 {
+		log!(Warn, "`{}` would use imports {:#x}, which this host doesn't have", module_name, res.missing());
+	}
+	res
+}
+
+/// The main executable's exports, moved by `bias`, and where its import area is
+fn read_exports(wbx: &Elf, bias: usize) -> (HashMap<String, AddressRange>, Option<AddressRange>) {
+	let mut exports = HashMap::new();
+	let mut import_area_opt = None;
+
+	for sym in wbx.syms.iter() {
+		let name = match wbx.strtab.get(sym.st_name) {
+			Some(Ok(s)) => s,
+			_ => continue
+		};
+		if sym.st_shndx as u32 == SHN_UNDEF {
+			continue
+		}
+		if sym.st_visibility() == STV_DEFAULT && sym.st_bind() == STB_GLOBAL {
+			exports.insert(
+				name.to_string(),
+				AddressRange { start: bias + sym.st_value as usize, size: sym.st_size as usize }
+			);
+		}
+		if name == IMPORTS_OBJECT_NAME {
+			import_area_opt = Some(AddressRange { start: bias + sym.st_value as usize, size: sym.st_size as usize });
+		}
+	}
+	(exports, import_area_opt)
+}
+
+/// What a rebuild of the main executable has to have in common with it for reload() to keep the guest's data:  Where
+/// its writable segments and TLS are, and where every variable in them is
+fn data_layout(wbx: &Elf) -> Vec<u8> {
+	let writable = loaded_segments(wbx).filter(|x| x.is_write()).map(|x| x.vm_range()).collect::<Vec<_>>();
+	let mut res = Vec::new();
+	let mut put = |v: &[u8]| res.extend_from_slice(v);
+	for r in writable.iter() {
+		put(&r.start.to_le_bytes());
+		put(&r.end.to_le_bytes());
+	}
+	if let Some(tls) = wbx.program_headers.iter().find(|x| x.p_type == PT_TLS) {
+		put(&tls.p_memsz.to_le_bytes());
+		put(&tls.p_align.to_le_bytes());
+	}
+	for (addr, name) in symbol_table(wbx, 0) {
+		if writable.iter().any(|r| r.contains(&addr.start)) {
+			put(&addr.start.to_le_bytes());
+			put(&addr.size.to_le_bytes());
+			put(name.as_bytes());
+		}
+	}
+	bin::hash(&res[..])
+}
+
+/// Functions and data in an ELF, for crash reports and symbol lookups.  Sorted by address.
+type SymbolTable = Vec<(AddressRange, String)>;
+
+fn symbol_table(wbx: &Elf, base: usize) -> SymbolTable {
+	let mut res = wbx.syms.iter().map(|sym| (sym, &wbx.strtab))
+		.chain(wbx.dynsyms.iter().map(|sym| (sym, &wbx.dynstrtab)))
+		.filter(|(sym, _)| (sym.st_type() == STT_FUNC || sym.st_type() == STT_OBJECT) && sym.st_value != 0 && sym.st_size != 0)
+		.filter_map(|(sym, strtab)| match strtab.get(sym.st_name) {
+			Some(Ok(name)) => Some((
+				AddressRange { start: base + sym.st_value as usize, size: sym.st_size as usize },
+				name.to_string()
+			)),
+			_ => None
+		})
+		.collect::<Vec<_>>();
+	res.sort_by_key(|(addr, _)| addr.start);
+	res.dedup_by_key(|(addr, _)| addr.start);
+	res
+}
+
+/// Name an address as `function+offset`
+fn lookup_symbol(symbols: &SymbolTable, addr: usize) -> Option<String> {
+	let index = match symbols.binary_search_by_key(&addr, |(a, _)| a.start) {
+		Ok(i) => i,
+		Err(0) => return None,
+		Err(i) => i - 1,
+	};
+	let (range, name) = &symbols[index];
+	if range.contains(addr) {
+		Some(format!("{}+{:#x}", name, addr - range.start))
+	} else {
+		None
+	}
+}
+
+/// Where the symbol `name` is
+fn find_symbol(symbols: &SymbolTable, name: &str) -> Option<usize> {
+	symbols.iter().find(|(_, n)| n == name).map(|(a, _)| a.start)
+}
+
+/// A secondary library, loaded at an address of the host's choosing and linked against what was loaded before it
+struct Module {
+	name: String,
+	base: usize,
+	size: usize,
+	exports: HashMap<String, AddressRange>,
+	symbols: SymbolTable,
+	hash: Vec<u8>,
+}
+
 pub struct SectionInfo {
 	name: String,
 	addr: AddressRange,
 }
 pub struct ElfLoader {
+	name: String,
+	addr: AddressRange,
 	sections: Vec<SectionInfo>,
 	exports: HashMap<String, AddressRange>,
 	entry_point: usize,
 	hash: Vec<u8>,
+	abi: GuestAbi,
 	import_area: AddressRange,
+	/// The guest's __wbximports, if it has one
+	imports_table: AddressRange,
+	imports: ImportInfo,
+	/// The guest's floating point environment, whenever it's not running
+	fp_env: FpEnv,
+	/// What an ILP32 guest's syscall pointer points to
+	syscall_thunk: Option<SyscallThunk>,
+	/// The main thread's thread pointer, if there's TLS
+	thread_pointer: usize,
+	/// Libraries loaded after the main executable, in load order
+	modules: Vec<Module>,
+	symbols: SymbolTable,
+	/// If the guest is interpreted wasm rather than native code
+	wasm: Option<Box<wasm::Instance>>,
+	/// How far a position independent main executable was moved from where it was linked
+	load_bias: usize,
+	/// data_layout() of the main executable
+	data_layout: Vec<u8>,
 }
 impl ElfLoader {
-	pub fn elf_addr(wbx: &Elf) -> AddressRange {
-		let start = wbx.program_headers.iter()
-			.filter(|x| x.p_vaddr != 0)
+	/// Where the main executable goes.  Fixed address executables go where they were linked, and position independent
+	/// ones at a base of the host's choosing.
+	pub fn elf_addr(wbx: &Elf, abi: GuestAbi) -> AddressRange {
+		let bias = load_bias(wbx, abi);
+		let start = loaded_segments(wbx)
 			.map(|x| x.vm_range().start)
 			.min()
 			.unwrap();
-		let end = wbx.program_headers.iter()
-			.filter(|x| x.p_vaddr != 0)
+		let end = loaded_segments(wbx)
 			.map(|x| x.vm_range().end)
 			.max()
 			.unwrap();
-		return AddressRange { start, size: end - start };
+		AddressRange { start: bias + start, size: end - start }
+	}
+	/// If the main executable was loaded somewhere other than where it was linked
+	pub fn relocated(&self) -> bool {
+		self.load_bias != 0
 	}
 	pub fn new(wbx: &Elf, data: &[u8],
+		abi: GuestAbi,
 		module_name: &str,
 		layout: &WbxSysLayout,
 		b: &mut ActivatedMemoryBlock
 	) -> anyhow::Result<ElfLoader> {
-		println!("Mouting `{}` @{:x}", module_name, layout.elf.start);
-		println!("  Sections:");
-
-		let mut sections = Vec::new();	
-
-		for section in wbx.section_headers.iter() {
-			let name = match wbx.shdr_strtab.get(section.sh_name) {
-				Some(Ok(s)) => s,
-				_ => "<anon>"
-			};
-			println!("    @{:x}:{:x} {}{}{} `{}` {} bytes",
-				section.sh_addr,
-				section.sh_addr + section.sh_size,
-				if section.sh_flags & (SHF_ALLOC as u64) != 0 { "R" } else { " " },
-				if section.sh_flags & (SHF_WRITE as u64) != 0 { "W" } else { " " },
-				if section.sh_flags & (SHF_EXECINSTR as u64) != 0 { "X" } else { " " },
-				name,
-				section.sh_size
-			);
-			if section.sh_type != SHT_NOBITS
-				&& name != "<anon>"
-				&& section.sh_addr != 0 {
-				let si = SectionInfo {
-					name: name.to_string(),
-					addr: AddressRange {
-						start: section.sh_addr as usize,
-						size: section.sh_size as usize
-					}
-				};
-				sections.push(si);
-			}
-		}
-
-		let mut exports = HashMap::new();
-		let mut import_area_opt = None;
-
-		for sym in wbx.syms.iter() {
-			let name = match wbx.strtab.get(sym.st_name) {
-				Some(Ok(s)) => s,
-				_ => continue
-			};
-			if sym.st_visibility() == STV_DEFAULT && sym.st_bind() == STB_GLOBAL {
-				exports.insert(
-					name.to_string(),
-					AddressRange { start: sym.st_value as usize, size: sym.st_size as usize }
-				);
-			}
-			if name == IMPORTS_OBJECT_NAME {
-				import_area_opt = Some(AddressRange { start: sym.st_value as usize, size: sym.st_size as usize });
-			}
-		}
+		log!(Info, "Mouting `{}` @{:x}", module_name, layout.elf.start);
+		let bias = load_bias(wbx, abi);
+		let sections = read_sections(wbx, bias);
+		let (exports, import_area_opt) = read_exports(wbx, bias);
 
 		let import_area = match import_area_opt {
 			Some(i) => {
-				if i.size != std::mem::size_of::<WbxSysArea>() {
+				if i.size != abi.sys_area_size() {
 					return Err(anyhow!("Symbol {} is the wrong size", IMPORTS_OBJECT_NAME))
 				}
 				i
@@ -134,21 +385,15 @@ impl ElfLoader {
 
 		b.mark_invisible(layout.invis)?;
 
-		println!("  Segments:");
-		for segment in wbx.program_headers.iter().filter(|x| x.p_vaddr != 0) {
-			let addr = AddressRange {
-				start: segment.vm_range().start,
-				size: segment.vm_range().end - segment.vm_range().start
-			};
+		log!(Debug, "  Segments:");
+		let segment_addr = |segment: &ProgramHeader| AddressRange {
+			start: bias + segment.vm_range().start,
+			size: segment.vm_range().end - segment.vm_range().start
+		};
+		for segment in loaded_segments(wbx) {
+			let addr = segment_addr(segment);
 			let prot_addr = addr.align_expand();
-			let prot = match (segment.is_read(), segment.is_write(), segment.is_executable()) {
-				(false, false, false) => Protection::None,
-				(true, false, false) => Protection::R,
-				(_, false, true) => Protection::RX,
-				(_, true, false) => Protection::RW,
-				(_, true, true) => Protection::RWX
-			};
-			println!("    %{:x}:{:x} {}{}{} {} bytes",
+			log!(Debug, "    %{:x}:{:x} {}{}{} {} bytes",
 				addr.start,
 				addr.end(),
 				if segment.is_read() { "R" } else { " " },
@@ -163,56 +408,391 @@ impl ElfLoader {
 				let dst = AddressRange { start: addr.start, size: segment.file_range().end - segment.file_range().start }.slice_mut();
 				dst.copy_from_slice(src);
 			}
-			b.mprotect(prot_addr, prot)?;
+		}
+		if bias != 0 {
+			// nothing else is loaded yet for it to link against
+			relocate(wbx, bias, abi, module_name, |_| None)?;
+		}
+		for segment in loaded_segments(wbx) {
+			b.mprotect(segment_addr(segment).align_expand(), segment_prot(segment))?;
 		}
 
+		let thread_pointer = match wbx.program_headers.iter().find(|x| x.p_type == PT_TLS) {
+			Some(tls) => {
+				if !threading::tls_supported() {
+					return Err(anyhow!("Guest TLS isn't supported on this platform"))
+				}
+				let (size, tp_offset) = tls_layout(tls.p_memsz as usize, tls.p_align as usize);
+				let mut block = b.mmap(AddressRange { start: 0, size }, Protection::RW, layout.mmap, false)?;
+				let align = tls.p_align as usize;
+				if align > PAGESIZE {
+					block = (block + align - 1) & !(align - 1);
+				}
+				let tp = block + tp_offset;
+				let image = &data[tls.file_range()];
+				log!(Debug, "  TLS: %{:x}:{:x} {} bytes, thread pointer %{:x}", block, tp, tls.p_memsz, tp);
+				unsafe {
+					AddressRange { start: block, size: image.len() }.slice_mut().copy_from_slice(image);
+					// the first word of the TCB points to itself
+					abi.write_pointer(tp, tp);
+				}
+				tp
+			},
+			None => 0,
+		};
+
+		let syscall_thunk = match abi {
+			GuestAbi::Lp64 | GuestAbi::Wasm32 => None,
+			GuestAbi::Ilp32 => Some(SyscallThunk::new(b, layout.mmap)?),
+		};
+		let imports_table = match exports.get(IMPORTS_TABLE_NAME) {
+			Some(&t) if t.size < imports::IMPORTS_TABLE_SIZE => {
+				return Err(anyhow!("Symbol {} is the wrong size", IMPORTS_TABLE_NAME))
+			},
+			Some(&t) => t,
+			None => AddressRange { start: layout.elf.start, size: 0 },
+		};
+		let imports = read_imports(imports_table, module_name);
+
 		Ok(ElfLoader {
+			name: module_name.to_string(),
+			addr: layout.elf,
 			sections,
 			exports,
-			entry_point: wbx.entry as usize,
+			entry_point: bias + wbx.entry as usize,
 			hash: bin::hash(data),
-			import_area
+			abi,
+			import_area,
+			imports_table,
+			imports,
+			fp_env: FpEnv::DEFAULT,
+			syscall_thunk,
+			thread_pointer,
+			modules: Vec::new(),
+			symbols: symbol_table(wbx, bias),
+			wasm: None,
+			load_bias: bias,
+			data_layout: data_layout(wbx),
 		})
 	}
+	pub fn new_wasm(module: wasm::Module, data: &[u8],
+		module_name: &str,
+		layout: &WbxSysLayout,
+		b: &mut ActivatedMemoryBlock
+	) -> anyhow::Result<ElfLoader> {
+		log!(Info, "Mouting wasm `{}` @{:x}", module_name, layout.elf.start);
+		let abi = GuestAbi::Wasm32;
+		let instance = wasm::Instance::new(module, layout, b)?;
+		let import_area = match (instance.export_address(IMPORTS_OBJECT_NAME), instance.personality()) {
+			(Some(start), _) => AddressRange { start, size: abi.sys_area_size() },
+			// nothing in a WASI guest would look at it
+			(None, wasm::Personality::Wasi) => AddressRange { start: layout.elf.start, size: 0 },
+			(None, wasm::Personality::Waterbox) => return Err(anyhow!("Symbol {} is missing", IMPORTS_OBJECT_NAME)),
+		};
+		if import_area.start < layout.elf.start || import_area.end() > layout.elf.end() {
+			return Err(anyhow!("{} is outside of the module's memory", IMPORTS_OBJECT_NAME))
+		}
+		let imports_table = match instance.export_address(IMPORTS_TABLE_NAME) {
+			Some(start) => AddressRange { start, size: imports::IMPORTS_TABLE_SIZE },
+			None => AddressRange { start: layout.elf.start, size: 0 },
+		};
+		if imports_table.start < layout.elf.start || imports_table.end() > layout.elf.end() {
+			return Err(anyhow!("{} is outside of the module's memory", IMPORTS_TABLE_NAME))
+		}
+		let imports = read_imports(imports_table, module_name);
+		b.mark_invisible(layout.invis)?;
+		Ok(ElfLoader {
+			name: module_name.to_string(),
+			addr: layout.elf,
+			sections: Vec::new(),
+			exports: HashMap::new(),
+			entry_point: 0,
+			hash: bin::hash(data),
+			abi,
+			import_area,
+			imports_table,
+			imports,
+			fp_env: FpEnv::DEFAULT,
+			syscall_thunk: None,
+			thread_pointer: 0,
+			modules: Vec::new(),
+			symbols: Vec::new(),
+			wasm: Some(instance),
+			load_bias: 0,
+			data_layout: Vec::new(),
+		})
+	}
+	/// Replace the main executable's code and read only data with those of `wbx`, a rebuild of it, keeping everything
+	/// the guest wrote.  Only works if none of the variables moved, and is for trying out changes to a core's code without
+	/// restarting it; function pointers the guest kept, saved return addresses included, still point into the old code.
+	pub fn reload(&mut self, wbx: &Elf, data: &[u8], b: &mut ActivatedMemoryBlock, sealed: bool) -> anyhow::Result<()> {
+		if self.wasm.is_some() {
+			return Err(coded(ErrorCode::Unsupported, "Wasm guests can't be reloaded"))
+		}
+		if !self.modules.is_empty() {
+			return Err(coded(ErrorCode::BadState, "Guests with modules loaded can't be reloaded"))
+		}
+		if GuestAbi::of(wbx, &self.name)? != self.abi || wbx.header.e_type == ET_DYN || self.load_bias != 0 {
+			return Err(coded(ErrorCode::Unsupported, "Only fixed address executables can be reloaded, with the same ABI"))
+		}
+		if ElfLoader::elf_addr(wbx, self.abi).align_expand() != self.addr || data_layout(wbx) != self.data_layout {
+			return Err(coded(ErrorCode::InvalidArgument,
+				format!("Variables in the new `{}` aren't where they were, so the guest's data can't be kept", self.name)))
+		}
+		log!(Info, "Reloading `{}`", self.name);
+		for segment in loaded_segments(wbx).filter(|x| !x.is_write()) {
+			let addr = AddressRange {
+				start: segment.vm_range().start,
+				size: segment.vm_range().end - segment.vm_range().start
+			};
+			b.mmap_fixed(addr.align_expand(), Protection::RW, false)?;
+			unsafe {
+				let dst = addr.slice_mut();
+				let (file, rest) = dst.split_at_mut(segment.p_filesz as usize);
+				file.copy_from_slice(&data[segment.file_range()]);
+				rest.iter_mut().for_each(|x| *x = 0);
+			}
+		}
+		// the same order as when it was first loaded, for pages that segments share
+		for segment in loaded_segments(wbx) {
+			let addr = AddressRange {
+				start: segment.vm_range().start,
+				size: segment.vm_range().end - segment.vm_range().start
+			};
+			b.mprotect(addr.align_expand(), segment_prot(segment))?;
+		}
+		self.sections = read_sections(wbx, 0);
+		self.exports = read_exports(wbx, 0).0;
+		self.entry_point = wbx.entry as usize;
+		self.hash = bin::hash(data);
+		self.symbols = symbol_table(wbx, 0);
+		if sealed {
+			self.protect_readonly_sections(b);
+		}
+		Ok(())
+	}
+	/// Load a position independent library from `data` into the mmap area.  Its undefined symbols are resolved against
+	/// the main executable's exports, then against other libraries in the order they were loaded.  Its own exports are
+	/// available through get_proc_addr() afterwards.  Returns the address it was loaded at.
+	pub fn load_module(&mut self, data: &[u8], module_name: &str, layout: &WbxSysLayout, b: &mut ActivatedMemoryBlock) -> anyhow::Result<usize> {
+		if self.wasm.is_some() {
+			return Err(anyhow!("Module `{}` can't be loaded into a wasm guest", module_name))
+		}
+		if self.modules.iter().any(|m| m.name == module_name) {
+			return Err(anyhow!("Module `{}` is already loaded", module_name))
+		}
+		let wbx = Elf::parse(data)?;
+		if GuestAbi::of(&wbx, module_name)? != self.abi {
+			return Err(anyhow!("Module `{}` was built for a different ABI than `{}`", module_name, self.name))
+		}
+		if wbx.header.e_type != ET_DYN {
+			return Err(anyhow!("Module `{}` is not position independent", module_name))
+		}
+		if wbx.program_headers.iter().any(|x| x.p_type == PT_TLS) {
+			return Err(anyhow!("Module `{}` uses TLS, which is only supported in the main executable", module_name))
+		}
+		let segments = wbx.program_headers.iter().filter(|x| x.p_type == PT_LOAD).collect::<Vec<_>>();
+		let size = match segments.iter().map(|x| x.vm_range().end).max() {
+			Some(end) => align_up(end),
+			None => return Err(anyhow!("Module `{}` has nothing to load", module_name))
+		};
+		let base = b.mmap(AddressRange { start: 0, size }, Protection::RW, layout.mmap, false)?;
+		log!(Info, "Mounting module `{}` @{:x}", module_name, base);
+		for segment in segments.iter() {
+			unsafe {
+				AddressRange { start: base + segment.p_vaddr as usize, size: segment.p_filesz as usize }
+					.slice_mut()
+					.copy_from_slice(&data[segment.file_range()]);
+			}
+		}
+
+		let mut exports = HashMap::new();
+		for sym in wbx.dynsyms.iter() {
+			if sym.st_shndx as u32 == SHN_UNDEF
+				|| sym.st_visibility() != STV_DEFAULT
+				|| sym.st_bind() != STB_GLOBAL && sym.st_bind() != STB_WEAK {
+				continue
+			}
+			if let Some(Ok(name)) = wbx.dynstrtab.get(sym.st_name) {
+				exports.insert(
+					name.to_string(),
+					AddressRange { start: base + sym.st_value as usize, size: sym.st_size as usize }
+				);
+			}
+		}
+		relocate(&wbx, base, self.abi, module_name, |name| self.resolve(name).or_else(|| exports.get(name).map(|a| a.start)))?;
+
+		for segment in segments.iter() {
+			let addr = AddressRange {
+				start: base + segment.vm_range().start,
+				size: segment.vm_range().end - segment.vm_range().start
+			};
+			b.mprotect(addr.align_expand(), segment_prot(segment))?;
+		}
+
+		if let Some(dynamic) = wbx.dynamic.as_ref() {
+			let info = &dynamic.info;
+			let mut inits = Vec::new();
+			if info.init != 0 {
+				inits.push(base + info.init as usize);
+			}
+			let size = self.abi.pointer_size();
+			for i in 0..info.init_arraysz / size {
+				inits.push(unsafe { self.abi.read_pointer(base + info.init_array as usize + i * size) });
+			}
+			for init in inits {
+				log!(Debug, "Calling init @{:x}", init);
+				let fp = GuestFp::enter(self.fp_env);
+				unsafe {
+					let _fs = threading::GuestFs::enter(self.thread_pointer);
+					std::mem::transmute::<usize, guest_abi!(fn() -> ())>(init)();
+				}
+				self.fp_env = fp.leave();
+			}
+		}
+
+		self.modules.push(Module {
+			name: module_name.to_string(),
+			base,
+			size,
+			exports,
+			symbols: symbol_table(&wbx, base),
+			hash: bin::hash(data),
+		});
+		Ok(base)
+	}
+	/// Everything that's loaded, and where
+	pub fn mapped_files(&self) -> Vec<coredump::MappedFile> {
+		std::iter::once(coredump::MappedFile { name: self.name.clone(), addr: self.addr })
+			.chain(self.modules.iter()
+				.map(|m| coredump::MappedFile { name: m.name.clone(), addr: AddressRange { start: m.base, size: m.size } }))
+			.collect()
+	}
+	/// Describe a guest address as `symbol+offset`, with the module it's in if that's not the main executable
+	pub fn symbolize(&self, addr: usize) -> Option<String> {
+		match self.modules.iter().find(|m| addr >= m.base && addr < m.base + m.size) {
+			Some(m) => Some(match lookup_symbol(&m.symbols, addr) {
+				Some(s) => format!("{} in `{}`", s, m.name),
+				None => format!("`{}`+{:#x}", m.name, addr - m.base),
+			}),
+			None => lookup_symbol(&self.symbols, addr),
+		}
+	}
+	/// Find an export of the main executable or any module
+	fn resolve(&self, name: &str) -> Option<usize> {
+		self.exports.get(name)
+			.or_else(|| self.modules.iter().find_map(|m| m.exports.get(name)))
+			.map(|a| a.start)
+	}
+	/// Find any function or data the guest has a symbol for, exported or not.  Only exported globals are known in wasm.
+	pub fn resolve_symbol(&self, name: &str) -> Option<usize> {
+		if let Some(w) = self.wasm.as_ref() {
+			return w.export_address(name)
+		}
+		self.resolve(name)
+			.or_else(|| find_symbol(&self.symbols, name))
+			.or_else(|| self.modules.iter().find_map(|m| find_symbol(&m.symbols, name)))
+	}
+	pub fn abi(&self) -> GuestAbi {
+		self.abi
+	}
+	/// The main thread's thread pointer, or 0 if the guest doesn't use TLS
+	pub fn thread_pointer(&self) -> usize {
+		self.thread_pointer
+	}
 	pub fn pre_seal(&mut self, b: &mut ActivatedMemoryBlock) {
 		self.run_proc(b, "co_clean");
 		self.run_proc(b, "ecl_seal");
+		self.protect_readonly_sections(b);
+		self.clear_syscalls(b);
+	}
+	fn protect_readonly_sections(&mut self, b: &mut ActivatedMemoryBlock) {
 		for section in self.sections.iter() {
 			if section_name_is_readonly(section.name.as_str()) {
 				b.mprotect(section.addr.align_expand(), Protection::R).unwrap();
 			}
 		}
-		self.clear_syscalls(b);
+	}
+	/// Undo what pre_seal() protected, so a developer can change it.  The next pre_seal() protects it again.
+	#[cfg(feature = "dev-unseal")]
+	pub fn unprotect_sealed(&mut self, b: &mut ActivatedMemoryBlock, layout: &WbxSysLayout) -> syscall_defs::SyscallResult {
+		for section in self.sections.iter() {
+			if section_name_is_readonly(section.name.as_str()) {
+				b.make_writable(section.addr.align_expand())?;
+			}
+		}
+		b.make_writable(layout.sealed)
 	}
 	pub fn connect_syscalls(&mut self, _b: &mut ActivatedMemoryBlock, sys: &WbxSysArea) {
 		let addr = self.import_area;
-		unsafe { *(addr.start as *mut WbxSysArea) = *sys; }
+		unsafe {
+			let thunk = match self.syscall_thunk.as_ref() {
+				Some(t) => {
+					t.connect(sys.syscall.ud, sys.syscall.syscall as usize);
+					t.addr()
+				},
+				None => 0,
+			};
+			if let Some(w) = self.wasm.as_mut() {
+				w.connect(sys.syscall.ud);
+			}
+			if addr.size != 0 {
+				self.abi.write_sys_area(addr.start, sys, thunk);
+			}
+			imports::write_host(self.imports_table);
+		}
+	}
+	/// What the guest and the host said in the imports handshake
+	pub fn imports(&self) -> ImportInfo {
+		self.imports
+	}
+	/// The guest's floating point environment, as of when it last stopped running
+	pub fn fp_env(&self) -> FpEnv {
+		self.fp_env
+	}
+	pub fn set_fp_env(&mut self, env: FpEnv) {
+		self.fp_env = env.normalized();
 	}
 	fn clear_syscalls(&mut self, _b: &mut ActivatedMemoryBlock) {
 		let addr = self.import_area;
 		unsafe { addr.zero(); }
 	}
-	pub fn native_init(&mut self, _b: &mut ActivatedMemoryBlock) {
-		println!("Calling _start()");
+	/// Run the guest's startup code, with a start block made by startup::write_start_block() if it's native code
+	pub fn native_init(&mut self, _b: &mut ActivatedMemoryBlock, start_block: usize, start_info: StartInfo) {
+		if let Some(w) = self.wasm.as_mut() {
+			log!(Debug, "Calling wasm start functions");
+			w.set_start_info(start_info);
+			let fp = GuestFp::enter(self.fp_env);
+			w.init();
+			self.fp_env = fp.leave();
+			return
+		}
+		log!(Debug, "Calling _start()");
+		let fp = GuestFp::enter(self.fp_env);
 		unsafe {
-			std::mem::transmute::<usize, extern "sysv64" fn() -> ()>(self.entry_point)();
+			let _fs = threading::GuestFs::enter(self.thread_pointer);
+			std::mem::transmute::<usize, guest_abi!(fn(start_block: usize) -> ())>(self.entry_point)(start_block);
 		}
+		self.fp_env = fp.leave();
 	}
 	fn run_proc(&mut self, _b: &mut ActivatedMemoryBlock, name: &str) {
 		match self.get_proc_addr(name) {
 			0 => (),
 			ptr => {
-				println!("Calling {}()", name);
+				log!(Debug, "Calling {}()", name);
+				let fp = GuestFp::enter(self.fp_env);
 				unsafe {
-					std::mem::transmute::<usize, extern "sysv64" fn() -> ()>(ptr)();
+					let _fs = threading::GuestFs::enter(self.thread_pointer);
+					std::mem::transmute::<usize, guest_abi!(fn() -> ())>(ptr)();
 				}
+				self.fp_env = fp.leave();
 			},
 		}
 	}
 	pub fn get_proc_addr(&self, proc: &str) -> usize {
-		match self.exports.get(proc) {
-			Some(addr) => addr.start,
-			None => 0,
+		match self.wasm.as_ref() {
+			Some(w) => w.proc_addr(proc),
+			None => self.resolve(proc).unwrap_or(0),
 		}
 	}
 }
@@ -223,11 +803,79 @@ impl IStateable for ElfLoader {
 	fn save_state(&mut self, stream: &mut dyn Write) -> anyhow::Result<()> {
 		bin::write_magic(stream, MAGIC)?;
 		bin::write_hash(stream, &self.hash[..])?;
+		bin::write(stream, &self.thread_pointer)?;
+		bin::writeval(stream, self.modules.len())?;
+		for m in self.modules.iter() {
+			bin::write_magic(stream, &m.name)?;
+			bin::write_hash(stream, &m.hash[..])?;
+			bin::write(stream, &m.base)?;
+		}
+		if let Some(w) = self.wasm.as_mut() {
+			w.save_state(stream)?;
+		}
 		Ok(())
 	}
 	fn load_state(&mut self, stream: &mut dyn Read) -> anyhow::Result<()> {
 		bin::verify_magic(stream, MAGIC)?;
 		bin::verify_hash(stream, &self.hash[..])?;
+		bin::read(stream, &mut self.thread_pointer)?;
+		if bin::readval::<usize>(stream)? != self.modules.len() {
+			return Err(anyhow!("Savestate has a different set of modules loaded"))
+		}
+		for m in self.modules.iter() {
+			bin::verify_magic(stream, &m.name)?;
+			bin::verify_hash(stream, &m.hash[..])?;
+			if bin::readval::<usize>(stream)? != m.base {
+				return Err(anyhow!("Module `{}` was loaded somewhere else in the savestate", m.name))
+			}
+		}
+		if let Some(w) = self.wasm.as_mut() {
+			w.load_state(stream)?;
+		}
 		Ok(())
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_tls_layout() {
+		assert_eq!(tls_layout(0x24, 8), (0x1000, 0x30));
+		assert_eq!(tls_layout(0x1000, 0x40), (0x2000, 0x1000));
+		let (size, tp) = tls_layout(0x10, 0x4000);
+		assert_eq!(tp, 0x4000);
+		assert!(size >= 0x3000 + tp + TCB_SIZE);
+	}
+
+	#[test]
+	fn test_lookup_symbol() {
+		let symbols = vec![
+			(AddressRange { start: 0x1000, size: 0x20 }, "foo".to_string()),
+			(AddressRange { start: 0x1040, size: 0x10 }, "bar".to_string()),
+		];
+		assert_eq!(lookup_symbol(&symbols, 0x1000), Some("foo+0x0".to_string()));
+		assert_eq!(lookup_symbol(&symbols, 0x104f), Some("bar+0xf".to_string()));
+		assert_eq!(lookup_symbol(&symbols, 0x1020), None);
+		assert_eq!(lookup_symbol(&symbols, 0xfff), None);
+		assert_eq!(lookup_symbol(&symbols, 0x1050), None);
+		assert_eq!(find_symbol(&symbols, "bar"), Some(0x1040));
+		assert_eq!(find_symbol(&symbols, "baz"), None);
+	}
+
+	#[test]
+	#[cfg(target_arch = "x86_64")]
+	fn test_relocation_value() {
+		assert_eq!(relocation_value(R_X86_64_RELATIVE, 0x10000, 0, 0x123).unwrap(), Some(0x10123));
+		assert_eq!(relocation_value(R_X86_64_64, 0x10000, 0x5000, -8).unwrap(), Some(0x4ff8));
+		assert_eq!(relocation_value(R_X86_64_JUMP_SLOT, 0x10000, 0x5000, 0).unwrap(), Some(0x5000));
+		assert_eq!(relocation_value(R_X86_64_NONE, 0x10000, 0, 0).unwrap(), None);
+		assert!(relocation_value(R_X86_64_IRELATIVE, 0x10000, 0, 0).is_err());
+		assert_eq!(relocation_value(R_X86_64_RELATIVE64, 0x10000, 0, 0x10).unwrap(), Some(0x10010));
+		assert_eq!(relocation_size(R_X86_64_RELATIVE, GuestAbi::Ilp32), 4);
+		assert_eq!(relocation_size(R_X86_64_RELATIVE64, GuestAbi::Ilp32), 8);
+		assert_eq!(relocation_size(R_X86_64_32, GuestAbi::Lp64), 4);
+		assert_eq!(relocation_size(R_X86_64_JUMP_SLOT, GuestAbi::Lp64), 8);
+	}
+}
@@ -0,0 +1,109 @@
+// Randomness for cores, through NR_WBX_ENTROPY.  getrandom(2) is deterministic too, but libc and whatever else a core
+// links draw from it for their own reasons, so what a core gets from it depends on what else ran first.  This is a
+// stream only the core draws from:  SHA-256 in counter mode over a key made from the frontend's seed, so the same seed
+// always gives the same bytes however they're asked for, and its position is in savestates.  Frontends can reseed it
+// every frame, from the movie's seed and the frame number, say, so that it can't drift.  It's not for secrets.
+use crate::*;
+use sha2::{Sha256, Digest};
+use std::io::{Read, Write};
+
+const BLOCK: usize = 32;
+
+pub struct Entropy {
+	key: [u8; BLOCK],
+	/// Of the next block
+	counter: u64,
+	/// What's left of the last block is its end
+	block: [u8; BLOCK],
+	used: usize,
+}
+impl Entropy {
+	/// As seeded with nothing
+	pub fn new() -> Entropy {
+		let mut res = Entropy { key: [0; BLOCK], counter: 0, block: [0; BLOCK], used: BLOCK };
+		res.seed(&[]);
+		res
+	}
+	/// Start over from `seed`, forgetting everything before
+	pub fn seed(&mut self, seed: &[u8]) {
+		let mut hasher = Sha256::new();
+		hasher.update(b"WbxEntropy");
+		hasher.update(seed);
+		self.key.copy_from_slice(&hasher.finalize()[..]);
+		self.counter = 0;
+		self.used = BLOCK;
+	}
+	pub fn fill(&mut self, buf: &mut [u8]) {
+		let mut done = 0;
+		while done < buf.len() {
+			if self.used == BLOCK {
+				let mut hasher = Sha256::new();
+				hasher.update(self.key);
+				hasher.update(self.counter.to_le_bytes());
+				self.block.copy_from_slice(&hasher.finalize()[..]);
+				self.counter += 1;
+				self.used = 0;
+			}
+			let n = std::cmp::min(buf.len() - done, BLOCK - self.used);
+			buf[done..done + n].copy_from_slice(&self.block[self.used..self.used + n]);
+			self.used += n;
+			done += n;
+		}
+	}
+}
+impl IStateable for Entropy {
+	fn save_state(&mut self, stream: &mut dyn Write) -> anyhow::Result<()> {
+		bin::write_magic(stream, "Entropy")?;
+		bin::write(stream, &self.key)?;
+		bin::write(stream, &self.counter)?;
+		bin::write(stream, &self.block)?;
+		bin::writeval(stream, self.used as u64)?;
+		Ok(())
+	}
+	fn load_state(&mut self, stream: &mut dyn Read) -> anyhow::Result<()> {
+		bin::verify_magic(stream, "Entropy")?;
+		bin::read(stream, &mut self.key)?;
+		bin::read(stream, &mut self.counter)?;
+		bin::read(stream, &mut self.block)?;
+		let used = bin::readval::<u64>(stream)? as usize;
+		if used > BLOCK {
+			return Err(coded(ErrorCode::BadStateData, "Entropy position is past its block"))
+		}
+		self.used = used;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_stream() -> anyhow::Result<()> {
+		let mut e = Entropy::new();
+		e.seed(b"movie");
+		let mut all = [0u8; 100];
+		e.fill(&mut all);
+		// the same bytes however they're asked for
+		e.seed(b"movie");
+		let mut pieces = [0u8; 100];
+		for chunk in pieces.chunks_mut(7) {
+			e.fill(chunk);
+		}
+		assert_eq!(all[..], pieces[..]);
+		e.seed(b"other");
+		e.fill(&mut pieces);
+		assert_ne!(all[..], pieces[..]);
+
+		// and from a state picked up partway through
+		e.seed(b"movie");
+		e.fill(&mut pieces[..45]);
+		let mut state = Vec::new();
+		e.save_state(&mut state)?;
+		let mut loaded = Entropy::new();
+		loaded.load_state(&mut &state[..])?;
+		loaded.fill(&mut pieces[45..]);
+		assert_eq!(all[..], pieces[..]);
+		Ok(())
+	}
+}
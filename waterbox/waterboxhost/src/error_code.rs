@@ -0,0 +1,109 @@
+// Stable numbers for the kinds of errors the C interface reports, so that frontends can act on a failure without
+// parsing its message.  Errors that know their kind are made with coded(); the rest are classified by what they
+// wrap, and anything unrecognized is Other.  Numbers are part of the interface:  Never change or reuse one, and add
+// new kinds at the end.
+use crate::*;
+use memory_block::OutOfMemory;
+use state_format::StateError;
+use std::fmt;
+
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+	/// No error
+	None = 0,
+	Other = 1,
+	/// The host isn't in the right state for the call, like not being sealed yet
+	BadState = 2,
+	InvalidArgument = 3,
+	/// An address or range isn't inside guest memory
+	UnmappedAddress = 4,
+	/// Not available on this platform, or with this core
+	Unsupported = 5,
+	OutOfMemory = 6,
+	/// A guest call was cancelled with wbx_cancel_guest_call
+	Cancelled = 7,
+	/// A guest call ran past the watchdog's limit
+	TimedOut = 8,
+	/// The guest trapped, and its call was abandoned
+	Trapped = 9,
+	/// A savestate came from a different core, version, or set of features
+	StateMismatch = 10,
+	/// A savestate is damaged, or isn't one
+	BadStateData = 11,
+	/// Reading or writing through a callback, or a host file, failed
+	Io = 12,
+	/// Guest memory is unusable after an OS failure
+	Poisoned = 13,
+	/// No file, watchpoint, cheat, or other object by that name or id
+	NotFound = 14,
+	/// The guest aborted, and its call was abandoned
+	Aborted = 15,
+	/// A replay went differently from its recording
+	Desync = 16,
+	/// A ROM region no longer matches its hash
+	Corrupted = 17,
+}
+
+/// An error with a known ErrorCode
+#[derive(Debug)]
+pub struct CodedError {
+	pub code: ErrorCode,
+	pub message: String,
+}
+impl fmt::Display for CodedError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.message)
+	}
+}
+impl std::error::Error for CodedError {}
+
+pub fn coded(code: ErrorCode, message: impl Into<String>) -> anyhow::Error {
+	anyhow::Error::new(CodedError { code, message: message.into() })
+}
+
+impl ErrorCode {
+	/// The kind of an error, going by the outermost cause that has one
+	pub fn of(e: &anyhow::Error) -> ErrorCode {
+		for cause in e.chain() {
+			if let Some(c) = cause.downcast_ref::<CodedError>() {
+				return c.code
+			}
+			if cause.downcast_ref::<OutOfMemory>().is_some() {
+				return ErrorCode::OutOfMemory
+			}
+			if let Some(s) = cause.downcast_ref::<StateError>() {
+				return match s {
+					StateError::NotAState => ErrorCode::BadStateData,
+					_ => ErrorCode::StateMismatch,
+				}
+			}
+			if let Some(io) = cause.downcast_ref::<std::io::Error>() {
+				return match io.kind() {
+					// a state or file that stops short
+					std::io::ErrorKind::UnexpectedEof => ErrorCode::BadStateData,
+					_ => ErrorCode::Io,
+				}
+			}
+		}
+		ErrorCode::Other
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_of() {
+		assert_eq!(ErrorCode::of(&coded(ErrorCode::BadState, "Not sealed!")), ErrorCode::BadState);
+		assert_eq!(ErrorCode::of(&anyhow!("Something else")), ErrorCode::Other);
+		assert_eq!(ErrorCode::of(&anyhow::Error::new(OutOfMemory)), ErrorCode::OutOfMemory);
+		assert_eq!(ErrorCode::of(&StateError::WrongCore { state: None, host: "`x`".to_string() }.into()), ErrorCode::StateMismatch);
+		let eof = std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "short");
+		assert_eq!(ErrorCode::of(&eof.into()), ErrorCode::BadStateData);
+		// context doesn't hide the cause
+		let wrapped = anyhow::Error::new(OutOfMemory).context("Loading the state");
+		assert_eq!(ErrorCode::of(&wrapped), ErrorCode::OutOfMemory);
+	}
+}
@@ -0,0 +1,225 @@
+// Extra stacks for a core to run on, for script interpreters and the like that recurse deeper than the guest stack
+// allows, or that want coroutines.  NR_WBX_CREATE_FIBER maps a stack in the mmap area and sets up a context that starts
+// on it, NR_WBX_SWITCH_FIBER parks the calling thread's context and resumes the fiber's instead, the way swapcontext(3)
+// would, and NR_WBX_DESTROY_FIBER unmaps it again.  Fiber 0 is always the stack the thread started on.  A fiber runs on
+// whichever thread switched to it, and only one at a time.  The stacks are guest memory, so they're in states already;
+// the parked contexts, and which fiber each thread is on, are in them here.
+use crate::*;
+use syscall_defs::*;
+use threading::{GuestContext, ThreadId};
+use std::io::{Read, Write};
+
+/// More than this are turned down
+const MAX_FIBERS: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FiberState {
+	/// Parked, waiting for something to switch to it
+	Suspended,
+	Running(ThreadId),
+	/// Its thread exited while it was running, so it can only be destroyed
+	Dead,
+}
+
+#[derive(Debug)]
+struct Fiber {
+	id: u32,
+	/// Not including the guard page below
+	stack: AddressRange,
+	/// Where it resumes, if it's suspended
+	ctx: GuestContext,
+	state: FiberState,
+}
+
+#[derive(Default)]
+pub struct Fibers {
+	fibers: Vec<Fiber>,
+	next_id: u32,
+	/// Where each thread that's on a fiber left its own stack
+	homes: Vec<(ThreadId, GuestContext)>,
+	/// Set by switch() for the syscall to switch_to() on its way out.  Never in states, as it never outlives the
+	/// syscall.
+	pending: Option<GuestContext>,
+}
+impl Fibers {
+	pub fn full(&self) -> bool {
+		self.fibers.len() >= MAX_FIBERS
+	}
+	/// Add a fiber on `stack`, which starts at `entry` with `func` and `arg` in the registers
+	/// GuestContext::starting_at() puts them in.  Returns its id.
+	pub fn create(&mut self, stack: AddressRange, entry: usize, func: usize, arg: usize) -> u32 {
+		self.next_id += 1;
+		let id = self.next_id;
+		self.fibers.push(Fiber {
+			id,
+			stack,
+			ctx: GuestContext::starting_at(entry, stack.end(), func, arg),
+			state: FiberState::Suspended,
+		});
+		id
+	}
+	/// Drop a fiber that isn't running, and return its stack for the caller to unmap
+	pub fn destroy(&mut self, id: usize) -> Result<AddressRange, SyscallError> {
+		let index = self.index(id)?;
+		if let FiberState::Running(_) = self.fibers[index].state {
+			return Err(EBUSY)
+		}
+		Ok(self.fibers.remove(index).stack)
+	}
+	/// The fiber thread `tid` is running on, or 0 for its own stack
+	pub fn current(&self, tid: ThreadId) -> u32 {
+		self.fibers.iter().find(|f| f.state == FiberState::Running(tid)).map_or(0, |f| f.id)
+	}
+	pub fn count(&self) -> usize {
+		self.fibers.len()
+	}
+	fn index(&self, id: usize) -> Result<usize, SyscallError> {
+		self.fibers.iter().position(|f| f.id as usize == id).ok_or(ENOENT)
+	}
+	/// Have thread `tid`, which is in a syscall that would return to `ctx`, go on with fiber `id` instead.  Where it
+	/// was is parked, to see the switch return 0 when it's switched back to.  The syscall picks up the switch with
+	/// take_switch().
+	pub fn switch(&mut self, tid: ThreadId, ctx: &GuestContext, id: usize) -> SyscallResult {
+		let from = self.current(tid);
+		if id == from as usize {
+			return Ok(())
+		}
+		let target = if id == 0 {
+			let home = self.homes.iter().position(|h| h.0 == tid).ok_or(EINVAL)?;
+			self.homes.remove(home).1
+		} else {
+			let index = self.index(id)?;
+			let f = &mut self.fibers[index];
+			match f.state {
+				FiberState::Suspended => (),
+				FiberState::Running(_) => return Err(EBUSY),
+				FiberState::Dead => return Err(EINVAL),
+			}
+			f.state = FiberState::Running(tid);
+			f.ctx
+		};
+		let mut parked = *ctx;
+		parked.set_return(0);
+		if from == 0 {
+			self.homes.push((tid, parked));
+		} else {
+			let index = self.index(from as usize).unwrap();
+			let f = &mut self.fibers[index];
+			f.ctx = parked;
+			f.state = FiberState::Suspended;
+		}
+		self.pending = Some(target);
+		Ok(())
+	}
+	/// The context the current thread has to go on in, if the syscall it's in switched fibers
+	pub fn take_switch(&mut self) -> Option<GuestContext> {
+		self.pending.take()
+	}
+	/// Thread `tid` is gone, and whatever fiber it was on along with it
+	pub fn thread_exited(&mut self, tid: ThreadId) {
+		self.homes.retain(|h| h.0 != tid);
+		for f in self.fibers.iter_mut().filter(|f| f.state == FiberState::Running(tid)) {
+			f.state = FiberState::Dead;
+		}
+	}
+}
+impl IStateable for Fibers {
+	fn save_state(&mut self, stream: &mut dyn Write) -> anyhow::Result<()> {
+		bin::write_magic(stream, "Fibers")?;
+		bin::write(stream, &self.next_id)?;
+		bin::writeval(stream, self.fibers.len() as u64)?;
+		for f in self.fibers.iter_mut() {
+			bin::write(stream, &f.id)?;
+			f.stack.save_state(stream)?;
+			bin::write(stream, &f.ctx)?;
+			let (state, tid) = match f.state {
+				FiberState::Suspended => (0u32, 0),
+				FiberState::Running(tid) => (1, tid),
+				FiberState::Dead => (2, 0),
+			};
+			bin::write(stream, &state)?;
+			bin::write(stream, &tid)?;
+		}
+		bin::writeval(stream, self.homes.len() as u64)?;
+		for (tid, ctx) in self.homes.iter() {
+			bin::write(stream, tid)?;
+			bin::write(stream, ctx)?;
+		}
+		Ok(())
+	}
+	fn load_state(&mut self, stream: &mut dyn Read) -> anyhow::Result<()> {
+		bin::verify_magic(stream, "Fibers")?;
+		self.fibers.clear();
+		self.homes.clear();
+		self.pending = None;
+		bin::read(stream, &mut self.next_id)?;
+		let count = bin::readval::<u64>(stream)? as usize;
+		if count > MAX_FIBERS {
+			return Err(coded(ErrorCode::BadStateData, "Too many fibers in state"))
+		}
+		for _ in 0..count {
+			let id = bin::readval(stream)?;
+			let mut stack = AddressRange { start: 0, size: 0 };
+			stack.load_state(stream)?;
+			let ctx = bin::readval(stream)?;
+			let state = bin::readval::<u32>(stream)?;
+			let tid = bin::readval(stream)?;
+			let state = match state {
+				0 => FiberState::Suspended,
+				1 => FiberState::Running(tid),
+				2 => FiberState::Dead,
+				_ => return Err(coded(ErrorCode::BadStateData, "Bad fiber state")),
+			};
+			self.fibers.push(Fiber { id, stack, ctx, state });
+		}
+		for _ in 0..bin::readval::<u64>(stream)? {
+			let tid = bin::readval(stream)?;
+			let ctx = bin::readval(stream)?;
+			self.homes.push((tid, ctx));
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_fibers() -> anyhow::Result<()> {
+		let mut fibers = Fibers::default();
+		let stack = AddressRange { start: 0x20000, size: 0x4000 };
+		let a = fibers.create(stack, 0x1000, 0x1100, 77);
+		let b = fibers.create(AddressRange { start: 0x30000, size: 0x4000 }, 0x1000, 0x1200, 78);
+		assert_eq!((a, b), (1, 2));
+		let home = GuestContext::starting_at(0x5000, 0x9000, 0, 0);
+		fibers.switch(1, &home, a as usize)?;
+		let ctx = fibers.take_switch().unwrap();
+		assert_eq!((ctx.pc(), ctx.stack_pointer()), (0x1000, stack.end()));
+		assert_eq!(fibers.current(1), a);
+		assert_eq!(fibers.take_switch(), None);
+		// nobody else can have it, or destroy it, while it's running
+		assert_eq!(fibers.switch(2, &home, a as usize), Err(EBUSY));
+		assert_eq!(fibers.destroy(a as usize), Err(EBUSY));
+		assert_eq!(fibers.switch(1, &ctx, 9), Err(ENOENT));
+		let mut state = Vec::new();
+		fibers.save_state(&mut state)?;
+		let in_a = GuestContext::starting_at(0x1080, stack.end() - 0x100, 0, 0);
+		fibers.switch(1, &in_a, 0)?;
+		let back = fibers.take_switch().unwrap();
+		assert_eq!(back.pc(), 0x5000);
+		assert_eq!(fibers.current(1), 0);
+		fibers.switch(1, &back, a as usize)?;
+		let ctx = fibers.take_switch().unwrap();
+		assert_eq!((ctx.pc(), ctx.stack_pointer()), (0x1080, stack.end() - 0x100));
+		// the state from before still has the thread just starting on a
+		fibers.load_state(&mut &state[..])?;
+		assert_eq!(fibers.current(1), a);
+		assert_eq!(fibers.count(), 2);
+		fibers.thread_exited(1);
+		assert_eq!(fibers.switch(2, &home, a as usize), Err(EINVAL));
+		assert_eq!(fibers.destroy(a as usize)?, stack);
+		assert_eq!(fibers.create(stack, 0x1000, 0, 0), 3);
+		Ok(())
+	}
+}
@@ -0,0 +1,135 @@
+// The guest's floating point environment:  rounding, flushing denormals to zero and which exceptions are masked.  The
+// host process shares these registers with the guest, and its own code, .NET especially, can change them whenever it
+// likes, which would make the same guest code give different answers from one run to the next.  So the guest gets its
+// own, which starts out as the defaults, goes in on every call into the guest and comes back out again afterwards, is
+// put back after every syscall, and is in savestates.
+
+/// On x86_64, MXCSR and the x87 control word.  On aarch64, FPCR and nothing.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FpEnv {
+	pub control: u32,
+	pub x87: u32,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl FpEnv {
+	/// Round to nearest, no flushing, all exceptions masked
+	pub const DEFAULT: FpEnv = FpEnv { control: 0x1f80, x87: 0x037f };
+	/// Just the control bits, as setting others would fault.  The exception flags are dropped too, or whatever the host
+	/// did in a syscall would show up in them.
+	pub fn normalized(self) -> FpEnv {
+		FpEnv { control: self.control & 0xffc0, x87: (self.x87 & 0x1f7f) | 0x40 }
+	}
+}
+#[cfg(target_arch = "aarch64")]
+impl FpEnv {
+	pub const DEFAULT: FpEnv = FpEnv { control: 0, x87: 0 };
+	pub fn normalized(self) -> FpEnv {
+		FpEnv { control: self.control & 0x07f79f00, x87: 0 }
+	}
+}
+
+#[cfg(target_arch = "x86_64")]
+global_asm!(r#"
+.intel_syntax noprefix
+.text
+.globl wbx_get_fp_env
+wbx_get_fp_env:
+	stmxcsr [rdi]
+	fnstcw [rdi + 4]
+	mov word ptr [rdi + 6], 0
+	ret
+.globl wbx_set_fp_env
+wbx_set_fp_env:
+	ldmxcsr [rdi]
+	fldcw [rdi + 4]
+	ret
+.att_syntax
+"#);
+
+#[cfg(target_arch = "aarch64")]
+global_asm!(r#"
+.text
+.globl wbx_get_fp_env
+wbx_get_fp_env:
+	mrs x9, fpcr
+	stp w9, wzr, [x0]
+	ret
+.globl wbx_set_fp_env
+wbx_set_fp_env:
+	ldr w9, [x0]
+	msr fpcr, x9
+	ret
+"#);
+
+guest_abi! {{
+	fn wbx_get_fp_env(env: *mut FpEnv);
+	fn wbx_set_fp_env(env: *const FpEnv);
+}}
+
+impl FpEnv {
+	/// What this thread has now
+	pub fn get() -> FpEnv {
+		let mut res = FpEnv::DEFAULT;
+		unsafe { wbx_get_fp_env(&mut res) }
+		res
+	}
+	/// Give this thread this environment, normalized
+	pub fn set(self) {
+		let env = self.normalized();
+		unsafe { wbx_set_fp_env(&env) }
+	}
+}
+impl Default for FpEnv {
+	fn default() -> FpEnv {
+		FpEnv::DEFAULT
+	}
+}
+
+/// Runs guest code with the guest's environment, and puts the host's back afterwards
+pub struct GuestFp {
+	host: FpEnv,
+}
+impl GuestFp {
+	pub fn enter(guest: FpEnv) -> GuestFp {
+		let host = FpEnv::get();
+		guest.set();
+		GuestFp { host }
+	}
+	/// Put the host's environment back, and return what the guest left in
+	pub fn leave(self) -> FpEnv {
+		FpEnv::get().normalized()
+	}
+}
+impl Drop for GuestFp {
+	fn drop(&mut self) {
+		self.host.set();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	#[cfg(target_arch = "x86_64")]
+	fn test_guest_fp() {
+		let host = FpEnv::get();
+		// round towards zero
+		let chopped = FpEnv { control: FpEnv::DEFAULT.control | 0x6000, x87: FpEnv::DEFAULT.x87 | 0xc00 };
+		let fp = GuestFp::enter(chopped);
+		let (one, ten, mut tenth) = (1f64, 10f64, 0f64);
+		// volatile, so the division can't be moved past leave()
+		unsafe { std::ptr::write_volatile(&mut tenth, std::ptr::read_volatile(&one) / std::ptr::read_volatile(&ten)) }
+		let left = fp.leave();
+		assert_eq!(FpEnv::get(), host);
+		// which rounds up to nearest
+		assert!(tenth < 0.1);
+		assert_eq!(left, chopped);
+
+		let fp = GuestFp::enter(FpEnv::DEFAULT);
+		assert_eq!(fp.leave(), FpEnv::DEFAULT);
+		assert_eq!(FpEnv { control: 0xffff_ffff, x87: 0xffff_ffff }.normalized(), FpEnv { control: 0xffc0, x87: 0x1f7f });
+	}
+}
@@ -0,0 +1,138 @@
+use std::io::{self, Read};
+use sha2::{Sha256, Digest};
+
+/// How much of a file goes in each separate allocation
+const CHUNK_SIZE: usize = 1 << 20;
+
+/// File content split up into fixed size allocations, so that even a disc image of several gigabytes can be held
+/// without finding room for all of it in one piece.
+#[derive(Default)]
+pub struct ChunkedData {
+	chunks: Vec<Vec<u8>>,
+	len: usize,
+}
+impl ChunkedData {
+	pub fn from_reader(reader: &mut dyn Read) -> io::Result<ChunkedData> {
+		let mut res = ChunkedData::default();
+		loop {
+			let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+			reader.take(CHUNK_SIZE as u64).read_to_end(&mut chunk)?;
+			let n = chunk.len();
+			if n == 0 {
+				break
+			}
+			res.len += n;
+			res.chunks.push(chunk);
+			if n < CHUNK_SIZE {
+				break
+			}
+		}
+		Ok(res)
+	}
+	pub fn size(&self) -> usize {
+		self.len
+	}
+	/// Copy out as much as is there starting at `pos`, returning how much that was
+	pub fn read_at(&self, pos: usize, buf: &mut [u8]) -> usize {
+		let n = std::cmp::min(buf.len(), self.len.saturating_sub(pos));
+		let mut done = 0;
+		while done < n {
+			let p = pos + done;
+			let chunk = &self.chunks[p / CHUNK_SIZE];
+			let offset = p % CHUNK_SIZE;
+			let count = std::cmp::min(n - done, chunk.len() - offset);
+			buf[done..done + count].copy_from_slice(&chunk[offset..offset + count]);
+			done += count;
+		}
+		n
+	}
+	/// Copy in all of buf at `pos`, growing if needed
+	pub fn write_at(&mut self, pos: usize, buf: &[u8]) {
+		if pos + buf.len() > self.len {
+			self.resize(pos + buf.len());
+		}
+		let mut done = 0;
+		while done < buf.len() {
+			let p = pos + done;
+			let chunk = &mut self.chunks[p / CHUNK_SIZE];
+			let offset = p % CHUNK_SIZE;
+			let count = std::cmp::min(buf.len() - done, chunk.len() - offset);
+			chunk[offset..offset + count].copy_from_slice(&buf[done..done + count]);
+			done += count;
+		}
+	}
+	/// Grow with zeros or shrink to exactly `len`
+	pub fn resize(&mut self, len: usize) {
+		let nchunks = len.div_ceil(CHUNK_SIZE);
+		self.chunks.truncate(nchunks);
+		while self.chunks.len() < nchunks {
+			self.chunks.push(Vec::new());
+		}
+		// everything before the shorter of the two ends is already full
+		let first = std::cmp::min(self.len, len) / CHUNK_SIZE;
+		for (i, chunk) in self.chunks.iter_mut().enumerate().skip(first) {
+			chunk.resize(std::cmp::min(CHUNK_SIZE, len - i * CHUNK_SIZE), 0);
+		}
+		self.len = len;
+	}
+	/// The same as bin::hash of all of the content together
+	pub fn hash(&self) -> Vec<u8> {
+		let mut hasher = Sha256::new();
+		for chunk in self.chunks.iter() {
+			hasher.update(&chunk[..]);
+		}
+		hasher.finalize()[..].to_owned()
+	}
+	pub fn into_vec(self) -> Vec<u8> {
+		let mut res = Vec::with_capacity(self.len);
+		for chunk in self.chunks {
+			res.extend(chunk);
+		}
+		res
+	}
+}
+impl From<Vec<u8>> for ChunkedData {
+	fn from(data: Vec<u8>) -> ChunkedData {
+		if data.len() <= CHUNK_SIZE {
+			let len = data.len();
+			let chunks = if len == 0 { Vec::new() } else { vec![data] };
+			return ChunkedData { chunks, len }
+		}
+		ChunkedData::from_reader(&mut &data[..]).unwrap()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_chunked() -> io::Result<()> {
+		let data = (0..CHUNK_SIZE * 2 + 100).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+		let mut c = ChunkedData::from_reader(&mut &data[..])?;
+		assert_eq!(c.chunks.len(), 3);
+		assert_eq!(c.size(), data.len());
+		assert_eq!(c.hash(), crate::bin::hash(&data[..]));
+
+		// reads and writes that cross chunks
+		let mut buf = vec![0u8; 300];
+		assert_eq!(c.read_at(CHUNK_SIZE - 150, &mut buf[..]), 300);
+		assert_eq!(&buf[..], &data[CHUNK_SIZE - 150..CHUNK_SIZE + 150]);
+		assert_eq!(c.read_at(data.len() - 10, &mut buf[..]), 10);
+		assert_eq!(c.read_at(data.len() + 10, &mut buf[..]), 0);
+		c.write_at(CHUNK_SIZE * 2 - 1, &[7u8; 300]);
+		assert_eq!(c.size(), CHUNK_SIZE * 2 + 299);
+		assert_eq!(c.read_at(CHUNK_SIZE * 2 - 2, &mut buf[..3]), 3);
+		assert_eq!(&buf[..3], &[data[CHUNK_SIZE * 2 - 2], 7, 7]);
+
+		c.resize(CHUNK_SIZE + 1);
+		assert_eq!(c.chunks.len(), 2);
+		c.resize(CHUNK_SIZE + 5);
+		assert_eq!(c.read_at(CHUNK_SIZE, &mut buf[..]), 5);
+		assert_eq!(&buf[..5], &[data[CHUNK_SIZE], 0, 0, 0, 0]);
+		let v = c.into_vec();
+		assert_eq!(&v[..CHUNK_SIZE], &data[..CHUNK_SIZE]);
+		assert_eq!(ChunkedData::from(Vec::new()).size(), 0);
+		Ok(())
+	}
+}
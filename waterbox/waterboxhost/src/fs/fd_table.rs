@@ -0,0 +1,152 @@
+// The guest's file descriptors.  Each one refers to an open file, which is a mounted file or an epoll instance, and any
+// number of them can refer to the same one, once the guest dups them.  Open files are known by the descriptor they were
+// first opened as, which is what their MountedFile or Epoll holds; if that one is closed while others are still open,
+// the file is known by one of those from then on, so what an open file is known by is always in use.  Open files also
+// have their status flags here, as fcntl(2) sees them.
+use super::*;
+
+/// One more than the highest descriptor the guest can have
+pub const MAX_FDS: i32 = 65536;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Slot {
+	/// Which open file
+	open: FileDescriptor,
+	cloexec: bool,
+}
+
+pub struct FdTable {
+	/// By descriptor
+	slots: Vec<Option<Slot>>,
+	/// The status flags of each open file, by what it's known as
+	status: Vec<(FileDescriptor, i32)>,
+}
+impl FdTable {
+	pub fn empty() -> FdTable {
+		FdTable { slots: Vec::new(), status: Vec::new() }
+	}
+	/// With stdin, stdout and stderr open
+	pub fn new() -> FdTable {
+		let mut res = FdTable::empty();
+		res.open(FileDescriptor(0), O_RDONLY, false);
+		res.open(FileDescriptor(1), O_WRONLY, false);
+		res.open(FileDescriptor(2), O_WRONLY, false);
+		res
+	}
+	/// Which open file `fd` refers to
+	pub fn get(&self, fd: FileDescriptor) -> Result<FileDescriptor, SyscallError> {
+		match self.slots.get(fd.0 as usize) {
+			Some(Some(s)) if fd.0 >= 0 => Ok(s.open),
+			_ => Err(EBADF),
+		}
+	}
+	/// The lowest descriptor at least `min` that isn't in use
+	pub fn lowest_free(&self, min: i32) -> Result<FileDescriptor, SyscallError> {
+		if !(0..MAX_FDS).contains(&min) {
+			return Err(EINVAL)
+		}
+		(min..MAX_FDS)
+			.find(|&i| matches!(self.slots.get(i as usize), None | Some(None)))
+			.map(FileDescriptor)
+			.ok_or(EMFILE)
+	}
+	fn set(&mut self, fd: FileDescriptor, slot: Option<Slot>) {
+		let index = fd.0 as usize;
+		if self.slots.len() <= index {
+			self.slots.resize(index + 1, None);
+		}
+		self.slots[index] = slot;
+		while let Some(None) = self.slots.last() {
+			self.slots.pop();
+		}
+	}
+	/// A file was just opened as `fd`, which has to be free, with open(2)'s `flags`.  Open files are known by the
+	/// descriptor they were opened as.
+	pub fn open(&mut self, fd: FileDescriptor, flags: i32, cloexec: bool) {
+		self.set(fd, Some(Slot { open: fd, cloexec }));
+		self.status.push((fd, flags & (O_ACCMODE | O_APPEND | O_NONBLOCK)));
+	}
+	/// Make `new`, which has to be free, refer to the same open file as `fd`
+	pub fn dup(&mut self, fd: FileDescriptor, new: FileDescriptor, cloexec: bool) -> SyscallResult {
+		let open = self.get(fd)?;
+		self.set(new, Some(Slot { open, cloexec }));
+		Ok(())
+	}
+	/// Close `fd`.  If others still refer to its open file, returns one of them, and otherwise None, after which the
+	/// open file is gone.
+	pub fn close(&mut self, fd: FileDescriptor) -> Result<Option<FileDescriptor>, SyscallError> {
+		let open = self.get(fd)?;
+		self.set(fd, None);
+		let other = self.slots.iter().position(|s| matches!(s, Some(s) if s.open == open));
+		match other {
+			Some(i) => Ok(Some(FileDescriptor(i as i32))),
+			None => {
+				self.status.retain(|s| s.0 != open);
+				Ok(None)
+			},
+		}
+	}
+	/// Have the open file known as `old` be known as `new` instead
+	pub fn rename(&mut self, old: FileDescriptor, new: FileDescriptor) {
+		for s in self.slots.iter_mut().flatten().filter(|s| s.open == old) {
+			s.open = new;
+		}
+		for s in self.status.iter_mut().filter(|s| s.0 == old) {
+			s.0 = new;
+		}
+	}
+	pub fn status(&self, open: FileDescriptor) -> i32 {
+		self.status.iter().find(|s| s.0 == open).map_or(0, |s| s.1)
+	}
+	/// Set the flags that F_SETFL can change, leaving the rest alone
+	pub fn set_status(&mut self, open: FileDescriptor, flags: i32) {
+		for s in self.status.iter_mut().filter(|s| s.0 == open) {
+			s.1 = (s.1 & !(O_APPEND | O_NONBLOCK)) | (flags & (O_APPEND | O_NONBLOCK));
+		}
+	}
+	pub fn cloexec(&self, fd: FileDescriptor) -> Result<bool, SyscallError> {
+		self.get(fd)?;
+		Ok(self.slots[fd.0 as usize].unwrap().cloexec)
+	}
+	pub fn set_cloexec(&mut self, fd: FileDescriptor, cloexec: bool) -> SyscallResult {
+		self.get(fd)?;
+		self.slots[fd.0 as usize].as_mut().unwrap().cloexec = cloexec;
+		Ok(())
+	}
+}
+impl IStateable for FdTable {
+	fn save_state(&mut self, stream: &mut dyn Write) -> anyhow::Result<()> {
+		let used = self.slots.iter().enumerate().filter_map(|(fd, s)| s.map(|s| (fd, s))).collect::<Vec<_>>();
+		bin::writeval(stream, used.len() as u64)?;
+		for (fd, s) in used {
+			bin::writeval(stream, fd as i32)?;
+			bin::write(stream, &s.open)?;
+			bin::writeval(stream, s.cloexec as u8)?;
+		}
+		bin::writeval(stream, self.status.len() as u64)?;
+		for (open, flags) in self.status.iter() {
+			bin::write(stream, open)?;
+			bin::write(stream, flags)?;
+		}
+		Ok(())
+	}
+	fn load_state(&mut self, stream: &mut dyn Read) -> anyhow::Result<()> {
+		self.slots.clear();
+		self.status.clear();
+		for _ in 0..bin::readval::<u64>(stream)? {
+			let fd = bin::readval::<i32>(stream)?;
+			let open = FileDescriptor(bin::readval::<i32>(stream)?);
+			let cloexec = bin::readval::<u8>(stream)? != 0;
+			if !(0..MAX_FDS).contains(&fd) {
+				return Err(coded(ErrorCode::BadStateData, "Bad file descriptor in state"))
+			}
+			self.set(FileDescriptor(fd), Some(Slot { open, cloexec }));
+		}
+		for _ in 0..bin::readval::<u64>(stream)? {
+			let open = FileDescriptor(bin::readval::<i32>(stream)?);
+			let flags = bin::readval::<i32>(stream)?;
+			self.status.push((open, flags));
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,189 @@
+// Which files of the host's own the host may touch on a guest's behalf.  Nothing is restricted until a path is allowed;
+// after that, host directories can only be mounted from under an allowed path, and core dumps only written under a
+// writable one.  Paths are compared after resolving symlinks, so a link can't lead anywhere else.  On Linux the same
+// list can also be enforced on the whole thread with Landlock, which covers anything the host might be tricked into.
+use crate::*;
+use std::path::{Path, PathBuf};
+
+#[derive(Default)]
+pub struct HostPaths {
+	/// Canonical directories, and whether they're writable
+	allowed: Vec<(PathBuf, bool)>,
+}
+impl HostPaths {
+	/// Allow everything under the directory `path` to be read, and written too if `writable`
+	pub fn allow(&mut self, path: &Path, writable: bool) -> anyhow::Result<()> {
+		let path = path.canonicalize().map_err(|e| anyhow!("Can't allow host path {}: {}", path.display(), e))?;
+		if !path.is_dir() {
+			return Err(anyhow!("Host path {} is not a directory", path.display()))
+		}
+		self.allowed.push((path, writable));
+		Ok(())
+	}
+	/// Whether anything is restricted
+	pub fn restricted(&self) -> bool {
+		!self.allowed.is_empty()
+	}
+	/// Check that `path` can be read, or written if `write`.  A file that's to be written doesn't have to exist yet, but
+	/// its directory does.
+	pub fn check(&self, path: &Path, write: bool) -> anyhow::Result<()> {
+		if !self.restricted() {
+			return Ok(())
+		}
+		let resolved = match (path.canonicalize(), write, path.parent(), path.file_name()) {
+			(Ok(p), _, _, _) => p,
+			(Err(_), true, Some(parent), Some(name)) => {
+				let parent = if parent.as_os_str().is_empty() { Path::new(".") } else { parent };
+				parent.canonicalize().map(|p| p.join(name))
+					.map_err(|e| anyhow!("Can't resolve host path {}: {}", path.display(), e))?
+			},
+			(Err(e), _, _, _) => return Err(anyhow!("Can't resolve host path {}: {}", path.display(), e)),
+		};
+		if self.allowed.iter().any(|(dir, writable)| resolved.starts_with(dir) && (*writable || !write)) {
+			Ok(())
+		} else {
+			Err(anyhow!("Host path {} is outside of the allowed {}paths", path.display(), if write { "writable " } else { "" }))
+		}
+	}
+	/// Restrict the current thread, and threads it starts from now on, to the allowed paths for good
+	pub fn landlock(&self) -> anyhow::Result<()> {
+		if !self.restricted() {
+			return Err(anyhow!("No host paths have been allowed"))
+		}
+		landlock::restrict(&self.allowed)
+	}
+}
+
+#[cfg(target_os = "linux")]
+mod landlock {
+	use super::*;
+	use libc::{c_int, c_long, c_ulong};
+	use std::os::unix::ffi::OsStrExt;
+
+	const SYS_LANDLOCK_CREATE_RULESET: c_long = 444;
+	const SYS_LANDLOCK_ADD_RULE: c_long = 445;
+	const SYS_LANDLOCK_RESTRICT_SELF: c_long = 446;
+	const LANDLOCK_RULE_PATH_BENEATH: c_int = 1;
+
+	const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+	const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+	const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+	const ACCESS_FS_MAKE_CHAR: u64 = 1 << 6;
+	const ACCESS_FS_MAKE_BLOCK: u64 = 1 << 11;
+	/// Everything in the first Landlock ABI
+	const ACCESS_FS_ALL: u64 = (1 << 13) - 1;
+
+	#[repr(C)]
+	struct RulesetAttr {
+		handled_access_fs: u64,
+	}
+	#[repr(C, packed)]
+	struct PathBeneathAttr {
+		allowed_access: u64,
+		parent_fd: c_int,
+	}
+
+	pub fn restrict(allowed: &[(PathBuf, bool)]) -> anyhow::Result<()> {
+		let err = |what: &str| anyhow!("{} failed: {}", what, std::io::Error::last_os_error());
+		unsafe {
+			let attr = RulesetAttr { handled_access_fs: ACCESS_FS_ALL };
+			let ruleset = libc::syscall(SYS_LANDLOCK_CREATE_RULESET, &attr as *const RulesetAttr, std::mem::size_of::<RulesetAttr>(), 0) as c_int;
+			if ruleset < 0 {
+				return Err(err("Creating a Landlock ruleset"))
+			}
+			let res = (|| {
+				for (path, writable) in allowed {
+					let name = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+					let fd = libc::open(name.as_ptr(), libc::O_PATH | libc::O_CLOEXEC);
+					if fd < 0 {
+						return Err(err("Opening an allowed path"))
+					}
+					let access = if *writable {
+						ACCESS_FS_ALL & !(ACCESS_FS_EXECUTE | ACCESS_FS_MAKE_CHAR | ACCESS_FS_MAKE_BLOCK)
+					} else {
+						ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR
+					};
+					let rule = PathBeneathAttr { allowed_access: access, parent_fd: fd };
+					let added = libc::syscall(SYS_LANDLOCK_ADD_RULE, ruleset, LANDLOCK_RULE_PATH_BENEATH, &rule as *const PathBeneathAttr, 0);
+					libc::close(fd);
+					if added != 0 {
+						return Err(err("Adding a Landlock rule"))
+					}
+				}
+				if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1 as c_ulong, 0 as c_ulong, 0 as c_ulong, 0 as c_ulong) != 0 {
+					return Err(err("PR_SET_NO_NEW_PRIVS"))
+				}
+				if libc::syscall(SYS_LANDLOCK_RESTRICT_SELF, ruleset, 0) != 0 {
+					return Err(err("Enforcing the Landlock ruleset"))
+				}
+				Ok(())
+			})();
+			libc::close(ruleset);
+			res
+		}
+	}
+}
+#[cfg(not(target_os = "linux"))]
+mod landlock {
+	use super::*;
+
+	pub fn restrict(_allowed: &[(PathBuf, bool)]) -> anyhow::Result<()> {
+		Err(coded(ErrorCode::Unsupported, "Landlock is only available on Linux"))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_check() -> anyhow::Result<()> {
+		let dir = std::env::temp_dir().join(format!("wbx_test_host_paths_{}", std::process::id()));
+		let res = (|| -> anyhow::Result<()> {
+			std::fs::create_dir_all(dir.join("firmware"))?;
+			std::fs::create_dir_all(dir.join("saves"))?;
+			std::fs::write(dir.join("secret"), "x")?;
+			std::fs::write(dir.join("firmware/bios.bin"), "x")?;
+			let mut p = HostPaths::default();
+			assert!(p.check(&dir.join("secret"), true).is_ok());
+			p.allow(&dir.join("firmware"), false)?;
+			p.allow(&dir.join("saves"), true)?;
+			assert!(p.allow(&dir.join("secret"), false).is_err());
+			assert!(p.check(&dir.join("firmware/bios.bin"), false).is_ok());
+			assert!(p.check(&dir.join("firmware/bios.bin"), true).is_err());
+			assert!(p.check(&dir.join("saves/new.core"), true).is_ok());
+			assert!(p.check(&dir.join("secret"), false).is_err());
+			assert!(p.check(&dir.join("firmware/../secret"), false).is_err());
+			#[cfg(unix)]
+			{
+				std::os::unix::fs::symlink(dir.join("secret"), dir.join("firmware/link"))?;
+				assert!(p.check(&dir.join("firmware/link"), false).is_err());
+			}
+			Ok(())
+		})();
+		std::fs::remove_dir_all(&dir)?;
+		res
+	}
+
+	#[test]
+	#[cfg(target_os = "linux")]
+	fn test_landlock() -> anyhow::Result<()> {
+		let dir = std::env::temp_dir().join(format!("wbx_test_landlock_{}", std::process::id()));
+		std::fs::create_dir_all(dir.join("allowed"))?;
+		std::fs::write(dir.join("allowed/a"), "a")?;
+		std::fs::write(dir.join("b"), "b")?;
+		let mut p = HostPaths::default();
+		p.allow(&dir.join("allowed"), false)?;
+		let res = std::thread::spawn(move || match p.landlock() {
+			Ok(()) => Some((std::fs::read(dir.join("allowed/a")).is_ok(), std::fs::read(dir.join("b")).is_ok(),
+				std::fs::write(dir.join("allowed/c"), "c").is_ok())),
+			// not every kernel has it
+			Err(_) => None,
+		}).join().unwrap();
+		if let Some(res) = res {
+			assert_eq!(res, (true, false, false));
+		}
+		std::fs::remove_dir_all(std::env::temp_dir().join(format!("wbx_test_landlock_{}", std::process::id())))?;
+		Ok(())
+	}
+}
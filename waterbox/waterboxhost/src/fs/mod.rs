@@ -1,15 +1,41 @@
 mod empty_read;
 mod sys_out;
 mod regular_file;
+mod random;
+mod overlay_file;
+mod zip_file;
+mod stream_file;
+mod chunked;
+mod poll;
+mod socket;
+mod host_paths;
+mod fd_table;
+mod tmp_file;
+mod proc_file;
 
 use crate::syscall_defs::*;
 use crate::*;
 use std::io::{Write, Read};
 use empty_read::EmptyRead;
-use sys_out::SysOutObj;
+use sys_out::{OutputCapture, SysOutObj};
 use regular_file::RegularFile;
+use random::RandomDevice;
+pub use random::Rng;
+use overlay_file::OverlayFile;
+use zip_file::{ZipArchive, ZipFile};
+use stream_file::StreamFile;
+pub use chunked::ChunkedData;
+use poll::Epoll;
+use socket::SocketFile;
+use fd_table::{FdTable, MAX_FDS};
+use tmp_file::{TmpFile, TmpSpace, TMP_DIR};
+use proc_file::ProcFile;
+pub use socket::{SocketHost, format_sockaddr};
+pub use host_paths::HostPaths;
+use std::{cell::RefCell, rc::Rc, path::{Path, PathBuf}};
+use cinterface::GuestOutputCallback;
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 #[repr(transparent)]
 pub struct FileDescriptor(pub i32);
 
@@ -26,12 +52,39 @@ pub trait FileObject: IStateable {
 	fn reset(&mut self);
 	fn can_unmount(&self) -> bool;
 	fn unmount(self: Box<Self>) -> Vec<u8>;
+	/// Content the host should write back to disk when it flushes files, if any
+	fn persistent_data(&self) -> Option<&[u8]> {
+		None
+	}
+	/// Whether the guest made this in /tmp, so that savestates have all of it
+	fn is_tmp(&self) -> bool {
+		false
+	}
+	/// Whether this is one of the made up files in /proc, which savestates have apart from the others
+	fn is_proc(&self) -> bool {
+		false
+	}
+	fn as_socket(&mut self) -> Option<&mut SocketFile> {
+		None
+	}
+	/// What poll(2) would say is ready right now
+	fn poll(&mut self) -> i16 {
+		let mut res = 0;
+		if self.can_read() {
+			res |= POLLIN;
+		}
+		if self.can_write() {
+			res |= POLLOUT;
+		}
+		res
+	}
 }
 
 fn fill_stat(s: &mut KStat, can_read: bool, can_write: bool, can_seek: bool, length: i64) -> SyscallResult {
 	s.st_dev = 1;
+	// FileSystem fills in the real one, since it knows the name
 	s.st_ino = 1;
-	s.st_nlink = 0;
+	s.st_nlink = 1;
 
 	let mut flags = 0;
 	if can_read {
@@ -68,6 +121,48 @@ fn fill_stat(s: &mut KStat, can_read: bool, can_write: bool, can_seek: bool, len
 	Ok(())
 }
 
+/// An inode number for a file or directory that only depends on its name, so it's the same from run to run no matter
+/// what else is mounted.  This is FNV-1a.
+fn inode(name: &str) -> u64 {
+	let mut h = 0xcbf29ce484222325u64;
+	for &b in name.as_bytes() {
+		h = (h ^ b as u64).wrapping_mul(0x100000001b3);
+	}
+	// 0 isn't a valid inode
+	std::cmp::max(h, 1)
+}
+
+fn fill_dir_stat(s: &mut KStat, name: &str) {
+	fill_stat(s, true, false, true, 0).unwrap();
+	s.st_ino = inode(name);
+	s.st_nlink = 2;
+	s.st_mode = S_IFDIR | S_IRUSR | S_IXUSR | S_IRGRP | S_IXGRP | S_IROTH | S_IXOTH;
+}
+
+/// Turn a stat(2) result into a statx(2) one
+pub fn fill_statx(s: &KStat, x: &mut Statx) {
+	let time = |sec, nsec| StatxTimestamp { tv_sec: sec, tv_nsec: nsec as u32, __reserved: 0 };
+	*x = Statx {
+		stx_mask: STATX_BASIC_STATS,
+		stx_blksize: s.st_blksize as u32,
+		stx_nlink: s.st_nlink as u32,
+		stx_uid: s.st_uid,
+		stx_gid: s.st_gid,
+		stx_mode: s.st_mode as u16,
+		stx_ino: s.st_ino,
+		stx_size: s.st_size as u64,
+		stx_blocks: s.st_blocks as u64,
+		stx_atime: time(s.st_atime_sec, s.st_atime_nsec),
+		stx_ctime: time(s.st_ctime_sec, s.st_ctime_nsec),
+		stx_mtime: time(s.st_mtime_sec, s.st_mtime_nsec),
+		stx_rdev_major: (s.st_rdev >> 8) as u32,
+		stx_rdev_minor: (s.st_rdev & 0xff) as u32,
+		stx_dev_major: (s.st_dev >> 8) as u32,
+		stx_dev_minor: (s.st_dev & 0xff) as u32,
+		..Default::default()
+	};
+}
+
 struct MountedFile {
 	name: String,
 	fd: FileDescriptor,
@@ -96,12 +191,43 @@ impl IStateable for MountedFile {
 // 	pub writable: bool,
 // }
 
+/// Do something at another position in a file, without moving its current position.  `action` is told whether the
+/// position is past the end of the file, where the file couldn't seek to.
+fn at_offset<T>(f: &mut dyn FileObject, offset: i64, action: impl FnOnce(&mut dyn FileObject, bool) -> Result<T, SyscallError>) -> Result<T, SyscallError> {
+	if offset < 0 {
+		return Err(EINVAL)
+	}
+	let old = f.seek(0, SEEK_CUR)?;
+	let past_end = offset > f.seek(0, SEEK_END)?;
+	if !past_end {
+		f.seek(offset, SEEK_SET)?;
+	}
+	let res = action(f, past_end);
+	f.seek(old, SEEK_SET)?;
+	res
+}
+
 pub struct FileSystem {
 	files: Vec<MountedFile>,
+	/// Backs getrandom(2), /dev/random and /dev/urandom
+	rng: Rc<RefCell<Rng>>,
+	epolls: Vec<Epoll>,
+	/// What each of the guest's file descriptors refers to
+	fds: FdTable,
+	/// How much room the guest's scratch files in /tmp have
+	tmp: Rc<TmpSpace>,
+	/// Where stdout and stderr go, if not the host's own
+	output: Rc<RefCell<OutputCapture>>,
+	/// Where guest sockets connect to, if anywhere
+	socket_host: Option<Rc<RefCell<Box<dyn SocketHost>>>>,
+	/// Which of the host's files can be read in or written out
+	pub host_paths: HostPaths,
 	// missing_file_callback: Option<MissingFileCallback>,
 }
 impl FileSystem {
 	pub fn new() -> FileSystem {
+		let rng = Rc::new(RefCell::new(Rng::new(0)));
+		let output = Rc::new(RefCell::new(OutputCapture::default()));
 		FileSystem {
 			files: vec![
 				MountedFile {
@@ -112,14 +238,31 @@ impl FileSystem {
 				MountedFile {
 					name: "/dev/stdout".to_string(),
 					fd: FileDescriptor(1),
-					obj: Box::new(SysOutObj { host_handle: Box::new(std::io::stdout()) })
+					obj: Box::new(SysOutObj { host_handle: Box::new(std::io::stdout()), index: 0, capture: output.clone() })
 				},
 				MountedFile {
 					name: "/dev/stderr".to_string(),
 					fd: FileDescriptor(2),
-					obj: Box::new(SysOutObj { host_handle: Box::new(std::io::stderr()) })
+					obj: Box::new(SysOutObj { host_handle: Box::new(std::io::stderr()), index: 1, capture: output.clone() })
+				},
+				MountedFile {
+					name: "/dev/random".to_string(),
+					fd: BAD_FD,
+					obj: Box::new(RandomDevice { rng: rng.clone() })
+				},
+				MountedFile {
+					name: "/dev/urandom".to_string(),
+					fd: BAD_FD,
+					obj: Box::new(RandomDevice { rng: rng.clone() })
 				},
 			],
+			rng,
+			epolls: Vec::new(),
+			fds: FdTable::new(),
+			tmp: Rc::new(TmpSpace::default()),
+			output,
+			socket_host: None,
+			host_paths: HostPaths::default(),
 			// missing_file_callback: None,
 		}
 	}
@@ -130,30 +273,136 @@ impl FileSystem {
 	/// Accept a file from the outside world.  Writable files may never appear in a savestate,
 	/// and readonly files must not be added or removed from savestate to savestate, so all uses
 	/// are either transient or read only resources that last for the life of emulation.
-	pub fn mount(&mut self, name: String, data: Vec<u8>, writable: bool) -> anyhow::Result<()> {
+	pub fn mount(&mut self, name: String, data: impl Into<ChunkedData>, writable: bool) -> anyhow::Result<()> {
 		if self.files.iter().any(|f| f.name == name) {
-			return Err(anyhow!("File with name {} already mounted.", name))
+			return Err(coded(ErrorCode::InvalidArgument, format!("File with name {} already mounted.", name)))
 		}
 		self.files.push(MountedFile {
 			name: name.to_string(),
 			fd: BAD_FD,
-			obj: Box::new(RegularFile::new(data, writable))
+			obj: Box::new(RegularFile::new(data.into(), writable))
 		});
 		Ok(())
 	}
+	/// Make up /proc/self/maps, /proc/cpuinfo and the other /proc files, from the guest's layout and nothing else
+	pub fn mount_proc(&mut self, layout: &WbxSysLayout, module_name: &str) {
+		for (name, data) in proc_file::proc_files(layout, module_name) {
+			self.files.push(MountedFile { name, fd: BAD_FD, obj: Box::new(ProcFile::new(data)) });
+		}
+	}
+	/// Accept a file from the outside world that the guest can write to, with its writes kept in savestates.  Like
+	/// readonly files, these must be mounted the same way from savestate to savestate.  If `persist`, its content
+	/// is given to the host whenever the host flushes files, once the guest has changed it.
+	pub fn mount_overlay(&mut self, name: String, data: Vec<u8>, persist: bool) -> anyhow::Result<()> {
+		if self.files.iter().any(|f| f.name == name) {
+			return Err(coded(ErrorCode::InvalidArgument, format!("File with name {} already mounted.", name)))
+		}
+		self.files.push(MountedFile {
+			name,
+			fd: BAD_FD,
+			obj: Box::new(OverlayFile::new(data, persist))
+		});
+		Ok(())
+	}
+	/// Mount every file under a directory of the host, recursively, as `guest_path/relative/path`.  All of the files
+	/// are read in right away, and are mounted in name order so that every savestate sees the same list.  Readonly files
+	/// follow the rules of mount(); otherwise they are overlay files that never persist.  Returns how many files were mounted.
+	pub fn mount_host_dir(&mut self, guest_path: &str, host_path: &Path, readonly: bool) -> anyhow::Result<usize> {
+		fn walk(dir: &Path, prefix: &str, res: &mut Vec<(String, PathBuf)>) -> anyhow::Result<()> {
+			for entry in std::fs::read_dir(dir)? {
+				let entry = entry?;
+				let name = match entry.file_name().into_string() {
+					Ok(n) => format!("{}/{}", prefix, n),
+					Err(n) => return Err(anyhow!("Host file name {:?} is not valid unicode", n))
+				};
+				let path = entry.path();
+				if path.is_dir() {
+					walk(&path, &name, res)?;
+				} else if path.is_file() {
+					res.push((name, path));
+				}
+			}
+			Ok(())
+		}
+		self.host_paths.check(host_path, false)?;
+		let mut found = Vec::new();
+		walk(host_path, guest_path.trim_end_matches('/'), &mut found)
+			.map_err(|e| anyhow!("Could not read host directory {}: {}", host_path.display(), e))?;
+		found.sort();
+		if let Some((name, _)) = found.iter().find(|(name, _)| self.files.iter().any(|f| &f.name == name)) {
+			return Err(coded(ErrorCode::InvalidArgument, format!("File with name {} already mounted.", name)))
+		}
+		let mut files = Vec::new();
+		for (_, path) in found.iter() {
+			// anything reached through a link has to be allowed too
+			self.host_paths.check(path, false)?;
+		}
+		for (name, path) in found {
+			let err = |e| anyhow!("Could not read host file {}: {}", path.display(), e);
+			let obj: Box<dyn FileObject> = if readonly {
+				let mut file = std::fs::File::open(&path).map_err(err)?;
+				Box::new(RegularFile::new(ChunkedData::from_reader(&mut file).map_err(err)?, false))
+			} else {
+				Box::new(OverlayFile::new(std::fs::read(&path).map_err(err)?, false))
+			};
+			files.push(MountedFile { name, fd: BAD_FD, obj });
+		}
+		let count = files.len();
+		self.files.extend(files);
+		Ok(count)
+	}
+	/// Mount every file in a zip archive as a readonly file under `mount_point`.  Files are only decompressed while
+	/// the guest has them open.  Returns how many files were mounted.
+	pub fn mount_zip(&mut self, mount_point: &str, data: Vec<u8>) -> anyhow::Result<usize> {
+		let (archive, entries) = ZipArchive::open(data)?;
+		let prefix = mount_point.trim_end_matches('/');
+		let mut files = Vec::new();
+		for entry in entries {
+			let name = format!("{}/{}", prefix, entry.name);
+			if self.files.iter().chain(files.iter()).any(|f: &MountedFile| f.name == name) {
+				return Err(coded(ErrorCode::InvalidArgument, format!("File with name {} already mounted.", name)))
+			}
+			files.push(MountedFile { name, fd: BAD_FD, obj: Box::new(ZipFile::new(archive.clone(), entry)) });
+		}
+		let count = files.len();
+		self.files.extend(files);
+		Ok(count)
+	}
+	/// Mount a readonly pipe whose reads are passed on to `source` as the guest makes them, and whose readiness for
+	/// poll(2) comes from `ready`.  Like writable files, these are transient and can't exist when saving or loading state.
+	pub fn mount_stream(&mut self, name: String, source: Box<dyn Read>, ready: Option<Box<dyn FnMut() -> bool>>) -> anyhow::Result<()> {
+		if self.files.iter().any(|f| f.name == name) {
+			return Err(coded(ErrorCode::InvalidArgument, format!("File with name {} already mounted.", name)))
+		}
+		self.files.push(MountedFile {
+			name,
+			fd: BAD_FD,
+			obj: Box::new(StreamFile::new(source, ready))
+		});
+		Ok(())
+	}
+	/// Call `sink` with the name and current content of each persistent overlay file that the guest has changed
+	pub fn flush(&self, mut sink: impl FnMut(&str, &[u8]) -> anyhow::Result<()>) -> anyhow::Result<()> {
+		for f in self.files.iter() {
+			if let Some(data) = f.obj.persistent_data() {
+				sink(&f.name, data)?;
+			}
+		}
+		Ok(())
+	}
 	/// Remove a file previously loaded with mount().  Returns the content of the file at this time.
 	/// Not possible if the guest has yet to close the file.
 	pub fn unmount(&mut self, name: &str) -> anyhow::Result<Vec<u8>> {
 		let idx = match self.files.iter().position(|f| f.name == name) {
 			Some(f) => f,
-			None => return Err(anyhow!("File with name {} not previously mounted.", name))
+			None => return Err(coded(ErrorCode::NotFound, format!("File with name {} not previously mounted.", name)))
 		};
 		let file = &self.files[idx];
 		if file.fd != BAD_FD {
-			return Err(anyhow!("File {} is still open in the system", name))
+			return Err(coded(ErrorCode::BadState, format!("File {} is still open in the system", name)))
 		}
 		if !file.obj.can_unmount() {
-			return Err(anyhow!("File {} cannot be unmounted as it is permanently attached", name))
+			return Err(coded(ErrorCode::BadState, format!("File {} cannot be unmounted as it is permanently attached", name)))
 		}
 		Ok(self.files.remove(idx).obj.unmount())
 	}
@@ -164,17 +413,38 @@ impl FileSystem {
 	// 		.and_then(|cb| cb(name))
 	// 		.map(|res| self.mount(name.to_string(), res.data, res.writable).unwrap())
 	// }
-	/// Implements a subset of open(2)
+	/// Send what the guest writes to stdout and stderr to `callback` a line at a time, with `prefix` in front of each, or
+	/// back to the host's own console with None.  Whatever was waiting for the end of its line goes out first.
+	pub fn set_output_callback(&mut self, callback: Option<(GuestOutputCallback, usize)>, prefix: String) {
+		self.output.borrow_mut().set_callback(callback, prefix);
+	}
+	/// Pass on any lines of guest output that haven't ended yet
+	pub fn flush_output(&mut self) {
+		self.output.borrow_mut().flush();
+	}
+	/// Let the guest keep up to `quota` bytes of scratch files in /tmp, or none at all with 0.  Files it already has are
+	/// kept even if they don't fit, but can't grow.  The quota isn't part of savestates.
+	pub fn set_tmp_quota(&mut self, quota: usize) {
+		self.tmp.quota.set(quota);
+	}
+	/// How many bytes the guest's scratch files take up
+	pub fn tmp_used(&self) -> usize {
+		self.tmp.used.get()
+	}
+	/// Implements a subset of open(2).  Only scratch files in /tmp can be made with O_CREAT, or cut off with O_TRUNC.
 	pub fn open(&mut self, name: &str, flags: i32, _mode: i32) -> Result<FileDescriptor, SyscallError> {
-		let fd = {
-			let mut i = 0;
-			loop {
-				if !self.files.iter().any(|f| f.fd.0 == i) {
-					break FileDescriptor(i)
-				}
-				i += 1;
-			}
-		};
+		let fd = self.fds.lowest_free(0)?;
+		let exists = self.files.iter().any(|f| f.name == name);
+		if exists && flags & (O_CREAT | O_EXCL) == O_CREAT | O_EXCL {
+			return Err(EEXIST)
+		}
+		if !exists && flags & O_CREAT != 0 && self.tmp.quota.get() > 0 && tmp_file::in_tmp(name) {
+			self.files.push(MountedFile {
+				name: name.to_string(),
+				fd: BAD_FD,
+				obj: Box::new(TmpFile::new(self.tmp.clone()))
+			});
+		}
 		let file_opt = {
 			// let mut did_cb = false;
 			loop {
@@ -221,20 +491,135 @@ impl FileSystem {
 			},
 			_ => return Err(EINVAL)
 		}
+		if flags & O_TRUNC != 0 && flags & O_ACCMODE != O_RDONLY && file.obj.is_tmp() {
+			file.obj.truncate(0)?;
+		}
 		// TODO: If the requested access was R on an RW file (transient), we still allow writing once opened
 		file.fd = fd;
+		self.fds.open(fd, flags, flags & O_CLOEXEC != 0);
 		Ok(fd)
 	}
-	/// Implements a subset of close(2)
+	/// Implements a subset of close(2).  The open file is only closed along with the last descriptor that refers to it.
 	pub fn close(&mut self, fd: FileDescriptor) -> SyscallResult {
-		let file = match self.files.iter_mut().find(|f| f.fd == fd) {
-			Some(f) => f,
+		let open = self.fds.get(fd)?;
+		let other = self.fds.close(fd)?;
+		for e in self.epolls.iter_mut() {
+			e.forget(fd);
+		}
+		if let Some(other) = other {
+			if open == fd {
+				// it's known by another of its descriptors from now on
+				self.fds.rename(open, other);
+				for f in self.files.iter_mut().filter(|f| f.fd == open) {
+					f.fd = other;
+				}
+				for e in self.epolls.iter_mut().filter(|e| e.fd == open) {
+					e.fd = other;
+				}
+			}
+			return Ok(())
+		}
+		if let Some(i) = self.epolls.iter().position(|e| e.fd == open) {
+			self.epolls.remove(i);
+			return Ok(())
+		}
+		let idx = match self.files.iter().position(|f| f.fd == open) {
+			Some(i) => i,
 			None => return Err(EBADF)
 		};
-		file.obj.reset();
-		file.fd = BAD_FD;
+		let file = &mut self.files[idx];
+		if file.obj.as_socket().is_some() {
+			// the guest made it, so it goes away entirely
+			self.files.remove(idx);
+		} else {
+			file.obj.reset();
+			file.fd = BAD_FD;
+		}
 		Ok(())
 	}
+	/// Implements a subset of unlink(2), for scratch files in /tmp.  Those can't be removed while they're open.
+	pub fn unlink(&mut self, name: &str) -> SyscallResult {
+		match self.files.iter().position(|f| f.name == name) {
+			Some(i) if !self.files[i].obj.is_tmp() => Err(EROFS),
+			Some(i) if self.files[i].fd != BAD_FD => Err(EBUSY),
+			Some(i) => {
+				self.files.remove(i);
+				Ok(())
+			},
+			None => Err(ENOENT),
+		}
+	}
+	/// Implements a subset of rename(2), for scratch files in /tmp, which can be renamed while they're open, but not
+	/// onto one that's open.  With `noreplace`, fails instead of replacing `new`.
+	pub fn rename(&mut self, old: &str, new: &str, noreplace: bool) -> SyscallResult {
+		match self.files.iter().find(|f| f.name == old) {
+			Some(f) if !f.obj.is_tmp() => return Err(EROFS),
+			Some(_) => (),
+			None => return Err(ENOENT),
+		}
+		if !tmp_file::in_tmp(new) {
+			return Err(EXDEV)
+		}
+		if old == new {
+			return Ok(())
+		}
+		if let Some(i) = self.files.iter().position(|f| f.name == new) {
+			let f = &self.files[i];
+			if noreplace {
+				return Err(EEXIST)
+			}
+			if !f.obj.is_tmp() {
+				return Err(EROFS)
+			}
+			if f.fd != BAD_FD {
+				return Err(EBUSY)
+			}
+			self.files.remove(i);
+		}
+		self.files.iter_mut().find(|f| f.name == old).unwrap().name = new.to_string();
+		Ok(())
+	}
+	/// Implements dup(2), or fcntl(2)'s F_DUPFD with the lowest descriptor it can pick
+	pub fn dup(&mut self, fd: FileDescriptor, min: i32, cloexec: bool) -> Result<FileDescriptor, SyscallError> {
+		self.fds.get(fd)?;
+		let new = self.fds.lowest_free(min)?;
+		self.fds.dup(fd, new, cloexec)?;
+		Ok(new)
+	}
+	/// Implements dup3(2), closing `new` first if it's open
+	pub fn dup3(&mut self, fd: FileDescriptor, new: FileDescriptor, cloexec: bool) -> Result<FileDescriptor, SyscallError> {
+		self.fds.get(fd)?;
+		if !(0..MAX_FDS).contains(&new.0) {
+			return Err(EBADF)
+		}
+		if fd == new {
+			return Err(EINVAL)
+		}
+		if self.fds.get(new).is_ok() {
+			self.close(new)?;
+		}
+		self.fds.dup(fd, new, cloexec)?;
+		Ok(new)
+	}
+	/// Implements a subset of fcntl(2):  Duplicating descriptors, and getting and setting their flags and their open
+	/// file's.  Only O_APPEND and O_NONBLOCK can be set, and only O_APPEND does anything.
+	pub fn fcntl(&mut self, fd: FileDescriptor, cmd: i32, arg: usize) -> Result<i32, SyscallError> {
+		let open = self.fds.get(fd)?;
+		match cmd {
+			F_DUPFD | F_DUPFD_CLOEXEC => {
+				let min = std::cmp::min(arg, MAX_FDS as usize) as i32;
+				self.dup(fd, min, cmd == F_DUPFD_CLOEXEC).map(|new| new.0)
+			},
+			F_GETFD => Ok(if self.fds.cloexec(fd)? { FD_CLOEXEC } else { 0 }),
+			F_SETFD => self.fds.set_cloexec(fd, arg as i32 & FD_CLOEXEC != 0).map(|_| 0),
+			F_GETFL => Ok(self.fds.status(open)),
+			F_SETFL => {
+				self.fds.set_status(open, arg as i32);
+				Ok(0)
+			},
+			_ => Err(EINVAL),
+		}
+	}
 	fn wrap_action<T, P: FnOnce(&mut dyn FileObject) -> Result<T, SyscallError>>(&mut self, name: &str, action: P) -> Result<T, SyscallError> {
 		match self.files.iter_mut().find(|f| f.name == name) {
 			Some(f) => action(f.obj.as_mut()),
@@ -242,18 +627,43 @@ impl FileSystem {
 		}
 	}
 	fn wrap_faction<T, P: FnOnce(&mut dyn FileObject) -> Result<T, SyscallError>>(&mut self, fd: FileDescriptor, action: P) -> Result<T, SyscallError> {
-		match self.files.iter_mut().find(|f| f.fd == fd) {
+		let open = self.fds.get(fd)?;
+		match self.files.iter_mut().find(|f| f.fd == open) {
 			Some(f) => action(f.obj.as_mut()),
 			None => Err(ENOENT)
 		}
 	}
-	/// Implements a subset of stat(2)
+	/// Implements a subset of stat(2).  Anything that other files are mounted under is a directory.
 	pub fn stat(&mut self, name: &str, statbuff: &mut KStat) -> SyscallResult {
-		self.wrap_action(name, |f| f.stat(statbuff))
+		match self.files.iter().find(|f| f.name == name) {
+			Some(f) => {
+				f.obj.stat(statbuff)?;
+				statbuff.st_ino = inode(name);
+				Ok(())
+			},
+			None => {
+				let dir = name.trim_end_matches('/');
+				let prefix = format!("{}/", dir);
+				if self.files.iter().any(|f| f.name.starts_with(&prefix)) || dir == TMP_DIR && self.tmp.quota.get() > 0 {
+					fill_dir_stat(statbuff, if dir.is_empty() { "/" } else { dir });
+					Ok(())
+				} else {
+					Err(ENOENT)
+				}
+			}
+		}
 	}
 	/// Implements a subset of fstat(2)
 	pub fn fstat(&mut self, fd: FileDescriptor, statbuff: &mut KStat) -> SyscallResult {
-		self.wrap_faction(fd, |f| f.stat(statbuff))
+		let open = self.fds.get(fd)?;
+		match self.files.iter().find(|f| f.fd == open) {
+			Some(f) => {
+				f.obj.stat(statbuff)?;
+				statbuff.st_ino = inode(&f.name);
+				Ok(())
+			},
+			None => Err(EBADF)
+		}
 	}
 	/// Implements a subset of truncate(2)
 	pub fn truncate(&mut self, name: &str, size: i64) -> SyscallResult {
@@ -269,28 +679,224 @@ impl FileSystem {
 	}
 	/// Implements a subset of write(2)
 	pub fn write(&mut self, fd: FileDescriptor, buf: &[u8]) -> Result<i64, SyscallError> {
-		self.wrap_faction(fd, |f| f.write(buf))
+		let append = self.fds.status(self.fds.get(fd)?) & O_APPEND != 0;
+		self.wrap_faction(fd, |f| {
+			if append {
+				// files that can't seek, like stdout, have no end to go to
+				let _ = f.seek(0, SEEK_END);
+			}
+			f.write(buf)
+		})
+	}
+	/// Implements a subset of pread(2), for files that can seek
+	pub fn pread(&mut self, fd: FileDescriptor, buf: &mut [u8], offset: i64) -> Result<i64, SyscallError> {
+		self.wrap_faction(fd, |f| at_offset(f, offset, |f, past_end| if past_end { Ok(0) } else { f.read(buf) }))
+	}
+	/// Implements a subset of pwrite(2), for files that can seek
+	pub fn pwrite(&mut self, fd: FileDescriptor, buf: &[u8], offset: i64) -> Result<i64, SyscallError> {
+		self.wrap_faction(fd, |f| at_offset(f, offset, |f, past_end| {
+			if past_end {
+				// fill the gap with zeros
+				f.truncate(offset)?;
+				f.seek(offset, SEEK_SET)?;
+			}
+			f.write(buf)
+		}))
+	}
+	/// Implements a subset of readv(2), or preadv(2) at `offset`.  Stops at the first short read, as reading all of the
+	/// buffers back to back would.
+	pub fn readv(&mut self, fd: FileDescriptor, bufs: &mut [&mut [u8]], offset: Option<i64>) -> Result<i64, SyscallError> {
+		self.wrap_faction(fd, |f| {
+			let mut read_all = |f: &mut dyn FileObject| {
+				let mut total = 0;
+				for buf in bufs.iter_mut() {
+					let n = match f.read(buf) {
+						Ok(n) => n,
+						// what was read before still counts
+						Err(_) if total > 0 => break,
+						Err(e) => return Err(e),
+					};
+					total += n;
+					if (n as usize) < buf.len() {
+						break
+					}
+				}
+				Ok(total)
+			};
+			match offset {
+				Some(offset) => at_offset(f, offset, |f, past_end| if past_end { Ok(0) } else { read_all(f) }),
+				None => read_all(f),
+			}
+		})
+	}
+	/// Implements a subset of writev(2), or pwritev(2) at `offset`.  The buffers are written all at once, so that nothing
+	/// else written to the same file, like another thread's console output, can land in between them.
+	pub fn writev(&mut self, fd: FileDescriptor, bufs: &[&[u8]], offset: Option<i64>) -> Result<i64, SyscallError> {
+		let data = bufs.concat();
+		match offset {
+			Some(offset) => self.pwrite(fd, &data[..], offset),
+			None => self.write(fd, &data[..]),
+		}
 	}
 	/// Implements a subset of lseek(2)
 	pub fn seek(&mut self, fd: FileDescriptor, offset: i64, whence: i32) -> Result<i64, SyscallError> {
 		self.wrap_faction(fd, |f| f.seek(offset, whence))
 	}
+	/// Set (or clear, with None) where guest sockets connect to.  Without one, the guest can't make sockets at all.
+	/// Sockets already open keep using the old one.
+	pub fn set_socket_host(&mut self, host: Option<Box<dyn SocketHost>>) {
+		self.socket_host = host.map(|h| Rc::new(RefCell::new(h)));
+	}
+	/// Whether the guest can make sockets
+	pub fn has_socket_host(&self) -> bool {
+		self.socket_host.is_some()
+	}
+	/// Swap in a socket host for new sockets, and take back the old one, shared or not with sockets it already made
+	pub fn replace_socket_host(&mut self, host: Option<Rc<RefCell<Box<dyn SocketHost>>>>) -> Option<Rc<RefCell<Box<dyn SocketHost>>>> {
+		std::mem::replace(&mut self.socket_host, host)
+	}
+	/// Implements a subset of socket(2).  Sockets can connect, and the rest of the file syscalls work on them, but
+	/// they can't bind or listen.
+	pub fn socket(&mut self, domain: u16, kind: usize) -> Result<FileDescriptor, SyscallError> {
+		if domain != AF_UNIX && domain != AF_INET && domain != AF_INET6 {
+			return Err(EAFNOSUPPORT)
+		}
+		if kind & !(SOCK_NONBLOCK | SOCK_CLOEXEC | 0xf) != 0 {
+			return Err(EINVAL)
+		}
+		if kind & 0xf != SOCK_STREAM && kind & 0xf != SOCK_DGRAM {
+			return Err(EPROTONOSUPPORT)
+		}
+		let host = match &self.socket_host {
+			Some(h) => h.clone(),
+			None => return Err(EACCES)
+		};
+		let fd = self.fds.lowest_free(0)?;
+		self.files.push(MountedFile {
+			name: format!("socket:[{}]", fd.0),
+			fd,
+			obj: Box::new(SocketFile::new(host))
+		});
+		self.fds.open(fd, O_RDWR | kind as i32 & O_NONBLOCK, kind & SOCK_CLOEXEC != 0);
+		Ok(fd)
+	}
+	/// Implements a subset of connect(2), with an address from format_sockaddr
+	pub fn connect(&mut self, fd: FileDescriptor, addr: &str) -> SyscallResult {
+		let open = self.fds.get(fd)?;
+		match self.files.iter_mut().find(|f| f.fd == open) {
+			Some(f) => match f.obj.as_socket() {
+				Some(s) => s.connect(addr),
+				None => Err(ENOTSOCK)
+			},
+			None => Err(EBADF)
+		}
+	}
+	/// Fill buf with deterministic random bytes, for getrandom(2)
+	pub fn getrandom(&mut self, buf: &mut [u8]) {
+		self.rng.borrow_mut().fill(buf);
+	}
+	/// Restart the random number generator from a new seed.  Its state is part of savestates.
+	pub fn set_random_seed(&mut self, seed: u64) {
+		*self.rng.borrow_mut() = Rng::new(seed);
+	}
 }
 impl IStateable for FileSystem {
 	fn save_state(&mut self, stream: &mut dyn Write) -> anyhow::Result<()> {
 		bin::write_magic(stream, "FileSystem")?;
-		for f in self.files.iter_mut() {
+		for f in self.files.iter_mut().filter(|f| !f.obj.is_tmp() && !f.obj.is_proc()) {
 			f.save_state(stream)?;
 		}
+		self.rng.borrow_mut().save_state(stream)?;
+		bin::writeval(stream, self.epolls.len())?;
+		for e in self.epolls.iter_mut() {
+			e.save_state(stream)?;
+		}
+		bin::write_magic(stream, "FileSystemFds")?;
+		self.fds.save_state(stream)?;
+		bin::write_magic(stream, "FileSystemTmp")?;
+		let tmp = self.files.iter_mut().filter(|f| f.obj.is_tmp()).collect::<Vec<_>>();
+		bin::writeval(stream, tmp.len() as u64)?;
+		for f in tmp {
+			bin::writeval(stream, f.name.len() as u64)?;
+			bin::write_magic(stream, &f.name)?;
+			bin::write(stream, &f.fd)?;
+			f.obj.save_state(stream)?;
+		}
+		bin::write_magic(stream, "FileSystemPrc")?;
+		let proc = self.files.iter_mut().filter(|f| f.obj.is_proc()).collect::<Vec<_>>();
+		bin::writeval(stream, proc.len() as u64)?;
+		for f in proc {
+			bin::writeval(stream, f.name.len() as u64)?;
+			bin::write_magic(stream, &f.name)?;
+			bin::write(stream, &f.fd)?;
+			f.obj.save_state(stream)?;
+		}
 		bin::write_magic(stream, "FileSystemEnd")?;
 		Ok(())
 	}
 	fn load_state(&mut self, stream: &mut dyn Read) -> anyhow::Result<()> {
 		bin::verify_magic(stream, "FileSystem")?;
-		for f in self.files.iter_mut() {
+		// the state has its own scratch files, which come later
+		self.files.retain(|f| !f.obj.is_tmp());
+		for f in self.files.iter_mut().filter(|f| !f.obj.is_proc()) {
 			f.load_state(stream)?;
 		}
-		bin::verify_magic(stream, "FileSystemEnd")?;
+		// and the /proc files are closed unless the state says otherwise
+		for f in self.files.iter_mut().filter(|f| f.obj.is_proc()) {
+			f.fd = BAD_FD;
+			f.obj.reset();
+		}
+		self.rng.borrow_mut().load_state(stream)?;
+		let count: usize = bin::readval(stream)?;
+		self.epolls.clear();
+		for _ in 0..count {
+			let mut e = Epoll::new(BAD_FD);
+			e.load_state(stream)?;
+			self.epolls.push(e);
+		}
+		// the descriptor table, scratch files and /proc files are each only there in states from hosts that had them
+		let mut tag = [0u8; 13];
+		stream.read_exact(&mut tag[..])?;
+		if &tag[..] == b"FileSystemFds" {
+			self.fds.load_state(stream)?;
+			stream.read_exact(&mut tag[..])?;
+		} else {
+			// every descriptor was its own open file
+			self.fds = FdTable::empty();
+			for fd in self.files.iter().map(|f| f.fd).chain(self.epolls.iter().map(|e| e.fd)).filter(|&fd| fd != BAD_FD) {
+				self.fds.open(fd, O_RDWR, false);
+			}
+		}
+		if &tag[..] == b"FileSystemTmp" {
+			for _ in 0..bin::readval::<u64>(stream)? {
+				let mut name = vec![0u8; bin::readval::<u64>(stream)? as usize];
+				stream.read_exact(&mut name[..])?;
+				let name = match String::from_utf8(name) {
+					Ok(n) if tmp_file::in_tmp(&n) => n,
+					_ => return Err(coded(ErrorCode::BadStateData, "Bad name for TmpFile state")),
+				};
+				let fd = bin::readval::<FileDescriptor>(stream)?;
+				let mut obj = TmpFile::new(self.tmp.clone());
+				obj.load_state(stream)?;
+				self.files.push(MountedFile { name, fd, obj: Box::new(obj) });
+			}
+			stream.read_exact(&mut tag[..])?;
+		}
+		if &tag[..] == b"FileSystemPrc" {
+			for _ in 0..bin::readval::<u64>(stream)? {
+				let mut name = vec![0u8; bin::readval::<u64>(stream)? as usize];
+				stream.read_exact(&mut name[..])?;
+				let f = self.files.iter_mut()
+					.find(|f| f.obj.is_proc() && f.name.as_bytes() == &name[..])
+					.ok_or_else(|| coded(ErrorCode::BadStateData, "State has a /proc file this host doesn't"))?;
+				bin::read(stream, &mut f.fd)?;
+				f.obj.load_state(stream)?;
+			}
+			stream.read_exact(&mut tag[..])?;
+		}
+		if &tag[..] != b"FileSystemEnd" {
+			return Err(coded(ErrorCode::BadStateData, "Bad magic for FileSystemEnd state"))
+		}
 		Ok(())
 	}
 }
@@ -298,6 +904,7 @@ impl IStateable for FileSystem {
 #[cfg(test)]
 mod tests {
 	use super::*;
+	use std::net::Ipv6Addr;
 
 	type TestResult = anyhow::Result<()>;
 
@@ -379,4 +986,606 @@ mod tests {
 		assert_eq!(vec, "Qig)tes$$$$".as_bytes());
 		Ok(())
 	}
+
+	#[test]
+	fn test_vectored() -> TestResult {
+		let mut fs = FileSystem::new();
+		fs.mount("v".to_string(), Vec::new(), true)?;
+		let fd = fs.open("v", O_RDWR, 0)?;
+		assert_eq!(fs.writev(fd, &[&b"Hello"[..], &b", "[..], &b"world"[..]], None)?, 12);
+		assert_eq!(fs.writev(fd, &[&b"W"[..]], Some(7))?, 1);
+		let (mut a, mut b) = (vec![0u8; 4], vec![0u8; 10]);
+		assert_eq!(fs.readv(fd, &mut [&mut a[..], &mut b[..]], Some(1))?, 11);
+		assert_eq!((&a[..], &b[..7]), (&b"ello"[..], &b", World"[..]));
+		// where the file was is left alone by preadv, but not readv
+		assert_eq!(fs.readv(fd, &mut [&mut a[..]], None)?, 0);
+		fs.seek(fd, 10, SEEK_SET)?;
+		assert_eq!(fs.readv(fd, &mut [&mut a[..], &mut b[..]], None)?, 2);
+		assert_eq!(&a[..2], b"ld");
+		assert!(fs.readv(FileDescriptor(99), &mut [&mut a[..]], None).is_err());
+		fs.close(fd)?;
+		assert_eq!(fs.unmount("v")?, b"Hello, World");
+		Ok(())
+	}
+
+	#[test]
+	fn test_dup() -> TestResult {
+		let mut fs = FileSystem::new();
+		fs.mount_overlay("d".to_string(), Vec::new(), false)?;
+		let fd = fs.open("d", O_RDWR | O_CLOEXEC, 0)?;
+		let copy = fs.dup(fd, 0, false)?;
+		assert_eq!((fd.0, copy.0), (3, 4));
+		assert_eq!(fs.fcntl(fd, F_GETFD, 0)?, FD_CLOEXEC);
+		assert_eq!(fs.fcntl(copy, F_GETFD, 0)?, 0);
+		// they share a position and status flags, but not FD_CLOEXEC
+		fs.write(fd, b"abc")?;
+		assert_eq!(fs.seek(copy, 0, SEEK_CUR)?, 3);
+		assert_eq!(fs.fcntl(copy, F_SETFL, (O_APPEND | O_RDONLY) as usize)?, 0);
+		assert_eq!(fs.fcntl(fd, F_GETFL, 0)?, O_RDWR | O_APPEND);
+		fs.seek(fd, 0, SEEK_SET)?;
+		fs.write(fd, b"d")?;
+		assert_eq!(fs.seek(fd, 0, SEEK_CUR)?, 4);
+		assert_eq!(fs.fcntl(fd, F_DUPFD, 10)?, 10);
+		assert_eq!(fs.fcntl(fd, F_DUPFD_CLOEXEC, 10)?, 11);
+		assert_eq!(fs.fcntl(FileDescriptor(11), F_GETFD, 0)?, FD_CLOEXEC);
+		assert_eq!(fs.fcntl(fd, 999, 0), Err(EINVAL));
+
+		// stdout onto the file, and the file stays open once what it was opened as is closed
+		assert_eq!(fs.dup3(fd, FileDescriptor(1), false)?.0, 1);
+		fs.close(fd)?;
+		assert_eq!(fs.fcntl(fd, F_GETFD, 0), Err(EBADF));
+		assert!(fs.unmount("d").is_err());
+		let mut state = Vec::new();
+		fs.save_state(&mut state)?;
+		fs.write(FileDescriptor(1), b"e")?;
+		fs.load_state(&mut &state[..])?;
+		let mut buf = [0u8; 8];
+		fs.seek(copy, 0, SEEK_SET)?;
+		assert_eq!(fs.read(FileDescriptor(10), &mut buf[..])?, 4);
+		assert_eq!(&buf[..4], b"abcd");
+		for fd in [1, 4, 10, 11].iter() {
+			fs.close(FileDescriptor(*fd))?;
+		}
+		assert_eq!(fs.unmount("d")?, b"abcd");
+		assert_eq!(fs.open("/dev/stdout", O_WRONLY, 0)?.0, 1);
+
+		// lots of them
+		for i in 3..5000 {
+			assert_eq!(fs.dup(FileDescriptor(0), 0, false)?.0, i);
+		}
+		assert_eq!(fs.dup(FileDescriptor(0), MAX_FDS - 1, false)?.0, MAX_FDS - 1);
+		assert_eq!(fs.dup(FileDescriptor(0), MAX_FDS - 1, false), Err(EMFILE));
+		assert_eq!(fs.dup(FileDescriptor(0), MAX_FDS, false), Err(EINVAL));
+		assert_eq!(fs.dup3(FileDescriptor(0), FileDescriptor(MAX_FDS), false), Err(EBADF));
+		assert_eq!(fs.dup3(FileDescriptor(5000), FileDescriptor(6), false), Err(EBADF));
+
+		// a state from before the descriptor table
+		let mut state = Vec::new();
+		fs.save_state(&mut state)?;
+		let at = state.windows(13).position(|w| w == b"FileSystemFds").unwrap();
+		state.truncate(at);
+		state.extend_from_slice(b"FileSystemEnd");
+		fs.load_state(&mut &state[..])?;
+		assert_eq!(fs.dup(FileDescriptor(0), 0, false)?.0, 3);
+		assert_eq!(fs.fcntl(FileDescriptor(4), F_GETFD, 0), Err(EBADF));
+		Ok(())
+	}
+
+	#[test]
+	fn test_proc() -> TestResult {
+		let range = |start, size| AddressRange { start, size };
+		let layout = WbxSysLayout {
+			elf: range(0x36f00000000, 0x10000),
+			sbrk: range(0x36f00010000, 0x20000),
+			sealed: range(0x36f00030000, 0),
+			invis: range(0x36f00030000, 0),
+			plain: range(0x36f00030000, 0x1000),
+			mmap: range(0x36f00031000, 0x100000),
+		};
+		let mut fs = FileSystem::new();
+		fs.mount_proc(&layout, "core");
+		let mut stat = KStat::default();
+		fs.stat("/proc/self", &mut stat)?;
+		assert_eq!(stat.st_mode & S_IFDIR, S_IFDIR);
+		let maps = fs.open("/proc/self/maps", O_RDONLY, 0)?;
+		assert_eq!(fs.open("/proc/cpuinfo", O_RDWR, 0), Err(EACCES));
+		let mut buf = [0u8; 4096];
+		let n = fs.read(maps, &mut buf[..30])? as usize;
+		let mut state = Vec::new();
+		fs.save_state(&mut state)?;
+		let rest = fs.read(maps, &mut buf[n..])? as usize;
+		assert_eq!(std::str::from_utf8(&buf[..n + rest])?, "36f00000000-36f00010000 r-xp 00000000 00:00 0          /core
+36f00010000-36f00030000 rw-p 00000000 00:00 0          [heap]
+36f00030000-36f00031000 rw-p 00000000 00:00 0
+36f00031000-36f00131000 rw-p 00000000 00:00 0
+");
+		// where it's read up to is in states
+		fs.load_state(&mut &state[..])?;
+		assert_eq!(fs.read(maps, &mut buf[..])? as usize, rest);
+		// states from before there were /proc files have them closed
+		let find = |tag: &[u8]| state.windows(tag.len()).position(|w| w == tag).unwrap();
+		let mut old = state[..find(b"FileSystemPrc")].to_vec();
+		old.extend_from_slice(&state[find(b"FileSystemEnd")..]);
+		fs.load_state(&mut &old[..])?;
+		assert!(fs.read(maps, &mut buf[..]).is_err());
+		fs.load_state(&mut &state[..])?;
+		fs.close(maps)?;
+		let meminfo = fs.open("/proc/meminfo", O_RDONLY, 0)?;
+		let n = fs.read(meminfo, &mut buf[..])? as usize;
+		assert!(std::str::from_utf8(&buf[..n])?.starts_with("MemTotal:           1220 kB\n"));
+		assert_eq!(fs.unlink("/proc/meminfo"), Err(EROFS));
+		Ok(())
+	}
+
+	#[test]
+	fn test_tmp() -> TestResult {
+		let mut fs = FileSystem::new();
+		let mut stat = KStat::default();
+		assert_eq!(fs.open("/tmp/a", O_RDWR | O_CREAT, 0o644), Err(ENOENT));
+		assert_eq!(fs.stat("/tmp", &mut stat), Err(ENOENT));
+		fs.set_tmp_quota(10);
+		fs.stat("/tmp", &mut stat)?;
+		assert_eq!(fs.open("/dev/stdin", O_RDONLY | O_CREAT | O_EXCL, 0), Err(EEXIST));
+		assert_eq!(fs.open("/other/a", O_RDWR | O_CREAT, 0), Err(ENOENT));
+		let a = fs.open("/tmp/a", O_RDWR | O_CREAT, 0o644)?;
+		assert_eq!(fs.write(a, b"0123456")?, 7);
+		assert_eq!(fs.tmp_used(), 7);
+		let b = fs.open("/tmp/dir/b", O_WRONLY | O_CREAT | O_EXCL, 0o644)?;
+		assert_eq!(fs.write(b, b"abcdef")?, 3);
+		assert_eq!(fs.write(b, b"g"), Err(ENOSPC));
+		assert_eq!(fs.ftruncate(b, 4), Err(ENOSPC));
+		fs.close(b)?;
+
+		// everything about them is in states
+		let mut state = Vec::new();
+		fs.save_state(&mut state)?;
+		assert_eq!(fs.rename("/tmp/dir/b", "/tmp/a", false), Err(EBUSY));
+		fs.rename("/tmp/a", "/tmp/c", false)?;
+		fs.unlink("/tmp/dir/b")?;
+		assert_eq!(fs.tmp_used(), 7);
+		fs.load_state(&mut &state[..])?;
+		assert_eq!(fs.tmp_used(), 10);
+		fs.stat("/tmp/a", &mut stat)?;
+		assert_eq!(stat.st_size, 7);
+		assert_eq!(fs.stat("/tmp/c", &mut stat), Err(ENOENT));
+		fs.seek(a, 0, SEEK_SET)?;
+		let mut buf = [0u8; 8];
+		assert_eq!(fs.read(a, &mut buf[..])?, 7);
+
+		assert_eq!(fs.unlink("/tmp/a"), Err(EBUSY));
+		assert_eq!(fs.unlink("/dev/stdin"), Err(EROFS));
+		assert_eq!(fs.rename("/tmp/dir/b", "/dev/b", false), Err(EXDEV));
+		assert_eq!(fs.rename("/tmp/dir/b", "/tmp/a", true), Err(EEXIST));
+		fs.close(a)?;
+		fs.rename("/tmp/dir/b", "/tmp/a", false)?;
+		assert_eq!(fs.tmp_used(), 3);
+		let a = fs.open("/tmp/a", O_RDWR | O_TRUNC, 0)?;
+		assert_eq!(fs.tmp_used(), 0);
+		fs.close(a)?;
+		fs.unlink("/tmp/a")?;
+		assert_eq!(fs.unlink("/tmp/a"), Err(ENOENT));
+		Ok(())
+	}
+
+	#[test]
+	fn test_output() -> TestResult {
+		lazy_static::lazy_static! {
+			static ref SEEN: std::sync::Mutex<Vec<(i32, String)>> = std::sync::Mutex::new(Vec::new());
+		}
+		extern fn callback(userdata: usize, fd: i32, line: *const std::os::raw::c_char) {
+			assert_eq!(userdata, 5);
+			let line = unsafe { std::ffi::CStr::from_ptr(line) }.to_str().unwrap().to_string();
+			SEEN.lock().unwrap().push((fd, line));
+		}
+		let mut fs = FileSystem::new();
+		fs.set_output_callback(Some((callback, 5)), "[core] ".to_string());
+		fs.write(FileDescriptor(1), b"one\ntw")?;
+		fs.write(FileDescriptor(2), b"err\r\n\n")?;
+		fs.write(FileDescriptor(1), b"o\nthr")?;
+		fs.write(FileDescriptor(1), &vec![b'x'; 5000][..])?;
+		fs.flush_output();
+		fs.write(FileDescriptor(1), b"four")?;
+		fs.set_output_callback(None, String::new());
+		fs.write(FileDescriptor(1), b"")?;
+		let seen = SEEN.lock().unwrap();
+		let lines = seen.iter().map(|(fd, l)| (*fd, l.len())).collect::<Vec<_>>();
+		assert_eq!(&seen[..4], &[
+			(1, "[core] one".to_string()),
+			(2, "[core] err".to_string()),
+			(2, "[core] ".to_string()),
+			(1, "[core] two".to_string()),
+		]);
+		assert_eq!(&lines[4..], &[(1, 7 + 4096), (1, 7 + 907), (1, 11)]);
+		Ok(())
+	}
+
+	#[test]
+	fn test_stat() -> TestResult {
+		let mut fs = FileSystem::new();
+		fs.mount("/bios/scph1001.bin".to_string(), vec![0u8; 1000], false)?;
+		fs.mount("/bios/sub/b".to_string(), Vec::new(), false)?;
+		let mut a = KStat::default();
+		fs.stat("/bios/scph1001.bin", &mut a)?;
+		assert_eq!((a.st_size, a.st_nlink, a.st_mode & S_IFMT), (1000, 1, S_IFREG));
+		let fd = fs.open("/bios/scph1001.bin", O_RDONLY, 0)?;
+		let mut b = KStat::default();
+		fs.fstat(fd, &mut b)?;
+		assert_eq!(a.st_ino, b.st_ino);
+		fs.stat("/bios/sub/b", &mut b)?;
+		assert_ne!(a.st_ino, b.st_ino);
+
+		// the same name is the same inode, whatever else there is
+		let mut fs2 = FileSystem::new();
+		fs2.mount("/bios/scph1001.bin".to_string(), Vec::new(), false)?;
+		fs2.stat("/bios/scph1001.bin", &mut b)?;
+		assert_eq!(a.st_ino, b.st_ino);
+
+		for dir in ["/bios", "/bios/", "/bios/sub", "/"].iter() {
+			fs.stat(dir, &mut b)?;
+			assert_eq!(b.st_mode & S_IFMT, S_IFDIR);
+		}
+		assert_eq!(fs.stat("/bio", &mut b), Err(ENOENT));
+		assert_eq!(fs.fstat(FileDescriptor(99), &mut b), Err(EBADF));
+
+		let mut x = Statx::default();
+		fill_statx(&a, &mut x);
+		assert_eq!((x.stx_size, x.stx_ino, x.stx_mode as u32 & S_IFMT), (1000, a.st_ino, S_IFREG));
+		assert_eq!(std::mem::size_of::<Statx>(), 256);
+		Ok(())
+	}
+
+	#[test]
+	fn test_pread() -> TestResult {
+		let mut fs = FileSystem::new();
+		fs.mount("disc.iso".to_string(), "0123456789".to_string().into_bytes(), true)?;
+		let fd = fs.open("disc.iso", O_RDWR, 0)?;
+		assert_eq!(fs.seek(fd, 2, SEEK_SET)?, 2);
+		let mut buff = [0u8; 4];
+		assert_eq!(fs.pread(fd, &mut buff[..], 6)?, 4);
+		assert_eq!(&buff, b"6789");
+		assert_eq!(fs.pread(fd, &mut buff[..], 20)?, 0);
+		assert_eq!(fs.pread(fd, &mut buff[..], -1), Err(EINVAL));
+		assert_eq!(fs.pwrite(fd, b"ab", 12)?, 2);
+		// none of that moved the file position
+		assert_eq!(fs.read(fd, &mut buff[..])?, 4);
+		assert_eq!(&buff, b"2345");
+		assert_eq!(fs.seek(fd, 0, SEEK_END)?, 14);
+		fs.close(fd)?;
+		assert_eq!(fs.unmount("disc.iso")?, b"0123456789\0\0ab");
+		let fd = fs.open("/dev/urandom", O_RDONLY, 0)?;
+		assert_eq!(fs.pread(fd, &mut buff[..], 0), Err(ESPIPE));
+		Ok(())
+	}
+
+	#[test]
+	fn test_overlay() -> TestResult {
+		let mut fs = FileSystem::new();
+		fs.mount_overlay("save.srm".to_string(), "original".to_string().into_bytes(), true)?;
+		fs.mount_overlay("scratch".to_string(), Vec::new(), false)?;
+		let mut flushed = Vec::new();
+		fs.flush(|name, data| { flushed.push((name.to_string(), data.to_vec())); Ok(()) })?;
+		assert!(flushed.is_empty());
+
+		let fd = fs.open("save.srm", O_RDWR, 0)?;
+		fs.seek(fd, 4, SEEK_SET)?;
+		fs.write(fd, "INAL!".as_bytes())?;
+		let mut state0 = Vec::new();
+		fs.save_state(&mut state0)?;
+		fs.truncate("save.srm", 2)?;
+		fs.load_state(&mut &state0[..])?;
+		fs.flush(|name, data| { flushed.push((name.to_string(), data.to_vec())); Ok(()) })?;
+		assert_eq!(flushed, vec![("save.srm".to_string(), "origINAL!".as_bytes().to_vec())]);
+
+		let scratch = fs.open("scratch", O_WRONLY, 0)?;
+		fs.write(scratch, "abc".as_bytes())?;
+		fs.close(scratch)?;
+		flushed.clear();
+		fs.flush(|name, _| { flushed.push((name.to_string(), Vec::new())); Ok(()) })?;
+		assert_eq!(flushed.len(), 1);
+		assert_eq!(fs.unmount("scratch")?, "abc".as_bytes());
+		Ok(())
+	}
+
+	#[test]
+	fn test_host_dir() -> TestResult {
+		let dir = std::env::temp_dir().join(format!("wbx_test_host_dir_{}", std::process::id()));
+		std::fs::create_dir_all(dir.join("sub"))?;
+		std::fs::write(dir.join("bios.bin"), "BIOS")?;
+		std::fs::write(dir.join("sub/shader.glsl"), "void main() {}")?;
+		let res = (|| -> TestResult {
+			let mut fs = FileSystem::new();
+			assert_eq!(fs.mount_host_dir("/firmware/", &dir, true)?, 2);
+			let fd = fs.open("/firmware/sub/shader.glsl", O_RDONLY, 0)?;
+			let mut buff = vec![0u8; 4];
+			assert_eq!(fs.read(fd, &mut buff[..])?, 4);
+			assert_eq!(buff, "void".as_bytes());
+			assert!(fs.write(fd, "x".as_bytes()).is_err());
+			assert!(fs.open("/firmware/bios.bin", O_RDWR, 0).is_err());
+			// mounting over files that are already there fails without mounting anything
+			assert!(fs.mount_host_dir("/firmware", &dir, false).is_err());
+			assert_eq!(fs.mount_host_dir("/rw", &dir, false)?, 2);
+			let fd = fs.open("/rw/bios.bin", O_RDWR, 0)?;
+			fs.write(fd, "b".as_bytes())?;
+			fs.close(fd)?;
+			assert_eq!(fs.unmount("/rw/bios.bin")?, "bIOS".as_bytes());
+			assert_eq!(std::fs::read(dir.join("bios.bin"))?, "BIOS".as_bytes());
+			assert!(fs.mount_host_dir("/missing", &dir.join("nope"), true).is_err());
+			// only allowed directories can be mounted once any are
+			fs.host_paths.allow(&dir.join("sub"), false)?;
+			assert!(fs.mount_host_dir("/denied", &dir, true).is_err());
+			assert_eq!(fs.mount_host_dir("/allowed", &dir.join("sub"), true)?, 1);
+			Ok(())
+		})();
+		std::fs::remove_dir_all(&dir)?;
+		res
+	}
+
+	#[test]
+	fn test_stream() -> TestResult {
+		struct Counter(u8);
+		impl Read for Counter {
+			fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+				// hand out at most 3 bytes at a time, then stop at 10
+				let n = std::cmp::min(std::cmp::min(buf.len(), 3), 10 - self.0 as usize);
+				for b in buf[..n].iter_mut() {
+					*b = self.0;
+					self.0 += 1;
+				}
+				Ok(n)
+			}
+		}
+		let mut fs = FileSystem::new();
+		fs.mount_stream("/dev/tape".to_string(), Box::new(Counter(0)), None)?;
+		assert!(fs.open("/dev/tape", O_RDWR, 0).is_err());
+		let fd = fs.open("/dev/tape", O_RDONLY, 0)?;
+		let mut buff = [0u8; 8];
+		assert_eq!(fs.read(fd, &mut buff[..])?, 3);
+		assert_eq!(&buff[..3], &[0, 1, 2]);
+		assert_eq!(fs.seek(fd, 0, SEEK_SET), Err(ESPIPE));
+		let mut state0 = Vec::new();
+		assert!(fs.save_state(&mut state0).is_err());
+		let mut rest = Vec::new();
+		loop {
+			match fs.read(fd, &mut buff[..])? {
+				0 => break,
+				n => rest.extend_from_slice(&buff[..n as usize]),
+			}
+		}
+		assert_eq!(rest, (3..10).collect::<Vec<u8>>());
+		fs.close(fd)?;
+		fs.unmount("/dev/tape")?;
+		state0.clear();
+		fs.save_state(&mut state0)?;
+		Ok(())
+	}
+
+	#[test]
+	fn test_poll() -> TestResult {
+		let mut fs = FileSystem::new();
+		let ready = Rc::new(RefCell::new(false));
+		let r = ready.clone();
+		fs.mount_stream("/dev/link".to_string(), Box::new(std::io::empty()), Some(Box::new(move || *r.borrow())))?;
+		fs.mount("rom".to_string(), Vec::new(), false)?;
+		let link = fs.open("/dev/link", O_RDONLY, 0)?;
+		let rom = fs.open("rom", O_RDONLY, 0)?;
+		let mut fds = [
+			PollFd { fd: link.0, events: POLLIN, revents: 0 },
+			PollFd { fd: rom.0, events: POLLIN | POLLOUT, revents: 0 },
+			PollFd { fd: 1, events: POLLOUT, revents: 0 },
+			PollFd { fd: 42, events: POLLIN, revents: 0 },
+			PollFd { fd: -1, events: POLLIN, revents: 0 },
+		];
+		assert_eq!(fs.poll(&mut fds), 3);
+		assert_eq!(fds.iter().map(|p| p.revents).collect::<Vec<_>>(), vec![0, POLLIN, POLLOUT, POLLNVAL, 0]);
+		*ready.borrow_mut() = true;
+		assert_eq!(fs.poll(&mut fds[..1]), 1);
+		assert_eq!(fds[0].revents, POLLIN);
+
+		let ep = fs.epoll_create(false)?;
+		assert_eq!(ep.0, 5);
+		fs.epoll_ctl(ep, EPOLL_CTL_ADD, link, Some(EpollEvent { events: POLLIN as u32, data: 77 }))?;
+		fs.epoll_ctl(ep, EPOLL_CTL_ADD, rom, Some(EpollEvent { events: (POLLIN as u32) | EPOLLONESHOT, data: 88 }))?;
+		assert_eq!(fs.epoll_ctl(ep, EPOLL_CTL_ADD, rom, Some(EpollEvent::default())), Err(EEXIST));
+		assert_eq!(fs.epoll_ctl(ep, EPOLL_CTL_ADD, ep, Some(EpollEvent::default())), Err(EINVAL));
+		assert_eq!(fs.epoll_ctl(rom, EPOLL_CTL_ADD, link, Some(EpollEvent::default())), Err(EINVAL));
+		let mut state0 = Vec::new();
+		fs.close(link)?;
+		fs.unmount("/dev/link")?;
+		fs.save_state(&mut state0)?;
+
+		let mut events = [EpollEvent::default(); 4];
+		assert_eq!(fs.epoll_wait(ep, &mut events)?, 1);
+		assert_eq!({ events[0].data }, 88);
+		// oneshot, so it's done until it's modified
+		assert_eq!(fs.epoll_wait(ep, &mut events)?, 0);
+		fs.load_state(&mut &state0[..])?;
+		assert_eq!(fs.epoll_wait(ep, &mut events)?, 1);
+		fs.epoll_ctl(ep, EPOLL_CTL_DEL, rom, None)?;
+		assert_eq!(fs.epoll_wait(ep, &mut events)?, 0);
+		fs.close(ep)?;
+		assert_eq!(fs.epoll_wait(ep, &mut events), Err(EBADF));
+		Ok(())
+	}
+
+	#[test]
+	fn test_socket() -> TestResult {
+		#[derive(Default)]
+		struct Log {
+			events: Vec<String>,
+		}
+		struct Host(Rc<RefCell<Log>>);
+		impl SocketHost for Host {
+			fn connect(&mut self, addr: &str) -> Result<u64, SyscallError> {
+				self.0.borrow_mut().events.push(format!("connect {}", addr));
+				if addr.starts_with("unix:") { Ok(7) } else { Err(ECONNREFUSED) }
+			}
+			fn send(&mut self, handle: u64, buf: &[u8]) -> Result<usize, SyscallError> {
+				self.0.borrow_mut().events.push(format!("send {} {:?}", handle, std::str::from_utf8(buf).unwrap()));
+				Ok(buf.len())
+			}
+			fn recv(&mut self, _handle: u64, buf: &mut [u8]) -> Result<usize, SyscallError> {
+				buf[0] = b'!';
+				Ok(1)
+			}
+			fn ready(&mut self, _handle: u64) -> bool {
+				false
+			}
+			fn close(&mut self, handle: u64) {
+				self.0.borrow_mut().events.push(format!("close {}", handle));
+			}
+		}
+		let mut fs = FileSystem::new();
+		assert_eq!(fs.socket(AF_UNIX, SOCK_STREAM), Err(EACCES));
+		let log = Rc::new(RefCell::new(Log::default()));
+		fs.set_socket_host(Some(Box::new(Host(log.clone()))));
+		assert_eq!(fs.socket(17, SOCK_STREAM), Err(EAFNOSUPPORT));
+		assert_eq!(fs.socket(AF_INET, 5), Err(EPROTONOSUPPORT));
+
+		let inet = fs.socket(AF_INET, SOCK_STREAM | SOCK_CLOEXEC)?;
+		assert_eq!(fs.write(inet, b"x"), Err(ENOTCONN));
+		assert_eq!(fs.connect(inet, "inet:10.0.0.1:80"), Err(ECONNREFUSED));
+		let unix = fs.socket(AF_UNIX, SOCK_STREAM)?;
+		fs.connect(unix, "unix:/tmp/link")?;
+		assert_eq!(fs.connect(unix, "unix:/tmp/link"), Err(EISCONN));
+		assert_eq!(fs.connect(FileDescriptor(1), "unix:/tmp/link"), Err(ENOTSOCK));
+		assert_eq!(fs.write(unix, b"hi")?, 2);
+		let mut buff = [0u8; 4];
+		assert_eq!(fs.read(unix, &mut buff[..])?, 1);
+		let mut fds = [PollFd { fd: unix.0, events: POLLIN | POLLOUT, revents: 0 }];
+		fs.poll(&mut fds);
+		assert_eq!(fds[0].revents, POLLOUT);
+		let mut statbuff = KStat::default();
+		fs.fstat(unix, &mut statbuff)?;
+		assert_eq!(statbuff.st_mode & S_IFMT, S_IFSOCK);
+		let mut state0 = Vec::new();
+		assert!(fs.save_state(&mut state0).is_err());
+		fs.close(unix)?;
+		fs.close(inet)?;
+		assert_eq!(fs.close(unix), Err(EBADF));
+		assert_eq!(log.borrow().events, vec!["connect inet:10.0.0.1:80", "connect unix:/tmp/link", "send 7 \"hi\"", "close 7"]);
+		state0.clear();
+		fs.save_state(&mut state0)?;
+		Ok(())
+	}
+
+	#[test]
+	fn test_sockaddr() {
+		assert_eq!(format_sockaddr(&[2, 0, 0x1f, 0x90, 127, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0]), Ok("inet:127.0.0.1:8080".to_string()));
+		let mut v6 = vec![10, 0, 0, 80, 0, 0, 0, 0];
+		v6.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+		v6.extend_from_slice(&[0, 0, 0, 0]);
+		assert_eq!(format_sockaddr(&v6), Ok("inet6:[::1]:80".to_string()));
+		assert_eq!(format_sockaddr(b"\x01\x00/tmp/link\x00junk"), Ok("unix:/tmp/link".to_string()));
+		assert_eq!(format_sockaddr(b"\x01\x00\x00link"), Ok("unix:@link".to_string()));
+		assert_eq!(format_sockaddr(b"\x01\x00"), Err(EINVAL));
+		assert_eq!(format_sockaddr(&[2, 0, 0]), Err(EINVAL));
+		assert_eq!(format_sockaddr(&[9, 0, 0]), Err(EAFNOSUPPORT));
+	}
+
+	/// A zip archive with the given files, which are (name, method, compressed content, crc, size)
+	fn make_zip(files: &[(&str, u16, &[u8], u32, usize)]) -> Vec<u8> {
+		let mut res = Vec::new();
+		let mut dir = Vec::new();
+		for &(name, method, content, crc, size) in files.iter() {
+			let offset = res.len() as u32;
+			let common = |v: &mut Vec<u8>| {
+				v.extend_from_slice(&20u16.to_le_bytes());
+				v.extend_from_slice(&0u16.to_le_bytes());
+				v.extend_from_slice(&method.to_le_bytes());
+				v.extend_from_slice(&[0u8; 4]);
+				v.extend_from_slice(&crc.to_le_bytes());
+				v.extend_from_slice(&(content.len() as u32).to_le_bytes());
+				v.extend_from_slice(&(size as u32).to_le_bytes());
+				v.extend_from_slice(&(name.len() as u16).to_le_bytes());
+				v.extend_from_slice(&0u16.to_le_bytes());
+			};
+			res.extend_from_slice(&0x04034b50u32.to_le_bytes());
+			common(&mut res);
+			res.extend_from_slice(name.as_bytes());
+			res.extend_from_slice(content);
+			dir.extend_from_slice(&0x02014b50u32.to_le_bytes());
+			dir.extend_from_slice(&20u16.to_le_bytes());
+			common(&mut dir);
+			dir.extend_from_slice(&[0u8; 10]);
+			dir.extend_from_slice(&offset.to_le_bytes());
+			dir.extend_from_slice(name.as_bytes());
+		}
+		let dir_offset = res.len() as u32;
+		res.extend_from_slice(&dir);
+		res.extend_from_slice(&0x06054b50u32.to_le_bytes());
+		res.extend_from_slice(&[0u8; 4]);
+		res.extend_from_slice(&(files.len() as u16).to_le_bytes());
+		res.extend_from_slice(&(files.len() as u16).to_le_bytes());
+		res.extend_from_slice(&(dir.len() as u32).to_le_bytes());
+		res.extend_from_slice(&dir_offset.to_le_bytes());
+		res.extend_from_slice(&0u16.to_le_bytes());
+		res
+	}
+
+	#[test]
+	fn test_zip() -> TestResult {
+		let deflated = [0x0b, 0xc9, 0x48, 0x55, 0x28, 0x2c, 0xcd, 0x4c, 0xce, 0x56, 0x48, 0x2a, 0xca, 0x2f, 0xcf, 0x53,
+			0x48, 0xcb, 0xaf, 0x50, 0xc8, 0x2a, 0xcd, 0x2d, 0x28, 0x56, 0xc8, 0x2f, 0x4b, 0x2d, 0x52, 0x28, 0x01, 0x4a, 0xe7,
+			0x24, 0x56, 0x55, 0x2a, 0xa4, 0xe4, 0xa7, 0xeb, 0x29, 0x84, 0xd0, 0x4c, 0x31, 0x00];
+		let fox = "The quick brown fox jumps over the lazy dog. ".repeat(3).into_bytes();
+		let zip = make_zip(&[
+			("roms/", 0, b"", 0, 0),
+			("roms/a.bin", 0, b"hello", inflate::crc32(b"hello"), 5),
+			("fox.txt", 8, &deflated, inflate::crc32(&fox), fox.len()),
+			("bad.txt", 0, b"oops", 1234, 4),
+		]);
+		let mut fs = FileSystem::new();
+		assert_eq!(fs.mount_zip("/sets/game.zip/", zip.clone())?, 3);
+		assert!(fs.open("/sets/game.zip/roms", O_RDONLY, 0).is_err());
+		let fd = fs.open("/sets/game.zip/fox.txt", O_RDONLY, 0)?;
+		let mut statbuff = Box::new(KStat::default());
+		fs.fstat(fd, statbuff.as_mut())?;
+		assert_eq!(statbuff.st_size, fox.len() as i64);
+		assert_eq!(fs.seek(fd, 4, SEEK_SET)?, 4);
+		let mut buff = vec![0u8; 5];
+		assert_eq!(fs.read(fd, &mut buff[..])?, 5);
+		assert_eq!(buff, "quick".as_bytes());
+		let mut state0 = Vec::new();
+		fs.save_state(&mut state0)?;
+		fs.close(fd)?;
+		fs.load_state(&mut &state0[..])?;
+		assert_eq!(fs.read(fd, &mut buff[..])?, 5);
+		assert_eq!(buff, " brow".as_bytes());
+
+		let fd2 = fs.open("/sets/game.zip/bad.txt", O_RDONLY, 0)?;
+		assert!(fs.write(fd2, b"x").is_err());
+		assert_eq!(fs.read(fd2, &mut buff[..]), Err(EIO));
+		assert_eq!(fs.unmount("/sets/game.zip/roms/a.bin")?, b"hello");
+
+		// fox.txt is still there
+		assert!(fs.mount_zip("/sets/game.zip", zip).is_err());
+		assert!(fs.mount_zip("/other", b"not a zip".to_vec()).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_random() -> TestResult {
+		let mut fs = FileSystem::new();
+		fs.set_random_seed(1234);
+		let fd = fs.open("/dev/urandom", O_RDONLY, 0)?;
+		let mut a = vec![0u8; 13];
+		assert_eq!(fs.read(fd, &mut a[..])?, 13);
+		let mut state0 = Vec::new();
+		fs.save_state(&mut state0)?;
+		let mut b = vec![0u8; 16];
+		fs.getrandom(&mut b[..]);
+		fs.load_state(&mut &state0[..])?;
+		let mut c = vec![0u8; 16];
+		fs.getrandom(&mut c[..]);
+		assert_eq!(b, c);
+
+		// the same seed gives the same bytes, no matter where they're read from
+		let mut fs2 = FileSystem::new();
+		fs2.set_random_seed(1234);
+		let mut d = vec![0u8; 13];
+		fs2.getrandom(&mut d[..]);
+		assert_eq!(a, d);
+		assert_ne!(a, vec![0u8; 13]);
+		Ok(())
+	}
 }
@@ -0,0 +1,135 @@
+use crate::syscall_defs::*;
+use crate::*;
+use std::io::{Write, Read};
+use super::*;
+
+/// A file with fixed original content that the guest can still write to.  Writes go to a copy of the content that is
+/// made the first time it's changed, and that copy is part of savestates, so the original never has to be.
+pub struct OverlayFile {
+	base: Vec<u8>,
+	hash: Vec<u8>,
+	overlay: Option<Vec<u8>>,
+	position: usize,
+	/// Whether the host wants the overlay written back to disk when it flushes files
+	persist: bool,
+}
+impl OverlayFile {
+	pub fn new(base: Vec<u8>, persist: bool) -> OverlayFile {
+		let hash = bin::hash(&base[..]);
+		OverlayFile {
+			base,
+			hash,
+			overlay: None,
+			position: 0,
+			persist,
+		}
+	}
+	fn data(&self) -> &[u8] {
+		match &self.overlay {
+			Some(o) => &o[..],
+			None => &self.base[..],
+		}
+	}
+	fn data_mut(&mut self) -> &mut Vec<u8> {
+		let base = &self.base;
+		self.overlay.get_or_insert_with(|| base.clone())
+	}
+}
+impl IStateable for OverlayFile {
+	fn save_state(&mut self, stream: &mut dyn Write) -> anyhow::Result<()> {
+		bin::write_magic(stream, "OverlayFile")?;
+		bin::write_hash(stream, &self.hash[..])?;
+		bin::write(stream, &self.position)?;
+		match &self.overlay {
+			Some(o) => {
+				bin::writeval(stream, o.len())?;
+				stream.write_all(&o[..])?;
+			},
+			None => bin::writeval(stream, usize::MAX)?,
+		}
+		Ok(())
+	}
+	fn load_state(&mut self, stream: &mut dyn Read) -> anyhow::Result<()> {
+		bin::verify_magic(stream, "OverlayFile")?;
+		bin::verify_hash(stream, &self.hash[..])?;
+		bin::read(stream, &mut self.position)?;
+		self.overlay = match bin::readval::<usize>(stream)? {
+			usize::MAX => None,
+			len => {
+				let mut o = vec![0u8; len];
+				stream.read_exact(&mut o[..])?;
+				Some(o)
+			}
+		};
+		Ok(())
+	}
+}
+impl FileObject for OverlayFile {
+	fn can_read(&self) -> bool {
+		true
+	}
+	fn read(&mut self, buf: &mut [u8]) -> Result<i64, SyscallError> {
+		let data = self.data();
+		let n = std::cmp::min(buf.len(), data.len() - self.position);
+		buf[0..n].copy_from_slice(&data[self.position..self.position + n]);
+		self.position += n;
+		Ok(n as i64)
+	}
+	fn can_write(&self) -> bool {
+		true
+	}
+	fn write(&mut self, buf: &[u8]) -> Result<i64, SyscallError> {
+		let position = self.position;
+		let newpos = position + buf.len();
+		let data = self.data_mut();
+		if newpos > data.len() {
+			data.resize(newpos, 0);
+		}
+		data[position..newpos].copy_from_slice(buf);
+		self.position = newpos;
+		Ok(buf.len() as i64)
+	}
+	fn seek(&mut self, offset: i64, whence: i32) -> Result<i64, SyscallError> {
+		let newpos = match whence {
+			SEEK_SET => offset,
+			SEEK_CUR => self.position as i64 + offset,
+			SEEK_END => self.data().len() as i64 + offset,
+			_ => return Err(EINVAL)
+		};
+		if newpos < 0 || newpos > self.data().len() as i64 {
+			return Err(EINVAL)
+		}
+		self.position = newpos as usize;
+		Ok(newpos)
+	}
+	fn truncate(&mut self, size: i64) -> SyscallResult {
+		if size < 0 {
+			return Err(EINVAL)
+		}
+		self.data_mut().resize(size as usize, 0);
+		self.position = std::cmp::min(self.position, size as usize);
+		Ok(())
+	}
+	fn reset(&mut self) {
+		self.position = 0;
+	}
+	fn stat(&self, statbuff: &mut KStat) -> SyscallResult {
+		fill_stat(statbuff, true, true, true, self.data().len() as i64)
+	}
+	fn can_unmount(&self) -> bool {
+		true
+	}
+	fn unmount(self: Box<Self>) -> Vec<u8> {
+		match self.overlay {
+			Some(o) => o,
+			None => self.base,
+		}
+	}
+	fn persistent_data(&self) -> Option<&[u8]> {
+		if self.persist {
+			self.overlay.as_deref()
+		} else {
+			None
+		}
+	}
+}
@@ -0,0 +1,143 @@
+use crate::syscall_defs::*;
+use crate::*;
+use std::io::{Write, Read};
+use super::*;
+
+/// What an epoll instance is watching for on one file
+#[derive(Clone, Copy)]
+struct Interest {
+	fd: FileDescriptor,
+	events: u32,
+	data: u64,
+}
+
+/// An epoll instance.  These are made by the guest, not mounted by the host, so all of their state goes in savestates.
+pub struct Epoll {
+	pub fd: FileDescriptor,
+	interest: Vec<Interest>,
+}
+impl Epoll {
+	pub fn new(fd: FileDescriptor) -> Epoll {
+		Epoll { fd, interest: Vec::new() }
+	}
+	/// Stop watching a file, because it was closed
+	pub fn forget(&mut self, fd: FileDescriptor) {
+		self.interest.retain(|i| i.fd != fd);
+	}
+}
+impl IStateable for Epoll {
+	fn save_state(&mut self, stream: &mut dyn Write) -> anyhow::Result<()> {
+		bin::write_magic(stream, "Epoll")?;
+		bin::write(stream, &self.fd)?;
+		bin::writeval(stream, self.interest.len())?;
+		for i in self.interest.iter() {
+			bin::write(stream, &i.fd)?;
+			bin::write(stream, &i.events)?;
+			bin::write(stream, &i.data)?;
+		}
+		Ok(())
+	}
+	fn load_state(&mut self, stream: &mut dyn Read) -> anyhow::Result<()> {
+		bin::verify_magic(stream, "Epoll")?;
+		bin::read(stream, &mut self.fd)?;
+		let count: usize = bin::readval(stream)?;
+		self.interest.clear();
+		for _ in 0..count {
+			self.interest.push(Interest {
+				fd: bin::readval(stream)?,
+				events: bin::readval(stream)?,
+				data: bin::readval(stream)?,
+			});
+		}
+		Ok(())
+	}
+}
+
+impl FileSystem {
+	/// What's ready on an open file, or None if it isn't open
+	fn readiness(&mut self, fd: FileDescriptor) -> Option<i16> {
+		let fd = self.fds.get(fd).ok()?;
+		if self.epolls.iter().any(|e| e.fd == fd) {
+			// an epoll instance is only readable when something it watches is ready, and nobody's polling those
+			return Some(0)
+		}
+		self.files.iter_mut().find(|f| f.fd == fd).map(|f| f.obj.poll())
+	}
+	/// Implements poll(2), without waiting.  Fills in revents and returns how many descriptors have something.
+	pub fn poll(&mut self, fds: &mut [PollFd]) -> usize {
+		let mut res = 0;
+		for p in fds.iter_mut() {
+			p.revents = if p.fd < 0 {
+				0
+			} else {
+				match self.readiness(FileDescriptor(p.fd)) {
+					Some(r) => r & (p.events | POLLERR | POLLHUP),
+					None => POLLNVAL,
+				}
+			};
+			if p.revents != 0 {
+				res += 1;
+			}
+		}
+		res
+	}
+	/// Implements epoll_create1(2)
+	pub fn epoll_create(&mut self, cloexec: bool) -> Result<FileDescriptor, SyscallError> {
+		let fd = self.fds.lowest_free(0)?;
+		self.epolls.push(Epoll::new(fd));
+		self.fds.open(fd, O_RDWR, cloexec);
+		Ok(fd)
+	}
+	fn epoll(&mut self, epfd: FileDescriptor) -> Result<&mut Epoll, SyscallError> {
+		let open = self.fds.get(epfd)?;
+		match self.epolls.iter_mut().find(|e| e.fd == open) {
+			Some(e) => Ok(e),
+			None => Err(EINVAL),
+		}
+	}
+	/// Implements epoll_ctl(2).  Everything is level triggered; EPOLLET is accepted, but treated the same.
+	pub fn epoll_ctl(&mut self, epfd: FileDescriptor, op: i32, fd: FileDescriptor, event: Option<EpollEvent>) -> SyscallResult {
+		let open = self.fds.get(fd)?;
+		if !self.files.iter().any(|f| f.fd == open) {
+			return Err(EINVAL)
+		}
+		let epoll = self.epoll(epfd)?;
+		let existing = epoll.interest.iter().position(|i| i.fd == fd);
+		match (op, existing, event) {
+			(EPOLL_CTL_ADD, None, Some(ev)) => epoll.interest.push(Interest { fd, events: ev.events, data: ev.data }),
+			(EPOLL_CTL_ADD, Some(_), _) => return Err(EEXIST),
+			(EPOLL_CTL_MOD, Some(i), Some(ev)) => epoll.interest[i] = Interest { fd, events: ev.events, data: ev.data },
+			(EPOLL_CTL_DEL, Some(i), _) => { epoll.interest.remove(i); },
+			(EPOLL_CTL_MOD, None, _) | (EPOLL_CTL_DEL, None, _) => return Err(ENOENT),
+			(EPOLL_CTL_ADD, _, None) | (EPOLL_CTL_MOD, _, None) => return Err(EFAULT),
+			_ => return Err(EINVAL),
+		}
+		Ok(())
+	}
+	/// Implements epoll_wait(2), without waiting.  Returns how many of `events` were filled in.
+	pub fn epoll_wait(&mut self, epfd: FileDescriptor, events: &mut [EpollEvent]) -> Result<usize, SyscallError> {
+		if events.is_empty() {
+			return Err(EINVAL)
+		}
+		let interest = self.epoll(epfd)?.interest.clone();
+		let mut res = 0;
+		for i in interest.iter() {
+			if res == events.len() {
+				break
+			}
+			let ready = self.readiness(i.fd).unwrap_or(0) as u16 as u32 & (i.events | POLLERR as u32 | POLLHUP as u32);
+			if ready != 0 {
+				events[res] = EpollEvent { events: ready, data: i.data };
+				res += 1;
+				if i.events & EPOLLONESHOT != 0 {
+					// disarmed until the guest uses EPOLL_CTL_MOD
+					let epoll = self.epoll(epfd)?;
+					if let Some(x) = epoll.interest.iter_mut().find(|x| x.fd == i.fd) {
+						x.events = 0;
+					}
+				}
+			}
+		}
+		Ok(res)
+	}
+}
@@ -0,0 +1,136 @@
+// A few files from /proc, for libraries that read them when they start up, like allocators that look for their stack
+// in /proc/self/maps and math libraries that look for CPU features in /proc/cpuinfo.  What they say is made up from the
+// guest's layout and nothing else, so every machine gives the guest the same thing.  They're readonly, and savestates
+// only have where each one is read up to.
+use crate::syscall_defs::*;
+use crate::*;
+use std::io::{Write, Read};
+use super::*;
+
+/// The same for every guest:  One plain x86-64 CPU with no features past the baseline
+const CPUINFO: &str = "processor\t: 0
+vendor_id\t: Waterbox
+cpu family\t: 6
+model\t\t: 0
+model name\t: Waterbox Virtual CPU
+stepping\t: 0
+cpu MHz\t\t: 1000.000
+cache size\t: 0 KB
+physical id\t: 0
+siblings\t: 1
+core id\t\t: 0
+cpu cores\t: 1
+fpu\t\t: yes
+flags\t\t: fpu tsc cx8 cmov mmx fxsr sse sse2 syscall lm
+bogomips\t: 2000.00
+clflush size\t: 64
+cache_alignment\t: 64
+address sizes\t: 48 bits physical, 48 bits virtual
+
+";
+
+/// The /proc files, by name, for a guest called `module_name` laid out as `layout`
+pub fn proc_files(layout: &WbxSysLayout, module_name: &str) -> Vec<(String, Vec<u8>)> {
+	let areas = [
+		(layout.elf, "r-xp", format!("/{}", module_name)),
+		(layout.sbrk, "rw-p", "[heap]".to_string()),
+		(layout.sealed, "rw-p", String::new()),
+		(layout.invis, "rw-p", String::new()),
+		(layout.plain, "rw-p", String::new()),
+		(layout.mmap, "rw-p", String::new()),
+	];
+	let mut maps = String::new();
+	for (addr, perms, name) in areas.iter().filter(|a| a.0.size > 0) {
+		maps += &format!("{:08x}-{:08x} {} 00000000 00:00 0", addr.start, addr.end(), perms);
+		if !name.is_empty() {
+			maps += "          ";
+			maps += name;
+		}
+		maps += "\n";
+	}
+	let total = layout.all().size >> 10;
+	let meminfo = format!("MemTotal:       {:8} kB\nMemFree:        {:8} kB\nMemAvailable:   {:8} kB\nSwapTotal:             0 kB\nSwapFree:              0 kB\n",
+		total, total, total);
+	let mut cmdline = module_name.as_bytes().to_vec();
+	cmdline.push(0);
+	vec![
+		("/proc/cpuinfo".to_string(), CPUINFO.as_bytes().to_vec()),
+		("/proc/meminfo".to_string(), meminfo.into_bytes()),
+		("/proc/self/cmdline".to_string(), cmdline),
+		("/proc/self/maps".to_string(), maps.into_bytes()),
+	]
+}
+
+pub struct ProcFile {
+	data: Vec<u8>,
+	position: usize,
+}
+impl ProcFile {
+	pub fn new(data: Vec<u8>) -> ProcFile {
+		ProcFile { data, position: 0 }
+	}
+}
+impl IStateable for ProcFile {
+	fn save_state(&mut self, stream: &mut dyn Write) -> anyhow::Result<()> {
+		bin::write_magic(stream, "ProcFile")?;
+		bin::write(stream, &self.position)?;
+		Ok(())
+	}
+	fn load_state(&mut self, stream: &mut dyn Read) -> anyhow::Result<()> {
+		bin::verify_magic(stream, "ProcFile")?;
+		bin::read(stream, &mut self.position)?;
+		if self.position > self.data.len() {
+			return Err(coded(ErrorCode::BadStateData, "ProcFile position is past its end"))
+		}
+		Ok(())
+	}
+}
+impl FileObject for ProcFile {
+	fn can_read(&self) -> bool {
+		true
+	}
+	fn read(&mut self, buf: &mut [u8]) -> Result<i64, SyscallError> {
+		let n = std::cmp::min(buf.len(), self.data.len() - self.position);
+		buf[0..n].copy_from_slice(&self.data[self.position..self.position + n]);
+		self.position += n;
+		Ok(n as i64)
+	}
+	fn can_write(&self) -> bool {
+		false
+	}
+	fn write(&mut self, _buf: &[u8]) -> Result<i64, SyscallError> {
+		Err(EBADF)
+	}
+	fn seek(&mut self, offset: i64, whence: i32) -> Result<i64, SyscallError> {
+		let newpos = match whence {
+			SEEK_SET => offset,
+			SEEK_CUR => self.position as i64 + offset,
+			SEEK_END => self.data.len() as i64 + offset,
+			_ => return Err(EINVAL)
+		};
+		if newpos < 0 || newpos > self.data.len() as i64 {
+			return Err(EINVAL)
+		}
+		self.position = newpos as usize;
+		Ok(newpos)
+	}
+	fn truncate(&mut self, _size: i64) -> SyscallResult {
+		Err(EINVAL)
+	}
+	fn reset(&mut self) {
+		self.position = 0;
+	}
+	/// Like the real ones, which are always 0 bytes long, so readers go until they get nothing
+	fn stat(&self, statbuff: &mut KStat) -> SyscallResult {
+		fill_stat(statbuff, true, false, true, 0)
+	}
+	fn can_unmount(&self) -> bool {
+		false
+	}
+	fn unmount(self: Box<Self>) -> Vec<u8> {
+		panic!()
+	}
+	fn is_proc(&self) -> bool {
+		true
+	}
+}
@@ -0,0 +1,86 @@
+use crate::syscall_defs::*;
+use crate::*;
+use std::io::{Write, Read};
+use std::{cell::RefCell, rc::Rc};
+use super::*;
+
+/// Deterministic source of "entropy" for the guest, so that runs stay reproducible.  This is splitmix64.
+pub struct Rng {
+	state: u64,
+}
+impl Rng {
+	pub fn new(seed: u64) -> Rng {
+		Rng { state: seed }
+	}
+	pub fn next(&mut self) -> u64 {
+		self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+		let mut z = self.state;
+		z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+		z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+		z ^ (z >> 31)
+	}
+	pub fn fill(&mut self, buf: &mut [u8]) {
+		for chunk in buf.chunks_mut(8) {
+			let v = self.next().to_le_bytes();
+			chunk.copy_from_slice(&v[..chunk.len()]);
+		}
+	}
+}
+impl IStateable for Rng {
+	fn save_state(&mut self, stream: &mut dyn Write) -> anyhow::Result<()> {
+		bin::write_magic(stream, "Rng")?;
+		bin::write(stream, &self.state)?;
+		Ok(())
+	}
+	fn load_state(&mut self, stream: &mut dyn Read) -> anyhow::Result<()> {
+		bin::verify_magic(stream, "Rng")?;
+		bin::read(stream, &mut self.state)?;
+		Ok(())
+	}
+}
+
+/// /dev/urandom and /dev/random.  The FileSystem owns the state of the shared Rng.
+pub struct RandomDevice {
+	pub rng: Rc<RefCell<Rng>>,
+}
+impl IStateable for RandomDevice {
+	fn save_state(&mut self, stream: &mut dyn Write) -> anyhow::Result<()> {
+		bin::write_magic(stream, "RandomDevice")?;
+		Ok(())
+	}
+	fn load_state(&mut self, stream: &mut dyn Read) -> anyhow::Result<()> {
+		bin::verify_magic(stream, "RandomDevice")?;
+		Ok(())
+	}
+}
+impl FileObject for RandomDevice {
+	fn can_read(&self) -> bool {
+		true
+	}
+	fn read(&mut self, buf: &mut [u8]) -> Result<i64, SyscallError> {
+		self.rng.borrow_mut().fill(buf);
+		Ok(buf.len() as i64)
+	}
+	fn can_write(&self) -> bool {
+		false
+	}
+	fn write(&mut self, _buf: &[u8]) -> Result<i64, SyscallError> {
+		Err(EBADF)
+	}
+	fn seek(&mut self, _offset: i64, _whence: i32) -> Result<i64, SyscallError> {
+		Err(ESPIPE)
+	}
+	fn truncate(&mut self, _size: i64) -> SyscallResult {
+		Err(EINVAL)
+	}
+	fn stat(&self, statbuff: &mut KStat) -> SyscallResult {
+		fill_stat(statbuff, true, false, false, 0)
+	}
+	fn can_unmount(&self) -> bool {
+		false
+	}
+	fn unmount(self: Box<Self>) -> Vec<u8> {
+		panic!()
+	}
+	fn reset(&mut self) {}
+}
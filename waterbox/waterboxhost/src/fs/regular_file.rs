@@ -2,19 +2,20 @@ use crate::syscall_defs::*;
 use crate::*;
 use std::io::{Write, Read};
 use super::*;
+use super::chunked::ChunkedData;
 
 /// A file whose content is in memory and managed by the waterbox host
 pub struct RegularFile {
-	data: Vec<u8>,
+	data: ChunkedData,
 	hash: Option<Vec<u8>>,
 	position: usize,
 }
 impl RegularFile {
-	pub fn new(data: Vec<u8>, writable: bool) -> RegularFile {
+	pub fn new(data: ChunkedData, writable: bool) -> RegularFile {
 		let hash = if writable {
 			None
 		} else {
-			Some(bin::hash(&data[..]))
+			Some(data.hash())
 		};
 		RegularFile {
 			data,
@@ -53,10 +54,7 @@ impl FileObject for RegularFile {
 		true
 	}
 	fn read(&mut self, buf: &mut [u8]) -> Result<i64, SyscallError> {
-		let n = std::cmp::min(buf.len(), self.data.len() - self.position);
-		let dst = &mut buf[0..n];
-		let src = &self.data[self.position..self.position + n];
-		dst.copy_from_slice(src);
+		let n = self.data.read_at(self.position, buf);
 		self.position += n;
 		Ok(n as i64)
 	}
@@ -71,30 +69,24 @@ impl FileObject for RegularFile {
 			return Err(EBADF)
 		}
 		let n = buf.len();
-		let newpos = self.position + n;
-		if newpos > self.data.len() {
-			self.data.resize(newpos, 0);
-		}
-		let dst = &mut self.data[self.position..newpos];
-
-		dst.copy_from_slice(buf);
-		self.position = newpos;
+		self.data.write_at(self.position, buf);
+		self.position += n;
 		Ok(n as i64)
 	}
 	fn seek(&mut self, offset: i64, whence: i32) -> Result<i64, SyscallError> {
 		let newpos = match whence {
 			SEEK_SET => {
-				0
+				offset
 			},
 			SEEK_CUR => {
 				self.position as i64 + offset
 			},
 			SEEK_END => {
-				self.data.len() as i64 + offset
+				self.data.size() as i64 + offset
 			}
 			_ => return Err(EINVAL)
 		};
-		if newpos < 0 || newpos > self.data.len() as i64 {
+		if newpos < 0 || newpos > self.data.size() as i64 {
 			return Err(EINVAL)
 		}
 		self.position = newpos as usize;
@@ -107,7 +99,7 @@ impl FileObject for RegularFile {
 		if size < 0 {
 			return Err(EINVAL)
 		}
-		self.data.resize(size as usize, 0);
+		self.data.resize(size as usize);
 		self.position = std::cmp::min(self.position, size as usize);
 		Ok(())
 	}
@@ -115,12 +107,12 @@ impl FileObject for RegularFile {
 		self.position = 0;
 	}
 	fn stat(&self, statbuff: &mut KStat) -> SyscallResult {
-		fill_stat(statbuff, true, self.can_write(), true, self.data.len() as i64)
+		fill_stat(statbuff, true, self.can_write(), true, self.data.size() as i64)
 	}
 	fn can_unmount(&self) -> bool {
 		true
 	}
 	fn unmount(self: Box<Self>) -> Vec<u8> {
-		self.data
+		self.data.into_vec()
 	}
 }
@@ -0,0 +1,134 @@
+use crate::syscall_defs::*;
+use crate::*;
+use std::io::{Write, Read};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+use std::{cell::RefCell, rc::Rc};
+use super::*;
+
+/// Whatever the host has standing in for the network.  Connections are named by the handles it gives out.
+pub trait SocketHost {
+	/// Connect to an address like `inet:127.0.0.1:8080` or `unix:/tmp/link`
+	fn connect(&mut self, addr: &str) -> Result<u64, SyscallError>;
+	fn send(&mut self, handle: u64, buf: &[u8]) -> Result<usize, SyscallError>;
+	/// Nothing there yet should be EAGAIN; the guest can't wait for it
+	fn recv(&mut self, handle: u64, buf: &mut [u8]) -> Result<usize, SyscallError>;
+	/// Whether a recv would get anything, for poll(2)
+	fn ready(&mut self, handle: u64) -> bool;
+	fn close(&mut self, handle: u64);
+}
+
+/// Describe a guest sockaddr in the form SocketHost::connect takes
+pub fn format_sockaddr(addr: &[u8]) -> Result<String, SyscallError> {
+	if addr.len() < 2 {
+		return Err(EINVAL)
+	}
+	match u16::from_le_bytes([addr[0], addr[1]]) {
+		AF_INET if addr.len() >= 8 => {
+			let port = u16::from_be_bytes([addr[2], addr[3]]);
+			let ip = Ipv4Addr::new(addr[4], addr[5], addr[6], addr[7]);
+			Ok(format!("inet:{}", SocketAddrV4::new(ip, port)))
+		},
+		AF_INET6 if addr.len() >= 24 => {
+			let port = u16::from_be_bytes([addr[2], addr[3]]);
+			let mut ip = [0u8; 16];
+			ip.copy_from_slice(&addr[8..24]);
+			Ok(format!("inet6:{}", SocketAddrV6::new(Ipv6Addr::from(ip), port, 0, 0)))
+		},
+		AF_UNIX => {
+			let path = &addr[2..];
+			// abstract names start with a nul, and aren't nul terminated
+			let (prefix, path) = match path.first() {
+				Some(0) => ("@", &path[1..]),
+				_ => ("", path.split(|&b| b == 0).next().unwrap()),
+			};
+			match std::str::from_utf8(path) {
+				Ok(p) if !p.is_empty() => Ok(format!("unix:{}{}", prefix, p)),
+				_ => Err(EINVAL),
+			}
+		},
+		AF_INET | AF_INET6 => Err(EINVAL),
+		_ => Err(EAFNOSUPPORT),
+	}
+}
+
+/// A socket the guest made, which only ever connects out through the SocketHost.  Like host streams, sockets are
+/// transient and can't be open in savestates.
+pub struct SocketFile {
+	host: Rc<RefCell<Box<dyn SocketHost>>>,
+	handle: Option<u64>,
+}
+impl SocketFile {
+	pub fn new(host: Rc<RefCell<Box<dyn SocketHost>>>) -> SocketFile {
+		SocketFile { host, handle: None }
+	}
+	pub fn connect(&mut self, addr: &str) -> SyscallResult {
+		if self.handle.is_some() {
+			return Err(EISCONN)
+		}
+		self.handle = Some(self.host.borrow_mut().connect(addr)?);
+		Ok(())
+	}
+}
+impl Drop for SocketFile {
+	fn drop(&mut self) {
+		if let Some(h) = self.handle {
+			self.host.borrow_mut().close(h);
+		}
+	}
+}
+impl IStateable for SocketFile {
+	fn save_state(&mut self, _stream: &mut dyn Write) -> anyhow::Result<()> {
+		Err(anyhow!("Cannot save state while the guest has sockets open"))
+	}
+	fn load_state(&mut self, _stream: &mut dyn Read) -> anyhow::Result<()> {
+		Err(anyhow!("Cannot load state while the guest has sockets open"))
+	}
+}
+impl FileObject for SocketFile {
+	fn can_read(&self) -> bool {
+		true
+	}
+	fn read(&mut self, buf: &mut [u8]) -> Result<i64, SyscallError> {
+		match self.handle {
+			Some(h) => Ok(self.host.borrow_mut().recv(h, buf)? as i64),
+			None => Err(ENOTCONN)
+		}
+	}
+	fn can_write(&self) -> bool {
+		true
+	}
+	fn write(&mut self, buf: &[u8]) -> Result<i64, SyscallError> {
+		match self.handle {
+			Some(h) => Ok(self.host.borrow_mut().send(h, buf)? as i64),
+			None => Err(ENOTCONN)
+		}
+	}
+	fn seek(&mut self, _offset: i64, _whence: i32) -> Result<i64, SyscallError> {
+		Err(ESPIPE)
+	}
+	fn truncate(&mut self, _size: i64) -> SyscallResult {
+		Err(EINVAL)
+	}
+	fn stat(&self, statbuff: &mut KStat) -> SyscallResult {
+		fill_stat(statbuff, true, true, false, 0)?;
+		statbuff.st_mode = statbuff.st_mode & !S_IFMT | S_IFSOCK;
+		Ok(())
+	}
+	fn can_unmount(&self) -> bool {
+		false
+	}
+	fn unmount(self: Box<Self>) -> Vec<u8> {
+		panic!()
+	}
+	fn reset(&mut self) {}
+	fn poll(&mut self) -> i16 {
+		match self.handle {
+			Some(h) if self.host.borrow_mut().ready(h) => POLLIN | POLLOUT,
+			Some(_) => POLLOUT,
+			None => POLLOUT | POLLHUP,
+		}
+	}
+	fn as_socket(&mut self) -> Option<&mut SocketFile> {
+		Some(self)
+	}
+}
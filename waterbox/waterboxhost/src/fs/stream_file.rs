@@ -0,0 +1,65 @@
+use crate::syscall_defs::*;
+use crate::*;
+use std::io::{Write, Read};
+use super::*;
+
+/// A pipe that the guest reads from, with each read going straight to the host.  Nothing is buffered, so the host can
+/// stream in something much larger than the guest could hold.  There's no way to rewind what was read, so this is
+/// transient like a writable RegularFile and can't be in savestates.
+pub struct StreamFile {
+	source: Box<dyn Read>,
+	/// Asked whether a read would get anything, for poll(2).  Without it, the stream is always ready.
+	ready: Option<Box<dyn FnMut() -> bool>>,
+}
+impl StreamFile {
+	pub fn new(source: Box<dyn Read>, ready: Option<Box<dyn FnMut() -> bool>>) -> StreamFile {
+		StreamFile { source, ready }
+	}
+}
+impl IStateable for StreamFile {
+	fn save_state(&mut self, _stream: &mut dyn Write) -> anyhow::Result<()> {
+		Err(anyhow!("Cannot save state while host streams are mounted"))
+	}
+	fn load_state(&mut self, _stream: &mut dyn Read) -> anyhow::Result<()> {
+		Err(anyhow!("Cannot load state while host streams are mounted"))
+	}
+}
+impl FileObject for StreamFile {
+	fn can_read(&self) -> bool {
+		true
+	}
+	fn read(&mut self, buf: &mut [u8]) -> Result<i64, SyscallError> {
+		match self.source.read(buf) {
+			Ok(n) => Ok(n as i64),
+			Err(_) => Err(EIO)
+		}
+	}
+	fn can_write(&self) -> bool {
+		false
+	}
+	fn write(&mut self, _buf: &[u8]) -> Result<i64, SyscallError> {
+		Err(EBADF)
+	}
+	fn seek(&mut self, _offset: i64, _whence: i32) -> Result<i64, SyscallError> {
+		Err(ESPIPE)
+	}
+	fn truncate(&mut self, _size: i64) -> SyscallResult {
+		Err(EINVAL)
+	}
+	fn stat(&self, statbuff: &mut KStat) -> SyscallResult {
+		fill_stat(statbuff, true, false, false, 0)
+	}
+	fn can_unmount(&self) -> bool {
+		true
+	}
+	fn unmount(self: Box<Self>) -> Vec<u8> {
+		Vec::new()
+	}
+	fn reset(&mut self) {}
+	fn poll(&mut self) -> i16 {
+		match self.ready.as_mut() {
+			Some(ready) => if ready() { POLLIN } else { 0 },
+			None => POLLIN,
+		}
+	}
+}
@@ -2,10 +2,68 @@ use crate::syscall_defs::*;
 use crate::*;
 use std::io::{Write, Read};
 use super::*;
+use cinterface::GuestOutputCallback;
+use std::ffi::CString;
+
+/// Lines longer than this are passed on in pieces
+const MAX_LINE: usize = 4096;
+
+/// Where the guest's stdout and stderr go when the frontend takes them.  Whole lines are passed on as they're written,
+/// and the rest of a line waits for its newline, or for a flush.
+#[derive(Default)]
+pub struct OutputCapture {
+	callback: Option<(GuestOutputCallback, usize)>,
+	/// Goes in front of every line
+	prefix: String,
+	/// What's been written since the last newline, on stdout and stderr
+	pending: [Vec<u8>; 2],
+}
+impl OutputCapture {
+	/// Send output to `callback` from now on, or back to the host's own console with None
+	pub fn set_callback(&mut self, callback: Option<(GuestOutputCallback, usize)>, prefix: String) {
+		self.flush();
+		self.callback = callback;
+		self.prefix = prefix;
+	}
+	fn emit(&self, index: usize, line: &[u8]) {
+		if let Some((callback, userdata)) = self.callback {
+			let mut text = self.prefix.clone().into_bytes();
+			text.extend(line.strip_suffix(b"\r").unwrap_or(line).iter().filter(|&&b| b != 0));
+			let text = CString::new(text).unwrap_or_default();
+			callback(userdata, index as i32 + 1, text.as_ptr());
+		}
+	}
+	fn write(&mut self, index: usize, mut buf: &[u8]) {
+		while let Some(end) = buf.iter().position(|&b| b == b'\n') {
+			let mut line = std::mem::take(&mut self.pending[index]);
+			line.extend_from_slice(&buf[..end]);
+			self.emit(index, &line[..]);
+			buf = &buf[end + 1..];
+		}
+		self.pending[index].extend_from_slice(buf);
+		while self.pending[index].len() >= MAX_LINE {
+			let rest = self.pending[index].split_off(MAX_LINE);
+			let line = std::mem::replace(&mut self.pending[index], rest);
+			self.emit(index, &line[..]);
+		}
+	}
+	/// Pass on the lines that haven't ended yet
+	pub fn flush(&mut self) {
+		for index in 0..2 {
+			let line = std::mem::take(&mut self.pending[index]);
+			if !line.is_empty() {
+				self.emit(index, &line[..]);
+			}
+		}
+	}
+}
 
 /// stdout, stderr
 pub struct SysOutObj {
 	pub host_handle: Box<dyn Write>,
+	/// 0 for stdout, 1 for stderr
+	pub index: usize,
+	pub capture: Rc<RefCell<OutputCapture>>,
 }
 impl IStateable for SysOutObj {
 	fn save_state(&mut self, stream: &mut dyn Write) -> anyhow::Result<()> {
@@ -28,8 +86,13 @@ impl FileObject for SysOutObj {
 		true
 	}
 	fn write(&mut self, buf: &[u8]) -> Result<i64, SyscallError> {
-		// do not propogate host errors up to the waterbox!
-		let _ = self.host_handle.write_all(buf);
+		let mut capture = self.capture.borrow_mut();
+		if capture.callback.is_some() {
+			capture.write(self.index, buf);
+		} else {
+			// do not propogate host errors up to the waterbox!
+			let _ = self.host_handle.write_all(buf);
+		}
 		Ok(buf.len() as i64)
 	}
 	fn seek(&mut self, _offset: i64, _whence: i32) -> Result<i64, SyscallError> {
@@ -0,0 +1,142 @@
+// Scratch files the guest makes for itself in /tmp, like assets it decompresses to read back later.  They live in host
+// memory, and together they can only take up so much, past which writes fail with ENOSPC.  The guest makes and removes
+// them, unlike mounted files, so savestates have all of them, by name and in full.
+use crate::syscall_defs::*;
+use crate::*;
+use std::io::{Write, Read};
+use std::cell::Cell;
+use super::*;
+
+/// Where the guest's scratch files go
+pub const TMP_DIR: &str = "/tmp";
+
+/// Whether `name` is somewhere the guest can make files
+pub fn in_tmp(name: &str) -> bool {
+	name.len() > TMP_DIR.len() + 1 && name.starts_with(TMP_DIR) && name.as_bytes()[TMP_DIR.len()] == b'/' && !name.ends_with('/')
+}
+
+/// The room all of the scratch files share
+#[derive(Debug, Default)]
+pub struct TmpSpace {
+	/// In bytes.  With 0, there's no /tmp.
+	pub quota: Cell<usize>,
+	pub used: Cell<usize>,
+}
+
+pub struct TmpFile {
+	data: Vec<u8>,
+	position: usize,
+	space: Rc<TmpSpace>,
+}
+impl TmpFile {
+	pub fn new(space: Rc<TmpSpace>) -> TmpFile {
+		TmpFile { data: Vec::new(), position: 0, space }
+	}
+	/// Change the size of the data, without checking whether there's room
+	fn set_len(&mut self, len: usize) {
+		self.space.used.set(self.space.used.get() - self.data.len() + len);
+		self.data.resize(len, 0);
+	}
+	/// How big the data can get before there's no room
+	fn max_len(&self) -> usize {
+		self.data.len() + self.space.quota.get().saturating_sub(self.space.used.get())
+	}
+}
+impl Drop for TmpFile {
+	fn drop(&mut self) {
+		self.set_len(0);
+	}
+}
+impl IStateable for TmpFile {
+	fn save_state(&mut self, stream: &mut dyn Write) -> anyhow::Result<()> {
+		bin::write_magic(stream, "TmpFile")?;
+		bin::write(stream, &self.position)?;
+		bin::writeval(stream, self.data.len())?;
+		stream.write_all(&self.data[..])?;
+		Ok(())
+	}
+	fn load_state(&mut self, stream: &mut dyn Read) -> anyhow::Result<()> {
+		bin::verify_magic(stream, "TmpFile")?;
+		bin::read(stream, &mut self.position)?;
+		// states are loaded whether or not they fit, and only later writes are held to the quota
+		let len = bin::readval::<usize>(stream)?;
+		let mut data = vec![0u8; len];
+		stream.read_exact(&mut data[..])?;
+		self.set_len(0);
+		self.data = data;
+		self.space.used.set(self.space.used.get() + len);
+		if self.position > len {
+			return Err(coded(ErrorCode::BadStateData, "TmpFile position is past its end"))
+		}
+		Ok(())
+	}
+}
+impl FileObject for TmpFile {
+	fn can_read(&self) -> bool {
+		true
+	}
+	fn read(&mut self, buf: &mut [u8]) -> Result<i64, SyscallError> {
+		let n = std::cmp::min(buf.len(), self.data.len() - self.position);
+		buf[0..n].copy_from_slice(&self.data[self.position..self.position + n]);
+		self.position += n;
+		Ok(n as i64)
+	}
+	fn can_write(&self) -> bool {
+		true
+	}
+	/// Writes as much as there's room for
+	fn write(&mut self, buf: &[u8]) -> Result<i64, SyscallError> {
+		let end = std::cmp::min(self.position + buf.len(), self.max_len());
+		if end == self.position && !buf.is_empty() {
+			return Err(ENOSPC)
+		}
+		if end > self.data.len() {
+			self.set_len(end);
+		}
+		let n = end - self.position;
+		self.data[self.position..end].copy_from_slice(&buf[..n]);
+		self.position = end;
+		Ok(n as i64)
+	}
+	fn seek(&mut self, offset: i64, whence: i32) -> Result<i64, SyscallError> {
+		let newpos = match whence {
+			SEEK_SET => offset,
+			SEEK_CUR => self.position as i64 + offset,
+			SEEK_END => self.data.len() as i64 + offset,
+			_ => return Err(EINVAL)
+		};
+		if newpos < 0 || newpos > self.data.len() as i64 {
+			return Err(EINVAL)
+		}
+		self.position = newpos as usize;
+		Ok(newpos)
+	}
+	fn truncate(&mut self, size: i64) -> SyscallResult {
+		if size < 0 {
+			return Err(EINVAL)
+		}
+		if size as usize > self.max_len() {
+			return Err(ENOSPC)
+		}
+		self.set_len(size as usize);
+		self.position = std::cmp::min(self.position, size as usize);
+		Ok(())
+	}
+	fn reset(&mut self) {
+		self.position = 0;
+	}
+	fn stat(&self, statbuff: &mut KStat) -> SyscallResult {
+		fill_stat(statbuff, true, true, true, self.data.len() as i64)
+	}
+	fn can_unmount(&self) -> bool {
+		true
+	}
+	fn unmount(mut self: Box<Self>) -> Vec<u8> {
+		let data = std::mem::take(&mut self.data);
+		self.space.used.set(self.space.used.get() - data.len());
+		data
+	}
+	fn is_tmp(&self) -> bool {
+		true
+	}
+}
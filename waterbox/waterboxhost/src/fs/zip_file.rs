@@ -0,0 +1,206 @@
+use crate::syscall_defs::*;
+use crate::*;
+use std::io::{Write, Read};
+use std::rc::Rc;
+use super::*;
+use inflate::{inflate, crc32};
+
+const END_OF_DIRECTORY: u32 = 0x06054b50;
+const DIRECTORY_ENTRY: u32 = 0x02014b50;
+const LOCAL_HEADER: u32 = 0x04034b50;
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+
+fn read_u16(src: &[u8], i: usize) -> anyhow::Result<u16> {
+	match src.get(i..i + 2) {
+		Some(b) => Ok(u16::from_le_bytes([b[0], b[1]])),
+		None => Err(anyhow!("Zip archive is truncated"))
+	}
+}
+fn read_u32(src: &[u8], i: usize) -> anyhow::Result<u32> {
+	match src.get(i..i + 4) {
+		Some(b) => Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+		None => Err(anyhow!("Zip archive is truncated"))
+	}
+}
+
+/// A whole zip archive, which all of the files mounted from it share
+pub struct ZipArchive {
+	data: Vec<u8>,
+	hash: Vec<u8>,
+}
+
+/// Where one file is in a zip archive
+pub struct ZipEntry {
+	pub name: String,
+	method: u16,
+	crc: u32,
+	compressed_size: usize,
+	size: usize,
+	header_offset: usize,
+}
+
+impl ZipArchive {
+	/// Read the central directory of an archive.  Directories are left out, and only stored and deflated files
+	/// are supported; zip64 and encryption are not.
+	pub fn open(data: Vec<u8>) -> anyhow::Result<(Rc<ZipArchive>, Vec<ZipEntry>)> {
+		// the end of directory record is at the end, before a comment of up to 64K
+		let min = data.len().saturating_sub(22 + 0xffff);
+		let eocd = match (min..=data.len().saturating_sub(22)).rev().find(|&i| read_u32(&data, i).ok() == Some(END_OF_DIRECTORY)) {
+			Some(i) => i,
+			None => return Err(anyhow!("Not a zip archive"))
+		};
+		let count = read_u16(&data, eocd + 10)? as usize;
+		let mut p = read_u32(&data, eocd + 16)? as usize;
+		if count == 0xffff || p == 0xffffffff {
+			return Err(anyhow!("Zip64 archives are not supported"))
+		}
+		let mut entries = Vec::new();
+		for _ in 0..count {
+			if read_u32(&data, p)? != DIRECTORY_ENTRY {
+				return Err(anyhow!("Bad zip central directory"))
+			}
+			let flags = read_u16(&data, p + 8)?;
+			let method = read_u16(&data, p + 10)?;
+			let crc = read_u32(&data, p + 16)?;
+			let compressed_size = read_u32(&data, p + 20)? as usize;
+			let size = read_u32(&data, p + 24)? as usize;
+			let name_len = read_u16(&data, p + 28)? as usize;
+			let extra_len = read_u16(&data, p + 30)? as usize;
+			let comment_len = read_u16(&data, p + 32)? as usize;
+			let header_offset = read_u32(&data, p + 42)? as usize;
+			let name = match data.get(p + 46..p + 46 + name_len) {
+				Some(n) => std::str::from_utf8(n).map_err(|_| anyhow!("Zip file name is not valid UTF-8"))?.to_string(),
+				None => return Err(anyhow!("Zip archive is truncated"))
+			};
+			p += 46 + name_len + extra_len + comment_len;
+			if name.ends_with('/') {
+				continue
+			}
+			if flags & 1 != 0 {
+				return Err(anyhow!("Zip file {} is encrypted", name))
+			}
+			if method != METHOD_STORED && method != METHOD_DEFLATED {
+				return Err(anyhow!("Zip file {} uses unsupported compression method {}", name, method))
+			}
+			if compressed_size == 0xffffffff || size == 0xffffffff || header_offset == 0xffffffff {
+				return Err(anyhow!("Zip64 archives are not supported"))
+			}
+			entries.push(ZipEntry { name, method, crc, compressed_size, size, header_offset });
+		}
+		let hash = bin::hash(&data[..]);
+		Ok((Rc::new(ZipArchive { data, hash }), entries))
+	}
+	fn extract(&self, entry: &ZipEntry) -> anyhow::Result<Vec<u8>> {
+		let p = entry.header_offset;
+		if read_u32(&self.data, p)? != LOCAL_HEADER {
+			return Err(anyhow!("Bad zip local header for {}", entry.name))
+		}
+		let start = p + 30 + read_u16(&self.data, p + 26)? as usize + read_u16(&self.data, p + 28)? as usize;
+		let src = match self.data.get(start..start + entry.compressed_size) {
+			Some(s) => s,
+			None => return Err(anyhow!("Zip archive is truncated"))
+		};
+		let res = match entry.method {
+			METHOD_STORED if entry.compressed_size == entry.size => src.to_vec(),
+			METHOD_STORED => return Err(anyhow!("Stored zip file {} has mismatched sizes", entry.name)),
+			_ => inflate(src, entry.size)?,
+		};
+		if crc32(&res) != entry.crc {
+			return Err(anyhow!("Zip file {} failed its CRC check", entry.name))
+		}
+		Ok(res)
+	}
+}
+
+/// A readonly file in a zip archive.  It's only decompressed when the guest reads it, and the decompressed copy is
+/// let go when the guest closes it.
+pub struct ZipFile {
+	archive: Rc<ZipArchive>,
+	entry: ZipEntry,
+	data: Option<Vec<u8>>,
+	position: usize,
+}
+impl ZipFile {
+	pub fn new(archive: Rc<ZipArchive>, entry: ZipEntry) -> ZipFile {
+		ZipFile {
+			archive,
+			entry,
+			data: None,
+			position: 0,
+		}
+	}
+}
+impl IStateable for ZipFile {
+	fn save_state(&mut self, stream: &mut dyn Write) -> anyhow::Result<()> {
+		bin::write_magic(stream, "ZipFile")?;
+		bin::write_hash(stream, &self.archive.hash[..])?;
+		bin::write(stream, &self.position)?;
+		Ok(())
+	}
+	fn load_state(&mut self, stream: &mut dyn Read) -> anyhow::Result<()> {
+		bin::verify_magic(stream, "ZipFile")?;
+		bin::verify_hash(stream, &self.archive.hash[..])?;
+		bin::read(stream, &mut self.position)?;
+		Ok(())
+	}
+}
+impl FileObject for ZipFile {
+	fn can_read(&self) -> bool {
+		true
+	}
+	fn read(&mut self, buf: &mut [u8]) -> Result<i64, SyscallError> {
+		if self.data.is_none() {
+			match self.archive.extract(&self.entry) {
+				Ok(d) => self.data = Some(d),
+				Err(e) => {
+					log!(Warn, "{}", e);
+					return Err(EIO)
+				}
+			}
+		}
+		let data = self.data.as_ref().unwrap();
+		let n = std::cmp::min(buf.len(), data.len() - self.position);
+		buf[0..n].copy_from_slice(&data[self.position..self.position + n]);
+		self.position += n;
+		Ok(n as i64)
+	}
+	fn can_write(&self) -> bool {
+		false
+	}
+	fn write(&mut self, _buf: &[u8]) -> Result<i64, SyscallError> {
+		Err(EBADF)
+	}
+	fn seek(&mut self, offset: i64, whence: i32) -> Result<i64, SyscallError> {
+		let newpos = match whence {
+			SEEK_SET => offset,
+			SEEK_CUR => self.position as i64 + offset,
+			SEEK_END => self.entry.size as i64 + offset,
+			_ => return Err(EINVAL)
+		};
+		if newpos < 0 || newpos > self.entry.size as i64 {
+			return Err(EINVAL)
+		}
+		self.position = newpos as usize;
+		Ok(newpos)
+	}
+	fn truncate(&mut self, _size: i64) -> SyscallResult {
+		Err(EBADF)
+	}
+	fn reset(&mut self) {
+		self.position = 0;
+		self.data = None;
+	}
+	fn stat(&self, statbuff: &mut KStat) -> SyscallResult {
+		fill_stat(statbuff, true, false, true, self.entry.size as i64)
+	}
+	fn can_unmount(&self) -> bool {
+		true
+	}
+	fn unmount(self: Box<Self>) -> Vec<u8> {
+		match self.data {
+			Some(d) => d,
+			None => self.archive.extract(&self.entry).unwrap_or_default(),
+		}
+	}
+}
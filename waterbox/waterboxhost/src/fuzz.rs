@@ -0,0 +1,188 @@
+// A fuzzing harness for the syscall layer, for tests.  Random syscalls go straight into dispatch on a live host, with a
+// mix of sensible and nonsense arguments:  Pointers into mapped and unmapped guest memory and well outside of it,
+// lengths that run past the end of things, made up file descriptors, and random flags.  The host has to get through
+// every one without crashing, and with what it knows about the guest still adding up afterwards.  Only syscalls that
+// check all of their arguments are made, so nothing that blocks, switches threads, or ends the guest.  Anything that
+// takes the host down shows up as the process dying; with the log at Debug, the last syscall logged is the culprit.
+use crate::*;
+use host::ActivatedWaterboxHost;
+use fs::Rng;
+use syscall_defs::*;
+
+/// What kind of value an argument gets
+#[derive(Clone, Copy)]
+enum Arg {
+	/// A pointer into guest memory, or somewhere that isn't
+	Ptr,
+	/// A pointer to a string, which might not be terminated
+	Path,
+	/// A length or count
+	Len,
+	Fd,
+	/// Bit flags, or an enumerated value
+	Flags,
+	/// An address for a memory syscall, which might not be aligned
+	Addr,
+	Any,
+}
+
+/// The syscalls made, and their arguments
+const CASES: &[(SyscallNumber, &[Arg])] = {
+	use Arg::*;
+	&[
+		(NR_READ, &[Fd, Ptr, Len]),
+		(NR_WRITE, &[Fd, Ptr, Len]),
+		(NR_PREAD64, &[Fd, Ptr, Len, Any]),
+		(NR_PWRITE64, &[Fd, Ptr, Len, Any]),
+		(NR_READV, &[Fd, Ptr, Len]),
+		(NR_WRITEV, &[Fd, Ptr, Len]),
+		(NR_PREADV, &[Fd, Ptr, Len, Any]),
+		(NR_PWRITEV, &[Fd, Ptr, Len, Any]),
+		(NR_OPEN, &[Path, Flags, Flags]),
+		(NR_CLOSE, &[Fd]),
+		(NR_UNLINK, &[Path]),
+		(NR_RENAME, &[Path, Path]),
+		(NR_DUP, &[Fd]),
+		(NR_DUP2, &[Fd, Fd]),
+		(NR_DUP3, &[Fd, Fd, Flags]),
+		(NR_FCNTL, &[Fd, Flags, Any]),
+		(NR_LSEEK, &[Fd, Any, Flags]),
+		(NR_STAT, &[Path, Ptr]),
+		(NR_LSTAT, &[Path, Ptr]),
+		(NR_FSTAT, &[Fd, Ptr]),
+		(NR_IOCTL, &[Fd, Flags, Any]),
+		(NR_MMAP, &[Addr, Len, Flags, Flags, Fd, Any]),
+		(NR_MUNMAP, &[Addr, Len]),
+		(NR_MPROTECT, &[Addr, Len, Flags]),
+		(NR_MREMAP, &[Addr, Len, Len, Flags]),
+		(NR_MADVISE, &[Addr, Len, Flags]),
+		(NR_BRK, &[Addr]),
+		(NR_CLOCK_GETTIME, &[Flags, Ptr]),
+		(NR_CLOCK_GETRES, &[Flags, Ptr]),
+		(NR_GETTIMEOFDAY, &[Ptr, Ptr]),
+		(NR_TIME, &[Ptr]),
+		(NR_CLOCK_SETTIME, &[Flags, Ptr]),
+		(NR_SETTIMEOFDAY, &[Ptr, Ptr]),
+		(NR_GETRUSAGE, &[Flags, Ptr]),
+		(NR_SYSINFO, &[Ptr]),
+		(NR_SCHED_GETAFFINITY, &[Flags, Len, Ptr]),
+		(NR_SCHED_SETAFFINITY, &[Flags, Len, Ptr]),
+		(NR_GETRANDOM, &[Ptr, Len, Flags]),
+		(NR_RT_SIGPROCMASK, &[Flags, Ptr, Ptr, Flags]),
+		(NR_GETTID, &[]),
+		(NR_SCHED_YIELD, &[]),
+		(NR_NANOSLEEP, &[Ptr, Ptr]),
+		(NR_CLOCK_NANOSLEEP, &[Flags, Flags, Ptr, Ptr]),
+		(NR_WBX_GET_CONFIG, &[Path, Ptr, Len]),
+		(NR_WBX_ENTROPY, &[Ptr, Len]),
+		(NR_WBX_CREATE_ARENA, &[Path, Ptr, Len]),
+		(NR_WBX_DESTROY_ARENA, &[Path]),
+		(NR_WBX_SET_DOMAIN_TRANSLATOR, &[Path, Ptr]),
+		(NR_WBX_CREATE_FIBER, &[Ptr, Ptr, Any, Len]),
+		(NR_WBX_DESTROY_FIBER, &[Any]),
+		(NR_WBX_TRANSFER, &[Path, Ptr, Len, Len]),
+	]
+};
+
+/// Where pointers mostly point, mapped before anything else happens
+const SCRATCH_SIZE: usize = 0x4000;
+
+const PATHS: &[&[u8]] = &[b"/dev/null", b"/dev/urandom", b"/", b"", b"a/b/../c", b"/dev/null/x"];
+
+struct Fuzzer {
+	rng: Rng,
+	all: AddressRange,
+	mmap: AddressRange,
+	scratch: usize,
+}
+impl Fuzzer {
+	fn below(&mut self, n: usize) -> usize {
+		(self.rng.next() % n as u64) as usize
+	}
+	fn pick<T: Copy>(&mut self, from: &[T]) -> T {
+		from[self.below(from.len())]
+	}
+	fn pointer(&mut self) -> usize {
+		match self.below(7) {
+			0 => 0,
+			1 => self.scratch + self.below(SCRATCH_SIZE),
+			// so that lengths run off the end
+			2 => self.scratch + SCRATCH_SIZE - self.below(64),
+			3 => self.all.start + self.below(self.all.size),
+			4 => self.pick(&[self.all.start.wrapping_sub(8), self.all.end() - 4, self.all.end()]),
+			5 => self.pick(&[8, 0x7fff_ffff_f000, 0xffff_8000_0000_0000, usize::MAX - 7]),
+			// host memory
+			_ => self as *const Fuzzer as usize,
+		}
+	}
+	fn length(&mut self) -> usize {
+		let small = self.below(64);
+		let any = self.below(1 << 20);
+		self.pick(&[0, 1, small, PAGESIZE - 1, PAGESIZE, PAGESIZE + 1, SCRATCH_SIZE, any,
+			isize::MAX as usize, usize::MAX, usize::MAX - PAGESIZE + 1])
+	}
+	fn address(&mut self) -> usize {
+		let page = self.below(self.mmap.size >> PAGESHIFT) << PAGESHIFT;
+		match self.below(5) {
+			0 => 0,
+			1 => self.mmap.start + page,
+			2 => self.all.start + (self.below(self.all.size) & !(PAGESIZE - 1)),
+			3 => self.mmap.start + page + self.below(PAGESIZE),
+			_ => self.pointer(),
+		}
+	}
+	fn arg(&mut self, kind: Arg, h: &mut ActivatedWaterboxHost) -> usize {
+		match kind {
+			Arg::Ptr => self.pointer(),
+			Arg::Path => {
+				let p = self.pointer();
+				let mut path = self.pick(PATHS).to_vec();
+				// or left unterminated, if nothing after it happens to be zero
+				if self.below(4) != 0 {
+					path.push(0);
+				}
+				h.write_memory(p, &path);
+				p
+			},
+			Arg::Len => self.length(),
+			Arg::Fd => {
+				let any = self.rng.next() as usize;
+				self.pick(&[0, 3, 4, 5, 6, 0x7fffffff, 1 << 32, usize::MAX, any])
+			},
+			Arg::Flags => {
+				let bit = 1 << self.below(32);
+				let any = self.rng.next() as usize;
+				self.pick(&[0, 1, 2, 3, bit, bit | MAP_PRIVATE | MAP_ANONYMOUS, any, any & 0xffffffff])
+			},
+			Arg::Addr => self.address(),
+			Arg::Any => {
+				let any = self.rng.next() as usize;
+				self.pick(&[0, 1, any, any & 0xffff, any as i32 as isize as usize])
+			},
+		}
+	}
+}
+
+/// Make `count` random syscalls on `h`, one at a time from `seed`, and check after each one that the host still makes
+/// sense.  The first problem found is returned.
+pub fn fuzz_syscalls(h: &mut ActivatedWaterboxHost, seed: u64, count: usize) -> anyhow::Result<()> {
+	let ud = h as *mut ActivatedWaterboxHost as usize;
+	let layout = h.layout();
+	let scratch = host::syscall(NR_MMAP, ud, 0, SCRATCH_SIZE, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, usize::MAX, 0);
+	if scratch.0 > SyscallReturn::ERROR_THRESH {
+		return Err(anyhow!("Couldn't map the scratch area: {:x}", scratch.0))
+	}
+	let mut f = Fuzzer { rng: Rng::new(seed), all: layout.all(), mmap: layout.mmap, scratch: scratch.0 };
+	for i in 0..count {
+		let (nr, kinds) = &CASES[f.below(CASES.len())];
+		let mut args = [0usize; 6];
+		for (a, &kind) in args.iter_mut().zip(kinds.iter()) {
+			*a = f.arg(kind, h);
+		}
+		log!(Debug, "Fuzzing syscall {}: {}{:x?}", i, lookup_syscall(nr), &args[..kinds.len()]);
+		let [a1, a2, a3, a4, a5, a6] = args;
+		host::syscall(SyscallNumber(nr.0), ud, a1, a2, a3, a4, a5, a6);
+		h.check_bookkeeping().map_err(|e| anyhow!("After syscall {}, {}{:x?}:  {}", i, lookup_syscall(nr), &args[..kinds.len()], e))?;
+	}
+	Ok(())
+}
@@ -68,6 +68,7 @@ pub unsafe fn register(data: &[u8]) {
 	__jit_debug_descriptor.relevant_entry = entry;
 	__jit_debug_descriptor.action_flag = JIT_REGISTER_FN;
 	__jit_debug_register_code();
+	crate::leaks::GDB_IMAGES.opened();
 }
 
 /// unsafe: undefined if not exactly matching a register call
@@ -91,4 +92,5 @@ pub unsafe fn deregister(data: &[u8]) {
 	__jit_debug_register_code();
 
 	Box::from_raw(entry);
+	crate::leaks::GDB_IMAGES.closed();
 }
@@ -0,0 +1,500 @@
+// A small gdb remote serial protocol server, for debugging guest code one host instruction at a time.
+// Breakpoints are execute watches (see memory_block/watch.rs), so guest code is never patched, and savestates don't see
+// them.  A stopped thread waits inside the signal handler that caught it, and gdb reads and writes its registers
+// through the signal context there.  Waterbox guests are single threaded, so there's only ever one thread to report.
+use crate::*;
+use std::cell::Cell;
+use std::convert::TryInto;
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Mutex, Condvar};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use lazy_static::lazy_static;
+#[cfg(all(unix, target_arch = "x86_64"))]
+use signal_context::Reg;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Resume {
+	Continue,
+	Step,
+}
+
+struct State {
+	/// The signal context of the stopped thread, if there is one
+	stopped: Option<usize>,
+	resume: Option<Resume>,
+}
+
+lazy_static! {
+	static ref STATE: Mutex<State> = Mutex::new(State {
+		stopped: None,
+		resume: None,
+	});
+	static ref CHANGED: Condvar = Condvar::new();
+}
+static STARTED: AtomicBool = AtomicBool::new(false);
+static ATTACHED: AtomicBool = AtomicBool::new(false);
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+/// True if gdb asked this thread to single step when it last stopped
+#[thread_local]
+static STEPPING: Cell<bool> = Cell::new(false);
+
+/// Start listening for gdb on localhost.  Only one server can be started per process, and it only accepts one
+/// connection at a time.
+pub fn start(port: u16) -> anyhow::Result<()> {
+	if STARTED.swap(true, Ordering::SeqCst) {
+		return Err(anyhow!("The gdb stub is already running"))
+	}
+	let res = listen(port);
+	if res.is_err() {
+		STARTED.store(false, Ordering::SeqCst);
+	}
+	res
+}
+
+#[cfg(all(unix, target_arch = "x86_64"))]
+fn listen(port: u16) -> anyhow::Result<()> {
+	let listener = TcpListener::bind(("127.0.0.1", port))?;
+	std::thread::Builder::new()
+		.name("waterbox gdbstub".to_string())
+		.spawn(move || {
+			for stream in listener.incoming().flatten() {
+				ATTACHED.store(true, Ordering::SeqCst);
+				let _ = Connection { stream }.serve();
+				detach();
+			}
+		})?;
+	Ok(())
+}
+#[cfg(not(all(unix, target_arch = "x86_64")))]
+fn listen(_port: u16) -> anyhow::Result<()> {
+	Err(anyhow!("The gdb stub is not supported on this platform"))
+}
+
+/// True if gdb is connected
+pub fn attached() -> bool {
+	ATTACHED.load(Ordering::SeqCst)
+}
+/// True if gdb wants the guest to stop as soon as possible
+pub fn stop_requested() -> bool {
+	STOP_REQUESTED.load(Ordering::Relaxed) && attached()
+}
+/// True if the current thread is single stepping for gdb
+pub fn stepping() -> bool {
+	STEPPING.get()
+}
+
+/// Report the current thread as stopped, and wait for gdb to resume it.  Called from a signal handler.
+/// unsafe: ucontext must stay valid until this returns
+#[cfg(all(unix, target_arch = "x86_64"))]
+pub unsafe fn stop(ucontext: *mut std::ffi::c_void) {
+	STOP_REQUESTED.store(false, Ordering::SeqCst);
+	STEPPING.set(false);
+	let mut state = STATE.lock().unwrap();
+	state.stopped = Some(ucontext as usize);
+	state.resume = None;
+	CHANGED.notify_all();
+	while state.resume.is_none() {
+		state = CHANGED.wait(state).unwrap();
+	}
+	if state.resume.take() == Some(Resume::Step) {
+		STEPPING.set(true);
+		*signal_context::reg(ucontext, Reg::Rflags) |= 0x100;
+	}
+	state.stopped = None;
+}
+
+/// Let go of everything when gdb goes away
+fn detach() {
+	ATTACHED.store(false, Ordering::SeqCst);
+	STOP_REQUESTED.store(false, Ordering::SeqCst);
+	memory_block::clear_breakpoints();
+	resume(Resume::Continue);
+}
+
+fn resume(how: Resume) {
+	let mut state = STATE.lock().unwrap();
+	if state.stopped.is_some() {
+		state.resume = Some(how);
+		CHANGED.notify_all();
+	}
+}
+
+fn is_stopped() -> bool {
+	STATE.lock().unwrap().stopped.is_some()
+}
+
+/// Registers in the order gdb's amd64 target expects them.  rip is last.
+#[cfg(all(unix, target_arch = "x86_64"))]
+const GPRS: [Reg; 17] = [Reg::Rax, Reg::Rbx, Reg::Rcx, Reg::Rdx, Reg::Rsi, Reg::Rdi, Reg::Rbp, Reg::Rsp,
+	Reg::R8, Reg::R9, Reg::R10, Reg::R11, Reg::R12, Reg::R13, Reg::R14, Reg::R15, Reg::Rip];
+
+/// Encode bytes for a reply
+fn to_hex(data: &[u8]) -> String {
+	data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+/// Decode bytes from a packet
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+	if s.len() & 1 != 0 {
+		return None
+	}
+	(0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
+/// Parse a big endian hex number, like the addresses and lengths in packets
+fn parse_num(s: &str) -> Option<usize> {
+	usize::from_str_radix(s, 16).ok()
+}
+/// Parse "addr,len"
+fn parse_range(s: &str) -> Option<(usize, usize)> {
+	let mut it = s.splitn(2, ',');
+	Some((parse_num(it.next()?)?, parse_num(it.next()?)?))
+}
+
+fn checksum(data: &str) -> u8 {
+	data.bytes().fold(0u8, |acc, b| acc.wrapping_add(b))
+}
+
+enum Input {
+	Packet(String),
+	/// gdb sent ^C
+	Interrupt,
+}
+
+#[cfg(all(unix, target_arch = "x86_64"))]
+struct Connection {
+	stream: TcpStream,
+}
+#[cfg(all(unix, target_arch = "x86_64"))]
+impl Connection {
+	fn read_byte(&mut self) -> std::io::Result<u8> {
+		let mut b = [0u8];
+		self.stream.read_exact(&mut b)?;
+		Ok(b[0])
+	}
+	fn read_input(&mut self) -> std::io::Result<Input> {
+		loop {
+			match self.read_byte()? {
+				b'$' => (),
+				3 => return Ok(Input::Interrupt),
+				// acks, or garbage
+				_ => continue,
+			}
+			let mut data = Vec::new();
+			loop {
+				match self.read_byte()? {
+					b'#' => break,
+					b => data.push(b),
+				}
+			}
+			let sum = [self.read_byte()?, self.read_byte()?];
+			let data = String::from_utf8_lossy(&data).into_owned();
+			let ok = std::str::from_utf8(&sum).ok()
+				.and_then(|s| u8::from_str_radix(s, 16).ok()) == Some(checksum(&data));
+			self.stream.write_all(if ok { b"+" } else { b"-" })?;
+			if ok {
+				return Ok(Input::Packet(data))
+			}
+		}
+	}
+	fn reply(&mut self, data: &str) -> std::io::Result<()> {
+		self.stream.write_all(format!("${}#{:02x}", data, checksum(data)).as_bytes())
+	}
+
+	/// Wait for the guest to stop, forwarding any ^C from gdb while it runs
+	fn wait_for_stop(&mut self) -> std::io::Result<()> {
+		let mut state = STATE.lock().unwrap();
+		loop {
+			if state.stopped.is_some() && state.resume.is_none() {
+				return Ok(())
+			}
+			state = CHANGED.wait_timeout(state, Duration::from_millis(50)).unwrap().0;
+			drop(state);
+			self.stream.set_nonblocking(true)?;
+			let mut b = [0u8];
+			let res = self.stream.read(&mut b);
+			self.stream.set_nonblocking(false)?;
+			match res {
+				Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+				Ok(_) if b[0] == 3 => STOP_REQUESTED.store(true, Ordering::SeqCst),
+				Ok(_) => (),
+				Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => (),
+				Err(e) => return Err(e),
+			}
+			state = STATE.lock().unwrap();
+		}
+	}
+
+	fn serve(&mut self) -> std::io::Result<()> {
+		self.stream.set_nodelay(true)?;
+		loop {
+			let packet = match self.read_input()? {
+				Input::Packet(p) => p,
+				Input::Interrupt => {
+					STOP_REQUESTED.store(true, Ordering::SeqCst);
+					continue
+				}
+			};
+			let (cmd, args) = packet.split_at(packet.chars().next().map_or(0, |c| c.len_utf8()));
+			let res = match cmd {
+				"?" => {
+					if !is_stopped() {
+						// the guest stops at its next syscall
+						STOP_REQUESTED.store(true, Ordering::SeqCst);
+						self.wait_for_stop()?;
+					}
+					"S05".to_string()
+				},
+				"c" | "s" => {
+					resume(if cmd == "s" { Resume::Step } else { Resume::Continue });
+					self.wait_for_stop()?;
+					"S05".to_string()
+				},
+				"D" => {
+					self.reply("OK")?;
+					return Ok(())
+				},
+				"k" => return Ok(()),
+				"g" => self.read_registers(),
+				"G" => self.write_registers(args),
+				"p" => self.read_register(args),
+				"P" => self.write_register(args),
+				"m" => match parse_range(args) {
+					Some((addr, len)) => {
+						let mut buf = vec![0u8; len];
+						if is_stopped() && unsafe { memory_block::debug_read(addr, &mut buf[..]) } {
+							to_hex(&buf[..])
+						} else {
+							"E14".to_string()
+						}
+					},
+					None => "E01".to_string(),
+				},
+				"M" => {
+					let mut it = args.splitn(2, ':');
+					match (it.next().and_then(parse_range), it.next().and_then(from_hex)) {
+						(Some((addr, len)), Some(data)) if data.len() == len => {
+							if is_stopped() && unsafe { memory_block::debug_write(addr, &data[..]) } {
+								"OK".to_string()
+							} else {
+								"E14".to_string()
+							}
+						},
+						_ => "E01".to_string(),
+					}
+				},
+				"Z" | "z" => {
+					let mut it = args.splitn(3, ',');
+					match (it.next(), it.next().and_then(parse_num)) {
+						// software and hardware breakpoints are the same thing here
+						(Some("0"), Some(addr)) | (Some("1"), Some(addr)) => {
+							if memory_block::set_breakpoint(addr, cmd == "Z") { "OK" } else { "E01" }.to_string()
+						},
+						// other watchpoint kinds go through wbx_add_watchpoint instead
+						_ => String::new(),
+					}
+				},
+				"H" | "T" => "OK".to_string(),
+				"q" => {
+					if args.starts_with("Supported") {
+						"PacketSize=4000".to_string()
+					} else if args == "Attached" {
+						"1".to_string()
+					} else if args == "C" {
+						"QC1".to_string()
+					} else if args == "fThreadInfo" {
+						"m1".to_string()
+					} else if args == "sThreadInfo" {
+						"l".to_string()
+					} else {
+						String::new()
+					}
+				},
+				_ => String::new(),
+			};
+			self.reply(&res)?;
+		}
+	}
+
+	/// Run `f` on the stopped thread's registers
+	fn with_registers(&self, f: impl FnOnce(Registers) -> String) -> String {
+		let state = STATE.lock().unwrap();
+		match state.stopped {
+			Some(ucontext) => f(Registers(ucontext as *mut std::ffi::c_void)),
+			None => "E01".to_string(),
+		}
+	}
+
+	/// gdb's register layout:  The 17 64 bit registers in GPRS, then eflags and six segment registers at 32 bits each
+	fn read_registers(&self) -> String {
+		self.with_registers(|gregs| {
+			let mut res = String::new();
+			for &r in GPRS.iter() {
+				res += &to_hex(&gregs.get(r).to_le_bytes());
+			}
+			let seg = unsafe { signal_context::segments(gregs.0) };
+			for &v in [gregs.get(Reg::Rflags), seg.cs, 0, 0, 0, seg.fs, seg.gs].iter() {
+				res += &to_hex(&(v as u32).to_le_bytes());
+			}
+			res
+		})
+	}
+	fn write_registers(&self, args: &str) -> String {
+		let data = match from_hex(args) {
+			Some(d) if d.len() >= GPRS.len() * 8 + 4 => d,
+			_ => return "E01".to_string(),
+		};
+		self.with_registers(|gregs| {
+			for (i, &r) in GPRS.iter().enumerate() {
+				gregs.set(r, u64::from_le_bytes(data[i * 8..i * 8 + 8].try_into().unwrap()));
+			}
+			let efl = GPRS.len() * 8;
+			gregs.set_eflags(u32::from_le_bytes(data[efl..efl + 4].try_into().unwrap()));
+			"OK".to_string()
+		})
+	}
+	fn read_register(&self, args: &str) -> String {
+		let n = match parse_num(args) {
+			Some(n) => n,
+			None => return "E01".to_string(),
+		};
+		self.with_registers(|gregs| {
+			if n < GPRS.len() {
+				to_hex(&gregs.get(GPRS[n]).to_le_bytes())
+			} else if n == GPRS.len() {
+				to_hex(&(gregs.get(Reg::Rflags) as u32).to_le_bytes())
+			} else {
+				// everything else is unavailable
+				"xxxxxxxx".to_string()
+			}
+		})
+	}
+	fn write_register(&self, args: &str) -> String {
+		let mut it = args.splitn(2, '=');
+		let (n, data) = match (it.next().and_then(parse_num), it.next().and_then(from_hex)) {
+			(Some(n), Some(d)) => (n, d),
+			_ => return "E01".to_string(),
+		};
+		self.with_registers(|gregs| {
+			if n < GPRS.len() && data.len() == 8 {
+				gregs.set(GPRS[n], u64::from_le_bytes(data[..].try_into().unwrap()));
+				"OK".to_string()
+			} else if n == GPRS.len() && data.len() == 4 {
+				gregs.set_eflags(u32::from_le_bytes(data[..].try_into().unwrap()));
+				"OK".to_string()
+			} else {
+				"E01".to_string()
+			}
+		})
+	}
+}
+
+/// The registers of the stopped thread, while STATE is locked
+#[cfg(all(unix, target_arch = "x86_64"))]
+struct Registers(*mut std::ffi::c_void);
+#[cfg(all(unix, target_arch = "x86_64"))]
+impl Registers {
+	fn get(&self, r: Reg) -> u64 {
+		unsafe { *signal_context::reg(self.0, r) }
+	}
+	fn set(&self, r: Reg, val: u64) {
+		unsafe { *signal_context::reg(self.0, r) = val; }
+	}
+	/// Set eflags, except for the trap flag, which belongs to the stub
+	fn set_eflags(&self, val: u32) {
+		let efl = self.get(Reg::Rflags);
+		self.set(Reg::Rflags, (efl & 0x100) | (val as u64 & !0x100));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use memory_block::{MemoryBlock, Protection};
+
+	type TestResult = anyhow::Result<()>;
+
+	fn command(stream: &mut TcpStream, cmd: &str) -> String {
+		stream.write_all(format!("${}#{:02x}", cmd, checksum(cmd)).as_bytes()).unwrap();
+		let mut b = [0u8];
+		loop {
+			stream.read_exact(&mut b).unwrap();
+			if b[0] == b'$' {
+				break
+			}
+		}
+		let mut res = Vec::new();
+		loop {
+			stream.read_exact(&mut b).unwrap();
+			if b[0] == b'#' {
+				break
+			}
+			res.push(b[0]);
+		}
+		stream.read_exact(&mut [0u8; 2]).unwrap();
+		stream.write_all(b"+").unwrap();
+		String::from_utf8(res).unwrap()
+	}
+	fn register(regs: &str, index: usize) -> u64 {
+		u64::from_le_bytes(from_hex(&regs[index * 16..index * 16 + 16]).unwrap()[..].try_into().unwrap())
+	}
+
+	#[test]
+	fn test_encoding() {
+		assert_eq!(checksum("OK"), 0x9a);
+		assert_eq!(to_hex(&[0x12, 0xab, 0]), "12ab00");
+		assert_eq!(from_hex("12ab00"), Some(vec![0x12, 0xab, 0]));
+		assert_eq!(from_hex("12a"), None);
+		assert_eq!(from_hex("zz"), None);
+		assert_eq!(parse_range("7f001000,40"), Some((0x7f001000, 0x40)));
+		assert_eq!(parse_range("7f001000"), None);
+	}
+
+	#[test]
+	#[cfg(target_arch = "x86_64")]
+	fn test_breakpoint_and_step() -> TestResult {
+		unsafe {
+			const PORT: u16 = 47019;
+			let addr = AddressRange { start: 0x37b00000000, size: 0x2000 };
+			let mut b = MemoryBlock::new(addr);
+			let mut g = b.enter();
+			g.mmap_fixed(addr, Protection::RW, true)?;
+			// mov eax, 42; ret
+			addr.slice_mut()[0x1000..0x1006].copy_from_slice(&[0xb8, 0x2a, 0, 0, 0, 0xc3]);
+			g.mprotect(addr, Protection::RX)?;
+			g.seal();
+			let code = addr.start + 0x1000;
+
+			start(PORT)?;
+			assert!(start(PORT).is_err());
+			let (tx, rx) = std::sync::mpsc::channel();
+			let client = std::thread::spawn(move || {
+				let mut s = TcpStream::connect(("127.0.0.1", PORT)).unwrap();
+				assert!(command(&mut s, "qSupported:swbreak+").starts_with("PacketSize"));
+				assert_eq!(command(&mut s, &format!("Z0,{:x},1", code)), "OK");
+				tx.send(()).unwrap();
+
+				assert_eq!(command(&mut s, "?"), "S05");
+				let regs = command(&mut s, "g");
+				assert_eq!(register(&regs, 16), code as u64);
+				assert_eq!(command(&mut s, &format!("m{:x},6", code)), "b82a000000c3");
+				// code isn't guest writable
+				assert_eq!(command(&mut s, &format!("M{:x},1:90", code)), "E14");
+				assert_eq!(command(&mut s, "c"), "S05");
+
+				assert_eq!(command(&mut s, "s"), "S05");
+				let regs = command(&mut s, "g");
+				assert_eq!(register(&regs, 16), code as u64 + 5);
+				assert_eq!(register(&regs, 0) as u32, 42);
+				assert_eq!(command(&mut s, &format!("z0,{:x},1", code)), "OK");
+				assert_eq!(command(&mut s, "D"), "OK");
+			});
+			rx.recv()?;
+			let f = std::mem::transmute::<usize, extern "sysv64" fn() -> u32>(code);
+			// the first call is continued from the breakpoint, and the second one single stepped
+			assert_eq!(f(), 42);
+			assert_eq!(f(), 42);
+			client.join().unwrap();
+			Ok(())
+		}
+	}
+}
@@ -0,0 +1,174 @@
+// Checking that the guest lays its memory out the same way every time.  A core that maps memory at different places from
+// run to run, because of something the host let slip or an uninitialized variable, can play the same movie differently,
+// which is hard to track down from where the emulation ends up going wrong.  So a reference run records what every brk,
+// mmap, mremap and munmap asked for and got, and later runs check theirs against it, and say which call went
+// differently first.  Calls are numbered from when the guest started, and the count is in savestates, so checking can
+// start from any state of the reference run.
+use crate::*;
+use syscall_defs::*;
+
+const MAGIC: &str = "WaterboxHeapLayout";
+
+/// One memory call the guest made
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapOp {
+	/// How many memory calls the guest made before this one
+	pub index: u64,
+	pub nr: usize,
+	/// Any the call doesn't take are 0
+	pub args: [usize; 5],
+	pub ret: usize,
+}
+impl HeapOp {
+	fn describe(&self) -> String {
+		let args = &self.args[..arg_count(&SyscallNumber(self.nr)).unwrap_or(0)];
+		let args = args.iter().map(|a| format!("{:#x}", a)).collect::<Vec<_>>().join(", ");
+		let name = lookup_syscall(&SyscallNumber(self.nr)).trim_start_matches("NR_").to_lowercase();
+		format!("{}({}) = {:#x}", name, args, self.ret)
+	}
+}
+
+/// Whether `nr` is one of the calls that are checked
+pub fn is_memory_call(nr: &SyscallNumber) -> bool {
+	arg_count(nr).is_some()
+}
+
+/// How many arguments of each memory call count, or None if it's not one
+fn arg_count(nr: &SyscallNumber) -> Option<usize> {
+	match *nr {
+		NR_BRK => Some(1),
+		NR_MUNMAP => Some(2),
+		NR_MMAP => Some(4),
+		NR_MREMAP => Some(5),
+		_ => None,
+	}
+}
+
+/// What a reference run recorded, for another run to check against
+pub struct HeapLog {
+	pub image_hash: Vec<u8>,
+	/// In order of index
+	pub ops: Vec<HeapOp>,
+}
+impl HeapLog {
+	pub fn save(&self, stream: &mut dyn Write) -> anyhow::Result<()> {
+		bin::write_magic(stream, MAGIC)?;
+		bin::write_hash(stream, &self.image_hash[..])?;
+		bin::writeval(stream, self.ops.len() as u64)?;
+		for op in self.ops.iter() {
+			bin::writeval(stream, op.index)?;
+			bin::writeval(stream, op.nr as u64)?;
+			for &a in op.args.iter() {
+				bin::writeval(stream, a as u64)?;
+			}
+			bin::writeval(stream, op.ret as u64)?;
+		}
+		Ok(())
+	}
+	pub fn load(stream: &mut dyn Read) -> anyhow::Result<HeapLog> {
+		bin::verify_magic(stream, MAGIC)?;
+		let mut image_hash = vec![0u8; 32];
+		stream.read_exact(&mut image_hash[..])?;
+		let count = bin::readval::<u64>(stream)?;
+		let mut ops: Vec<HeapOp> = Vec::new();
+		for _ in 0..count {
+			let index = bin::readval::<u64>(stream)?;
+			let nr = bin::readval::<u64>(stream)? as usize;
+			let mut args = [0usize; 5];
+			for a in args.iter_mut() {
+				*a = bin::readval::<u64>(stream)? as usize;
+			}
+			let ret = bin::readval::<u64>(stream)? as usize;
+			if matches!(ops.last(), Some(o) if o.index >= index) {
+				return Err(coded(ErrorCode::BadStateData, "Heap layout log is out of order"))
+			}
+			ops.push(HeapOp { index, nr, args, ret });
+		}
+		Ok(HeapLog { image_hash, ops })
+	}
+}
+
+pub enum HeapLayout {
+	Off,
+	Recording(Vec<HeapOp>),
+	Checking {
+		ops: Vec<HeapOp>,
+		/// How many calls have been checked, which is those in the log
+		checked: u64,
+		/// The first that went differently
+		divergence: Option<String>,
+	},
+}
+impl HeapLayout {
+	/// The guest made memory call number `index`
+	pub fn observe(&mut self, index: u64, nr: &SyscallNumber, args: &[usize; 6], ret: usize) {
+		let n = match arg_count(nr) {
+			Some(n) => n,
+			None => return,
+		};
+		let mut op = HeapOp { index, nr: nr.0, args: [0; 5], ret };
+		op.args[..n].copy_from_slice(&args[..n]);
+		match self {
+			HeapLayout::Off => (),
+			HeapLayout::Recording(ops) => {
+				// an earlier state was loaded, so what came after it is another timeline
+				let keep = ops.iter().position(|o| o.index >= index).unwrap_or(ops.len());
+				ops.truncate(keep);
+				ops.push(op);
+			},
+			HeapLayout::Checking { ops, checked, divergence } => {
+				let expected = match ops.binary_search_by_key(&index, |o| o.index) {
+					Ok(i) => ops[i],
+					Err(_) => return,
+				};
+				*checked += 1;
+				if expected != op && divergence.is_none() {
+					let what = format!("Guest memory call {} went differently:  expected {}, got {}", index, expected.describe(), op.describe());
+					log!(Warn, "{}", what);
+					*divergence = Some(what);
+				}
+			},
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_heap_layout() -> anyhow::Result<()> {
+		let mmap = |len: usize| [0, len, 3, 0x22, 0xdead, 0xbeef];
+		let mut h = HeapLayout::Recording(Vec::new());
+		h.observe(0, &NR_MMAP, &mmap(0x1000), 0x10000);
+		h.observe(1, &NR_BRK, &[0x20000, 7, 7, 7, 7, 7], 0x20000);
+		h.observe(2, &NR_MMAP, &mmap(0x2000), 0x11000);
+		// an earlier state was loaded
+		h.observe(1, &NR_MMAP, &mmap(0x3000), 0x11000);
+		let ops = match h {
+			HeapLayout::Recording(ops) => ops,
+			_ => unreachable!(),
+		};
+		assert_eq!(ops.len(), 2);
+		assert_eq!(ops[1], HeapOp { index: 1, nr: NR_MMAP.0, args: [0, 0x3000, 3, 0x22, 0], ret: 0x11000 });
+
+		let mut saved = Vec::new();
+		HeapLog { image_hash: bin::hash(b"core"), ops }.save(&mut saved)?;
+		let log = HeapLog::load(&mut &saved[..])?;
+		assert_eq!(log.image_hash, bin::hash(b"core"));
+
+		let mut h = HeapLayout::Checking { ops: log.ops, checked: 0, divergence: None };
+		h.observe(0, &NR_MMAP, &mmap(0x1000), 0x10000);
+		h.observe(1, &NR_GETTID, &[0; 6], 1);
+		h.observe(1, &NR_MMAP, &mmap(0x3000), 0x12000);
+		h.observe(2, &NR_MMAP, &mmap(0x3000), 0x13000);
+		match h {
+			HeapLayout::Checking { checked, divergence, .. } => {
+				assert_eq!(checked, 2);
+				assert_eq!(divergence.unwrap(), "Guest memory call 1 went differently:  expected mmap(0x0, 0x3000, 0x3, 0x22) = 0x11000, got mmap(0x0, 0x3000, 0x3, 0x22) = 0x12000");
+			},
+			_ => unreachable!(),
+		}
+		Ok(())
+	}
+}
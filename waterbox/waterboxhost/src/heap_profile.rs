@@ -0,0 +1,206 @@
+// Which guest code is using guest memory.  While profiling is on, every brk, mmap, mremap and munmap the guest makes is
+// charged to the guest backtrace that made it, so that a slow leak can be traced back to the subsystem responsible.
+// Memory mapped before profiling started isn't known about, and freeing it is ignored.
+// Separately, a HeapBaseline remembers what was mapped at one point, to check that nothing was mapped since.
+use crate::*;
+use std::collections::{BTreeMap, HashMap};
+
+/// How many return addresses identify where an allocation came from
+const SITE_FRAMES: usize = 8;
+
+/// Allocations made from one backtrace
+#[derive(Debug, Default)]
+struct Site {
+	/// Most recent first
+	frames: Vec<usize>,
+	live_bytes: usize,
+	total_bytes: u64,
+	allocations: u64,
+}
+
+#[derive(Default)]
+pub struct HeapProfiler {
+	enabled: bool,
+	sites: Vec<Site>,
+	site_index: HashMap<Vec<usize>, usize>,
+	/// Every live mapping, by start address:  its end, and the site it was charged to
+	regions: BTreeMap<usize, (usize, usize)>,
+}
+impl HeapProfiler {
+	/// Start or stop recording.  Stopping forgets everything recorded.
+	pub fn set_enabled(&mut self, enabled: bool) {
+		if !enabled {
+			*self = HeapProfiler::default();
+		}
+		self.enabled = enabled;
+	}
+	pub fn enabled(&self) -> bool {
+		self.enabled
+	}
+	/// Memory in `addr` was mapped by the code with backtrace `frames`.  Anything that was there before was replaced.
+	pub fn allocate(&mut self, addr: AddressRange, frames: &[usize]) {
+		self.free(addr);
+		let frames = &frames[..std::cmp::min(frames.len(), SITE_FRAMES)];
+		let index = match self.site_index.get(frames) {
+			Some(&i) => i,
+			None => {
+				self.sites.push(Site { frames: frames.to_vec(), ..Default::default() });
+				self.site_index.insert(frames.to_vec(), self.sites.len() - 1);
+				self.sites.len() - 1
+			},
+		};
+		let site = &mut self.sites[index];
+		site.live_bytes += addr.size;
+		site.total_bytes += addr.size as u64;
+		site.allocations += 1;
+		self.regions.insert(addr.start, (addr.end(), index));
+	}
+	/// Memory in `addr` was unmapped
+	pub fn free(&mut self, addr: AddressRange) {
+		let overlapping = self.regions.range(..addr.end())
+			.rev()
+			.take_while(|(_, &(end, _))| end > addr.start)
+			.map(|(&start, &(end, site))| (start, end, site))
+			.collect::<Vec<_>>();
+		for (start, end, site) in overlapping {
+			self.regions.remove(&start);
+			let cut_start = std::cmp::max(start, addr.start);
+			let cut_end = std::cmp::min(end, addr.end());
+			self.sites[site].live_bytes -= cut_end - cut_start;
+			if start < cut_start {
+				self.regions.insert(start, (cut_start, site));
+			}
+			if cut_end < end {
+				self.regions.insert(cut_end, (end, site));
+			}
+		}
+	}
+	/// The `top` sites with the most live memory, described with `symbolize`
+	pub fn report(&self, top: usize, symbolize: impl Fn(usize) -> Option<String>) -> String {
+		let live = self.sites.iter().map(|s| s.live_bytes).sum::<usize>();
+		let mut res = format!("Guest heap: {} bytes live in {} mappings, from {} places\n", live, self.regions.len(), self.sites.len());
+		let mut sites = self.sites.iter().collect::<Vec<_>>();
+		sites.sort_by(|a, b| b.live_bytes.cmp(&a.live_bytes).then(b.total_bytes.cmp(&a.total_bytes)));
+		for s in sites.iter().take(top) {
+			res.push_str(&format!("{} bytes live, {} mapped in {} calls, from:\n", s.live_bytes, s.total_bytes, s.allocations));
+			for (i, &f) in s.frames.iter().enumerate() {
+				// return addresses, except the first, are just after the interesting call instruction
+				let name = symbolize(if i == 0 { f } else { f - 1 }).map(|n| format!(" {}", n)).unwrap_or_default();
+				res.push_str(&format!("  {:#x}{}\n", f, name));
+			}
+		}
+		res
+	}
+}
+
+/// Which pages were mapped, and where brk was, at some point, for finding what was mapped and unmapped since
+pub struct HeapBaseline {
+	/// One per page of `addr`
+	allocated: Vec<bool>,
+	addr: AddressRange,
+	program_break: usize,
+}
+
+/// What changed since a HeapBaseline, leaving out the brk heap
+pub struct HeapDelta {
+	pub mapped: Vec<AddressRange>,
+	pub unmapped: Vec<AddressRange>,
+	pub brk_change: isize,
+}
+impl HeapDelta {
+	pub fn mapped_bytes(&self) -> usize {
+		self.mapped.iter().map(|r| r.size).sum()
+	}
+	pub fn unmapped_bytes(&self) -> usize {
+		self.unmapped.iter().map(|r| r.size).sum()
+	}
+	pub fn describe(&self) -> String {
+		let mut res = format!("Since the baseline: {} bytes mapped in {} regions, {} bytes unmapped in {} regions, brk moved by {}\n",
+			self.mapped_bytes(), self.mapped.len(), self.unmapped_bytes(), self.unmapped.len(), self.brk_change);
+		for (what, regions) in [("mapped", &self.mapped), ("unmapped", &self.unmapped)].iter() {
+			for r in regions.iter() {
+				res.push_str(&format!("  {} {:#x}-{:#x} ({} bytes)\n", what, r.start, r.end(), r.size));
+			}
+		}
+		res
+	}
+}
+
+impl HeapBaseline {
+	pub fn new(addr: AddressRange, allocated: Vec<bool>, program_break: usize) -> HeapBaseline {
+		HeapBaseline { allocated, addr, program_break }
+	}
+	/// Compare with how things are now.  Pages in `sbrk` are only counted in brk_change.
+	pub fn delta(&self, allocated: &[bool], program_break: usize, sbrk: AddressRange) -> HeapDelta {
+		let runs = |want_before: bool| {
+			let mut res: Vec<AddressRange> = Vec::new();
+			for (i, (&before, &now)) in self.allocated.iter().zip(allocated).enumerate() {
+				let page = AddressRange { start: self.addr.start + (i << PAGESHIFT), size: PAGESIZE };
+				if before != want_before || now == want_before || sbrk.contains(page.start) {
+					continue
+				}
+				match res.last_mut() {
+					Some(r) if r.end() == page.start => r.size += PAGESIZE,
+					_ => res.push(page),
+				}
+			}
+			res
+		};
+		HeapDelta {
+			mapped: runs(false),
+			unmapped: runs(true),
+			brk_change: program_break as isize - self.program_break as isize,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn range(start: usize, size: usize) -> AddressRange {
+		AddressRange { start, size }
+	}
+
+	#[test]
+	fn test_heap_profile() {
+		let mut p = HeapProfiler::default();
+		p.set_enabled(true);
+		p.allocate(range(0x10000, 0x4000), &[0x100, 0x200]);
+		p.allocate(range(0x20000, 0x1000), &[0x100, 0x200]);
+		p.allocate(range(0x30000, 0x2000), &[0x300]);
+		// punches a hole in the first mapping
+		p.free(range(0x11000, 0x1000));
+		// replaces part of the third
+		p.allocate(range(0x31000, 0x3000), &[0x400]);
+		p.free(range(0x50000, 0x1000));
+		assert_eq!(p.sites.iter().map(|s| s.live_bytes).collect::<Vec<_>>(), vec![0x4000, 0x1000, 0x3000]);
+		assert_eq!(p.regions.len(), 5);
+		assert_eq!(p.sites[0].allocations, 2);
+
+		let report = p.report(2, |addr| if addr == 0x1ff { Some("leaky".to_string()) } else { None });
+		assert!(report.starts_with("Guest heap: 32768 bytes live in 5 mappings, from 3 places\n"));
+		assert!(report.contains("16384 bytes live, 20480 mapped in 2 calls, from:\n  0x100\n  0x200 leaky\n"));
+		assert!(report.contains("12288 bytes live"));
+		assert!(!report.contains("4096 bytes live"));
+
+		p.set_enabled(false);
+		assert!(p.sites.is_empty() && !p.enabled());
+	}
+
+	#[test]
+	fn test_heap_delta() {
+		let addr = range(0x100000, 8 * PAGESIZE);
+		let sbrk = range(0x106000, 2 * PAGESIZE);
+		let baseline = HeapBaseline::new(addr, vec![true, true, false, false, true, false, false, false], 0x106000);
+		let delta = baseline.delta(&[true, false, true, true, true, true, true, true], 0x108000, sbrk);
+		assert_eq!(delta.mapped, vec![range(0x102000, 0x2000), range(0x105000, 0x1000)]);
+		assert_eq!(delta.unmapped, vec![range(0x101000, 0x1000)]);
+		assert_eq!(delta.brk_change, 0x2000);
+		assert_eq!((delta.mapped_bytes(), delta.unmapped_bytes()), (0x3000, 0x1000));
+		assert!(delta.describe().contains("  mapped 0x102000-0x104000 (8192 bytes)\n"));
+
+		let same = baseline.delta(&baseline.allocated[..], 0x106000, sbrk);
+		assert!(same.mapped.is_empty() && same.unmapped.is_empty() && same.brk_change == 0);
+	}
+}
@@ -0,0 +1,134 @@
+// A record of how a few values in guest memory changed over time, for TASers asking when a value changed without
+// saving and diffing a pile of states.  The frontend picks the values, the way it reads them with wbx_read_value(), and
+// tells the host each frame; every time one is different from what it last was, the frame and the new value go in its
+// ring of changes, which drops the oldest when it's full.  A frame that isn't after the last one recorded, like after
+// loading a state or rewinding, starts a new timeline from there.  None of this is in states.
+use crate::*;
+use std::collections::VecDeque;
+
+/// More than this are turned down
+const MAX_WATCHES: usize = 64;
+
+/// A value a watch changed to, and the frame it was first seen at
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HistorySample {
+	pub frame: u64,
+	pub value: u64,
+}
+
+struct Watch {
+	id: u32,
+	domain: Option<usize>,
+	offset: usize,
+	/// memory_domains::VALUE_*
+	ty: u32,
+	/// How many changes are kept
+	capacity: usize,
+	/// Oldest first
+	samples: VecDeque<HistorySample>,
+}
+
+#[derive(Default)]
+pub struct History {
+	watches: Vec<Watch>,
+	next_id: u32,
+	last_frame: Option<u64>,
+}
+impl History {
+	/// Watch the value of type `ty` at `offset` in `domain`, keeping its last `capacity` changes.  Returns an id for
+	/// remove() and samples().
+	pub fn add(&mut self, domain: Option<usize>, offset: usize, ty: u32, capacity: usize) -> anyhow::Result<u32> {
+		if capacity == 0 {
+			return Err(coded(ErrorCode::InvalidArgument, "A history watch has to keep at least one change"))
+		}
+		if self.watches.len() >= MAX_WATCHES {
+			return Err(coded(ErrorCode::InvalidArgument, format!("At most {} values can be watched", MAX_WATCHES)))
+		}
+		self.next_id += 1;
+		self.watches.push(Watch { id: self.next_id, domain, offset, ty, capacity, samples: VecDeque::new() });
+		Ok(self.next_id)
+	}
+	pub fn remove(&mut self, id: u32) -> anyhow::Result<()> {
+		let index = self.index(id)?;
+		self.watches.remove(index);
+		Ok(())
+	}
+	fn index(&self, id: u32) -> anyhow::Result<usize> {
+		self.watches.iter().position(|w| w.id == id).ok_or_else(|| coded(ErrorCode::NotFound, format!("No history watch {}", id)))
+	}
+	/// What each watch reads, in the order record() takes their values in
+	pub fn watches(&self) -> Vec<(Option<usize>, usize, u32)> {
+		self.watches.iter().map(|w| (w.domain, w.offset, w.ty)).collect()
+	}
+	/// Take what each watch reads at `frame`, or None for one that couldn't be read, which records nothing
+	pub fn record(&mut self, frame: u64, values: &[Option<u64>]) {
+		let rewound = matches!(self.last_frame, Some(last) if frame <= last);
+		for (w, value) in self.watches.iter_mut().zip(values.iter()) {
+			if rewound {
+				while matches!(w.samples.back(), Some(s) if s.frame >= frame) {
+					w.samples.pop_back();
+				}
+			}
+			let value = match *value {
+				Some(v) => v,
+				None => continue,
+			};
+			if w.samples.back().map(|s| s.value) != Some(value) {
+				w.samples.push_back(HistorySample { frame, value });
+				if w.samples.len() > w.capacity {
+					w.samples.pop_front();
+				}
+			}
+		}
+		self.last_frame = Some(frame);
+	}
+	/// Copy the changes watch `id` has from `since` on into `dest`, oldest first, and return how many there were room
+	/// for
+	pub fn samples(&self, id: u32, since: u64, dest: &mut [HistorySample]) -> anyhow::Result<usize> {
+		let w = &self.watches[self.index(id)?];
+		let mut n = 0;
+		for (s, d) in w.samples.iter().filter(|s| s.frame >= since).zip(dest.iter_mut()) {
+			*d = *s;
+			n += 1;
+		}
+		Ok(n)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use memory_domains::VALUE_U8;
+
+	fn all(h: &History, id: u32) -> Vec<(u64, u64)> {
+		let mut dest = [HistorySample::default(); 16];
+		let n = h.samples(id, 0, &mut dest).unwrap();
+		dest[..n].iter().map(|s| (s.frame, s.value)).collect()
+	}
+
+	#[test]
+	fn test_history() -> anyhow::Result<()> {
+		let mut h = History::default();
+		let a = h.add(None, 0x1000, VALUE_U8, 3)?;
+		let b = h.add(Some(0), 4, VALUE_U8, 8)?;
+		assert_eq!(ErrorCode::of(&h.add(None, 0, VALUE_U8, 0).unwrap_err()), ErrorCode::InvalidArgument);
+		for (frame, va, vb) in [(0, 1, 5), (1, 1, 5), (2, 2, 5), (3, 3, 6), (4, 4, 6)].iter() {
+			h.record(*frame, &[Some(*va), Some(*vb)]);
+		}
+		// only the last three changes of a
+		assert_eq!(all(&h, a), [(2, 2), (3, 3), (4, 4)]);
+		assert_eq!(all(&h, b), [(0, 5), (3, 6)]);
+		let mut dest = [HistorySample::default(); 1];
+		assert_eq!(h.samples(b, 1, &mut dest)?, 1);
+		assert_eq!(dest[0], HistorySample { frame: 3, value: 6 });
+		// loading a state from frame 2 replaces everything after it
+		h.record(3, &[Some(9), None]);
+		assert_eq!(all(&h, a), [(2, 2), (3, 9)]);
+		assert_eq!(all(&h, b), [(0, 5)]);
+		h.remove(a)?;
+		assert_eq!(ErrorCode::of(&h.remove(a).unwrap_err()), ErrorCode::NotFound);
+		assert_eq!(h.watches(), [(Some(0), 4, VALUE_U8)]);
+		Ok(())
+	}
+}
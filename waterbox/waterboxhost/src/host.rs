@@ -1,11 +1,41 @@
 use crate::*;
 use crate::{memory_block::ActivatedMemoryBlock, syscall_defs::*};
-use memory_block::{MemoryBlock, Protection};
-use std::{os::raw::c_char, ffi::CStr};
-use fs::{FileDescriptor, FileSystem/*, MissingFileCallback*/};
+use memory_block::{AuditEntry, CowSnapshot, HeatMapInfo, IntegrityCallback, IntegrityInfo, MemoryBlock, MemoryStats, PageDiff, PageHeat, Protection, WatchCallback};
+use std::{os::raw::c_char, ffi::{CStr, CString}};
+use fs::{ChunkedData, FileDescriptor, FileSystem/*, MissingFileCallback*/};
 use elf::ElfLoader;
-use cinterface::MemoryLayoutTemplate;
+use abi::GuestAbi;
+use cinterface::{CrashCallback, GuestOutputCallback, GuestTrapCallback, MemoryLayoutTemplate, SyscallTraceCallback, SyscallTrapCallback, WxViolationCallback, YieldCallback};
+use syscall_policy::{SyscallAction, SyscallPolicy};
+use config::WaterboxConfig;
 use goblin::elf::Elf;
+use rewind::{RewindBuffer, RewindInfo};
+use journal::Journal;
+use clock::Clock;
+use entropy::Entropy;
+use rom::Roms;
+use memory_domains::{MemoryDomainInfo, MemoryDomains};
+use arenas::{ArenaInfo, Arenas};
+use fibers::Fibers;
+use history::{History, HistorySample};
+use metrics::Metrics;
+use transfers::{TransferInfo, Transfers};
+use imports::ImportInfo;
+use fpenv::{FpEnv, GuestFp};
+use profile::{EntryProfile, Profiler};
+use heap_profile::{HeapBaseline, HeapDelta, HeapProfiler};
+use heap_layout::{HeapLayout, HeapLog};
+use replay::{Event, Log, Session};
+use state_format::{CoreIdentity, StateCheck, StateHeader};
+use state_diff::StateDiffInfo;
+use threading::{MAIN_TID, SyscallEntry, Threads};
+use std::collections::HashMap;
+use std::{cell::RefCell, rc::Rc};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use std::io::{BufRead, Seek, SeekFrom};
+use lazy_static::lazy_static;
 
 pub struct WaterboxHost {
 	fs: FileSystem,
@@ -13,41 +43,243 @@ pub struct WaterboxHost {
 	elf: ElfLoader,
 	layout: WbxSysLayout,
 	memory_block: Box<MemoryBlock>,
-	active: bool,
+	/// Set by whichever thread activates the host, and cleared by whichever deactivates it
+	active: AtomicBool,
+	/// A guest call is running, on whatever thread
+	calling: AtomicBool,
+	/// Set by shutdown(), after which the host has nothing left but can be destroyed
+	shut_down: bool,
 	sealed: bool,
+	/// How many times the host has been sealed, which is in states, as they can only be loaded after the same one
+	seals: u32,
 	image_file: Vec<u8>,
+	/// Identifies the core in savestates
+	image_hash: Vec<u8>,
+	/// What the frontend called the core, and its version if it said, for telling people which made a state
+	core_name: String,
+	core_version: String,
+	/// state_format::FEATURE_*s that states from this host have
+	state_features: u32,
+	compress_states: bool,
+	delta_states: bool,
+	rewind: Option<RewindBuffer>,
+	journal: Option<Journal>,
+	wx_policy: WxPolicy,
+	/// If only wasm guests can be loaded, and guest memory is never executable
+	wasm_no_exec: bool,
+	wx_callback: Option<(WxViolationCallback, usize)>,
+	syscall_trace: Option<(SyscallTraceCallback, usize)>,
+	yield_callback: Option<(YieldCallback, usize)>,
+	syscall_policy: SyscallPolicy,
+	threads: Threads,
+	crash_callback: Option<(CrashCallback, usize)>,
+	guest_trap_callback: Option<(GuestTrapCallback, usize)>,
+	core_dump_path: Option<String>,
+	clock: Clock,
+	/// For NR_WBX_ENTROPY
+	entropy: Entropy,
+	roms: Roms,
+	/// How long call_guest lets a call run, if there's a limit
+	watchdog: Option<Duration>,
+	/// Set by request_cancel(), from any thread
+	cancel: AtomicBool,
+	/// The guest aborted in the middle of a call, so nothing in it can be trusted until a state is loaded
+	aborted: bool,
+	memory_domains: MemoryDomains,
+	arenas: Arenas,
+	fibers: Fibers,
+	/// Values the frontend is keeping a history of, which isn't in states
+	history: History,
+	metrics: Metrics,
+	/// The frontend's buffers for NR_WBX_TRANSFER, which aren't in states
+	transfers: Transfers,
+	profile: Profiler,
+	heap_profile: HeapProfiler,
+	heap_baseline: Option<HeapBaseline>,
+	/// How many brk, mmap, mremap and munmap calls the guest has made, which is in states
+	heap_ops: u64,
+	heap_layout: HeapLayout,
+	/// Settings from the frontend for the guest to look up, which aren't in states
+	config: HashMap<String, String>,
+	/// Shared with the socket host, which takes part in recordings and replays
+	session: Rc<RefCell<Session>>,
+}
+
+/// What to do when the guest asks for memory that is both writable and executable
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WxPolicy {
+	Allow,
+	/// Fail the request with EPERM
+	Deny,
+	/// Allow the request, but still report it to the callback
+	Report,
+}
+impl WxPolicy {
+	/// From the C interface's numbering, as in wbx_set_wx_policy
+	pub fn from_raw(policy: u32) -> anyhow::Result<WxPolicy> {
+		match policy {
+			0 => Ok(WxPolicy::Allow),
+			1 => Ok(WxPolicy::Deny),
+			2 => Ok(WxPolicy::Report),
+			_ => Err(coded(ErrorCode::InvalidArgument, format!("Unknown W^X policy {}", policy))),
+		}
+	}
 }
 impl WaterboxHost {
+	/// With everything but the layout as the wbx_set_* calls have it
 	pub fn new(image_file: Vec<u8>, module_name: &str, layout_template: &MemoryLayoutTemplate) -> anyhow::Result<Box<WaterboxHost>> {
-		let wbx = Elf::parse(&image_file[..])?;
-		let elf_addr = ElfLoader::elf_addr(&wbx);
-		let layout = layout_template.make_layout(elf_addr)?;
-		let mut memory_block = MemoryBlock::new(layout.all());
+		WaterboxHost::with_config(image_file, module_name, &WaterboxConfig::new().layout(*layout_template))
+	}
+	pub fn with_config(image_file: Vec<u8>, module_name: &str, config: &WaterboxConfig) -> anyhow::Result<Box<WaterboxHost>> {
+		config.validate()?;
+		let (wbx, wasm) = if wasm::is_wasm(&image_file[..]) {
+			(None, Some(wasm::Module::decode(&image_file[..])?))
+		} else {
+			(Some(Elf::parse(&image_file[..])?), None)
+		};
+		let (abi, elf_addr) = match (&wbx, &wasm) {
+			(Some(wbx), _) => {
+				let abi = GuestAbi::of(wbx, module_name)?;
+				(abi, ElfLoader::elf_addr(wbx, abi))
+			},
+			(None, m) => (GuestAbi::Wasm32, m.as_ref().unwrap().image_addr()?),
+		};
+		let wasm_no_exec = config.wasm_no_exec;
+		if wasm_no_exec && abi != GuestAbi::Wasm32 {
+			return Err(coded(ErrorCode::Unsupported, format!("Module `{}` is native code, but hosts without executable memory only load wasm cores", module_name)))
+		}
+		let layout = config.layout.make_layout(elf_addr)?;
+		abi.check_layout(layout.all(), module_name)?;
+		let mut memory_block = MemoryBlock::with_tracking(layout.all(), config.dirty_tracking);
+		memory_block.set_sanitizer_mode(config.sanitizer_mode);
+		memory_block.set_no_exec(wasm_no_exec);
 		let mut b = memory_block.enter();
-		let elf = ElfLoader::new(&wbx, &image_file[..], module_name, &layout, &mut b)?;
-		let fs = FileSystem::new();
+		b.set_huge_pages(config.huge_pages);
+		b.set_scribble(config.scribble);
+		if config.commit_upfront {
+			b.commit_all()?;
+		}
+		if let Some(seed) = config.mmap_seed {
+			b.set_mmap_seed(seed);
+		}
+		let elf = match wasm {
+			Some(m) => ElfLoader::new_wasm(m, &image_file[..], module_name, &layout, &mut b)?,
+			None => ElfLoader::new(wbx.as_ref().unwrap(), &image_file[..], abi, module_name, &layout, &mut b)?,
+		};
+		let start_info = startup::start_info();
+		let start_block = if abi != GuestAbi::Wasm32 { startup::write_start_block(&start_info, abi, layout.mmap, &mut b)? } else { 0 };
+		let mut fs = FileSystem::new();
+		fs.mount_proc(&layout, module_name);
+		let mut threads = Threads::new();
+		threads.set_tls(elf.thread_pointer());
 		drop(b);
-		unsafe { gdb::register(&image_file[..]) }
+		// gdb takes the addresses in the image as they are
+		if abi != GuestAbi::Wasm32 && !elf.relocated() {
+			unsafe { gdb::register(&image_file[..]) }
+		}
+		let image_hash = bin::hash(&image_file[..]);
+		let state_features = if config.mmap_seed.is_some() { state_format::FEATURE_MMAP_RANDOMIZATION } else { 0 };
 		let mut res = Box::new(WaterboxHost {
 			fs,
 			program_break: layout.sbrk.start,
 			elf,
 			layout,
 			memory_block,
-			active: false,
+			active: AtomicBool::new(false),
+			calling: AtomicBool::new(false),
+			shut_down: false,
 			sealed: false,
+			seals: 0,
 			image_file,
+			image_hash,
+			core_name: module_name.to_string(),
+			core_version: String::new(),
+			state_features,
+			compress_states: false,
+			delta_states: false,
+			rewind: None,
+			journal: None,
+			wx_policy: config.wx_policy,
+			wasm_no_exec,
+			wx_callback: None,
+			syscall_trace: None,
+			yield_callback: None,
+			syscall_policy: SyscallPolicy::default(),
+			threads,
+			crash_callback: None,
+			guest_trap_callback: None,
+			core_dump_path: None,
+			clock: Clock::new(),
+			entropy: Entropy::new(),
+			roms: Roms::default(),
+			watchdog: None,
+			cancel: AtomicBool::new(false),
+			aborted: false,
+			memory_domains: MemoryDomains::default(),
+			arenas: Arenas::default(),
+			fibers: Fibers::default(),
+			history: History::default(),
+			metrics: Metrics::default(),
+			transfers: Transfers::default(),
+			profile: Profiler::default(),
+			heap_profile: HeapProfiler::default(),
+			heap_baseline: None,
+			heap_ops: 0,
+			heap_layout: HeapLayout::Off,
+			config: HashMap::new(),
+			session: Rc::new(RefCell::new(Session::Off)),
 		});
+		leaks::HOSTS.opened();
 
 		let mut active = res.activate();
-		active.h.elf.native_init(&mut active.b);
+		active.h.elf.native_init(&mut active.b, start_block, start_info);
 		drop(active);
 
 		Ok(res)
 	}
 
 	pub fn active(&self) -> bool {
-		self.active
+		self.active.load(Ordering::SeqCst)
+	}
+	/// Mark the host active for activate(), unless it already is or it's been shut down.  Unlike activate(), this can
+	/// be raced by other threads.
+	pub fn claim(&self) -> bool {
+		!self.shut_down && self.active.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_ok()
+	}
+	pub fn shut_down(&self) -> bool {
+		self.shut_down
+	}
+	/// Give back everything the host holds outside of itself, the way destroying it would:  Guest memory and the
+	/// snapshots of it, the files the guest has open, the rewind buffer and journal, and the frontend's transfer
+	/// buffers.  The host can't be activated again afterwards, only destroyed.  Doing it twice does nothing.
+	pub fn shutdown(&mut self) -> anyhow::Result<()> {
+		if self.active() {
+			return Err(coded(ErrorCode::BadState, "WaterboxHost is still active!"))
+		}
+		if !self.shut_down {
+			self.shut_down = true;
+			leaks::SHUT_DOWN_HOSTS.opened();
+			self.release();
+		}
+		Ok(())
+	}
+	fn release(&mut self) {
+		if self.elf.abi() != GuestAbi::Wasm32 && !self.elf.relocated() {
+			unsafe { gdb::deregister(&self.image_file[..]) }
+		}
+		self.rewind = None;
+		self.journal = None;
+		self.transfers = Transfers::default();
+		self.history = History::default();
+		self.heap_baseline = None;
+		self.fs = FileSystem::new();
+		self.memory_block.release();
+	}
+
+	/// Have the call_guest() in progress, or the next one if there isn't one, give up at its next syscall.  Unlike
+	/// everything else, this can be called from any thread.
+	pub fn request_cancel(&self) {
+		self.cancel.store(true, Ordering::SeqCst);
 	}
 
 	pub fn activate(&mut self) -> Box<ActivatedWaterboxHost> {
@@ -57,37 +289,151 @@ impl WaterboxHost {
 			layout: self.layout,
 			syscall: WbxSysSyscall {
 				ud: 0,
-				syscall,
+				// the guest goes through the entry stub, which records where its thread can be resumed
+				syscall: unsafe { std::mem::transmute::<guest_abi!(unsafe fn()), SyscallFn>(threading::wbx_syscall_entry) },
 			}
 		};
 		let mut res = Box::new(ActivatedWaterboxHost {
 			tag: TAG,
+			entry: SyscallEntry {
+				handler: syscall as guest_abi!(fn(SyscallNumber, usize, usize, usize, usize, usize, usize, usize) -> SyscallReturn) as usize,
+				ctx: Default::default(),
+			},
 			h,
 			b,
 			sys
 		});
+		assert_eq!(&res.entry as *const SyscallEntry as usize - res.as_ref() as *const ActivatedWaterboxHost as usize, 8);
 		res.sys.syscall.ud = res.as_mut() as *mut ActivatedWaterboxHost as usize;
 		res.h.elf.connect_syscalls(&mut res.b, &res.sys);
-		res.h.active = true;
+		res.h.active.store(true, Ordering::SeqCst);
+		ACTIVE_HOSTS.lock().unwrap().push(res.sys.syscall.ud);
+		if !res.h.roms.is_empty() {
+			if let Err(e) = res.verify_roms() {
+				log!(Error, "{}", e);
+			}
+		}
 		res
 	}
 }
 impl Drop for WaterboxHost {
 	fn drop(&mut self) {
-		unsafe { gdb::deregister(&self.image_file[..]) }
+		if self.shut_down {
+			leaks::SHUT_DOWN_HOSTS.closed();
+		} else {
+			self.release();
+		}
+		leaks::HOSTS.closed();
 	}
 }
 
 const TAG: u64 = 0xd01487803948acff;
+#[repr(C)]
 pub struct ActivatedWaterboxHost<'a> {
 	tag: u64,
+	/// Must come right after the tag, for the syscall entry stub
+	entry: SyscallEntry,
 	h: &'a mut WaterboxHost,
 	b: ActivatedMemoryBlock<'a>,
 	sys: WbxSysArea,
 }
 impl<'a> Drop for ActivatedWaterboxHost<'a> {
 	fn drop(&mut self) {
-		self.h.active = false;
+		// the end of a frame, for most frontends
+		self.h.fs.flush_output();
+		self.h.active.store(false, Ordering::SeqCst);
+		let mut hosts = ACTIVE_HOSTS.lock().unwrap();
+		let me = self as *mut ActivatedWaterboxHost as usize;
+		hosts.retain(|&h| h != me);
+	}
+}
+
+lazy_static! {
+	/// Every ActivatedWaterboxHost, for finding who a fault belongs to
+	static ref ACTIVE_HOSTS: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+}
+
+/// Called from the trap handlers when guest code hits int3 (GUEST_TRAP_BREAKPOINT) or an illegal instruction
+/// (GUEST_TRAP_ILLEGAL).  If the code belongs to an active host with a guest trap callback, the callback is shown the
+/// registers, with rip at the trapping instruction, and says what happens next.  Returns true if the guest goes on,
+/// with the registers as the callback left them, and false if the trap should be handled as if there were no callback.
+/// unsafe: ucontext must be the handler's
+#[cfg(target_arch = "x86_64")]
+pub unsafe fn forward_guest_trap(ucontext: *mut std::ffi::c_void, kind: u32) -> bool {
+	use cinterface::{GUEST_TRAP_BREAKPOINT, GUEST_TRAP_RESUME, GUEST_TRAP_SKIP};
+	let hosts = match ACTIVE_HOSTS.try_lock() {
+		Ok(h) => h,
+		Err(_) => return false,
+	};
+	let mut regs = signal_context::user_regs(ucontext);
+	// int3 leaves rip past itself.  only it and ud2 are known to be so long, so only they can be skipped
+	let (addr, len) = if kind == GUEST_TRAP_BREAKPOINT {
+		(regs.rip as usize - 1, Some(1))
+	} else {
+		let mut code = [0u8; 2];
+		let ud2 = memory_block::debug_read(regs.rip as usize, &mut code) && code == [0x0f, 0x0b];
+		(regs.rip as usize, if ud2 { Some(2) } else { None })
+	};
+	for &ud in hosts.iter() {
+		let h = &*(ud as *const ActivatedWaterboxHost);
+		if !h.sys.layout.all().contains(addr) {
+			continue
+		}
+		let (callback, userdata) = match h.h.guest_trap_callback {
+			Some(c) => c,
+			None => return false,
+		};
+		regs.rip = addr as u64;
+		match callback(userdata, kind, addr, &mut regs) {
+			GUEST_TRAP_RESUME => (),
+			GUEST_TRAP_SKIP => match len {
+				Some(len) => regs.rip = (addr + len) as u64,
+				None => {
+					log!(Warn, "Guest trap at {:x} can't be skipped, as the instruction's length isn't known", addr);
+					return false
+				}
+			},
+			_ => return false,
+		}
+		signal_context::set_user_regs(ucontext, &regs);
+		return true
+	}
+	false
+}
+
+/// Called from the fault handler when guest code faults on something nobody can handle.  If the code belongs to an
+/// active host with a crash callback, the callback gets a report.
+pub fn report_crash(fault: &crash::Fault) {
+	let hosts = match ACTIVE_HOSTS.try_lock() {
+		Ok(h) => h,
+		// whoever has it is about to regret it
+		Err(_) => return,
+	};
+	for &ud in hosts.iter() {
+		let h = unsafe { &*(ud as *const ActivatedWaterboxHost) };
+		let all = h.sys.layout.all();
+		let rip = fault.regs.rip as usize;
+		if !all.contains(rip) {
+			continue
+		}
+		if let Some((callback, userdata)) = h.h.crash_callback {
+			let frames = crash::backtrace(fault.regs.rbp as usize, |addr| all.contains(addr));
+			let text = crash::describe(fault, h.h.threads.current(), &frames, |addr| h.h.elf.symbolize(addr));
+			let text = CString::new(text).unwrap_or_default();
+			callback(userdata, rip, fault.addr, text.as_ptr());
+		}
+		if let Some(path) = h.h.core_dump_path.as_ref() {
+			let regions = memory_block::debug_regions(all);
+			let files = h.h.elf.mapped_files();
+			let res = h.h.fs.host_paths.check(std::path::Path::new(path), true)
+				.and_then(|()| std::fs::File::create(path).map_err(anyhow::Error::from))
+				.and_then(|mut f| coredump::write_core(&mut f, fault.signal, &fault.regs, &regions, &files));
+			match res {
+				Ok(()) => log!(Info, "Wrote guest core dump to {}", path),
+				Err(e) => log!(Error, "Couldn't write guest core dump to {}: {}", path, e),
+			}
+		}
+		return
 	}
 }
 
@@ -95,62 +441,1286 @@ impl<'a> ActivatedWaterboxHost<'a> {
 	pub fn get_proc_addr(&self, name: &str) -> usize {
 		self.h.elf.get_proc_addr(name)
 	}
+	/// Whether a guest call is running, maybe on another thread
+	pub fn calling(&self) -> bool {
+		self.h.calling.load(Ordering::SeqCst)
+	}
+	fn check_aborted(&self) -> anyhow::Result<()> {
+		if self.h.aborted {
+			Err(coded(ErrorCode::Poisoned, "The guest aborted, and can't be used until a state is loaded"))
+		} else {
+			Ok(())
+		}
+	}
 	fn check_sealed(&self) -> anyhow::Result<()> {
 		if !self.h.sealed {
-			Err(anyhow!("Not sealed!"))
+			Err(coded(ErrorCode::BadState, "Not sealed!"))
 		} else {
 			Ok(())
 		}
 	}
+	/// Seal the host, after which states can be made.  If it was sealed before and then unsealed, everything the guest
+	/// changed in between becomes part of the new baseline, and states from before can't be loaded anymore.
 	pub fn seal(&mut self) -> anyhow::Result<()> {
 		if self.h.sealed {
-			return Err(anyhow!("Already sealed!"))
+			return Err(coded(ErrorCode::BadState, "Already sealed!"))
+		}
+		self.h.elf.pre_seal(&mut self.b);
+		let res = if self.b.sealed() { self.b.reseal() } else { self.b.seal(); Ok(()) };
+		self.h.elf.connect_syscalls(&mut self.b, &self.sys);
+		res?;
+		self.h.sealed = true;
+		self.h.seals += 1;
+		// none of these could be loaded anymore
+		if let Some(r) = self.h.rewind.as_mut() {
+			r.clear();
+		}
+		self.restart_journal();
+		Ok(())
+	}
+	/// Open a window for more setup after sealing, like an init the core only does once it has run a frame.  States
+	/// can't be made or loaded until the host is sealed again, which takes whatever the guest did in between into the
+	/// baseline, so that it isn't in every state after.
+	pub fn unseal(&mut self) -> anyhow::Result<()> {
+		self.check_sealed()?;
+		self.check_no_session("Unsealing")?;
+		if self.calling() {
+			return Err(coded(ErrorCode::BadState, "Can't unseal during a guest call"))
+		}
+		self.h.sealed = false;
+		Ok(())
+	}
+	/// unseal(), and also make what sealing made read only writable again, like the sealed heap and relocated data, so
+	/// that a core's developer can change what its setup left there through write_memory() without rebuilding it.  For
+	/// development builds only.
+	#[cfg(feature = "dev-unseal")]
+	pub fn unseal_for_development(&mut self) -> anyhow::Result<()> {
+		self.unseal()?;
+		let layout = self.sys.layout;
+		self.h.elf.unprotect_sealed(&mut self.b, &layout)?;
+		Ok(())
+	}
+	/// Restrict the current thread to the syscalls the host makes, plus `extra`, for good.  Only allowed once sealed,
+	/// as loading the core needs more.
+	pub fn restrict_syscalls(&mut self, extra: &[u32]) -> anyhow::Result<()> {
+		self.check_sealed()?;
+		seccomp::install(extra)
+	}
+	/// Load a secondary library into the guest and run its initializers.  Only allowed before sealing, so that every
+	/// savestate has the same libraries in the same places.
+	pub fn load_module(&mut self, name: &str, data: Vec<u8>) -> anyhow::Result<usize> {
+		if self.h.sealed {
+			return Err(coded(ErrorCode::BadState, "Modules must be loaded before sealing"))
+		}
+		let layout = self.sys.layout;
+		self.h.elf.load_module(&data[..], name, &layout, &mut self.b)
+	}
+	/// Experimental:  Swap in the code and read only data of `image`, a rebuild of the guest, keeping its memory.  States
+	/// made before then can't be loaded after.
+	pub fn reload_elf(&mut self, image: Vec<u8>) -> anyhow::Result<()> {
+		self.check_no_session("Reloading the executable")?;
+		let wbx = Elf::parse(&image[..])?;
+		let sealed = self.h.sealed;
+		self.h.elf.reload(&wbx, &image[..], &mut self.b, sealed)?;
+		if !self.h.elf.relocated() {
+			unsafe {
+				gdb::deregister(&self.h.image_file[..]);
+				gdb::register(&image[..]);
+			}
+		}
+		self.h.image_hash = bin::hash(&image[..]);
+		self.h.image_file = image;
+		Ok(())
+	}
+	pub fn mount_file(&mut self, name: String, data: ChunkedData, writable: bool) -> anyhow::Result<()> {
+		self.check_no_session("Mounting files")?;
+		self.h.fs.mount(name, data, writable)
+	}
+	pub fn mount_overlay_file(&mut self, name: String, data: Vec<u8>, persist: bool) -> anyhow::Result<()> {
+		self.check_no_session("Mounting files")?;
+		self.h.fs.mount_overlay(name, data, persist)
+	}
+	pub fn mount_host_dir(&mut self, guest_path: &str, host_path: &str, readonly: bool) -> anyhow::Result<usize> {
+		self.check_no_session("Mounting files")?;
+		self.h.fs.mount_host_dir(guest_path, std::path::Path::new(host_path), readonly)
+	}
+	pub fn mount_zip(&mut self, mount_point: &str, data: Vec<u8>) -> anyhow::Result<usize> {
+		self.check_no_session("Mounting files")?;
+		self.h.fs.mount_zip(mount_point, data)
+	}
+	pub fn mount_stream(&mut self, name: String, source: Box<dyn Read>, ready: Option<Box<dyn FnMut() -> bool>>) -> anyhow::Result<()> {
+		self.check_no_session("Mounting files")?;
+		self.h.fs.mount_stream(name, source, ready)
+	}
+	pub fn unmount_file(&mut self, name: &str) -> anyhow::Result<Vec<u8>> {
+		self.check_no_session("Unmounting files")?;
+		self.h.fs.unmount(name)
+	}
+	/// Hand the guest's changes to persistent overlay files to `sink`, so they can be written back to disk
+	pub fn flush_files(&self, sink: impl FnMut(&str, &[u8]) -> anyhow::Result<()>) -> anyhow::Result<()> {
+		self.h.fs.flush(sink)
+	}
+	/// Set the policy for guest requests for writable and executable memory.  If a callback is supplied, it is told
+	/// about every such request under Deny and Report, whether or not it's allowed; under Allow it's dropped.  Does not
+	/// affect anything already mapped.
+	pub fn set_wx_policy(&mut self, policy: WxPolicy, callback: Option<(WxViolationCallback, usize)>) {
+		self.h.wx_policy = policy;
+		self.h.wx_callback = if policy == WxPolicy::Allow { None } else { callback };
+	}
+	/// Map a read only view of guest memory into the host, which mirrors it without copying.  Returns where in the
+	/// view `addr.start` ended up.
+	pub fn map_host_view(&mut self, addr: AddressRange) -> anyhow::Result<usize> {
+		let view = self.b.map_host_view(addr)?;
+		Ok(view.start + (addr.start & PAGEMASK))
+	}
+	/// Leave guest memory in `addr` out of savestates, such as a framebuffer, and have it read as zero after one is
+	/// loaded.  `transient` false puts it back in.
+	pub fn set_transient(&mut self, addr: AddressRange, transient: bool) -> anyhow::Result<()> {
+		self.b.mark_transient(addr.align_expand(), transient)?;
+		Ok(())
+	}
+	/// Copy guest memory out, up to the first page the guest couldn't read.  Returns how much was copied.
+	pub fn read_memory(&mut self, addr: usize, dest: &mut [u8]) -> usize {
+		self.b.read(addr, dest)
+	}
+	/// Copy into guest memory, up to the first page the guest couldn't write.  Returns how much was copied.
+	pub fn write_memory(&mut self, addr: usize, src: &[u8]) -> usize {
+		let n = self.b.write(addr, src);
+		self.record(Event::WriteMemory { addr, data: src[..n].to_vec() });
+		n
+	}
+	/// Where everything is in guest memory
+	#[cfg(feature = "fuzz")]
+	pub fn layout(&self) -> WbxSysLayout {
+		self.sys.layout
+	}
+	/// Check that what the host knows about the guest still adds up:  The program break is in the heap, and every
+	/// page of guest memory has the protections it should.
+	#[cfg(feature = "fuzz")]
+	pub fn check_bookkeeping(&self) -> anyhow::Result<()> {
+		let brk = self.h.program_break;
+		if brk != align_down(brk) || !(self.sys.layout.sbrk.contains(brk) || brk == self.sys.layout.sbrk.end()) {
+			return Err(anyhow!("Program break {:x} is outside of the heap", brk))
+		}
+		self.b.check_protections()
+	}
+	/// The shared memory object behind all of guest memory, for other processes to map
+	pub fn memory_handle(&mut self) -> usize {
+		self.b.backing_handle()
+	}
+	/// Set the callback for reports on unrecoverable guest faults.  The report is given while the fault is being
+	/// handled, before the process probably goes down, so the callback must not call back into waterbox.
+	pub fn set_crash_callback(&mut self, callback: Option<(CrashCallback, usize)>) {
+		self.h.crash_callback = callback;
+	}
+	/// Set the callback that decides what happens when the guest hits int3 or an illegal instruction, instead of its
+	/// call being abandoned.  Like the crash callback, it runs in the signal handler, and must not call back into
+	/// waterbox.
+	pub fn set_guest_trap_callback(&mut self, callback: Option<(GuestTrapCallback, usize)>) {
+		self.h.guest_trap_callback = callback;
+	}
+	/// Write an ELF core file of the guest to `path` on unrecoverable guest faults, or stop doing so
+	pub fn set_core_dump_path(&mut self, path: Option<String>) -> anyhow::Result<()> {
+		if let Some(p) = path.as_ref() {
+			self.h.fs.host_paths.check(std::path::Path::new(p), true)?;
+		}
+		self.h.core_dump_path = path;
+		Ok(())
+	}
+	/// Allow host files under the directory `path` to be read in, and written out if `writable`.  Once any path is
+	/// allowed, no others are.
+	pub fn allow_host_path(&mut self, path: &str, writable: bool) -> anyhow::Result<()> {
+		self.h.fs.host_paths.allow(std::path::Path::new(path), writable)
+	}
+	/// Enforce the allowed host paths on everything the calling thread does from now on, with Landlock
+	pub fn restrict_host_paths(&mut self) -> anyhow::Result<()> {
+		self.h.fs.host_paths.landlock()
+	}
+	/// Guest output lines get the core's name in front, so they stand out in the frontend's log
+	pub fn set_output_callback(&mut self, callback: Option<(GuestOutputCallback, usize)>) {
+		let prefix = if self.h.core_name.is_empty() { String::new() } else { format!("[{}] ", self.h.core_name) };
+		self.h.fs.set_output_callback(callback, prefix);
+	}
+	pub fn flush_output(&mut self) {
+		self.h.fs.flush_output();
+	}
+	pub fn set_tmp_quota(&mut self, quota: usize) {
+		self.h.fs.set_tmp_quota(quota);
+	}
+	pub fn tmp_used(&self) -> usize {
+		self.h.fs.tmp_used()
+	}
+	/// Pass over readonly guest memory in `addr` in everything to do with states, for good, and don't let the guest
+	/// change it.  Before sealing.
+	pub fn mark_immutable(&mut self, addr: AddressRange) -> anyhow::Result<()> {
+		if self.b.sealed() {
+			return Err(coded(ErrorCode::BadState, "Memory can only be made immutable before sealing"))
+		}
+		self.b.mark_immutable(addr).map_err(|e| match e {
+			ENOMEM => coded(ErrorCode::UnmappedAddress, format!("{:x}:{:x} isn't all mapped", addr.start, addr.end())),
+			_ => coded(ErrorCode::InvalidArgument, format!("{:x}:{:x} has to be whole pages of readonly memory", addr.start, addr.end())),
+		})
+	}
+	/// Make `addr`, which the guest has already loaded a ROM into, readonly for good.  With `expected`, it has to have
+	/// that SHA-256 already, and without, it's meant to keep the one it has now.
+	pub fn register_rom(&mut self, addr: AddressRange, expected: Option<&[u8]>) -> anyhow::Result<()> {
+		let guest = self.sys.layout.all();
+		if addr.size == 0 || addr.start != align_down(addr.start) || addr.size != align_down(addr.size) {
+			return Err(coded(ErrorCode::InvalidArgument, "ROM regions have to be whole pages"))
+		}
+		if addr.start < guest.start || addr.checked_end().filter(|&end| end <= guest.end()).is_none() {
+			return Err(coded(ErrorCode::UnmappedAddress, format!("ROM at {:x} isn't in guest memory", addr.start)))
+		}
+		let hash = self.rom_hash(addr)?;
+		if let Some(e) = expected {
+			if e != &hash[..] {
+				return Err(coded(ErrorCode::Corrupted, format!("ROM at {:x} doesn't have the hash it was registered with", addr.start)))
+			}
+		}
+		self.h.roms.add(addr, hash)?;
+		// immutable pages are readonly already
+		if !matches!(self.b.mprotect(addr, Protection::R), Ok(()) | Err(EPERM)) {
+			self.h.roms.remove(addr.start)?;
+			return Err(coded(ErrorCode::UnmappedAddress, format!("ROM at {:x} isn't all mapped", addr.start)))
+		}
+		Ok(())
+	}
+	/// Stop guarding the ROM region that starts at `start`, and let the guest have it back as readable and writable
+	pub fn unregister_rom(&mut self, start: usize) -> anyhow::Result<()> {
+		let addr = self.h.roms.iter().find(|r| r.0.start == start).map(|r| r.0);
+		self.h.roms.remove(start)?;
+		match addr.map(|addr| self.b.mprotect(addr, Protection::RW)) {
+			None | Some(Ok(())) | Some(Err(EPERM)) => (),
+			Some(Err(e)) => return Err(anyhow!("Couldn't unprotect ROM at {:x}: {:?}", start, e)),
+		}
+		Ok(())
+	}
+	/// Check that every ROM region still has its hash
+	pub fn verify_roms(&mut self) -> anyhow::Result<()> {
+		let roms = self.h.roms.iter().map(|(addr, hash)| (addr, hash.to_vec())).collect::<Vec<_>>();
+		for (addr, hash) in roms {
+			if self.rom_hash(addr)? != hash {
+				return Err(coded(ErrorCode::Corrupted, format!("ROM at {:x} was changed", addr.start)))
+			}
+		}
+		Ok(())
+	}
+	fn rom_hash(&mut self, addr: AddressRange) -> anyhow::Result<Vec<u8>> {
+		let mut data = vec![0u8; addr.size];
+		if self.b.read(addr.start, &mut data[..]) != addr.size {
+			return Err(coded(ErrorCode::UnmappedAddress, format!("ROM at {:x} isn't all readable", addr.start)))
+		}
+		Ok(bin::hash(&data[..]))
+	}
+	/// After a state is loaded, which has its own idea of the ROM regions' protections and content
+	fn guard_roms(&mut self) -> anyhow::Result<()> {
+		let roms = self.h.roms.iter().map(|r| r.0).collect::<Vec<_>>();
+		for addr in roms {
+			if !matches!(self.b.mprotect(addr, Protection::R), Ok(()) | Err(EPERM)) {
+				return Err(coded(ErrorCode::Corrupted, format!("ROM at {:x} isn't mapped in the state", addr.start)))
+			}
+		}
+		self.verify_roms()
+	}
+	/// Start NR_WBX_ENTROPY's stream over from `seed`
+	pub fn seed_entropy(&mut self, seed: &[u8]) {
+		self.record(Event::SeedEntropy(seed.to_vec()));
+		self.h.entropy.seed(seed);
+	}
+	/// Reseed the generator behind the guest's getrandom() and /dev/urandom
+	pub fn set_random_seed(&mut self, seed: u64) {
+		self.record(Event::SetRandomSeed(seed));
+		self.h.fs.set_random_seed(seed);
+	}
+	/// Move the guest's clocks forward
+	pub fn advance_clock(&mut self, ns: u64) {
+		self.record(Event::AdvanceClock(ns));
+		self.h.clock.advance(ns);
+	}
+	/// Set the guest's wall clock, in seconds since the epoch
+	pub fn set_clock_realtime(&mut self, secs: i64) {
+		self.record(Event::SetClockRealtime(secs));
+		self.h.clock.set_realtime(secs);
+	}
+	/// What the guest's wall clock says, in seconds since the epoch, which the guest may have set itself
+	pub fn clock_realtime(&self) -> i64 {
+		self.h.clock.realtime()
+	}
+	/// Set (or clear, with None) how long a call_guest() can run before it's abandoned
+	pub fn set_watchdog(&mut self, budget: Option<Duration>) {
+		self.h.watchdog = budget;
+	}
+	/// Call a guest function, under the watchdog if one is set
+	pub fn call_guest(&mut self, func: usize, args: &[usize; 6]) -> anyhow::Result<usize> {
+		if self.calling() {
+			// a host callback calling back in, which a replay couldn't do again
+			self.check_no_session("Calling into the guest from inside another call")?;
+			return self.run_guest(func, args)
+		}
+		self.record(Event::Call { func, args: *args });
+		let started = self.h.metrics.start();
+		let res = self.run_guest(func, args);
+		self.h.metrics.guest_call(started, res.is_ok());
+		self.record(Event::Return(res.as_ref().map(|&r| r).map_err(|e| ErrorCode::of(e) as i32)));
+		res
+	}
+	/// Run guest code, either from the top, or nested inside another call, from a host callback it made
+	fn run_guest(&mut self, func: usize, args: &[usize; 6]) -> anyhow::Result<usize> {
+		self.b.check_poisoned()?;
+		self.check_aborted()?;
+		let nested = self.h.calling.swap(true, Ordering::SeqCst);
+		// the outer call is in a syscall, which goes back to its guest thread with this once the callback returns
+		let outer_ctx = self.entry.ctx;
+		let span = self.h.profile.begin(func);
+		if !nested {
+			self.b.arm_heat_map();
+		}
+		let fp = GuestFp::enter(self.h.elf.fp_env());
+		let res = watchdog::call(func, args, self.sys.layout.all(), self.h.watchdog, &self.h.cancel);
+		self.h.elf.set_fp_env(fp.leave());
+		self.entry.ctx = outer_ctx;
+		self.h.calling.store(nested, Ordering::SeqCst);
+		if !nested {
+			self.b.disarm_heat_map();
+		}
+		self.b.unpoison_for_sanitizers();
+		self.h.profile.end(span);
+		if !nested {
+			// a cancel is only for calls that are running or about to
+			self.h.cancel.store(false, Ordering::SeqCst);
+		}
+		match res {
+			Ok(res) => {
+				// once per frame, not once per callback
+				if !nested {
+					self.b.apply_cheats();
+				}
+				Ok(res)
+			},
+			Err(watchdog::Abandoned::TimedOut) => Err(coded(ErrorCode::TimedOut, "Guest call ran past the watchdog's limit, and was abandoned")),
+			Err(watchdog::Abandoned::Cancelled) => Err(coded(ErrorCode::Cancelled, "Guest call was cancelled")),
+			Err(watchdog::Abandoned::Trapped) => Err(coded(ErrorCode::Trapped, "Guest call trapped, and was abandoned")),
+			Err(watchdog::Abandoned::Aborted) => {
+				self.h.aborted = true;
+				Err(coded(ErrorCode::Aborted, "Guest aborted, and its call was abandoned"))
+			},
+		}
+	}
+	/// Set (or clear, with None) where the guest's sockets connect to
+	pub fn set_socket_host(&mut self, host: Option<Box<dyn fs::SocketHost>>) {
+		self.record(Event::SocketHost(host.is_some()));
+		let session = self.h.session.clone();
+		self.h.fs.set_socket_host(host.map(|h| Box::new(replay::Sockets::new(Some(h), session)) as Box<dyn fs::SocketHost>));
+	}
+	fn record(&mut self, e: Event) {
+		self.h.session.borrow_mut().record(e);
+	}
+	fn check_no_session(&self, what: &str) -> anyhow::Result<()> {
+		if self.h.session.borrow().active() {
+			return Err(coded(ErrorCode::BadState, format!("{} isn't allowed while recording or replaying", what)))
+		}
+		Ok(())
+	}
+	/// Start recording everything that goes into the guest from here on, for replay().  The host has to be sealed,
+	/// and the frontend has to keep whatever state it's in now to replay from.
+	pub fn start_recording(&mut self) -> anyhow::Result<()> {
+		self.check_no_session("Starting a recording")?;
+		let start_hash = self.state_hash()?;
+		let events = vec![Event::SocketHost(self.h.fs.has_socket_host())];
+		*self.h.session.borrow_mut() = Session::Recording(Log { image_hash: self.h.image_hash.clone(), start_hash, events });
+		Ok(())
+	}
+	/// Log the end of a frame in a recording, with a hash of guest memory, which replay() checks
+	pub fn mark_frame(&mut self) -> anyhow::Result<()> {
+		if !self.h.session.borrow().recording() {
+			return Err(coded(ErrorCode::BadState, "Not recording"))
+		}
+		let hash = self.b.state_hash();
+		self.record(Event::Frame(hash));
+		Ok(())
+	}
+	/// Stop recording, and return what was recorded
+	pub fn stop_recording(&mut self) -> anyhow::Result<Log> {
+		let mut session = self.h.session.borrow_mut();
+		match std::mem::replace(&mut *session, Session::Off) {
+			Session::Recording(log) => Ok(log),
+			other => {
+				*session = other;
+				Err(coded(ErrorCode::BadState, "Not recording"))
+			},
+		}
+	}
+	/// Play back `log` from the state it was recorded in, which the host has to be in already.  Returns how many frames
+	/// matched, which is all of them if it succeeds; otherwise, the error says where it went differently.
+	pub fn replay(&mut self, log: Log) -> anyhow::Result<u64> {
+		self.check_no_session("Starting a replay")?;
+		if log.image_hash != self.h.image_hash {
+			return Err(coded(ErrorCode::StateMismatch, "The replay was recorded with a different core"))
+		}
+		if self.state_hash()? != log.start_hash {
+			return Err(coded(ErrorCode::StateMismatch, "The host isn't in the state the replay was recorded from"))
+		}
+		// the frontend's sockets sit the replay out
+		let sockets = self.h.fs.replace_socket_host(None);
+		*self.h.session.borrow_mut() = Session::Replaying { events: log.events, next: 0, desync: None };
+		let res = self.replay_events();
+		*self.h.session.borrow_mut() = Session::Off;
+		self.h.fs.replace_socket_host(sockets);
+		res
+	}
+	fn replay_events(&mut self) -> anyhow::Result<u64> {
+		let mut frames = 0;
+		loop {
+			let at = self.h.session.borrow().position();
+			let desync = |what: String| Err(coded(ErrorCode::Desync, format!("Replay desynced at event {}, after {} frames:  {}", at, frames, what)));
+			let event = self.h.session.borrow_mut().next();
+			match event {
+				None => return Ok(frames),
+				Some(Event::Call { func, args }) => {
+					let res = self.call_guest(func, &args).map_err(|e| ErrorCode::of(&e) as i32);
+					if let Session::Replaying { desync: Some(what), .. } = &*self.h.session.borrow() {
+						return Err(coded(ErrorCode::Desync, what.clone()))
+					}
+					let recorded = self.h.session.borrow_mut().next();
+					match recorded {
+						Some(Event::Return(r)) if r == res => (),
+						Some(Event::Return(r)) => return desync(format!("Call to {:x} returned {:x?}, but {:x?} was recorded", func, res, r)),
+						_ => return desync(format!("Call to {:x} made fewer socket calls than were recorded", func)),
+					}
+				},
+				Some(Event::WriteMemory { addr, data }) => {
+					if self.write_memory(addr, &data) != data.len() {
+						return desync(format!("Guest memory at {:x} wasn't writable", addr))
+					}
+				},
+				Some(Event::AdvanceClock(ns)) => self.advance_clock(ns),
+				Some(Event::SetClockRealtime(secs)) => self.set_clock_realtime(secs),
+				Some(Event::SetRandomSeed(seed)) => self.set_random_seed(seed),
+				Some(Event::SeedEntropy(seed)) => self.seed_entropy(&seed),
+				Some(Event::SetConfig { key, value }) => self.set_config(&key, value.as_deref()),
+				Some(Event::SocketHost(has)) => {
+					let host = if has {
+						let sockets = replay::Sockets::new(None, self.h.session.clone());
+						Some(Rc::new(RefCell::new(Box::new(sockets) as Box<dyn fs::SocketHost>)))
+					} else {
+						None
+					};
+					self.h.fs.replace_socket_host(host);
+				},
+				Some(Event::Frame(hash)) => {
+					let now = self.b.state_hash();
+					if now != hash {
+						return desync(format!("Guest memory hashes to {:x}, but {:x} was recorded", now, hash))
+					}
+					frames += 1;
+				},
+				Some(e) => return desync(format!("{:x?} was recorded outside of any call", e)),
+			}
+		}
+	}
+	/// Call `callback` after every guest syscall, or stop if None
+	pub fn set_syscall_trace(&mut self, callback: Option<(SyscallTraceCallback, usize)>) {
+		self.h.syscall_trace = callback;
+	}
+	/// Call `callback` whenever the guest yields or sleeps, or stop if None
+	pub fn set_yield_callback(&mut self, callback: Option<(YieldCallback, usize)>) {
+		self.h.yield_callback = callback;
+	}
+	/// Set what happens when the guest makes syscall `nr`, numbered as on x86_64
+	pub fn set_syscall_action(&mut self, nr: usize, action: SyscallAction) {
+		self.h.syscall_policy.set(nr, action);
+	}
+	/// Set (or clear, with None) what answers syscalls whose action is SyscallAction::Trap
+	pub fn set_syscall_trap_callback(&mut self, callback: Option<(SyscallTrapCallback, usize)>) {
+		self.h.syscall_policy.set_trap_callback(callback);
+	}
+	/// Control whether save_state emits compressed states.  load_state accepts either kind regardless.
+	pub fn set_compress_states(&mut self, val: bool) {
+		self.h.compress_states = val;
+	}
+	/// Control whether save_state compares dirty pages against the sealed baseline, and leaves
+	/// out the ones that have returned to their original content.
+	pub fn set_delta_states(&mut self, val: bool) {
+		self.h.delta_states = val;
+	}
+	/// Set the size of the rewind buffer in bytes, discarding any frames already in it.  0 disables rewind.
+	pub fn set_rewind_capacity(&mut self, capacity: usize) {
+		self.set_rewind(capacity, rewind::DEFAULT_KEYFRAME_INTERVAL);
+	}
+	/// Like set_rewind_capacity, with every `keyframe_interval`th frame a whole state and deltas against it between
+	pub fn set_rewind(&mut self, capacity: usize, keyframe_interval: usize) {
+		self.h.rewind = if capacity == 0 {
+			None
+		} else {
+			Some(RewindBuffer::new(capacity, keyframe_interval))
+		};
+	}
+	/// Save the current state into the rewind buffer
+	pub fn capture_rewind_frame(&mut self) -> anyhow::Result<()> {
+		if self.h.rewind.is_none() {
+			return Err(coded(ErrorCode::BadState, "Rewind is not enabled"))
+		}
+		self.check_sealed()?;
+		if self.h.delta_states {
+			self.b.clean_unchanged_pages();
+		}
+		// uncompressed, so the buffer can make a delta of it
+		let mut frame = Vec::new();
+		self.save_state_raw(&mut frame)?;
+		self.h.rewind.as_mut().unwrap().push(&frame[..]);
+		Ok(())
+	}
+	/// Load the state captured `n` frames ago, discarding it and every frame captured after it.  If the state can't
+	/// be loaded, no frames are discarded.
+	pub fn rewind(&mut self, n: usize) -> anyhow::Result<()> {
+		let frame = match self.h.rewind.as_mut() {
+			Some(r) => r.peek(n)?,
+			None => return Err(coded(ErrorCode::BadState, "Rewind is not enabled")),
+		};
+		self.load_state(&mut &frame[..])?;
+		self.h.rewind.as_mut().unwrap().truncate(n)
+	}
+	pub fn rewind_info(&self) -> RewindInfo {
+		match &self.h.rewind {
+			Some(r) => RewindInfo { capacity: r.capacity(), used: r.used(), frames: r.len(), keyframes: r.keyframes(), keyframe_interval: r.keyframe_interval() },
+			None => RewindInfo { capacity: 0, used: 0, frames: 0, keyframes: 0, keyframe_interval: 0 },
+		}
+	}
+	/// Watch a range of guest memory for reads and/or writes.  See ActivatedMemoryBlock::add_watchpoint()
+	pub fn add_watchpoint(&mut self, addr: AddressRange, kind: u8, callback: WatchCallback, userdata: usize) -> anyhow::Result<u32> {
+		self.b.add_watchpoint(addr, kind, callback, userdata)
+	}
+	pub fn remove_watchpoint(&mut self, id: u32) -> anyhow::Result<()> {
+		self.b.remove_watchpoint(id)
+	}
+	/// Set what the guest gets when it looks up `key` with __wbx_get_config, or remove it with None
+	pub fn set_config(&mut self, key: &str, value: Option<&str>) {
+		self.record(Event::SetConfig { key: key.to_string(), value: value.map(|v| v.to_string()) });
+		match value {
+			Some(v) => self.h.config.insert(key.to_string(), v.to_string()),
+			None => self.h.config.remove(key),
+		};
+	}
+	/// How many memory domains the guest has registered
+	pub fn arena_count(&self) -> usize {
+		self.h.arenas.count()
+	}
+	/// One of the arenas the guest has made, with how much of it is in use now
+	pub fn arena(&mut self, index: usize) -> anyhow::Result<ArenaInfo> {
+		let (name, addr) = self.h.arenas.get(index).ok_or_else(|| coded(ErrorCode::NotFound, format!("No arena {}", index)))?;
+		let (mapped_bytes, state_bytes) = self.b.usage(addr);
+		Ok(ArenaInfo { name, start: addr.start, size: addr.size, mapped_bytes, state_bytes })
+	}
+	pub fn memory_domain_count(&self) -> usize {
+		self.h.memory_domains.count()
+	}
+	/// What the guest and this host said in the imports handshake
+	pub fn imports(&self) -> ImportInfo {
+		self.h.elf.imports()
+	}
+	pub fn memory_domain(&self, index: usize) -> anyhow::Result<MemoryDomainInfo> {
+		self.h.memory_domains.get(index).ok_or_else(|| coded(ErrorCode::NotFound, format!("No memory domain {}", index)))
+	}
+	/// Where each byte of a `width` byte value at `offset` is:  In memory domain `domain`, through its translator if it
+	/// has one, or in all of guest memory if None
+	fn value_addresses(&mut self, domain: Option<usize>, offset: usize, width: usize, write: bool) -> anyhow::Result<Vec<usize>> {
+		let index = match domain {
+			Some(index) => index,
+			None => return Ok((0..width).map(|i| offset.wrapping_add(i)).collect()),
+		};
+		let d = self.memory_domain(index)?;
+		if offset >= d.size || width > d.size - offset {
+			return Err(coded(ErrorCode::InvalidArgument, format!("{} bytes at {:x} are past the end of memory domain {}", width, offset, index)))
+		}
+		if write && d.flags & memory_domains::DOMAIN_WRITABLE == 0 {
+			return Err(coded(ErrorCode::InvalidArgument, format!("Memory domain {} isn't writable", index)))
+		}
+		let translator = match self.h.memory_domains.translator(index) {
+			Some(t) => t,
+			None => return Ok((0..width).map(|i| d.start + offset + i).collect()),
+		};
+		// runs guest code, which a replay wouldn't
+		self.check_no_session("Reading through a memory domain's translator")?;
+		let mut res = Vec::with_capacity(width);
+		for i in 0..width {
+			match self.call_guest(translator, &[offset + i, 0, 0, 0, 0, 0])? {
+				0 => return Err(coded(ErrorCode::UnmappedAddress, format!("Byte {:x} of memory domain {} isn't mapped now", offset + i, index))),
+				addr => res.push(addr),
+			}
+		}
+		Ok(res)
+	}
+	/// Read a value of type `ty` (VALUE_*) from guest memory, at `offset` in memory domain `domain`, or at that address
+	/// if None
+	pub fn read_value(&mut self, domain: Option<usize>, offset: usize, ty: u32) -> anyhow::Result<u64> {
+		let width = memory_domains::value_width(ty).ok_or_else(|| coded(ErrorCode::InvalidArgument, format!("No value type {:#x}", ty)))?;
+		let addrs = self.value_addresses(domain, offset, width, false)?;
+		let mut bytes = [0u8; 8];
+		for (&addr, b) in addrs.iter().zip(bytes.iter_mut()) {
+			if self.read_memory(addr, std::slice::from_mut(b)) != 1 {
+				return Err(coded(ErrorCode::UnmappedAddress, format!("Guest memory at {:x} isn't readable", addr)))
+			}
+		}
+		Ok(memory_domains::decode_value(ty, &bytes[..width]))
+	}
+	/// Write a value of type `ty` the other way.  A value that's only partly writable is written up to there.
+	pub fn write_value(&mut self, domain: Option<usize>, offset: usize, ty: u32, value: u64) -> anyhow::Result<()> {
+		let width = memory_domains::value_width(ty).ok_or_else(|| coded(ErrorCode::InvalidArgument, format!("No value type {:#x}", ty)))?;
+		let addrs = self.value_addresses(domain, offset, width, true)?;
+		let mut bytes = [0u8; 8];
+		memory_domains::encode_value(ty, value, &mut bytes[..width]);
+		for (&addr, b) in addrs.iter().zip(bytes.iter()) {
+			if self.write_memory(addr, std::slice::from_ref(b)) != 1 {
+				return Err(coded(ErrorCode::UnmappedAddress, format!("Guest memory at {:x} isn't writable", addr)))
+			}
+		}
+		Ok(())
+	}
+	/// Keep the last `capacity` changes to the value read_value() would read, checking now that it can be read
+	pub fn add_history_watch(&mut self, domain: Option<usize>, offset: usize, ty: u32, capacity: usize) -> anyhow::Result<u32> {
+		self.read_value(domain, offset, ty)?;
+		self.h.history.add(domain, offset, ty, capacity)
+	}
+	pub fn remove_history_watch(&mut self, id: u32) -> anyhow::Result<()> {
+		self.h.history.remove(id)
+	}
+	/// Read every history watch for `frame`.  One that can't be read now, say because it has a translator and there's
+	/// a replay session, records nothing.
+	pub fn record_history(&mut self, frame: u64) {
+		let values = self.h.history.watches().into_iter()
+			.map(|(domain, offset, ty)| self.read_value(domain, offset, ty).ok())
+			.collect::<Vec<_>>();
+		self.h.history.record(frame, &values[..]);
+	}
+	/// Copy the changes a history watch has from `since` on into `dest`, and return how many
+	pub fn history(&self, id: u32, since: u64, dest: &mut [HistorySample]) -> anyhow::Result<usize> {
+		self.h.history.samples(id, since, dest)
+	}
+	/// How big the core's SaveRAM, every battery backed memory domain one after another, is
+	pub fn saveram_size(&self) -> usize {
+		self.h.memory_domains.saveram().iter().map(|a| a.size).sum()
+	}
+	/// Copy out the core's SaveRAM, which is every battery backed memory domain, one after another in the order they
+	/// were first registered
+	pub fn get_saveram(&mut self) -> anyhow::Result<Vec<u8>> {
+		let mut res = Vec::with_capacity(self.saveram_size());
+		for addr in self.h.memory_domains.saveram() {
+			let at = res.len();
+			res.resize(at + addr.size, 0);
+			if self.b.read(addr.start, &mut res[at..]) != addr.size {
+				return Err(coded(ErrorCode::UnmappedAddress, format!("SaveRAM at {:#x} can't be read", addr.start)))
+			}
+		}
+		Ok(res)
+	}
+	/// Put back SaveRAM that get_saveram() returned, which has to be the same size
+	pub fn put_saveram(&mut self, data: &[u8]) -> anyhow::Result<()> {
+		if data.len() != self.saveram_size() {
+			return Err(coded(ErrorCode::InvalidArgument, format!("SaveRAM is {} bytes, but this core has {}", data.len(), self.saveram_size())))
+		}
+		let mut at = 0;
+		for addr in self.h.memory_domains.saveram() {
+			if self.write_memory(addr.start, &data[at..at + addr.size]) != addr.size {
+				return Err(coded(ErrorCode::UnmappedAddress, format!("SaveRAM at {:#x} can't be written", addr.start)))
+			}
+			at += addr.size;
+		}
+		Ok(())
+	}
+	/// Every address where `pattern` is in readable guest memory, or just in memory domain `domain`, up to `limit` of
+	/// them.  Only addresses that are multiples of `alignment` are looked at, and bytes are only compared where `mask`
+	/// has bits set.
+	pub fn search_memory(&mut self, domain: Option<usize>, pattern: &[u8], mask: Option<&[u8]>, alignment: usize, limit: usize) -> anyhow::Result<Vec<usize>> {
+		if pattern.is_empty() || alignment == 0 {
+			return Err(coded(ErrorCode::InvalidArgument, "Search pattern and alignment must not be empty"))
+		}
+		let addr = match domain {
+			Some(index) => {
+				let d = self.memory_domain(index)?;
+				AddressRange { start: d.start, size: d.size }
+			},
+			None => self.sys.layout.all(),
+		};
+		Ok(self.b.search(addr, pattern, mask, alignment, limit))
+	}
+	/// Hold guest memory at a value; see ActivatedMemoryBlock::add_cheat.  Cheats are applied after every call_guest.
+	pub fn add_cheat(&mut self, addr: usize, width: usize, value: u64, compare: Option<u64>, on_write: bool) -> anyhow::Result<u32> {
+		self.b.add_cheat(addr, width, value, compare, on_write)
+	}
+	pub fn remove_cheat(&mut self, id: u32) -> anyhow::Result<()> {
+		self.b.remove_cheat(id)
+	}
+	/// Log every write to a range of guest memory; see ActivatedMemoryBlock::add_audit
+	pub fn add_audit(&mut self, addr: AddressRange) -> anyhow::Result<u32> {
+		self.b.add_audit(addr)
+	}
+	pub fn remove_audit(&mut self, id: u32) -> anyhow::Result<()> {
+		self.b.remove_audit(id)
+	}
+	pub fn set_audit_capacity(&mut self, capacity: usize) {
+		self.b.set_audit_capacity(capacity)
+	}
+	/// Move the oldest logged writes into `dest`, and return how many
+	pub fn read_audit_log(&mut self, dest: &mut [AuditEntry]) -> usize {
+		self.b.read_audit_log(dest)
+	}
+	pub fn take_audit_dropped(&mut self) -> u64 {
+		self.b.take_audit_dropped()
+	}
+	/// Apply cheats now, for frontends that call guest functions themselves instead of through call_guest
+	pub fn apply_cheats(&mut self) {
+		self.b.apply_cheats()
+	}
+	/// Have the guest's NR_WBX_TRANSFERs to `name` go to `data`; see Transfers::register
+	/// # Safety
+	/// `data` must stay valid for writes of `size` bytes until the buffer is unregistered or replaced
+	pub unsafe fn register_transfer_buffer(&mut self, name: &str, data: *mut u8, size: usize) -> anyhow::Result<()> {
+		self.h.transfers.register(name, data, size)
+	}
+	pub fn unregister_transfer_buffer(&mut self, name: &str) -> anyhow::Result<()> {
+		self.h.transfers.unregister(name)
+	}
+	pub fn transfer_info(&self, name: &str) -> anyhow::Result<TransferInfo> {
+		self.h.transfers.info(name)
+	}
+	/// Start or stop gathering metrics; see Metrics
+	pub fn set_metrics(&mut self, enabled: bool) {
+		self.h.metrics.set_enabled(enabled);
+	}
+	/// Everything gathered so far, in the Prometheus text format
+	pub fn metrics(&mut self) -> String {
+		let stats = self.b.stats();
+		self.h.metrics.render(&self.h.core_name, &counters::read(), &stats)
+	}
+	/// Start or stop timing calls made with call_guest, and savestates
+	pub fn set_profiling(&mut self, enabled: bool) {
+		self.h.profile.set_enabled(enabled);
+	}
+	/// Totals for each entry point that's been profiled so far
+	pub fn profile(&self) -> &[EntryProfile] {
+		self.h.profile.entries()
+	}
+	pub fn reset_profile(&mut self) {
+		self.h.profile.reset();
+	}
+	/// Start or stop counting how often each guest page is accessed, with each guest call as a sampling period.
+	/// Stopping forgets the counts.
+	pub fn set_heat_map(&mut self, enabled: bool) -> anyhow::Result<()> {
+		self.b.set_heat_map(enabled)
+	}
+	pub fn heat_map(&self) -> Option<(HeatMapInfo, &[PageHeat])> {
+		self.b.heat_map()
+	}
+	pub fn reset_heat_map(&mut self) {
+		self.b.reset_heat_map();
+	}
+	/// Check readonly guest pages and the page table in the background; see ActivatedMemoryBlock::start_integrity_check
+	pub fn start_integrity_check(&mut self, pages_per_second: usize, callback: IntegrityCallback, userdata: usize) -> anyhow::Result<()> {
+		self.b.start_integrity_check(pages_per_second, callback, userdata)
+	}
+	pub fn stop_integrity_check(&mut self) {
+		self.b.stop_integrity_check();
+	}
+	pub fn integrity_info(&self) -> anyhow::Result<IntegrityInfo> {
+		self.b.integrity_info().ok_or_else(|| coded(ErrorCode::BadState, "The integrity checker isn't running"))
+	}
+	/// Place a marker to count the pages the guest writes from now on, without saving states to find out
+	pub fn add_dirty_marker(&mut self) -> anyhow::Result<u32> {
+		self.b.add_dirty_marker()
+	}
+	pub fn reset_dirty_marker(&mut self, marker: u32) -> anyhow::Result<()> {
+		self.b.reset_dirty_marker(marker)
+	}
+	pub fn remove_dirty_marker(&mut self, marker: u32) -> anyhow::Result<()> {
+		self.b.remove_dirty_marker(marker)
+	}
+	/// How many pages the guest has written since `marker` was placed or last reset
+	pub fn dirty_page_count_since(&self, marker: u32) -> anyhow::Result<usize> {
+		self.b.dirty_marker(marker)
+	}
+	/// Start or stop charging guest memory mappings to the code that made them.  Stopping forgets what was recorded.
+	pub fn set_heap_profiling(&mut self, enabled: bool) {
+		self.h.heap_profile.set_enabled(enabled);
+	}
+	/// The address of guest function or data `name`, exported or not
+	pub fn resolve_symbol(&self, name: &str) -> anyhow::Result<usize> {
+		self.h.elf.resolve_symbol(name).ok_or_else(|| coded(ErrorCode::NotFound, format!("No symbol `{}` in the guest", name)))
+	}
+	/// Name the guest function or data at `addr` as `symbol+offset`
+	pub fn symbolize(&self, addr: usize) -> anyhow::Result<String> {
+		self.h.elf.symbolize(addr).ok_or_else(|| coded(ErrorCode::NotFound, format!("No symbol at {:#x} in the guest", addr)))
+	}
+	/// Describe the `top` places in guest code with the most memory mapped since heap profiling started
+	pub fn heap_report(&self, top: usize) -> String {
+		let elf = &self.h.elf;
+		self.h.heap_profile.report(top, |addr| elf.symbolize(addr))
+	}
+	/// Remember what guest memory is mapped now, for report_heap_delta()
+	pub fn mark_heap_baseline(&mut self) {
+		self.h.heap_baseline = Some(HeapBaseline::new(self.sys.layout.all(), self.b.allocated_pages(), self.h.program_break));
+	}
+	/// What the guest has mapped and unmapped since mark_heap_baseline()
+	pub fn report_heap_delta(&self) -> anyhow::Result<HeapDelta> {
+		match &self.h.heap_baseline {
+			Some(b) => Ok(b.delta(&self.b.allocated_pages()[..], self.h.program_break, self.sys.layout.sbrk)),
+			None => Err(coded(ErrorCode::BadState, "No heap baseline was marked")),
+		}
+	}
+	/// Start recording every brk, mmap, mremap and munmap the guest makes, for another run to check against
+	pub fn start_heap_layout_recording(&mut self) {
+		self.h.heap_layout = HeapLayout::Recording(Vec::new());
+	}
+	pub fn stop_heap_layout_recording(&mut self) -> anyhow::Result<HeapLog> {
+		match std::mem::replace(&mut self.h.heap_layout, HeapLayout::Off) {
+			HeapLayout::Recording(ops) => Ok(HeapLog { image_hash: self.h.image_hash.clone(), ops }),
+			other => {
+				self.h.heap_layout = other;
+				Err(coded(ErrorCode::BadState, "Not recording the heap layout"))
+			},
+		}
+	}
+	/// Start checking the guest's memory calls against those in `log`, from wherever the guest is now
+	pub fn start_heap_layout_check(&mut self, log: HeapLog) -> anyhow::Result<()> {
+		if log.image_hash != self.h.image_hash {
+			return Err(coded(ErrorCode::StateMismatch, "The heap layout was recorded with a different core"))
+		}
+		self.h.heap_layout = HeapLayout::Checking { ops: log.ops, checked: 0, divergence: None };
+		Ok(())
+	}
+	/// How many memory calls have been checked and matched, or if one didn't, which
+	pub fn check_heap_layout(&self) -> anyhow::Result<u64> {
+		match &self.h.heap_layout {
+			HeapLayout::Checking { divergence: Some(what), .. } => Err(coded(ErrorCode::Desync, what.clone())),
+			HeapLayout::Checking { checked, .. } => Ok(*checked),
+			_ => Err(coded(ErrorCode::BadState, "Not checking the heap layout")),
+		}
+	}
+	/// Stop checking, with what check_heap_layout() would have said
+	pub fn stop_heap_layout_check(&mut self) -> anyhow::Result<u64> {
+		let res = self.check_heap_layout();
+		if matches!(self.h.heap_layout, HeapLayout::Checking { .. }) {
+			self.h.heap_layout = HeapLayout::Off;
+		}
+		res
+	}
+	/// Memory usage information for this host's guest memory
+	pub fn memory_stats(&mut self) -> MemoryStats {
+		self.b.stats()
+	}
+	/// Give back the host memory behind guest pages that only hold zeroes.  Returns how many bytes were given back.
+	pub fn trim_memory(&mut self) -> usize {
+		self.b.trim()
+	}
+	/// Limit how much guest memory can be allocated at once, or remove the limit with None
+	pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+		self.b.set_memory_limit(limit);
+	}
+	pub fn set_numa_node(&mut self, node: Option<u32>) -> anyhow::Result<()> {
+		self.b.set_numa_node(node)
+	}
+	pub fn numa_usage(&self) -> anyhow::Result<Vec<usize>> {
+		self.b.numa_usage()
+	}
+	/// Number of frames currently available to rewind to
+	pub fn rewind_frame_count(&self) -> usize {
+		match &self.h.rewind {
+			Some(r) => r.len(),
+			None => 0,
 		}
-		self.h.elf.pre_seal(&mut self.b);
-		self.b.seal();
-		self.h.elf.connect_syscalls(&mut self.b, &self.sys);
-		self.h.sealed = true;
+	}
+	/// Start appending a state to the journal at `path` every `interval` frames, and one with all of guest memory every
+	/// `full_interval` of those, instead of only what changed.  The journal is made if it isn't there, and otherwise
+	/// added to, after cutting off an entry a crash left unfinished.
+	pub fn start_journal(&mut self, path: &str, interval: u64, full_interval: u32) -> anyhow::Result<()> {
+		self.check_sealed()?;
+		self.h.journal = Some(Journal::open(path, interval, full_interval)?);
 		Ok(())
 	}
-	pub fn mount_file(&mut self, name: String, data: Vec<u8>, writable: bool) -> anyhow::Result<()> {
-		self.h.fs.mount(name, data, writable)
+	pub fn stop_journal(&mut self) {
+		self.h.journal = None;
 	}
-	pub fn unmount_file(&mut self, name: &str) -> anyhow::Result<Vec<u8>> {
-		self.h.fs.unmount(name)
+	/// The frontend is at `frame`.  Returns whether that was a frame for a journal entry.
+	pub fn journal_frame(&mut self, frame: u64) -> anyhow::Result<bool> {
+		self.check_sealed()?;
+		let mut journal = match self.h.journal.take() {
+			Some(j) => j,
+			None => return Err(coded(ErrorCode::BadState, "No journal is open")),
+		};
+		let due = journal.due(frame);
+		let res = if due {
+			journal.append(frame, |stream, hashes| {
+				let mut head = Vec::new();
+				self.save_state_head(&mut head)?;
+				bin::writeval(stream, head.len() as u64)?;
+				stream.write_all(&head[..])?;
+				self.b.save_journal_pages(stream, hashes)
+			})
+		} else {
+			Ok(())
+		};
+		self.h.journal = Some(journal);
+		res.map(|_| due)
+	}
+	/// Load the state from the last journal entry at or before `frame`, which for picking up after a crash is
+	/// u64::MAX.  Returns the frame it was made at.
+	pub fn load_journal_frame(&mut self, frame: u64) -> anyhow::Result<u64> {
+		self.check_sealed()?;
+		let journal = match self.h.journal.as_mut() {
+			Some(j) => j,
+			None => return Err(coded(ErrorCode::BadState, "No journal is open")),
+		};
+		let index = match journal.find(frame) {
+			Some(i) => i,
+			None => return Err(coded(ErrorCode::NotFound, format!("The journal has nothing at or before frame {}", frame))),
+		};
+		let mut state = Vec::new();
+		journal.rebuild(index, &mut state)?;
+		bin::write_magic(&mut state, SAVE_END_MAGIC)?;
+		let found = journal.entries()[index].frame;
+		self.load_state_raw(&mut &state[..])?;
+		Ok(found)
 	}
 	// pub fn set_missing_file_callback(&mut self, cb: Option<MissingFileCallback>) {
 	// 	self.h.fs.set_missing_file_callback(cb);
 	// }
 }
 
-const SAVE_START_MAGIC: &str = "ActivatedWaterboxHost_v1";
 const SAVE_END_MAGIC: &str = "ʇsoHxoqɹǝʇɐMpǝʇɐʌᴉʇɔ∀";
-impl<'a> IStateable for ActivatedWaterboxHost<'a> {
-	fn save_state(&mut self, stream: &mut dyn Write) -> anyhow::Result<()> {
-		self.check_sealed()?;
-		bin::write_magic(stream, SAVE_START_MAGIC)?;
-		self.h.fs.save_state(stream)?;
-		bin::write(stream, &self.h.program_break)?;
-		self.h.elf.save_state(stream)?;
-		self.b.save_state(stream)?;
-		bin::write_magic(stream, SAVE_END_MAGIC)?;
+impl<'a> ActivatedWaterboxHost<'a> {
+	/// What states made with this host say made them
+	fn identity(&self) -> CoreIdentity {
+		CoreIdentity {
+			name: self.h.core_name.clone(),
+			version: self.h.core_version.clone(),
+			image_hash: self.h.image_hash.clone(),
+			abi: format!("{:?}", self.h.elf.abi()),
+			host_abi: imports::IMPORTS_VERSION,
+			layout: state_format::layout_areas(&self.h.layout),
+			seals: self.h.seals,
+		}
+	}
+	/// Say which version of the core this is, for states to say which made them
+	pub fn set_core_version(&mut self, version: &str) {
+		self.h.core_version = version.to_string();
+	}
+	/// Everything in a state that comes before the MemoryBlock
+	fn save_state_head(&mut self, stream: &mut dyn Write) -> anyhow::Result<()> {
+		self.check_aborted()?;
+		let mut parts = Vec::new();
+		// first, so that states from before it was saved only needed it put in front
+		bin::write(&mut parts, &self.h.elf.fp_env())?;
+		bin::write(&mut parts, &self.h.heap_ops)?;
+		self.h.entropy.save_state(&mut parts)?;
+		self.h.arenas.save_state(&mut parts)?;
+		self.h.fibers.save_state(&mut parts)?;
+		self.h.fs.save_state(&mut parts)?;
+		self.h.threads.save_state(&mut parts)?;
+		self.h.clock.save_state(&mut parts)?;
+		bin::write(&mut parts, &self.h.program_break)?;
+		self.h.elf.save_state(&mut parts)?;
+		let following = parts.len() + self.b.state_header_size();
+		StateHeader::write(stream, &self.identity(), self.h.state_features, following)?;
+		stream.write_all(&parts[..])?;
 		Ok(())
 	}
-	fn load_state(&mut self, stream: &mut dyn Read) -> anyhow::Result<()> {
+	fn save_state_raw(&mut self, stream: &mut dyn Write) -> anyhow::Result<()> {
+		self.save_state_raw_with_progress(stream, None)
+	}
+	/// save_state_raw(), telling `progress` how much of the state is written, if there is one
+	fn save_state_raw_with_progress(&mut self, stream: &mut dyn Write, progress: Option<&mut dyn FnMut(u64, u64)>) -> anyhow::Result<()> {
+		let mut counted = bin::CountingWriter::new(stream);
+		match progress {
+			Some(progress) => {
+				// how much there is to write is only known once the head is made
+				let mut head = Vec::new();
+				self.save_state_head(&mut head)?;
+				let total = head.len() + self.b.state_size() + SAVE_END_MAGIC.len();
+				let mut writer = bin::ProgressWriter::new(&mut counted, bin::Progress::new(total as u64, progress));
+				writer.write_all(&head[..])?;
+				self.b.save_state(&mut writer)?;
+				bin::write_magic(&mut writer, SAVE_END_MAGIC)?;
+				writer.progress.finish();
+			},
+			None => {
+				self.save_state_head(&mut counted)?;
+				self.b.save_state(&mut counted)?;
+				bin::write_magic(&mut counted, SAVE_END_MAGIC)?;
+			},
+		}
+		counters::add_state_bytes(counted.count);
+		Ok(())
+	}
+	/// IStateable::save_state(), with the uncompressed state's progress told to `progress`, if there is one
+	fn save_state_with(&mut self, stream: &mut dyn Write, progress: Option<&mut dyn FnMut(u64, u64)>) -> anyhow::Result<()> {
+		self.check_sealed()?;
+		let span = self.h.profile.begin(profile::ENTRY_SAVE_STATE);
+		let started = self.h.metrics.start();
+		if self.h.delta_states {
+			self.b.clean_unchanged_pages();
+		}
+		let mut counted = bin::CountingWriter::new(stream);
+		let res = if self.h.compress_states {
+			self.save_state_compressed(&mut counted, progress)
+		} else {
+			self.save_state_raw_with_progress(&mut counted, progress)
+		};
+		if res.is_ok() {
+			let bytes = counted.count;
+			self.h.metrics.state_saved(started, bytes);
+		}
+		self.h.profile.end(span);
+		res
+	}
+	/// save_state_raw_with_progress(), through the compressor
+	fn save_state_compressed(&mut self, stream: &mut dyn Write, progress: Option<&mut dyn FnMut(u64, u64)>) -> anyhow::Result<()> {
+		let mut writer = compress::CompressedWriter::new(stream)?;
+		self.save_state_raw_with_progress(&mut writer, progress)?;
+		writer.finish()
+	}
+	/// Capture the current state without copying out all of guest memory.  The result can be written out later,
+	/// on any thread, while this host keeps running.  Has the same restrictions as save_state.
+	pub fn begin_save_state(&mut self) -> anyhow::Result<PendingState> {
+		self.check_sealed()?;
+		if self.h.delta_states {
+			self.b.clean_unchanged_pages();
+		}
+		let mut head = Vec::new();
+		self.save_state_head(&mut head)?;
+		let memory = self.b.cow_snapshot()?;
+		Ok(PendingState {
+			head,
+			memory,
+			compress: self.h.compress_states,
+		})
+	}
+	/// A quick 64 bit hash of everything a savestate would hold, for seeing whether two machines are still in sync
+	/// without making states.  Has the same restrictions as save_state.
+	pub fn state_hash(&mut self) -> anyhow::Result<u64> {
 		self.check_sealed()?;
-		bin::verify_magic(stream, SAVE_START_MAGIC)?;
+		let mut head = Vec::new();
+		self.save_state_head(&mut head)?;
+		head.extend_from_slice(&self.b.state_hash().to_le_bytes());
+		Ok(xxh3::xxh3_64(&head[..]))
+	}
+	/// Capture a state like begin_save_state, and write it to `stream` on a worker thread, which gives the stream back
+	/// to `done` once it's all written.  A Vec<u8> gets the whole state, but the stream can just as well be a file or
+	/// a socket, so that the state is never all in memory at once.
+	pub fn save_state_async<W: Write + Send + 'static>(&mut self, mut stream: W, done: impl FnOnce(anyhow::Result<W>) + Send + 'static) -> anyhow::Result<()> {
+		let state = self.begin_save_state()?;
+		std::thread::Builder::new()
+			.name("waterbox state saver".to_string())
+			.spawn(move || {
+				let res = state.save_state(&mut stream).map(|_| stream);
+				done(res);
+			})?;
+		Ok(())
+	}
+	/// Like save_state, but telling `progress` how many bytes of the uncompressed state are written, out of how many
+	pub fn save_state_with_progress(&mut self, stream: &mut dyn Write, progress: &mut dyn FnMut(u64, u64)) -> anyhow::Result<()> {
+		self.save_state_with(stream, Some(progress))
+	}
+	/// Like load_state, but telling `progress` how many bytes of the state are read, out of the `size` it's stored in
+	pub fn load_state_with_progress(&mut self, stream: &mut dyn Read, size: u64, progress: &mut dyn FnMut(u64, u64)) -> anyhow::Result<()> {
+		let mut reader = bin::ProgressReader::new(stream, bin::Progress::new(size, progress));
+		self.load_state(&mut reader)?;
+		reader.progress.finish();
+		Ok(())
+	}
+	/// Everything in a state that comes before the MemoryBlock, the other way.  Returns the rest of the state.
+	fn load_state_head<'s>(&mut self, stream: &'s mut dyn Read) -> anyhow::Result<Box<dyn Read + 's>> {
+		let header = StateHeader::read(stream)?;
+		header.check(&self.identity(), self.h.state_features)?;
+		let mut body = state_format::migrate(&header, stream)?;
+		self.load_host_parts(&mut *body)?;
+		Ok(body)
+	}
+	/// The host's own part of a state, which comes right after the header
+	fn load_host_parts(&mut self, stream: &mut dyn Read) -> anyhow::Result<()> {
+		self.h.elf.set_fp_env(bin::readval(stream)?);
+		bin::read(stream, &mut self.h.heap_ops)?;
+		self.h.entropy.load_state(stream)?;
+		self.h.arenas.load_state(stream)?;
+		self.h.fibers.load_state(stream)?;
 		self.h.fs.load_state(stream)?;
+		self.h.threads.load_state(stream)?;
+		self.h.clock.load_state(stream)?;
 		bin::read(stream, &mut self.h.program_break)?;
 		self.h.elf.load_state(stream)?;
+		Ok(())
+	}
+	fn load_state_raw(&mut self, stream: &mut dyn Read) -> anyhow::Result<()> {
+		self.check_no_session("Loading a state")?;
+		let mut body = self.load_state_head(stream)?;
+		let stream = &mut *body;
 		self.b.load_state(stream)?;
 		bin::verify_magic(stream, SAVE_END_MAGIC)?;
 		self.h.elf.connect_syscalls(&mut self.b, &self.sys);
+		self.h.aborted = false;
+		self.restart_journal();
+		self.guard_roms()
+	}
+	/// Guest memory was replaced, so the next journal entry is on a new timeline
+	fn restart_journal(&mut self) {
+		if let Some(j) = self.h.journal.as_mut() {
+			j.restart();
+		}
+	}
+	/// Load a state like load_state, from the file at `path`.  Guest memory is mapped from the file where possible, so
+	/// that pages are only copied in once something needs them to be.  The file must not change while this host is
+	/// using it, which can be until another state is loaded.
+	pub fn load_state_file(&mut self, path: &str) -> anyhow::Result<()> {
+		self.check_sealed()?;
+		let span = self.h.profile.begin(profile::ENTRY_LOAD_STATE);
+		let started = self.h.metrics.start();
+		let res = std::fs::File::open(path).map_err(anyhow::Error::from).and_then(|f| self.load_state_file_raw(&f));
+		self.h.metrics.state_loaded(started, res.is_ok());
+		self.h.profile.end(span);
+		res
+	}
+	fn load_state_file_raw(&mut self, file: &std::fs::File) -> anyhow::Result<()> {
+		self.check_no_session("Loading a state")?;
+		let mut buffered = std::io::BufReader::new(file);
+		let compressed = compress::is_compressed(buffered.fill_buf()?);
+		// which counts how much has been read
+		let mut reader = buffered.take(u64::MAX);
+		let header = if compressed { None } else { Some(StateHeader::read(&mut reader)?) };
+		let header = match header {
+			Some(h) if h.version == state_format::VERSION => h,
+			// compressed states, and ones that need migrating, can't be mapped
+			_ => {
+				let mut f = file;
+				f.seek(SeekFrom::Start(0))?;
+				let mut reader = std::io::BufReader::new(file);
+				let mut body = compress::maybe_decompress(&mut reader)?;
+				return self.load_state_raw(&mut *body)
+			},
+		};
+		header.check(&self.identity(), self.h.state_features)?;
+		self.load_host_parts(&mut reader)?;
+		let offset = u64::MAX - reader.limit();
+		drop(reader);
+		let mut f = file;
+		let end = self.b.load_state_file(file, offset)?;
+		f.seek(SeekFrom::Start(end))?;
+		bin::verify_magic(&mut f, SAVE_END_MAGIC)?;
+		self.h.elf.connect_syscalls(&mut self.b, &self.sys);
+		self.h.aborted = false;
+		self.restart_journal();
+		self.guard_roms()
+	}
+	/// Read a state all the way through and check everything loading it would, without touching guest memory, so that a
+	/// frontend can tell which of its states this host can load.  Fails with whatever loading it would have failed with,
+	/// other than a ROM that doesn't match.  Has the same restrictions as load_state.
+	pub fn validate_state(&mut self, stream: &mut dyn Read) -> anyhow::Result<StateCheck> {
+		self.check_sealed()?;
+		let mut current = Vec::new();
+		self.save_state_head(&mut current)?;
+		let res = self.validate_state_raw(stream);
+		// as in compare_states
+		self.load_state_head(&mut &current[..])?;
+		res
+	}
+	fn validate_state_raw(&mut self, stream: &mut dyn Read) -> anyhow::Result<StateCheck> {
+		let mut prefix = vec![0u8; compress::MAGIC.len()];
+		stream.read_exact(&mut prefix[..])?;
+		let compressed = compress::is_compressed(&prefix[..]);
+		let mut whole = std::io::Cursor::new(prefix).chain(stream);
+		let mut body = compress::maybe_decompress(&mut whole)?;
+		// which counts how much has been read
+		let mut reader = (&mut *body).take(u64::MAX);
+		let header = StateHeader::read(&mut reader)?;
+		header.check(&self.identity(), self.h.state_features)?;
+		let mut rest = state_format::migrate(&header, &mut reader)?;
+		self.load_host_parts(&mut *rest)?;
+		let pages = self.b.check_state(&mut *rest)?;
+		bin::verify_magic(&mut *rest, SAVE_END_MAGIC)?;
+		drop(rest);
+		Ok(StateCheck { version: header.version, compressed, size: u64::MAX - reader.limit(), pages })
+	}
+	/// Compare two states for this guest without loading either, and report which pages of guest memory they differ
+	/// on, and how much, along with how many bytes differ in the rest.  Has the same restrictions as load_state.
+	pub fn compare_states(&mut self, a: &mut dyn Read, b: &mut dyn Read) -> anyhow::Result<(StateDiffInfo, Vec<PageDiff>)> {
+		self.check_sealed()?;
+		let mut current = Vec::new();
+		self.save_state_head(&mut current)?;
+		let res = self.compare_states_raw(a, b);
+		// the host's part of each state was loaded to compare them, so put this one's back
+		self.load_state_head(&mut &current[..])?;
+		res
+	}
+	fn compare_states_raw(&mut self, a: &mut dyn Read, b: &mut dyn Read) -> anyhow::Result<(StateDiffInfo, Vec<PageDiff>)> {
+		let mut a = compress::maybe_decompress(a)?;
+		let mut b = compress::maybe_decompress(b)?;
+		// saved again instead of compared as read, so states from before a format change compare with newer ones
+		let mut head_a = Vec::new();
+		let mut body_a = self.load_state_head(&mut *a)?;
+		self.save_state_head(&mut head_a)?;
+		let mut head_b = Vec::new();
+		let mut body_b = self.load_state_head(&mut *b)?;
+		self.save_state_head(&mut head_b)?;
+		let pages = self.b.compare_states(&mut *body_a, &mut *body_b)?;
+		bin::verify_magic(&mut *body_a, SAVE_END_MAGIC)?;
+		bin::verify_magic(&mut *body_b, SAVE_END_MAGIC)?;
+		let host_bytes = memory_block::count_differences(&head_a[..], &head_b[..]);
+		Ok((state_diff::summarize(&self.sys.layout, host_bytes, &pages[..]), pages))
+	}
+}
+impl<'a> IStateable for ActivatedWaterboxHost<'a> {
+	fn save_state(&mut self, stream: &mut dyn Write) -> anyhow::Result<()> {
+		self.save_state_with(stream, None)
+	}
+	fn load_state(&mut self, stream: &mut dyn Read) -> anyhow::Result<()> {
+		self.check_sealed()?;
+		let span = self.h.profile.begin(profile::ENTRY_LOAD_STATE);
+		let started = self.h.metrics.start();
+		let res = compress::maybe_decompress(stream).and_then(|mut reader| self.load_state_raw(&mut *reader));
+		self.h.metrics.state_loaded(started, res.is_ok());
+		self.h.profile.end(span);
+		res
+	}
+}
+
+/// A state captured by begin_save_state() that has not been written out yet
+pub struct PendingState {
+	head: Vec<u8>,
+	memory: CowSnapshot,
+	compress: bool,
+}
+impl PendingState {
+	fn save_state_raw(&mut self, stream: &mut dyn Write) -> anyhow::Result<()> {
+		let mut writer = bin::CountingWriter::new(stream);
+		writer.write_all(&self.head[..])?;
+		self.memory.save_state(&mut writer)?;
+		bin::write_magic(&mut writer, SAVE_END_MAGIC)?;
+		counters::add_state_bytes(writer.count);
 		Ok(())
 	}
+	/// Write out the state, exactly as save_state would have at the time it was captured
+	pub fn save_state(mut self, stream: &mut dyn Write) -> anyhow::Result<()> {
+		if self.compress {
+			let mut writer = compress::CompressedWriter::new(stream)?;
+			self.save_state_raw(&mut writer)?;
+			writer.finish()
+		} else {
+			self.save_state_raw(stream)
+		}
+	}
+}
+
+/// kill(2) and friends, which can only be the guest signalling itself.  Signals are never delivered, but any real one
+/// would end the guest, like the SIGABRT abort() raises, so the call the guest is in is abandoned.  If there isn't one,
+/// this fails, and abort() goes on to crash instead.
+fn raise(sig: usize) -> SyscallResult {
+	if sig == 0 {
+		return Ok(())
+	}
+	log!(Error, "Guest raised signal {}, and aborted", sig);
+	unsafe { watchdog::abandon(watchdog::Abandoned::Aborted) }
+	Err(ENOSYS)
 }
 
 fn unimp(nr: SyscallNumber) -> SyscallResult {
-	eprintln!("Stopped on unimplemented syscall {}", lookup_syscall(&nr));
+	log!(Warn, "Stopped on unimplemented syscall {}", lookup_syscall(&nr));
 	unsafe { std::intrinsics::breakpoint() }
 	Err(ENOSYS)
 }
@@ -164,6 +1734,74 @@ fn gethost<'a>(ud: usize) -> &'a mut ActivatedWaterboxHost<'a> {
 	res
 }
 
+/// Apply the W^X policy to a guest request for `prot` memory at `addr`, made from `rip`
+fn check_wx(h: &ActivatedWaterboxHost, addr: AddressRange, prot: Protection, rip: usize) -> SyscallResult {
+	if h.h.wasm_no_exec && (prot == Protection::RX || prot == Protection::RWX) {
+		return Err(EPERM)
+	}
+	if prot != Protection::RWX || h.h.wx_policy == WxPolicy::Allow {
+		return Ok(())
+	}
+	if let Some((callback, userdata)) = h.h.wx_callback {
+		callback(userdata, addr.start, addr.size, rip);
+	}
+	match h.h.wx_policy {
+		WxPolicy::Deny => Err(EPERM),
+		_ => Ok(()),
+	}
+}
+
+/// ROM regions can't be changed, dropped or mapped over by the guest
+fn check_rom(h: &ActivatedWaterboxHost, addr: AddressRange) -> SyscallResult {
+	if h.h.roms.overlaps(addr) {
+		log!(Warn, "Guest tried to change ROM memory at {:x}:{:x}", addr.start, addr.start.wrapping_add(addr.size));
+		return Err(EPERM)
+	}
+	Ok(())
+}
+
+/// mmap(2) of a file, which is done as anonymous memory with the file's content copied in.  Because writes can't go
+/// back to the file, only private mappings, or shared ones that can't be written to, are supported.
+fn mmap_file(h: &mut ActivatedWaterboxHost, addr: AddressRange, prot: Protection, flags: usize, fd: FileDescriptor,
+	offset: usize, rip: usize) -> Result<usize, SyscallError>
+{
+	match flags & MAP_TYPE {
+		MAP_PRIVATE => (),
+		MAP_SHARED | MAP_SHARED_VALIDATE if prot == Protection::R || prot == Protection::RX => (),
+		_ => return Err(EOPNOTSUPP)
+	}
+	if flags & MAP_STACK != 0 || offset & (PAGESIZE - 1) != 0 || offset > i64::MAX as usize {
+		return Err(EINVAL)
+	}
+	check_wx(h, addr, prot, rip)?;
+	let no_replace = flags & MAP_FIXED_NOREPLACE != 0;
+	let arena_addr = h.sys.layout.mmap;
+	let start = h.b.mmap(addr, Protection::RW, arena_addr, no_replace)?;
+	let dest = AddressRange { start, size: addr.size };
+	let res = (|| {
+		let buf = unsafe { dest.slice_mut() };
+		let mut done = 0;
+		while done < buf.len() {
+			// anything past the end of the file stays zero
+			let n = match h.h.fs.pread(fd, &mut buf[done..], (offset + done) as i64) {
+				Ok(0) => break,
+				Ok(n) => n as usize,
+				Err(ESPIPE) => return Err(ENODEV),
+				Err(e) => return Err(e),
+			};
+			done += n;
+		}
+		if prot != Protection::RW {
+			h.b.mprotect(dest, prot)?;
+		}
+		Ok(start)
+	})();
+	if res.is_err() {
+		h.b.munmap(dest).unwrap();
+	}
+	res
+}
+
 fn arg_to_prot(arg: usize) -> Result<Protection, SyscallError> {
 	use Protection::*;
 	if arg != arg & (PROT_READ | PROT_WRITE | PROT_EXEC) {
@@ -199,26 +1837,270 @@ fn arg_to_str(arg: usize) -> Result<String, SyscallError> {
 	}
 }
 
-fn arg_to_statbuff<'a>(arg: usize) -> &'a mut KStat {
-	unsafe { &mut *(arg as *mut KStat) }
+/// Run `f` on a stat buffer, and copy what it filled in out to the guest's at `arg` if it succeeds
+fn with_statbuff(arg: usize, f: impl FnOnce(&mut KStat) -> SyscallResult) -> SyscallResult {
+	let mut statbuff = KStat::default();
+	f(&mut statbuff)?;
+	unsafe { write_guest(arg, statbuff); }
+	Ok(())
+}
+
+/// The `len` bytes at `addr` in guest memory, where `addr` can be anything if there are none
+unsafe fn guest_slice<'a>(addr: usize, len: usize) -> &'a mut [u8] {
+	if len == 0 {
+		&mut []
+	} else {
+		std::slice::from_raw_parts_mut(addr as *mut u8, len)
+	}
+}
+
+/// Store `value` at `addr` in guest memory, which the guest doesn't have to have aligned
+unsafe fn write_guest<T>(addr: usize, value: T) {
+	std::ptr::write_unaligned(addr as *mut T, value)
+}
+
+/// What poll(2) and friends return when `n` things were ready.  Nothing can become ready while the guest waits, so it
+/// never does:  a timeout runs out right away, and waiting forever is interrupted so that the guest tries again.
+fn poll_result(n: usize, wait: bool, timed: bool) -> SyscallReturn {
+	if n == 0 && wait && !timed {
+		syscall_err(EINTR)
+	} else {
+		syscall_ok(n)
+	}
+}
+
+/// The name for a *at() syscall's `dirfd` and `path`.  There are no directories to open, so `dirfd` only matters for a
+/// relative path, which it has to be AT_FDCWD for.
+fn name_at(dirfd: usize, path: usize) -> Result<String, SyscallError> {
+	let name = arg_to_str(path)?;
+	if !name.starts_with('/') && dirfd as i32 != AT_FDCWD {
+		return Err(ENOTDIR)
+	}
+	Ok(name)
+}
+
+/// The lookup for fstatat(2) and statx(2).  There are no directories to open, so `dirfd` only matters with
+/// AT_EMPTY_PATH, or if it isn't AT_FDCWD for a relative path.
+fn stat_at(h: &mut ActivatedWaterboxHost, dirfd: usize, path: usize, flags: usize, statbuff: &mut KStat) -> SyscallResult {
+	if flags & !(AT_SYMLINK_NOFOLLOW | AT_NO_AUTOMOUNT | AT_EMPTY_PATH | AT_STATX_SYNC_TYPE) != 0 {
+		return Err(EINVAL)
+	}
+	let name = if path == 0 { String::new() } else { arg_to_str(path)? };
+	if name.is_empty() {
+		if flags & AT_EMPTY_PATH == 0 {
+			return Err(ENOENT)
+		}
+		return h.h.fs.fstat(arg_to_fd(dirfd)?, statbuff)
+	}
+	if !name.starts_with('/') && dirfd as i32 != AT_FDCWD {
+		return Err(ENOTDIR)
+	}
+	h.h.fs.stat(&name, statbuff)
+}
+
+guest_abi! { pub fn syscall(nr: SyscallNumber, ud: usize, a1: usize, a2: usize, a3: usize, a4: usize, a5: usize, a6: usize) -> SyscallReturn {
+	let mut fs = threading::HostFs::enter();
+	// callbacks the syscall makes can change it, or call into the guest again
+	gethost(ud).h.elf.set_fp_env(FpEnv::get());
+	if gdbstub::stop_requested() {
+		// the debugger picks this up in the trap handler
+		std::intrinsics::breakpoint();
+	}
+	unsafe { watchdog::check() }
+	let rip = std::intrinsics::return_address() as usize;
+	let mut args = [a1, a2, a3, a4, a5, a6];
+	let started = if gethost(ud).h.profile.enabled() { Some(Instant::now()) } else { None };
+	let old_brk = gethost(ud).h.program_break;
+	let abi = gethost(ud).h.elf.abi();
+	let nr = abi.syscall_number(nr.0);
+	abi.syscall_args(&nr, &mut args);
+	let ret = dispatch_syscall(SyscallNumber(nr.0), ud, &args, rip);
+	let h = gethost(ud);
+	if let Some(t) = started {
+		h.h.profile.syscall(t.elapsed());
+	}
+	if h.h.metrics.enabled() {
+		h.h.metrics.syscall(&nr, &ret);
+	}
+	if h.h.heap_profile.enabled() && ret.0 <= SyscallReturn::ERROR_THRESH {
+		profile_heap(h, &nr, &args, ret.0, old_brk);
+	}
+	if heap_layout::is_memory_call(&nr) {
+		h.h.heap_layout.observe(h.h.heap_ops, &nr, &args, ret.0);
+		h.h.heap_ops += 1;
+	}
+	if let Some((callback, userdata)) = h.h.syscall_trace {
+		let text = CString::new(trace::describe_syscall(&nr, &args, &ret)).unwrap_or_default();
+		callback(userdata, nr.0, args.as_ptr(), ret.0, text.as_ptr());
+	}
+	// a fiber switch changes where the current thread goes on from, whether it's the one that runs next or not
+	let next = match h.h.fibers.take_switch() {
+		Some(ctx) => h.h.threads.reschedule(&ctx, 0).or(Some(ctx)),
+		None => h.h.threads.reschedule(&h.entry.ctx, ret.0),
+	};
+	h.h.elf.fp_env().set();
+	fs.set_guest(h.h.threads.tls());
+	drop(fs);
+	if let Some(ctx) = next {
+		// nothing on this stack needs cleaning up; the current thread, if it's still alive, will resume from its ctx
+		unsafe { threading::switch_to(&ctx) }
+	}
+	ret
+}}
+
+/// The iovecs of a readv(2) or writev(2) and friends, once they and the buffers they point to are checked
+fn guest_iovecs(h: &ActivatedWaterboxHost, addr: usize, count: usize, write: bool) -> Result<Vec<Iovec>, SyscallError> {
+	if count > IOV_MAX {
+		return Err(EINVAL)
+	}
+	let abi = h.h.elf.abi();
+	if count > 0 && !h.b.guest_accessible(addr, count * abi.pointer_size() * 2, false) {
+		return Err(EFAULT)
+	}
+	let iovecs = unsafe { abi.iovecs(addr, count) };
+	for io in iovecs.iter() {
+		if io.iov_len > 0 && !h.b.guest_accessible(io.iov_base, io.iov_len, write) {
+			return Err(EFAULT)
+		}
+	}
+	// an empty one can point anywhere, even at null, which a slice can't
+	Ok(iovecs.into_iter().filter(|io| io.iov_len > 0).collect())
+}
+
+/// Where one of readv(2), preadv(2) or preadv2(2), or their write versions, goes in the file, given its offset and
+/// flags arguments.  None is wherever the file is now.
+fn vectored_offset(nr: &SyscallNumber, offset: usize, flags: usize) -> Result<Option<i64>, SyscallError> {
+	match *nr {
+		NR_PREADV | NR_PWRITEV => Ok(Some(offset as i64)),
+		NR_PREADV2 | NR_PWRITEV2 if flags != 0 => Err(EOPNOTSUPP),
+		NR_PREADV2 | NR_PWRITEV2 if offset as i64 == -1 => Ok(None),
+		NR_PREADV2 | NR_PWRITEV2 => Ok(Some(offset as i64)),
+		_ => Ok(None),
+	}
+}
+
+/// The guest yielded, or slept for `ns`.  Its clock only moves when the frontend says, so it wakes right away, but the
+/// frontend can be told so that a core spinning on sched_yield() or short sleeps doesn't have to spin a host CPU too.
+fn guest_yield(h: &mut ActivatedWaterboxHost, ns: u64) -> SyscallReturn {
+	if let Some((callback, userdata)) = h.h.yield_callback {
+		callback(userdata, ns, h.h.threads.others_can_run());
+	}
+	syscall_ok(0)
+}
+
+/// The program break of the ActivatedWaterboxHost `ud`
+pub fn program_break(ud: usize) -> usize {
+	gethost(ud).h.program_break
 }
 
-pub extern "sysv64" fn syscall(nr: SyscallNumber, ud: usize, a1: usize, a2: usize, a3: usize, a4: usize, _a5: usize, _a6: usize) -> SyscallReturn {
+/// Tell the heap profiler about a memory syscall that succeeded with `ret`
+fn profile_heap(h: &mut ActivatedWaterboxHost, nr: &SyscallNumber, args: &[usize; 6], ret: usize, old_brk: usize) {
+	let all = h.sys.layout.all();
+	let ctx = &h.entry.ctx;
+	let frames = || std::iter::once(ctx.pc()).chain(crash::backtrace(ctx.frame_pointer(), |addr| all.contains(addr))).collect::<Vec<_>>();
+	let p = &mut h.h.heap_profile;
+	match *nr {
+		NR_MMAP => p.allocate(AddressRange { start: ret, size: align_up(args[1]) }, &frames()),
+		NR_MREMAP => {
+			p.free(AddressRange { start: args[0], size: align_up(args[1]) });
+			p.allocate(AddressRange { start: ret, size: align_up(args[2]) }, &frames());
+		},
+		NR_MUNMAP => p.free(AddressRange { start: args[0], size: align_up(args[1]) }),
+		NR_BRK if ret > old_brk => p.allocate(AddressRange { start: old_brk, size: ret - old_brk }, &frames()),
+		NR_BRK if ret < old_brk => p.free(AddressRange { start: ret, size: old_brk - ret }),
+		_ => (),
+	}
+}
+
+/// Guest memory a syscall touches, by argument index, so that bad pointers fail with EFAULT instead of taking the host
+/// down.  Syscalls that aren't listed aren't checked.
+#[derive(Clone, Copy)]
+enum Buffer {
+	/// args[.0] points to args[.1] bytes, which are written if .2, or otherwise read
+	Sized(usize, usize, bool),
+	/// args[.0] points to .1 bytes that are written, unless it's null and .2 allows that
+	Fixed(usize, usize, bool),
+	/// args[.0] points to a nul terminated string
+	Str(usize),
+	/// args[.0] points to .1 bytes that are read, unless it's null and .2 allows that
+	In(usize, usize, bool),
+}
+
+fn checked_buffers(nr: &SyscallNumber) -> &'static [Buffer] {
+	use Buffer::*;
+	const KSTAT: usize = std::mem::size_of::<KStat>();
+	const TIMESPEC: usize = std::mem::size_of::<TimeSpec>();
+	match *nr {
+		NR_READ | NR_PREAD64 => &[Sized(1, 2, true)],
+		NR_WRITE | NR_PWRITE64 | NR_SENDTO | NR_CONNECT => &[Sized(1, 2, false)],
+		NR_RECVFROM => &[Sized(1, 2, true), Fixed(5, 4, true)],
+		NR_OPEN | NR_TRUNCATE | NR_UNLINK | NR_WBX_REGISTER_MEMORY_DOMAIN | NR_WBX_CREATE_ARENA | NR_WBX_DESTROY_ARENA
+			| NR_WBX_SET_DOMAIN_TRANSLATOR => &[Str(0)],
+		NR_UNLINKAT | NR_OPENAT => &[Str(1)],
+		NR_RENAME => &[Str(0), Str(1)],
+		NR_RENAMEAT | NR_RENAMEAT2 => &[Str(1), Str(3)],
+		NR_STAT | NR_LSTAT => &[Str(0), Fixed(1, KSTAT, false)],
+		NR_FSTAT => &[Fixed(1, KSTAT, false)],
+		NR_CLOCK_GETTIME => &[Fixed(1, TIMESPEC, false)],
+		NR_CLOCK_GETRES => &[Fixed(1, TIMESPEC, true)],
+		NR_GETTIMEOFDAY => &[Fixed(0, std::mem::size_of::<TimeVal>(), true), Fixed(1, 8, true)],
+		NR_TIME => &[Fixed(0, 8, true)],
+		NR_CLOCK_SETTIME => &[In(1, TIMESPEC, false)],
+		NR_NANOSLEEP => &[In(0, TIMESPEC, false)],
+		NR_CLOCK_NANOSLEEP => &[In(2, TIMESPEC, false)],
+		NR_GETRUSAGE => &[Fixed(1, std::mem::size_of::<RUsage>(), false)],
+		NR_SYSINFO => &[Fixed(0, std::mem::size_of::<SysInfo>(), false)],
+		NR_SCHED_GETAFFINITY => &[Sized(2, 1, true)],
+		NR_SCHED_SETAFFINITY => &[Sized(2, 1, false)],
+		NR_SETTIMEOFDAY => &[In(0, std::mem::size_of::<TimeVal>(), true)],
+		NR_RT_SIGPROCMASK => &[Fixed(2, 8, true)],
+		NR_GETRANDOM | NR_WBX_ENTROPY => &[Sized(0, 1, true)],
+		NR_WBX_GET_CONFIG => &[Str(0), Sized(1, 2, true)],
+		NR_WBX_TRANSFER => &[Str(0), Sized(1, 2, false)],
+		_ => &[],
+	}
+}
+
+fn check_buffers(h: &ActivatedWaterboxHost, nr: &SyscallNumber, args: &[usize; 6]) -> SyscallResult {
+	for &b in checked_buffers(nr).iter() {
+		let ok = match b {
+			Buffer::Sized(p, n, write) => h.b.guest_accessible(args[p], args[n], write),
+			Buffer::Fixed(p, _, true) if args[p] == 0 => true,
+			Buffer::Fixed(p, size, _) => h.b.guest_accessible(args[p], size, true),
+			Buffer::Str(p) => h.b.guest_str_accessible(args[p]),
+			Buffer::In(p, _, true) if args[p] == 0 => true,
+			Buffer::In(p, size, _) => h.b.guest_accessible(args[p], size, false),
+		};
+		if !ok {
+			return Err(EFAULT)
+		}
+	}
+	Ok(())
+}
+
+/// `rip` is the guest code that made the syscall
+fn dispatch_syscall(nr: SyscallNumber, ud: usize, args: &[usize; 6], rip: usize) -> SyscallReturn {
+	let [a1, a2, a3, a4, a5, a6] = *args;
 	let h = gethost(ud);
+	if let Some(ret) = h.h.syscall_policy.apply(&nr, args) {
+		return ret
+	}
+	check_buffers(h, &nr, args)?;
 	match nr {
 		NR_MMAP => {
 			let mut prot = arg_to_prot(a3)?;
 			let flags = a4;
-			if flags & MAP_ANONYMOUS == 0 {
-				// anonymous + private is easy
-				// anonymous by itself is hard
-				// nothing needs either right now
-				return syscall_err(EOPNOTSUPP)
-			}
 			if flags & 0xf00 != 0 {
 				// various unsupported flags
 				return syscall_err(EOPNOTSUPP)
 			}
+			if flags & (MAP_FIXED | MAP_FIXED_NOREPLACE) != 0 {
+				check_rom(h, AddressRange { start: a1, size: a2 })?;
+			}
+			if flags & MAP_ANONYMOUS == 0 {
+				let res = mmap_file(h, AddressRange { start: a1, size: a2 }, prot, flags, arg_to_fd(a5)?, a6, rip)?;
+				return syscall_ok(res)
+			}
 			if flags & MAP_STACK != 0 {
 				if prot == Protection::RW {
 					prot = Protection::RWStack;
@@ -226,91 +2108,321 @@ pub extern "sysv64" fn syscall(nr: SyscallNumber, ud: usize, a1: usize, a2: usiz
 					return syscall_err(EINVAL) // stacks must be readable and writable
 				}
 			}
+			check_wx(h, AddressRange { start: a1, size: a2 }, prot, rip)?;
 			let no_replace = flags & MAP_FIXED_NOREPLACE != 0;
 			let arena_addr = h.sys.layout.mmap;
 			let res = h.b.mmap(AddressRange { start: a1, size: a2 }, prot, arena_addr, no_replace)?;
+			h.b.scribble_new(AddressRange { start: res, size: align_up(a2) });
 			syscall_ok(res)
 		},
 		NR_MREMAP => {
+			check_rom(h, AddressRange { start: a1, size: std::cmp::max(a2, a3) })?;
+			if a4 & MREMAP_FIXED != 0 {
+				check_rom(h, AddressRange { start: a5, size: a3 })?;
+			}
 			let arena_addr = h.sys.layout.mmap;
-			let res = h.b.mremap(AddressRange { start: a1, size: a2 }, a3, arena_addr)?;
+			let res = h.b.mremap(AddressRange { start: a1, size: a2 }, a3, a4, arena_addr)?;
+			if align_up(a3) > align_up(a2) {
+				h.b.scribble_new(AddressRange { start: res + align_up(a2), size: align_up(a3) - align_up(a2) });
+			}
 			syscall_ok(res)
 		},
 		NR_MPROTECT => {
 			let prot = arg_to_prot(a3)?;
+			check_wx(h, AddressRange { start: a1, size: a2 }, prot, rip)?;
+			check_rom(h, AddressRange { start: a1, size: a2 })?;
 			let res = h.b.mprotect(AddressRange { start: a1, size: a2 }, prot);
 			syscall_ret(res)
 		},
-		NR_MUNMAP => syscall_ret(h.b.munmap(AddressRange { start: a1, size: a2 })),
+		NR_MUNMAP => {
+			check_rom(h, AddressRange { start: a1, size: a2 })?;
+			syscall_ret(h.b.munmap(AddressRange { start: a1, size: a2 }))
+		},
 		NR_MADVISE => {
-			match a3 {
-				MADV_DONTNEED => syscall_ret(h.b.madvise_dontneed(AddressRange { start: a1, size: a2 })),
-				_ => syscall_ok(0),
+			if matches!(a3, MADV_DONTNEED | MADV_FREE | MADV_REMOVE) {
+				check_rom(h, AddressRange { start: a1, size: a2 })?;
 			}
+			syscall_ret(h.b.madvise(AddressRange { start: a1, size: a2 }, a3))
 		},
-		NR_STAT => {
+		NR_STAT | NR_LSTAT => {
 			let name = arg_to_str(a1)?;
-			syscall_ret(h.h.fs.stat(&name, arg_to_statbuff(a2)))
+			syscall_ret(with_statbuff(a2, |s| h.h.fs.stat(&name, s)))
 		},
 		NR_FSTAT => {
-			syscall_ret(h.h.fs.fstat(arg_to_fd(a1)?, arg_to_statbuff(a2)))
+			syscall_ret(with_statbuff(a2, |s| h.h.fs.fstat(arg_to_fd(a1)?, s)))
+		},
+		NR_NEWFSTATAT => syscall_ret(with_statbuff(a3, |s| stat_at(h, a1, a2, a4, s))),
+		NR_STATX => {
+			let mut statbuff = KStat::default();
+			stat_at(h, a1, a2, a3, &mut statbuff)?;
+			fs::fill_statx(&statbuff, unsafe { &mut *(a5 as *mut Statx) });
+			syscall_ok(0)
 		},
 		NR_IOCTL => syscall_ok(0),
 		NR_READ => {
 			unsafe {
-				syscall_ret_i64(h.h.fs.read(arg_to_fd(a1)?, std::slice::from_raw_parts_mut(a2 as *mut u8, a3)))
+				syscall_ret_i64(h.h.fs.read(arg_to_fd(a1)?, guest_slice(a2, a3)))
 			}
 		},
 		NR_WRITE => {
 			unsafe {
-				syscall_ret_i64(h.h.fs.write(arg_to_fd(a1)?, std::slice::from_raw_parts(a2 as *const u8, a3)))
+				syscall_ret_i64(h.h.fs.write(arg_to_fd(a1)?, guest_slice(a2, a3)))
 			}
 		},
-		NR_READV => {
-			let fd = arg_to_fd(a1)?;
+		NR_PREAD64 => {
 			unsafe {
-				let mut ret = 0;
-				let iov = std::slice::from_raw_parts_mut(a2 as *mut Iovec, a3);
-				for io in iov {
-					if io.iov_base != 0 {
-						ret += h.h.fs.read(fd, io.slice_mut())?;
-					}
-				}
-				syscall_ok(ret as usize)
+				syscall_ret_i64(h.h.fs.pread(arg_to_fd(a1)?, guest_slice(a2, a3), a4 as i64))
 			}
 		},
-		NR_WRITEV => {
-			let fd = arg_to_fd(a1)?;
+		NR_PWRITE64 => {
 			unsafe {
-				let mut ret = 0;
-				let iov = std::slice::from_raw_parts(a2 as *const Iovec, a3);
-				for io in iov {
-					if io.iov_base != 0 {
-						ret += h.h.fs.write(fd, io.slice())?;
-					}
-				}
-				syscall_ok(ret as usize)
+				syscall_ret_i64(h.h.fs.pwrite(arg_to_fd(a1)?, guest_slice(a2, a3), a4 as i64))
 			}
 		},
+		NR_READV | NR_PREADV | NR_PREADV2 => {
+			let offset = vectored_offset(&nr, a4, a6)?;
+			let iovecs = guest_iovecs(h, a2, a3, true)?;
+			let mut bufs = iovecs.iter().map(|io| unsafe { io.slice_mut() }).collect::<Vec<_>>();
+			syscall_ret_i64(h.h.fs.readv(arg_to_fd(a1)?, &mut bufs[..], offset))
+		},
+		NR_WRITEV | NR_PWRITEV | NR_PWRITEV2 => {
+			let offset = vectored_offset(&nr, a4, a6)?;
+			let iovecs = guest_iovecs(h, a2, a3, false)?;
+			let bufs = iovecs.iter().map(|io| unsafe { io.slice() }).collect::<Vec<_>>();
+			syscall_ret_i64(h.h.fs.writev(arg_to_fd(a1)?, &bufs[..], offset))
+		},
 		NR_OPEN => {
 			syscall_ret_val(h.h.fs.open(&arg_to_str(a1)?, a2 as i32, a3 as i32).map(|x| x.0 as usize))
 		},
+		NR_OPENAT => {
+			syscall_ret_val(h.h.fs.open(&name_at(a1, a2)?, a3 as i32, a4 as i32).map(|x| x.0 as usize))
+		},
 		NR_CLOSE => syscall_ret(h.h.fs.close(arg_to_fd(a1)?)),
+		NR_DUP => syscall_ret_val(h.h.fs.dup(arg_to_fd(a1)?, 0, false).map(|fd| fd.0 as usize)),
+		NR_DUP2 if a1 == a2 => {
+			// only checks that it's open
+			syscall_ret_val(h.h.fs.fcntl(arg_to_fd(a1)?, F_GETFD, 0).map(|_| a1))
+		},
+		NR_DUP2 | NR_DUP3 => {
+			if nr == NR_DUP3 && (a3 as i32 & !O_CLOEXEC != 0 || a1 == a2) {
+				return syscall_err(EINVAL)
+			}
+			let new = arg_to_fd(a2).map_err(|_| EBADF)?;
+			syscall_ret_val(h.h.fs.dup3(arg_to_fd(a1)?, new, nr == NR_DUP3 && a3 as i32 & O_CLOEXEC != 0).map(|fd| fd.0 as usize))
+		},
+		NR_FCNTL => syscall_ret_val(h.h.fs.fcntl(arg_to_fd(a1)?, a2 as i32, a3).map(|x| x as usize)),
 		NR_LSEEK => syscall_ret_i64(h.h.fs.seek(arg_to_fd(a1)?, a2 as i64, a3 as i32)),
 		NR_TRUNCATE => syscall_ret(h.h.fs.truncate(&arg_to_str(a1)?, a2 as i64)),
+		NR_UNLINK => syscall_ret(h.h.fs.unlink(&arg_to_str(a1)?)),
+		NR_UNLINKAT => {
+			if a3 != 0 {
+				return syscall_err(EINVAL)
+			}
+			syscall_ret(h.h.fs.unlink(&name_at(a1, a2)?))
+		},
+		NR_RENAME => syscall_ret(h.h.fs.rename(&arg_to_str(a1)?, &arg_to_str(a2)?, false)),
+		NR_RENAMEAT | NR_RENAMEAT2 => {
+			let flags = if nr == NR_RENAMEAT2 { a5 } else { 0 };
+			if flags & !RENAME_NOREPLACE != 0 {
+				return syscall_err(EINVAL)
+			}
+			syscall_ret(h.h.fs.rename(&name_at(a1, a2)?, &name_at(a3, a4)?, flags != 0))
+		},
 		NR_FTRUNCATE => syscall_ret(h.h.fs.ftruncate(arg_to_fd(a1)?, a2 as i64)),
 		// TODO: 99% sure nothing calls this
 		NR_SET_THREAD_AREA => syscall_err(ENOSYS),
-		// TODO: What calls this?
-		NR_SET_TID_ADDRESS => syscall_ok(8675309),
+		NR_ARCH_PRCTL => {
+			match a1 {
+				ARCH_SET_FS => {
+					h.h.threads.set_tls(a2);
+					syscall_ok(0)
+				},
+				ARCH_GET_FS => {
+					unsafe { h.h.elf.abi().write_pointer(a2, h.h.threads.tls()); }
+					syscall_ok(0)
+				},
+				_ => syscall_err(EINVAL),
+			}
+		},
+		NR_SET_TID_ADDRESS => syscall_ok(h.h.threads.set_tid_address(a1)),
+		NR_GETTID => syscall_ok(h.h.threads.current() as usize),
+		// the interpreter only has the one thread
+		NR_CLONE if h.h.elf.abi() == GuestAbi::Wasm32 => syscall_err(ENOSYS),
+		NR_CLONE => syscall_ret_val(unsafe { h.h.threads.clone(&h.entry.ctx, a1, a2, a3, a4, a5) }),
+		// the main thread exiting would be the end of the guest, which is up to the host
+		NR_EXIT if h.h.threads.current() != MAIN_TID => {
+			h.h.fibers.thread_exited(h.h.threads.current());
+			syscall_ret(unsafe { h.h.threads.exit() })
+		},
+		// any other thread that's runnable gets a turn first
+		NR_SCHED_YIELD => guest_yield(h, 0),
+		NR_NANOSLEEP => {
+			let time = unsafe { &*(a1 as *const TimeSpec) };
+			let ns = h.h.clock.sleep_ns(1, false, time)?;
+			guest_yield(h, ns)
+		},
+		NR_CLOCK_NANOSLEEP => {
+			let time = unsafe { &*(a3 as *const TimeSpec) };
+			let ns = h.h.clock.sleep_ns(a1, a2 & TIMER_ABSTIME != 0, time)?;
+			guest_yield(h, ns)
+		},
+		NR_SCHED_GETAFFINITY => {
+			let len = guest_affinity_len(a2)?;
+			unsafe { write_guest(a3, (1u64 << GUEST_CPUS) - 1); }
+			syscall_ok(len)
+		},
+		NR_SCHED_SETAFFINITY => {
+			// anything with the one CPU in it
+			if a2 == 0 || unsafe { *(a3 as *const u8) } & 1 == 0 {
+				return syscall_err(EINVAL)
+			}
+			syscall_ok(0)
+		},
+		NR_GETRUSAGE => {
+			let usage = guest_rusage(a1, &h.h.clock.now(1)?)?;
+			unsafe { write_guest(a2, usage); }
+			syscall_ok(0)
+		},
+		NR_SYSINFO => {
+			let layout = &h.sys.layout;
+			let info = guest_sysinfo(layout.all().size, layout.sbrk.size + layout.mmap.size, h.h.clock.now(1)?.tv_sec);
+			unsafe { write_guest(a1, info); }
+			syscall_ok(0)
+		},
+		NR_RT_SIGPROCMASK => {
+			if a4 != 8 {
+				return syscall_err(EINVAL)
+			}
+			// no signal is ever delivered, so none is ever blocked either
+			if a3 != 0 {
+				unsafe { write_guest(a3, 0u64); }
+			}
+			syscall_ok(0)
+		},
+		NR_KILL | NR_TKILL => syscall_ret(raise(a2)),
+		NR_TGKILL => syscall_ret(raise(a3)),
 		NR_CLOCK_GETTIME => {
-			let ts = a2 as *mut TimeSpec;
+			let now = h.h.clock.now(a1)?;
+			unsafe { write_guest(a2, now); }
+			syscall_ok(0)
+		},
+		NR_CLOCK_GETRES => {
+			h.h.clock.now(a1)?;
+			if a2 != 0 {
+				unsafe { write_guest(a2, TimeSpec { tv_sec: 0, tv_nsec: 1 }); }
+			}
+			syscall_ok(0)
+		},
+		NR_GETTIMEOFDAY => {
+			let now = h.h.clock.now(0)?;
+			if a1 != 0 {
+				unsafe { write_guest(a1, TimeVal { tv_sec: now.tv_sec, tv_usec: now.tv_nsec / 1000 }); }
+			}
+			if a2 != 0 {
+				// struct timezone, which is always UTC
+				unsafe { write_guest(a2, [0i32, 0]); }
+			}
+			syscall_ok(0)
+		},
+		NR_TIME => {
+			let now = h.h.clock.now(0)?;
+			if a1 != 0 {
+				unsafe { write_guest(a1, now.tv_sec); }
+			}
+			syscall_ok(now.tv_sec as usize)
+		},
+		NR_CLOCK_SETTIME => {
+			let time = unsafe { &*(a2 as *const TimeSpec) };
+			h.h.clock.set(a1, TimeSpec { tv_sec: time.tv_sec, tv_nsec: time.tv_nsec })?;
+			syscall_ok(0)
+		},
+		NR_SETTIMEOFDAY => {
+			// the timezone is ignored, as it's always UTC
+			if a1 != 0 {
+				let time = unsafe { &*(a1 as *const TimeVal) };
+				if time.tv_usec < 0 || time.tv_usec >= 1000000 {
+					return syscall_err(EINVAL)
+				}
+				h.h.clock.set(0, TimeSpec { tv_sec: time.tv_sec, tv_nsec: time.tv_usec * 1000 })?;
+			}
+			syscall_ok(0)
+		},
+		NR_SOCKET => {
+			if a1 > u16::MAX as usize {
+				return syscall_err(EAFNOSUPPORT)
+			}
+			syscall_ret_val(h.h.fs.socket(a1 as u16, a2).map(|fd| fd.0 as usize))
+		},
+		NR_CONNECT => {
+			let addr = fs::format_sockaddr(unsafe { guest_slice(a2, a3) })?;
+			syscall_ret(h.h.fs.connect(arg_to_fd(a1)?, &addr))
+		},
+		NR_SENDTO => {
+			// the destination only matters for sockets that aren't connected, which can't send anyway
+			unsafe {
+				syscall_ret_i64(h.h.fs.write(arg_to_fd(a1)?, guest_slice(a2, a3)))
+			}
+		},
+		NR_RECVFROM => {
+			if a6 != 0 {
+				// there's no address to tell about
+				unsafe { *(a6 as *mut u32) = 0; }
+			}
+			unsafe {
+				syscall_ret_i64(h.h.fs.read(arg_to_fd(a1)?, guest_slice(a2, a3)))
+			}
+		},
+		NR_SHUTDOWN | NR_SETSOCKOPT => syscall_ok(0),
+		NR_GETSOCKOPT => {
+			if a2 != SOL_SOCKET || a3 != SO_ERROR {
+				return syscall_err(ENOPROTOOPT)
+			}
 			unsafe {
-				(*ts).tv_sec = 1495889068;
-				(*ts).tv_nsec = 0;
+				*(a4 as *mut i32) = 0;
+				*(a5 as *mut u32) = 4;
 			}
 			syscall_ok(0)
 		},
+		NR_BIND | NR_LISTEN | NR_ACCEPT | NR_ACCEPT4 => syscall_err(EOPNOTSUPP),
+		NR_POLL => {
+			let fds = unsafe { std::slice::from_raw_parts_mut(a1 as *mut PollFd, a2) };
+			poll_result(h.h.fs.poll(fds), a3 as i32 != 0, a3 as i32 > 0)
+		},
+		NR_PPOLL => {
+			let fds = unsafe { std::slice::from_raw_parts_mut(a1 as *mut PollFd, a2) };
+			// a null timeout waits forever
+			let wait = a3 == 0 || {
+				let ts = unsafe { &*(a3 as *const TimeSpec) };
+				ts.tv_sec != 0 || ts.tv_nsec != 0
+			};
+			poll_result(h.h.fs.poll(fds), wait, a3 != 0)
+		},
+		NR_EPOLL_CREATE | NR_EPOLL_CREATE1 => {
+			if nr == NR_EPOLL_CREATE && a1 as i32 <= 0 || nr == NR_EPOLL_CREATE1 && a1 & !EPOLL_CLOEXEC != 0 {
+				return syscall_err(EINVAL)
+			}
+			syscall_ret_val(h.h.fs.epoll_create(nr == NR_EPOLL_CREATE1 && a1 & EPOLL_CLOEXEC != 0).map(|fd| fd.0 as usize))
+		},
+		NR_EPOLL_CTL => {
+			let event = if a4 == 0 { None } else { Some(unsafe { *(a4 as *const EpollEvent) }) };
+			syscall_ret(h.h.fs.epoll_ctl(arg_to_fd(a1)?, a2 as i32, arg_to_fd(a3)?, event))
+		},
+		NR_EPOLL_WAIT | NR_EPOLL_PWAIT => {
+			if a3 as i32 <= 0 {
+				return syscall_err(EINVAL)
+			}
+			let events = unsafe { std::slice::from_raw_parts_mut(a2 as *mut EpollEvent, a3 as i32 as usize) };
+			let n = h.h.fs.epoll_wait(arg_to_fd(a1)?, events)?;
+			poll_result(n, a4 as i32 != 0, a4 as i32 > 0)
+		},
+		NR_FUTEX => syscall_ret_val(unsafe { h.h.threads.futex(a1, a2, a3, a4, a5, a6) }),
+		NR_GETRANDOM => {
+			if a3 & !(GRND_NONBLOCK | GRND_RANDOM | GRND_INSECURE) != 0 {
+				return syscall_err(EINVAL)
+			}
+			unsafe { h.h.fs.getrandom(guest_slice(a1, a2)); }
+			syscall_ok(a2)
+		},
 		NR_BRK => {
 			// TODO: This could be done on the C side
 			let addr = h.sys.layout.sbrk;
@@ -319,15 +2431,31 @@ pub extern "sysv64" fn syscall(nr: SyscallNumber, ud: usize, a1: usize, a2: usiz
 				old
 			} else if a1 < addr.start {
 				if a1 == 0 {
-					println!("Initializing heap sbrk at {:x}:{:x}", addr.start, addr.end());
+					log!(Debug, "Initializing heap sbrk at {:x}:{:x}", addr.start, addr.end());
 				}
 				old
 			} else if a1 > addr.end() {
-				eprintln!("Failed to satisfy allocation of {} bytes on sbrk heap", a1 - old);
+				log!(Warn, "Failed to satisfy allocation of {} bytes on sbrk heap", a1 - old);
 				old	
 			} else if a1 > old {
-				h.b.mmap_fixed(AddressRange { start: old, size: a1 - old }, Protection::RW, true).unwrap();
-				println!("Allocated {} bytes on sbrk heap, usage {}/{}", a1 - old, a1 - addr.start, addr.size);
+				match h.b.mmap_fixed(AddressRange { start: old, size: a1 - old }, Protection::RW, true) {
+					Ok(()) => {
+						h.b.scribble_new(AddressRange { start: old, size: a1 - old });
+						log!(Debug, "Allocated {} bytes on sbrk heap, usage {}/{}", a1 - old, a1 - addr.start, addr.size);
+						a1
+					},
+					Err(_) => {
+						log!(Warn, "Failed to satisfy allocation of {} bytes on sbrk heap", a1 - old);
+						old
+					},
+				}
+			} else if a1 < old {
+				let range = AddressRange { start: a1, size: old - a1 };
+				// if the guest has already unmapped some of it, the rest still has to go
+				if h.b.munmap(range).is_err() {
+					h.b.mmap_fixed(range, Protection::RW, false).unwrap();
+					h.b.munmap(range).unwrap();
+				}
 				a1
 			} else {
 				old
@@ -335,6 +2463,61 @@ pub extern "sysv64" fn syscall(nr: SyscallNumber, ud: usize, a1: usize, a2: usiz
 			h.h.program_break = res;
 			syscall_ok(res)
 		},
+		NR_WBX_REGISTER_MEMORY_DOMAIN => {
+			let all = h.sys.layout.all();
+			syscall_ret(h.h.memory_domains.register(&arg_to_str(a1)?, AddressRange { start: a2, size: a3 }, a4, a5, all))
+		},
+		NR_WBX_GET_CONFIG => {
+			// like snprintf:  as much as fits, always terminated, and the length of all of it
+			let value = h.h.config.get(&arg_to_str(a1)?).ok_or(ENOENT)?.as_bytes();
+			if a3 > 0 {
+				let n = std::cmp::min(value.len(), a3 - 1);
+				let dest = unsafe { std::slice::from_raw_parts_mut(a2 as *mut u8, n + 1) };
+				dest[..n].copy_from_slice(&value[..n]);
+				dest[n] = 0;
+			}
+			syscall_ok(value.len())
+		},
+		NR_WBX_ENTROPY => {
+			h.h.entropy.fill(unsafe { guest_slice(a1, a2) });
+			syscall_ok(0)
+		},
+		NR_WBX_CREATE_ARENA => {
+			let all = h.sys.layout.all();
+			syscall_ret(h.h.arenas.create(&arg_to_str(a1)?, AddressRange { start: a2, size: a3 }, all))
+		},
+		NR_WBX_DESTROY_ARENA => syscall_ret(h.h.arenas.destroy(&arg_to_str(a1)?)),
+		NR_WBX_SET_DOMAIN_TRANSLATOR => {
+			let all = h.sys.layout.all();
+			syscall_ret(h.h.memory_domains.set_translator(&arg_to_str(a1)?, a2, all))
+		},
+		// the interpreter can't switch stacks
+		NR_WBX_CREATE_FIBER | NR_WBX_SWITCH_FIBER if h.h.elf.abi() == GuestAbi::Wasm32 => syscall_err(ENOSYS),
+		NR_WBX_CREATE_FIBER => {
+			if !h.sys.layout.all().contains(a1) || a4 == 0 {
+				return syscall_err(EINVAL)
+			}
+			if h.h.fibers.full() {
+				return syscall_err(EAGAIN)
+			}
+			let arena_addr = h.sys.layout.mmap;
+			let start = h.b.mmap(AddressRange { start: 0, size: a4 }, Protection::RWStack, arena_addr, false)?;
+			syscall_ok(h.h.fibers.create(AddressRange { start, size: align_up(a4) }, a1, a2, a3) as usize)
+		},
+		NR_WBX_SWITCH_FIBER => {
+			let tid = h.h.threads.current();
+			syscall_ret(h.h.fibers.switch(tid, &h.entry.ctx, a1))
+		},
+		NR_WBX_DESTROY_FIBER => {
+			let stack = h.h.fibers.destroy(a1)?;
+			syscall_ret(h.b.munmap(stack))
+		},
+		NR_WBX_TRANSFER => syscall_ret(h.h.transfers.transfer(&arg_to_str(a1)?, unsafe { guest_slice(a2, a3) }, a4)),
+		// one from a newer host, which a guest that checked __wbximports wouldn't make
+		SyscallNumber(n) if n >= NR_WBX_REGISTER_MEMORY_DOMAIN.0 => {
+			log!(Warn, "Guest made waterbox call {:#x}, which this host doesn't have", n);
+			syscall_err(ENOSYS)
+		},
 		_ => syscall_ret(unimp(nr)),
 	}
 }
@@ -0,0 +1,92 @@
+// The handshake between the host and the guest over which of waterbox's own calls the host has.  A guest that wants to
+// know exports __wbximports, four 64 bit words:  The version of the table it was built for and the IMPORT_*s it would
+// use, which it fills in itself, then the host's version and the IMPORT_*s the host has, which the host writes before
+// the guest starts, and again whenever the guest's memory is replaced by a state.  A host that predates the table
+// leaves those last two as the guest had them, zero, so a guest knows to do without.  Newer tables can only add words
+// past these four, and the host only looks at the ones it knows.
+use crate::*;
+
+/// The version of the table this host writes
+pub const IMPORTS_VERSION: u64 = 1;
+/// NR_WBX_REGISTER_MEMORY_DOMAIN
+pub const IMPORT_MEMORY_DOMAINS: u64 = 1;
+/// NR_WBX_GET_CONFIG
+pub const IMPORT_CONFIG: u64 = 2;
+/// DOMAIN_SAVERAM for NR_WBX_REGISTER_MEMORY_DOMAIN
+pub const IMPORT_SAVERAM: u64 = 4;
+/// NR_WBX_ENTROPY
+pub const IMPORT_ENTROPY: u64 = 8;
+/// NR_WBX_CREATE_ARENA and NR_WBX_DESTROY_ARENA
+pub const IMPORT_ARENAS: u64 = 16;
+/// NR_WBX_SET_DOMAIN_TRANSLATOR
+pub const IMPORT_DOMAIN_TRANSLATORS: u64 = 32;
+/// NR_WBX_CREATE_FIBER, NR_WBX_SWITCH_FIBER and NR_WBX_DESTROY_FIBER
+pub const IMPORT_FIBERS: u64 = 64;
+/// NR_WBX_TRANSFER
+pub const IMPORT_TRANSFERS: u64 = 128;
+/// Every IMPORT_* this host has
+pub const HOST_IMPORTS: u64 = IMPORT_MEMORY_DOMAINS | IMPORT_CONFIG | IMPORT_SAVERAM | IMPORT_ENTROPY | IMPORT_ARENAS
+	| IMPORT_DOMAIN_TRANSLATORS | IMPORT_FIBERS | IMPORT_TRANSFERS;
+
+/// How big __wbximports must be, at least
+pub const IMPORTS_TABLE_SIZE: usize = 32;
+
+/// What the guest and the host each said in the handshake
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ImportInfo {
+	pub host_version: u64,
+	pub host_imports: u64,
+	/// 0 if the guest has no __wbximports
+	pub guest_version: u64,
+	pub guest_imports: u64,
+}
+impl ImportInfo {
+	/// What the guest would use that this host doesn't have
+	pub fn missing(&self) -> u64 {
+		self.guest_imports & !self.host_imports
+	}
+}
+
+/// Read the guest's half of the table at `addr`, which is empty if the guest has none
+/// unsafe: `addr` must be readable, if it's not empty
+pub unsafe fn read_guest(addr: AddressRange) -> ImportInfo {
+	let mut res = ImportInfo { host_version: IMPORTS_VERSION, host_imports: HOST_IMPORTS, ..Default::default() };
+	if addr.size >= IMPORTS_TABLE_SIZE {
+		let words = std::slice::from_raw_parts(addr.start as *const u64, 2);
+		res.guest_version = words[0];
+		res.guest_imports = words[1];
+	}
+	res
+}
+
+/// Write the host's half of the table at `addr`, if there is one
+/// unsafe: `addr` must be writable, if it's not empty
+pub unsafe fn write_host(addr: AddressRange) {
+	if addr.size >= IMPORTS_TABLE_SIZE {
+		let words = std::slice::from_raw_parts_mut(addr.start as *mut u64, 4);
+		words[2] = IMPORTS_VERSION;
+		words[3] = HOST_IMPORTS;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_handshake() {
+		// a newer guest's table, with a word this host doesn't know about
+		let mut table = [1u64, IMPORT_CONFIG | 0x100, 0, 0, 77];
+		let addr = AddressRange { start: table.as_mut_ptr() as usize, size: 40 };
+		let info = unsafe { read_guest(addr) };
+		assert_eq!((info.guest_version, info.guest_imports), (1, IMPORT_CONFIG | 0x100));
+		assert_eq!(info.missing(), 0x100);
+		unsafe { write_host(addr); }
+		assert_eq!(table, [1, IMPORT_CONFIG | 0x100, IMPORTS_VERSION, HOST_IMPORTS, 77]);
+
+		let none = unsafe { read_guest(AddressRange { start: 0, size: 0 }) };
+		assert_eq!((none.guest_version, none.missing()), (0, 0));
+		unsafe { write_host(AddressRange { start: 0, size: 0 }); }
+	}
+}
@@ -0,0 +1,278 @@
+// DEFLATE decompression (RFC 1951), for reading zip archives mounted in the guest filesystem.  Decompression only;
+// the host never needs to make these.
+use anyhow::anyhow;
+
+const MAX_BITS: usize = 15;
+const LENGTH_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115,
+	131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DIST_BASE: [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+	2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+const DIST_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+/// The order code length code lengths come in, in a dynamic block header
+const CLEN_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+struct Bits<'a> {
+	src: &'a [u8],
+	pos: usize,
+	buf: u32,
+	count: u32,
+}
+impl<'a> Bits<'a> {
+	fn take(&mut self, n: u32) -> anyhow::Result<u32> {
+		while self.count < n {
+			let b = *self.src.get(self.pos).ok_or_else(|| anyhow!("Unexpected end of deflate stream"))?;
+			self.buf |= (b as u32) << self.count;
+			self.pos += 1;
+			self.count += 8;
+		}
+		let res = self.buf & ((1u32 << n) - 1);
+		self.buf = self.buf.checked_shr(n).unwrap_or(0);
+		self.count -= n;
+		Ok(res)
+	}
+	/// Throw away what's left of the current byte, for stored blocks
+	fn align(&mut self) {
+		self.buf = 0;
+		self.count = 0;
+	}
+}
+
+/// A canonical Huffman code, in the form that can be decoded one bit at a time
+struct Huffman {
+	/// How many codes there are of each length
+	counts: [u16; MAX_BITS + 1],
+	/// Symbols ordered by code
+	symbols: Vec<u16>,
+}
+impl Huffman {
+	fn new(lengths: &[u8]) -> anyhow::Result<Huffman> {
+		let mut counts = [0u16; MAX_BITS + 1];
+		for &l in lengths.iter() {
+			counts[l as usize] += 1;
+		}
+		counts[0] = 0;
+		let mut left = 1i32;
+		for &c in counts[1..].iter() {
+			left = (left << 1) - c as i32;
+			if left < 0 {
+				return Err(anyhow!("Over-subscribed Huffman code"))
+			}
+		}
+		let mut offsets = [0u16; MAX_BITS + 2];
+		for len in 1..=MAX_BITS {
+			offsets[len + 1] = offsets[len] + counts[len];
+		}
+		let mut symbols = vec![0u16; offsets[MAX_BITS + 1] as usize];
+		for (sym, &l) in lengths.iter().enumerate() {
+			if l != 0 {
+				symbols[offsets[l as usize] as usize] = sym as u16;
+				offsets[l as usize] += 1;
+			}
+		}
+		Ok(Huffman { counts, symbols })
+	}
+	fn decode(&self, bits: &mut Bits) -> anyhow::Result<u16> {
+		let mut code = 0i32;
+		let mut first = 0i32;
+		let mut index = 0i32;
+		for len in 1..=MAX_BITS {
+			code |= bits.take(1)? as i32;
+			let count = self.counts[len] as i32;
+			if code - first < count {
+				return Ok(self.symbols[(index + code - first) as usize])
+			}
+			index += count;
+			first = (first + count) << 1;
+			code <<= 1;
+		}
+		Err(anyhow!("Invalid Huffman code"))
+	}
+}
+
+fn fixed_codes() -> anyhow::Result<(Huffman, Huffman)> {
+	let mut lengths = [0u8; 288];
+	for (i, l) in lengths.iter_mut().enumerate() {
+		*l = match i {
+			0..=143 => 8,
+			144..=255 => 9,
+			256..=279 => 7,
+			_ => 8,
+		};
+	}
+	Ok((Huffman::new(&lengths)?, Huffman::new(&[5u8; 30])?))
+}
+
+fn dynamic_codes(bits: &mut Bits) -> anyhow::Result<(Huffman, Huffman)> {
+	let nlen = bits.take(5)? as usize + 257;
+	let ndist = bits.take(5)? as usize + 1;
+	let ncode = bits.take(4)? as usize + 4;
+	if nlen > 286 || ndist > 30 {
+		return Err(anyhow!("Bad dynamic block header"))
+	}
+	let mut clens = [0u8; 19];
+	for &i in CLEN_ORDER[..ncode].iter() {
+		clens[i] = bits.take(3)? as u8;
+	}
+	let clen_code = Huffman::new(&clens)?;
+	let mut lengths = Vec::with_capacity(nlen + ndist);
+	while lengths.len() < nlen + ndist {
+		let sym = clen_code.decode(bits)?;
+		let (val, repeat) = match sym {
+			0..=15 => (sym as u8, 1),
+			16 => match lengths.last() {
+				Some(&prev) => (prev, 3 + bits.take(2)?),
+				None => return Err(anyhow!("Repeated code length with nothing before it"))
+			},
+			17 => (0, 3 + bits.take(3)?),
+			_ => (0, 11 + bits.take(7)?),
+		};
+		for _ in 0..repeat {
+			lengths.push(val);
+		}
+	}
+	if lengths.len() > nlen + ndist {
+		return Err(anyhow!("Code lengths overrun the dynamic block header"))
+	}
+	if lengths[256] == 0 {
+		return Err(anyhow!("Dynamic block has no end of block code"))
+	}
+	Ok((Huffman::new(&lengths[..nlen])?, Huffman::new(&lengths[nlen..])?))
+}
+
+fn inflate_codes(bits: &mut Bits, dst: &mut Vec<u8>, limit: usize, lit: &Huffman, dist: &Huffman) -> anyhow::Result<()> {
+	loop {
+		let sym = lit.decode(bits)? as usize;
+		if sym < 256 {
+			dst.push(sym as u8);
+		} else if sym == 256 {
+			return Ok(())
+		} else {
+			let i = sym - 257;
+			if i >= LENGTH_BASE.len() {
+				return Err(anyhow!("Bad length code"))
+			}
+			let len = LENGTH_BASE[i] as usize + bits.take(LENGTH_EXTRA[i] as u32)? as usize;
+			let d = dist.decode(bits)? as usize;
+			if d >= DIST_BASE.len() {
+				return Err(anyhow!("Bad distance code"))
+			}
+			let back = DIST_BASE[d] as usize + bits.take(DIST_EXTRA[d] as u32)? as usize;
+			if back > dst.len() {
+				return Err(anyhow!("Distance reaches before the start of the output"))
+			}
+			let from = dst.len() - back;
+			for j in 0..len {
+				dst.push(dst[from + j]);
+			}
+		}
+		if dst.len() > limit {
+			return Err(anyhow!("Deflate stream is larger than expected"))
+		}
+	}
+}
+
+/// Decompress a raw deflate stream, which must come out to exactly `expected_size` bytes
+pub fn inflate(src: &[u8], expected_size: usize) -> anyhow::Result<Vec<u8>> {
+	let mut dst = Vec::with_capacity(expected_size);
+	let mut bits = Bits { src, pos: 0, buf: 0, count: 0 };
+	loop {
+		let last = bits.take(1)? != 0;
+		match bits.take(2)? {
+			0 => {
+				bits.align();
+				let p = bits.pos;
+				if p + 4 > src.len() {
+					return Err(anyhow!("Unexpected end of deflate stream"))
+				}
+				let len = u16::from_le_bytes([src[p], src[p + 1]]) as usize;
+				let nlen = u16::from_le_bytes([src[p + 2], src[p + 3]]) as usize;
+				if len != !nlen & 0xffff || p + 4 + len > src.len() {
+					return Err(anyhow!("Bad stored block"))
+				}
+				dst.extend_from_slice(&src[p + 4..p + 4 + len]);
+				bits.pos = p + 4 + len;
+			},
+			1 => {
+				let (lit, dist) = fixed_codes()?;
+				inflate_codes(&mut bits, &mut dst, expected_size, &lit, &dist)?;
+			},
+			2 => {
+				let (lit, dist) = dynamic_codes(&mut bits)?;
+				inflate_codes(&mut bits, &mut dst, expected_size, &lit, &dist)?;
+			},
+			_ => return Err(anyhow!("Bad deflate block type"))
+		}
+		if dst.len() > expected_size {
+			return Err(anyhow!("Deflate stream is larger than expected"))
+		}
+		if last {
+			break
+		}
+	}
+	if dst.len() != expected_size {
+		return Err(anyhow!("Deflate stream is smaller than expected"))
+	}
+	Ok(dst)
+}
+
+/// The CRC-32 that zip uses to check its contents
+pub fn crc32(data: &[u8]) -> u32 {
+	let mut table = [0u32; 256];
+	for (i, t) in table.iter_mut().enumerate() {
+		let mut c = i as u32;
+		for _ in 0..8 {
+			c = if c & 1 != 0 { 0xedb88320 ^ (c >> 1) } else { c >> 1 };
+		}
+		*t = c;
+	}
+	let mut crc = !0u32;
+	for &b in data.iter() {
+		crc = table[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8);
+	}
+	!crc
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	type TestResult = anyhow::Result<()>;
+
+	#[test]
+	fn test_fixed() -> TestResult {
+		let src = [0x0b, 0xc9, 0x48, 0x55, 0x28, 0x2c, 0xcd, 0x4c, 0xce, 0x56, 0x48, 0x2a, 0xca, 0x2f, 0xcf, 0x53, 0x48,
+			0xcb, 0xaf, 0x50, 0xc8, 0x2a, 0xcd, 0x2d, 0x28, 0x56, 0xc8, 0x2f, 0x4b, 0x2d, 0x52, 0x28, 0x01, 0x4a, 0xe7, 0x24,
+			0x56, 0x55, 0x2a, 0xa4, 0xe4, 0xa7, 0xeb, 0x29, 0x84, 0xd0, 0x4c, 0x31, 0x00];
+		let expected = "The quick brown fox jumps over the lazy dog. ".repeat(3).into_bytes();
+		assert_eq!(inflate(&src, expected.len())?, expected);
+		assert!(inflate(&src, expected.len() - 1).is_err());
+		assert!(inflate(&src, expected.len() + 1).is_err());
+		assert!(inflate(&src[..20], expected.len()).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_dynamic() -> TestResult {
+		let src = [0xcd, 0xcb, 0xb1, 0x09, 0x00, 0x30, 0x0c, 0x03, 0xc1, 0x59, 0x5d, 0x3c, 0xc4, 0x8d, 0x55, 0x58, 0xfb,
+			0x13, 0x65, 0x8b, 0x74, 0x7a, 0x38, 0x81, 0xa9, 0x11, 0x36, 0x2e, 0x86, 0xb5, 0x0a, 0xb5, 0x81, 0xda, 0xf1, 0x1e,
+			0x65, 0x75, 0x02, 0x36, 0xf4, 0x10, 0x43, 0xe8, 0x03, 0x7c, 0xf4, 0xbd];
+		let expected = (0..200usize).map(|i| b"eeeeeeeettttaaoinshr"[(i * i * 7 + i / 3) % 20]).collect::<Vec<_>>();
+		let res = inflate(&src, expected.len())?;
+		assert_eq!(res, expected);
+		assert_eq!(crc32(&res), 0x2da509d8);
+		Ok(())
+	}
+
+	#[test]
+	fn test_stored() -> TestResult {
+		// a stored block that isn't last, and then an empty fixed block that is
+		let src = [0x00, 0x05, 0x00, 0xfa, 0xff, b'h', b'e', b'l', b'l', b'o', 0x03, 0x00];
+		assert_eq!(inflate(&src, 5)?, b"hello");
+		let mut bad = src;
+		bad[3] = 0;
+		assert!(inflate(&bad, 5).is_err());
+		assert_eq!(crc32(b"123456789"), 0xcbf43926);
+		Ok(())
+	}
+}
@@ -0,0 +1,214 @@
+// A journal of savestates on disk, appended to every so many frames, for picking up where an emulator that died left
+// off and for rewinding further back than memory has room for.  Each entry is a compressed savestate that only holds
+// the pages of guest memory that changed since the entry before, and every so often one has all of them, so that
+// putting a state back together only has to read from the last of those.  Entries end with a hash of what came
+// before, so one that was cut off by a crash is found and left out.  Loading a state starts a new timeline, whose
+// first entry has everything, and the file keeps both; finding a frame takes the last entry written at or before it.
+use crate::*;
+use std::fs::{File, OpenOptions};
+use std::io::{Seek, SeekFrom};
+use memory_block::JournalPages;
+
+const MAGIC: &str = "WaterboxJournal";
+const ENTRY_MAGIC: &str = "JournalEntry";
+/// The magic, the frame, whether it's full and the length, before each entry's data
+const ENTRY_HEAD: u64 = ENTRY_MAGIC.len() as u64 + 8 + 1 + 8;
+
+/// Where one entry is in the file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryInfo {
+	pub frame: u64,
+	/// Whether it has every page, instead of only what changed since the entry before
+	pub full: bool,
+	/// Of its data
+	pub offset: u64,
+	pub len: u64,
+}
+
+/// The entries that are entirely in `file`, and where the last of them ends
+fn scan(file: &mut File) -> anyhow::Result<(Vec<EntryInfo>, u64)> {
+	let size = file.seek(SeekFrom::End(0))?;
+	file.seek(SeekFrom::Start(0))?;
+	let mut stream = std::io::BufReader::new(&mut *file);
+	bin::verify_magic(&mut stream, MAGIC)?;
+	let mut pos = MAGIC.len() as u64;
+	let mut entries = Vec::new();
+	while pos + ENTRY_HEAD + 8 <= size {
+		let head = (|| {
+			bin::verify_magic(&mut stream, ENTRY_MAGIC)?;
+			let frame = bin::readval::<u64>(&mut stream)?;
+			let full = bin::readval::<u8>(&mut stream)? != 0;
+			let len = bin::readval::<u64>(&mut stream)?;
+			Ok::<_, anyhow::Error>(EntryInfo { frame, full, offset: pos + ENTRY_HEAD, len })
+		})();
+		let entry = match head {
+			Ok(e) if e.offset + e.len + 8 <= size => e,
+			_ => break,
+		};
+		let mut data = vec![0u8; entry.len as usize];
+		stream.read_exact(&mut data[..])?;
+		if bin::readval::<u64>(&mut stream)? != xxh3::xxh3_64(&data[..]) {
+			break
+		}
+		entries.push(entry);
+		pos = entry.offset + entry.len + 8;
+	}
+	Ok((entries, pos))
+}
+
+pub struct Journal {
+	file: File,
+	entries: Vec<EntryInfo>,
+	/// Where the next entry goes
+	end: u64,
+	/// How many frames apart entries are
+	interval: u64,
+	/// How many entries there are from one that has every page to the next
+	full_interval: u32,
+	since_full: u32,
+	last_frame: Option<u64>,
+	/// What each page held as of the last entry, or nothing if the next has to have every page
+	hashes: Vec<Option<u64>>,
+}
+impl Drop for Journal {
+	fn drop(&mut self) {
+		leaks::DESCRIPTORS.closed();
+	}
+}
+impl Journal {
+	/// Open the journal at `path` to append to, making it if it isn't there.  Anything after its last whole entry is cut
+	/// off.
+	pub fn open(path: &str, interval: u64, full_interval: u32) -> anyhow::Result<Journal> {
+		if interval == 0 || full_interval == 0 {
+			return Err(coded(ErrorCode::InvalidArgument, "Journal intervals must be at least 1"))
+		}
+		let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+		let (entries, end) = if file.metadata()?.len() == 0 {
+			bin::write_magic(&mut file, MAGIC)?;
+			(Vec::new(), MAGIC.len() as u64)
+		} else {
+			scan(&mut file)?
+		};
+		file.set_len(end)?;
+		leaks::DESCRIPTORS.opened();
+		Ok(Journal { file, entries, end, interval, full_interval, since_full: 0, last_frame: None, hashes: Vec::new() })
+	}
+	pub fn entries(&self) -> &[EntryInfo] {
+		&self.entries
+	}
+	/// Whether an entry is due at `frame`, which it is if there's been none since the journal was opened or guest
+	/// memory was replaced, or `frame` is `interval` past the last, or before it
+	pub fn due(&self, frame: u64) -> bool {
+		match self.last_frame {
+			Some(last) => frame < last || frame - last >= self.interval,
+			None => true,
+		}
+	}
+	/// Make the next entry have every page, as guest memory was replaced
+	pub fn restart(&mut self) {
+		self.hashes.clear();
+		self.last_frame = None;
+	}
+	/// Add an entry for `frame`, which `write` writes, given what each page held as of the last entry to be updated
+	pub fn append(&mut self, frame: u64, write: impl FnOnce(&mut dyn Write, &mut Vec<Option<u64>>) -> anyhow::Result<()>) -> anyhow::Result<()> {
+		if self.since_full + 1 >= self.full_interval {
+			self.hashes.clear();
+		}
+		let full = self.hashes.is_empty();
+		let mut data = Vec::new();
+		let res = (|| {
+			let mut writer = compress::CompressedWriter::new(&mut data)?;
+			write(&mut writer, &mut self.hashes)?;
+			writer.finish()
+		})();
+		if res.is_err() {
+			self.restart();
+			return res
+		}
+		let mut entry = Vec::with_capacity(data.len() + (ENTRY_HEAD + 8) as usize);
+		bin::write_magic(&mut entry, ENTRY_MAGIC)?;
+		bin::write(&mut entry, &frame)?;
+		bin::writeval(&mut entry, full as u8)?;
+		bin::writeval(&mut entry, data.len() as u64)?;
+		entry.extend_from_slice(&data[..]);
+		bin::write(&mut entry, &xxh3::xxh3_64(&data[..]))?;
+		self.file.seek(SeekFrom::Start(self.end))?;
+		if let Err(e) = self.file.write_all(&entry[..]) {
+			// what did get written is cut off when the journal is next opened
+			self.restart();
+			return Err(e.into())
+		}
+		self.entries.push(EntryInfo { frame, full, offset: self.end + ENTRY_HEAD, len: data.len() as u64 });
+		self.end += entry.len() as u64;
+		self.since_full = if full { 0 } else { self.since_full + 1 };
+		self.last_frame = Some(frame);
+		Ok(())
+	}
+	/// The last entry written at or before `frame`
+	pub fn find(&self, frame: u64) -> Option<usize> {
+		self.entries.iter().rposition(|e| e.frame <= frame)
+	}
+	/// Put the state entry `index` was made from back together, leaving off only SAVE_END_MAGIC
+	pub fn rebuild(&mut self, index: usize, stream: &mut dyn Write) -> anyhow::Result<()> {
+		let first = match self.entries[..=index].iter().rposition(|e| e.full) {
+			Some(i) => i,
+			None => return Err(coded(ErrorCode::BadStateData, "Journal has no entry with every page before this one")),
+		};
+		let mut head = Vec::new();
+		let mut pages = JournalPages::default();
+		for e in self.entries[first..=index].iter() {
+			let mut data = vec![0u8; e.len as usize];
+			self.file.seek(SeekFrom::Start(e.offset))?;
+			self.file.read_exact(&mut data[..])?;
+			let mut slice = &data[..];
+			let mut reader = compress::maybe_decompress(&mut slice)?;
+			head = vec![0u8; bin::readval::<u64>(&mut reader)? as usize];
+			reader.read_exact(&mut head[..])?;
+			pages.apply(&mut reader)?;
+		}
+		stream.write_all(&head[..])?;
+		pages.save_state(stream)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_journal() -> anyhow::Result<()> {
+		let path = std::env::temp_dir().join(format!("wbx_journal_{}", std::process::id()));
+		let path = path.to_str().unwrap();
+		let _ = std::fs::remove_file(path);
+		let frames = |j: &Journal| j.entries().iter().map(|e| (e.frame, e.full)).collect::<Vec<_>>();
+		{
+			let mut j = Journal::open(path, 10, 3)?;
+			for frame in 0..35 {
+				if j.due(frame) {
+					j.append(frame, |w, hashes| {
+						hashes.push(Some(frame));
+						Ok(w.write_all(&frame.to_le_bytes())?)
+					})?;
+				}
+			}
+			assert_eq!(frames(&j), [(0, true), (10, false), (20, false), (30, true)]);
+			j.restart();
+			assert!(j.due(31));
+			j.append(12, |w, _| Ok(w.write_all(b"x")?))?;
+			assert_eq!(j.find(15), Some(4));
+			assert_eq!(j.find(29), Some(4));
+			assert_eq!(j.find(30), Some(4));
+			assert_eq!(j.find(11), Some(1));
+		}
+		// a crash partway through writing one
+		let mut f = OpenOptions::new().append(true).open(path)?;
+		f.write_all(&b"JournalEntry\x01\x02"[..])?;
+		drop(f);
+		let j = Journal::open(path, 10, 3)?;
+		assert_eq!(frames(&j), [(0, true), (10, false), (20, false), (30, true), (12, true)]);
+		assert_eq!(std::fs::metadata(path)?.len(), j.end);
+		drop(j);
+		std::fs::remove_file(path)?;
+		Ok(())
+	}
+}
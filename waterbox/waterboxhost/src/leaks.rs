@@ -0,0 +1,103 @@
+// What the hosts in the process are holding on to from the OS, for frontends that swap cores in and out many times a
+// session and need to find out what isn't given back.  Each kind of resource is counted where it's made and where it's
+// freed, across every host, so once they've all been shut down or destroyed, anything still counted was leaked, or is a
+// host view the frontend never unmapped.  Debug builds can read the counts with wbx_get_leak_audit().
+use crate::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A count of something that's made and freed
+pub struct Live(AtomicU64);
+impl Live {
+	pub const fn new() -> Live {
+		Live(AtomicU64::new(0))
+	}
+	pub fn opened(&self) {
+		self.0.fetch_add(1, Ordering::Relaxed);
+	}
+	pub fn closed(&self) {
+		self.0.fetch_sub(1, Ordering::Relaxed);
+	}
+	pub fn get(&self) -> u64 {
+		self.0.load(Ordering::Relaxed)
+	}
+}
+
+/// WaterboxHosts that haven't been destroyed
+pub static HOSTS: Live = Live::new();
+/// Of those, the ones that have been shut down
+pub static SHUT_DOWN_HOSTS: Live = Live::new();
+/// MemoryBlocks that haven't released their memory
+pub static BLOCKS: Live = Live::new();
+/// Blocks whose guest memory is mapped in at their address
+pub static MAPPED_BLOCKS: Live = Live::new();
+/// Views of guest memory made for the frontend
+pub static HOST_VIEWS: Live = Live::new();
+/// Shared memory objects behind blocks, and journal files
+pub static DESCRIPTORS: Live = Live::new();
+/// Guest images registered with gdb
+pub static GDB_IMAGES: Live = Live::new();
+/// Snapshots of guest pages, and the copies rewind buffers and the like keep of them
+pub static SNAPSHOT_PAGES: Live = Live::new();
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LeakAudit {
+	pub hosts: u64,
+	pub shut_down_hosts: u64,
+	pub blocks: u64,
+	pub mapped_blocks: u64,
+	pub host_views: u64,
+	pub descriptors: u64,
+	pub gdb_images: u64,
+	pub snapshot_pages: u64,
+	/// Freed snapshot pages kept to be reused, which aren't leaked, but are only given back by wbx_trim_page_pool()
+	pub pooled_pages: u64,
+}
+impl LeakAudit {
+	/// Whether nothing is left but the page pool
+	pub fn clean(&self) -> bool {
+		*self == LeakAudit { pooled_pages: self.pooled_pages, ..Default::default() }
+	}
+}
+
+pub fn audit() -> LeakAudit {
+	LeakAudit {
+		hosts: HOSTS.get(),
+		shut_down_hosts: SHUT_DOWN_HOSTS.get(),
+		blocks: BLOCKS.get(),
+		mapped_blocks: MAPPED_BLOCKS.get(),
+		host_views: HOST_VIEWS.get(),
+		descriptors: DESCRIPTORS.get(),
+		gdb_images: GDB_IMAGES.get(),
+		snapshot_pages: SNAPSHOT_PAGES.get(),
+		pooled_pages: (memory_block::page_pool_size() / PAGESIZE) as u64,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use memory_block::{MemoryBlock, Protection};
+
+	#[test]
+	fn test_leak_audit() -> anyhow::Result<()> {
+		let live = Live::new();
+		live.opened();
+		live.opened();
+		live.closed();
+		assert_eq!(live.get(), 1);
+		assert!(LeakAudit { pooled_pages: 5, ..Default::default() }.clean());
+		assert!(!LeakAudit { host_views: 1, ..Default::default() }.clean());
+		// other tests run at the same time, so all that's certain is that what this one holds is counted
+		let addr = AddressRange { start: 0x3a700000000, size: 0x10000 };
+		let mut b = MemoryBlock::new(addr);
+		let mut g = b.enter();
+		g.mmap_fixed(addr, Protection::RW, false)?;
+		let view = g.map_host_view(addr)?;
+		let during = audit();
+		assert!(during.blocks >= 1 && during.mapped_blocks >= 1 && during.host_views >= 1 && during.descriptors >= 1);
+		assert!(!during.clean());
+		unsafe { memory_block::unmap_host_view(view)? };
+		Ok(())
+	}
+}
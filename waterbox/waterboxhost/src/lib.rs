@@ -1,26 +1,102 @@
-#![crate_type = "cdylib"]
-
 #![feature(try_trait)]
 #![feature(core_intrinsics)]
+#![feature(thread_local)]
+#![feature(global_asm)]
+#![cfg_attr(test, feature(test))]
 
 #![allow(dead_code)]
 
 use std::io::{Read, Write};
 use anyhow::anyhow;
 use syscall_defs::{SyscallNumber, SyscallReturn};
+use error_code::coded;
+pub use error_code::ErrorCode;
 
 const PAGESIZE: usize = 0x1000;
 const PAGEMASK: usize = 0xfff;
 const PAGESHIFT: i32 = 12;
 
+/// Guest code is always called with the System V convention for the host's architecture, which on x86_64 Windows isn't
+/// "C".  Wraps a function, a function pointer type, or an extern block.
+#[cfg(target_arch = "x86_64")]
+macro_rules! guest_abi {
+	({ $($t:tt)* }) => { extern "sysv64" { $($t)* } };
+	(unsafe fn $($t:tt)*) => { unsafe extern "sysv64" fn $($t)* };
+	(fn $($t:tt)*) => { extern "sysv64" fn $($t)* };
+	($v:vis fn $($t:tt)*) => { $v extern "sysv64" fn $($t)* };
+}
+#[cfg(not(target_arch = "x86_64"))]
+macro_rules! guest_abi {
+	({ $($t:tt)* }) => { extern "C" { $($t)* } };
+	(unsafe fn $($t:tt)*) => { unsafe extern "C" fn $($t)* };
+	(fn $($t:tt)*) => { extern "C" fn $($t)* };
+	($v:vis fn $($t:tt)*) => { $v extern "C" fn $($t)* };
+}
+
+/// Log a message from the host at a logging::Level, like `log!(Warn, "Bad thing {}", x)`
+macro_rules! log {
+	($level:ident, $($arg:tt)*) => {
+		if crate::logging::enabled(crate::logging::Level::$level) {
+			crate::logging::write(crate::logging::Level::$level, format_args!($($arg)*));
+		}
+	};
+}
+
 mod memory_block;
 mod syscall_defs;
 mod bin;
+mod compress;
+mod inflate;
+mod xxh3;
+mod abi;
 mod elf;
+mod wasm;
 mod fs;
 mod host;
 mod cinterface;
+mod config;
+pub mod api;
 mod gdb;
+mod gdbstub;
+mod rewind;
+mod journal;
+mod trace;
+mod threading;
+mod crash;
+mod coredump;
+mod clock;
+mod entropy;
+mod rom;
+mod watchdog;
+mod state_format;
+mod state_diff;
+mod error_code;
+mod logging;
+mod memory_domains;
+mod arenas;
+mod fibers;
+mod history;
+mod metrics;
+mod transfers;
+mod leaks;
+mod imports;
+mod fpenv;
+mod profile;
+mod counters;
+mod heap_profile;
+mod heap_layout;
+mod workers;
+mod seccomp;
+mod syscall_policy;
+mod startup;
+mod replay;
+#[cfg(unix)]
+mod signal_context;
+// arm64 macOS only runs code from MAP_JIT memory, which guest memory can't be
+#[cfg(all(target_os = "macos", not(target_arch = "x86_64")))]
+compile_error!("waterboxhost only supports x86_64 macOS");
+#[cfg(feature = "fuzz")]
+mod fuzz;
 
 pub trait IStateable {
 	fn save_state(&mut self, stream: &mut dyn Write) -> anyhow::Result<()>;
@@ -37,6 +113,10 @@ impl AddressRange {
 	pub fn end(&self) -> usize {
 		self.start + self.size
 	}
+	/// end(), or None if the range runs off the end of the address space
+	pub fn checked_end(&self) -> Option<usize> {
+		self.start.checked_add(self.size)
+	}
 	pub fn contains(&self, addr: usize) -> bool {
 		addr >= self.start && addr < self.end()
 	}
@@ -105,8 +185,9 @@ impl WbxSysLayout {
 #[derive(Copy, Clone)]
 pub struct WbxSysSyscall {
 	pub ud: usize,
-	pub syscall: extern "sysv64" fn(nr: SyscallNumber, ud: usize, a1: usize, a2: usize, a3: usize, a4: usize, a5: usize, a6: usize) -> SyscallReturn,
+	pub syscall: SyscallFn,
 }
+pub type SyscallFn = guest_abi!(fn(nr: SyscallNumber, ud: usize, a1: usize, a2: usize, a3: usize, a4: usize, a5: usize, a6: usize) -> SyscallReturn);
 
 /// Data that is injected into the guest application
 #[repr(C)]
@@ -120,10 +201,32 @@ pub struct WbxSysArea {
 /// Severe performance consequences.
 static mut ALWAYS_EVICT_BLOCKS: bool = true;
 
+/// Dirty tracking backend used for hosts created from now on.
+static mut DIRTY_TRACKING: memory_block::DirtyTracking = memory_block::DirtyTracking::Signal;
+
+/// Whether hosts created from now on are set up to run under sanitizers.
+static mut SANITIZER_MODE: bool = false;
+
+/// Whether hosts created from now on ask for huge pages.
+static mut HUGE_PAGES: bool = false;
+
+/// Whether hosts created from now on scribble over memory the guest maps or discards.
+static mut SCRIBBLE: bool = false;
+
+/// Whether hosts created from now on commit all of their memory up front.
+static mut COMMIT_UPFRONT: bool = false;
+
+/// If Some, hosts created from now on randomize mmap placement with this seed.
+static mut MMAP_SEED: Option<u64> = None;
+
+/// Whether hosts created from now on only load wasm cores, and never make executable memory.
+static mut WASM_NO_EXEC: bool = false;
+
 #[cfg(test)]
 mod tests {
 	#[test]
 	fn test_pagesize() {
-		assert_eq!(crate::PAGESIZE, page_size::get());
+		// guest pages are always 4K, whatever the host's are
+		assert_eq!(page_size::get() % crate::PAGESIZE, 0);
 	}
 }
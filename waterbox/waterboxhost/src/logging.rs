@@ -0,0 +1,80 @@
+// Where the host's own diagnostics go.  They're printed to the console unless the frontend takes them with
+// wbx_set_log_callback, to show in its own log window.  Messages above the chosen level aren't even formatted.
+// This isn't built on `tracing`:  Its spans and structured fields would all flatten into the one string the callback
+// takes anyway, and a subscriber would mean three more dependencies for what's a level check and a function call.
+use crate::cinterface::LogCallback;
+use lazy_static::lazy_static;
+use std::ffi::CString;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+	/// Something failed, and a guest or host probably can't continue
+	Error = 1,
+	/// The guest did something unsupported or suspicious, but things carry on
+	Warn = 2,
+	/// Loading and other milestones
+	Info = 3,
+	/// Details for debugging the host or a core
+	Debug = 4,
+}
+
+static MAX_LEVEL: AtomicI32 = AtomicI32::new(Level::Info as i32);
+lazy_static! {
+	static ref SINK: Mutex<Option<(LogCallback, usize)>> = Mutex::new(None);
+}
+
+/// Whether messages at `level` are shown
+pub fn enabled(level: Level) -> bool {
+	level as i32 <= MAX_LEVEL.load(Ordering::Relaxed)
+}
+/// Show messages at `level` and more important ones.  0 shows nothing.
+pub fn set_max_level(level: i32) {
+	MAX_LEVEL.store(level, Ordering::Relaxed);
+}
+/// Send messages to a callback from now on, or back to the console with None
+pub fn set_callback(callback: Option<(LogCallback, usize)>) {
+	*SINK.lock().unwrap() = callback;
+}
+
+/// Use log! instead, which skips formatting messages that won't be shown
+pub fn write(level: Level, args: std::fmt::Arguments) {
+	let sink = *SINK.lock().unwrap();
+	match sink {
+		Some((callback, userdata)) => {
+			let text = CString::new(std::fmt::format(args)).unwrap_or_default();
+			callback(userdata, level, text.as_ptr());
+		},
+		None if level <= Level::Warn => eprintln!("{}", args),
+		None => println!("{}", args),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_callback() {
+		lazy_static! {
+			static ref SEEN: Mutex<Vec<(Level, String)>> = Mutex::new(Vec::new());
+		}
+		extern fn callback(userdata: usize, level: Level, message: *const std::os::raw::c_char) {
+			assert_eq!(userdata, 77);
+			let message = unsafe { std::ffi::CStr::from_ptr(message) }.to_str().unwrap().to_string();
+			SEEN.lock().unwrap().push((level, message));
+		}
+		set_callback(Some((callback, 77)));
+		set_max_level(Level::Warn as i32);
+		log!(Warn, "warning {}", 1);
+		log!(Debug, "debugging {}", 2);
+		set_max_level(Level::Info as i32);
+		set_callback(None);
+		// other tests log too, so only look for these
+		let seen = SEEN.lock().unwrap();
+		assert!(seen.contains(&(Level::Warn, "warning 1".to_string())));
+		assert!(!seen.iter().any(|(_, m)| m == "debugging 2"));
+	}
+}
@@ -0,0 +1,156 @@
+// Write audits:  A log of every write to some ranges of guest memory, with where it came from, what was there before
+// and what was written, for trace loggers.  Audited pages are watched for writes like watchpoints are, and the old
+// value is read when the write faults, and the new one once it has been single stepped through.  Writes aren't sized,
+// so up to 8 bytes from the written address are logged, as far as the range goes.  The log holds so many entries, and
+// past that the oldest are dropped.
+use super::*;
+use std::cell::Cell;
+use std::collections::VecDeque;
+
+/// How many entries the log holds unless told otherwise
+pub const DEFAULT_AUDIT_CAPACITY: usize = 4096;
+
+/// One guest write to an audited range
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AuditEntry {
+	/// The instruction that wrote
+	pub rip: u64,
+	pub addr: u64,
+	/// How many bytes of old and new are memory, from the low end
+	pub width: u64,
+	pub old: u64,
+	pub new: u64,
+}
+
+#[derive(Debug)]
+pub struct Audit {
+	id: u32,
+	addr: AddressRange,
+}
+
+#[derive(Debug)]
+pub struct AuditLog {
+	entries: VecDeque<AuditEntry>,
+	capacity: usize,
+	/// Entries dropped to make room since this was last looked at
+	dropped: u64,
+}
+impl AuditLog {
+	pub fn new() -> AuditLog {
+		AuditLog { entries: VecDeque::new(), capacity: DEFAULT_AUDIT_CAPACITY, dropped: 0 }
+	}
+	/// Make room for as many entries as the log holds, so push() never has to
+	fn reserve(&mut self) {
+		self.entries.shrink_to_fit();
+		self.entries.reserve_exact(self.capacity - self.entries.len());
+	}
+	/// Doesn't allocate, as it's called from the fault handler
+	fn push(&mut self, entry: AuditEntry) {
+		if self.capacity == 0 {
+			self.dropped += 1;
+			return
+		}
+		if self.entries.len() == self.capacity {
+			self.entries.pop_front();
+			self.dropped += 1;
+		}
+		self.entries.push_back(entry);
+	}
+}
+
+/// Writes the current thread is single stepping through, which could be to a few different pages
+#[thread_local]
+static STEP_AUDITS: [Cell<Option<AuditEntry>>; 4] = [Cell::new(None), Cell::new(None), Cell::new(None), Cell::new(None)];
+
+fn read_value(addr: usize, width: usize) -> u64 {
+	let mut b = [0u8; 8];
+	unsafe { std::ptr::copy_nonoverlapping(addr as *const u8, b.as_mut_ptr(), width); }
+	u64::from_le_bytes(b)
+}
+
+/// If any of `audits` overlap this page
+pub(super) fn audited(audits: &[Audit], page_addr: AddressRange) -> bool {
+	audits.iter().any(|a| a.addr.start < page_addr.end() && page_addr.start < a.addr.end())
+}
+
+impl MemoryBlock {
+	/// The guest is about to write to `addr` from `rip`, on a page that's been unwatched to let it
+	/// unsafe: the page must be readable
+	pub(super) unsafe fn begin_audit(&self, addr: usize, rip: usize) {
+		let audit = match self.audits.iter().find(|a| a.addr.contains(addr)) {
+			Some(a) => a,
+			None => return,
+		};
+		let width = std::cmp::min(8, std::cmp::min(audit.addr.end(), align_down(addr) + PAGESIZE) - addr);
+		if let Some(slot) = STEP_AUDITS.iter().find(|s| s.get().is_none()) {
+			slot.set(Some(AuditEntry { rip: rip as u64, addr: addr as u64, width: width as u64, old: read_value(addr, width), new: 0 }));
+		}
+	}
+}
+
+/// The writes begin_audit() was told about have been stepped through, so log them
+/// unsafe: their pages must still be unwatched
+pub(super) unsafe fn end_audits() {
+	for slot in STEP_AUDITS.iter() {
+		let mut entry = match slot.replace(None) {
+			Some(e) => e,
+			None => break,
+		};
+		if let Some(memory_block) = tripguard::find_block(entry.addr as usize) {
+			entry.new = read_value(entry.addr as usize, entry.width as usize);
+			memory_block.audit_log.push(entry);
+		}
+	}
+}
+
+impl<'block> ActivatedMemoryBlock<'block> {
+	/// Log every write to `addr`.  Returns an id for remove_audit().
+	pub fn add_audit(&mut self, addr: AddressRange) -> anyhow::Result<u32> {
+		self.b.check_watch_supported("Write audits")?;
+		if addr.size == 0 || addr.start < self.b.addr.start || addr.end() > self.b.addr.end() {
+			return Err(coded(ErrorCode::UnmappedAddress, "Audited range must be inside the MemoryBlock"))
+		}
+		let id = self.b.next_watch_id;
+		self.b.next_watch_id += 1;
+		self.b.audits.push(Audit { id, addr });
+		self.b.audit_log.reserve();
+		self.b.update_watch(addr);
+		Ok(id)
+	}
+	pub fn remove_audit(&mut self, id: u32) -> anyhow::Result<()> {
+		match self.b.audits.iter().position(|a| a.id == id) {
+			Some(index) => {
+				let a = self.b.audits.remove(index);
+				self.b.update_watch(a.addr);
+				Ok(())
+			},
+			None => Err(coded(ErrorCode::NotFound, format!("No audit with id {}", id)))
+		}
+	}
+	/// Keep at most `capacity` entries in the log, dropping the oldest to make room
+	pub fn set_audit_capacity(&mut self, capacity: usize) {
+		let log = &mut self.b.audit_log;
+		while log.entries.len() > capacity {
+			log.entries.pop_front();
+			log.dropped += 1;
+		}
+		log.capacity = capacity;
+		if !self.b.audits.is_empty() {
+			log.reserve();
+		}
+	}
+	/// Move the oldest entries in the log into `dest`, as many as fit, and return how many that was
+	pub fn read_audit_log(&mut self, dest: &mut [AuditEntry]) -> usize {
+		let log = &mut self.b.audit_log;
+		let n = std::cmp::min(dest.len(), log.entries.len());
+		for (d, e) in dest.iter_mut().zip(log.entries.drain(..n)) {
+			*d = e;
+		}
+		n
+	}
+	/// How many entries have been dropped to make room, since the last time this was asked
+	pub fn take_audit_dropped(&mut self) -> u64 {
+		std::mem::replace(&mut self.b.audit_log.dropped, 0)
+	}
+}
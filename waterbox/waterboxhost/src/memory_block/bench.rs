@@ -0,0 +1,128 @@
+// Benchmarks for the memory system, run with `cargo bench`.  Looking a page up is an index into one flat Vec, so
+// deciding what a fault is costs next to nothing beside the fault itself and the snapshot it takes.  What does cost is
+// walking every page of a big block, at a few dozen bytes a page, so paths that run every frame, like loading a state
+// to rewind, should walk them once and only do more for the pages that need it, and ones that only care about the dirty
+// pages, like state_size(), find them from the block's dirty_index bitmap without walking at all.  The counters in
+// crate::counters say how many faults, protection changes and snapshot pages a benchmark took, which is steadier than
+// the time it took.  These are libtest's #[bench]es rather than criterion ones, since the crate needs nightly for its
+// syscall plumbing anyway, and they need to reach into the block's private tables, which a benches/ harness outside the
+// crate can't.
+#![cfg(test)]
+
+extern crate test;
+use test::{Bencher, black_box};
+use super::*;
+
+/// A 1GiB block with 256MiB mapped and sealed, and `dirty` pages written since
+fn big_block(start: usize, dirty: usize) -> Box<MemoryBlock> {
+	let addr = AddressRange { start, size: 0x40000000 };
+	let mut b = MemoryBlock::new(addr);
+	{
+		let mut g = b.enter();
+		g.mmap_fixed(AddressRange { start, size: 0x10000000 }, Protection::RW, true).unwrap();
+		g.seal();
+		for i in 0..dirty {
+			unsafe { *((start + (i << PAGESHIFT)) as *mut u8) = 1; }
+		}
+	}
+	b
+}
+
+#[bench]
+fn bench_page_lookup(bn: &mut Bencher) {
+	let mut b = big_block(0x39600000000, 0);
+	let g = b.enter();
+	let start = g.b.addr.start;
+	let mut addr = start;
+	bn.iter(|| {
+		// what tripguard does to decide whether a fault is a first write
+		addr = start + ((addr - start + 0x7654321) & 0xfffffff);
+		let p = &g.b.pages[(addr - start) >> PAGESHIFT];
+		black_box(p.status.writable() && p.needs_trip())
+	});
+}
+
+#[bench]
+fn bench_state_size(bn: &mut Bencher) {
+	let mut b = big_block(0x39700000000, 1000);
+	let mut g = b.enter();
+	bn.iter(|| black_box(g.state_size()));
+}
+
+#[bench]
+fn bench_save_state(bn: &mut Bencher) {
+	let mut b = big_block(0x39800000000, 1000);
+	let mut g = b.enter();
+	let mut state = Vec::new();
+	bn.iter(|| {
+		state.clear();
+		g.save_state(&mut state).unwrap();
+	});
+}
+
+#[bench]
+fn bench_dirty_and_load(bn: &mut Bencher) {
+	let mut b = big_block(0x39900000000, 0);
+	let mut g = b.enter();
+	let mut state = Vec::new();
+	g.save_state(&mut state).unwrap();
+	let start = g.b.addr.start;
+	bn.iter(|| {
+		// a frame that writes 1MiB, each page of which faults, and rewinding it
+		for i in 0..256 {
+			unsafe { *((start + (i << PAGESHIFT)) as *mut u8) = 2; }
+		}
+		g.load_state(&mut &state[..]).unwrap();
+	});
+}
+
+#[bench]
+fn bench_protect(bn: &mut Bencher) {
+	let mut b = big_block(0x3a200000000, 0);
+	let mut g = b.enter();
+	let range = AddressRange { start: g.b.addr.start, size: 0x10000000 };
+	bn.iter(|| {
+		// every page the same way, so each is one run
+		g.mprotect(range, Protection::R).unwrap();
+		g.mprotect(range, Protection::RW).unwrap();
+	});
+}
+
+#[bench]
+fn bench_snapshot_page(bn: &mut Bencher) {
+	let src = vec![7u8; PAGESIZE];
+	// made and dropped over and over, so after the first it comes from the pool
+	bn.iter(|| black_box(PageBlock::try_copy(&src[..])));
+}
+
+/// Dirty one byte in each of 256 pages a MiB apart and load `state` over them, and count what that took:  Faults,
+/// protect calls and snapshot pages
+fn dirty_and_rewind(g: &mut ActivatedMemoryBlock, state: &[u8]) -> (u64, u64, u64) {
+	let start = g.b.addr.start;
+	let before = crate::counters::read();
+	for i in (0..0x10000000).step_by(0x100000) {
+		unsafe { *((start + i) as *mut u8) = 3; }
+	}
+	g.load_state(&mut &state[..]).unwrap();
+	let after = crate::counters::read();
+	(after.faults - before.faults, after.protect_calls - before.protect_calls, after.snapshot_pages - before.snapshot_pages)
+}
+
+#[bench]
+fn bench_fault_counts(bn: &mut Bencher) {
+	let mut b = big_block(0x3a300000000, 0);
+	let mut g = b.enter();
+	let mut state = Vec::new();
+	g.save_state(&mut state).unwrap();
+	let mut counts = (0, 0, 0);
+	bn.iter(|| counts = dirty_and_rewind(&mut g, &state));
+	// the counters are for the whole process, and tests running at the same time only add to them, so the fewest of a
+	// few more tries is what this really took
+	for _ in 0..8 {
+		let c = dirty_and_rewind(&mut g, &state);
+		counts = (counts.0.min(c.0), counts.1.min(c.1), counts.2.min(c.2));
+	}
+	// a regression in how much work dirtying and rewinding takes shows up here before it shows up in the time.  Each
+	// page faults once, which unprotects it, the load protects it again, and new pages need no snapshot to be copied.
+	assert_eq!(counts, (256, 512, 0), "faults, protect calls and snapshot pages for 256 pages dirtied and rewound");
+}
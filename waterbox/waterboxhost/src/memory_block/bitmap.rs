@@ -0,0 +1,81 @@
+// A bit for each page of a block, for finding the few pages something applies to without walking all of their Page
+// structs.  A 1GiB block has 262144 pages, which is 32KiB of bits, next to several MiB of Pages, and a word of bits
+// with nothing set is passed over all at once.
+use super::*;
+
+/// One bit per page
+#[derive(Debug)]
+pub struct Bitmap(Vec<u64>);
+
+impl Bitmap {
+	pub fn new(len: usize) -> Bitmap {
+		Bitmap(vec![0; len.div_ceil(64)])
+	}
+	pub fn get(&self, index: usize) -> bool {
+		self.0[index >> 6] & 1 << (index & 63) != 0
+	}
+	pub fn set(&mut self, index: usize) {
+		self.0[index >> 6] |= 1 << (index & 63);
+	}
+	pub fn clear(&mut self, index: usize) {
+		self.0[index >> 6] &= !(1 << (index & 63));
+	}
+	/// Set the bits of the pages in `addr`, which is in `block`
+	pub fn set_range(&mut self, block: AddressRange, addr: AddressRange) {
+		let first = (addr.start - block.start) >> PAGESHIFT;
+		for index in first..first + (addr.size >> PAGESHIFT) {
+			self.set(index);
+		}
+	}
+	/// The indices of the set bits, in order
+	pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+		self.0.iter().enumerate()
+			.filter(|(_, &w)| w != 0)
+			.flat_map(|(i, &w)| {
+				let mut w = w;
+				std::iter::from_fn(move || {
+					if w == 0 {
+						return None
+					}
+					let bit = w.trailing_zeros() as usize;
+					w &= w - 1;
+					Some(i << 6 | bit)
+				})
+			})
+	}
+	/// Clear the set bits that `keep` returns false for
+	pub fn retain(&mut self, mut keep: impl FnMut(usize) -> bool) {
+		for i in 0..self.0.len() {
+			let mut w = self.0[i];
+			while w != 0 {
+				let bit = w.trailing_zeros() as usize;
+				w &= w - 1;
+				if !keep(i << 6 | bit) {
+					self.0[i] &= !(1 << bit);
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_bitmap() {
+		let mut b = Bitmap::new(200);
+		assert_eq!(b.iter().count(), 0);
+		for &i in [0, 5, 63, 64, 130, 199].iter() {
+			b.set(i);
+		}
+		assert!(b.get(63) && b.get(64) && !b.get(65));
+		assert_eq!(b.iter().collect::<Vec<_>>(), vec![0, 5, 63, 64, 130, 199]);
+		b.clear(5);
+		b.retain(|i| i != 130);
+		assert_eq!(b.iter().collect::<Vec<_>>(), vec![0, 63, 64, 199]);
+		let block = AddressRange { start: 0x10000, size: 200 << PAGESHIFT };
+		b.set_range(block, AddressRange { start: 0x10000 + (100 << PAGESHIFT), size: 3 << PAGESHIFT });
+		assert_eq!(b.iter().collect::<Vec<_>>(), vec![0, 63, 64, 100, 101, 102, 199]);
+	}
+}
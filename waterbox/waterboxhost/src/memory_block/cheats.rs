@@ -0,0 +1,115 @@
+// Cheats that hold a value in guest memory.  The host applies all of them whenever it's asked to, which is usually
+// right after each frame.  Cheats that apply on write are also put back right after any guest write to them, by
+// watching their pages for writes the same way watchpoints do.
+use super::*;
+
+#[derive(Debug)]
+pub struct Cheat {
+	id: u32,
+	/// Never crosses a page
+	addr: AddressRange,
+	value: u64,
+	/// If Some, the cheat only applies while memory holds this value
+	compare: Option<u64>,
+	on_write: bool,
+}
+impl Cheat {
+	fn mask(&self) -> u64 {
+		if self.addr.size == 8 { !0 } else { (1 << (self.addr.size * 8)) - 1 }
+	}
+	/// If the cheat should change memory that currently holds `current`
+	fn wants(&self, current: u64) -> bool {
+		let value = self.value & self.mask();
+		current != value && !matches!(self.compare, Some(c) if c & self.mask() != current)
+	}
+}
+
+fn to_value(bytes: &[u8]) -> u64 {
+	let mut b = [0u8; 8];
+	b[..bytes.len()].copy_from_slice(bytes);
+	u64::from_le_bytes(b)
+}
+
+impl MemoryBlock {
+	/// Put back on-write cheats on a page the guest might have just written to, while it's still unwatched
+	/// unsafe: the page must be unwatched, as by tripguard::watch_trip()
+	pub(super) unsafe fn reapply_cheats(&mut self, page_addr: AddressRange) {
+		let index = (page_addr.start - self.addr.start) >> PAGESHIFT;
+		if !self.pages[index].dirty || !self.pages[index].status.writable() {
+			// not written since it was last unwatched, so whatever a cheat wrote is still there
+			return
+		}
+		for c in self.cheats.iter().filter(|c| c.on_write && page_addr.contains(c.addr.start)) {
+			let mem = c.addr.slice_mut();
+			if c.wants(to_value(mem)) {
+				if self.pages[index].cow_pending {
+					cow::preserve(&self.cow, index, page_addr.start, PageBlock::try_copy);
+					self.pages[index].cow_pending = false;
+				}
+				mem.copy_from_slice(&c.value.to_le_bytes()[..c.addr.size]);
+			}
+		}
+	}
+}
+
+/// If any of `cheats` apply on write to this page
+pub(super) fn has_write_cheat(cheats: &[Cheat], page_addr: AddressRange) -> bool {
+	cheats.iter().any(|c| c.on_write && page_addr.contains(c.addr.start))
+}
+
+impl<'block> ActivatedMemoryBlock<'block> {
+	/// Hold the `width` bytes at `addr` at `value`, little endian, or only while they're `compare` if that's given.
+	/// Returns an id for remove_cheat().  Cheats are only applied by apply_cheats(), and also right after every guest
+	/// write to them if `on_write`.
+	pub fn add_cheat(&mut self, addr: usize, width: usize, value: u64, compare: Option<u64>, on_write: bool) -> anyhow::Result<u32> {
+		if !matches!(width, 1 | 2 | 4 | 8) {
+			return Err(coded(ErrorCode::InvalidArgument, format!("Bad cheat width {}", width)))
+		}
+		if on_write {
+			self.b.check_watch_supported("Cheats that apply on write")?;
+		}
+		let addr = AddressRange { start: addr, size: width };
+		if addr.start < self.b.addr.start || addr.start > self.b.addr.end() - width {
+			return Err(coded(ErrorCode::UnmappedAddress, "Cheat must be inside the MemoryBlock"))
+		}
+		if align_down(addr.start) != align_down(addr.end() - 1) {
+			return Err(coded(ErrorCode::InvalidArgument, "Cheat can't cross a page boundary"))
+		}
+		let id = self.b.next_cheat_id;
+		self.b.next_cheat_id += 1;
+		self.b.cheats.push(Cheat {
+			id,
+			addr,
+			value,
+			compare,
+			on_write,
+		});
+		if on_write {
+			self.b.update_watch(addr);
+		}
+		Ok(id)
+	}
+	pub fn remove_cheat(&mut self, id: u32) -> anyhow::Result<()> {
+		match self.b.cheats.iter().position(|c| c.id == id) {
+			Some(index) => {
+				let c = self.b.cheats.remove(index);
+				if c.on_write {
+					self.b.update_watch(c.addr);
+				}
+				Ok(())
+			},
+			None => Err(coded(ErrorCode::NotFound, format!("No cheat with id {}", id)))
+		}
+	}
+	/// Apply every cheat whose memory the guest can currently write.  Memory that already holds a cheat's value
+	/// isn't touched, so it isn't dirtied.
+	pub fn apply_cheats(&mut self) {
+		for index in 0..self.b.cheats.len() {
+			let (addr, value) = (self.b.cheats[index].addr, self.b.cheats[index].value);
+			let mut current = [0u8; 8];
+			if self.read(addr.start, &mut current[..addr.size]) == addr.size && self.b.cheats[index].wants(to_value(&current)) {
+				self.write(addr.start, &value.to_le_bytes()[..addr.size]);
+			}
+		}
+	}
+}
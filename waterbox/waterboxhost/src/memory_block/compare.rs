@@ -0,0 +1,110 @@
+// Comparing the guest memory in two states, for tracking down desyncs.  Both states are read side by side, a page at a
+// time, and neither is loaded, so the block is left as it was.  A page that isn't in a state gets whatever it had when
+// the block was sealed when that state is loaded, so that's what it's compared as.
+use super::*;
+
+/// One page that two states disagree about
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PageDiff {
+	pub addr: usize,
+	/// How many of its bytes differ
+	pub bytes: u32,
+	/// Whether it's mapped differently
+	pub status_changed: bool,
+}
+
+/// How many bytes differ between `a` and `b`, counting any that one has past the end of the other
+pub fn count_differences(a: &[u8], b: &[u8]) -> usize {
+	let longest = std::cmp::max(a.len(), b.len());
+	let shortest = std::cmp::min(a.len(), b.len());
+	a.iter().zip(b.iter()).filter(|(x, y)| x != y).count() + longest - shortest
+}
+
+impl<'block> ActivatedMemoryBlock<'block> {
+	/// What page `index` had when the block was sealed
+	fn read_original(&self, index: usize, dest: &mut [u8]) -> anyhow::Result<()> {
+		let p = &self.b.pages[index];
+		match &p.snapshot {
+			Snapshot::ZeroFilled => dest.iter_mut().for_each(|b| *b = 0),
+			Snapshot::Data(d) => pagecmp::copy(dest, d.slice()),
+			// dirty pages always have snapshots, so this one hasn't changed
+			Snapshot::None => unsafe {
+				let paddr = AddressRange { start: self.b.addr.start + (index << PAGESHIFT), size: PAGESIZE };
+				if !p.host_readable() && !self.b.host_protect(paddr, Protection::R) {
+					return self.check_poisoned()
+				}
+				pagecmp::copy(dest, paddr.slice());
+				if !p.host_readable() {
+					self.b.apply_protections(paddr);
+				}
+			},
+		}
+		Ok(())
+	}
+	/// Read the memory sections of two states side by side, and list the pages they differ on
+	pub fn compare_states(&mut self, a: &mut dyn Read, b: &mut dyn Read) -> anyhow::Result<Vec<PageDiff>> {
+		if !self.b.sealed {
+			return Err(coded(ErrorCode::BadState, "Must seal first"))
+		}
+		self.check_poisoned()?;
+		self.b.get_stack_dirty();
+		let pages_a = self.read_state_header(a)?;
+		let pages_b = self.read_state_header(b)?;
+		let mut page_a = vec![0u8; PAGESIZE];
+		let mut page_b = vec![0u8; PAGESIZE];
+		let mut res = Vec::new();
+		for index in 0..self.b.pages.len() {
+			let p = &self.b.pages[index];
+			if p.immutable {
+				continue
+			}
+			let in_a = pages_a.dirtii[index] && !p.invisible;
+			let in_b = pages_b.dirtii[index] && !p.invisible;
+			if in_a {
+				a.read_exact(&mut page_a[..])?;
+			}
+			if in_b {
+				b.read_exact(&mut page_b[..])?;
+			}
+			// loading either state zeroes transient pages
+			let bytes = if p.transient || !(in_a || in_b) {
+				0
+			} else {
+				if !in_a {
+					self.read_original(index, &mut page_a[..])?;
+				}
+				if !in_b {
+					self.read_original(index, &mut page_b[..])?;
+				}
+				count_differences(&page_a[..], &page_b[..])
+			};
+			let status_changed = pages_a.statii[index] != pages_b.statii[index];
+			if bytes != 0 || status_changed {
+				res.push(PageDiff {
+					addr: self.b.addr.start + (index << PAGESHIFT),
+					bytes: bytes as u32,
+					status_changed,
+				});
+			}
+		}
+		Ok(res)
+	}
+	/// Read the memory section of a state as loading it would, but without loading any of it, and return how many
+	/// pages of guest memory it has
+	pub fn check_state(&mut self, stream: &mut dyn Read) -> anyhow::Result<usize> {
+		if !self.b.sealed {
+			return Err(coded(ErrorCode::BadState, "Must seal first"))
+		}
+		self.check_poisoned()?;
+		let pages = self.read_state_header(stream)?;
+		let count = self.b.pages.iter().zip(pages.dirtii.iter())
+			.filter(|&(p, &dirty)| dirty && !p.invisible && !p.immutable)
+			.count();
+		let size = (count << PAGESHIFT) as u64;
+		if std::io::copy(&mut stream.take(size), &mut std::io::sink())? != size {
+			return Err(coded(ErrorCode::BadStateData, "Savestate ends partway through guest memory"))
+		}
+		Ok(count)
+	}
+}
@@ -0,0 +1,164 @@
+// CowSnapshot:  Captures a consistent view of a MemoryBlock's savestate without copying everything up front.
+// Pages that would be in the state are write protected, and the first write to each one after the snapshot
+// is taken copies out the old content before letting the write proceed.  Anything not yet written by then
+// is read straight out of guest memory when the snapshot is serialized.
+use super::*;
+use std::io::Write;
+
+#[derive(Debug)]
+pub enum CowPage {
+	/// Not part of the state, or already consumed by the snapshot
+	Skip,
+	/// Content is still in guest memory, unchanged since the snapshot was taken
+	Pending,
+	/// Content was copied out before the guest changed it
+	Taken(PageBlock),
+}
+
+/// Copy out a page's content for the snapshot with `copy`, if the snapshot still needs it.
+/// unsafe: page must be mapped and readable
+pub(super) unsafe fn preserve(cow: &Option<Arc<Mutex<Vec<CowPage>>>>, index: usize, addr: usize, copy: fn(&[u8]) -> Option<PageBlock>) {
+	if let Some(c) = cow {
+		let mut pages = c.lock().unwrap();
+		if let CowPage::Pending = pages[index] {
+			match copy(std::slice::from_raw_parts(addr as *const u8, PAGESIZE)) {
+				Some(pb) => pages[index] = CowPage::Taken(pb),
+				None => panic!("PageBlock could not allocate memory!"),
+			}
+		}
+	}
+}
+
+impl MemoryBlock {
+	/// Preserve content for the outstanding CowSnapshot, if any, in a range that is about to be modified by the host.
+	/// Must be called before any operation that might change the contents or readability of pages.
+	pub(super) fn resolve_cow(&mut self, addr: AddressRange) {
+		if self.cow.is_none() {
+			return
+		}
+		let pstart = (addr.start - self.addr.start) >> PAGESHIFT;
+		let pend = (addr.end() - self.addr.start) >> PAGESHIFT;
+		for index in pstart..pend {
+			let page = &mut self.pages[index];
+			if page.cow_pending {
+				unsafe { preserve(&self.cow, index, self.addr.start + (index << PAGESHIFT), PageBlock::try_copy); }
+				page.cow_pending = false;
+			}
+		}
+	}
+	pub(super) fn resolve_cow_all(&mut self) {
+		let addr = self.addr;
+		self.resolve_cow(addr);
+		self.cow = None;
+	}
+	/// resolve_cow(), but tolerates invalid ranges, which will be rejected by the caller anyway
+	pub(super) fn resolve_cow_checked(&mut self, addr: AddressRange) {
+		if addr.start >= self.addr.start && addr.checked_end().filter(|&end| end <= self.addr.end()).is_some() && addr.size > 0 {
+			self.resolve_cow(addr.align_expand());
+		}
+	}
+}
+
+/// A consistent copy of a MemoryBlock's savestate at one point in time, which can be serialized later
+/// on any thread, while the guest keeps running.
+pub struct CowSnapshot {
+	hash: Vec<u8>,
+	addr: AddressRange,
+	statii: Vec<PageAllocation>,
+	dirtii: Vec<bool>,
+	aslr: Option<u64>,
+	pages: Arc<Mutex<Vec<CowPage>>>,
+}
+
+impl<'block> ActivatedMemoryBlock<'block> {
+	/// Capture the current state for later serialization with CowSnapshot::save_state().  Any snapshot
+	/// that was previously outstanding is preserved in full first.
+	pub fn cow_snapshot(&mut self) -> anyhow::Result<CowSnapshot> {
+		if !self.b.sealed {
+			return Err(coded(ErrorCode::BadState, "Must seal first"))
+		}
+		self.check_poisoned()?;
+		self.b.get_stack_dirty();
+		self.b.resolve_cow_all();
+
+		let mut statii = Vec::with_capacity(self.b.pages.len());
+		let mut dirtii = Vec::with_capacity(self.b.pages.len());
+		let mut pages = Vec::with_capacity(self.b.pages.len());
+		let mut failure = None;
+		for index in 0..self.b.pages.len() {
+			let paddr = AddressRange { start: self.b.addr.start + (index << PAGESHIFT), size: PAGESIZE };
+			// put back by the refresh below
+			if !self.b.pages[index].host_readable() && self.b.pages[index].in_state() && unsafe { !self.b.host_protect(paddr, Protection::R) } {
+				failure = Some(self.check_poisoned().unwrap_err());
+				break
+			}
+			let p = &mut self.b.pages[index];
+			statii.push(p.status);
+			dirtii.push(p.saved_dirty());
+			pages.push(if !p.in_state() {
+				CowPage::Skip
+			} else if p.host_readable() && p.status != PageAllocation::Allocated(Protection::RWStack) {
+				p.cow_pending = true;
+				CowPage::Pending
+			} else {
+				// can't be read from another thread later, or writes to it might not be caught, so take it now
+				match PageBlock::try_new() {
+					Some(mut pb) => {
+						unsafe { pagecmp::copy(pb.slice_mut(), paddr.slice()); }
+						CowPage::Taken(pb)
+					},
+					None => {
+						failure = Some(anyhow::Error::new(OutOfMemory));
+						break
+					},
+				}
+			});
+		}
+		if let Some(e) = failure {
+			// leave everything as it was
+			for (i, p) in pages.iter().enumerate() {
+				if let CowPage::Pending = p {
+					self.b.pages[i].cow_pending = false;
+				}
+			}
+			self.b.refresh_all_protections();
+			return Err(e)
+		}
+		let pages = Arc::new(Mutex::new(pages));
+		self.b.cow = Some(pages.clone());
+		self.b.refresh_all_protections();
+		Ok(CowSnapshot {
+			hash: self.b.hash.clone(),
+			addr: self.b.addr,
+			statii,
+			dirtii,
+			aslr: self.b.aslr,
+			pages,
+		})
+	}
+}
+
+impl CowSnapshot {
+	/// Write out the state, in exactly the same format as ActivatedMemoryBlock::save_state would have at the
+	/// time the snapshot was taken.  Can only be done once.
+	pub fn save_state(&mut self, stream: &mut dyn Write) -> anyhow::Result<()> {
+		write_state_header(stream, &self.hash[..], self.addr, &self.statii[..], &self.dirtii[..], self.aslr)?;
+		let mut buf = vec![0u8; PAGESIZE];
+		for index in 0..self.statii.len() {
+			{
+				let mut pages = self.pages.lock().unwrap();
+				match std::mem::replace(&mut pages[index], CowPage::Skip) {
+					CowPage::Skip => continue,
+					CowPage::Pending => unsafe {
+						// the mutex keeps the guest from being allowed to write here until we're done
+						let src = self.addr.start + (index << PAGESHIFT);
+						pagecmp::copy(&mut buf[..], std::slice::from_raw_parts(src as *const u8, PAGESIZE));
+					},
+					CowPage::Taken(pb) => pagecmp::copy(&mut buf[..], pb.slice()),
+				}
+			}
+			stream.write_all(&buf[..])?;
+		}
+		Ok(())
+	}
+}
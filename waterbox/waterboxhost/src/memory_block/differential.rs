@@ -0,0 +1,155 @@
+// Differential fuzzing of the dirty tracking backends, for tests.  Two blocks at the same address, each with its own
+// backend, get the same random mix of mappings, protection changes, writes through real faults, cleaning, and states
+// saved and loaded again, taking turns being active after every step.  Every step has to turn out the same on both, and
+// so do the states:  Byte for byte between backends that write protect, and between those and Eager, which takes every
+// writable page as dirty, in the guest memory they hold.  Last of all, each block has to load the other's state.
+use super::*;
+use crate::fs::Rng;
+
+/// How big the blocks are
+const PAGES: usize = 64;
+/// How many states are kept to go back to
+const CHECKPOINTS: usize = 4;
+const PROTECTIONS: &[Protection] = &[Protection::None, Protection::R, Protection::RW, Protection::RX, Protection::RWX, Protection::RWStack];
+
+/// One step, done to both blocks
+#[derive(Debug, Clone)]
+enum Op {
+	Map(AddressRange, Protection),
+	Protect(AddressRange, Protection),
+	Unmap(AddressRange),
+	/// Write this at that address, if it's all writable
+	Write(usize, Vec<u8>),
+	Clean,
+	/// Save a state, as checkpoint .0
+	Save(usize),
+	/// Load checkpoint .0, if there is one
+	Load(usize),
+}
+
+fn random_op(rng: &mut Rng, addr: AddressRange) -> Op {
+	let mut below = |n: usize| (rng.next() % n as u64) as usize;
+	let first = below(PAGES);
+	let range = AddressRange { start: addr.start + (first << PAGESHIFT), size: std::cmp::min(1 + below(8), PAGES - first) << PAGESHIFT };
+	let prot = PROTECTIONS[below(PROTECTIONS.len())];
+	match below(16) {
+		0 | 1 => Op::Map(range, prot),
+		2 | 3 => Op::Protect(range, prot),
+		4 => Op::Unmap(range),
+		5 => Op::Clean,
+		6 => Op::Save(below(CHECKPOINTS)),
+		7 => Op::Load(below(CHECKPOINTS)),
+		n => {
+			let offset = below(PAGESIZE);
+			let len = 1 + below(PAGESIZE - offset);
+			// sometimes putting back what a page had to start with, so there's something to clean
+			let data = if n == 8 { vec![0; len] } else { (0..len).map(|_| rng.next() as u8).collect() };
+			Op::Write(range.start + offset, data)
+		},
+	}
+}
+
+/// What a step did, to compare between the two
+#[derive(Debug, PartialEq, Eq)]
+enum Outcome {
+	Syscall(SyscallResult),
+	Wrote(bool),
+	Saved(Vec<u8>),
+	Loaded,
+	Nothing,
+}
+
+struct Side {
+	b: Box<MemoryBlock>,
+	checkpoints: Vec<Option<Vec<u8>>>,
+}
+impl Side {
+	fn step(&mut self, op: &Op) -> anyhow::Result<Outcome> {
+		let mut g = self.b.enter();
+		Ok(match op {
+			Op::Map(range, prot) => Outcome::Syscall(g.mmap_fixed(*range, *prot, false)),
+			Op::Protect(range, prot) => Outcome::Syscall(g.mprotect(*range, *prot)),
+			Op::Unmap(range) => Outcome::Syscall(g.munmap(*range)),
+			Op::Write(addr, data) => {
+				let index = (addr - g.b.addr.start) >> PAGESHIFT;
+				let writable = g.b.pages[index].status.writable();
+				if writable {
+					unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), *addr as *mut u8, data.len()) }
+				}
+				Outcome::Wrote(writable)
+			},
+			Op::Clean => {
+				g.clean_unchanged_pages();
+				Outcome::Nothing
+			},
+			Op::Save(slot) => {
+				let mut state = Vec::new();
+				g.save_state(&mut state)?;
+				self.checkpoints[*slot] = Some(state.clone());
+				Outcome::Saved(state)
+			},
+			Op::Load(slot) => match &self.checkpoints[*slot] {
+				Some(state) => {
+					g.load_state(&mut &state[..])?;
+					Outcome::Loaded
+				},
+				None => Outcome::Nothing,
+			},
+		})
+	}
+	/// What each page is mapped as, and a hash of what's in it if it's readable
+	fn contents(&mut self) -> Vec<(PageAllocation, Option<u64>)> {
+		let g = self.b.enter();
+		g.b.page_range().iter_with_addr()
+			.map(|(paddr, p)| (p.status, if p.status.readable() { Some(unsafe { xxh3::xxh3_64(paddr.slice()) }) } else { None }))
+			.collect()
+	}
+}
+
+/// Put blocks at `addr` with backends `a` and `b` through `count` random steps from `seed`, and check that they do the
+/// same thing all the way.  The first difference found is returned.
+pub fn fuzz_tracking(a: DirtyTracking, b: DirtyTracking, addr: AddressRange, seed: u64, count: usize) -> anyhow::Result<()> {
+	let addr = AddressRange { start: addr.start, size: PAGES << PAGESHIFT };
+	let exact = a != DirtyTracking::Eager && b != DirtyTracking::Eager;
+	let mut sides = [a, b].iter().map(|&tracking| {
+		let mut side = Side { b: MemoryBlock::with_tracking(addr, tracking), checkpoints: vec![None; CHECKPOINTS] };
+		{
+			let mut g = side.b.enter();
+			g.mmap_fixed(AddressRange { start: addr.start, size: addr.size / 2 }, Protection::RW, false)?;
+			g.seal();
+		}
+		Ok(side)
+	}).collect::<anyhow::Result<Vec<_>>>()?;
+	let mut rng = Rng::new(seed);
+	for i in 0..count {
+		let op = random_op(&mut rng, addr);
+		let what = |e: anyhow::Error| anyhow!("{:?} and {:?}, seed {}, step {}, {:x?}:  {}", a, b, seed, i, op, e);
+		let ra = sides[0].step(&op).map_err(what)?;
+		let rb = sides[1].step(&op).map_err(what)?;
+		match (&ra, &rb) {
+			(Outcome::Saved(sa), Outcome::Saved(sb)) if exact && sa != sb => return Err(what(anyhow!("The states are different"))),
+			(Outcome::Saved(_), Outcome::Saved(_)) => (),
+			_ if ra != rb => return Err(what(anyhow!("{:?} on one, but {:?} on the other", ra, rb))),
+			_ => (),
+		}
+		if let Op::Save(_) | Op::Load(_) = op {
+			if sides[0].contents() != sides[1].contents() {
+				return Err(what(anyhow!("Guest memory is different")))
+			}
+		}
+	}
+	// states from one backend have to load with the other
+	let mut states = Vec::new();
+	for side in sides.iter_mut() {
+		let mut state = Vec::new();
+		side.b.enter().save_state(&mut state)?;
+		states.push(state);
+	}
+	let before = sides[0].contents();
+	sides[0].b.enter().load_state(&mut &states[1][..])?;
+	sides[1].b.enter().load_state(&mut &states[0][..])?;
+	if sides[0].contents() != before || sides[1].contents() != before {
+		return Err(anyhow!("{:?} and {:?}, seed {}:  Guest memory is different after loading each other's states", a, b, seed))
+	}
+	Ok(())
+}
@@ -0,0 +1,130 @@
+// Page access heat map:  Every guest call is a sampling period.  When one starts, all readable guest pages are armed,
+// which takes their native protection away, so the first access to each one faults, and tripguard counts it.  A page
+// that was only read stays armed for writes until the call is over, the same way dirty tracking write protects, so a
+// later write is counted too.  Pages sharing a host page are counted together.  Nothing here is in states.
+use super::*;
+
+/// Not armed
+pub const HEAT_OFF: u8 = 0;
+/// Any access faults
+pub const HEAT_ANY: u8 = 1;
+/// Only writes fault
+pub const HEAT_WRITE: u8 = 2;
+
+/// How often the guest used one page
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PageHeat {
+	/// Sampling periods in which the page was read, written or run
+	pub accessed: u32,
+	/// Sampling periods in which the page was written
+	pub written: u32,
+}
+
+/// What wbx_get_heat_map() reports, along with a PageHeat for every page
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct HeatMapInfo {
+	/// Address of the first page
+	pub start: usize,
+	pub pages: usize,
+	/// Sampling periods so far
+	pub samples: u64,
+}
+
+#[derive(Debug)]
+pub struct HeatMap {
+	pages: Vec<PageHeat>,
+	samples: u64,
+}
+
+/// Reduce a native protection so that accesses an armed page is waiting for will fault
+pub fn heat_prot(prot: Protection, heat: u8) -> Protection {
+	match heat {
+		HEAT_ANY => Protection::None,
+		HEAT_WRITE => watch::watch_prot(prot, WATCH_WRITE),
+		_ => prot,
+	}
+}
+
+impl MemoryBlock {
+	/// Count an access that faulted on an armed page, and disarm the host page holding it as far as that access needs.
+	/// Returns false if the page wasn't armed for it.
+	pub(super) fn heat_trip(&mut self, addr: usize, write: bool) -> bool {
+		let index = (addr - self.addr.start) >> PAGESHIFT;
+		let armed = match self.pages[index].heat {
+			HEAT_ANY => true,
+			HEAT_WRITE => write,
+			_ => false,
+		};
+		let host_addr = self.host_expand(AddressRange { start: addr, size: 1 });
+		let counts = match &mut self.heat {
+			Some(h) if armed => &mut h.pages,
+			_ => return false,
+		};
+		let first = (host_addr.start - self.addr.start) >> PAGESHIFT;
+		let last = std::cmp::min(first + (host_addr.size >> PAGESHIFT), self.pages.len());
+		for (p, c) in self.pages[first..last].iter_mut().zip(counts[first..last].iter_mut()) {
+			if p.heat == HEAT_ANY {
+				c.accessed += 1;
+			}
+			if write && p.heat != HEAT_OFF {
+				c.written += 1;
+				p.heat = HEAT_OFF;
+			} else if p.heat == HEAT_ANY {
+				p.heat = if p.status.writable() { HEAT_WRITE } else { HEAT_OFF };
+			}
+		}
+		self.refresh_protections(host_addr);
+		true
+	}
+}
+
+impl<'block> ActivatedMemoryBlock<'block> {
+	/// Start or stop gathering a heat map.  Stopping forgets what was gathered.
+	pub fn set_heat_map(&mut self, enabled: bool) -> anyhow::Result<()> {
+		if enabled && self.b.sanitizer {
+			return Err(coded(ErrorCode::Unsupported, "Heat maps need fault handlers, which sanitizer mode doesn't have"))
+		}
+		if !enabled {
+			self.disarm_heat_map();
+			self.b.heat = None;
+		} else if self.b.heat.is_none() {
+			self.b.heat = Some(HeatMap { pages: vec![PageHeat::default(); self.b.pages.len()], samples: 0 });
+		}
+		Ok(())
+	}
+	/// Start a sampling period, if gathering a heat map
+	pub fn arm_heat_map(&mut self) {
+		match &mut self.b.heat {
+			Some(h) => h.samples += 1,
+			None => return,
+		}
+		// a CowSnapshot can't read pages that are about to become unreadable
+		self.b.resolve_cow_all();
+		for p in self.b.pages.iter_mut() {
+			p.heat = if p.status.readable() { HEAT_ANY } else { HEAT_OFF };
+		}
+		self.b.refresh_all_protections();
+	}
+	/// End a sampling period
+	pub fn disarm_heat_map(&mut self) {
+		if self.b.heat.is_none() {
+			return
+		}
+		for p in self.b.pages.iter_mut() {
+			p.heat = HEAT_OFF;
+		}
+		self.b.refresh_all_protections();
+	}
+	/// The heat map gathered so far, if there is one, with a PageHeat for each page
+	pub fn heat_map(&self) -> Option<(HeatMapInfo, &[PageHeat])> {
+		self.b.heat.as_ref().map(|h| (HeatMapInfo { start: self.b.addr.start, pages: h.pages.len(), samples: h.samples }, &h.pages[..]))
+	}
+	pub fn reset_heat_map(&mut self) {
+		if let Some(h) = &mut self.b.heat {
+			h.pages.iter_mut().for_each(|p| *p = PageHeat::default());
+			h.samples = 0;
+		}
+	}
+}
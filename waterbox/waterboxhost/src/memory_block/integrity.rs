@@ -0,0 +1,278 @@
+// Background integrity checking, for machines with flaky RAM or an overclock that isn't as stable as it looks, where
+// emulation bugs that can't happen turn out to be a bit that flipped.  A low priority thread goes over the guest pages
+// that are readonly to the guest, a few at a time, hashing each through a host view and comparing that with what it
+// hashed to when it last became readonly.  The host keeps those hashes up to date around everything that can rightly
+// change such a page, or take it away.  After each full pass, the next activation also checks the page table for
+// things that can't happen, like native protections other than the ones the host asked for.  Anything wrong is
+// reported to a callback.  Nothing here is in states.
+use super::*;
+use std::sync::Condvar;
+use std::sync::atomic::AtomicU64;
+use std::time::Duration;
+
+/// A readonly page's content changed
+pub const INTEGRITY_PAGE: u32 = 1;
+/// The host's bookkeeping for a page is wrong
+pub const INTEGRITY_TABLE: u32 = 2;
+
+/// Called with INTEGRITY_* and the page it's about, from the checking thread for INTEGRITY_PAGE, or from whichever
+/// thread is activating the block for INTEGRITY_TABLE.  It must not call back into waterbox.
+pub type IntegrityCallback = extern fn(userdata: usize, kind: u32, addr: usize);
+
+/// How long the checking thread sleeps between batches
+const TICK: Duration = Duration::from_millis(100);
+const TICKS_PER_SECOND: usize = 10;
+
+/// What the checker has done so far
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct IntegrityInfo {
+	/// Pages being checked now
+	pub pages: usize,
+	/// Full passes over them
+	pub passes: u64,
+	pub failures: u64,
+}
+
+#[derive(Debug)]
+struct Table {
+	/// For each page of the block, what its content hashes to, if it's checked
+	hashes: Vec<Option<u64>>,
+	/// Bumped whenever the host changes the hashes, so the thread can tell a page that rightly changed while it was
+	/// hashing it from one that's corrupt
+	generation: u64,
+}
+
+#[derive(Debug)]
+struct Shared {
+	table: Mutex<Table>,
+	stop: Mutex<bool>,
+	wake: Condvar,
+	passes: AtomicU64,
+	failures: AtomicU64,
+	/// Set after each pass, until the page table has been checked
+	tables_due: AtomicBool,
+	callback: IntegrityCallback,
+	userdata: usize,
+}
+impl Shared {
+	fn report(&self, kind: u32, addr: usize) {
+		self.failures.fetch_add(1, Ordering::Relaxed);
+		(self.callback)(self.userdata, kind, addr);
+	}
+}
+
+#[derive(Debug)]
+pub struct IntegrityChecker {
+	shared: Arc<Shared>,
+	/// Of the whole block
+	view: AddressRange,
+	thread: Option<std::thread::JoinHandle<()>>,
+}
+impl Drop for IntegrityChecker {
+	fn drop(&mut self) {
+		*self.shared.stop.lock().unwrap() = true;
+		self.shared.wake.notify_all();
+		if let Some(thread) = self.thread.take() {
+			let _ = thread.join();
+		}
+		unsafe { let _ = unmap_host_view(self.view); }
+	}
+}
+
+fn page_hash(view: AddressRange, index: usize) -> u64 {
+	unsafe { xxh3::xxh3_64(AddressRange { start: view.start + (index << PAGESHIFT), size: PAGESIZE }.slice()) }
+}
+
+/// The checking thread, for the block that starts at `start`, doing `batch` pages a tick
+fn run(shared: Arc<Shared>, view: AddressRange, start: usize, batch: usize) {
+	// nice is per thread on linux, so this only takes idle time from the rest of the process
+	#[cfg(target_os = "linux")]
+	unsafe { libc::setpriority(libc::PRIO_PROCESS, libc::syscall(libc::SYS_gettid) as libc::id_t, 19); }
+	let mut next = 0;
+	loop {
+		{
+			let stop = shared.stop.lock().unwrap();
+			if *stop {
+				return
+			}
+			let (stop, _) = shared.wake.wait_timeout(stop, TICK).unwrap();
+			if *stop {
+				return
+			}
+		}
+		let (work, generation) = {
+			let table = shared.table.lock().unwrap();
+			let len = table.hashes.len();
+			let mut work = Vec::with_capacity(batch);
+			let mut seen = 0;
+			while work.len() < batch && seen < len {
+				if let Some(hash) = table.hashes[next] {
+					work.push((next, hash));
+				}
+				next += 1;
+				seen += 1;
+				if next == len {
+					next = 0;
+					shared.passes.fetch_add(1, Ordering::Relaxed);
+					shared.tables_due.store(true, Ordering::Relaxed);
+				}
+			}
+			(work, table.generation)
+		};
+		for (index, expected) in work {
+			if page_hash(view, index) == expected {
+				continue
+			}
+			let mut table = shared.table.lock().unwrap();
+			// a page the host changed in the meantime is checked again on the next pass
+			if table.generation == generation && table.hashes[index] == Some(expected) {
+				// reported once, not every pass
+				table.hashes[index] = None;
+				drop(table);
+				shared.report(INTEGRITY_PAGE, start + (index << PAGESHIFT));
+			}
+		}
+	}
+}
+
+impl MemoryBlock {
+	/// The pages of the block that `addr` overlaps
+	fn integrity_pages(&self, addr: AddressRange) -> std::ops::Range<usize> {
+		let start = std::cmp::max(addr.start, self.addr.start);
+		let end = std::cmp::min(addr.checked_end().unwrap_or(usize::MAX), self.addr.end());
+		if start >= end {
+			return 0..0
+		}
+		((start - self.addr.start) >> PAGESHIFT)..(align_up(end - self.addr.start) >> PAGESHIFT)
+	}
+	/// Stop checking the pages `addr` overlaps, before something changes them
+	pub(super) fn integrity_forget(&self, addr: AddressRange) {
+		let pages = self.integrity_pages(addr);
+		self.integrity_forget_pages(pages);
+	}
+	pub(super) fn integrity_forget_pages(&self, indices: impl Iterator<Item = usize>) {
+		let c = match &self.integrity {
+			Some(c) => c,
+			None => return,
+		};
+		let mut table = c.shared.table.lock().unwrap();
+		for index in indices {
+			table.hashes[index] = None;
+		}
+		table.generation += 1;
+	}
+	/// Check the pages `addr` overlaps that are readonly now, as they are now
+	pub(super) fn integrity_learn(&self, addr: AddressRange) {
+		let pages = self.integrity_pages(addr);
+		self.integrity_learn_pages(pages);
+	}
+	pub(super) fn integrity_learn_pages(&self, indices: impl Iterator<Item = usize>) {
+		let c = match &self.integrity {
+			Some(c) => c,
+			None => return,
+		};
+		let mut table = c.shared.table.lock().unwrap();
+		for index in indices {
+			let p = &self.pages[index];
+			// pages that were never written might not be committed yet, and reading them would commit them
+			let checked = p.status.readable() && !p.status.writable() && !p.from_file && !p.known_zero();
+			table.hashes[index] = if checked { Some(page_hash(c.view, index)) } else { None };
+		}
+		table.generation += 1;
+	}
+	/// Pages whose bookkeeping is wrong, given what the checker has for them
+	fn table_problems(&self, hashes: &[Option<u64>]) -> Vec<usize> {
+		let mut res = Vec::new();
+		let per_host = self.host_page >> PAGESHIFT;
+		for (index, p) in self.pages.iter().enumerate() {
+			let readonly = p.status.readable() && !p.status.writable();
+			let ok = (!p.immutable || readonly)
+				&& (!p.cow_pending || self.cow.is_some())
+				&& (!p.from_file || !self.handle_shared)
+				&& (hashes[index].is_none() || readonly);
+			if !ok {
+				res.push(self.addr.start + (index << PAGESHIFT));
+			}
+		}
+		// what each host page was last given has to be what it should have now, whenever the host isn't in the middle
+		// of changing it
+		for (index, applied) in self.applied.0.iter().enumerate() {
+			let first = index * per_host;
+			let pages = &self.pages[first..std::cmp::min(first + per_host, self.pages.len())];
+			match applied.get() {
+				Some(prot) if prot != host_native_state(pages, self.tracking).0 => res.push(self.addr.start + index * self.host_page),
+				_ => (),
+			}
+		}
+		if self.pages.iter().filter(|p| p.from_file).count() != self.state_file_pages {
+			res.push(self.addr.start);
+		}
+		res
+	}
+	/// Check the page table, if a pass has finished since it last was
+	pub(super) fn integrity_check_tables(&self) {
+		let c = match &self.integrity {
+			Some(c) if c.shared.tables_due.swap(false, Ordering::Relaxed) => c,
+			_ => return,
+		};
+		let problems = {
+			let table = c.shared.table.lock().unwrap();
+			self.table_problems(&table.hashes[..])
+		};
+		for addr in problems {
+			c.shared.report(INTEGRITY_TABLE, addr);
+		}
+	}
+}
+
+impl<'block> ActivatedMemoryBlock<'block> {
+	/// Start checking readonly guest pages in the background, `pages_per_second` at most, reporting anything wrong to
+	/// `callback`.  Starting again starts over.
+	pub fn start_integrity_check(&mut self, pages_per_second: usize, callback: IntegrityCallback, userdata: usize) -> anyhow::Result<()> {
+		if pages_per_second == 0 {
+			return Err(coded(ErrorCode::InvalidArgument, "The integrity checker has to check at least one page a second"))
+		}
+		self.b.integrity = None;
+		let all = self.b.addr;
+		let view = self.map_host_view(all)?;
+		let shared = Arc::new(Shared {
+			table: Mutex::new(Table { hashes: vec![None; self.b.pages.len()], generation: 0 }),
+			stop: Mutex::new(false),
+			wake: Condvar::new(),
+			passes: AtomicU64::new(0),
+			failures: AtomicU64::new(0),
+			tables_due: AtomicBool::new(false),
+			callback,
+			userdata,
+		});
+		self.b.integrity = Some(IntegrityChecker { shared: shared.clone(), view, thread: None });
+		self.b.integrity_learn(all);
+		let batch = std::cmp::max(1, pages_per_second / TICKS_PER_SECOND);
+		let start = all.start;
+		let thread = std::thread::Builder::new()
+			.name("waterbox integrity checker".to_string())
+			.spawn(move || run(shared, view, start, batch));
+		match thread {
+			Ok(thread) => {
+				self.b.integrity.as_mut().unwrap().thread = Some(thread);
+				Ok(())
+			},
+			Err(e) => {
+				self.b.integrity = None;
+				Err(e.into())
+			},
+		}
+	}
+	pub fn stop_integrity_check(&mut self) {
+		self.b.integrity = None;
+	}
+	/// None if the checker isn't running
+	pub fn integrity_info(&self) -> Option<IntegrityInfo> {
+		self.b.integrity.as_ref().map(|c| IntegrityInfo {
+			pages: c.shared.table.lock().unwrap().hashes.iter().filter(|h| h.is_some()).count(),
+			passes: c.shared.passes.load(Ordering::Relaxed),
+			failures: c.shared.failures.load(Ordering::Relaxed),
+		})
+	}
+}
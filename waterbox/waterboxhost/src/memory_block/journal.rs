@@ -0,0 +1,104 @@
+// Guest memory's part of a journal entry.  An entry has the same state header a savestate does and says which pages
+// the state has, but only holds the ones that changed since the entry before, found by comparing a quick hash of each
+// page that dirty tracking says is in the state against what it was then.  Everything but the guest writing to its
+// memory, like the host writing to it or mapping over it, is caught that way too.  Putting a state back together
+// takes the last full entry and every one after it up to the one wanted.
+use super::*;
+use std::collections::HashMap;
+
+impl<'block> ActivatedMemoryBlock<'block> {
+	/// Write guest memory's part of a journal entry.  `hashes` is what each page held as of the last entry, which this
+	/// updates; empty it to write every page in the state.
+	pub fn save_journal_pages(&mut self, stream: &mut dyn Write, hashes: &mut Vec<Option<u64>>) -> anyhow::Result<()> {
+		if !self.b.sealed {
+			return Err(coded(ErrorCode::BadState, "Must seal first"))
+		}
+		self.check_poisoned()?;
+		self.b.get_stack_dirty();
+		hashes.resize(self.b.pages.len(), None);
+		let statii = self.b.pages.iter().map(|p| p.status).collect::<Vec<_>>();
+		let dirtii = self.b.pages.iter().map(|p| p.saved_dirty()).collect::<Vec<_>>();
+		let mut header = Vec::new();
+		write_state_header(&mut header, &self.b.hash[..], self.b.addr, &statii[..], &dirtii[..], self.b.aslr)?;
+		bin::writeval(stream, header.len() as u64)?;
+		stream.write_all(&header[..])?;
+		let in_state = self.b.pages.iter().map(|p| p.in_state()).collect::<Vec<_>>();
+		bin::writeval(stream, in_state.len() as u64)?;
+		stream.write_all(unsafe { std::mem::transmute::<&[bool], &[u8]>(&in_state[..]) })?;
+
+		let saved = (0..self.b.pages.len()).filter(|&index| in_state[index]).collect::<Vec<_>>();
+		let hidden = match unsafe { self.b.host_protect_unreadable(saved.iter().copied()) } {
+			Some(runs) => runs,
+			None => return self.check_poisoned(),
+		};
+		let start = self.b.addr.start;
+		let slice = |index: usize| unsafe { AddressRange { start: start + (index << PAGESHIFT), size: PAGESIZE }.slice() };
+		let now = workers::map(&saved[..], |&index| xxh3::xxh3_64(slice(index)));
+		let changed = saved.iter().zip(now.iter())
+			.filter(|&(&index, &h)| hashes[index] != Some(h))
+			.map(|(&index, _)| index)
+			.collect::<Vec<_>>();
+		let res = (|| {
+			bin::writeval(stream, changed.len() as u64)?;
+			for &index in changed.iter() {
+				bin::writeval(stream, index as u64)?;
+				stream.write_all(slice(index))?;
+			}
+			Ok::<_, anyhow::Error>(())
+		})();
+		for &run in hidden.iter() {
+			self.b.apply_protections(run);
+		}
+		res?;
+		for h in hashes.iter_mut() {
+			*h = None;
+		}
+		for (&index, &h) in saved.iter().zip(now.iter()) {
+			hashes[index] = Some(h);
+		}
+		Ok(())
+	}
+}
+
+/// Guest memory as journal entries have put it together so far
+#[derive(Default)]
+pub struct JournalPages {
+	header: Vec<u8>,
+	in_state: Vec<bool>,
+	pages: HashMap<usize, Vec<u8>>,
+}
+impl JournalPages {
+	/// Read the next entry's part, which is on top of those read before
+	pub fn apply(&mut self, stream: &mut dyn Read) -> anyhow::Result<()> {
+		let len = bin::readval::<u64>(stream)? as usize;
+		let mut header = vec![0u8; len];
+		stream.read_exact(&mut header[..])?;
+		let mut in_state = vec![0u8; bin::readval::<u64>(stream)? as usize];
+		stream.read_exact(&mut in_state[..])?;
+		let in_state = in_state.iter().map(|&s| s != 0).collect::<Vec<_>>();
+		let count = bin::readval::<u64>(stream)?;
+		for _ in 0..count {
+			let index = bin::readval::<u64>(stream)? as usize;
+			if index >= in_state.len() {
+				return Err(coded(ErrorCode::BadStateData, "Bad journal entry"))
+			}
+			let mut data = vec![0u8; PAGESIZE];
+			stream.read_exact(&mut data[..])?;
+			self.pages.insert(index, data);
+		}
+		self.header = header;
+		self.in_state = in_state;
+		Ok(())
+	}
+	/// Write guest memory's part of a state, just as save_state would have
+	pub fn save_state(&self, stream: &mut dyn Write) -> anyhow::Result<()> {
+		stream.write_all(&self.header[..])?;
+		for (index, _) in self.in_state.iter().enumerate().filter(|(_, &s)| s) {
+			match self.pages.get(&index) {
+				Some(data) => stream.write_all(&data[..])?,
+				None => return Err(coded(ErrorCode::BadStateData, "Journal is missing a page")),
+			}
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,191 @@
+// Loading states straight from files.  Instead of being copied in, the pages of guest memory in the file are mapped
+// from it copy-on-write, and left that way until something needs them in the block's own memory:  The guest or the host
+// writing to them, anything else that works on a range of pages, like munmap or mprotect, or the block being swapped
+// out.  Then they're copied in, only a host page at a time for writes.  Executable pages are always copied, since the
+// file could be somewhere that can't be executed from.  Mapping needs the pages to start on a page boundary in the
+// file, which the state header's padding sees to, 4K host pages, and fault based dirty tracking.  It also needs nothing
+// to be looking at the block's memory through its handle, so once a host view has been made, states are just copied.
+use super::*;
+use std::fs::File;
+use std::io::{BufReader, Seek, SeekFrom};
+
+/// Most pages copied at once when a run of them is taken off a state file
+const COPY_PAGES: usize = 256;
+
+/// Read all of `buf` from `offset` in `file`
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+	#[cfg(unix)]
+	{
+		use std::os::unix::fs::FileExt;
+		file.read_exact_at(buf, offset)
+	}
+	#[cfg(windows)]
+	{
+		use std::os::windows::fs::FileExt;
+		let mut done = 0;
+		while done < buf.len() {
+			match file.seek_read(&mut buf[done..], offset + done as u64)? {
+				0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+				n => done += n,
+			}
+		}
+		Ok(())
+	}
+}
+
+impl MemoryBlock {
+	fn can_map_states(&self) -> bool {
+		cfg!(unix) && self.tracking == DirtyTracking::Signal && !self.sanitizer && !self.handle_shared
+			&& self.host_page == PAGESIZE && page_size::get() == PAGESIZE
+	}
+	/// Something outside of the block is about to look at its memory through the handle
+	pub(super) fn share_handle(&mut self) {
+		let all = self.addr;
+		self.unmap_state_file(all);
+		self.handle_shared = true;
+	}
+	pub(super) fn any_from_file(&self, addr: AddressRange) -> bool {
+		if self.state_file_pages == 0 {
+			return false
+		}
+		let first = (addr.start - self.addr.start) >> PAGESHIFT;
+		let last = std::cmp::min(align_up(addr.end() - self.addr.start) >> PAGESHIFT, self.pages.len());
+		self.pages[first..last].iter().any(|p| p.from_file)
+	}
+	/// Copy any pages in `addr` that are still mapped from a state file into the block's own memory
+	pub(super) fn unmap_state_file(&mut self, addr: AddressRange) {
+		if self.state_file_pages == 0 {
+			return
+		}
+		let first = (addr.start - self.addr.start) >> PAGESHIFT;
+		let last = std::cmp::min(align_up(addr.end() - self.addr.start) >> PAGESHIFT, self.pages.len());
+		// a CowSnapshot could read them on another thread halfway through
+		self.resolve_cow(AddressRange { start: self.addr.start + (first << PAGESHIFT), size: (last - first) << PAGESHIFT });
+		let runs = (first..last)
+			.filter(|&index| self.pages[index].from_file)
+			.map(|index| (index, index + 1))
+			.coalesce(|x, y| if x.1 == y.0 { Ok((x.0, y.1)) } else { Err((x, y)) })
+			.collect::<Vec<_>>();
+		let mut buf = vec![0u8; std::cmp::min(last - first, COPY_PAGES) << PAGESHIFT];
+		for (start, end) in runs {
+			for chunk in (start..end).step_by(COPY_PAGES) {
+				let n = std::cmp::min(COPY_PAGES, end - chunk);
+				let range = AddressRange { start: self.addr.start + (chunk << PAGESHIFT), size: n << PAGESHIFT };
+				let buf = &mut buf[..range.size];
+				unsafe {
+					if !self.host_protect(range, Protection::R) {
+						return
+					}
+					buf.copy_from_slice(range.slice());
+					self.forget_protections(range);
+					if !pal::map_part(&self.handle, range, chunk << PAGESHIFT, !self.no_exec) {
+						self.poison("mapping guest memory");
+						return
+					}
+					range.slice_mut().copy_from_slice(buf);
+				}
+				for p in self.pages[chunk..chunk + n].iter_mut() {
+					p.from_file = false;
+				}
+				self.state_file_pages -= n;
+				self.apply_protections(range);
+			}
+		}
+	}
+}
+
+impl<'block> ActivatedMemoryBlock<'block> {
+	/// load_state(), from the block's part of a state that starts at `offset` in `file`, mapping the pages in it from
+	/// the file where possible.  Returns where in the file the block's part ends.  The file must not change while any
+	/// of it is still mapped, which can be until the next state is loaded.
+	pub fn load_state_file(&mut self, file: &File, offset: u64) -> anyhow::Result<u64> {
+		assert!(self.b.sealed);
+		self.check_poisoned()?;
+		let mut f = file;
+		f.seek(SeekFrom::Start(offset))?;
+		let mut reader = BufReader::new(file);
+		let StatePages { statii, dirtii, aslr } = self.read_state_header(&mut reader)?;
+		drop(reader);
+		let mut offset = offset + self.state_header_size() as u64;
+		self.b.get_stack_dirty();
+		if aslr.is_some() {
+			self.b.aslr = aslr;
+		}
+		let mappable = self.b.can_map_states() && offset & PAGEMASK as u64 == 0;
+
+		self.b.resolve_cow_all();
+		let all = self.b.addr;
+		self.b.unmap_state_file(all);
+		self.b.integrity_forget(all);
+		// what to map from the file, as (first page, where it is in the file, how many pages)
+		let mut runs: Vec<(usize, u64, usize)> = Vec::new();
+		unsafe {
+			self.b.host_protect(all, Protection::RW);
+
+			for (index, p) in self.b.pages.iter_mut().enumerate() {
+				let paddr = AddressRange { start: self.b.addr.start + (index << PAGESHIFT), size: PAGESIZE };
+				if p.immutable {
+					continue
+				}
+				let status = statii[index];
+				let saved = dirtii[index] && !p.invisible;
+				if p.transient {
+					if !pagecmp::is_zero(paddr.slice()) {
+						p.try_snapshot(paddr.start)?;
+						paddr.zero();
+						p.dirty = true;
+						self.b.dirty_index.set(index);
+					}
+				} else if !p.invisible {
+					let dirty = dirtii[index];
+					if dirty {
+						p.try_snapshot(paddr.start)?;
+						if mappable && status != PageAllocation::Free && !status.executable() {
+							match runs.last_mut() {
+								Some(r) if r.0 + r.2 == index && r.1 + (r.2 << PAGESHIFT) as u64 == offset => r.2 += 1,
+								_ => runs.push((index, offset, 1)),
+							}
+						} else {
+							read_at(file, paddr.slice_mut(), offset)?;
+						}
+					} else if p.dirty {
+						match &p.snapshot {
+							Snapshot::ZeroFilled => paddr.zero(),
+							Snapshot::Data(b) => pagecmp::copy(paddr.slice_mut(), b.slice()),
+							Snapshot::None => panic!("Missing snapshot for dirty region"),
+						}
+					}
+					p.dirty = dirty;
+					if dirty {
+						self.b.dirty_index.set(index);
+					}
+				}
+				if saved {
+					offset += PAGESIZE as u64;
+				}
+				p.status = status;
+			}
+
+			for (first, at, n) in runs {
+				let addr = AddressRange { start: self.b.addr.start + (first << PAGESHIFT), size: n << PAGESHIFT };
+				self.b.forget_protections(addr);
+				if pal::map_file(file, at, addr) {
+					for p in self.b.pages[first..first + n].iter_mut() {
+						p.from_file = true;
+					}
+					self.b.state_file_pages += n;
+				} else {
+					read_at(file, addr.slice_mut(), at)?;
+				}
+			}
+
+			self.b.refresh_all_protections();
+		}
+		self.b.integrity_learn(all);
+		Ok(offset)
+	}
+	/// How many pages are still mapped from the last state loaded with load_state_file()
+	pub fn state_file_pages(&self) -> usize {
+		self.b.state_file_pages
+	}
+}
@@ -0,0 +1,85 @@
+// Dirty page markers, for seeing how much memory changes between two points without saving a state.  Placing a marker
+// write protects all of guest memory, and tripguard counts each page the first time it's written afterwards, then
+// lets the write through; dirty tracking itself is left alone.  Each page has a bit for every marker still waiting
+// for it to be written.  Pages sharing a host page are counted together.  Nothing here is in states.
+use super::*;
+
+/// How many markers can be placed at once
+pub const MAX_DIRTY_MARKERS: usize = 8;
+
+/// Reduce a native protection so that writes to a page that markers are waiting on will fault
+pub fn marker_prot(prot: Protection, markers: u8) -> Protection {
+	if markers != 0 {
+		watch::watch_prot(prot, WATCH_WRITE)
+	} else {
+		prot
+	}
+}
+
+impl MemoryBlock {
+	/// Count a write that faulted on a page markers were waiting for, and let it through.  Returns false if there were
+	/// none.
+	pub(super) fn marker_trip(&mut self, addr: usize) -> bool {
+		if self.pages[(addr - self.addr.start) >> PAGESHIFT].markers == 0 {
+			return false
+		}
+		let host_addr = self.host_expand(AddressRange { start: addr, size: 1 });
+		let first = (host_addr.start - self.addr.start) >> PAGESHIFT;
+		let last = std::cmp::min(first + (host_addr.size >> PAGESHIFT), self.pages.len());
+		for p in self.pages[first..last].iter_mut() {
+			for (bit, count) in self.dirty_markers.iter_mut().enumerate() {
+				if p.markers & 1 << bit != 0 {
+					*count.as_mut().unwrap() += 1;
+				}
+			}
+			p.markers = 0;
+		}
+		self.refresh_protections(host_addr);
+		true
+	}
+	/// Start marker `id` waiting on every page again
+	fn rearm_marker(&mut self, id: usize) {
+		self.dirty_markers[id] = Some(0);
+		for p in self.pages.iter_mut() {
+			p.markers |= 1 << id;
+		}
+		self.refresh_all_protections();
+	}
+}
+
+impl<'block> ActivatedMemoryBlock<'block> {
+	/// Place a marker that counts pages written from now on.  Returns its id.
+	pub fn add_dirty_marker(&mut self) -> anyhow::Result<u32> {
+		if self.b.sanitizer {
+			return Err(coded(ErrorCode::Unsupported, "Dirty markers need fault handlers, which sanitizer mode doesn't have"))
+		}
+		let id = match self.b.dirty_markers.iter().position(|m| m.is_none()) {
+			Some(id) => id,
+			None => return Err(coded(ErrorCode::BadState, format!("There can't be more than {} dirty markers at once", MAX_DIRTY_MARKERS))),
+		};
+		self.b.rearm_marker(id);
+		Ok(id as u32)
+	}
+	/// Move a marker to now, so it counts again from zero
+	pub fn reset_dirty_marker(&mut self, id: u32) -> anyhow::Result<()> {
+		self.dirty_marker(id)?;
+		self.b.rearm_marker(id as usize);
+		Ok(())
+	}
+	pub fn remove_dirty_marker(&mut self, id: u32) -> anyhow::Result<()> {
+		self.dirty_marker(id)?;
+		self.b.dirty_markers[id as usize] = None;
+		for p in self.b.pages.iter_mut() {
+			p.markers &= !(1 << id);
+		}
+		self.b.refresh_all_protections();
+		Ok(())
+	}
+	/// How many pages have been written since marker `id` was placed or last reset
+	pub fn dirty_marker(&self, id: u32) -> anyhow::Result<usize> {
+		match self.b.dirty_markers.get(id as usize) {
+			Some(&Some(count)) => Ok(count),
+			_ => Err(coded(ErrorCode::NotFound, format!("No dirty marker with id {}", id))),
+		}
+	}
+}
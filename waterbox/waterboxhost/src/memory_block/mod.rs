@@ -1,28 +1,139 @@
 mod pageblock;
 mod pal;
 mod tripguard;
+mod cow;
+mod watch;
+mod search;
+mod cheats;
+mod sanitizer;
+mod heat;
+mod markers;
+mod compare;
+mod mapped;
+mod protect;
+mod bitmap;
+mod journal;
+mod audit;
+mod integrity;
+#[cfg(target_arch = "x86_64")]
+mod pagecmp;
+#[cfg(target_os = "linux")]
+mod uffd;
 mod tests;
+mod bench;
+#[cfg(feature = "fuzz")]
+mod differential;
 
-use std::sync::MutexGuard;
 use std::ops::DerefMut;
 use pageblock::PageBlock;
 use crate::*;
 use getset::Getters;
 use crate::syscall_defs::*;
 use itertools::Itertools;
-use std::sync::atomic::AtomicU32;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use crate::bin;
 use sha2::{Sha256, Digest};
+use std::sync::{Arc, Mutex};
+pub use cow::CowSnapshot;
+pub use watch::{WatchCallback, WATCH_READ, WATCH_WRITE};
+pub use heat::{HeatMapInfo, PageHeat};
+pub use compare::{PageDiff, count_differences};
+pub use protect::protect_call_count;
+pub use journal::JournalPages;
+pub use audit::AuditEntry;
+pub use integrity::{IntegrityCallback, IntegrityInfo};
+pub use tripguard::{set_breakpoint, clear_breakpoints, debug_read, debug_write, debug_regions, dirty_fault_count, fault_count};
+pub use pageblock::page_block_count;
 
-/// Tracks one lock for each 4GB memory area
+/// Return all recycled snapshot pages that are not currently in use to the OS.  Returns the number of bytes released.
+pub fn trim_page_pool() -> usize {
+	pageblock::trim() * PAGESIZE
+}
+/// Number of bytes of recycled snapshot pages that are waiting to be reused, counting those set aside for faults
+pub fn page_pool_size() -> usize {
+	pageblock::pooled_pages() * PAGESIZE
+}
+
+/// The error for when guest memory can't be had, either because the host's memory limit is reached or because the OS
+/// refused.  Downcast host errors to this to tell running out of memory apart from other failures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutOfMemory;
+impl std::fmt::Display for OutOfMemory {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "Out of memory")
+	}
+}
+impl std::error::Error for OutOfMemory {}
+
+/// What memory the guest has just mapped reads as, when scribbling
+pub const SCRIBBLE_NEW: u8 = 0xcd;
+/// What memory the guest has discarded reads as, when scribbling
+pub const SCRIBBLE_FREED: u8 = 0xdd;
+
+/// Size of the huge pages that set_huge_pages() asks for
+const HUGE_PAGESIZE: usize = 0x200000;
+
+/// Round up to a multiple of the host page size
+fn align_host(p: usize, host_page: usize) -> usize {
+	(p + host_page - 1) & !(host_page - 1)
+}
+
+/// Tracks one lock for each 4GB memory area.  These aren't mutexes, since a block can be activated on one thread and
+/// deactivated on another, and a mutex has to be unlocked by the thread that locked it.
 mod lock_list {
 	use lazy_static::lazy_static;
+	use std::cell::UnsafeCell;
 	use std::collections::HashMap;
-	use std::sync::Mutex;
+	use std::ops::{Deref, DerefMut};
+	use std::sync::{Condvar, Mutex};
 	use super::MemoryBlockRef;
 
+	/// Which block is swapped in to an area, guarded by whoever has it locked
+	pub struct Area {
+		locked: Mutex<bool>,
+		unlocked: Condvar,
+		resident: UnsafeCell<Option<MemoryBlockRef>>,
+	}
+	// resident is only touched through an AreaGuard, and there's only ever one of those
+	unsafe impl Sync for Area {}
+
+	impl Area {
+		/// Wait for the area to be free, and take it
+		pub fn lock(&'static self) -> AreaGuard {
+			let mut locked = self.locked.lock().unwrap();
+			while *locked {
+				locked = self.unlocked.wait(locked).unwrap();
+			}
+			*locked = true;
+			AreaGuard { area: self }
+		}
+	}
+
+	/// An area that's locked, to be unlocked on drop, on whatever thread that happens on
+	pub struct AreaGuard {
+		area: &'static Area,
+	}
+	unsafe impl Send for AreaGuard {}
+	impl Deref for AreaGuard {
+		type Target = Option<MemoryBlockRef>;
+		fn deref(&self) -> &Option<MemoryBlockRef> {
+			unsafe { &*self.area.resident.get() }
+		}
+	}
+	impl DerefMut for AreaGuard {
+		fn deref_mut(&mut self) -> &mut Option<MemoryBlockRef> {
+			unsafe { &mut *self.area.resident.get() }
+		}
+	}
+	impl Drop for AreaGuard {
+		fn drop(&mut self) {
+			*self.area.locked.lock().unwrap() = false;
+			self.area.unlocked.notify_one();
+		}
+	}
+
 	lazy_static! {
-		static ref LOCK_LIST: Mutex<HashMap<u32, Box<Mutex<Option<MemoryBlockRef>>>>> = Mutex::new(HashMap::new());
+		static ref LOCK_LIST: Mutex<HashMap<u32, Box<Area>>> = Mutex::new(HashMap::new());
 	}
 
 	unsafe fn extend<T>(o: &T) -> &'static T {
@@ -31,10 +142,14 @@ mod lock_list {
 	/// adds a lock if it does not exist; no effect if it already does.
 	pub fn maybe_add(lock_index: u32) {
 		let map = &mut LOCK_LIST.lock().unwrap();
-		map.entry(lock_index).or_insert_with(|| Box::new(Mutex::new(None)));	
+		map.entry(lock_index).or_insert_with(|| Box::new(Area {
+			locked: Mutex::new(false),
+			unlocked: Condvar::new(),
+			resident: UnsafeCell::new(None),
+		}));
 	}
 	/// Gets the lock for a particular index.
-	pub fn get(lock_index: u32) -> &'static Mutex<Option<MemoryBlockRef>> {
+	pub fn get(lock_index: u32) -> &'static Area {
 		let map = &mut LOCK_LIST.lock().unwrap();
 		unsafe {
 			extend(map.get(&lock_index).unwrap())
@@ -49,7 +164,97 @@ pub enum Protection {
 	RW,
 	RX,
 	RWX,
-	RWStack
+	RWStack,
+	/// No access, below a stack, so that faults in it can be reported as the stack overflowing
+	StackGuard,
+}
+impl Protection {
+	/// The same protection, but never executable
+	pub fn without_exec(self) -> Protection {
+		match self {
+			Protection::RX => Protection::R,
+			Protection::RWX => Protection::RW,
+			x => x,
+		}
+	}
+}
+
+/// Memory usage information for a MemoryBlock, as returned by ActivatedMemoryBlock::stats()
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MemoryStats {
+	/// Bytes of host memory currently backing the block.  Where the OS can't report this, it's the size of all allocated pages.
+	pub committed_bytes: usize,
+	/// Bytes of pages that have changed since sealing, and so will be in savestates
+	pub dirty_bytes: usize,
+	pub free_pages: usize,
+	pub none_pages: usize,
+	pub r_pages: usize,
+	pub rw_pages: usize,
+	pub rx_pages: usize,
+	pub rwx_pages: usize,
+	pub rwstack_pages: usize,
+	/// Bytes of host memory holding copies of pages' original content, for restoring them on loadstate
+	pub snapshot_bytes: usize,
+	/// Bytes of recycled snapshot pages waiting to be reused.  This pool is shared by all blocks.
+	pub page_pool_bytes: usize,
+}
+
+/// How writes to clean pages are detected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DirtyTracking {
+	/// Native write protection, with the resulting SIGSEGV or access violation caught in tripguard
+	Signal,
+	/// Linux only: userfaultfd write protection, with faults serviced on a dedicated thread instead of in a signal handler
+	Userfaultfd,
+	/// No write protection at all:  Writable pages are taken to be dirty as soon as they could be written, and
+	/// snapshotted right then.  States get bigger, but nothing faults.
+	Eager,
+}
+impl DirtyTracking {
+	/// From the C interface's numbering, as in wbx_set_dirty_tracking
+	pub fn from_raw(backend: u32) -> anyhow::Result<DirtyTracking> {
+		match backend {
+			0 => Ok(DirtyTracking::Signal),
+			1 => Ok(DirtyTracking::Userfaultfd),
+			2 => Ok(DirtyTracking::Eager),
+			_ => Err(coded(ErrorCode::InvalidArgument, format!("Unknown dirty tracking backend {}", backend))),
+		}
+	}
+	/// Returns true if this backend can be used on the current system.  May do one time initialization.
+	pub fn available(&self) -> bool {
+		match self {
+			DirtyTracking::Signal | DirtyTracking::Eager => true,
+			DirtyTracking::Userfaultfd => tripguard::initialize_uffd(),
+		}
+	}
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+mod pagecmp {
+	pub fn eq(a: &[u8], b: &[u8]) -> bool {
+		a == b
+	}
+	pub fn is_zero(a: &[u8]) -> bool {
+		a.iter().all(|&b| b == 0)
+	}
+	pub fn copy(dst: &mut [u8], src: &[u8]) {
+		dst.copy_from_slice(src);
+	}
+}
+
+#[cfg(not(target_os = "linux"))]
+mod uffd {
+	use crate::*;
+	pub fn initialize(_handler: fn(usize)) -> bool {
+		false
+	}
+	pub unsafe fn register(_addr: AddressRange) -> bool {
+		false
+	}
+	pub unsafe fn writeprotect(_addr: AddressRange, _wp: bool) -> bool {
+		false
+	}
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -73,11 +278,14 @@ impl PageAllocation {
 	pub fn readable(&self) -> bool {
 		use PageAllocation::*;
 		match self {
-			Allocated(Protection::None) => false,
+			Allocated(Protection::None) | Allocated(Protection::StackGuard) => false,
 			Free => false,
 			_ => true,
 		}
 	}
+	pub fn executable(&self) -> bool {
+		matches!(self, PageAllocation::Allocated(Protection::RX) | PageAllocation::Allocated(Protection::RWX))
+	}
 }
 
 /// Stores information about the original data content of a memory area, before it got dirty
@@ -97,6 +305,22 @@ struct Page {
 	pub snapshot: Snapshot,
 	/// If true, the page content is not stored in states (but status still is).
 	pub invisible: bool,
+	/// If true, the page content is not stored in states, and is zeroed when one is loaded.
+	pub transient: bool,
+	/// If true, the page was readonly when the block was sealed and can never change, so it's never dirty, and
+	/// nothing that saves, loads or compares states has to look at it
+	pub immutable: bool,
+	/// If true, an outstanding CowSnapshot still needs this page's current content
+	pub cow_pending: bool,
+	/// Combination of WATCH_READ and WATCH_WRITE for the watchpoints overlapping this page, plus WATCH_EXEC if
+	/// it has breakpoints
+	pub watch: u8,
+	/// HEAT_* for how the heat map is waiting for the page to be accessed
+	pub heat: u8,
+	/// A bit for each dirty marker waiting for the page to be written
+	pub markers: u8,
+	/// If true, the page is still mapped from the state file it was loaded from, instead of from the block's own memory
+	pub from_file: bool,
 }
 impl Page {
 	pub fn new() -> Page {
@@ -105,33 +329,163 @@ impl Page {
 			dirty: false,
 			snapshot: Snapshot::ZeroFilled,
 			invisible: false,
+			transient: false,
+			immutable: false,
+			cow_pending: false,
+			watch: 0,
+			heat: heat::HEAT_OFF,
+			markers: 0,
+			from_file: false,
 		}
 	}
+	/// The dirty flag as it's written in states
+	pub fn saved_dirty(&self) -> bool {
+		self.dirty && !self.transient
+	}
+	/// Whether the page content is written in states
+	pub fn in_state(&self) -> bool {
+		self.saved_dirty() && !self.invisible
+	}
 	/// Take a snapshot if one is not yet stored
 	/// unsafe: caller must ensure pages are mapped and addr is correct
 	/// Does not check dirty or invisible
 	pub unsafe fn maybe_snapshot(&mut self, addr: usize) {
+		if self.try_snapshot(addr).is_err() {
+			panic!("PageBlock could not allocate memory!");
+		}
+	}
+	/// maybe_snapshot(), but fails instead of panicking if there's no memory for the snapshot
+	pub unsafe fn try_snapshot(&mut self, addr: usize) -> Result<(), OutOfMemory> {
 		if match self.snapshot { Snapshot:: None => true, _ => false } {
-			let mut snapshot = PageBlock::new();
 			let src = std::slice::from_raw_parts(addr as *const u8, PAGESIZE);
-			let dst = snapshot.slice_mut();
-			dst.copy_from_slice(src);
-			self.snapshot = Snapshot::Data(snapshot);	
+			self.snapshot = Snapshot::Data(PageBlock::try_copy(src).ok_or(OutOfMemory)?);
+		}
+		Ok(())
+	}
+	/// maybe_snapshot(), for fault handlers
+	pub unsafe fn fault_snapshot(&mut self, addr: usize) {
+		if matches!(self.snapshot, Snapshot::None) {
+			match PageBlock::fault_copy(std::slice::from_raw_parts(addr as *const u8, PAGESIZE)) {
+				Some(b) => self.snapshot = Snapshot::Data(b),
+				None => panic!("PageBlock could not allocate memory!"),
+			}
+		}
+	}
+	/// fault_snapshot(), but only with a page from the reserve, so without any system calls.  Returns false if there
+	/// wasn't one.
+	pub unsafe fn reserved_snapshot(&mut self, addr: usize) -> bool {
+		if matches!(self.snapshot, Snapshot::None) {
+			match PageBlock::copy_reserved(std::slice::from_raw_parts(addr as *const u8, PAGESIZE)) {
+				Some(b) => self.snapshot = Snapshot::Data(b),
+				None => return false,
+			}
 		}
+		true
+	}
+	/// True if a write to this page is a first write that only dirty tracking is waiting on
+	pub fn plain_trip(&self) -> bool {
+		self.status.writable() && !self.dirty && !self.cow_pending && !self.from_file
+			&& (self.watch | self.markers) == 0 && self.heat == heat::HEAT_OFF
+	}
+	/// True if writes to this page need to be caught:  Either because it is clean, or because its current content
+	/// has to be preserved for an outstanding CowSnapshot
+	pub fn needs_trip(&self) -> bool {
+		!self.dirty || self.cow_pending
+	}
+	/// True if the page's content is known to be all zeros because it has never been written.
+	/// Such a page may not even be committed in host memory yet, so avoid touching it if possible.
+	pub fn known_zero(&self) -> bool {
+		!self.dirty && matches!(self.snapshot, Snapshot::ZeroFilled)
+	}
+	/// True if the host can read this page directly, without tripping a watchpoint or needing to change protections
+	pub fn host_readable(&self) -> bool {
+		self.status.readable() && self.watch & WATCH_READ == 0 && self.heat != heat::HEAT_ANY
 	}
 	/// Compute the appropriate native protection value given this page's current status
 	pub fn native_prot(&self) -> Protection {
+		self.reduce_prot(self.unwatched_prot())
+	}
+	/// Reduce a native protection for everything that's waiting for accesses to this page to fault
+	fn reduce_prot(&self, prot: Protection) -> Protection {
+		let prot = watch::watch_prot(prot, self.watch);
+		let prot = heat::heat_prot(prot, self.heat);
+		let prot = markers::marker_prot(prot, self.markers);
+		// writes have to go to the block's own memory
+		if self.from_file { watch::watch_prot(prot, WATCH_WRITE) } else { prot }
+	}
+	/// native_prot(), ignoring watchpoints
+	pub fn unwatched_prot(&self) -> Protection {
 		match self.status {
 			#[cfg(windows)]
-			PageAllocation::Allocated(Protection::RWStack) if self.dirty => Protection::RW,
-			PageAllocation::Allocated(Protection::RW) if !self.dirty => Protection::R,
-			PageAllocation::Allocated(Protection::RWX) if !self.dirty => Protection::RX,
+			PageAllocation::Allocated(Protection::RWStack) if !self.needs_trip() => Protection::RW,
+			PageAllocation::Allocated(Protection::RW) if self.needs_trip() => Protection::R,
+			PageAllocation::Allocated(Protection::RWX) if self.needs_trip() => Protection::RX,
 			#[cfg(unix)]
-			PageAllocation::Allocated(Protection::RWStack) => if self.dirty { Protection::RW } else { Protection::R },
+			PageAllocation::Allocated(Protection::RWStack) => if !self.needs_trip() { Protection::RW } else { Protection::R },
+			PageAllocation::Allocated(Protection::StackGuard) => Protection::None,
 			PageAllocation::Allocated(x) => x,
 			PageAllocation::Free => Protection::None,
 		}
 	}
+	/// Compute the appropriate native protection value, and whether the page should be write protected through userfaultfd,
+	/// given this page's current status
+	pub fn native_state(&self, tracking: DirtyTracking) -> (Protection, bool) {
+		match tracking {
+			DirtyTracking::Signal | DirtyTracking::Eager => (self.native_prot(), false),
+			DirtyTracking::Userfaultfd => {
+				let (prot, wp) = match self.status {
+					PageAllocation::Allocated(Protection::RW) | PageAllocation::Allocated(Protection::RWStack) => (Protection::RW, self.needs_trip()),
+					PageAllocation::Allocated(Protection::RWX) => (Protection::RWX, self.needs_trip()),
+					PageAllocation::Allocated(Protection::StackGuard) => (Protection::None, false),
+					PageAllocation::Allocated(x) => (x, false),
+					PageAllocation::Free => (Protection::None, false),
+				};
+				(self.reduce_prot(prot), wp)
+			}
+		}
+	}
+}
+
+/// Read, write and execute as 1, 2 and 4
+fn prot_bits(prot: Protection) -> u8 {
+	match prot {
+		Protection::None | Protection::StackGuard => 0,
+		Protection::R => 1,
+		Protection::RW | Protection::RWStack => 3,
+		Protection::RX => 5,
+		Protection::RWX => 7,
+	}
+}
+
+/// The native state of a host page holding all of `pages`.  Any access that one of them allows is let through, except
+/// that an access one of them has to catch is caught on all of them.  With host pages no bigger than guest pages, this
+/// is just native_state().
+fn host_native_state(pages: &[Page], tracking: DirtyTracking) -> (Protection, bool) {
+	if let [p] = pages {
+		return p.native_state(tracking)
+	}
+	let mut allow = 0;
+	let mut catch = 0;
+	let mut wp = false;
+	for p in pages {
+		let (prot, w) = p.native_state(tracking);
+		let wanted = match p.status {
+			PageAllocation::Allocated(x) => prot_bits(x),
+			PageAllocation::Free => 0,
+		};
+		allow |= prot_bits(prot);
+		catch |= wanted & !prot_bits(prot);
+		wp |= w;
+	}
+	let prot = match allow & !catch {
+		7 => Protection::RWX,
+		5 => Protection::RX,
+		3 => Protection::RW,
+		1 => Protection::R,
+		// nothing is allowed without read
+		_ => Protection::None,
+	};
+	(prot, wp)
 }
 
 /// Used internally to talk about regions of memory together with their allocation status
@@ -140,6 +494,14 @@ struct PageRange<'a> {
 	pub pages: &'a mut [Page]
 }
 impl<'a> PageRange<'a> {
+	/// Fail with EPERM if any of the pages are immutable, for anything that would change them
+	pub fn check_mutable(&self) -> SyscallResult {
+		if self.pages.iter().any(|p| p.immutable) {
+			Err(EPERM)
+		} else {
+			Ok(())
+		}
+	}
 	pub fn addr(&self) -> AddressRange {
 		AddressRange {
 			start: self.start,
@@ -208,15 +570,66 @@ pub struct MemoryBlock {
 	sealed: bool,
 	#[get]
 	hash: Vec<u8>,
+	/// Whether seal() has been called again since the first time, moving the baseline
+	resealed: bool,
 
 	lock_index: u32,
 	handle: pal::Handle,
+	tracking: DirtyTracking,
+	/// Size of the host's pages, which native protections apply to.  A multiple of PAGESIZE.
+	host_page: usize,
+	/// What native protections each host page has
+	applied: protect::Applied,
+	/// Pages still owed to the most recent CowSnapshot, if any
+	cow: Option<Arc<Mutex<Vec<cow::CowPage>>>>,
+	/// If true, fully allocated parts of the block are hinted to be backed by huge pages
+	huge_pages: bool,
+	/// If true, memory the guest maps starts out as SCRIBBLE_NEW, and memory it discards becomes SCRIBBLE_FREED
+	scribble: bool,
+	/// If true, the block is set up to run under sanitizers:  No fault handlers, nothing mapped over what's already
+	/// there, and ASan told about it
+	sanitizer: bool,
+	/// If true, all of the block's host memory was committed up front, and is never given back
+	committed: bool,
+	/// If true, none of the block's memory is ever mapped executable, even when the guest's protections say it is
+	no_exec: bool,
+	/// If Some, the most bytes of pages that can be allocated at once
+	memory_limit: Option<usize>,
+	/// Set when an OS call that guest memory depends on has failed, after which the guest can't safely run anymore
+	poisoned: AtomicBool,
+	/// If Some, placement of movable mappings is randomized with this rng state
+	aslr: Option<u64>,
+	watchpoints: Vec<watch::Watchpoint>,
+	next_watch_id: u32,
+	/// Debugger breakpoints, which are not part of the state
+	breakpoints: Vec<usize>,
+	/// Not part of the state either
+	cheats: Vec<cheats::Cheat>,
+	/// Nor are these
+	audits: Vec<audit::Audit>,
+	audit_log: audit::AuditLog,
+	/// Also not part of the state
+	heat: Option<heat::HeatMap>,
+	/// Nor is the background integrity checker, if it's running
+	integrity: Option<integrity::IntegrityChecker>,
+	/// Pages written since each dirty marker was placed, for the ones that are
+	dirty_markers: [Option<usize>; markers::MAX_DIRTY_MARKERS],
+	/// How many pages are from_file
+	state_file_pages: usize,
+	/// A bit set for every dirty page, and maybe for some that have been cleaned since, for dirty_pages()
+	dirty_index: bitmap::Bitmap,
+	/// Set once anything outside of the block might look at its memory through the handle, which wouldn't see pages
+	/// mapped from a state file
+	handle_shared: bool,
+	next_cheat_id: u32,
 
 	debug_id: u32,
 	active: bool,
+	/// Set by release(), after which the block has no memory
+	released: bool,
 }
 
-type BlockGuard = MutexGuard<'static, Option<MemoryBlockRef>>;
+type BlockGuard = lock_list::AreaGuard;
 
 pub struct ActivatedMemoryBlock<'block> {
 	b: &'block mut MemoryBlock,
@@ -233,19 +646,37 @@ impl<'block> Drop for ActivatedMemoryBlock<'block> {
 
 impl MemoryBlock {
 	pub fn new(addr: AddressRange) -> Box<MemoryBlock> {
+		MemoryBlock::with_tracking(addr, DirtyTracking::Signal)
+	}
+	/// Create a MemoryBlock with a particular dirty tracking backend.  Panics if the backend is not available.
+	pub fn with_tracking(addr: AddressRange, tracking: DirtyTracking) -> Box<MemoryBlock> {
+		MemoryBlock::with_host_page(addr, tracking, std::cmp::max(page_size::get(), PAGESIZE))
+	}
+	/// with_tracking(), treating host pages as `host_page` bytes big.  That can be more than they really are, which is
+	/// how bigger host pages get tested.
+	fn with_host_page(addr: AddressRange, tracking: DirtyTracking, host_page: usize) -> Box<MemoryBlock> {
+		if !tracking.available() {
+			panic!("Dirty tracking backend {:?} is not available!", tracking);
+		}
 		if addr.start != align_down(addr.start) || addr.size != align_down(addr.size) {
 			panic!("Addresses and sizes must be aligned!");
 		}
 		if addr.start >> 32 != (addr.end() - 1) >> 32 {
 			panic!("MemoryBlock must fit into a single 4G region!");
 		}
+		if !host_page.is_power_of_two() || host_page < PAGESIZE || addr.start & (host_page - 1) != 0 {
+			panic!("MemoryBlock must start on a host page boundary!");
+		}
 		let npage = addr.size >> PAGESHIFT;
 		let mut pages = Vec::new();
 		pages.reserve_exact(npage);
 		for _ in 0..npage {
 			pages.push(Page::new());
 		}
-		let handle = pal::open(addr.size).unwrap();
+		// the last host page can stick out past the end, in which case its tail is never used
+		let handle = pal::open(align_host(addr.size, host_page), true).unwrap();
+		leaks::BLOCKS.opened();
+		leaks::DESCRIPTORS.opened();
 		let lock_index = (addr.start >> 32) as u32;
 		// add the lock_index stuff now, so we won't have to check for it later on activate / drop
 		lock_list::maybe_add(lock_index);
@@ -256,17 +687,65 @@ impl MemoryBlock {
 			addr,
 			sealed: false,
 			hash: Vec::new(),
+			resealed: false,
 
 			lock_index,
 			handle,
+			tracking,
+			host_page,
+			applied: protect::Applied::new(align_host(addr.size, host_page) / host_page),
+			cow: None,
+			huge_pages: false,
+			scribble: false,
+			sanitizer: false,
+			committed: false,
+			no_exec: false,
+			memory_limit: None,
+			poisoned: AtomicBool::new(false),
+			aslr: None,
+			watchpoints: Vec::new(),
+			next_watch_id: 1,
+			breakpoints: Vec::new(),
+			cheats: Vec::new(),
+			audits: Vec::new(),
+			audit_log: audit::AuditLog::new(),
+			heat: None,
+			integrity: None,
+			dirty_markers: [None; markers::MAX_DIRTY_MARKERS],
+			state_file_pages: 0,
+			dirty_index: bitmap::Bitmap::new(npage),
+			handle_shared: false,
+			next_cheat_id: 1,
 
 			debug_id,
 			active: false,
+			released: false,
 		});
 		// res.trace("new");
 		res
 	}
 
+	/// Set the block up to run under ASan or Valgrind, which the host's usual tricks confuse.  Needs Eager tracking,
+	/// and has to be done before the block is first entered.  Watchpoints and breakpoints don't work like this, since
+	/// nothing catches their faults, and neither does anything else that relies on faults being caught.
+	pub fn set_sanitizer_mode(&mut self, val: bool) {
+		assert!(!self.active);
+		assert!(!val || self.tracking == DirtyTracking::Eager, "Sanitizer mode needs eager dirty tracking");
+		self.sanitizer = val;
+	}
+
+	/// Never map any of the block's memory executable, for environments that don't allow executable memory to be created.
+	/// Pages with executable protections are only readable then.  Has to be done before the block is first entered.
+	pub fn set_no_exec(&mut self, val: bool) {
+		assert!(!self.active && !self.committed);
+		if val != self.no_exec {
+			// on Windows, the section itself can't have execute access
+			let h = pal::open(align_host(self.addr.size, self.host_page), !val).unwrap();
+			unsafe { pal::close(std::mem::replace(&mut self.handle, h)); }
+			self.no_exec = val;
+		}
+	}
+
 	pub fn trace(&self, name: &str) {
 		let ptr = unsafe { std::mem::transmute::<&Self, usize>(self) };
 		let tid = unsafe { std::mem::transmute::<std::thread::ThreadId, u64>(std::thread::current().id()) };
@@ -277,6 +756,7 @@ impl MemoryBlock {
 	pub fn enter(&mut self) -> ActivatedMemoryBlock {
 		unsafe {
 			let mutex_guard = self.activate();
+			self.integrity_check_tables();
 			ActivatedMemoryBlock {
 				b: self,
 				mutex_guard: Some(mutex_guard),
@@ -287,9 +767,13 @@ impl MemoryBlock {
 	/// lock memory region and potentially swap this block into memory
 	unsafe fn activate(&mut self) -> BlockGuard {
 		// self.trace("activate");
-		assert!(!self.active);
+		// guest writes to snapshot are about to start taking pages from it
+		pageblock::refill_reserve();
 		let area = lock_list::get(self.lock_index);
-		let mut guard = area.lock().unwrap();
+		let mut guard = area.lock();
+		// checked after the lock, since another thread could still be deactivating it
+		assert!(!self.active);
+		assert!(!self.released, "MemoryBlock was released!");
 
 		let other_opt = guard.deref_mut();
 		match *other_opt {
@@ -337,15 +821,67 @@ impl MemoryBlock {
 
 	unsafe fn swapin(&mut self) {
 		// self.trace("swapin");
-		assert!(pal::map(&self.handle, self.addr));
+		let mapped = self.host_expand(self.addr);
+		self.forget_protections(mapped);
+		if self.sanitizer {
+			if !pal::map_noreplace(&self.handle, mapped, !self.no_exec) {
+				self.poison("mapping guest memory, maybe because something like a sanitizer's shadow memory is already there");
+			}
+			sanitizer::unpoison(mapped);
+		} else if !pal::map(&self.handle, mapped, !self.no_exec) {
+			self.poison("mapping guest memory");
+		} else if self.tracking == DirtyTracking::Userfaultfd && !uffd::register(mapped) {
+			self.poison("registering guest memory with userfaultfd");
+		}
 		tripguard::register(self);
+		leaks::MAPPED_BLOCKS.opened();
 		self.refresh_all_protections();
+		let addr = self.addr;
+		self.hint_huge_pages(addr);
 	}
 	unsafe fn swapout(&mut self) {
 		// self.trace("swapout");
 		self.get_stack_dirty();
-		assert!(pal::unmap(self.addr));
+		// a CowSnapshot might still be reading from this memory on another thread
+		self.resolve_cow_all();
+		// only the handle is still there afterwards
+		self.unmap_state_file(self.addr);
+		if !pal::unmap(self.host_expand(self.addr)) {
+			self.poison("unmapping guest memory");
+		}
+		self.forget_protections(self.addr);
 		tripguard::unregister(self);
+		leaks::MAPPED_BLOCKS.closed();
+	}
+
+	/// Record that an OS call guest memory depends on failed.  Nothing is undone, but the block's host refuses to run
+	/// the guest or touch its state from then on, instead of taking the process down.
+	fn poison(&self, what: &str) {
+		if !self.poisoned.swap(true, Ordering::SeqCst) {
+			log!(Error, "Failed {}: {}.  This guest can't be used anymore.", what, std::io::Error::last_os_error());
+		}
+	}
+	pub fn poisoned(&self) -> bool {
+		self.poisoned.load(Ordering::SeqCst)
+	}
+
+	/// Ask for huge pages on any fully allocated, huge page aligned chunks of the block that overlap `addr`
+	fn hint_huge_pages(&mut self, addr: AddressRange) {
+		if !self.huge_pages {
+			return
+		}
+		let mut chunk = addr.start & !(HUGE_PAGESIZE - 1);
+		while chunk < addr.end() {
+			if chunk >= self.addr.start && chunk + HUGE_PAGESIZE <= self.addr.end() {
+				let pstart = (chunk - self.addr.start) >> PAGESHIFT;
+				let pend = pstart + (HUGE_PAGESIZE >> PAGESHIFT);
+				if self.pages[pstart..pend].iter().all(|p| p.status != PageAllocation::Free) {
+					// failure just means normal pages
+					unsafe { pal::hint_huge(AddressRange { start: chunk, size: HUGE_PAGESIZE }); }
+				}
+			}
+			chunk += HUGE_PAGESIZE;
+		}
 	}
 
 	fn page_range(&mut self) -> PageRange {
@@ -355,14 +891,40 @@ impl MemoryBlock {
 		}
 	}
 
+	/// How many of the `size` bytes at `addr` are in pages that pass `check`, stopping at the first one that doesn't
+	fn accessible_len(&self, addr: usize, size: usize, check: fn(&Page) -> bool) -> usize {
+		if !self.addr.contains(addr) {
+			return 0
+		}
+		let end = std::cmp::min(addr.saturating_add(size), self.addr.end());
+		let mut pos = addr;
+		while pos < end && check(&self.pages[(pos - self.addr.start) >> PAGESHIFT]) {
+			pos = std::cmp::min(align_down(pos) + PAGESIZE, end);
+		}
+		pos - addr
+	}
+
+	/// `addr`, and the stack guard page right below it if there is one
+	fn with_stack_guard(&self, addr: AddressRange) -> AddressRange {
+		let below = addr.start.wrapping_sub(PAGESIZE);
+		if addr.start == align_down(addr.start) && self.addr.contains(below)
+			&& self.pages[(below - self.addr.start) >> PAGESHIFT].status == PageAllocation::Allocated(Protection::StackGuard) {
+			AddressRange { start: below, size: addr.size + PAGESIZE }
+		} else {
+			addr
+		}
+	}
+
 	fn validate_range(&mut self, addr: AddressRange) -> Result<PageRange, SyscallError> {
 		if addr.start < self.addr.start
-			|| addr.end() > self.addr.end()
+			|| addr.checked_end().filter(|&end| end <= self.addr.end()).is_none()
 			|| addr.size == 0
 			|| addr.start != align_down(addr.start)
 			|| addr.size != align_down(addr.size) {
 			Err(EINVAL)
 		} else {
+			// anything that cares about a range at a time might change its mappings
+			self.unmap_state_file(addr);
 			let pstart = (addr.start - self.addr.start) >> PAGESHIFT;
 			let psize = (addr.size) >> PAGESHIFT;
 			Ok(PageRange {
@@ -372,62 +934,192 @@ impl MemoryBlock {
 		}
 	}
 
-	/// Refresh the correct protections in underlying host RAM on a page range.  Use after
-	/// temporary pal::protect(...) modifications, or to apply the effect of a dirty/prot change on the page
-	fn refresh_protections(range: &PageRange) {
-		struct Chunk {
-			addr: AddressRange,
-			prot: Protection,
+	/// Round a range out to whole host pages.  Guest state always works in PAGESIZE pages, but native protections
+	/// can only be changed a host page at a time, and host pages can be bigger.
+	fn host_expand(&self, addr: AddressRange) -> AddressRange {
+		let start = addr.start & !(self.host_page - 1);
+		AddressRange { start, size: align_host(addr.end(), self.host_page) - start }
+	}
+
+	/// Change native protections on the host pages overlapping a range so the host can access it directly, without
+	/// tripping dirty detection.  Use refresh_protections(...) afterwards to put things back.
+	unsafe fn host_protect(&self, addr: AddressRange, prot: Protection) -> bool {
+		let addr = self.host_expand(addr);
+		debug_assert!(prot == Protection::None || prot == Protection::R || prot == Protection::RX || !self.any_from_file(addr),
+			"Host writes to pages mapped from a state file would be lost");
+		let res = self.protect_native(addr, |_| prot);
+		let res = if self.tracking == DirtyTracking::Userfaultfd && prot != Protection::None && prot != Protection::R && prot != Protection::RX {
+			res && uffd::writeprotect(addr, false)
+		} else {
+			res
 		};
-		let mut start = range.start;
-		let chunks = range.iter()
-			.map(|p| {
-				let cstart = start;
-				start += PAGESIZE;
-				Chunk {
-					addr: AddressRange { start: cstart, size: PAGESIZE },
-					prot: p.native_prot(),
+		if !res {
+			self.poison("changing guest memory protections");
+		}
+		res
+	}
+
+	/// With Eager tracking, do what a write fault would have to every page overlapping a range that could catch one
+	fn trip_eagerly(&mut self, addr: AddressRange) {
+		if self.tracking != DirtyTracking::Eager {
+			return
+		}
+		let addr = self.host_expand(addr);
+		let first = (addr.start - self.addr.start) >> PAGESHIFT;
+		let last = std::cmp::min((addr.end() - self.addr.start) >> PAGESHIFT, self.pages.len());
+		if !self.pages[first..last].iter().any(|p| p.status.writable() && p.needs_trip()) {
+			return
+		}
+		unsafe {
+			// the refresh after this puts the real protections back
+			if !self.host_protect(addr, Protection::R) {
+				return
+			}
+			for index in first..last {
+				let page_start_addr = self.addr.start + (index << PAGESHIFT);
+				let page = &mut self.pages[index];
+				if !(page.status.writable() && page.needs_trip()) {
+					continue
 				}
-			})
-			.coalesce(|x, y| if x.prot == y.prot {
-				Ok(Chunk {
-					addr: AddressRange { start: x.addr.start, size: x.addr.size + y.addr.size },
-					prot: x.prot,
-				})
+				page.maybe_snapshot(page_start_addr);
+				page.dirty = true;
+				self.dirty_index.set(index);
+				if page.cow_pending {
+					cow::preserve(&self.cow, index, page_start_addr, PageBlock::try_copy);
+					page.cow_pending = false;
+				}
+			}
+		}
+	}
+
+	/// Refresh the correct protections in underlying host RAM on the host pages overlapping a range.  Use after
+	/// temporary host_protect(...) modifications, or to apply the effect of a dirty/prot change on the page
+	fn refresh_protections(&mut self, addr: AddressRange) {
+		self.trip_eagerly(addr);
+		self.apply_protections(addr);
+	}
+	/// refresh_protections(), for after host_protect(...) when nothing else has changed
+	fn apply_protections(&self, addr: AddressRange) {
+		let addr = self.host_expand(addr);
+		let per_host = self.host_page >> PAGESHIFT;
+		let host_state = |start: usize| {
+			let index = (start - self.addr.start) >> PAGESHIFT;
+			host_native_state(&self.pages[index..std::cmp::min(index + per_host, self.pages.len())], self.tracking)
+		};
+		unsafe {
+			if !self.protect_native(addr, |start| host_state(start).0) {
+				self.poison("changing guest memory protections");
+				return
+			}
+		}
+		if self.tracking != DirtyTracking::Userfaultfd {
+			return
+		}
+		let chunks = (addr.start..addr.end()).step_by(self.host_page)
+			.map(|start| (AddressRange { start, size: self.host_page }, host_state(start).1))
+			.coalesce(|x, y| if x.1 == y.1 {
+				Ok((AddressRange { start: x.0.start, size: x.0.size + y.0.size }, x.1))
 			} else {
 				Err((x, y))
 			});
+		for (range, wp) in chunks {
+			if unsafe { !uffd::writeprotect(range, wp) } {
+				self.poison("write protecting guest memory");
+			}
+		}
+	}
 
-		for c in chunks {
-			unsafe {
-				assert!(pal::protect(c.addr, c.prot));
+	/// The other half of ActivatedMemoryBlock::check_protections(), going by what Linux says is mapped
+	#[cfg(all(feature = "fuzz", target_os = "linux"))]
+	fn check_protections(&self) -> anyhow::Result<()> {
+		let maps = std::fs::read_to_string("/proc/self/maps")?;
+		let real_page = page_size::get();
+		let per_host = self.host_page >> PAGESHIFT;
+		let mut pos = self.addr.start;
+		for line in maps.lines() {
+			let mut fields = line.split_whitespace();
+			let (range, perms) = match (fields.next(), fields.next()) {
+				(Some(r), Some(p)) if p.len() >= 3 => (r, &p[..3]),
+				_ => return Err(anyhow!("Can't parse mapping {}", line)),
+			};
+			let mut ends = range.split('-').map(|v| usize::from_str_radix(v, 16));
+			let (start, end) = match (ends.next(), ends.next()) {
+				(Some(Ok(s)), Some(Ok(e))) => (std::cmp::max(s, self.addr.start), std::cmp::min(e, self.addr.end())),
+				_ => return Err(anyhow!("Can't parse mapping {}", line)),
+			};
+			if start >= end {
+				continue
+			}
+			if start != pos {
+				return Err(anyhow!("Guest memory at {:x}..{:x} isn't mapped", pos, start))
+			}
+			for p in (start..end).step_by(real_page) {
+				let first = ((p - self.addr.start) & !(self.host_page - 1)) >> PAGESHIFT;
+				let pages = &self.pages[first..std::cmp::min(first + per_host, self.pages.len())];
+				let mut wanted = host_native_state(pages, self.tracking).0;
+				if self.no_exec {
+					wanted = wanted.without_exec();
+				}
+				let wanted = match wanted {
+					Protection::None | Protection::StackGuard => "---",
+					Protection::R => "r--",
+					Protection::RW | Protection::RWStack => "rw-",
+					Protection::RX => "r-x",
+					Protection::RWX => "rwx",
+				};
+				if perms != wanted {
+					return Err(anyhow!("Guest memory at {:x} is {} but should be {}", p, perms, wanted))
+				}
 			}
+			pos = end;
+		}
+		if pos != self.addr.end() {
+			return Err(anyhow!("Guest memory at {:x}..{:x} isn't mapped", pos, self.addr.end()))
 		}
+		Ok(())
+	}
+	#[cfg(all(feature = "fuzz", not(target_os = "linux")))]
+	fn check_protections(&self) -> anyhow::Result<()> {
+		Ok(())
 	}
 
 	fn refresh_all_protections(&mut self) {
-		MemoryBlock::refresh_protections(&self.page_range())
+		let addr = self.addr;
+		self.refresh_protections(addr)
 	}
 
-	/// Applies new protections to a pagerange, including special RWStack handling on Windows
-	fn set_protections(range: &mut PageRange, status: PageAllocation) {
-		for p in range.iter_mut() {
+	/// Applies new protections to a range, which must already be validated, including special RWStack handling on Windows
+	fn set_protections(&mut self, addr: AddressRange, status: PageAllocation) {
+		for p in self.validate_range(addr).unwrap().iter_mut() {
 			p.status = status;
 		}
-		MemoryBlock::refresh_protections(&range);
+		self.refresh_protections(addr);
 		#[cfg(windows)]
 		if status == PageAllocation::Allocated(Protection::RWStack) {
 			// have to precapture snapshots here
-			let mut addr = range.start;
-			for p in range.iter_mut() {
+			for (paddr, p) in self.validate_range(addr).unwrap().iter_mut_with_addr() {
 				unsafe {
-					p.maybe_snapshot(addr);
+					p.maybe_snapshot(paddr.start);
 				}
-				addr += PAGESIZE;
 			}
 		}
 	}
 
+	/// Fail with ENOMEM if allocating `new_pages` more pages would go over the memory limit
+	fn check_memory_limit(&self, new_pages: usize) -> SyscallResult {
+		match self.memory_limit {
+			Some(limit) => {
+				let allocated = self.pages.iter().filter(|p| p.status != PageAllocation::Free).count();
+				if (allocated + new_pages) << PAGESHIFT > limit {
+					Err(ENOMEM)
+				} else {
+					Ok(())
+				}
+			},
+			None => Ok(()),
+		}
+	}
+
 	/// Updates knowledge on RWStack tripped areas.  Must be called before those areas change allocation type, or are swapped out.
 	/// noop on linux
 	fn get_stack_dirty(&mut self) {
@@ -441,6 +1133,7 @@ impl MemoryBlock {
 					while res.size > 0 && start < self.addr.end() {
 						if res.dirty && self.pages[pindex].status == PageAllocation::Allocated(Protection::RWStack) {
 							self.pages[pindex].dirty = true;
+							self.dirty_index.set(pindex);
 						}
 						res.size -= PAGESIZE;
 						start += PAGESIZE;
@@ -453,17 +1146,42 @@ impl MemoryBlock {
 			}
 		}
 	}
+
+	/// The indices of the dirty pages, in order, from dirty_index instead of a walk of every page
+	fn dirty_pages(&mut self) -> Vec<usize> {
+		debug_assert!(self.pages.iter().enumerate().all(|(index, p)| !p.dirty || self.dirty_index.get(index)),
+			"Dirty page missing from dirty_index");
+		let pages = &self.pages;
+		self.dirty_index.retain(|index| pages[index].dirty);
+		self.dirty_index.iter().collect()
+	}
 }
 
 impl Drop for MemoryBlock {
 	fn drop(&mut self) {
 		// self.trace("drop");
+		self.release();
+	}
+}
+
+impl MemoryBlock {
+	/// Give everything the block has back to the OS:  Its memory, if it's mapped in, the handle behind it, and the
+	/// snapshots of its pages.  Nothing can be done with the block afterwards but drop it.
+	pub fn release(&mut self) {
+		assert!(!self.active);
+		if self.released {
+			return
+		}
+		self.released = true;
+		self.integrity = None;
 		let area = lock_list::get(self.lock_index);
-		let mut guard = area.lock().unwrap();
+		let mut guard = area.lock();
 		let other_opt = guard.deref_mut();
 		match *other_opt {
 			Some(MemoryBlockRef(other)) => {
 				if other == self {
+					// nothing is going to look at them
+					self.state_file_pages = 0;
 					unsafe { self.swapout(); }
 					*other_opt = None;
 				}
@@ -472,12 +1190,33 @@ impl Drop for MemoryBlock {
 		}
 		let h = std::mem::replace(&mut self.handle, pal::bad());
 		unsafe { pal::close(h); }
+		leaks::BLOCKS.closed();
+		leaks::DESCRIPTORS.closed();
+		self.pages = Vec::new();
+		self.cow = None;
+	}
+	pub fn released(&self) -> bool {
+		self.released
+	}
+}
+
+/// Free a view made by map_host_view()
+/// unsafe: Nothing can be using the view anymore
+pub unsafe fn unmap_host_view(view: AddressRange) -> anyhow::Result<()> {
+	let extra = view.start % page_size::get();
+	if pal::unmap(AddressRange { start: view.start - extra, size: view.size + extra }) {
+		leaks::HOST_VIEWS.closed();
+		Ok(())
+	} else {
+		Err(anyhow!("Couldn't unmap host view"))
 	}
 }
 
 impl<'block> ActivatedMemoryBlock<'block> {
 	/// Looks for some free pages inside an arena
-	fn find_free_pages<'a>(arena: &'a mut PageRange<'a>, npages: usize) -> Result<PageRange<'a>, SyscallError> {
+	/// Find a run of free pages in the arena.  Normally the smallest free area that fits is used, but if `rng` is
+	/// supplied, the location is chosen at random out of all of the possible ones instead.
+	fn find_free_pages<'a>(arena: &'a mut PageRange<'a>, npages: usize, rng: Option<&mut u64>) -> Result<PageRange<'a>, SyscallError> {
 		struct Chunk<'a> {
 			range: PageRange<'a>,
 			free: bool,
@@ -498,7 +1237,27 @@ impl<'block> ActivatedMemoryBlock<'block> {
 				}
 			})
 			.filter(|c| c.free && c.range.pages.len() >= npages)
-			.map(|c| c.range)
+			.map(|c| c.range);
+		if let Some(rng) = rng {
+			let candidates = range.collect::<Vec<_>>();
+			let total = candidates.iter().map(|r| r.pages.len() - npages + 1).sum::<usize>();
+			if total == 0 {
+				return Err(ENOMEM)
+			}
+			let mut pick = (aslr_next(rng) % total as u64) as usize;
+			for r in candidates {
+				let n = r.pages.len() - npages + 1;
+				if pick < n {
+					return Ok(PageRange {
+						start: r.start + (pick << PAGESHIFT),
+						pages: &mut r.pages[pick..pick + npages]
+					})
+				}
+				pick -= n;
+			}
+			unreachable!()
+		}
+		let range = range
 			.sorted_by(|x, y| x.pages.len().cmp(&y.pages.len()))
 			.next();
 		match range {
@@ -521,11 +1280,16 @@ impl<'block> ActivatedMemoryBlock<'block> {
 		if size != align_down(size) {
 			return Err(EINVAL)
 		}
+		self.b.check_memory_limit(size >> PAGESHIFT)?;
+		let mut rng = self.b.aslr;
 		let mut arena = self.b.validate_range(arena_addr).unwrap();
-		match ActivatedMemoryBlock::find_free_pages(&mut arena, size >> PAGESHIFT) {
-			Ok(mut range) => {
-				MemoryBlock::set_protections(&mut range, PageAllocation::Allocated(prot));
-				Ok(range.start)		
+		let found = ActivatedMemoryBlock::find_free_pages(&mut arena, size >> PAGESHIFT, rng.as_mut()).map(|r| r.addr());
+		match found {
+			Ok(addr) => {
+				self.b.set_protections(addr, PageAllocation::Allocated(prot));
+				self.b.aslr = rng;
+				self.b.hint_huge_pages(addr);
+				Ok(addr.start)
 			},
 			Err(e) => Err(e),
 		}
@@ -533,11 +1297,20 @@ impl<'block> ActivatedMemoryBlock<'block> {
 
 	/// implements a subset of mmap(2) for anonymous, fixed address mappings
 	pub fn mmap_fixed(&mut self, addr: AddressRange, prot: Protection, no_replace: bool) -> SyscallResult {
-		let mut range = self.b.validate_range(addr)?;
+		if !no_replace {
+			self.b.resolve_cow_checked(addr);
+		}
+		let range = self.b.validate_range(addr)?;
 		if no_replace && range.iter().any(|p| p.status != PageAllocation::Free) {
 			return Err(EEXIST)
 		}
-		MemoryBlock::set_protections(&mut range, PageAllocation::Allocated(prot));
+		range.check_mutable()?;
+		let new_pages = range.iter().filter(|p| p.status == PageAllocation::Free).count();
+		self.b.check_memory_limit(new_pages)?;
+		self.b.integrity_forget(addr);
+		self.b.set_protections(addr, PageAllocation::Allocated(prot));
+		self.b.integrity_learn(addr);
+		self.b.hint_huge_pages(addr);
 		Ok(())
 	}
 
@@ -547,14 +1320,18 @@ impl<'block> ActivatedMemoryBlock<'block> {
 		if new_size > addr.size {
 			let full_addr = AddressRange { start: addr.start, size: new_size };
 			let mut range = self.b.validate_range(full_addr)?;
-			let (old_range, mut new_range) = range.split_at_size(addr.size);
+			let (old_range, new_range) = range.split_at_size(addr.size);
 			if old_range.iter().any(|p| p.status == PageAllocation::Free) {
 				return Err(EINVAL)
 			}
+			old_range.check_mutable()?;
 			if new_range.iter().any(|p| p.status != PageAllocation::Free) {
 				return Err(EEXIST)
 			}
-			MemoryBlock::set_protections(&mut new_range, old_range.pages[0].status);
+			let (status, new_addr) = (old_range.pages[0].status, new_range.addr());
+			self.b.check_memory_limit(new_addr.size >> PAGESHIFT)?;
+			self.b.set_protections(new_addr, status);
+			self.b.hint_huge_pages(full_addr);
 			Ok(())
 		} else {
 			let range = self.b.validate_range(addr)?;
@@ -573,75 +1350,217 @@ impl<'block> ActivatedMemoryBlock<'block> {
 		if new_size != align_down(new_size) {
 			return Err(EINVAL)
 		}
+		self.b.resolve_cow_checked(addr);
 
 		// save a copy of src, and unmap
-		let mut src = self.b.validate_range(addr)?;
+		let src = self.b.validate_range(addr)?;
 		if src.iter().any(|p| p.status == PageAllocation::Free) {
 			return Err(EINVAL)
 		}
+		src.check_mutable()?;
+		let src_pages = src.pages.len();
+		self.b.check_memory_limit((new_size >> PAGESHIFT).saturating_sub(src_pages))?;
+		let src = self.b.validate_range(addr)?;
 		let src_addr = src.addr();
 		let mut old_status = Vec::new();
 		old_status.reserve_exact(src.pages.len());
+		let mut old_zero = Vec::new();
+		old_zero.reserve_exact(src.pages.len());
 		let mut old_data = vec![0u8; src_addr.size];
 		for p in src.iter() {
 			old_status.push(p.status);
+			old_zero.push(p.known_zero());
 		}
 		unsafe {
-			pal::protect(src_addr, Protection::R);
-			old_data.copy_from_slice(src_addr.slice());
+			self.b.host_protect(src_addr, Protection::R);
+			// pages that were never touched don't need to be read, which would force them to be committed
+			for (i, &zero) in old_zero.iter().enumerate() {
+				if !zero {
+					let paddr = AddressRange { start: src_addr.start + (i << PAGESHIFT), size: PAGESIZE };
+					old_data[i << PAGESHIFT..(i + 1) << PAGESHIFT].copy_from_slice(paddr.slice());
+				}
+			}
 		}
-		ActivatedMemoryBlock::free_pages_impl(&mut src, false);
+		self.free_pages_impl(src_addr, false);
 
 		// find new location to map to, and copy into there
+		let mut rng = self.b.aslr;
 		let mut arena = self.b.validate_range(arena_addr).unwrap();
-		let mut dest = match ActivatedMemoryBlock::find_free_pages(&mut arena, new_size >> PAGESHIFT) {
-			Ok(r) => r,
-			Err(_) => {
-				// woops! reallocate at the old address.
-				// Or just panic because that probably won't happen
-				panic!("Failure in realloc")
-			},
+		let (dest_addr, res) = match ActivatedMemoryBlock::find_free_pages(&mut arena, new_size >> PAGESHIFT, rng.as_mut()) {
+			Ok(r) => (r.addr(), Ok(())),
+			// nowhere to go, so put it all back where it was
+			Err(_) => (src_addr, Err(ENOMEM)),
 		};
-		let nbcopy = std::cmp::min(addr.size, new_size);
-		let npcopy = nbcopy >> PAGESHIFT;
 		unsafe {
-			pal::protect(dest.addr(), Protection::RW);
-			dest.addr().slice_mut()[0..nbcopy].copy_from_slice(&old_data[0..nbcopy]);
+			self.b.host_protect(dest_addr, Protection::RW);
 		}
-		for (status, pdst) in old_status.iter().zip(dest.iter_mut()) {
-			pdst.status = *status;
-			// this is conservative; there are situations where dirty might be false,
-			// but we're unlikely to see them with real world realloc usage
-			pdst.dirty = true;
+		let mut dest = self.b.validate_range(dest_addr).unwrap();
+		let npcopy = std::cmp::min(old_status.len(), dest.pages.len());
+		unsafe {
+			for (i, (paddr, pdst)) in dest.iter_mut_with_addr().take(npcopy).enumerate() {
+				pdst.status = old_status[i];
+				if old_zero[i] && pdst.known_zero() {
+					// both are untouched zeroes; leave them that way
+					continue
+				}
+				paddr.slice_mut().copy_from_slice(&old_data[i << PAGESHIFT..(i + 1) << PAGESHIFT]);
+				// this is conservative; there are situations where dirty might be false,
+				// but we're unlikely to see them with real world realloc usage
+				pdst.dirty = true;
+			}
 		}
 		for pdst in dest.pages[npcopy..].iter_mut() {
 			pdst.status = old_status[0];
 		}
-		MemoryBlock::refresh_protections(&dest);
-		Ok(dest.start)
+		self.b.dirty_index.set_range(self.b.addr, dest_addr);
+		self.b.refresh_protections(dest_addr);
+		self.b.aslr = rng;
+		res?;
+		self.b.hint_huge_pages(dest_addr);
+		Ok(dest_addr.start)
 	}
 
 	/// implements a subset of mprotect(2)
 	pub fn mprotect(&mut self, addr: AddressRange, prot: Protection) -> SyscallResult {
 		self.b.get_stack_dirty();
-		let mut range = self.b.validate_range(addr)?;
+		self.b.resolve_cow_checked(addr);
+		let range = self.b.validate_range(addr)?;
 		if range.iter().any(|p| p.status == PageAllocation::Free) {
 			return Err(ENOMEM)
 		}
-		MemoryBlock::set_protections(&mut range, PageAllocation::Allocated(prot));
+		range.check_mutable()?;
+		self.b.integrity_forget(addr);
+		self.b.set_protections(addr, PageAllocation::Allocated(prot));
+		self.b.integrity_learn(addr);
 		Ok(())
 	}
 
-	/// implements a subset of munmap(2)
+	/// Whether all `size` bytes at `addr` are memory the guest could read, or write too if `write`
+	pub fn guest_accessible(&self, addr: usize, size: usize, write: bool) -> bool {
+		let check: fn(&Page) -> bool = if write { |p| p.status.writable() } else { |p| p.status.readable() };
+		size == 0 || addr.checked_add(size).is_some() && self.b.accessible_len(addr, size, check) == size
+	}
+	/// Whether there's a nul terminated string at `addr` that the guest could read
+	pub fn guest_str_accessible(&self, addr: usize) -> bool {
+		let mut pos = addr;
+		loop {
+			let size = PAGESIZE - (pos & (PAGESIZE - 1));
+			if !self.guest_accessible(pos, size, false) {
+				return false
+			}
+			if unsafe { AddressRange { start: pos, size }.slice() }.contains(&0) {
+				return true
+			}
+			pos += size;
+		}
+	}
+
+	/// implements a subset of munmap(2).  Unmapping the bottom of a stack takes its guard page with it.
 	pub fn munmap(&mut self, addr: AddressRange) -> SyscallResult {
+		let addr = self.b.with_stack_guard(addr);
 		self.munmap_impl(addr, false)
 	}
 
+	/// Map a second, read only view of part of guest memory somewhere outside of the guest's address space, so the
+	/// host can look at it without copying it out.  The view always shows exactly what the guest sees, including after
+	/// loading states, and stays valid even when this block isn't active.  Free it with unmap_host_view().  The view is
+	/// of the block's handle, which pages still mapped copy-on-write from a state file aren't in, so those are copied in
+	/// first, and from then on states loaded from files are copied instead of mapped.
+	pub fn map_host_view(&mut self, addr: AddressRange) -> anyhow::Result<AddressRange> {
+		let addr = addr.align_expand();
+		if addr.size == 0 || addr.start < self.b.addr.start || addr.end() > self.b.addr.end() {
+			return Err(coded(ErrorCode::UnmappedAddress, "Host view must be inside the MemoryBlock"))
+		}
+		self.b.share_handle();
+		// the block maps its whole backing object, starting from the beginning.  views of it have to start on a real
+		// host page, so there might be some extra in front.
+		let host_page = page_size::get();
+		let offset = addr.start - self.b.addr.start;
+		let extra = offset % host_page;
+		match pal::map_view(&self.b.handle, offset - extra, addr.size + extra) {
+			Some(start) => {
+				leaks::HOST_VIEWS.opened();
+				Ok(AddressRange { start: start + extra, size: addr.size })
+			},
+			None => Err(anyhow!("Couldn't map host view"))
+		}
+	}
+	/// Copy guest memory at `addr` into `dest`, stopping at the first page the guest can't read.  Returns how many
+	/// bytes were copied.  Doesn't trip watchpoints.
+	pub fn read(&mut self, addr: usize, dest: &mut [u8]) -> usize {
+		let len = self.b.accessible_len(addr, dest.len(), |p| p.status.readable());
+		let mut done = 0;
+		while done < len {
+			let pos = addr + done;
+			let paddr = AddressRange { start: align_down(pos), size: PAGESIZE };
+			let n = std::cmp::min(paddr.end() - pos, len - done);
+			let p = &self.b.pages[(paddr.start - self.b.addr.start) >> PAGESHIFT];
+			unsafe {
+				if !p.host_readable() && !self.b.host_protect(paddr, Protection::R) {
+					return done
+				}
+				dest[done..done + n].copy_from_slice(AddressRange { start: pos, size: n }.slice());
+				if !p.host_readable() {
+					self.b.refresh_protections(paddr);
+				}
+			}
+			done += n;
+		}
+		len
+	}
+	/// Copy `src` into guest memory at `addr`, stopping at the first page the guest can't write.  Returns how many
+	/// bytes were copied.  The pages written to are dirtied as if the guest had written them, but watchpoints aren't
+	/// tripped.
+	pub fn write(&mut self, addr: usize, src: &[u8]) -> usize {
+		let len = self.b.accessible_len(addr, src.len(), |p| p.status.writable());
+		if len == 0 {
+			return 0
+		}
+		let range = AddressRange { start: addr, size: len };
+		let expanded = range.align_expand();
+		self.b.get_stack_dirty();
+		self.b.resolve_cow(expanded);
+		self.b.unmap_state_file(expanded);
+		unsafe {
+			if !self.b.host_protect(expanded, Protection::RW) {
+				return 0
+			}
+			let mut snapshotted = true;
+			for (paddr, p) in self.b.validate_range(expanded).unwrap().iter_mut_with_addr() {
+				if p.try_snapshot(paddr.start).is_err() {
+					snapshotted = false;
+					break
+				}
+				p.dirty = true;
+			}
+			self.b.dirty_index.set_range(self.b.addr, expanded);
+			if !snapshotted {
+				// nothing's been written yet, and pages already dirtied can stay that way
+				self.b.refresh_protections(expanded);
+				return 0
+			}
+			range.slice_mut().copy_from_slice(&src[..len]);
+		}
+		self.b.refresh_protections(expanded);
+		len
+	}
+	/// The OS handle (or fd) of the shared memory object behind guest memory.  Offsets into it are relative to the
+	/// start of the block.  Other processes that map it must not write to it.
+	pub fn backing_handle(&mut self) -> usize {
+		self.b.share_handle();
+		self.b.handle.raw()
+	}
+
 	pub fn mmap(&mut self, addr: AddressRange, prot: Protection, arena_addr: AddressRange, no_replace: bool) -> Result<usize, SyscallError> {
 		if addr.size == 0 {
 			return Err(EINVAL)
 		}
-		if addr.start == 0 {
+		if addr.start == 0 && prot == Protection::RWStack {
+			// with a guard page below, so an overflow faults instead of running into whatever's next
+			let start = self.mmap_movable(addr.size.checked_add(PAGESIZE).ok_or(ENOMEM)?, prot, arena_addr)?;
+			self.b.set_protections(AddressRange { start, size: PAGESIZE }, PageAllocation::Allocated(Protection::StackGuard));
+			Ok(start + PAGESIZE)
+		} else if addr.start == 0 {
 			self.mmap_movable(addr.size, prot, arena_addr)
 		} else {
 			self.mmap_fixed(addr, prot, no_replace)?;
@@ -649,27 +1568,64 @@ impl<'block> ActivatedMemoryBlock<'block> {
 		}
 	}
 
-	pub fn mremap(&mut self, addr: AddressRange, new_size: usize, arena_addr: AddressRange) -> Result<usize, SyscallError> {
-		if addr.size == 0 || new_size == 0 {
+	/// implements a subset of mremap(2).  With MREMAP_MAYMOVE, a mapping that can't grow in place moves somewhere in
+	/// arena_addr.  MREMAP_FIXED is not supported.
+	pub fn mremap(&mut self, addr: AddressRange, new_size: usize, flags: usize, arena_addr: AddressRange) -> Result<usize, SyscallError> {
+		// sizes are rounded up, like linux does, and can't wrap around to nothing
+		let addr = AddressRange { start: addr.start, size: align_up(addr.size) };
+		let new_size = align_up(new_size);
+		if addr.size == 0 || new_size == 0 || flags & !MREMAP_MAYMOVE != 0 {
 			return Err(EINVAL)
 		}
-		if addr.start == 0 {
-			self.mremap_maymove(addr, new_size, arena_addr)
+		self.b.integrity_forget(addr);
+		let res = if flags & MREMAP_MAYMOVE != 0 && new_size > addr.size {
+			match self.mremap_nomove(addr, new_size) {
+				Ok(()) => Ok(addr.start),
+				Err(_) => self.mremap_maymove(addr, new_size, arena_addr),
+			}
 		} else {
-			self.mremap_nomove(addr, new_size)?;
-			Ok(addr.start)
+			self.mremap_nomove(addr, new_size).map(|_| addr.start)
+		};
+		self.b.integrity_learn(addr);
+		if let Ok(start) = res {
+			self.b.integrity_learn(AddressRange { start, size: new_size });
 		}
+		res
 	}
 
 	/// release pages, assuming the range has been fully validated already
-	fn free_pages_impl(range: &mut PageRange, advise_only: bool) {
-		let addr = range.addr();
+	fn free_pages_impl(&mut self, addr: AddressRange, advise_only: bool) {
 		// we do not save the current state of unmapped pages, and if they are later remapped,
 		// the expectation is that they will start out as zero filled.  accordingly, the most
 		// sensible way to do this is to zero them now
+		self.b.unmap_state_file(addr);
 		unsafe {
-			pal::protect(addr, Protection::RW);
-			addr.zero();
+			self.b.host_protect(addr, Protection::RW);
+			let host_page = self.b.host_page;
+			let committed = self.b.committed;
+			let mut range = self.b.validate_range(addr).unwrap();
+			// untouched pages are already zero, and zeroing them anyway would commit them.  everything else
+			// is given back to the OS if possible, so that long running guests don't grow without bound.
+			let runs = range.iter_with_addr()
+				.filter(|(_, p)| !p.known_zero())
+				.map(|(paddr, _)| paddr)
+				.coalesce(|x, y| if x.end() == y.start {
+					Ok(AddressRange { start: x.start, size: x.size + y.size })
+				} else {
+					Err((x, y))
+				});
+			for run in runs {
+				// only whole host pages can be given back, and the ends of the run might share theirs with other pages
+				let start = align_host(run.start, host_page);
+				let end = std::cmp::max(run.end() & !(host_page - 1), start);
+				let inner = AddressRange { start, size: end - start };
+				if inner.size == 0 || committed || !pal::decommit(inner) {
+					run.zero();
+				} else {
+					AddressRange { start: run.start, size: start - run.start }.zero();
+					AddressRange { start: end, size: run.end() - end }.zero();
+				}
+			}
 			// simple state size optimization: we can undirty pages in this case depending on the initial state
 			for p in range.iter_mut() {
 				p.dirty = !p.invisible && match p.snapshot {
@@ -677,22 +1633,26 @@ impl<'block> ActivatedMemoryBlock<'block> {
 					_ => true
 				};
 			}
+			self.b.dirty_index.set_range(self.b.addr, addr);
 		}
 		if advise_only {
-			MemoryBlock::refresh_protections(range);
+			self.b.refresh_protections(addr);
 		} else {
-			MemoryBlock::set_protections(range, PageAllocation::Free);
+			self.b.set_protections(addr, PageAllocation::Free);
 		}
 	}
 
 	/// munmap or MADV_DONTNEED
 	fn munmap_impl(&mut self, addr: AddressRange, advise_only: bool) -> SyscallResult {
 		self.b.get_stack_dirty();
-		let mut range = self.b.validate_range(addr)?;
+		self.b.resolve_cow_checked(addr);
+		let range = self.b.validate_range(addr)?;
 		if range.iter().any(|p| p.status == PageAllocation::Free) {
 			return Err(EINVAL)
 		}
-		ActivatedMemoryBlock::free_pages_impl(&mut range, advise_only);
+		range.check_mutable()?;
+		self.b.integrity_forget(addr);
+		self.free_pages_impl(addr, advise_only);
 		Ok(())
 	}
 	/// Marks an address range as invisible.  Its page content will not be saved in states (but
@@ -709,15 +1669,393 @@ impl<'block> ActivatedMemoryBlock<'block> {
 			p.dirty = true;
 			p.invisible = true;
 		}
-		MemoryBlock::refresh_protections(&range);
+		self.b.dirty_index.set_range(self.b.addr, addr);
+		self.b.refresh_protections(addr);
+		Ok(())
+	}
+
+	/// Marks an address range, which must be mapped readonly, as immutable:  The guest can never change, remap or
+	/// unmap it, so its pages are never dirty and are passed over by everything that saves, loads or compares states.
+	/// For cores whose guest memory is mostly ROM.  Cannot be revoked.  Must be done before sealing.
+	pub fn mark_immutable(&mut self, addr: AddressRange) -> SyscallResult {
+		assert!(!self.b.sealed);
+		self.b.get_stack_dirty();
+		self.b.resolve_cow_checked(addr);
+		let mut range = self.b.validate_range(addr)?;
+		if range.iter().any(|p| p.status == PageAllocation::Free) {
+			return Err(ENOMEM)
+		}
+		let readonly = |p: &Page| matches!(p.status, PageAllocation::Allocated(Protection::R) | PageAllocation::Allocated(Protection::RX));
+		if range.iter().any(|p| !readonly(p) || p.invisible || p.transient) {
+			return Err(EINVAL)
+		}
+		for p in range.iter_mut() {
+			p.immutable = true;
+		}
+		Ok(())
+	}
+
+	/// Marks an address range as transient, or not.  Transient page content, like a framebuffer or a decode cache
+	/// that's rebuilt as needed, is not saved in states and reads as zero after one is loaded.  Can be changed at
+	/// any time.  The pages need not be currently mapped.
+	/// !!Not actually saved in states, as is assumed to be unchanging for a particular layout.!!
+	pub fn mark_transient(&mut self, addr: AddressRange, transient: bool) -> SyscallResult {
+		let mut range = self.b.validate_range(addr)?;
+		range.check_mutable()?;
+		for p in range.iter_mut() {
+			p.transient = transient;
+		}
 		Ok(())
 	}
 
 	/// implements a subset of madvise(2)
 	pub fn madvise_dontneed(&mut self, addr: AddressRange) -> SyscallResult {
-		self.munmap_impl(addr, true)
+		self.munmap_impl(addr, true)?;
+		if self.b.scribble {
+			self.fill(addr, SCRIBBLE_FREED);
+		}
+		self.b.integrity_learn(addr);
+		Ok(())
+	}
+
+	/// implements madvise(2).  Advice that changes what the pages hold is followed, and the rest are only hints, which
+	/// are accepted and ignored.
+	pub fn madvise(&mut self, addr: AddressRange, advice: usize) -> SyscallResult {
+		if addr.start != align_down(addr.start) {
+			return Err(EINVAL)
+		}
+		let addr = AddressRange { start: addr.start, size: addr.size.checked_add(PAGEMASK).ok_or(EINVAL)? & !PAGEMASK };
+		if addr.size == 0 {
+			return Ok(())
+		}
+		if addr.start < self.b.addr.start || addr.checked_end().filter(|&end| end <= self.b.addr.end()).is_none() {
+			return Err(ENOMEM)
+		}
+		let first = (addr.start - self.b.addr.start) >> PAGESHIFT;
+		if self.b.pages[first..first + (addr.size >> PAGESHIFT)].iter().any(|p| p.status == PageAllocation::Free) {
+			return Err(ENOMEM)
+		}
+		match advice {
+			// MADV_FREE lets the kernel take the pages back whenever it likes, so until they're written again, the guest
+			// can't count on them holding either zeroes or what they did.  Taking them back right away keeps that the
+			// same from run to run.
+			MADV_DONTNEED | MADV_FREE | MADV_REMOVE => self.madvise_dontneed(addr),
+			_ => Ok(()),
+		}
+	}
+
+	/// Give back the host memory behind pages that only hold zeroes, the way malloc_trim() does for memory an allocator
+	/// has freed without unmapping it.  Nothing the guest can see changes, so this can be done whenever.  Returns how
+	/// many bytes were given back.
+	pub fn trim(&mut self) -> usize {
+		if self.b.committed || !cfg!(target_os = "linux") {
+			return 0
+		}
+		let all = self.b.host_expand(self.b.addr);
+		let host_page = page_size::get();
+		// reading a page that was never touched would commit it
+		let resident = match unsafe { pal::resident_map(all) } {
+			Some(r) => r,
+			None => return 0,
+		};
+		self.b.resolve_cow_all();
+		let mut res = 0;
+		unsafe {
+			self.b.host_protect(all, Protection::R);
+			let runs = resident.iter().enumerate()
+				.filter(|&(_, &r)| r)
+				.map(|(i, _)| AddressRange { start: all.start + i * host_page, size: host_page })
+				.filter(|&haddr| {
+					let first = haddr.start.saturating_sub(self.b.addr.start) >> PAGESHIFT;
+					let last = std::cmp::min(haddr.end().saturating_sub(self.b.addr.start) >> PAGESHIFT, self.b.pages.len());
+					!self.b.pages[first..last].iter().any(|p| p.from_file) && haddr.slice().chunks(PAGESIZE).all(pagecmp::is_zero)
+				})
+				.coalesce(|x, y| if x.end() == y.start {
+					Ok(AddressRange { start: x.start, size: x.size + y.size })
+				} else {
+					Err((x, y))
+				})
+				.collect::<Vec<_>>();
+			for run in runs {
+				if self.b.host_protect(run, Protection::RW) && pal::decommit(run) {
+					res += run.size;
+				}
+			}
+		}
+		self.b.refresh_all_protections();
+		res
+	}
+
+	/// In sanitizer mode, tell ASan that all of the block can be accessed.  Do this whenever the guest stops running,
+	/// since host code that ran on guest stacks has left ASan's marks for dead stack frames all over them.
+	pub fn unpoison_for_sanitizers(&mut self) {
+		if self.b.sanitizer {
+			sanitizer::unpoison(self.b.host_expand(self.b.addr));
+		}
+	}
+
+	/// Control whether memory the guest maps is filled with SCRIBBLE_NEW, and memory it discards with MADV_DONTNEED
+	/// with SCRIBBLE_FREED, instead of reading as zeros like it would on a real kernel
+	pub fn set_scribble(&mut self, val: bool) {
+		self.b.scribble = val;
+	}
+	/// Fill `addr`, which the guest just mapped, with SCRIBBLE_NEW if scribbling.  Not for memory the host maps for
+	/// itself, which has to start out zero.
+	pub fn scribble_new(&mut self, addr: AddressRange) {
+		if self.b.scribble {
+			self.fill(addr, SCRIBBLE_NEW);
+		}
+	}
+	/// Fill all of `addr`, which must be mapped, with `value`, like the guest had written it.  If there's no memory to
+	/// snapshot the pages first, they're left alone.
+	fn fill(&mut self, addr: AddressRange, value: u8) {
+		self.b.get_stack_dirty();
+		self.b.resolve_cow(addr);
+		self.b.unmap_state_file(addr);
+		self.b.integrity_forget(addr);
+		unsafe {
+			if !self.b.host_protect(addr, Protection::RW) {
+				return
+			}
+			for (paddr, p) in self.b.validate_range(addr).unwrap().iter_mut_with_addr() {
+				if p.try_snapshot(paddr.start).is_ok() {
+					p.dirty = true;
+					paddr.slice_mut().iter_mut().for_each(|b| *b = value);
+				}
+			}
+			self.b.dirty_index.set_range(self.b.addr, addr);
+		}
+		self.b.refresh_protections(addr);
+		self.b.integrity_learn(addr);
+	}
+
+	/// Control whether fully allocated, huge page aligned parts of the block are hinted to be backed by huge pages.
+	/// Improves TLB usage for large guests.  Only a hint; when the OS can't provide them, normal pages are used.
+	pub fn set_huge_pages(&mut self, val: bool) {
+		self.b.huge_pages = val;
+		let addr = self.b.addr;
+		self.b.hint_huge_pages(addr);
+	}
+
+	/// Commit host memory for the whole block now.  Otherwise, the block's address space is only reserved, and memory is
+	/// committed a page at a time as the guest uses it, so a big block costs nothing up front but running out of memory
+	/// later faults the guest.  Committed memory stays committed for the life of the block.
+	pub fn commit_all(&mut self) -> anyhow::Result<()> {
+		let mapped = self.b.host_expand(self.b.addr);
+		if !unsafe { pal::commit(&self.b.handle, mapped) } {
+			return Err(coded(ErrorCode::OutOfMemory, format!("Couldn't commit {} bytes of memory for the guest", mapped.size)))
+		}
+		self.b.committed = true;
+		self.b.refresh_protections(mapped);
+		Ok(())
+	}
+
+	/// Keep the block's host memory on one NUMA node, moving any that's elsewhere now, or let the OS place it as usual
+	/// again with None.  This outlives deactivating the block.
+	pub fn set_numa_node(&mut self, node: Option<u32>) -> anyhow::Result<()> {
+		let mapped = self.b.host_expand(self.b.addr);
+		if unsafe { pal::bind_node(mapped, node) } {
+			Ok(())
+		} else {
+			Err(anyhow!("Couldn't bind guest memory to NUMA node {:?}", node))
+		}
+	}
+
+	/// How many bytes of the block's host memory are on each NUMA node, indexed by node.  Memory that hasn't been
+	/// touched isn't anywhere yet, and isn't counted.
+	pub fn numa_usage(&self) -> anyhow::Result<Vec<usize>> {
+		let mapped = self.b.host_expand(self.b.addr);
+		let nodes = unsafe { pal::page_nodes(mapped) }
+			.ok_or_else(|| anyhow!("Couldn't get the NUMA nodes of guest memory"))?;
+		let mut res = Vec::new();
+		for n in nodes.into_iter().filter(|&n| n >= 0).map(|n| n as usize) {
+			if n >= res.len() {
+				res.resize(n + 1, 0);
+			}
+			res[n] += self.b.host_page;
+		}
+		Ok(res)
+	}
+
+	/// Fail if an OS call guest memory depends on has failed before
+	pub fn check_poisoned(&self) -> anyhow::Result<()> {
+		if self.b.poisoned() {
+			Err(coded(ErrorCode::Poisoned, "Guest memory is unusable after an earlier OS failure"))
+		} else {
+			Ok(())
+		}
+	}
+
+	/// Limit how many bytes of pages can be allocated at once, or remove the limit with None.  Guest allocations that
+	/// would go over it fail with ENOMEM.  Pages that are already allocated stay that way, even if they're over it.
+	pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+		self.b.memory_limit = limit;
+	}
+
+	/// Randomize the placement of movable mappings from now on, for shaking out hidden assumptions about where
+	/// memory ends up.  The sequence of placements is entirely determined by `seed`.  Must be done before sealing.
+	pub fn set_mmap_seed(&mut self, seed: u64) {
+		assert!(!self.b.sealed);
+		// xorshift gets stuck on 0
+		let state = seed ^ 0x9e3779b97f4a7c15;
+		self.b.aslr = Some(if state == 0 { 1 } else { state });
+	}
+
+	/// Gather memory usage information for this block
+	pub fn stats(&mut self) -> MemoryStats {
+		self.b.get_stack_dirty();
+		let mut res = MemoryStats::default();
+		for p in self.b.pages.iter() {
+			match p.status {
+				PageAllocation::Free => res.free_pages += 1,
+				PageAllocation::Allocated(Protection::None) | PageAllocation::Allocated(Protection::StackGuard) => res.none_pages += 1,
+				PageAllocation::Allocated(Protection::R) => res.r_pages += 1,
+				PageAllocation::Allocated(Protection::RW) => res.rw_pages += 1,
+				PageAllocation::Allocated(Protection::RX) => res.rx_pages += 1,
+				PageAllocation::Allocated(Protection::RWX) => res.rwx_pages += 1,
+				PageAllocation::Allocated(Protection::RWStack) => res.rwstack_pages += 1,
+			}
+			if p.in_state() && self.b.sealed {
+				res.dirty_bytes += PAGESIZE;
+			}
+			if let Snapshot::Data(_) = p.snapshot {
+				res.snapshot_bytes += PAGESIZE;
+			}
+		}
+		res.committed_bytes = unsafe { pal::resident_pages(self.b.host_expand(self.b.addr)) }
+			.map(|n| n * page_size::get())
+			.unwrap_or((self.b.pages.len() - res.free_pages) * PAGESIZE);
+		res.page_pool_bytes = page_pool_size();
+		res
+	}
+
+	/// How many bytes of the pages `addr` is on are mapped, and how many of those would be in a state now
+	pub fn usage(&mut self, addr: AddressRange) -> (usize, usize) {
+		self.b.get_stack_dirty();
+		let start = std::cmp::max(addr.start, self.b.addr.start);
+		let end = std::cmp::min(addr.checked_end().unwrap_or(usize::MAX), self.b.addr.end());
+		if start >= end {
+			return (0, 0)
+		}
+		let first = (align_down(start) - self.b.addr.start) >> PAGESHIFT;
+		let last = (align_up(end) - self.b.addr.start) >> PAGESHIFT;
+		let pages = &self.b.pages[first..last];
+		let mapped = pages.iter().filter(|p| p.status != PageAllocation::Free).count();
+		let saved = if self.b.sealed { pages.iter().filter(|p| p.in_state()).count() } else { 0 };
+		(mapped << PAGESHIFT, saved << PAGESHIFT)
+	}
+
+	/// For each page in the block, whether it's mapped
+	pub fn allocated_pages(&self) -> Vec<bool> {
+		self.b.pages.iter().map(|p| p.status != PageAllocation::Free).collect()
 	}
 
+	/// Make every read only page in `addr` read-write again, leaving the others as they are
+	#[cfg(feature = "dev-unseal")]
+	pub fn make_writable(&mut self, addr: AddressRange) -> SyscallResult {
+		self.b.validate_range(addr)?;
+		let first = (addr.start - self.b.addr.start) >> PAGESHIFT;
+		let runs = self.b.pages[first..first + (addr.size >> PAGESHIFT)].iter().enumerate()
+			.filter(|(_, p)| p.status == PageAllocation::Allocated(Protection::R))
+			.map(|(i, _)| (first + i, first + i + 1))
+			.coalesce(|x, y| if x.1 == y.0 { Ok((x.0, y.1)) } else { Err((x, y)) })
+			.collect::<Vec<_>>();
+		for (start, end) in runs {
+			let run = AddressRange { start: self.b.addr.start + (start << PAGESHIFT), size: (end - start) << PAGESHIFT };
+			self.mprotect(run, Protection::RW)?;
+		}
+		Ok(())
+	}
+
+	/// Check that the host's protections on every page of the block are what its bookkeeping says they should be
+	#[cfg(feature = "fuzz")]
+	pub fn check_protections(&self) -> anyhow::Result<()> {
+		self.b.check_protections()
+	}
+
+	/// How many bytes save_state() would write right now
+	pub fn state_size(&mut self) -> usize {
+		self.b.get_stack_dirty();
+		let dirty = self.b.dirty_pages().into_iter().filter(|&index| self.b.pages[index].in_state()).count();
+		self.state_header_size() + dirty * PAGESIZE
+	}
+	/// How many of those bytes come before the first page
+	pub fn state_header_size(&self) -> usize {
+		MAGIC.len() + self.b.hash.len() + std::mem::size_of::<AddressRange>()
+			+ self.b.pages.len() * (std::mem::size_of::<PageAllocation>() + std::mem::size_of::<bool>())
+			+ self.b.aslr.map_or(0, |_| std::mem::size_of::<u64>())
+	}
+
+	/// A quick hash of everything readable that would be in a savestate, for comparing states without making them.
+	/// Pages that haven't changed since sealing are left out, like they are from states.
+	pub fn state_hash(&mut self) -> u64 {
+		self.b.get_stack_dirty();
+		let pages = self.b.dirty_pages().into_iter()
+			.filter(|&index| self.b.pages[index].in_state() && self.b.pages[index].status.readable())
+			.map(|index| (index, AddressRange { start: self.b.addr.start + (index << PAGESHIFT), size: PAGESIZE }))
+			.collect::<Vec<_>>();
+		let hidden = match unsafe { self.b.host_protect_unreadable(pages.iter().map(|&(index, _)| index)) } {
+			Some(runs) => runs,
+			// the host is poisoned, so there's nothing sensible to compare this to anyway
+			None => return 0,
+		};
+		// each page is hashed on its own, so they can all be done at once
+		let hashes = workers::map(&pages[..], |&(_, paddr)| unsafe { xxh3::xxh3_64(paddr.slice()) });
+		for &run in hidden.iter() {
+			self.b.refresh_protections(run);
+		}
+		let mut all = Vec::with_capacity(pages.len() * 16);
+		for (&(index, _), h) in pages.iter().zip(hashes) {
+			all.extend_from_slice(&(index as u64).to_le_bytes());
+			all.extend_from_slice(&h.to_le_bytes());
+		}
+		xxh3::xxh3_64(&all[..])
+	}
+
+	/// Marks as clean any dirty pages whose content is once again identical to their snapshot, so that they
+	/// will not need to be included in states.  Returns the number of pages cleaned.
+	pub fn clean_unchanged_pages(&mut self) -> usize {
+		self.b.get_stack_dirty();
+		let mut count = 0;
+		let mut touched = false;
+		for index in self.b.dirty_pages() {
+			let paddr = AddressRange { start: self.b.addr.start + (index << PAGESHIFT), size: PAGESIZE };
+			if !self.b.pages[index].dirty || self.b.pages[index].invisible {
+				continue
+			}
+			if self.b.tracking == DirtyTracking::Eager && self.b.pages[index].status.writable() {
+				// it would only be dirtied again straight away
+				continue
+			}
+			unsafe {
+				if !self.b.pages[index].host_readable() {
+					touched = true;
+					if !self.b.host_protect(paddr, Protection::R) {
+						break
+					}
+				}
+				let p = &mut self.b.pages[index];
+				let unchanged = match &p.snapshot {
+					Snapshot::None => false,
+					Snapshot::ZeroFilled => pagecmp::is_zero(paddr.slice()),
+					Snapshot::Data(d) => pagecmp::eq(paddr.slice(), d.slice()),
+				};
+				if unchanged {
+					p.dirty = false;
+					count += 1;
+					touched = true;
+				}
+			}
+		}
+		if touched {
+			self.b.refresh_all_protections();
+		}
+		count
+	}
+
+	pub fn sealed(&self) -> bool {
+		self.b.sealed
+	}
 	pub fn seal(&mut self) {
 		assert!(!self.b.sealed);
 		for p in self.b.pages.iter_mut() {
@@ -726,13 +2064,14 @@ impl<'block> ActivatedMemoryBlock<'block> {
 				p.snapshot = Snapshot::None;
 			}
 		}
-		self.b.refresh_all_protections();
 		self.b.sealed = true;
 		self.b.hash = {
 			let mut hasher = Sha256::new();
 			bin::write(&mut hasher, &self.b.addr).unwrap();
 			for p in self.b.pages.iter() {
 				match &p.snapshot {
+					// which states have to agree on, since they leave those pages out
+					_ if p.immutable => bin::writeval(&mut hasher, 3).unwrap(),
 					Snapshot::None => bin::writeval(&mut hasher, 1).unwrap(),
 					Snapshot::ZeroFilled => bin::writeval(&mut hasher, 2).unwrap(),
 					Snapshot::Data(d) => { hasher.write(d.slice()).unwrap(); },
@@ -740,96 +2079,218 @@ impl<'block> ActivatedMemoryBlock<'block> {
 			}
 			hasher.finalize()[..].to_owned()
 		};
+		// after hashing, since with Eager tracking, this snapshots pages again
+		self.b.refresh_all_protections();
+	}
+	/// Seal again, so that everything that changed since the last seal becomes part of what states are made against,
+	/// and isn't in them anymore.  States made before can't be loaded after.
+	pub fn reseal(&mut self) -> anyhow::Result<()> {
+		assert!(self.b.sealed);
+		self.check_poisoned()?;
+		self.b.get_stack_dirty();
+		self.b.resolve_cow_all();
+		let all = self.b.addr;
+		self.b.unmap_state_file(all);
+		let changed = self.b.dirty_pages().into_iter().filter(|&index| self.b.pages[index].in_state()).collect::<Vec<_>>();
+		let hidden = match unsafe { self.b.host_protect_unreadable(changed.iter().copied()) } {
+			Some(runs) => runs,
+			None => return self.check_poisoned(),
+		};
+		// what changed goes into the hash, as the snapshots it's otherwise made of mostly say nothing about content
+		let mut hasher = Sha256::new();
+		hasher.update(&self.b.hash[..]);
+		for &index in changed.iter() {
+			bin::writeval(&mut hasher, index as u64).unwrap();
+			let paddr = AddressRange { start: self.b.addr.start + (index << PAGESHIFT), size: PAGESIZE };
+			hasher.update(unsafe { paddr.slice() });
+		}
+		for &run in hidden.iter() {
+			self.b.apply_protections(run);
+		}
+		for &index in changed.iter() {
+			let p = &mut self.b.pages[index];
+			p.dirty = false;
+			p.snapshot = Snapshot::None;
+		}
+		self.b.hash = hasher.finalize()[..].to_owned();
+		self.b.resealed = true;
+		self.b.refresh_all_protections();
+		Ok(())
 	}
 }
 
 const MAGIC: &str = "ActivatedMemoryBlock";
 
+/// xorshift64*
+fn aslr_next(state: &mut u64) -> u64 {
+	let mut x = *state;
+	x ^= x >> 12;
+	x ^= x << 25;
+	x ^= x >> 27;
+	*state = x;
+	x.wrapping_mul(0x2545f4914f6cdd1d)
+}
+
+/// Everything in a saved state that comes before the page contents
+fn write_state_header(stream: &mut dyn Write, hash: &[u8], mut addr: AddressRange, statii: &[PageAllocation], dirtii: &[bool],
+	aslr: Option<u64>) -> anyhow::Result<()> {
+	bin::write_magic(stream, MAGIC)?;
+	bin::write_hash(stream, hash)?;
+	addr.save_state(stream)?;
+	unsafe {
+		stream.write_all(std::mem::transmute::<&[PageAllocation], &[u8]>(statii))?;
+		stream.write_all(std::mem::transmute::<&[bool], &[u8]>(dirtii))?;
+	}
+	// only present when randomization is on, which has to match between save and load anyway
+	if let Some(rng) = aslr {
+		bin::write(stream, &rng)?;
+	}
+	Ok(())
+}
+
+/// What comes before the page contents in a saved state
+struct StatePages {
+	statii: Vec<PageAllocation>,
+	dirtii: Vec<bool>,
+	aslr: Option<u64>,
+}
+
+impl<'block> ActivatedMemoryBlock<'block> {
+	/// Read what write_state_header() wrote, and check that it's for this block
+	fn read_state_header(&self, stream: &mut dyn Read) -> anyhow::Result<StatePages> {
+		bin::verify_magic(stream, MAGIC)?;
+		match bin::verify_hash(stream, &self.b.hash[..]) {
+			Ok(_) => (),
+			// the pages a reseal took in aren't in the state, so it can't be loaded against anything else
+			Err(_) if self.b.resealed => return Err(coded(ErrorCode::StateMismatch, "Savestate was made against guest memory from a different seal")),
+			Err(_) => log!(Warn, "Unexpected MemoryBlock hash mismatch."),
+		}
+		let mut addr = AddressRange { start: 0, size: 0 };
+		addr.load_state(stream)?;
+		if addr != self.b.addr {
+			return Err(coded(ErrorCode::BadStateData, "Bad state data (addr) for ActivatedMemoryBlock"))
+		}
+		let mut statii = vec![PageAllocation::Free; self.b.pages.len()];
+		let mut dirtii = vec![false; self.b.pages.len()];
+		unsafe {
+			stream.read_exact(std::mem::transmute::<&mut [PageAllocation], &mut [u8]>(&mut statii[..]))?;
+			stream.read_exact(std::mem::transmute::<&mut [bool], &mut [u8]>(&mut dirtii[..]))?;
+		}
+		let aslr = match self.b.aslr {
+			Some(_) => Some(bin::readval(stream)?),
+			None => None,
+		};
+		let changes_immutable = self.b.pages.iter().enumerate()
+			.any(|(index, p)| p.immutable && (dirtii[index] || statii[index] != p.status));
+		if changes_immutable {
+			return Err(coded(ErrorCode::StateMismatch, "Savestate has changes to guest memory that's immutable here"))
+		}
+		Ok(StatePages { statii, dirtii, aslr })
+	}
+}
+
 impl<'block>  IStateable for ActivatedMemoryBlock<'block> {
 	fn save_state(&mut self, stream: &mut dyn Write) -> anyhow::Result<()> {
 		if !self.b.sealed {
-			return Err(anyhow!("Must seal first"))
+			return Err(coded(ErrorCode::BadState, "Must seal first"))
 		}
-		bin::write_magic(stream, MAGIC)?;
-		bin::write_hash(stream, &self.b.hash[..])?;
+		self.check_poisoned()?;
 		self.b.get_stack_dirty();
-		self.b.addr.save_state(stream)?;
-
-		unsafe {
-			let mut statii = Vec::new();
-			let mut dirtii = Vec::new();
-			statii.reserve_exact(self.b.pages.len());
-			dirtii.reserve_exact(self.b.pages.len());
-			for p in self.b.pages.iter() {
-				statii.push(p.status);
-				dirtii.push(p.dirty);
+		let dirty = self.b.dirty_pages();
+		{
+			let statii = self.b.pages.iter().map(|p| p.status).collect::<Vec<_>>();
+			let mut dirtii = vec![false; self.b.pages.len()];
+			for &index in dirty.iter() {
+				dirtii[index] = self.b.pages[index].saved_dirty();
 			}
-			stream.write_all(std::mem::transmute(&statii[..]))?;
-			stream.write_all(std::mem::transmute(&dirtii[..]))?;
+			write_state_header(stream, &self.b.hash[..], self.b.addr, &statii[..], &dirtii[..], self.b.aslr)?;
 		}
 
-		for (paddr, p) in self.b.page_range().iter_with_addr() {
-			// bin::write(stream, &p.status)?;
-			if !p.invisible {
-				// bin::write(stream, &p.dirty)?;
-				if p.dirty {
-					unsafe {
-						if !p.status.readable() {
-							assert!(pal::protect(paddr, Protection::R));
-						}
-						stream.write_all(paddr.slice())?;
-						if !p.status.readable() {
-							assert!(pal::protect(paddr, Protection::None));
-						}
-					}
-				}
-			}
+		let saved = dirty.into_iter().filter(|&index| self.b.pages[index].in_state()).collect::<Vec<_>>();
+		let hidden = match unsafe { self.b.host_protect_unreadable(saved.iter().copied()) } {
+			Some(runs) => runs,
+			None => return self.check_poisoned(),
+		};
+		let res = saved.iter().try_for_each(|&index| {
+			let paddr = AddressRange { start: self.b.addr.start + (index << PAGESHIFT), size: PAGESIZE };
+			stream.write_all(unsafe { paddr.slice() })
+		});
+		for &run in hidden.iter() {
+			self.b.apply_protections(run);
 		}
-		Ok(())
+		Ok(res?)
 	}
 	fn load_state(&mut self, stream: &mut dyn Read) -> anyhow::Result<()> {
 		assert!(self.b.sealed);
-		bin::verify_magic(stream, MAGIC)?;
-		match bin::verify_hash(stream, &self.b.hash[..]) {
-			Ok(_) => (),
-			Err(_) => eprintln!("Unexpected MemoryBlock hash mismatch."),
-		}
+		self.check_poisoned()?;
+		let StatePages { statii, dirtii, aslr } = self.read_state_header(stream)?;
 		self.b.get_stack_dirty();
-		{
-			let mut addr = AddressRange { start:0, size: 0 };
-			addr.load_state(stream)?;
-			if addr != self.b.addr {
-				return Err(anyhow!("Bad state data (addr) for ActivatedMemoryBlock"))
-			}
+		if aslr.is_some() {
+			self.b.aslr = aslr;
 		}
 
-		unsafe {
-			pal::protect(self.b.addr, Protection::RW);
-
-			let mut statii = vec![PageAllocation::Free; self.b.pages.len()];
-			let mut dirtii = vec![false; self.b.pages.len()];
-			stream.read_exact(std::mem::transmute(&mut statii[..]))?;
-			stream.read_exact(std::mem::transmute(&mut dirtii[..]))?;
-
-			let mut index = 0usize;
-			for (paddr, p) in self.b.page_range().iter_mut_with_addr() {
-				let status = statii[index];
+		self.b.resolve_cow_all();
+		let all = self.b.addr;
+		self.b.unmap_state_file(all);
+		// only pages that are or will be dirty are touched, and with status changes, only they need new protections.
+		// in a big block, that's usually few enough that walking all of the pages more than once would be most of the
+		// work.
+		let touched = |p: &Page, index: usize| p.transient || !p.invisible && (p.dirty || dirtii[index]);
+		if self.b.integrity.is_some() {
+			let pages = &self.b.pages;
+			self.b.integrity_forget_pages((0..pages.len())
+				.filter(|&index| !pages[index].immutable && (touched(&pages[index], index) || pages[index].status != statii[index])));
+		}
+		let mut changed = Vec::new();
+		let mut writable_until = 0;
+		for index in 0..self.b.pages.len() {
+			if self.b.pages[index].immutable {
+				continue
+			}
+			let paddr = AddressRange { start: self.b.addr.start + (index << PAGESHIFT), size: PAGESIZE };
+			let status = statii[index];
+			if !touched(&self.b.pages[index], index) {
+				if self.b.pages[index].status != status {
+					self.b.pages[index].status = status;
+					changed.push(index);
+				}
+				continue
+			}
+			if index >= writable_until {
+				writable_until = (index..self.b.pages.len())
+					.find(|&i| !touched(&self.b.pages[i], i))
+					.unwrap_or(self.b.pages.len());
+				let run = AddressRange { start: paddr.start, size: (writable_until - index) << PAGESHIFT };
+				unsafe { self.b.host_protect(run, Protection::RW); }
+			}
+			changed.push(index);
+			let p = &mut self.b.pages[index];
+			unsafe {
 				// let status = bin::readval::<PageAllocation>(stream)?;
-				if !p.invisible {
+				if p.transient {
+					if dirtii[index] && !p.invisible {
+						// saved before the page was transient
+						std::io::copy(&mut stream.take(PAGESIZE as u64), &mut std::io::sink())?;
+					}
+					if !pagecmp::is_zero(paddr.slice()) {
+						p.try_snapshot(paddr.start)?;
+						paddr.zero();
+						p.dirty = true;
+						self.b.dirty_index.set(index);
+					}
+				} else {
 					let dirty = dirtii[index];
 					// let dirty = bin::readval::<bool>(stream)?;
 					match (p.dirty, dirty) {
 						(false, false) => (),
 						(false, true) => {
-							p.maybe_snapshot(paddr.start);
+							p.try_snapshot(paddr.start)?;
 							stream.read_exact(paddr.slice_mut())?;
 						},
 						(true, false) => {
 							match &p.snapshot {
 								Snapshot::ZeroFilled => paddr.zero(),
-								Snapshot::Data(b) => {
-									std::ptr::copy_nonoverlapping(b.as_ptr(), paddr.start as *mut u8, PAGESIZE)
-								},
+								Snapshot::Data(b) => pagecmp::copy(paddr.slice_mut(), b.slice()),
 								Snapshot::None => panic!("Missing snapshot for dirty region"),
 							}
 						}
@@ -838,12 +2299,17 @@ impl<'block>  IStateable for ActivatedMemoryBlock<'block> {
 						}
 					}
 					p.dirty = dirty;
+					if dirty {
+						self.b.dirty_index.set(index);
+					}
 				}
-				p.status = status;
-				index += 1;
 			}
+			p.status = status;
+		}
 
-			self.b.refresh_all_protections();
+		self.b.integrity_learn_pages(changed.iter().copied());
+		for run in self.b.page_runs(changed.into_iter()) {
+			self.b.refresh_protections(run);
 		}
 		Ok(())
 	}
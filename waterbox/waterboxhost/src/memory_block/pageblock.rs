@@ -1,26 +1,83 @@
 use std::ptr::{null_mut, NonNull};
+use std::sync::atomic::{AtomicU64, Ordering};
 use core::ffi::c_void;
 use crate::*;
 
-/// wraps the allocation of a single PAGESIZE bytes of ram, and is safe-ish to call within a signal handler
+/// PageBlocks made, in every block, ever
+static MADE: AtomicU64 = AtomicU64::new(0);
+
+/// How many pages of snapshots and the like have been made, whether they were new to the process or reused, for
+/// profiling
+pub fn page_block_count() -> u64 {
+	MADE.load(Ordering::Relaxed)
+}
+
+/// wraps the allocation of a single PAGESIZE bytes of ram.  Fault handlers must only make these with copy_reserved() or
+/// fault_copy().
 #[derive(Debug)]
 pub struct PageBlock {
 	ptr: NonNull<u8>,
 }
+// the allocation is owned exclusively, so it can go anywhere
+unsafe impl Send for PageBlock {}
 
 impl PageBlock {
 	pub fn new() -> PageBlock {
+		match PageBlock::try_new() {
+			Some(p) => p,
+			None => panic!("PageBlock could not allocate memory!"),
+		}
+	}
+	/// new(), but returns None if the OS is out of memory
+	pub fn try_new() -> Option<PageBlock> {
 		unsafe {
-			let ptr = alloc();
-			if ptr == null_mut() {
-				panic!("PageBlock could not allocate memory!");
-			} else {
-				PageBlock {
-					ptr: NonNull::new_unchecked(ptr as *mut u8),
-				}
+			let mut ptr = pool::take(true);
+			if ptr.is_null() {
+				ptr = alloc();
+			}
+			let res = NonNull::new(ptr as *mut u8).map(|ptr| PageBlock { ptr });
+			if res.is_some() {
+				MADE.fetch_add(1, Ordering::Relaxed);
+				leaks::SNAPSHOT_PAGES.opened();
+			}
+			res
+		}
+	}
+
+	/// A copy of `src`, which must be PAGESIZE bytes, or None if the OS is out of memory
+	pub fn try_copy(src: &[u8]) -> Option<PageBlock> {
+		unsafe {
+			let mut ptr = pool::take(false);
+			if ptr.is_null() {
+				ptr = alloc();
+			}
+			PageBlock::copied(ptr, src)
+		}
+	}
+	/// try_copy(), for fault handlers:  Only pages set aside in the reserve are used, so nothing here takes a lock or
+	/// makes a system call.  Returns None if the reserve is out.
+	pub fn copy_reserved(src: &[u8]) -> Option<PageBlock> {
+		unsafe { PageBlock::copied(reserve::take(), src) }
+	}
+	/// copy_reserved(), but when the reserve is out, the page is mapped fresh, which is a system call but takes no
+	/// locks, and the reserve is made bigger for next time
+	pub fn fault_copy(src: &[u8]) -> Option<PageBlock> {
+		unsafe {
+			let mut ptr = reserve::take();
+			if ptr.is_null() {
+				reserve::missed();
+				ptr = alloc();
 			}
+			PageBlock::copied(ptr, src)
 		}
 	}
+	unsafe fn copied(ptr: *mut c_void, src: &[u8]) -> Option<PageBlock> {
+		let mut res = PageBlock { ptr: NonNull::new(ptr as *mut u8)? };
+		MADE.fetch_add(1, Ordering::Relaxed);
+		leaks::SNAPSHOT_PAGES.opened();
+		super::pagecmp::copy(res.slice_mut(), src);
+		Some(res)
+	}
 
 	pub fn slice<'a>(&'a self) -> &'a [u8] {
 		unsafe {
@@ -43,12 +100,187 @@ impl PageBlock {
 impl Drop for PageBlock {
 	fn drop(&mut self) {
 		unsafe {
-			let res = free(self.ptr.as_ptr() as *mut c_void);
-			if !res {
-				panic!("PageBlock could not free memory!");
+			pool::give(self.ptr.as_ptr() as *mut c_void);
+		}
+		leaks::SNAPSHOT_PAGES.closed();
+	}
+}
+
+/// Released PageBlocks are kept on a free list instead of going back to the OS right away.  The list is threaded
+/// through the free pages themselves, and guarded by a spinlock, so that nothing here allocates or blocks on a
+/// mutex.  Fault handlers don't use it at all, since the thread one interrupted could be holding the lock.
+mod pool {
+	use super::*;
+	use std::sync::atomic::{AtomicBool, Ordering};
+
+	static LOCKED: AtomicBool = AtomicBool::new(false);
+	static mut HEAD: *mut c_void = null_mut();
+	static mut COUNT: usize = 0;
+
+	unsafe fn with_lock<T>(f: impl FnOnce() -> T) -> T {
+		while LOCKED.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+			std::hint::spin_loop();
+		}
+		let res = f();
+		LOCKED.store(false, Ordering::Release);
+		res
+	}
+
+	/// Get a page off of the free list, zeroed if `zero`, or null if it's empty
+	pub unsafe fn take(zero: bool) -> *mut c_void {
+		let ptr = with_lock(|| {
+			let ptr = HEAD;
+			if !ptr.is_null() {
+				HEAD = *(ptr as *mut *mut c_void);
+				COUNT -= 1;
+			}
+			ptr
+		});
+		if zero && !ptr.is_null() {
+			std::ptr::write_bytes(ptr as *mut u8, 0, PAGESIZE);
+		}
+		ptr
+	}
+
+	pub unsafe fn give(ptr: *mut c_void) {
+		with_lock(|| {
+			*(ptr as *mut *mut c_void) = HEAD;
+			HEAD = ptr;
+			COUNT += 1;
+		});
+	}
+
+	/// Number of pages currently on the free list
+	pub fn count() -> usize {
+		unsafe { with_lock(|| COUNT) }
+	}
+
+	/// Empty the free list, returning everything on it to the OS.  Returns the number of pages freed.
+	pub fn trim() -> usize {
+		if page_size::get() > PAGESIZE {
+			// pages cut out of a bigger host page can't be given back one at a time
+			return 0
+		}
+		unsafe {
+			let mut ptr = with_lock(|| {
+				let ptr = HEAD;
+				HEAD = null_mut();
+				COUNT = 0;
+				ptr
+			});
+			let mut n = 0;
+			while !ptr.is_null() {
+				let next = *(ptr as *mut *mut c_void);
+				// a page that can't be freed is leaked, which is better than taking everything down
+				if free(ptr) {
+					n += 1;
+				}
+				ptr = next;
+			}
+			n
+		}
+	}
+}
+/// Pages set aside for fault handlers to take snapshots in, so they never need the free list's lock or the OS to
+/// allocate.  It's a fixed table of slots that are each swapped out atomically, which can't go wrong the ways a lock
+/// free list can, and it's only ever topped back up outside of fault handlers, by refill().  A handler that finds it
+/// empty doubles how much is set aside next time.
+mod reserve {
+	use super::*;
+	use lazy_static::lazy_static;
+	use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+	/// Fewest and most pages to keep set aside
+	const MIN_PAGES: usize = 256;
+	const MAX_PAGES: usize = 8192;
+
+	lazy_static! {
+		static ref SLOTS: Vec<AtomicPtr<c_void>> = (0..MAX_PAGES).map(|_| AtomicPtr::new(null_mut())).collect();
+	}
+	/// How many slots refill() fills
+	static WANT: AtomicUsize = AtomicUsize::new(MIN_PAGES);
+	/// Where take() starts looking, so it doesn't go over the slots it already emptied every time
+	static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+	/// Take a page out of the reserve, with whatever was in it, or null if it's empty.  Safe in fault handlers.
+	pub fn take() -> *mut c_void {
+		let want = WANT.load(Ordering::Relaxed);
+		let start = NEXT.load(Ordering::Relaxed);
+		for i in 0..want {
+			let index = (start + i) % want;
+			let slot = &SLOTS[index];
+			if !slot.load(Ordering::Relaxed).is_null() {
+				let ptr = slot.swap(null_mut(), Ordering::Acquire);
+				if !ptr.is_null() {
+					NEXT.store(index + 1, Ordering::Relaxed);
+					return ptr
+				}
+			}
+		}
+		null_mut()
+	}
+
+	/// Put a page in an empty slot, if there is one.  Safe in fault handlers.
+	pub fn put(ptr: *mut c_void) -> bool {
+		let want = WANT.load(Ordering::Relaxed);
+		SLOTS[..want].iter().any(|slot| {
+			slot.compare_exchange(null_mut(), ptr, Ordering::Release, Ordering::Relaxed).is_ok()
+		})
+	}
+
+	/// The reserve ran out in a fault handler
+	pub fn missed() {
+		let want = WANT.load(Ordering::Relaxed);
+		WANT.store(std::cmp::min(want * 2, MAX_PAGES), Ordering::Relaxed);
+	}
+
+	/// Fill every empty slot again, from the free list or the OS.  Must not be called from a fault handler.
+	pub fn refill() {
+		let want = WANT.load(Ordering::Relaxed);
+		for slot in SLOTS[..want].iter() {
+			if !slot.load(Ordering::Relaxed).is_null() {
+				continue
+			}
+			let mut ptr = unsafe { pool::take(false) };
+			if ptr.is_null() {
+				ptr = unsafe { alloc() };
+				if ptr.is_null() {
+					return
+				}
+			}
+			if slot.compare_exchange(null_mut(), ptr, Ordering::Release, Ordering::Relaxed).is_err() {
+				unsafe { pool::give(ptr); }
+			}
+		}
+	}
+
+	/// Empty the reserve onto the free list, and go back to setting aside as little as possible
+	pub fn drain() {
+		for slot in SLOTS.iter() {
+			let ptr = slot.swap(null_mut(), Ordering::Acquire);
+			if !ptr.is_null() {
+				unsafe { pool::give(ptr); }
 			}
 		}
+		WANT.store(MIN_PAGES, Ordering::Relaxed);
 	}
+
+	/// How many pages are set aside right now
+	pub fn count() -> usize {
+		SLOTS.iter().filter(|slot| !slot.load(Ordering::Relaxed).is_null()).count()
+	}
+}
+pub use reserve::refill as refill_reserve;
+
+/// Number of pages on the free list or set aside for fault handlers
+pub fn pooled_pages() -> usize {
+	pool::count() + reserve::count()
+}
+
+/// Empty the free list and the reserve, returning everything on them to the OS.  Returns the number of pages freed.
+pub fn trim() -> usize {
+	reserve::drain();
+	pool::trim()
 }
 
 #[cfg(windows)]
@@ -71,11 +303,19 @@ unsafe fn free(ptr: *mut c_void) -> bool {
 use libc::*;
 #[cfg(unix)]
 unsafe fn alloc() -> *mut c_void {
-	let ptr = mmap(null_mut(), PAGESIZE, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
-	match ptr {
-		MAP_FAILED => null_mut(),
-		_ => ptr
+	let size = std::cmp::max(page_size::get(), PAGESIZE);
+	let ptr = mmap(null_mut(), size, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANON, -1, 0);
+	if ptr == MAP_FAILED {
+		return null_mut()
+	}
+	// the rest of a bigger host page is kept for later, set aside first since this could be a fault handler
+	for offset in (PAGESIZE..size).step_by(PAGESIZE) {
+		let rest = (ptr as *mut u8).add(offset) as *mut c_void;
+		if !reserve::put(rest) {
+			pool::give(rest);
+		}
 	}
+	ptr
 }
 #[cfg(unix)]
 unsafe fn free(ptr: *mut c_void) -> bool {
@@ -86,6 +326,33 @@ unsafe fn free(ptr: *mut c_void) -> bool {
 	}
 }
 
+#[cfg(test)]
+#[test]
+fn pool_test() {
+	let mut s = PageBlock::new();
+	s.slice_mut()[100] = 42;
+	drop(s);
+	// recycled pages must come back zeroed, like fresh ones
+	let s = PageBlock::new();
+	assert!(s.slice().iter().all(|x| *x == 0));
+	drop(s);
+	trim();
+}
+
+#[cfg(test)]
+#[test]
+fn reserve_test() {
+	let mut src = vec![0u8; PAGESIZE];
+	src[5] = 17;
+	refill_reserve();
+	// whatever other tests do to the reserve meanwhile, one of these always makes a page
+	let pb = PageBlock::fault_copy(&src[..]).unwrap();
+	assert_eq!(pb.slice(), &src[..]);
+	if let Some(pb) = PageBlock::copy_reserved(&src[..]) {
+		assert_eq!(pb.slice(), &src[..]);
+	}
+}
+
 #[cfg(test)]
 #[test]
 fn basic_test() {
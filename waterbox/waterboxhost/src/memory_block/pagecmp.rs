@@ -0,0 +1,153 @@
+// Comparing and copying whole pages, which snapshots, states and dirty page cleaning do a lot of.  Every x86_64 CPU has
+// SSE2; AVX2 is used instead when the CPU has it.  Other architectures get the plain versions in mod.rs.
+use super::*;
+use std::arch::x86_64::*;
+
+fn avx2() -> bool {
+	is_x86_feature_detected!("avx2")
+}
+
+/// If two pages hold the same bytes
+pub fn eq(a: &[u8], b: &[u8]) -> bool {
+	assert!(a.len() == PAGESIZE && b.len() == PAGESIZE);
+	unsafe {
+		if avx2() {
+			eq_avx2(a.as_ptr(), b.as_ptr())
+		} else {
+			eq_sse2(a.as_ptr(), b.as_ptr())
+		}
+	}
+}
+
+/// If a page is all zeros
+pub fn is_zero(a: &[u8]) -> bool {
+	assert!(a.len() == PAGESIZE);
+	unsafe {
+		if avx2() {
+			zero_avx2(a.as_ptr())
+		} else {
+			zero_sse2(a.as_ptr())
+		}
+	}
+}
+
+/// Copy one page to another
+pub fn copy(dst: &mut [u8], src: &[u8]) {
+	assert!(dst.len() == PAGESIZE && src.len() == PAGESIZE);
+	unsafe {
+		if avx2() {
+			copy_avx2(dst.as_mut_ptr(), src.as_ptr())
+		} else {
+			copy_sse2(dst.as_mut_ptr(), src.as_ptr())
+		}
+	}
+}
+
+// Each loop does 128 bytes, and only checks for a difference once per loop.
+
+#[target_feature(enable = "avx2")]
+unsafe fn eq_avx2(a: *const u8, b: *const u8) -> bool {
+	for i in (0..PAGESIZE).step_by(128) {
+		let mut diff = _mm256_setzero_si256();
+		for j in (i..i + 128).step_by(32) {
+			let x = _mm256_loadu_si256(a.add(j) as *const __m256i);
+			let y = _mm256_loadu_si256(b.add(j) as *const __m256i);
+			diff = _mm256_or_si256(diff, _mm256_xor_si256(x, y));
+		}
+		if _mm256_testz_si256(diff, diff) == 0 {
+			return false
+		}
+	}
+	true
+}
+#[target_feature(enable = "avx2")]
+unsafe fn zero_avx2(a: *const u8) -> bool {
+	for i in (0..PAGESIZE).step_by(128) {
+		let mut bits = _mm256_setzero_si256();
+		for j in (i..i + 128).step_by(32) {
+			bits = _mm256_or_si256(bits, _mm256_loadu_si256(a.add(j) as *const __m256i));
+		}
+		if _mm256_testz_si256(bits, bits) == 0 {
+			return false
+		}
+	}
+	true
+}
+#[target_feature(enable = "avx2")]
+unsafe fn copy_avx2(dst: *mut u8, src: *const u8) {
+	for i in (0..PAGESIZE).step_by(32) {
+		_mm256_storeu_si256(dst.add(i) as *mut __m256i, _mm256_loadu_si256(src.add(i) as *const __m256i));
+	}
+}
+
+/// SSE2 has no ptest, so see whether every byte of `v` compares equal to zero instead
+unsafe fn all_zero_sse2(v: __m128i) -> bool {
+	_mm_movemask_epi8(_mm_cmpeq_epi8(v, _mm_setzero_si128())) == 0xffff
+}
+unsafe fn eq_sse2(a: *const u8, b: *const u8) -> bool {
+	for i in (0..PAGESIZE).step_by(128) {
+		let mut diff = _mm_setzero_si128();
+		for j in (i..i + 128).step_by(16) {
+			let x = _mm_loadu_si128(a.add(j) as *const __m128i);
+			let y = _mm_loadu_si128(b.add(j) as *const __m128i);
+			diff = _mm_or_si128(diff, _mm_xor_si128(x, y));
+		}
+		if !all_zero_sse2(diff) {
+			return false
+		}
+	}
+	true
+}
+unsafe fn zero_sse2(a: *const u8) -> bool {
+	for i in (0..PAGESIZE).step_by(128) {
+		let mut bits = _mm_setzero_si128();
+		for j in (i..i + 128).step_by(16) {
+			bits = _mm_or_si128(bits, _mm_loadu_si128(a.add(j) as *const __m128i));
+		}
+		if !all_zero_sse2(bits) {
+			return false
+		}
+	}
+	true
+}
+unsafe fn copy_sse2(dst: *mut u8, src: *const u8) {
+	for i in (0..PAGESIZE).step_by(16) {
+		_mm_storeu_si128(dst.add(i) as *mut __m128i, _mm_loadu_si128(src.add(i) as *const __m128i));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	type Path = (unsafe fn(*const u8, *const u8) -> bool, unsafe fn(*const u8) -> bool, unsafe fn(*mut u8, *const u8));
+
+	#[test]
+	fn test_pagecmp() {
+		let a = (0..PAGESIZE).map(|i| (i * 7 + 1) as u8).collect::<Vec<_>>();
+		let mut paths: Vec<Path> = vec![(eq_sse2, zero_sse2, copy_sse2)];
+		if avx2() {
+			paths.push((eq_avx2, zero_avx2, copy_avx2));
+		}
+		for &(eq, zero, copy) in paths.iter() {
+			unsafe {
+				let mut b = vec![0u8; PAGESIZE];
+				assert!(zero(b.as_ptr()));
+				copy(b.as_mut_ptr(), a.as_ptr());
+				assert_eq!(a, b);
+				assert!(eq(a.as_ptr(), b.as_ptr()));
+				assert!(!zero(b.as_ptr()));
+				for &i in [0, 1, 31, 127, 128, 2049, PAGESIZE - 1].iter() {
+					b[i] ^= 0x10;
+					assert!(!eq(a.as_ptr(), b.as_ptr()), "difference at {}", i);
+					b[i] ^= 0x10;
+					let mut z = vec![0u8; PAGESIZE];
+					z[i] = 1;
+					assert!(!zero(z.as_ptr()), "nonzero at {}", i);
+				}
+			}
+		}
+		assert!(super::eq(&a[..], &a[..]));
+		assert!(super::is_zero(&vec![0u8; PAGESIZE][..]));
+	}
+}
@@ -4,6 +4,12 @@ use crate::*;
 
 #[derive(Debug)]
 pub struct Handle(usize);
+impl Handle {
+	/// The OS handle or file descriptor
+	pub fn raw(&self) -> usize {
+		self.0
+	}
+}
 
 #[cfg(windows)]
 pub use win::*;
@@ -20,16 +26,18 @@ mod win {
 	fn error() {
 		unsafe {
 			let err = winapi::um::errhandlingapi::GetLastError();
-			eprintln!("WinApi failure code: {}", err);
+			log!(Error, "WinApi failure code: {}", err);
 		}
 	}
 
-	pub fn open(size: usize) -> Option<Handle> {
+	/// The section is only reserved; pages are committed as they're protected to something accessible, or all at once
+	/// with commit(...).  Only a section made with `exec` can be mapped executable.
+	pub fn open(size: usize, exec: bool) -> Option<Handle> {
 		unsafe {
 			let res = CreateFileMappingW(
 				INVALID_HANDLE_VALUE,
 				null_mut(),
-				PAGE_EXECUTE_READWRITE,
+				if exec { PAGE_EXECUTE_READWRITE } else { PAGE_READWRITE } | SEC_RESERVE,
 				(size >> 32) as u32,
 				size as u32,
 				null()
@@ -51,11 +59,11 @@ mod win {
 		return Handle(INVALID_HANDLE_VALUE as usize);
 	}
 
-	pub fn map(handle: &Handle, addr: AddressRange) -> bool {
+	pub fn map(handle: &Handle, addr: AddressRange, exec: bool) -> bool {
 		unsafe {
 			let res = MapViewOfFileEx(
 				handle.0 as *mut c_void,
-				FILE_MAP_ALL_ACCESS | FILE_MAP_EXECUTE,
+				FILE_MAP_ALL_ACCESS | if exec { FILE_MAP_EXECUTE } else { 0 },
 				0,
 				0,
 				addr.size,
@@ -70,10 +78,42 @@ mod win {
 		}
 	}
 
+	/// map(), which never replaces anything that's already mapped here anyway
+	pub fn map_noreplace(handle: &Handle, addr: AddressRange, exec: bool) -> bool {
+		map(handle, addr, exec)
+	}
+
 	pub unsafe fn unmap(addr: AddressRange) -> bool {
 		UnmapViewOfFile(addr.start as *mut c_void) != 0
 	}
 
+	/// Views can't be put over part of another view, so neither of these can do anything
+	pub unsafe fn map_part(_handle: &Handle, _addr: AddressRange, _offset: usize, _exec: bool) -> bool {
+		false
+	}
+	pub unsafe fn map_file(_file: &std::fs::File, _offset: u64, _addr: AddressRange) -> bool {
+		false
+	}
+
+	/// Map part of the object read only, wherever the OS likes
+	pub fn map_view(handle: &Handle, offset: usize, size: usize) -> Option<usize> {
+		unsafe {
+			let res = MapViewOfFile(
+				handle.0 as *mut c_void,
+				FILE_MAP_READ,
+				(offset >> 32) as u32,
+				offset as u32,
+				size
+			);
+			if res == null_mut() {
+				error();
+				None
+			} else {
+				Some(res as usize)
+			}
+		}
+	}
+
 	pub unsafe fn protect(addr: AddressRange, prot: Protection) -> bool {
 		let p = match prot {
 			Protection::None => PAGE_NOACCESS,
@@ -82,9 +122,73 @@ mod win {
 			Protection::RX => PAGE_EXECUTE_READ,
 			Protection::RWX => PAGE_EXECUTE_READWRITE,
 			Protection::RWStack => PAGE_READWRITE | PAGE_GUARD,
+			Protection::StackGuard => panic!("StackGuard should not be passed to pal layer"),
 		};
 		let mut old_protect: u32 = 0;
-		VirtualProtect(addr.start as *mut c_void, addr.size, p, &mut old_protect) != 0
+		if VirtualProtect(addr.start as *mut c_void, addr.size, p, &mut old_protect) != 0 {
+			return true
+		}
+		// some of the range hasn't been committed yet
+		if prot != Protection::None {
+			return VirtualAlloc(addr.start as *mut c_void, addr.size, MEM_COMMIT, p) == addr.start as *mut c_void
+		}
+		// and doesn't need to be to be inaccessible
+		let mut mbi = zeroed::<MEMORY_BASIC_INFORMATION>();
+		let mut start = addr.start;
+		while start < addr.end() {
+			if VirtualQuery(start as *const c_void, &mut mbi, size_of::<MEMORY_BASIC_INFORMATION>()) == 0 {
+				error();
+				return false
+			}
+			let end = std::cmp::min(mbi.BaseAddress as usize + mbi.RegionSize, addr.end());
+			if mbi.State == MEM_COMMIT && VirtualProtect(start as *mut c_void, end - start, p, &mut old_protect) == 0 {
+				error();
+				return false
+			}
+			start = end;
+		}
+		true
+	}
+
+	/// Commit all of a mapped range now, instead of as it's used.  Protections are left as PAGE_NOACCESS, so put them
+	/// back afterwards.
+	pub unsafe fn commit(_handle: &Handle, addr: AddressRange) -> bool {
+		if VirtualAlloc(addr.start as *mut c_void, addr.size, MEM_COMMIT, PAGE_NOACCESS) == addr.start as *mut c_void {
+			true
+		} else {
+			error();
+			false
+		}
+	}
+
+	/// Not implemented here; callers have to estimate instead.
+	pub unsafe fn resident_pages(_addr: AddressRange) -> Option<usize> {
+		None
+	}
+
+	/// Not implemented here.
+	pub unsafe fn resident_map(_addr: AddressRange) -> Option<Vec<bool>> {
+		None
+	}
+
+	/// MEM_RESET and friends leave the content undefined instead of zeroing it, so this is never possible here.
+	pub unsafe fn decommit(_addr: AddressRange) -> bool {
+		false
+	}
+
+	/// Large pages can't be protected in pieces, which dirty tracking needs, so this is never possible here.
+	pub unsafe fn hint_huge(_addr: AddressRange) -> bool {
+		false
+	}
+
+	/// A section's NUMA node can only be picked when it's created, so this is never possible here.
+	pub unsafe fn bind_node(_addr: AddressRange, _node: Option<u32>) -> bool {
+		false
+	}
+
+	/// Not implemented here.
+	pub unsafe fn page_nodes(_addr: AddressRange) -> Option<Vec<i32>> {
+		None
 	}
 
 	pub struct StackTripResult {
@@ -118,15 +222,38 @@ mod nix {
 
 	fn error() {
 		unsafe {
+			#[cfg(target_os = "linux")]
 			let err = *__errno_location();
-			eprintln!("Libc failure code: {}", err);
+			#[cfg(target_os = "macos")]
+			let err = *__error();
+			log!(Error, "Libc failure code: {}", err);
+		}
+	}
+
+	#[cfg(target_os = "linux")]
+	unsafe fn anonymous_file() -> i32 {
+		let s = std::ffi::CString::new("MemoryBlockUnix").unwrap();
+		syscall(SYS_memfd_create, s.as_ptr(), MFD_CLOEXEC) as i32
+	}
+	/// macOS has no memfd, so make a shared memory object with a name nothing else will use, and unlink it right away
+	#[cfg(target_os = "macos")]
+	unsafe fn anonymous_file() -> i32 {
+		use std::sync::atomic::{AtomicUsize, Ordering};
+		static COUNT: AtomicUsize = AtomicUsize::new(0);
+		// shm names are limited to 31 characters
+		let s = std::ffi::CString::new(format!("/wbx.{}.{}", getpid(), COUNT.fetch_add(1, Ordering::Relaxed))).unwrap();
+		let fd = shm_open(s.as_ptr(), O_RDWR | O_CREAT | O_EXCL, 0o600 as c_uint);
+		if fd != -1 {
+			shm_unlink(s.as_ptr());
+			fcntl(fd, F_SETFD, FD_CLOEXEC);
 		}
+		fd
 	}
 
-	pub fn open(size: usize) -> Option<Handle> {
+	/// Like all shared memory, the object's pages are only allocated as they're touched, or all at once with commit(...)
+	pub fn open(size: usize, _exec: bool) -> Option<Handle> {
 		unsafe {
-			let s = std::ffi::CString::new("MemoryBlockUnix").unwrap();
-			let fd = syscall(SYS_memfd_create, s.as_ptr(), MFD_CLOEXEC) as i32;
+			let fd = anonymous_file();
 			if fd == -1 {
 				error();
 				return None
@@ -148,11 +275,18 @@ mod nix {
 		return Handle(-1i32 as usize);
 	}
 
-	pub fn map(handle: &Handle, addr: AddressRange) -> bool {
+	fn map_prot(exec: bool) -> i32 {
+		if exec { PROT_READ | PROT_WRITE | PROT_EXEC } else { PROT_READ | PROT_WRITE }
+	}
+
+	/// On macOS, guest code runs from these shared mappings, which can't be MAP_JIT, and pthread_jit_write_protect_np
+	/// only exists on arm64, which waterbox doesn't run on.  So a frontend built with the hardened runtime needs the
+	/// com.apple.security.cs.allow-unsigned-executable-memory entitlement for this to work, unless it's not `exec`.
+	pub fn map(handle: &Handle, addr: AddressRange, exec: bool) -> bool {
 		unsafe {
 			let res = mmap(addr.start as *mut c_void,
 				addr.size,
-				PROT_READ | PROT_WRITE | PROT_EXEC,
+				map_prot(exec),
 				MAP_SHARED | MAP_FIXED,
 				handle.0 as i32,
 				0
@@ -166,10 +300,64 @@ mod nix {
 		}
 	}
 
+	/// map(), but failing instead of replacing anything that's already mapped there
+	pub fn map_noreplace(handle: &Handle, addr: AddressRange, exec: bool) -> bool {
+		#[cfg(target_os = "linux")]
+		let flags = MAP_SHARED | MAP_FIXED_NOREPLACE;
+		// without MAP_FIXED, the address is only a hint, which is also what kernels before 4.17 make of the above
+		#[cfg(target_os = "macos")]
+		let flags = MAP_SHARED;
+		unsafe {
+			let res = mmap(addr.start as *mut c_void, addr.size, map_prot(exec), flags, handle.0 as i32, 0);
+			if res == addr.start as *mut c_void {
+				return true
+			}
+			if res == MAP_FAILED {
+				error();
+			} else {
+				munmap(res, addr.size);
+			}
+			false
+		}
+	}
+
 	pub unsafe fn unmap(addr: AddressRange) -> bool {
 		munmap(addr.start as *mut c_void, addr.size) == 0
 	}
 
+	/// map(), for just the part of the object at `offset`, over whatever is mapped at `addr` now
+	pub unsafe fn map_part(handle: &Handle, addr: AddressRange, offset: usize, exec: bool) -> bool {
+		let res = mmap(addr.start as *mut c_void, addr.size, map_prot(exec), MAP_SHARED | MAP_FIXED,
+			handle.0 as i32, offset as i64);
+		if res == addr.start as *mut c_void {
+			true
+		} else {
+			error();
+			false
+		}
+	}
+
+	/// Map part of a file read only and copy-on-write at `addr`, over whatever is mapped there now
+	pub unsafe fn map_file(file: &std::fs::File, offset: u64, addr: AddressRange) -> bool {
+		use std::os::unix::io::AsRawFd;
+		let res = mmap(addr.start as *mut c_void, addr.size, PROT_READ, MAP_PRIVATE | MAP_FIXED, file.as_raw_fd(), offset as i64);
+		// running out of mappings is expected with a lot of small runs, and the caller copies instead
+		res == addr.start as *mut c_void
+	}
+
+	/// Map part of the object read only, wherever the OS likes
+	pub fn map_view(handle: &Handle, offset: usize, size: usize) -> Option<usize> {
+		unsafe {
+			let res = mmap(std::ptr::null_mut(), size, PROT_READ, MAP_SHARED, handle.0 as i32, offset as i64);
+			if res == MAP_FAILED {
+				error();
+				None
+			} else {
+				Some(res as usize)
+			}
+		}
+	}
+
 	pub unsafe fn protect(addr: AddressRange, prot: Protection) -> bool {
 		let p = match prot {
 			Protection::None => PROT_NONE,
@@ -178,9 +366,138 @@ mod nix {
 			Protection::RX => PROT_READ | PROT_EXEC,
 			Protection::RWX => PROT_READ | PROT_WRITE | PROT_EXEC,
 			Protection::RWStack => panic!("RWStack should not be passed to pal layer"),
+			Protection::StackGuard => panic!("StackGuard should not be passed to pal layer"),
 		};
 		mprotect(addr.start as *mut c_void, addr.size, p) == 0
 	}
+
+	/// Count how many pages in a mapped range are actually backed by host memory right now
+	pub unsafe fn resident_pages(addr: AddressRange) -> Option<usize> {
+		resident_map(addr).map(|v| v.iter().filter(|&&x| x).count())
+	}
+
+	/// For each host page in a mapped range, whether it's actually backed by host memory right now
+	pub unsafe fn resident_map(addr: AddressRange) -> Option<Vec<bool>> {
+		let host_page = page_size::get();
+		let mut vec = vec![0u8; addr.size.div_ceil(host_page)];
+		// the pointer types differ between platforms
+		if mincore(addr.start as _, addr.size, vec.as_mut_ptr() as _) == 0 {
+			Some(vec.iter().map(|x| x & 1 != 0).collect())
+		} else {
+			error();
+			None
+		}
+	}
+
+	/// Release the host memory behind a mapped, writable range.  On success, the range reads as zeroes afterwards.
+	/// Returns false if that's not supported, in which case the caller should zero the range itself.
+	pub unsafe fn decommit(addr: AddressRange) -> bool {
+		#[cfg(target_os = "linux")]
+		{
+			// punches a hole in the memfd, as opposed to MADV_DONTNEED which would only drop our view of it
+			madvise(addr.start as *mut c_void, addr.size, MADV_REMOVE) == 0
+		}
+		#[cfg(not(target_os = "linux"))]
+		{
+			let _ = addr;
+			false
+		}
+	}
+
+	/// Allocate all of the object's pages now, instead of as they're touched.  Returns false if there isn't enough memory,
+	/// or if that's not supported.
+	pub unsafe fn commit(handle: &Handle, addr: AddressRange) -> bool {
+		#[cfg(target_os = "linux")]
+		{
+			if fallocate(handle.0 as i32, 0, 0, addr.size as i64) == 0 {
+				true
+			} else {
+				error();
+				false
+			}
+		}
+		#[cfg(not(target_os = "linux"))]
+		{
+			let _ = (handle, addr);
+			false
+		}
+	}
+
+	/// Ask for a mapped range to be backed by transparent huge pages.  Returns false if that's not supported,
+	/// but even when it returns true, the kernel is free to keep using normal pages.
+	pub unsafe fn hint_huge(addr: AddressRange) -> bool {
+		#[cfg(target_os = "linux")]
+		{
+			madvise(addr.start as *mut c_void, addr.size, MADV_HUGEPAGE) == 0
+		}
+		#[cfg(not(target_os = "linux"))]
+		{
+			let _ = addr;
+			false
+		}
+	}
+
+	#[cfg(target_os = "linux")]
+	mod numa {
+		pub const MPOL_DEFAULT: i32 = 0;
+		pub const MPOL_BIND: i32 = 2;
+		pub const MPOL_MF_MOVE: u32 = 1 << 1;
+		/// Highest node number that can be bound to, plus one
+		pub const MAX_NODES: usize = 1024;
+	}
+
+	/// Keep the memory behind a mapped range on one NUMA node, moving what's already there, or go back to the default
+	/// policy with None.  For shared memory, the policy sticks to the object, not the mapping.  Returns false if that's
+	/// not supported.
+	pub unsafe fn bind_node(addr: AddressRange, node: Option<u32>) -> bool {
+		#[cfg(target_os = "linux")]
+		{
+			use numa::*;
+			let mut mask = [0 as c_ulong; MAX_NODES / 64];
+			let mode = match node {
+				Some(n) if (n as usize) < MAX_NODES => {
+					mask[n as usize / 64] |= 1 << (n % 64);
+					MPOL_BIND
+				},
+				Some(_) => return false,
+				None => MPOL_DEFAULT,
+			};
+			let (mask_ptr, max_node) = if mode == MPOL_DEFAULT { (std::ptr::null(), 0) } else { (mask.as_ptr(), MAX_NODES + 1) };
+			if syscall(SYS_mbind, addr.start, addr.size, mode, mask_ptr, max_node, MPOL_MF_MOVE) == 0 {
+				true
+			} else {
+				error();
+				false
+			}
+		}
+		#[cfg(not(target_os = "linux"))]
+		{
+			let _ = (addr, node);
+			false
+		}
+	}
+
+	/// The NUMA node of each host page in a mapped range, or a negative errno for pages that aren't there, like
+	/// -ENOENT for ones that were never touched.  None if that's not supported.
+	pub unsafe fn page_nodes(addr: AddressRange) -> Option<Vec<i32>> {
+		#[cfg(target_os = "linux")]
+		{
+			let host_page = page_size::get();
+			let pages = (addr.start..addr.end()).step_by(host_page).map(|a| a as *mut c_void).collect::<Vec<_>>();
+			let mut status = vec![0i32; pages.len()];
+			if syscall(SYS_move_pages, 0, pages.len(), pages.as_ptr(), std::ptr::null::<i32>(), status.as_mut_ptr(), 0) == 0 {
+				Some(status)
+			} else {
+				error();
+				None
+			}
+		}
+		#[cfg(not(target_os = "linux"))]
+		{
+			let _ = addr;
+			None
+		}
+	}
 }
 
 #[cfg(test)]
@@ -196,19 +513,24 @@ mod tests {
 			let size = 0x20000usize;
 			let start = 0x36a00000000usize;
 			let addr = AddressRange { start, size };
-			let handle = open(size).unwrap();
+			let handle = open(size, true).unwrap();
 
-			assert!(map(&handle, addr));
+			assert!(map(&handle, addr, true));
+			assert!(!map_noreplace(&handle, addr, true));
 			assert!(protect(addr, Protection::RW));
 			*((start + 0x14795) as *mut u8) = 42;
 			assert!(unmap(addr));
 
-			assert!(map(&handle, addr));
+			assert!(map_noreplace(&handle, addr, true));
+			assert_eq!(*((start + 0x14795) as *const u8), 42);
+			assert!(unmap(addr));
+
+			assert!(map(&handle, addr, true));
 			assert!(protect(addr, Protection::R));
 			assert_eq!(*((start + 0x14795) as *const u8), 42);
 			assert!(unmap(addr));
 
-			assert!(map(&handle, addr));
+			assert!(map(&handle, addr, true));
 			assert!(protect(addr, Protection::RW));
 			*(start as *mut u8) = 0xc3; // RET
 			assert!(protect(addr, Protection::RX));
@@ -225,4 +547,36 @@ mod tests {
 			assert!(close(handle));
 		}
 	}
+
+	#[cfg(unix)]
+	#[test]
+	fn test_map_file() {
+		unsafe {
+			let size = 0x4000usize;
+			let start = 0x36a80000000usize;
+			let addr = AddressRange { start, size };
+			let handle = open(size, true).unwrap();
+			assert!(map(&handle, addr, true));
+			*((start + 0x1000) as *mut u8) = 1;
+
+			let path = std::env::temp_dir().join(format!("wbx_map_file_{}", std::process::id()));
+			let mut data = vec![0u8; 0x3000];
+			data[0x2000] = 2;
+			std::fs::write(&path, &data[..]).unwrap();
+			let file = std::fs::File::open(&path).unwrap();
+			std::fs::remove_file(&path).unwrap();
+			let page = AddressRange { start: start + 0x1000, size: 0x1000 };
+			assert!(map_file(&file, 0x2000, page));
+			assert_eq!(*(page.start as *const u8), 2);
+			assert!(protect(page, Protection::RW));
+			*(page.start as *mut u8) = 3;
+			// the object itself, and the file, are still as they were
+			assert!(map_part(&handle, page, 0x1000, true));
+			assert_eq!(*(page.start as *const u8), 1);
+			assert!(map_file(&file, 0x2000, page));
+			assert_eq!(*(page.start as *const u8), 2);
+			assert!(unmap(addr));
+			assert!(close(handle));
+		}
+	}
 }
@@ -0,0 +1,87 @@
+// Native protection changes, all made through here so they cost as few OS calls as possible.  mprotect, and
+// VirtualProtect even more so, are slow, and many paths ask for the protections of a whole range again when only a few
+// host pages in it need to change, like loading a state, sealing, or putting things back after the host has read some
+// pages.  So what each host page was last given is remembered, the ones that already have what's asked for are
+// skipped, and the rest are changed a run at a time.  Anything that changes native protections another way, like
+// mapping over guest memory, has to forget_protections() there, or what's remembered will be wrong.
+use super::*;
+use std::cell::Cell;
+use std::sync::atomic::AtomicU64;
+
+static PROTECT_CALLS: AtomicU64 = AtomicU64::new(0);
+
+/// How many OS calls have been made to change guest memory protections, by all blocks
+pub fn protect_call_count() -> u64 {
+	PROTECT_CALLS.load(Ordering::Relaxed)
+}
+
+/// What each host page of a block was last given, if that's known
+#[derive(Debug)]
+pub struct Applied(pub(super) Vec<Cell<Option<Protection>>>);
+
+impl Applied {
+	pub fn new(host_pages: usize) -> Applied {
+		Applied((0..host_pages).map(|_| Cell::new(None)).collect())
+	}
+}
+
+impl MemoryBlock {
+	/// Give each host page of `addr`, which must be whole host pages, the protection `want` returns for the address it
+	/// starts at.  Returns false if an OS call failed.
+	pub(super) unsafe fn protect_native(&self, addr: AddressRange, want: impl Fn(usize) -> Protection) -> bool {
+		let host_page = self.host_page;
+		let first = (addr.start - self.addr.start) / host_page;
+		let runs = (first..first + addr.size / host_page)
+			.map(|index| (index, want(self.addr.start + index * host_page)))
+			.map(|(index, prot)| (index, if self.no_exec { prot.without_exec() } else { prot }))
+			// the OS takes the guard off a page by itself when it's touched
+			.filter(|&(index, prot)| self.applied.0[index].get() != Some(prot) || prot == Protection::RWStack)
+			.map(|(index, prot)| (index, index + 1, prot))
+			.coalesce(|x, y| if x.1 == y.0 && x.2 == y.2 { Ok((x.0, y.1, x.2)) } else { Err((x, y)) });
+		for (start, end, prot) in runs {
+			let run = AddressRange { start: self.addr.start + start * host_page, size: (end - start) * host_page };
+			PROTECT_CALLS.fetch_add(1, Ordering::Relaxed);
+			let ok = pal::protect(run, prot);
+			for a in self.applied.0[start..end].iter() {
+				a.set(if ok { Some(prot) } else { None });
+			}
+			if !ok {
+				return false
+			}
+		}
+		true
+	}
+	/// Note that native protections on the host pages overlapping `addr` were changed some other way
+	pub(super) fn forget_protections(&self, addr: AddressRange) {
+		let addr = self.host_expand(addr);
+		let first = (addr.start - self.addr.start) / self.host_page;
+		let last = std::cmp::min(first + addr.size / self.host_page, self.applied.0.len());
+		for a in self.applied.0[first..last].iter() {
+			a.set(None);
+		}
+	}
+	/// The host page aligned runs that the pages at `indices`, in order, make up
+	pub(super) fn page_runs(&self, indices: impl Iterator<Item = usize>) -> Vec<AddressRange> {
+		indices
+			.map(|index| self.host_expand(AddressRange { start: self.addr.start + (index << PAGESHIFT), size: PAGESIZE }))
+			.coalesce(|x, y| if x.end() >= y.start {
+				Ok(AddressRange { start: x.start, size: std::cmp::max(x.end(), y.end()) - x.start })
+			} else {
+				Err((x, y))
+			})
+			.collect()
+	}
+	/// host_protect() the pages that the host can't read directly out of those at `indices`, in order, a run at a time.
+	/// Returns the runs, to apply_protections() to when done, or None if that failed, with everything put back.
+	pub(super) unsafe fn host_protect_unreadable(&self, indices: impl Iterator<Item = usize>) -> Option<Vec<AddressRange>> {
+		let runs = self.page_runs(indices.filter(|&index| !self.pages[index].host_readable()));
+		if runs.iter().all(|&run| self.host_protect(run, Protection::R)) {
+			Some(runs)
+		} else {
+			for &run in runs.iter() {
+				self.apply_protections(run);
+			}
+			None
+		}
+	}
+}
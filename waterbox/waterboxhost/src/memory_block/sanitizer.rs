@@ -0,0 +1,40 @@
+// Annotations for running the host under AddressSanitizer.  Host code runs on guest stacks, and guest threads switch
+// stacks behind ASan's back, so ASan's shadow memory for guest memory ends up marked as old stack frames, and host
+// reads of it look like errors.  Unpoisoning it again is all that's needed.  ASan is found at runtime, so the same
+// build works with it and without it.  Valgrind needs no annotations, since it follows the mappings itself.
+use crate::*;
+
+#[cfg(unix)]
+mod asan {
+	use lazy_static::lazy_static;
+	use libc::*;
+
+	type Unpoison = unsafe extern fn(*const c_void, usize);
+
+	lazy_static! {
+		/// __asan_unpoison_memory_region, if ASan is in the process
+		static ref UNPOISON: Option<Unpoison> = unsafe {
+			let f = dlsym(RTLD_DEFAULT, b"__asan_unpoison_memory_region\0".as_ptr() as *const c_char);
+			if f.is_null() {
+				None
+			} else {
+				Some(std::mem::transmute::<*mut c_void, Unpoison>(f))
+			}
+		};
+	}
+
+	pub fn unpoison(start: usize, size: usize) {
+		if let Some(f) = *UNPOISON {
+			unsafe { f(start as *const c_void, size) }
+		}
+	}
+}
+#[cfg(not(unix))]
+mod asan {
+	pub fn unpoison(_start: usize, _size: usize) {}
+}
+
+/// Tell ASan, if it's there, that all of `addr` can be accessed
+pub fn unpoison(addr: AddressRange) {
+	asan::unpoison(addr.start, addr.size);
+}
@@ -0,0 +1,72 @@
+// Searching guest memory for byte patterns, for the frontend's RAM search and cheat finders.  Runs of readable pages
+// are scanned in place, so a match can span pages, but nothing unreadable is ever touched.
+use super::*;
+
+/// Find where `pattern` occurs in `hay`, which starts at guest address `base`, at addresses that are multiples of
+/// `alignment`.  Bytes are compared only where `mask` has bits set.  Stops once `out` has `limit` entries.
+fn find(hay: &[u8], base: usize, pattern: &[u8], mask: Option<&[u8]>, alignment: usize, limit: usize, out: &mut Vec<usize>) {
+	if hay.len() < pattern.len() {
+		return
+	}
+	let last = hay.len() - pattern.len();
+	let mut i = (alignment - base % alignment) % alignment;
+	while i <= last && out.len() < limit {
+		let found = match mask {
+			None => &hay[i..i + pattern.len()] == pattern,
+			Some(mask) => hay[i..i + pattern.len()].iter().zip(pattern).zip(mask)
+				.all(|((h, p), m)| h & m == p & m),
+		};
+		if found {
+			out.push(base + i);
+		}
+		i += alignment;
+	}
+}
+
+impl<'block> ActivatedMemoryBlock<'block> {
+	/// Every address in `addr` where `pattern` is found, as with find(), up to `limit` of them.  Only memory the guest
+	/// can read is searched.
+	pub fn search(&mut self, addr: AddressRange, pattern: &[u8], mask: Option<&[u8]>, alignment: usize, limit: usize) -> Vec<usize> {
+		assert!(!pattern.is_empty() && alignment > 0);
+		assert!(!matches!(mask, Some(m) if m.len() != pattern.len()));
+		let mut res = Vec::new();
+		let start = std::cmp::max(addr.start, self.b.addr.start);
+		let end = std::cmp::min(addr.start.saturating_add(addr.size), self.b.addr.end());
+		if start >= end {
+			return res
+		}
+		let pstart = (start - self.b.addr.start) >> PAGESHIFT;
+		let pend = (align_up(end) - self.b.addr.start) >> PAGESHIFT;
+		let mut index = pstart;
+		while index < pend && res.len() < limit {
+			if !self.b.pages[index].status.readable() {
+				index += 1;
+				continue
+			}
+			let first = index;
+			while index < pend && self.b.pages[index].status.readable() {
+				index += 1;
+			}
+			let run = self.b.pages[first..index].iter()
+				.enumerate()
+				.map(|(i, p)| (AddressRange { start: self.b.addr.start + ((first + i) << PAGESHIFT), size: PAGESIZE }, p));
+			unsafe {
+				// watched pages have to be opened up for the host to look at them
+				for (paddr, p) in run.clone() {
+					if !p.host_readable() && !self.b.host_protect(paddr, Protection::R) {
+						return res
+					}
+				}
+				let from = std::cmp::max(start, self.b.addr.start + (first << PAGESHIFT));
+				let to = std::cmp::min(end, self.b.addr.start + (index << PAGESHIFT));
+				find(AddressRange { start: from, size: to - from }.slice(), from, pattern, mask, alignment, limit, &mut res);
+				for (paddr, p) in run {
+					if !p.host_readable() {
+						self.b.apply_protections(paddr);
+					}
+				}
+			}
+		}
+		res
+	}
+}
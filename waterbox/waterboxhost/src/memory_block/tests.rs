@@ -88,6 +88,7 @@ fn test_stk_norm() -> TestResult {
 
 /// dirt detection in RWStack area when $rsp points there
 #[test]
+#[cfg(target_arch = "x86_64")]
 fn test_stack() -> TestResult {
 	use std::convert::TryInto;
 	unsafe {
@@ -141,6 +142,7 @@ fn test_state_basic() -> TestResult {
 		g.seal();
 		let mut state0 = Vec::new();
 		g.save_state(&mut state0)?;
+		let hash0 = g.state_hash();
 
 		// no pages should be in the state
 		assert!(state0.len() < 0x1000);
@@ -149,13 +151,18 @@ fn test_state_basic() -> TestResult {
 		ptr[0x3000] = 44;
 
 		let mut state1 = Vec::new();
+		assert_eq!(g.state_size(), state0.len() + 0x2000);
 		g.save_state(&mut state1)?;
 
 		// two pages should be in the state
 		assert!(state1.len() > 0x2000);
 		assert!(state1.len() < 0x3000);
+		assert_eq!(state1.len(), state0.len() + 0x2000);
 
+		let hash1 = g.state_hash();
+		assert_ne!(hash1, hash0);
 		g.load_state(&mut state0.as_slice())?;
+		assert_eq!(g.state_hash(), hash0);
 
 		assert_eq!(ptr[0x0000], 20);
 		assert_eq!(ptr[0x1000], 40);
@@ -215,6 +222,7 @@ fn test_state_unreadable() -> TestResult {
 }
 
 #[test]
+#[cfg(target_arch = "x86_64")]
 fn test_thready_stack() -> TestResult {
 	use std::sync::{Arc, Barrier};
 	use std::thread;
@@ -401,6 +409,70 @@ fn test_mremap_move_expand() -> TestResult {
 	Ok(())
 }
 
+#[test]
+fn test_mremap_flags() -> TestResult {
+	unsafe {
+		let addr = AddressRange { start: 0x37d00000000, size: 0x10000 };
+		let mut b = MemoryBlock::new(addr);
+		let mut g = b.enter();
+		let ptr = g.b.addr.slice_mut();
+
+		let initial_addr = AddressRange { start: addr.start + 0x4000, size: 0x1000 };
+		g.mmap_fixed(initial_addr, Protection::RW, true)?;
+		g.mmap_fixed(AddressRange { start: addr.start + 0x5000, size: 0x1000 }, Protection::R, true)?;
+		ptr[0x4004] = 11;
+		assert_eq!(g.mremap(initial_addr, 0x2000, 0, addr), Err(EEXIST));
+		assert_eq!(g.mremap(initial_addr, 0x2000, MREMAP_FIXED | MREMAP_MAYMOVE, addr), Err(EINVAL));
+		// nowhere big enough to go
+		let tiny_arena = AddressRange { start: addr.start + 0x4000, size: 0x2000 };
+		assert_eq!(g.mremap(initial_addr, 0x2000, MREMAP_MAYMOVE, tiny_arena), Err(ENOMEM));
+		assert_eq!(ptr[0x4004], 11);
+		ptr[0x4005] = 12;
+		let moved = g.mremap(initial_addr, 0x1800, MREMAP_MAYMOVE, addr)?;
+		assert_ne!(moved, initial_addr.start);
+		assert_eq!(*((moved + 4) as *const u8), 11);
+		assert_eq!(*((moved + 5) as *const u8), 12);
+		*((moved + 0x1000) as *mut u8) = 13;
+		// shrinking never moves
+		assert_eq!(g.mremap(AddressRange { start: moved, size: 0x2000 }, 0x1000, MREMAP_MAYMOVE, addr)?, moved);
+	}
+	Ok(())
+}
+
+#[test]
+fn test_host_view() -> TestResult {
+	unsafe {
+		let addr = AddressRange { start: 0x37e00000000, size: 0x10000 };
+		let mut b = MemoryBlock::new(addr);
+		let view = {
+			let mut g = b.enter();
+			g.mmap_fixed(AddressRange { start: addr.start + 0x2000, size: 0x2000 }, Protection::RW, true)?;
+			g.seal();
+			let ptr = g.b.addr.slice_mut();
+			ptr[0x3001] = 7;
+			assert!(g.map_host_view(AddressRange { start: addr.start + 0x11000, size: 0x1000 }).is_err());
+			let view = g.map_host_view(AddressRange { start: addr.start + 0x3001, size: 0x10 })?;
+			assert_eq!(view.size, 0x1000);
+			assert_ne!(view.start, addr.start + 0x3000);
+			assert_eq!(view.slice()[1], 7);
+			ptr[0x3002] = 8;
+			assert_eq!(view.slice()[2], 8);
+
+			let mut state = Vec::new();
+			g.save_state(&mut state)?;
+			ptr[0x3001] = 9;
+			assert_eq!(view.slice()[1], 9);
+			g.load_state(&mut &state[..])?;
+			assert_eq!(view.slice()[1], 7);
+			view
+		};
+		// still there while the block isn't
+		assert_eq!(view.slice()[2], 8);
+		unmap_host_view(view)?;
+	}
+	Ok(())
+}
+
 #[test]
 fn test_mremap_move_shrink() -> TestResult {
 	unsafe {
@@ -420,3 +492,1475 @@ fn test_mremap_move_shrink() -> TestResult {
 	}
 	Ok(())
 }
+
+#[test]
+fn test_clean_unchanged() -> TestResult {
+	unsafe {
+		let addr = AddressRange { start: 0x37000000000, size: 0x4000 };
+		let mut b = MemoryBlock::new(addr);
+		let mut g = b.enter();
+		let ptr = g.b.addr.slice_mut();
+		g.mmap_fixed(addr, Protection::RW, true)?;
+		ptr[0x1000] = 40;
+		g.seal();
+
+		ptr[0x0000] = 5;
+		ptr[0x0000] = 0;
+		ptr[0x1000] = 41;
+		ptr[0x1000] = 40;
+		ptr[0x2000] = 7;
+		ptr[0x3000] = 9;
+		g.mprotect(AddressRange { start: 0x37000003000, size: 0x1000 }, Protection::None)?;
+
+		assert_eq!(g.clean_unchanged_pages(), 2);
+		assert!(!g.b.pages[0].dirty);
+		assert!(!g.b.pages[1].dirty);
+		assert!(g.b.pages[2].dirty);
+		assert!(g.b.pages[3].dirty);
+
+		// the cleaned pages must still trip on the next write
+		ptr[0x0000] = 1;
+		assert!(g.b.pages[0].dirty);
+
+		let mut state0 = Vec::new();
+		g.save_state(&mut state0)?;
+		assert!(state0.len() > 0x3000);
+		assert!(state0.len() < 0x4000);
+
+		ptr[0x0000] = 0;
+		assert_eq!(g.clean_unchanged_pages(), 1);
+		g.load_state(&mut state0.as_slice())?;
+		assert_eq!(ptr[0x0000], 1);
+		assert_eq!(ptr[0x1000], 40);
+		assert_eq!(ptr[0x2000], 7);
+		Ok(())
+	}
+}
+
+#[test]
+fn test_uffd() -> TestResult {
+	if !DirtyTracking::Userfaultfd.available() {
+		eprintln!("userfaultfd not available; skipping");
+		return Ok(())
+	}
+	unsafe {
+		let addr = AddressRange { start: 0x37100000000, size: 0x4000 };
+		let mut b = MemoryBlock::with_tracking(addr, DirtyTracking::Userfaultfd);
+		let mut g = b.enter();
+		let ptr = g.b.addr.slice_mut();
+		g.mmap_fixed(addr, Protection::RW, true)?;
+		ptr[0x0000] = 20;
+		ptr[0x1000] = 40;
+		g.seal();
+		assert!(!g.b.pages[0].dirty);
+		assert!(!g.b.pages[2].dirty);
+
+		let mut state0 = Vec::new();
+		g.save_state(&mut state0)?;
+		assert!(state0.len() < 0x1000);
+
+		ptr[0x1000] = 100;
+		ptr[0x3000] = 44;
+		assert!(!g.b.pages[0].dirty);
+		assert!(g.b.pages[1].dirty);
+		assert!(g.b.pages[3].dirty);
+
+		let mut state1 = Vec::new();
+		g.save_state(&mut state1)?;
+		assert!(state1.len() > 0x2000);
+		assert!(state1.len() < 0x3000);
+
+		g.load_state(&mut state0.as_slice())?;
+		assert_eq!(ptr[0x1000], 40);
+		assert_eq!(ptr[0x3000], 0);
+		assert!(!g.b.pages[1].dirty);
+
+		ptr[0x2000] = 1;
+		assert!(g.b.pages[2].dirty);
+
+		g.load_state(&mut state1.as_slice())?;
+		assert_eq!(ptr[0x0000], 20);
+		assert_eq!(ptr[0x1000], 100);
+		assert_eq!(ptr[0x2000], 0);
+		assert_eq!(ptr[0x3000], 44);
+		Ok(())
+	}
+}
+
+#[test]
+fn test_cow_snapshot() -> TestResult {
+	unsafe {
+		let addr = AddressRange { start: 0x37200000000, size: 0x5000 };
+		let mut b = MemoryBlock::new(addr);
+		let mut g = b.enter();
+		let ptr = g.b.addr.slice_mut();
+		g.mmap_fixed(addr, Protection::RW, true)?;
+		g.seal();
+
+		ptr[0x0000] = 10;
+		ptr[0x1000] = 20;
+		ptr[0x2000] = 30;
+		ptr[0x4000] = 50;
+		g.mprotect(AddressRange { start: 0x37200004000, size: 0x1000 }, Protection::None)?;
+		let mut expected = Vec::new();
+		g.save_state(&mut expected)?;
+		let mut snapshot = g.cow_snapshot()?;
+
+		ptr[0x0000] = 11;
+		ptr[0x3000] = 41;
+		g.munmap(AddressRange { start: 0x37200001000, size: 0x1000 })?;
+		g.mprotect(AddressRange { start: 0x37200002000, size: 0x1000 }, Protection::None)?;
+
+		let mut actual = Vec::new();
+		snapshot.save_state(&mut actual)?;
+		assert!(actual == expected);
+
+		g.load_state(&mut actual.as_slice())?;
+		assert_eq!(ptr[0x0000], 10);
+		assert_eq!(ptr[0x1000], 20);
+		assert_eq!(ptr[0x2000], 30);
+		assert_eq!(ptr[0x3000], 0);
+		Ok(())
+	}
+}
+
+#[test]
+fn test_cow_snapshot_thread() -> TestResult {
+	unsafe {
+		let addr = AddressRange { start: 0x37300000000, size: 0x100000 };
+		let mut b = MemoryBlock::new(addr);
+		let mut g = b.enter();
+		let ptr = g.b.addr.slice_mut();
+		g.mmap_fixed(addr, Protection::RW, true)?;
+		g.seal();
+
+		for i in (0..addr.size).step_by(PAGESIZE) {
+			ptr[i] = 1;
+		}
+		let mut expected = Vec::new();
+		g.save_state(&mut expected)?;
+		let mut snapshot = g.cow_snapshot()?;
+		let saver = std::thread::spawn(move || {
+			let mut actual = Vec::new();
+			snapshot.save_state(&mut actual).unwrap();
+			actual
+		});
+		for i in (0..addr.size).step_by(PAGESIZE).rev() {
+			ptr[i] = 2;
+		}
+		let actual = saver.join().unwrap();
+		assert!(actual == expected);
+		Ok(())
+	}
+}
+
+#[test]
+fn test_huge_pages() -> TestResult {
+	unsafe {
+		let addr = AddressRange { start: 0x37400000000, size: 0x600000 };
+		let mut b = MemoryBlock::new(addr);
+		let mut g = b.enter();
+		let ptr = g.b.addr.slice_mut();
+		g.set_huge_pages(true);
+		g.mmap_fixed(AddressRange { start: addr.start, size: 0x400000 }, Protection::RW, true)?;
+		g.mmap_fixed(AddressRange { start: addr.start + 0x400000, size: 0x1000 }, Protection::RW, true)?;
+		for i in (0..0x401000).step_by(PAGESIZE) {
+			ptr[i] = 1;
+		}
+		g.seal();
+
+		// dirty tracking must still work on individual small pages
+		ptr[0x1000] = 2;
+		ptr[0x400000] = 3;
+		assert!(!g.b.pages[0].dirty);
+		assert!(g.b.pages[1].dirty);
+		assert!(!g.b.pages[2].dirty);
+		assert!(g.b.pages[0x400].dirty);
+
+		let mut state0 = Vec::new();
+		g.save_state(&mut state0)?;
+		assert!(state0.len() > 0x2000);
+		assert!(state0.len() < 0x3000);
+
+		ptr[0x1000] = 4;
+		ptr[0x200000] = 5;
+		g.load_state(&mut state0.as_slice())?;
+		assert_eq!(ptr[0x1000], 2);
+		assert_eq!(ptr[0x200000], 1);
+		assert_eq!(ptr[0x400000], 3);
+		Ok(())
+	}
+}
+
+#[cfg(target_os = "linux")]
+fn resident_pages(addr: AddressRange) -> usize {
+	unsafe { pal::resident_pages(addr).unwrap() }
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_untouched_uncommitted() -> TestResult {
+	unsafe {
+		let addr = AddressRange { start: 0x37500000000, size: 0x200000 };
+		let mut b = MemoryBlock::new(addr);
+		let mut g = b.enter();
+		let ptr = g.b.addr.slice_mut();
+		let arena = AddressRange { start: addr.start + 0x100000, size: 0x100000 };
+		g.mmap_fixed(AddressRange { start: addr.start, size: 0x80000 }, Protection::RW, true)?;
+		ptr[0x1000] = 1;
+		g.seal();
+		assert_eq!(resident_pages(addr), 1);
+
+		let moved = g.mremap_maymove(AddressRange { start: addr.start, size: 0x80000 }, 0x90000, arena)?;
+		assert_eq!(resident_pages(addr), 1);
+		assert_eq!(*((moved + 0x1000) as *const u8), 1);
+		g.munmap(AddressRange { start: moved, size: 0x90000 })?;
+		assert_eq!(resident_pages(addr), 0);
+
+		let mut state = Vec::new();
+		g.save_state(&mut state)?;
+		assert!(state.len() < 0x3000);
+		Ok(())
+	}
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_free_decommits() -> TestResult {
+	unsafe {
+		let addr = AddressRange { start: 0x37600000000, size: 0x10000 };
+		let mut b = MemoryBlock::new(addr);
+		let mut g = b.enter();
+		let ptr = g.b.addr.slice_mut();
+		g.mmap_fixed(addr, Protection::RW, true)?;
+		g.seal();
+		for i in (0..0x8000).step_by(PAGESIZE) {
+			ptr[i] = 7;
+		}
+		assert_eq!(resident_pages(addr), 8);
+		g.munmap(AddressRange { start: addr.start + 0x2000, size: 0x4000 })?;
+		g.madvise_dontneed(AddressRange { start: addr.start + 0x7000, size: 0x1000 })?;
+		assert_eq!(resident_pages(addr), 3);
+		assert_eq!(ptr[0x7000], 0);
+		g.mmap_fixed(AddressRange { start: addr.start + 0x2000, size: 0x4000 }, Protection::RW, true)?;
+		assert_eq!(ptr[0x2000], 0);
+		assert_eq!(ptr[0x1000], 7);
+		Ok(())
+	}
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_commit_upfront() -> TestResult {
+	// fallocated shared memory doesn't show up in mincore until it's touched, but it's allocated all the same
+	fn allocated(b: &MemoryBlock) -> usize {
+		unsafe {
+			let mut st = std::mem::zeroed::<libc::stat>();
+			assert_eq!(libc::fstat(b.handle.raw() as i32, &mut st), 0);
+			st.st_blocks as usize * 512
+		}
+	}
+	unsafe {
+		let addr = AddressRange { start: 0x38600000000, size: 0x10000 };
+		let mut b = MemoryBlock::new(addr);
+		let mut g = b.enter();
+		let ptr = g.b.addr.slice_mut();
+		g.mmap_fixed(AddressRange { start: addr.start, size: 0x4000 }, Protection::RW, true)?;
+		assert_eq!(allocated(g.b), 0);
+		g.commit_all()?;
+		assert_eq!(allocated(g.b), 0x10000);
+		// protections are kept
+		ptr[0x1000] = 5;
+		g.seal();
+		// and nothing is given back
+		g.munmap(AddressRange { start: addr.start, size: 0x4000 })?;
+		assert_eq!(allocated(g.b), 0x10000);
+		g.mmap_fixed(AddressRange { start: addr.start, size: 0x4000 }, Protection::RW, true)?;
+		assert_eq!(ptr[0x1000], 0);
+		Ok(())
+	}
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_numa() -> TestResult {
+	unsafe {
+		let addr = AddressRange { start: 0x38700000000, size: 0x10000 };
+		let mut b = MemoryBlock::new(addr);
+		let mut g = b.enter();
+		let ptr = g.b.addr.slice_mut();
+		g.mmap_fixed(AddressRange { start: addr.start, size: 0x4000 }, Protection::RW, true)?;
+		ptr[0] = 1;
+		// every machine has a node 0
+		g.set_numa_node(Some(0))?;
+		g.seal();
+		ptr[0x3000] = 1;
+		let usage = g.numa_usage()?;
+		assert_eq!(usage.len(), 1);
+		assert_eq!(usage[0], 2 * g.b.host_page);
+		assert!(g.set_numa_node(Some(5000)).is_err());
+		g.set_numa_node(None)?;
+		Ok(())
+	}
+}
+
+#[test]
+fn test_memory_limit() -> TestResult {
+	let addr = AddressRange { start: 0x38800000000, size: 0x100000 };
+	let mut b = MemoryBlock::new(addr);
+	let mut g = b.enter();
+	g.mmap_fixed(AddressRange { start: addr.start, size: 0x4000 }, Protection::RW, true)?;
+	g.seal();
+	g.set_memory_limit(Some(0x8000));
+	assert_eq!(g.mmap_fixed(AddressRange { start: addr.start + 0x10000, size: 0x5000 }, Protection::RW, true), Err(ENOMEM));
+	// remapping what's already there doesn't count again
+	g.mmap_fixed(AddressRange { start: addr.start, size: 0x8000 }, Protection::R, false)?;
+	assert_eq!(g.mmap(AddressRange { start: 0, size: 0x1000 }, Protection::RW, addr, false), Err(ENOMEM));
+	assert_eq!(g.mremap(AddressRange { start: addr.start, size: 0x8000 }, 0x9000, MREMAP_MAYMOVE, addr), Err(ENOMEM));
+	g.munmap(AddressRange { start: addr.start, size: 0x4000 })?;
+	g.mmap(AddressRange { start: 0, size: 0x4000 }, Protection::RW, addr, false)?;
+	g.set_memory_limit(None);
+	g.mmap(AddressRange { start: 0, size: 0x10000 }, Protection::RW, addr, false)?;
+	Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn test_poisoned() -> TestResult {
+	let addr = AddressRange { start: 0x38900000000, size: 0x10000 };
+	let mut b = MemoryBlock::new(addr);
+	let mut g = b.enter();
+	g.mmap_fixed(AddressRange { start: addr.start, size: 0x4000 }, Protection::RW, true)?;
+	g.seal();
+	g.check_poisoned()?;
+	// pull guest memory out from under the block, so the OS refuses to change its protections
+	unsafe { assert_eq!(libc::munmap(addr.start as *mut libc::c_void, addr.size), 0); }
+	// to something it doesn't already have natively, or nothing would be asked of the OS
+	g.mprotect(AddressRange { start: addr.start, size: 0x1000 }, Protection::None)?;
+	assert!(g.check_poisoned().is_err());
+	assert!(g.save_state(&mut Vec::new()).is_err());
+	assert!(g.cow_snapshot().is_err());
+	Ok(())
+}
+
+#[test]
+fn test_stats() -> TestResult {
+	unsafe {
+		let addr = AddressRange { start: 0x37700000000, size: 0x8000 };
+		let mut b = MemoryBlock::new(addr);
+		let mut g = b.enter();
+		let ptr = g.b.addr.slice_mut();
+		g.mmap_fixed(AddressRange { start: addr.start, size: 0x3000 }, Protection::RW, true)?;
+		g.mmap_fixed(AddressRange { start: addr.start + 0x3000, size: 0x1000 }, Protection::RX, true)?;
+		ptr[0x1000] = 1;
+		g.seal();
+		ptr[0x1000] = 2;
+		ptr[0x2000] = 3;
+
+		let stats = g.stats();
+		assert_eq!(stats.free_pages, 4);
+		assert_eq!(stats.rw_pages, 3);
+		assert_eq!(stats.rx_pages, 1);
+		assert_eq!(stats.dirty_bytes, 0x2000);
+		assert_eq!(stats.snapshot_bytes, 0x1000);
+		#[cfg(target_os = "linux")]
+		assert_eq!(stats.committed_bytes, 0x2000);
+		Ok(())
+	}
+}
+
+#[test]
+fn test_aslr() -> TestResult {
+	let mut results = Vec::new();
+	for &start in [0x37800000000usize, 0x37900000000].iter() {
+		let addr = AddressRange { start, size: 0x1000000 };
+		let mut b = MemoryBlock::new(addr);
+		let mut g = b.enter();
+		g.set_mmap_seed(1234);
+		g.seal();
+		let mut res = Vec::new();
+		for _ in 0..4 {
+			res.push(g.mmap(AddressRange { start: 0, size: 0x3000 }, Protection::RW, addr, false)? - start);
+		}
+		let mut state = Vec::new();
+		g.save_state(&mut state)?;
+		let a = g.mmap(AddressRange { start: 0, size: 0x3000 }, Protection::RW, addr, false)?;
+		g.load_state(&mut state.as_slice())?;
+		let b = g.mmap(AddressRange { start: 0, size: 0x3000 }, Protection::RW, addr, false)?;
+		assert_eq!(a, b);
+		results.push(res);
+	}
+	assert_eq!(results[0], results[1]);
+	// without randomization, these would have been packed at the start of the arena
+	assert!(results[0].iter().any(|a| *a >= 0x100000));
+	Ok(())
+}
+
+static WATCH_HITS: std::sync::Mutex<Vec<(u32, usize, bool)>> = std::sync::Mutex::new(Vec::new());
+extern fn watch_callback(userdata: usize, id: u32, addr: usize, write: bool, _rip: usize) {
+	assert_eq!(userdata, 77);
+	WATCH_HITS.lock().unwrap().push((id, addr, write));
+}
+
+#[test]
+fn test_watchpoints() -> TestResult {
+	unsafe {
+		let addr = AddressRange { start: 0x37a00000000, size: 0x4000 };
+		let mut b = MemoryBlock::new(addr);
+		let mut g = b.enter();
+		let ptr = g.b.addr.slice_mut();
+		g.mmap_fixed(addr, Protection::RW, true)?;
+		ptr[0x2010] = 5;
+		g.seal();
+
+		let w = g.add_watchpoint(AddressRange { start: addr.start + 0x1010, size: 4 }, WATCH_WRITE, watch_callback, 77)?;
+		let r = g.add_watchpoint(AddressRange { start: addr.start + 0x2010, size: 1 }, WATCH_READ, watch_callback, 77)?;
+		std::ptr::write_volatile(&mut ptr[0x1012], 1);
+		std::ptr::write_volatile(&mut ptr[0x1100], 2);
+		assert_eq!(std::ptr::read_volatile(&ptr[0x1012]), 1);
+		assert_eq!(std::ptr::read_volatile(&ptr[0x2010]), 5);
+		assert_eq!(std::ptr::read_volatile(&ptr[0x2011]), 0);
+		assert_eq!(*WATCH_HITS.lock().unwrap(), vec![(w, addr.start + 0x1012, true), (r, addr.start + 0x2010, false)]);
+		assert!(g.b.pages[1].dirty);
+		assert!(!g.b.pages[2].dirty);
+
+		// states can still see the watched memory, without tripping anything
+		let mut state = Vec::new();
+		g.save_state(&mut state)?;
+		g.load_state(&mut state.as_slice())?;
+		assert_eq!(WATCH_HITS.lock().unwrap().len(), 2);
+
+		g.remove_watchpoint(w)?;
+		g.remove_watchpoint(r)?;
+		assert!(g.remove_watchpoint(r).is_err());
+		std::ptr::write_volatile(&mut ptr[0x1012], 3);
+		assert_eq!(std::ptr::read_volatile(&ptr[0x2010]), 5);
+		assert_eq!(WATCH_HITS.lock().unwrap().len(), 2);
+		Ok(())
+	}
+}
+
+#[test]
+fn test_transient() -> TestResult {
+	unsafe {
+		let addr = AddressRange { start: 0x38000000000, size: 0x4000 };
+		let transient = AddressRange { start: 0x38000001000, size: 0x2000 };
+		let mut b = MemoryBlock::new(addr);
+		let mut g = b.enter();
+		let ptr = g.b.addr.slice_mut();
+		g.mmap_fixed(addr, Protection::RW, true)?;
+		ptr[0x1000] = 1;
+		g.seal();
+
+		let baseline = g.state_size();
+		ptr[0x0000] = 10;
+		ptr[0x2000] = 30;
+		ptr[0x3000] = 40;
+		let mut before = Vec::new();
+		g.save_state(&mut before)?;
+
+		g.mark_transient(transient, true)?;
+		assert_eq!(g.state_size(), baseline + 2 * PAGESIZE);
+		let mut state = Vec::new();
+		g.save_state(&mut state)?;
+		assert_eq!(state.len(), g.state_size());
+		let mut cow = Vec::new();
+		g.cow_snapshot()?.save_state(&mut cow)?;
+		assert!(cow == state);
+
+		ptr[0x0000] = 11;
+		ptr[0x2000] = 31;
+		g.load_state(&mut state.as_slice())?;
+		assert_eq!(ptr[0x0000], 10);
+		assert_eq!(ptr[0x1000], 0);
+		assert_eq!(ptr[0x2000], 0);
+		assert_eq!(ptr[0x3000], 40);
+
+		// states from before the pages were transient still load
+		ptr[0x2000] = 32;
+		g.load_state(&mut before.as_slice())?;
+		assert_eq!(ptr[0x2000], 0);
+		assert_eq!(ptr[0x3000], 40);
+
+		g.mark_transient(transient, false)?;
+		ptr[0x2000] = 33;
+		state.clear();
+		g.save_state(&mut state)?;
+		ptr[0x2000] = 0;
+		g.load_state(&mut state.as_slice())?;
+		assert_eq!(ptr[0x2000], 33);
+		Ok(())
+	}
+}
+
+#[test]
+fn test_read_write() -> TestResult {
+	unsafe {
+		let addr = AddressRange { start: 0x38100000000, size: 0x4000 };
+		let mut b = MemoryBlock::new(addr);
+		let mut g = b.enter();
+		let ptr = g.b.addr.slice_mut();
+		g.mmap_fixed(AddressRange { start: 0x38100000000, size: 0x3000 }, Protection::RW, true)?;
+		ptr[0x0ffe] = 1;
+		ptr[0x1001] = 2;
+		g.mprotect(AddressRange { start: 0x38100002000, size: 0x1000 }, Protection::R)?;
+		g.seal();
+
+		let mut buf = [0u8; 4];
+		assert_eq!(g.read(0x38100000ffe, &mut buf), 4);
+		assert_eq!(buf, [1, 0, 0, 2]);
+		// runs off the end of what's mapped
+		let mut big = vec![0xffu8; 0x2000];
+		assert_eq!(g.read(0x38100002000, &mut big[..]), 0x1000);
+		assert!(big[..0x1000].iter().all(|b| *b == 0));
+		assert_eq!(big[0x1000], 0xff);
+		assert_eq!(g.read(0x38100003000, &mut buf), 0);
+		assert_eq!(g.read(0x1000, &mut buf), 0);
+
+		assert_eq!(g.write(0x38100000fff, &[5, 6, 7]), 3);
+		assert_eq!(ptr[0x0fff..0x1002], [5, 6, 7]);
+		assert_eq!(g.stats().dirty_bytes, 2 * PAGESIZE);
+		// stops at the read only page
+		assert_eq!(g.write(0x38100001ffe, &[8, 9, 10, 11]), 2);
+		assert_eq!(ptr[0x2000], 0);
+
+		// written memory is in states
+		let mut state = Vec::new();
+		g.save_state(&mut state)?;
+		g.write(0x38100001000, &[0]);
+		g.load_state(&mut state.as_slice())?;
+		assert_eq!(ptr[0x1000], 6);
+		assert_eq!(ptr[0x1ffe], 8);
+		Ok(())
+	}
+}
+
+static SEARCH_WATCH_HITS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+extern fn search_watch_callback(_userdata: usize, _id: u32, _addr: usize, _write: bool, _rip: usize) {
+	SEARCH_WATCH_HITS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+}
+
+#[test]
+fn test_search() -> TestResult {
+	unsafe {
+		let addr = AddressRange { start: 0x38200000000, size: 0x5000 };
+		let mut b = MemoryBlock::new(addr);
+		let mut g = b.enter();
+		let ptr = g.b.addr.slice_mut();
+		g.mmap_fixed(AddressRange { start: 0x38200000000, size: 0x3000 }, Protection::RW, true)?;
+		g.mmap_fixed(AddressRange { start: 0x38200004000, size: 0x1000 }, Protection::RW, true)?;
+		ptr[0x0010..0x0013].copy_from_slice(&[1, 2, 3]);
+		ptr[0x0ffe..0x1001].copy_from_slice(&[1, 2, 3]);
+		ptr[0x1021..0x1024].copy_from_slice(&[1, 2, 3]);
+		ptr[0x2000..0x2003].copy_from_slice(&[1, 0x42, 3]);
+		ptr[0x4000..0x4003].copy_from_slice(&[1, 2, 3]);
+		g.mprotect(AddressRange { start: 0x38200002000, size: 0x1000 }, Protection::None)?;
+		g.mprotect(AddressRange { start: 0x38200004000, size: 0x1000 }, Protection::R)?;
+		g.add_watchpoint(AddressRange { start: 0x38200004000, size: 4 }, WATCH_READ, search_watch_callback, 0)?;
+		g.seal();
+
+		let all = addr;
+		// spans a page boundary, and finds the watched page without tripping it
+		assert_eq!(g.search(all, &[1, 2, 3], None, 1, 100), vec![0x38200000010, 0x38200000ffe, 0x38200001021, 0x38200004000]);
+		assert_eq!(g.search(all, &[1, 2, 3], None, 2, 100), vec![0x38200000010, 0x38200000ffe, 0x38200004000]);
+		assert_eq!(g.search(all, &[1, 2, 3], None, 1, 2), vec![0x38200000010, 0x38200000ffe]);
+		assert_eq!(g.search(AddressRange { start: 0x38200000fff, size: 0x1024 }, &[1, 2, 3], None, 1, 100), vec![0x38200001021]);
+		// the unreadable page isn't searched
+		assert_eq!(g.search(all, &[1, 0, 3], Some(&[0xff, 0, 0xff]), 1, 100).len(), 4);
+		g.mprotect(AddressRange { start: 0x38200002000, size: 0x1000 }, Protection::R)?;
+		assert_eq!(g.search(all, &[1, 0, 3], Some(&[0xff, 0, 0xff]), 1, 100).len(), 5);
+		assert_eq!(SEARCH_WATCH_HITS.load(std::sync::atomic::Ordering::SeqCst), 0);
+		Ok(())
+	}
+}
+
+#[test]
+fn test_cheats() -> TestResult {
+	unsafe {
+		let addr = AddressRange { start: 0x38300000000, size: 0x3000 };
+		let mut b = MemoryBlock::new(addr);
+		let mut g = b.enter();
+		let ptr = g.b.addr.slice_mut();
+		g.mmap_fixed(AddressRange { start: 0x38300000000, size: 0x2000 }, Protection::RW, true)?;
+		ptr[0x0010] = 3;
+		g.seal();
+
+		assert!(g.add_cheat(0x38300000ffe, 4, 0, None, false).is_err());
+		assert!(g.add_cheat(0x38300000000, 3, 0, None, false).is_err());
+		let held = g.add_cheat(0x38300000020, 2, 0x1234, None, false)?;
+		let compared = g.add_cheat(0x38300000010, 1, 99, Some(4), false)?;
+		let unmapped = g.add_cheat(0x38300002000, 1, 1, None, false)?;
+		g.apply_cheats();
+		assert_eq!(ptr[0x0020..0x0022], [0x34, 0x12]);
+		assert_eq!(ptr[0x0010], 3);
+		ptr[0x0010] = 4;
+		ptr[0x0020] = 0;
+		g.apply_cheats();
+		assert_eq!(ptr[0x0010], 99);
+		assert_eq!(ptr[0x0020], 0x34);
+		g.remove_cheat(held)?;
+		g.remove_cheat(compared)?;
+		g.remove_cheat(unmapped)?;
+		assert!(g.remove_cheat(held).is_err());
+
+		// already holding its value, so nothing is dirtied
+		let clean = g.add_cheat(0x38300001000, 4, 0, None, false)?;
+		g.apply_cheats();
+		assert!(!g.b.pages[1].dirty);
+		g.remove_cheat(clean)?;
+
+		let frozen = g.add_cheat(0x38300001100, 4, 0xdeadbeef, None, true)?;
+		g.apply_cheats();
+		std::ptr::write_volatile(&mut ptr[0x1101], 0);
+		std::ptr::write_volatile(&mut ptr[0x1200], 7);
+		assert_eq!(std::ptr::read_volatile(&ptr[0x1101]), 0xbe);
+		assert_eq!(std::ptr::read_volatile(&ptr[0x1200]), 7);
+		g.remove_cheat(frozen)?;
+		std::ptr::write_volatile(&mut ptr[0x1101], 0);
+		assert_eq!(std::ptr::read_volatile(&ptr[0x1101]), 0);
+		Ok(())
+	}
+}
+
+/// guest pages that share a bigger host page, which only happens for real on some ARM systems, but can be pretended
+#[test]
+fn test_big_host_pages() -> TestResult {
+	unsafe {
+		// the last host page sticks out past the end
+		let addr = AddressRange { start: 0x38400000000, size: 0x9000 };
+		let mut blocks = [PAGESIZE, 0x4000].iter()
+			.map(|&host_page| MemoryBlock::with_host_page(addr, DirtyTracking::Signal, host_page))
+			.collect::<Vec<_>>();
+		let mut states = Vec::new();
+		for b in blocks.iter_mut() {
+			let mut g = b.enter();
+			let ptr = g.b.addr.slice_mut();
+			g.mmap_fixed(AddressRange { start: 0x38400000000, size: 0x3000 }, Protection::RW, true)?;
+			g.mmap_fixed(AddressRange { start: 0x38400003000, size: 0x1000 }, Protection::R, true)?;
+			g.mmap_fixed(AddressRange { start: 0x38400008000, size: 0x1000 }, Protection::RW, true)?;
+			g.seal();
+
+			ptr[0x1010] = 7;
+			ptr[0x8004] = 9;
+			assert!(g.b.pages[1].dirty);
+			assert!(g.b.pages[8].dirty);
+			// everything writable in the host page is let through at once
+			assert_eq!(g.b.pages[0].dirty, g.b.host_page > PAGESIZE);
+			assert!(!g.b.pages[3].dirty);
+
+			g.munmap(AddressRange { start: 0x38400002000, size: 0x1000 })?;
+			g.mprotect(AddressRange { start: 0x38400000000, size: 0x1000 }, Protection::None)?;
+			assert_eq!(ptr[0x1010], 7);
+			assert_eq!(g.write(0x38400001020, &[5]), 1);
+			let mut buf = [0u8; 2];
+			assert_eq!(g.read(0x3840000101f, &mut buf), 2);
+			assert_eq!(buf, [0, 5]);
+
+			// which leaves exactly what a page at a time would have
+			g.clean_unchanged_pages();
+			let mut state = Vec::new();
+			g.save_state(&mut state)?;
+			states.push(state);
+		}
+		assert!(states[0] == states[1]);
+
+		let mut g = blocks[1].enter();
+		let ptr = g.b.addr.slice_mut();
+		ptr[0x1010] = 1;
+		ptr[0x8004] = 1;
+		g.load_state(&mut states[0].as_slice())?;
+		assert_eq!(ptr[0x1010], 7);
+		assert_eq!(ptr[0x1020], 5);
+		assert_eq!(ptr[0x8004], 9);
+
+		// watched pages can't be told apart inside a host page, so watchpoints say so instead of missing accesses
+		let err = g.add_watchpoint(AddressRange { start: 0x38400001000, size: 4 }, WATCH_WRITE, watch_callback, 0).unwrap_err();
+		assert_eq!(ErrorCode::of(&err), ErrorCode::Unsupported);
+		if watch::WATCH_SUPPORTED {
+			assert_eq!(err.to_string(), "Watchpoints need 4K host pages, but this host's are 16K");
+		}
+		assert!(g.add_audit(AddressRange { start: 0x38400001000, size: 4 }).is_err());
+		assert!(g.add_cheat(0x38400001000, 4, 0, None, true).is_err());
+		assert!(g.b.watchpoints.is_empty());
+		Ok(())
+	}
+}
+
+/// stack mappings get a guard page, which goes away with them
+#[test]
+fn test_stack_guard() -> TestResult {
+	let addr = AddressRange { start: 0x38b00000000, size: 0x10000 };
+	let mut b = MemoryBlock::new(addr);
+	let mut g = b.enter();
+	let stack = g.mmap(AddressRange { start: 0, size: 0x4000 }, Protection::RWStack, addr, false)?;
+	let stats = g.stats();
+	assert_eq!((stats.rwstack_pages, stats.none_pages), (4, 1));
+	assert_eq!(g.b.pages[((stack - addr.start) >> PAGESHIFT) - 1].status, PageAllocation::Allocated(Protection::StackGuard));
+	unsafe { *((stack + 0x3ff8) as *mut usize) = 1; }
+
+	let top = stack + 0x4000;
+	assert_eq!(tripguard::stack_depth(stack - 8, stack + 0x10), Some(0x3ff0));
+	assert_eq!(tripguard::stack_depth(stack + 0x100, stack + 0x110), None);
+	// not a stack, so no guard
+	let plain = g.mmap(AddressRange { start: 0, size: 0x1000 }, Protection::RW, addr, false)?;
+	assert_eq!(g.stats().none_pages, 1);
+	assert_eq!(tripguard::stack_depth(plain - 8, top - 0x10), None);
+
+	g.munmap(AddressRange { start: stack, size: 0x4000 })?;
+	let stats = g.stats();
+	assert_eq!((stats.rwstack_pages, stats.none_pages, stats.free_pages), (0, 0, 15));
+	Ok(())
+}
+
+#[test]
+fn test_scribble() -> TestResult {
+	let addr = AddressRange { start: 0x38d00000000, size: 0x10000 };
+	let mut b = MemoryBlock::new(addr);
+	let mut g = b.enter();
+	g.set_scribble(true);
+	let p = g.mmap(AddressRange { start: 0, size: 0x2000 }, Protection::RW, addr, false)?;
+	let range = AddressRange { start: p, size: 0x2000 };
+	g.scribble_new(range);
+	unsafe {
+		assert!(range.slice().iter().all(|&b| b == SCRIBBLE_NEW));
+		*(p as *mut u8) = 1;
+	}
+	g.madvise_dontneed(range)?;
+	assert!(unsafe { range.slice() }.iter().all(|&b| b == SCRIBBLE_FREED));
+
+	g.set_scribble(false);
+	let q = g.mmap(AddressRange { start: 0, size: 0x1000 }, Protection::RW, addr, false)?;
+	g.scribble_new(AddressRange { start: q, size: 0x1000 });
+	g.madvise_dontneed(range)?;
+	assert!(unsafe { AddressRange { start: q, size: 0x1000 }.slice() }.iter().all(|&b| b == 0));
+	assert!(unsafe { range.slice() }.iter().all(|&b| b == 0));
+	Ok(())
+}
+
+#[test]
+fn test_sanitizer_mode() -> TestResult {
+	unsafe {
+		let addr = AddressRange { start: 0x38e00000000, size: 0x4000 };
+		let mut b = MemoryBlock::with_tracking(addr, DirtyTracking::Eager);
+		b.set_sanitizer_mode(true);
+		let mut g = b.enter();
+		let ptr = g.b.addr.slice_mut();
+		g.mmap_fixed(addr, Protection::RW, true)?;
+		ptr[0x0000] = 20;
+		ptr[0x3000] = 80;
+		g.mprotect(AddressRange { start: addr.start + 0x2000, size: 0x1000 }, Protection::R)?;
+		assert!(g.add_watchpoint(addr, WATCH_WRITE, watch_callback, 0).is_err());
+
+		g.seal();
+		// the writable pages are all caught up front, so writing them doesn't fault
+		assert!(g.b.pages.iter().all(|p| p.dirty == p.status.writable()));
+		let mut state0 = Vec::new();
+		g.save_state(&mut state0)?;
+		assert!(state0.len() > 0x3000);
+		ptr[0x0000] = 21;
+		ptr[0x1000] = 40;
+
+		g.load_state(&mut state0.as_slice())?;
+		assert_eq!((ptr[0x0000], ptr[0x1000], ptr[0x3000]), (20, 0, 80));
+		assert!(g.b.pages.iter().all(|p| !(p.status.writable() && p.needs_trip())));
+		g.unpoison_for_sanitizers();
+		Ok(())
+	}
+}
+
+#[test]
+fn test_heat_map() -> TestResult {
+	unsafe {
+		let addr = AddressRange { start: 0x38f00000000, size: 0x4000 };
+		let mut b = MemoryBlock::new(addr);
+		let mut g = b.enter();
+		let ptr = addr.start as *mut u8;
+		g.mmap_fixed(addr, Protection::RW, true)?;
+		g.mprotect(AddressRange { start: addr.start + 0x3000, size: 0x1000 }, Protection::R)?;
+		g.seal();
+		g.set_heat_map(true)?;
+
+		g.arm_heat_map();
+		assert!(!g.b.pages[0].host_readable());
+		std::ptr::read_volatile(ptr);
+		*ptr.add(0x1000) = 1;
+		std::ptr::read_volatile(ptr.add(0x2000));
+		*ptr.add(0x2000) = 2;
+		*ptr.add(0x2008) = 3;
+		g.disarm_heat_map();
+		g.arm_heat_map();
+		std::ptr::read_volatile(ptr);
+		std::ptr::read_volatile(ptr.add(0x3000));
+		g.disarm_heat_map();
+
+		let (info, pages) = g.heat_map().unwrap();
+		assert_eq!((info.start, info.pages, info.samples), (addr.start, 4, 2));
+		let counts = pages.iter().map(|p| (p.accessed, p.written)).collect::<Vec<_>>();
+		assert_eq!(counts, [(2, 0), (1, 1), (1, 1), (1, 0)]);
+		// dirty tracking still saw the writes
+		assert!(!g.b.pages[0].dirty && g.b.pages[1].dirty && g.b.pages[2].dirty);
+		assert!(g.b.pages[0].host_readable());
+
+		g.reset_heat_map();
+		assert_eq!(g.heat_map().unwrap().0.samples, 0);
+		g.set_heat_map(false)?;
+		assert!(g.heat_map().is_none());
+		Ok(())
+	}
+}
+
+#[test]
+fn test_dirty_markers() -> TestResult {
+	unsafe {
+		let addr = AddressRange { start: 0x39000000000, size: 0x4000 };
+		let mut b = MemoryBlock::new(addr);
+		let mut g = b.enter();
+		let ptr = addr.start as *mut u8;
+		g.mmap_fixed(addr, Protection::RW, true)?;
+		*ptr = 1;
+		g.seal();
+
+		let frame = g.add_dirty_marker()?;
+		let total = g.add_dirty_marker()?;
+		*ptr = 2;
+		*ptr.add(0x1000) = 2;
+		*ptr.add(0x1008) = 2;
+		assert_eq!((g.dirty_marker(frame)?, g.dirty_marker(total)?), (2, 2));
+		assert!(g.b.pages[1].dirty);
+
+		g.reset_dirty_marker(frame)?;
+		assert_eq!(g.dirty_marker(frame)?, 0);
+		*ptr.add(0x1000) = 3;
+		*ptr.add(0x2000) = 3;
+		assert_eq!((g.dirty_marker(frame)?, g.dirty_marker(total)?), (2, 3));
+		// written by the host, so not counted
+		g.write(addr.start + 0x3000, &[4]);
+		assert_eq!(g.dirty_marker(total)?, 3);
+
+		g.remove_dirty_marker(total)?;
+		assert!(g.dirty_marker(total).is_err());
+		g.remove_dirty_marker(frame)?;
+		assert!(g.b.pages.iter().all(|p| p.markers == 0));
+		for _ in 0..markers::MAX_DIRTY_MARKERS {
+			g.add_dirty_marker()?;
+		}
+		assert!(g.add_dirty_marker().is_err());
+		Ok(())
+	}
+}
+
+#[test]
+fn test_compare_states() -> TestResult {
+	unsafe {
+		let addr = AddressRange { start: 0x39100000000, size: 0x4000 };
+		let mut b = MemoryBlock::new(addr);
+		let mut g = b.enter();
+		let ptr = addr.start as *mut u8;
+		g.mmap_fixed(addr, Protection::RW, true)?;
+		*ptr = 1;
+		g.seal();
+
+		*ptr = 2;
+		*ptr.add(0x3000) = 5;
+		let mut state0 = Vec::new();
+		g.save_state(&mut state0)?;
+		*ptr = 1;
+		*ptr.add(0x1000) = 3;
+		*ptr.add(0x1fff) = 3;
+		g.mprotect(AddressRange { start: addr.start + 0x2000, size: 0x1000 }, Protection::R)?;
+		let mut state1 = Vec::new();
+		g.save_state(&mut state1)?;
+
+		let diffs = g.compare_states(&mut &state0[..], &mut &state1[..])?;
+		assert_eq!(diffs, vec![
+			// back to what it was sealed with in state1
+			PageDiff { addr: addr.start, bytes: 1, status_changed: false },
+			PageDiff { addr: addr.start + 0x1000, bytes: 2, status_changed: false },
+			PageDiff { addr: addr.start + 0x2000, bytes: 0, status_changed: true },
+		]);
+		assert!(g.compare_states(&mut &state1[..], &mut &state1[..])?.is_empty());
+		// neither state was loaded
+		assert_eq!(*ptr.add(0x1000), 3);
+		Ok(())
+	}
+}
+
+#[test]
+fn test_load_state_file() -> TestResult {
+	unsafe {
+		let addr = AddressRange { start: 0x39200000000, size: 0x5000 };
+		let mut b = MemoryBlock::new(addr);
+		let mut g = b.enter();
+		let ptr = addr.start as *mut u8;
+		g.mmap_fixed(addr, Protection::RW, true)?;
+		g.mprotect(AddressRange { start: addr.start + 0x4000, size: 0x1000 }, Protection::RWX)?;
+		g.seal();
+
+		for i in 0..5 {
+			*ptr.add(i << PAGESHIFT) = i as u8 + 1;
+		}
+		// where a header padded to a page boundary would leave the pages
+		let pad = (PAGESIZE - g.state_header_size() % PAGESIZE) % PAGESIZE;
+		let mut state = vec![0u8; pad];
+		g.save_state(&mut state)?;
+		let path = std::env::temp_dir().join(format!("wbx_test_load_state_file_{}", std::process::id()));
+		std::fs::write(&path, &state[..])?;
+		let file = std::fs::File::open(&path)?;
+		std::fs::remove_file(&path)?;
+
+		for i in 0..5 {
+			*ptr.add(i << PAGESHIFT) = 9;
+		}
+		assert_eq!(g.load_state_file(&file, pad as u64)?, state.len() as u64);
+		for i in 0..5 {
+			assert_eq!(*ptr.add(i << PAGESHIFT), i as u8 + 1);
+		}
+		if page_size::get() != PAGESIZE {
+			return Ok(())
+		}
+		// the executable page was copied
+		assert_eq!(g.state_file_pages(), 4);
+		assert!(!g.b.pages[4].from_file);
+
+		*ptr.add(0x1000) = 7;
+		assert_eq!(g.state_file_pages(), 3);
+		g.mprotect(AddressRange { start: addr.start + 0x2000, size: 0x1000 }, Protection::R)?;
+		assert_eq!(g.state_file_pages(), 2);
+		assert_eq!(*ptr.add(0x2000), 3);
+		drop(g);
+
+		// swapping out took the rest off the file
+		let mut g = b.enter();
+		assert_eq!(g.state_file_pages(), 0);
+		assert_eq!(*ptr, 1);
+		assert_eq!(*ptr.add(0x1000), 7);
+		assert_eq!(*ptr.add(0x3000), 4);
+
+		// a host view takes everything off the file first, and has states copied from then on, so it sees the pages
+		g.load_state_file(&file, pad as u64)?;
+		assert_eq!(g.state_file_pages(), 4);
+		let view = g.map_host_view(addr)?;
+		assert_eq!(g.state_file_pages(), 0);
+		*ptr.add(0x1000) = 7;
+		g.load_state_file(&file, pad as u64)?;
+		assert_eq!(g.state_file_pages(), 0);
+		for i in 0..5 {
+			assert_eq!(view.slice()[i << PAGESHIFT], i as u8 + 1);
+		}
+		unmap_host_view(view)?;
+		*ptr.add(0x1000) = 7;
+
+		// a state that isn't page aligned in the file is copied
+		let mut state = vec![0u8; pad + 1];
+		g.save_state(&mut state)?;
+		std::fs::write(&path, &state[..])?;
+		let file = std::fs::File::open(&path)?;
+		std::fs::remove_file(&path)?;
+		*ptr.add(0x1000) = 9;
+		g.load_state_file(&file, pad as u64 + 1)?;
+		assert_eq!(g.state_file_pages(), 0);
+		assert_eq!(*ptr.add(0x1000), 7);
+		Ok(())
+	}
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_trim() -> TestResult {
+	unsafe {
+		let addr = AddressRange { start: 0x39300000000, size: 0x8000 };
+		let mut b = MemoryBlock::new(addr);
+		let mut g = b.enter();
+		let ptr = addr.start as *mut u8;
+		g.mmap_fixed(addr, Protection::RW, true)?;
+		g.seal();
+		for i in 0..6 {
+			*ptr.add(i << PAGESHIFT) = 5;
+		}
+		let mut state = Vec::new();
+		g.save_state(&mut state)?;
+		// freed by an allocator that zeroes, without being unmapped
+		for i in 2..5 {
+			*ptr.add(i << PAGESHIFT) = 0;
+		}
+		g.mprotect(AddressRange { start: addr.start + 0x3000, size: 0x1000 }, Protection::R)?;
+		assert_eq!(resident_pages(addr), 6);
+		assert_eq!(g.trim(), 0x3000);
+		assert_eq!(resident_pages(addr), 3);
+		assert_eq!(g.trim(), 0);
+
+		// nothing else noticed
+		assert_eq!(*ptr.add(0x3000), 0);
+		assert!(g.b.pages[2].dirty);
+		assert_eq!(g.b.pages[3].status, PageAllocation::Allocated(Protection::R));
+		*ptr.add(0x2000) = 6;
+		g.load_state(&mut &state[..])?;
+		assert_eq!(*ptr.add(0x2000), 5);
+		assert_eq!(*ptr.add(0x4000), 5);
+		Ok(())
+	}
+}
+
+#[test]
+fn test_madvise() -> TestResult {
+	unsafe {
+		let addr = AddressRange { start: 0x39400000000, size: 0x6000 };
+		let mut b = MemoryBlock::new(addr);
+		let mut g = b.enter();
+		let ptr = addr.start as *mut u8;
+		g.mmap_fixed(AddressRange { start: addr.start, size: 0x5000 }, Protection::RW, true)?;
+		g.seal();
+		for i in 0..5 {
+			*ptr.add(i << PAGESHIFT) = 3;
+		}
+
+		// lengths are rounded up
+		g.madvise(AddressRange { start: addr.start, size: 1 }, MADV_DONTNEED)?;
+		assert_eq!((*ptr, *ptr.add(0x1000)), (0, 3));
+		g.madvise(AddressRange { start: addr.start + 0x1000, size: 0x1000 }, MADV_FREE)?;
+		assert_eq!(*ptr.add(0x1000), 0);
+		assert!(!g.b.pages[1].dirty);
+		// only hints
+		g.madvise(AddressRange { start: addr.start + 0x2000, size: 0x3000 }, MADV_WILLNEED)?;
+		g.madvise(AddressRange { start: addr.start + 0x2000, size: 0x3000 }, MADV_HUGEPAGE)?;
+		assert_eq!(*ptr.add(0x2000), 3);
+		g.madvise(AddressRange { start: addr.start + 0x2000, size: 0 }, MADV_DONTNEED)?;
+
+		assert_eq!(g.madvise(AddressRange { start: addr.start + 1, size: 0x1000 }, MADV_DONTNEED), Err(EINVAL));
+		assert_eq!(g.madvise(AddressRange { start: addr.start + 0x4000, size: 0x2000 }, MADV_DONTNEED), Err(ENOMEM));
+		assert_eq!(g.madvise(AddressRange { start: addr.end(), size: 0x1000 }, MADV_NORMAL), Err(ENOMEM));
+		assert_eq!(*ptr.add(0x4000), 3);
+		Ok(())
+	}
+}
+
+#[test]
+fn test_applied_protections() -> TestResult {
+	unsafe {
+		let addr = AddressRange { start: 0x39500000000, size: 0x8000 };
+		let mut b = MemoryBlock::new(addr);
+		let mut g = b.enter();
+		let ptr = addr.start as *mut u8;
+		let expect = |g: &ActivatedMemoryBlock| {
+			for (index, p) in g.b.pages.iter().enumerate() {
+				let host = index * PAGESIZE / g.b.host_page;
+				assert_eq!(g.b.applied.0[host].get(), Some(p.native_state(g.b.tracking).0), "page {}", index);
+			}
+		};
+		g.mmap_fixed(AddressRange { start: addr.start, size: 0x6000 }, Protection::RW, true)?;
+		g.seal();
+		expect(&g);
+		for i in (0..6).step_by(2) {
+			*ptr.add(i << PAGESHIFT) = 1;
+		}
+		expect(&g);
+		let mut state = Vec::new();
+		g.save_state(&mut state)?;
+		expect(&g);
+		g.state_hash();
+		expect(&g);
+		*ptr.add(0x1000) = 2;
+		g.load_state(&mut &state[..])?;
+		expect(&g);
+		assert_eq!(*ptr.add(0x1000), 0);
+		// still caught after being skipped
+		*ptr.add(0x3000) = 3;
+		assert!(g.b.pages[3].dirty);
+		expect(&g);
+		drop(g);
+
+		let g = b.enter();
+		expect(&g);
+		Ok(())
+	}
+}
+
+#[test]
+fn test_first_write() -> TestResult {
+	unsafe {
+		let addr = AddressRange { start: 0x39a00000000, size: 0x4000 };
+		let mut b = MemoryBlock::new(addr);
+		// a recycled page with something in it, for the reserve to be topped up with
+		let mut pb = PageBlock::new();
+		pb.slice_mut()[7] = 99;
+		drop(pb);
+		let mut g = b.enter();
+		let ptr = addr.start as *mut u8;
+		g.mmap_fixed(addr, Protection::RW, true)?;
+		for i in 0..4 {
+			*ptr.add((i << PAGESHIFT) + 7) = i as u8 + 1;
+		}
+		g.seal();
+		*ptr.add(0x1007) = 42;
+		assert!(g.b.pages[1].dirty);
+		match &g.b.pages[1].snapshot {
+			Snapshot::Data(d) => assert_eq!(d.slice()[7], 2),
+			_ => panic!("no snapshot"),
+		}
+		assert_eq!(g.b.applied.0[1].get(), Some(Protection::RW));
+		assert!(!g.b.pages[2].dirty);
+		let mut state = Vec::new();
+		g.save_state(&mut state)?;
+		*ptr.add(0x2007) = 43;
+		g.load_state(&mut &state[..])?;
+		assert_eq!((*ptr.add(0x1007), *ptr.add(0x2007)), (42, 3));
+		Ok(())
+	}
+}
+
+#[test]
+fn test_concurrent_blocks() {
+	// blocks in different 4GiB regions can be active on different threads at once, faults and all
+	let threads = [0x39b00000000usize, 0x39c00000000].iter().map(|&start| {
+		std::thread::spawn(move || -> TestResult {
+			let addr = AddressRange { start, size: 0x40000 };
+			let mut b = MemoryBlock::new(addr);
+			let mut g = b.enter();
+			g.mmap_fixed(addr, Protection::RW, true)?;
+			g.seal();
+			let mut state = Vec::new();
+			g.save_state(&mut state)?;
+			for round in 0..50 {
+				for i in 0..0x40 {
+					unsafe { *((start + (i << PAGESHIFT)) as *mut u8) = round + 1; }
+				}
+				assert!(g.b.pages.iter().all(|p| p.dirty));
+				g.load_state(&mut &state[..])?;
+				assert_eq!(unsafe { *((start + 0x3f000) as *const u8) }, 0);
+			}
+			Ok(())
+		})
+	}).collect::<Vec<_>>();
+	for t in threads {
+		t.join().unwrap().unwrap();
+	}
+}
+
+extern fn nested_watch_callback(userdata: usize, _id: u32, _addr: usize, _write: bool, _rip: usize) {
+	// what a callback calling back into the guest does, which faults from inside the fault handler
+	unsafe { std::ptr::write_volatile(userdata as *mut u8, 9); }
+}
+
+#[test]
+fn test_watch_callback_faults() -> TestResult {
+	unsafe {
+		let addr = AddressRange { start: 0x39d00000000, size: 0x4000 };
+		let mut b = MemoryBlock::new(addr);
+		let mut g = b.enter();
+		let ptr = g.b.addr.slice_mut();
+		g.mmap_fixed(addr, Protection::RW, true)?;
+		g.seal();
+		g.add_watchpoint(AddressRange { start: addr.start + 0x1000, size: 4 }, WATCH_WRITE, nested_watch_callback, addr.start + 0x3000)?;
+		std::ptr::write_volatile(&mut ptr[0x1000], 1);
+		assert_eq!((ptr[0x1000], ptr[0x3000]), (1, 9));
+		assert!(g.b.pages[3].dirty);
+		Ok(())
+	}
+}
+
+#[test]
+fn test_reseal() -> TestResult {
+	unsafe {
+		let addr = AddressRange { start: 0x39e00000000, size: 0x4000 };
+		let mut b = MemoryBlock::new(addr);
+		let mut g = b.enter();
+		let ptr = addr.start as *mut u8;
+		g.mmap_fixed(addr, Protection::RW, true)?;
+		g.seal();
+		let baseline = g.state_size();
+		// setup the core does after its first frame
+		*ptr = 1;
+		*ptr.add(0x1000) = 2;
+		let mut before = Vec::new();
+		g.save_state(&mut before)?;
+		assert_eq!(g.state_size(), baseline + 2 * PAGESIZE);
+
+		g.reseal()?;
+		assert!(g.b.pages.iter().all(|p| !p.dirty));
+		assert_eq!(g.state_size(), baseline);
+		*ptr.add(0x2000) = 3;
+		let mut after = Vec::new();
+		g.save_state(&mut after)?;
+		assert_eq!(after.len(), baseline + PAGESIZE);
+
+		*ptr.add(0x1000) = 4;
+		g.load_state(&mut &after[..])?;
+		assert_eq!((*ptr, *ptr.add(0x1000), *ptr.add(0x2000)), (1, 2, 3));
+		let err = g.load_state(&mut &before[..]).unwrap_err();
+		assert_eq!(ErrorCode::of(&err), ErrorCode::StateMismatch);
+		Ok(())
+	}
+}
+
+#[test]
+#[cfg(feature = "dev-unseal")]
+fn test_make_writable() -> TestResult {
+	let addr = AddressRange { start: 0x39f00000000, size: 0x4000 };
+	let mut b = MemoryBlock::new(addr);
+	let mut g = b.enter();
+	g.mmap_fixed(addr, Protection::RW, true)?;
+	g.mprotect(AddressRange { start: addr.start, size: 0x2000 }, Protection::R)?;
+	g.mprotect(AddressRange { start: addr.start + 0x3000, size: 0x1000 }, Protection::RX)?;
+	g.seal();
+	assert_eq!(g.write(addr.start, &[1]), 0);
+	g.make_writable(addr)?;
+	assert_eq!(g.write(addr.start, &[1, 2]), 2);
+	assert_eq!(g.b.pages[1].status, PageAllocation::Allocated(Protection::RW));
+	assert_eq!(g.b.pages[3].status, PageAllocation::Allocated(Protection::RX));
+	Ok(())
+}
+
+#[test]
+fn test_audit() -> TestResult {
+	unsafe {
+		let addr = AddressRange { start: 0x3a000000000, size: 0x3000 };
+		let mut b = MemoryBlock::new(addr);
+		let mut g = b.enter();
+		let ptr = g.b.addr.slice_mut();
+		g.mmap_fixed(addr, Protection::RW, true)?;
+		ptr[0x1000..0x1004].copy_from_slice(&[1, 2, 3, 4]);
+		g.seal();
+
+		assert!(g.add_audit(AddressRange { start: addr.end(), size: 1 }).is_err());
+		let a = g.add_audit(AddressRange { start: addr.start + 0x1000, size: 4 })?;
+		g.add_cheat(addr.start + 0x1002, 1, 9, None, true)?;
+		std::ptr::write_volatile(ptr[0x1002..].as_mut_ptr() as *mut u16, 0x7777);
+		std::ptr::write_volatile(&mut ptr[0x1008], 5);
+		std::ptr::write_volatile(&mut ptr[0x1000], 8);
+		// what the guest wrote, not what the cheat put back
+		assert_eq!(ptr[0x1002], 9);
+		let mut log = [AuditEntry::default(); 4];
+		assert_eq!(g.read_audit_log(&mut log[..]), 2);
+		assert_eq!((log[0].addr as usize, log[0].width, log[0].old, log[0].new), (addr.start + 0x1002, 2, 0x0403, 0x7777));
+		assert_eq!((log[1].width, log[1].old, log[1].new), (4, 0x77090201, 0x77090208));
+		assert_ne!(log[0].rip, 0);
+		assert_eq!(g.read_audit_log(&mut log[..]), 0);
+
+		g.set_audit_capacity(1);
+		std::ptr::write_volatile(&mut ptr[0x1003], 1);
+		std::ptr::write_volatile(&mut ptr[0x1003], 2);
+		assert_eq!(g.read_audit_log(&mut log[..]), 1);
+		assert_eq!((log[0].old, log[0].new), (1, 2));
+		assert_eq!(g.take_audit_dropped(), 1);
+		assert_eq!(g.take_audit_dropped(), 0);
+
+		g.remove_audit(a)?;
+		assert!(g.remove_audit(a).is_err());
+		std::ptr::write_volatile(&mut ptr[0x1003], 3);
+		assert_eq!(g.read_audit_log(&mut log[..]), 0);
+		Ok(())
+	}
+}
+
+#[test]
+fn test_counters() -> TestResult {
+	unsafe {
+		let addr = AddressRange { start: 0x3a100000000, size: 0x10000 };
+		let mut b = MemoryBlock::new(addr);
+		let mut g = b.enter();
+		g.mmap_fixed(addr, Protection::RW, true)?;
+		// pages that were zero when sealed don't need copies made of them
+		for i in 0..16 {
+			std::ptr::write_volatile((addr.start + (i << PAGESHIFT)) as *mut u8, 2);
+		}
+		g.seal();
+		// other tests run at the same time, so these can only go up by at least so much
+		let before = crate::counters::read();
+		for i in 0..16 {
+			std::ptr::write_volatile((addr.start + (i << PAGESHIFT)) as *mut u8, 1);
+		}
+		g.mprotect(addr, Protection::R)?;
+		let mut state = Vec::new();
+		g.save_state(&mut state)?;
+		let after = crate::counters::read();
+		assert!(after.faults >= before.faults + 16);
+		assert!(after.dirty_faults >= before.dirty_faults + 16);
+		assert!(after.protect_calls > before.protect_calls);
+		assert!(after.snapshot_pages >= before.snapshot_pages + 16);
+		Ok(())
+	}
+}
+
+#[test]
+fn test_immutable() -> TestResult {
+	unsafe {
+		let addr = AddressRange { start: 0x3a400000000, size: 0x4000 };
+		let rom = AddressRange { start: 0x3a400001000, size: 0x2000 };
+		let mut b = MemoryBlock::new(addr);
+		let mut g = b.enter();
+		let ptr = g.b.addr.slice_mut();
+		g.mmap_fixed(addr, Protection::RW, true)?;
+		ptr[0x1000] = 7;
+		assert_eq!(g.mark_immutable(rom), Err(EINVAL));
+		g.mprotect(rom, Protection::R)?;
+		g.mark_immutable(rom)?;
+		g.seal();
+
+		// nothing can change it from then on
+		assert_eq!(g.mprotect(rom, Protection::RW), Err(EPERM));
+		assert_eq!(g.munmap(addr), Err(EPERM));
+		assert_eq!(g.madvise(rom, MADV_DONTNEED), Err(EPERM));
+		assert_eq!(g.mmap_fixed(rom, Protection::RW, false), Err(EPERM));
+		assert_eq!(g.mark_transient(rom, true), Err(EPERM));
+		assert_eq!(g.write(rom.start, &[1]), 0);
+
+		ptr[0x0000] = 1;
+		let mut state = Vec::new();
+		g.save_state(&mut state)?;
+		assert_eq!(state.len(), g.state_header_size() + PAGESIZE);
+		ptr[0x0000] = 2;
+		g.load_state(&mut state.as_slice())?;
+		assert_eq!((ptr[0x0000], ptr[0x1000]), (1, 7));
+		assert!(g.compare_states(&mut state.as_slice(), &mut state.as_slice())?.is_empty());
+
+		// a state with the page in it was made against different memory
+		let pages = addr.size >> PAGESHIFT;
+		state[g.state_header_size() - pages + 1] = 1;
+		let err = g.load_state(&mut state.as_slice()).unwrap_err();
+		assert_eq!(ErrorCode::of(&err), ErrorCode::StateMismatch);
+		Ok(())
+	}
+}
+
+#[test]
+fn test_integrity_check() -> TestResult {
+	use integrity::{INTEGRITY_PAGE, INTEGRITY_TABLE};
+	extern fn record(userdata: usize, kind: u32, addr: usize) {
+		unsafe { &*(userdata as *const Mutex<Vec<(u32, usize)>>) }.lock().unwrap().push((kind, addr));
+	}
+	let wait_for_pass = |g: &ActivatedMemoryBlock| {
+		let passes = g.integrity_info().unwrap().passes;
+		for _ in 0..1000 {
+			if g.integrity_info().unwrap().passes > passes + 1 {
+				return
+			}
+			std::thread::sleep(std::time::Duration::from_millis(10));
+		}
+		panic!("The integrity checker never finished a pass");
+	};
+	unsafe {
+		let addr = AddressRange { start: 0x3a500000000, size: 0x10000 };
+		let ro = AddressRange { start: 0x3a500001000, size: 0x2000 };
+		let mut b = MemoryBlock::new(addr);
+		let mut g = b.enter();
+		let ptr = g.b.addr.slice_mut();
+		g.mmap_fixed(AddressRange { start: addr.start + 0x1000, size: 0x4000 }, Protection::RW, true)?;
+		ptr[0x1000] = 1;
+		ptr[0x2000] = 2;
+		g.mprotect(ro, Protection::R)?;
+		g.seal();
+		let found = Mutex::new(Vec::new());
+		assert!(g.start_integrity_check(0, record, 0).is_err());
+		g.start_integrity_check(10000, record, &found as *const _ as usize)?;
+		assert_eq!(g.integrity_info().unwrap().pages, 2);
+		wait_for_pass(&g);
+
+		// changes the guest can make don't count
+		g.mprotect(ro, Protection::RW)?;
+		ptr[0x1000] = 3;
+		g.mprotect(ro, Protection::R)?;
+		g.madvise(AddressRange { start: ro.start + 0x1000, size: 0x1000 }, MADV_DONTNEED)?;
+		wait_for_pass(&g);
+		assert!(found.lock().unwrap().is_empty());
+
+		// but a page that changes by itself does
+		let page = AddressRange { start: ro.start, size: PAGESIZE };
+		assert!(g.b.host_protect(page, Protection::RW));
+		ptr[0x1001] = 4;
+		g.b.refresh_protections(page);
+		wait_for_pass(&g);
+		assert_eq!(*found.lock().unwrap(), [(INTEGRITY_PAGE, ro.start)]);
+
+		// and so does bookkeeping that's wrong
+		let host_page = (ro.start - addr.start) / g.b.host_page;
+		g.b.applied.0[host_page].set(Some(Protection::RWX));
+		wait_for_pass(&g);
+		g.b.integrity_check_tables();
+		g.b.applied.0[host_page].set(None);
+		assert_eq!(found.lock().unwrap().last(), Some(&(INTEGRITY_TABLE, ro.start)));
+		assert_eq!(g.integrity_info().unwrap().failures, found.lock().unwrap().len() as u64);
+		g.stop_integrity_check();
+		assert!(g.integrity_info().is_none());
+		Ok(())
+	}
+}
+
+#[test]
+#[cfg(feature = "fuzz")]
+fn test_tracking_differential() -> TestResult {
+	let addr = AddressRange { start: 0x3a600000000, size: 0x40000 };
+	let mut pairs = vec![(DirtyTracking::Signal, DirtyTracking::Eager)];
+	if DirtyTracking::Userfaultfd.available() {
+		pairs.push((DirtyTracking::Signal, DirtyTracking::Userfaultfd));
+		pairs.push((DirtyTracking::Userfaultfd, DirtyTracking::Eager));
+	}
+	for &(a, b) in pairs.iter() {
+		for seed in 0..4 {
+			differential::fuzz_tracking(a, b, addr, seed, 1000)?;
+		}
+	}
+	Ok(())
+}
+
+/// Whether Linux has any part of `addr` mapped executable
+#[cfg(target_os = "linux")]
+fn any_executable(addr: AddressRange) -> anyhow::Result<bool> {
+	let maps = std::fs::read_to_string("/proc/self/maps")?;
+	for line in maps.lines() {
+		let mut fields = line.split_whitespace();
+		let (range, perms) = (fields.next().unwrap(), fields.next().unwrap());
+		let mut ends = range.split('-').map(|v| usize::from_str_radix(v, 16).unwrap());
+		let (start, end) = (ends.next().unwrap(), ends.next().unwrap());
+		if start < addr.end() && end > addr.start && perms.contains('x') {
+			return Ok(true)
+		}
+	}
+	Ok(false)
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_no_exec() -> TestResult {
+	unsafe {
+		let addr = AddressRange { start: 0x3a800000000, size: 0x8000 };
+		let code = AddressRange { start: addr.start, size: 0x4000 };
+		let rwx = AddressRange { start: addr.start + 0x4000, size: 0x4000 };
+		let mut b = MemoryBlock::new(addr);
+		b.set_no_exec(true);
+		let mut g = b.enter();
+		let ptr = addr.slice_mut();
+		g.mmap_fixed(code, Protection::RW, true)?;
+		ptr[0] = 1;
+		g.mprotect(code, Protection::RX)?;
+		g.mmap_fixed(rwx, Protection::RWX, true)?;
+		ptr[0x4000] = 2;
+		assert!(!any_executable(addr)?);
+		// executable pages can still be read, and written to if they're meant to be
+		assert_eq!(ptr[0], 1);
+		assert!(g.b.pages[0].status.executable());
+		g.seal();
+		ptr[0x5000] = 3;
+		assert!(!any_executable(addr)?);
+
+		let pad = (PAGESIZE - g.state_header_size() % PAGESIZE) % PAGESIZE;
+		let mut state = vec![0u8; pad];
+		g.save_state(&mut state)?;
+		ptr[0x5000] = 4;
+		g.load_state(&mut &state[pad..])?;
+		assert_eq!(ptr[0x5000], 3);
+		assert!(!any_executable(addr)?);
+		let path = std::env::temp_dir().join(format!("wbx_test_no_exec_{}", std::process::id()));
+		std::fs::write(&path, &state[..])?;
+		let file = std::fs::File::open(&path)?;
+		std::fs::remove_file(&path)?;
+		g.load_state_file(&file, pad as u64)?;
+		assert!(!any_executable(addr)?);
+		// swapping back in maps the block again, and replaces what was mapped from the file
+		drop(g);
+		let g = b.enter();
+		assert!(!any_executable(addr)?);
+		assert_eq!((ptr[0], ptr[0x4000], ptr[0x5000]), (1, 2, 3));
+		drop(g);
+		Ok(())
+	}
+}
@@ -1,6 +1,8 @@
 
 use super::MemoryBlock;
 use std::sync::Mutex;
+use std::cell::Cell;
+use std::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
 use crate::*;
 use super::*;
 use lazy_static::lazy_static;
@@ -17,13 +19,31 @@ struct GlobalData {
 	active_blocks: Vec<MemoryBlockRef>, 
 }
 
+lazy_static! {
+	/// The active block in each 4GiB of address space, by `addr >> 32`.  Only one block in each can be active, and none
+	/// cross from one to the next, so this is all a fault needs to find its block, without taking any lock.
+	static ref REGIONS: Vec<AtomicPtr<MemoryBlock>> = (0..1 << 16).map(|_| AtomicPtr::new(std::ptr::null_mut())).collect();
+}
+
+/// The active block containing `addr`
+pub(super) unsafe fn find_block(addr: usize) -> Option<&'static mut MemoryBlock> {
+	let block = REGIONS.get(addr >> 32)?.load(Ordering::Acquire);
+	if !block.is_null() && (*block).addr.contains(addr) {
+		Some(&mut *block)
+	} else {
+		None
+	}
+}
+
 pub unsafe fn register(block: *mut MemoryBlock) {
 	let mut data = GLOBAL_DATA.lock().unwrap();
-	if !data.initialized {
+	// fault handlers are what sanitizers can't live with
+	if !data.initialized && !(*block).sanitizer {
 		trip_pal::initialize();
 		data.initialized = true;
 	}
 	data.active_blocks.push(MemoryBlockRef(block));
+	REGIONS[(*block).addr.start >> 32].store(block, Ordering::Release);
 }
 
 pub unsafe fn unregister(block: *mut MemoryBlock) {
@@ -32,6 +52,7 @@ pub unsafe fn unregister(block: *mut MemoryBlock) {
 	match pos {
 		Some(index) => {
 			data.active_blocks.remove(index);
+			REGIONS[(*block).addr.start >> 32].store(std::ptr::null_mut(), Ordering::Release);
 		},
 		None => {
 			panic!("Tried to unregister MemoryBlock which was not registered")
@@ -39,28 +60,90 @@ pub unsafe fn unregister(block: *mut MemoryBlock) {
 	}
 }
 
+/// Set up the userfaultfd handler thread if it isn't already running.  Returns false if userfaultfd is unavailable.
+pub fn initialize_uffd() -> bool {
+	fn handler(addr: usize) {
+		FAULTS.fetch_add(1, Ordering::Relaxed);
+		unsafe {
+			if let TripResult::NotHandled = trip(addr) {
+				// nobody else is going to wake the faulting thread
+				log!(Error, "Unexpected userfaultfd write fault at {:x}", addr);
+				std::process::abort();
+			}
+		}
+	}
+	uffd::initialize(handler)
+}
+
+#[derive(PartialEq, Eq)]
 enum TripResult {
 	Handled,
 	NotHandled,
+	/// Handled, and the faulting instruction has a debugger breakpoint on it
+	Breakpoint,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Access {
+	Read,
+	Write,
+	Execute,
+}
+
+/// Pages dirtied by guest writes to them, in every block, ever
+static DIRTY_FAULTS: AtomicU64 = AtomicU64::new(0);
+/// Faults the handlers have been called for, whatever they turned out to be
+static FAULTS: AtomicU64 = AtomicU64::new(0);
+
+/// How many faults have been taken, for profiling
+pub fn fault_count() -> u64 {
+	FAULTS.load(Ordering::Relaxed)
+}
+
+/// How many times a guest write has dirtied a page, for profiling
+pub fn dirty_fault_count() -> u64 {
+	DIRTY_FAULTS.load(Ordering::Relaxed)
 }
 
 unsafe fn trip(addr: usize) -> TripResult {
-	let data = GLOBAL_DATA.lock().unwrap();
-	let memory_block = match data.active_blocks
-		.iter()
-		.find(|x| (*x.0).addr.contains(addr)) {
-			Some(x) => &mut *x.0,
-			None => return TripResult::NotHandled,
-		};
-	let page_start_addr = addr & !PAGEMASK;
-	let page = &mut memory_block.pages[(addr - memory_block.addr.start) >> PAGESHIFT];
-	if !page.status.writable() {
+	let memory_block = match find_block(addr) {
+		Some(b) => b,
+		None => return TripResult::NotHandled,
+	};
+	let faulting = (addr - memory_block.addr.start) >> PAGESHIFT;
+	if !memory_block.pages[faulting].status.writable() {
 		std::intrinsics::breakpoint();
 		return TripResult::NotHandled
 	}
-	page.maybe_snapshot(page_start_addr);
-	page.dirty = true;
-	if pal::protect(AddressRange { start: page_start_addr, size: PAGESIZE }, page.native_prot()) {
+	// a host page can hold more than one guest page, and they all become writable together
+	let host_addr = memory_block.host_expand(AddressRange { start: addr, size: 1 });
+	memory_block.unmap_state_file(host_addr);
+	let first = (host_addr.start - memory_block.addr.start) >> PAGESHIFT;
+	let last = std::cmp::min(first + (host_addr.size >> PAGESHIFT), memory_block.pages.len());
+	for index in first..last {
+		let page_start_addr = memory_block.addr.start + (index << PAGESHIFT);
+		let page = &mut memory_block.pages[index];
+		if index != faulting && !(page.status.writable() && page.needs_trip()) {
+			continue
+		}
+		page.fault_snapshot(page_start_addr);
+		page.dirty = true;
+		memory_block.dirty_index.set(index);
+		DIRTY_FAULTS.fetch_add(1, Ordering::Relaxed);
+		if page.cow_pending {
+			cow::preserve(&memory_block.cow, index, page_start_addr, PageBlock::fault_copy);
+			page.cow_pending = false;
+		}
+	}
+	let ok = match memory_block.tracking {
+		DirtyTracking::Signal | DirtyTracking::Eager => {
+			let prot = host_native_state(&memory_block.pages[first..last], memory_block.tracking).0;
+			memory_block.protect_native(host_addr, |_| prot)
+		},
+		// this also wakes the faulting thread
+		DirtyTracking::Userfaultfd => uffd::writeprotect(host_addr, false),
+	};
+	if ok {
 		TripResult::Handled
 	} else {
 		std::intrinsics::breakpoint();
@@ -68,6 +151,299 @@ unsafe fn trip(addr: usize) -> TripResult {
 	}
 }
 
+/// trip(), for the common case of a first write to a page that nothing but dirty tracking is waiting on, in a block
+/// whose host pages are its pages.  That's one page to look at, a snapshot from the reserve, and one protection change,
+/// and none of it touches host TLS, so it can run before the host's thread pointer is put back.  Returns false if
+/// there's more to it than that, for the rest of the handler to deal with.
+unsafe fn fast_trip(addr: usize) -> bool {
+	let memory_block = match find_block(addr) {
+		Some(b) if b.host_page == PAGESIZE && b.tracking != DirtyTracking::Userfaultfd => b,
+		_ => return false,
+	};
+	let index = (addr - memory_block.addr.start) >> PAGESHIFT;
+	let page_start_addr = addr & !PAGEMASK;
+	let page = &mut memory_block.pages[index];
+	if !page.plain_trip() || !page.reserved_snapshot(page_start_addr) {
+		return false
+	}
+	page.dirty = true;
+	memory_block.dirty_index.set(index);
+	DIRTY_FAULTS.fetch_add(1, Ordering::Relaxed);
+	let prot = page.native_state(memory_block.tracking).0;
+	if !memory_block.protect_native(AddressRange { start: page_start_addr, size: PAGESIZE }, |_| prot) {
+		std::process::abort();
+	}
+	true
+}
+
+/// Count an access to a page that's armed for the heat map, and let it through
+unsafe fn heat_trip(addr: usize, access: Access) -> TripResult {
+	let memory_block = match find_block(addr) {
+		Some(b) => b,
+		None => return TripResult::NotHandled,
+	};
+	let page = &memory_block.pages[(addr - memory_block.addr.start) >> PAGESHIFT];
+	let allowed = match access {
+		Access::Read => page.status.readable(),
+		Access::Write => page.status.writable(),
+		Access::Execute => page.status.executable(),
+	};
+	if allowed && memory_block.heat_trip(addr, access == Access::Write) {
+		TripResult::Handled
+	} else {
+		TripResult::NotHandled
+	}
+}
+
+/// Count a write to a page that dirty markers are waiting for, and let it through
+unsafe fn marker_trip(addr: usize) -> TripResult {
+	let memory_block = match find_block(addr) {
+		Some(b) => b,
+		None => return TripResult::NotHandled,
+	};
+	let index = (addr - memory_block.addr.start) >> PAGESHIFT;
+	if memory_block.pages[index].status.writable() && memory_block.marker_trip(addr) {
+		TripResult::Handled
+	} else {
+		TripResult::NotHandled
+	}
+}
+
+/// x86 trap flag, for single stepping
+const TRAP_FLAG: u64 = 0x100;
+
+/// Pages that were unwatched to let the current thread's faulting instruction through.  One instruction can
+/// touch a few different pages.
+#[thread_local]
+static STEP_PAGES: [Cell<usize>; 4] = [Cell::new(0), Cell::new(0), Cell::new(0), Cell::new(0)];
+
+/// Handle an access to a page that has watchpoints on it.  If Handled or Breakpoint, the page has been unwatched, and
+/// the caller must single step the faulting instruction, and call end_watch_step() once it has executed.
+unsafe fn watch_trip(addr: usize, access: Access, rip: usize) -> TripResult {
+	let memory_block = match find_block(addr) {
+		Some(b) => b,
+		None => return TripResult::NotHandled,
+	};
+	let index = (addr - memory_block.addr.start) >> PAGESHIFT;
+	let page_start_addr = addr & !PAGEMASK;
+	// single stepping changes the page's protections behind protect_native()'s back
+	memory_block.forget_protections(AddressRange { start: page_start_addr, size: PAGESIZE });
+	let page = &mut memory_block.pages[index];
+	let allowed = match access {
+		Access::Read => page.status.readable(),
+		Access::Write => page.status.writable(),
+		Access::Execute => page.status.executable(),
+	};
+	if page.watch == 0 || !allowed {
+		return TripResult::NotHandled
+	}
+	let slot = match STEP_PAGES.iter().find(|p| p.get() == 0) {
+		Some(p) => p,
+		None => return TripResult::NotHandled,
+	};
+	let page_addr = AddressRange { start: page_start_addr, size: PAGESIZE };
+	if access == Access::Write {
+		// also take care of what trip() would have
+		assert!(pal::protect(page_addr, Protection::R));
+		page.fault_snapshot(page_start_addr);
+		page.dirty = true;
+		memory_block.dirty_index.set(index);
+		if page.cow_pending {
+			cow::preserve(&memory_block.cow, index, page_start_addr, PageBlock::fault_copy);
+			page.cow_pending = false;
+		}
+		if memory_block.tracking == DirtyTracking::Userfaultfd {
+			assert!(uffd::writeprotect(page_addr, false));
+		}
+	}
+	let prot = match memory_block.tracking {
+		DirtyTracking::Signal | DirtyTracking::Eager => page.unwatched_prot(),
+		DirtyTracking::Userfaultfd => match page.status {
+			PageAllocation::Allocated(Protection::RWStack) => Protection::RW,
+			PageAllocation::Allocated(Protection::StackGuard) => Protection::None,
+			PageAllocation::Allocated(x) => x,
+			PageAllocation::Free => Protection::None,
+		},
+	};
+	let prot = if memory_block.no_exec { prot.without_exec() } else { prot };
+	assert!(pal::protect(page_addr, prot));
+	slot.set(page_start_addr);
+	if access == Access::Write {
+		memory_block.begin_audit(addr, rip);
+	}
+	if access == Access::Execute {
+		if memory_block.has_breakpoint(rip) {
+			return TripResult::Breakpoint
+		}
+	} else {
+		// a callback that calls back into the guest needs that call's faults caught
+		trip_pal::unblock_faults();
+		memory_block.report_watch(addr, access == Access::Write, rip);
+	}
+	TripResult::Handled
+}
+
+/// Put back the watch protections that were lifted for a single step.  Returns false if this thread wasn't single
+/// stepping for a watchpoint.
+unsafe fn end_watch_step() -> bool {
+	if STEP_PAGES[0].get() == 0 {
+		return false
+	}
+	// what the guest wrote, before any cheat puts its value back
+	audit::end_audits();
+	for slot in STEP_PAGES.iter() {
+		let page_start_addr = slot.replace(0);
+		if page_start_addr == 0 {
+			break
+		}
+		if let Some(memory_block) = find_block(page_start_addr) {
+			memory_block.reapply_cheats(AddressRange { start: page_start_addr, size: PAGESIZE });
+			memory_block.refresh_protections(AddressRange { start: page_start_addr, size: PAGESIZE });
+		}
+	}
+	true
+}
+
+/// If a fault at `addr` with the stack pointer at `rsp` is a stack overflowing, how much of that stack is in use.  That's
+/// a fault in the guard page below a guest stack, or right by the stack pointer outside of guest memory for guest code
+/// running on the host's own stack, whose guard page the system put there.
+pub fn stack_depth(addr: usize, rsp: usize) -> Option<usize> {
+	let data = GLOBAL_DATA.lock().unwrap();
+	if let Some(x) = data.active_blocks.iter().find(|x| unsafe { (*x.0).addr.contains(addr) }) {
+		let memory_block = unsafe { &*x.0 };
+		let mut index = (addr - memory_block.addr.start) >> PAGESHIFT;
+		if memory_block.pages[index].status != PageAllocation::Allocated(Protection::StackGuard) {
+			return None
+		}
+		index += 1;
+		while index < memory_block.pages.len() && memory_block.pages[index].status == PageAllocation::Allocated(Protection::RWStack) {
+			index += 1;
+		}
+		let top = memory_block.addr.start + (index << PAGESHIFT);
+		return Some(top.saturating_sub(rsp))
+	}
+	match watchdog::entry_stack() {
+		Some(top) if rsp < top && addr < rsp + PAGESIZE && addr >= rsp.saturating_sub(PAGESIZE) => Some(top - rsp),
+		_ => None,
+	}
+}
+
+/// A fault that nothing here can handle is about to go elsewhere; let the host it came from report it
+fn report_crash(addr: usize, access: Access, signal: i32, regs: coredump::UserRegs) {
+	host::report_crash(&crash::Fault {
+		signal,
+		stack_depth: stack_depth(addr, regs.rsp as usize),
+		regs,
+		addr,
+		access: match access {
+			Access::Read => "read",
+			Access::Write => "write",
+			Access::Execute => "execute",
+		}
+	});
+}
+
+/// The parts of addr that are in active MemoryBlocks and readable, with their protections, for dumping guest memory
+pub fn debug_regions(addr: AddressRange) -> Vec<(AddressRange, Protection)> {
+	let data = GLOBAL_DATA.lock().unwrap();
+	let mut res: Vec<(AddressRange, Protection)> = Vec::new();
+	for x in data.active_blocks.iter() {
+		let memory_block = unsafe { &*x.0 };
+		for (index, page) in memory_block.pages.iter().enumerate() {
+			let start = memory_block.addr.start + (index << PAGESHIFT);
+			let prot = match page.status {
+				PageAllocation::Allocated(prot) if addr.contains(start) && page.host_readable() => prot,
+				_ => continue,
+			};
+			match res.last_mut() {
+				Some((r, p)) if r.end() == start && *p == prot => r.size += PAGESIZE,
+				_ => res.push((AddressRange { start, size: PAGESIZE }, prot)),
+			}
+		}
+	}
+	res
+}
+
+/// Add or remove a debugger breakpoint in whichever active MemoryBlock contains addr.  Returns false if there is no such
+/// block, or nothing to remove.
+pub fn set_breakpoint(addr: usize, enable: bool) -> bool {
+	let data = GLOBAL_DATA.lock().unwrap();
+	match data.active_blocks.iter().find(|x| unsafe { (*x.0).addr.contains(addr) }) {
+		Some(x) => unsafe { (*x.0).set_breakpoint(addr, enable) },
+		None => false,
+	}
+}
+
+/// Remove all debugger breakpoints from all active MemoryBlocks
+pub fn clear_breakpoints() {
+	let data = GLOBAL_DATA.lock().unwrap();
+	for x in data.active_blocks.iter() {
+		let memory_block = unsafe { &mut *x.0 };
+		for addr in std::mem::take(&mut memory_block.breakpoints) {
+			memory_block.update_watch(AddressRange { start: addr, size: 1 });
+		}
+	}
+}
+
+/// Find the active MemoryBlock containing all of addr, and check that every page in it passes `check`
+unsafe fn debug_block(data: &mut GlobalData, addr: AddressRange, check: fn(&Page) -> bool) -> Option<&mut MemoryBlock> {
+	let memory_block = &mut *data.active_blocks.iter_mut()
+		.find(|x| (*x.0).addr.contains(addr.start) && addr.end() <= (*x.0).addr.end())?.0;
+	let pstart = (addr.start - memory_block.addr.start) >> PAGESHIFT;
+	let pend = (addr.align_expand().end() - memory_block.addr.start) >> PAGESHIFT;
+	if memory_block.pages[pstart..pend].iter().all(check) {
+		Some(memory_block)
+	} else {
+		None
+	}
+}
+
+/// Read guest memory for a debugger.  Fails if any of it isn't readable by the guest.
+/// unsafe: the guest must be stopped
+pub unsafe fn debug_read(addr: usize, dest: &mut [u8]) -> bool {
+	let mut data = GLOBAL_DATA.lock().unwrap();
+	let range = AddressRange { start: addr, size: dest.len() };
+	if dest.is_empty() || debug_block(&mut data, range, Page::host_readable).is_none() {
+		return dest.is_empty()
+	}
+	dest.copy_from_slice(range.slice());
+	true
+}
+
+/// Write guest memory for a debugger.  Fails if any of it isn't writable by the guest.
+/// unsafe: the guest must be stopped
+pub unsafe fn debug_write(addr: usize, src: &[u8]) -> bool {
+	let mut data = GLOBAL_DATA.lock().unwrap();
+	let range = AddressRange { start: addr, size: src.len() };
+	if src.is_empty() {
+		return true
+	}
+	let memory_block = match debug_block(&mut data, range, |p| p.status.writable()) {
+		Some(b) => b,
+		None => return false,
+	};
+	let expanded = range.align_expand();
+	let pstart = (expanded.start - memory_block.addr.start) >> PAGESHIFT;
+	let pend = (expanded.end() - memory_block.addr.start) >> PAGESHIFT;
+	memory_block.unmap_state_file(expanded);
+	assert!(memory_block.host_protect(expanded, Protection::RW));
+	for index in pstart..pend {
+		// what trip() would have done, had the guest made the write
+		let page_start_addr = memory_block.addr.start + (index << PAGESHIFT);
+		let page = &mut memory_block.pages[index];
+		page.maybe_snapshot(page_start_addr);
+		page.dirty = true;
+		memory_block.dirty_index.set(index);
+		if page.cow_pending {
+			cow::preserve(&memory_block.cow, index, page_start_addr, PageBlock::try_copy);
+			page.cow_pending = false;
+		}
+	}
+	range.slice_mut().copy_from_slice(src);
+	memory_block.refresh_protections(expanded);
+	true
+}
+
 #[cfg(windows)]
 mod trip_pal {
 	use super::*;
@@ -75,12 +451,58 @@ mod trip_pal {
 	use winapi::um::winnt::*;
 	use winapi::vc::excpt::*;
 
+	fn user_regs(c: &CONTEXT) -> coredump::UserRegs {
+		coredump::UserRegs {
+			r15: c.R15, r14: c.R14, r13: c.R13, r12: c.R12, rbp: c.Rbp, rbx: c.Rbx, r11: c.R11, r10: c.R10,
+			r9: c.R9, r8: c.R8, rax: c.Rax, rcx: c.Rcx, rdx: c.Rdx, rsi: c.Rsi, rdi: c.Rdi, orig_rax: !0,
+			rip: c.Rip, cs: c.SegCs as u64, eflags: c.EFlags as u64, rsp: c.Rsp, ss: c.SegSs as u64,
+			fs_base: 0, gs_base: 0, ds: c.SegDs as u64, es: c.SegEs as u64, fs: c.SegFs as u64, gs: c.SegGs as u64,
+		}
+	}
+
+	/// Nothing is blocked while an exception is handled
+	pub fn unblock_faults() {}
+
 	pub fn initialize() {
 		unsafe extern "system" fn handler(p_info: *mut EXCEPTION_POINTERS) -> i32 {
 			let p_record = &*(*p_info).ExceptionRecord;
+			let p_context = &mut *(*p_info).ContextRecord;
 			let flags = p_record.ExceptionInformation[0];
 			match p_record.ExceptionCode {
-				STATUS_ACCESS_VIOLATION if (flags & 1) != 0 => (), // write exception
+				STATUS_ACCESS_VIOLATION if flags == 0 || flags == 1 || flags == 8 => {
+					// read, write, or execute exception
+					FAULTS.fetch_add(1, Ordering::Relaxed);
+					let access = match flags {
+						0 => Access::Read,
+						1 => Access::Write,
+						_ => Access::Execute,
+					};
+					let fault_address = p_record.ExceptionInformation[1] as usize;
+					if access == Access::Write && fast_trip(fault_address) {
+						return EXCEPTION_CONTINUE_EXECUTION
+					}
+					if heat_trip(fault_address, access) == TripResult::Handled
+						|| access == Access::Write && marker_trip(fault_address) == TripResult::Handled {
+						return EXCEPTION_CONTINUE_EXECUTION
+					}
+					if let TripResult::Handled | TripResult::Breakpoint = watch_trip(fault_address, access, p_context.Rip as usize) {
+						// there's no debugger stub on windows, so breakpoints do nothing
+						p_context.EFlags |= TRAP_FLAG as u32;
+						return EXCEPTION_CONTINUE_EXECUTION
+					}
+					if access != Access::Write {
+						report_crash(fault_address, access, 11, user_regs(p_context));
+						return EXCEPTION_CONTINUE_SEARCH
+					}
+				},
+				STATUS_SINGLE_STEP => {
+					return if end_watch_step() {
+						p_context.EFlags &= !(TRAP_FLAG as u32);
+						EXCEPTION_CONTINUE_EXECUTION
+					} else {
+						EXCEPTION_CONTINUE_SEARCH
+					}
+				},
 				STATUS_GUARD_PAGE_VIOLATION => {
 					// guard exception
 
@@ -101,8 +523,11 @@ mod trip_pal {
 			}
 			let fault_address = p_record.ExceptionInformation[1] as usize;
 			match trip(fault_address) {
-				TripResult::Handled => EXCEPTION_CONTINUE_EXECUTION,
-				TripResult::NotHandled => EXCEPTION_CONTINUE_SEARCH,
+				TripResult::NotHandled => {
+					report_crash(fault_address, Access::Write, 11, user_regs(p_context));
+					EXCEPTION_CONTINUE_SEARCH
+				},
+				_ => EXCEPTION_CONTINUE_EXECUTION,
 			}
 		}
 		unsafe {
@@ -116,31 +541,146 @@ mod trip_pal {
 mod trip_pal {
 	use libc::*;
 	use super::*;
+	use signal_context::{FAULT_EXECUTE, FAULT_WRITE};
 
 	type SaHandler = unsafe extern fn(i32) -> ();
-	type SaSigaction = unsafe extern fn(i32, *const siginfo_t, *const ucontext_t) -> ();
+	type SaSigaction = unsafe extern fn(i32, *const siginfo_t, *mut c_void) -> ();
 	static mut SA_OLD: Option<Box<sigaction>> = None;
+	/// macOS reports some protection faults as SIGBUS instead
+	static mut SA_OLD_BUS: Option<Box<sigaction>> = None;
+	static mut SA_OLD_TRAP: Option<Box<sigaction>> = None;
+	static mut SA_OLD_ILL: Option<Box<sigaction>> = None;
 
-	pub fn initialize() {
-		use std::mem::{transmute, zeroed};
+	/// unsafe: `old` must have come from sigaction().  Returns only if the old disposition was to ignore the signal.
+	unsafe fn chain(old: &sigaction, sig: i32, info: *const siginfo_t, ucontext: *mut c_void) {
+		use std::mem::transmute;
+		if old.sa_flags & SA_SIGINFO != 0 {
+			transmute::<usize, SaSigaction>(old.sa_sigaction)(sig, info, ucontext);
+		} else if old.sa_sigaction != SIG_DFL && old.sa_sigaction != SIG_IGN {
+			transmute::<usize, SaHandler>(old.sa_sigaction)(sig);
+		}
+	}
+
+	/// unsafe: overwrites `old`
+	unsafe fn install(sig: i32, handler: SaSigaction, old: &mut Option<Box<sigaction>>) {
+		use std::mem::zeroed;
+		*old = Some(Box::new(zeroed()));
+		// built up from zero, since the fields past these differ between platforms
+		let mut sa: sigaction = zeroed();
+		sa.sa_sigaction = handler as usize;
+		sa.sa_flags = SA_ONSTACK | SA_SIGINFO;
+		sigfillset(&mut sa.sa_mask);
+		assert!(sigaction(sig, &sa, &mut **old.as_mut().unwrap() as *mut sigaction) == 0, "sigaction failed");
+	}
 
-		unsafe extern fn handler(sig: i32, info: *const siginfo_t, ucontext: *const ucontext_t) {
-			let fault_address = (*info).si_addr() as usize;
-			let write = (*ucontext).uc_mcontext.gregs[REG_ERR as usize] & 2 != 0;
+	/// Let faults through again while still in the handler for one, which blocked all signals.  Returning from the
+	/// handler puts back what was blocked before.
+	pub fn unblock_faults() {
+		unsafe {
+			let mut set = std::mem::zeroed::<sigset_t>();
+			sigemptyset(&mut set);
+			// and the watchdog's, so that call can time out
+			for &sig in [SIGSEGV, SIGBUS, SIGTRAP, SIGILL, SIGVTALRM].iter() {
+				sigaddset(&mut set, sig);
+			}
+			pthread_sigmask(SIG_UNBLOCK, &set, std::ptr::null_mut());
+		}
+	}
+
+	pub fn initialize() {
+		unsafe extern fn handler(sig: i32, info: *const siginfo_t, ucontext: *mut c_void) {
+			FAULTS.fetch_add(1, Ordering::Relaxed);
+			let fault_address = signal_context::fault_address(info);
+			let err = signal_context::fault_error(ucontext);
+			let write = err & FAULT_WRITE != 0;
+			if write && fast_trip(fault_address) {
+				return
+			}
+			let _fs = threading::HostFs::enter();
+			let access = if err & FAULT_EXECUTE != 0 {
+				Access::Execute
+			} else if write {
+				Access::Write
+			} else {
+				Access::Read
+			};
+			if heat_trip(fault_address, access) == TripResult::Handled {
+				return
+			}
+			if write && marker_trip(fault_address) == TripResult::Handled {
+				return
+			}
+			// only x86_64 can single step the access through, so nothing else has watches to trip
+			#[cfg(target_arch = "x86_64")]
+			match watch_trip(fault_address, access, *signal_context::pc(ucontext) as usize) {
+				TripResult::NotHandled => (),
+				res => {
+					*signal_context::reg(ucontext, signal_context::Reg::Rflags) |= TRAP_FLAG;
+					if res == TripResult::Breakpoint && gdbstub::attached() {
+						gdbstub::stop(ucontext);
+					}
+					return
+				}
+			}
 			let rethrow = !write || match trip(fault_address) {
 				TripResult::NotHandled => true,
 				_ => false
 			};
 			if rethrow {
-				let sa_old = SA_OLD.as_ref().unwrap();
-				if sa_old.sa_flags & SA_SIGINFO != 0 {
-					transmute::<usize, SaSigaction>(sa_old.sa_sigaction)(sig, info, ucontext);
-				} else {
-					transmute::<usize, SaHandler>(sa_old.sa_sigaction)(sig);
-				}
+				report_crash(fault_address, access, sig, signal_context::user_regs(ucontext));
+				let sa_old = if sig == SIGBUS { SA_OLD_BUS.as_ref() } else { SA_OLD.as_ref() };
+				chain(sa_old.unwrap(), sig, info, ucontext);
 				abort();
 			}
 		}
+		#[cfg(target_arch = "x86_64")]
+		unsafe extern fn trap_handler(_sig: i32, info: *const siginfo_t, ucontext: *mut c_void) {
+			use signal_context::{Reg, reg};
+			let fs = threading::HostFs::enter();
+			let watch_step = end_watch_step();
+			if gdbstub::attached() && (!watch_step || gdbstub::stepping()) {
+				// a debugger single step, a stop request, or some other breakpoint instruction
+				*reg(ucontext, Reg::Rflags) &= !TRAP_FLAG;
+				gdbstub::stop(ucontext);
+				return
+			}
+			if watch_step {
+				*reg(ucontext, Reg::Rflags) &= !TRAP_FLAG;
+				return
+			}
+			if host::forward_guest_trap(ucontext, cinterface::GUEST_TRAP_BREAKPOINT) {
+				return
+			}
+			if watchdog::abandon_from_signal(ucontext, watchdog::Abandoned::Aborted) {
+				// going back to host code, so keep the host's thread pointer in
+				std::mem::forget(fs);
+				return
+			}
+			pass_on(SIGTRAP, SA_OLD_TRAP.as_ref().unwrap(), info, ucontext);
+		}
+		/// ud2, which is what compilers make of __builtin_trap() and the like
+		unsafe extern fn ill_handler(sig: i32, info: *const siginfo_t, ucontext: *mut c_void) {
+			let fs = threading::HostFs::enter();
+			#[cfg(target_arch = "x86_64")]
+			if host::forward_guest_trap(ucontext, cinterface::GUEST_TRAP_ILLEGAL) {
+				return
+			}
+			if watchdog::abandon_from_signal(ucontext, watchdog::Abandoned::Aborted) {
+				std::mem::forget(fs);
+				return
+			}
+			pass_on(sig, SA_OLD_ILL.as_ref().unwrap(), info, ucontext);
+		}
+		/// Hand a signal on to whoever had it before
+		unsafe fn pass_on(sig: i32, sa_old: &sigaction, info: *const siginfo_t, ucontext: *mut c_void) {
+			if sa_old.sa_sigaction == SIG_DFL || sa_old.sa_sigaction == SIG_IGN {
+				// put things back the way they were, and let that happen as soon as we return
+				sigaction(sig, sa_old, std::ptr::null_mut());
+				raise(sig);
+			} else {
+				chain(sa_old, sig, info, ucontext);
+			}
+		}
 		unsafe {
 			// TODO: sigaltstack is per thread, so this won't work
 			// At the same time, one seems to be set up automatically on each thread, so this isn't needed.
@@ -155,15 +695,14 @@ mod trip_pal {
 			// 	ss_size: 0
 			// };
 			// assert!(sigaltstack(&ss, &mut ss_old) == 0, "sigaltstack failed");
-			SA_OLD = Some(Box::new(zeroed()));
-			let mut sa = sigaction {
-				sa_mask: zeroed(),
-				sa_sigaction: transmute::<SaSigaction, usize>(handler),
-				sa_flags: SA_ONSTACK | SA_SIGINFO,
-				sa_restorer: None,
-			};
-			sigfillset(&mut sa.sa_mask);
-			assert!(sigaction(SIGSEGV, &sa, &mut **SA_OLD.as_mut().unwrap() as *mut sigaction) == 0, "sigaction failed");
+			install(SIGSEGV, handler, &mut SA_OLD);
+			if cfg!(target_os = "macos") {
+				install(SIGBUS, handler, &mut SA_OLD_BUS);
+			}
+			// single steps for watchpoints
+			#[cfg(target_arch = "x86_64")]
+			install(SIGTRAP, trap_handler, &mut SA_OLD_TRAP);
+			install(SIGILL, ill_handler, &mut SA_OLD_ILL);
 		}
 	}
 }
@@ -0,0 +1,149 @@
+// userfaultfd write protection, as an alternative to catching SIGSEGV for dirty tracking.
+// libc doesn't have any of these definitions yet, so they're replicated from <linux/userfaultfd.h>
+use crate::*;
+use libc::*;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicI32, Ordering};
+use lazy_static::lazy_static;
+
+const UFFD_API: u64 = 0xaa;
+const UFFD_USER_MODE_ONLY: c_int = 1;
+
+const UFFD_FEATURE_PAGEFAULT_FLAG_WP: u64 = 1 << 0;
+const UFFD_FEATURE_WP_HUGETLBFS_SHMEM: u64 = 1 << 12;
+
+const UFFDIO_API: c_ulong = 0xc018aa3f;
+const UFFDIO_REGISTER: c_ulong = 0xc020aa00;
+const UFFDIO_WRITEPROTECT: c_ulong = 0xc018aa06;
+
+const UFFDIO_REGISTER_MODE_WP: u64 = 1 << 1;
+const UFFDIO_WRITEPROTECT_MODE_WP: u64 = 1 << 0;
+
+const UFFD_EVENT_PAGEFAULT: u8 = 0x12;
+const UFFD_PAGEFAULT_FLAG_WP: u64 = 1 << 1;
+
+#[repr(C)]
+struct UffdioApi {
+	api: u64,
+	features: u64,
+	ioctls: u64,
+}
+#[repr(C)]
+struct UffdioRange {
+	start: u64,
+	len: u64,
+}
+#[repr(C)]
+struct UffdioRegister {
+	range: UffdioRange,
+	mode: u64,
+	ioctls: u64,
+}
+#[repr(C)]
+struct UffdioWriteprotect {
+	range: UffdioRange,
+	mode: u64,
+}
+#[repr(C)]
+struct UffdMsg {
+	event: u8,
+	reserved1: u8,
+	reserved2: u16,
+	reserved3: u32,
+	flags: u64,
+	address: u64,
+	arg3: u64,
+}
+
+const REQUIRED_FEATURES: u64 = UFFD_FEATURE_PAGEFAULT_FLAG_WP | UFFD_FEATURE_WP_HUGETLBFS_SHMEM;
+
+static FD: AtomicI32 = AtomicI32::new(-1);
+lazy_static! {
+	/// Some(true) if initialized successfully, Some(false) if initialization failed
+	static ref STATUS: Mutex<Option<bool>> = Mutex::new(None);
+}
+
+unsafe fn create() -> Option<c_int> {
+	let mut fd = syscall(SYS_userfaultfd, O_CLOEXEC) as c_int;
+	if fd == -1 {
+		// unprivileged processes may only be allowed to catch faults from user mode, which is all we need anyway
+		fd = syscall(SYS_userfaultfd, O_CLOEXEC | UFFD_USER_MODE_ONLY) as c_int;
+	}
+	if fd == -1 {
+		return None
+	}
+	let mut api = UffdioApi {
+		api: UFFD_API,
+		features: REQUIRED_FEATURES,
+		ioctls: 0,
+	};
+	if ioctl(fd, UFFDIO_API, &mut api as *mut UffdioApi) != 0 {
+		libc::close(fd);
+		return None
+	}
+	Some(fd)
+}
+
+/// Set up the process wide userfaultfd, and start a thread that forwards write faults to `handler`.
+/// The handler is responsible for unprotecting the page, which also wakes the faulting thread.
+/// Returns false if userfaultfd write protection is not supported on this system.
+pub fn initialize(handler: fn(usize)) -> bool {
+	let mut status = STATUS.lock().unwrap();
+	if let Some(s) = *status {
+		return s
+	}
+	let res = match unsafe { create() } {
+		Some(fd) => {
+			FD.store(fd, Ordering::SeqCst);
+			std::thread::Builder::new()
+				.name("waterbox uffd".to_string())
+				.spawn(move || {
+					while let Ok(fault) = read_fault(fd) {
+						if let Some(addr) = fault {
+							handler(addr);
+						}
+					}
+				})
+				.is_ok()
+		},
+		None => false,
+	};
+	*status = Some(res);
+	res
+}
+
+/// Wait for the next event.  Returns the address of the fault if it was a write protect fault, or Err if the fd is unusable.
+fn read_fault(fd: c_int) -> Result<Option<usize>, ()> {
+	unsafe {
+		let mut msg = std::mem::zeroed::<UffdMsg>();
+		let n = read(fd, &mut msg as *mut UffdMsg as *mut c_void, std::mem::size_of::<UffdMsg>());
+		if n != std::mem::size_of::<UffdMsg>() as isize {
+			return if *__errno_location() == EINTR { Ok(None) } else { Err(()) }
+		}
+		if msg.event == UFFD_EVENT_PAGEFAULT && msg.flags & UFFD_PAGEFAULT_FLAG_WP != 0 {
+			Ok(Some(msg.address as usize))
+		} else {
+			Ok(None)
+		}
+	}
+}
+
+/// Start tracking write protection on a range.  The range must be mapped.
+pub unsafe fn register(addr: AddressRange) -> bool {
+	let mut reg = UffdioRegister {
+		range: UffdioRange { start: addr.start as u64, len: addr.size as u64 },
+		mode: UFFDIO_REGISTER_MODE_WP,
+		ioctls: 0,
+	};
+	ioctl(FD.load(Ordering::SeqCst), UFFDIO_REGISTER, &mut reg as *mut UffdioRegister) == 0
+}
+
+/// Set or clear write protection on a range that was previously registered.  Clearing write protection wakes any
+/// threads waiting on a fault in that range.
+pub unsafe fn writeprotect(addr: AddressRange, wp: bool) -> bool {
+	let mut wpr = UffdioWriteprotect {
+		range: UffdioRange { start: addr.start as u64, len: addr.size as u64 },
+		mode: if wp { UFFDIO_WRITEPROTECT_MODE_WP } else { 0 },
+	};
+	ioctl(FD.load(Ordering::SeqCst), UFFDIO_WRITEPROTECT, &mut wpr as *mut UffdioWriteprotect) == 0
+}
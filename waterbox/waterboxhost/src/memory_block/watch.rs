@@ -0,0 +1,148 @@
+// Memory watchpoints:  Pages with watchpoints on them have their native protection reduced, so that tripguard sees
+// every access of a watched kind.  It reports the ones that actually hit a watchpoint, then lets the access through
+// by unprotecting the page for a single step of the faulting instruction, and protects it again afterwards.
+// Debugger breakpoints work the same way, by taking execute permission away from the pages they're on.
+use super::*;
+
+pub const WATCH_READ: u8 = 1;
+pub const WATCH_WRITE: u8 = 2;
+/// Only used for debugger breakpoints
+pub const WATCH_EXEC: u8 = 4;
+/// Letting a watched access through takes a single step, which only x86_64 lets signal handlers do
+pub const WATCH_SUPPORTED: bool = cfg!(target_arch = "x86_64");
+
+/// Called when a watchpoint is hit, before the access completes.  `addr` is the address that was accessed and `rip` is
+/// the instruction doing it.  This runs inside the fault handler with waterbox locks held, so it must not call back
+/// into waterbox.
+pub type WatchCallback = extern fn(userdata: usize, id: u32, addr: usize, write: bool, rip: usize);
+
+#[derive(Debug)]
+pub struct Watchpoint {
+	id: u32,
+	addr: AddressRange,
+	kind: u8,
+	callback: WatchCallback,
+	userdata: usize,
+}
+
+/// Reduce a native protection so that all accesses of the watched kinds will fault
+pub fn watch_prot(prot: Protection, watch: u8) -> Protection {
+	let prot = if watch & WATCH_EXEC != 0 {
+		match prot {
+			Protection::RX => Protection::R,
+			Protection::RWX => Protection::RW,
+			x => x,
+		}
+	} else {
+		prot
+	};
+	if watch & WATCH_READ != 0 {
+		Protection::None
+	} else if watch & WATCH_WRITE != 0 {
+		match prot {
+			Protection::RW | Protection::RWStack => Protection::R,
+			Protection::RWX => Protection::RX,
+			x => x,
+		}
+	} else {
+		prot
+	}
+}
+
+impl MemoryBlock {
+	/// Watched pages are tracked one guest page at a time, so they also need host pages no bigger than that, and a fault
+	/// handler, which sanitizer mode doesn't have.  `what` needs watched pages, and is what the error says can't be used.
+	pub(super) fn check_watch_supported(&self, what: &str) -> anyhow::Result<()> {
+		if !WATCH_SUPPORTED {
+			Err(coded(ErrorCode::Unsupported, format!("{} aren't supported on this platform", what)))
+		} else if self.host_page != PAGESIZE {
+			Err(coded(ErrorCode::Unsupported, format!("{} need {}K host pages, but this host's are {}K",
+				what, PAGESIZE >> 10, self.host_page >> 10)))
+		} else if self.sanitizer {
+			Err(coded(ErrorCode::Unsupported, format!("{} don't work in sanitizer mode", what)))
+		} else {
+			Ok(())
+		}
+	}
+	/// Recompute the watch flags of all pages overlapping addr, and apply them
+	pub(super) fn update_watch(&mut self, addr: AddressRange) {
+		let addr = addr.align_expand();
+		// a CowSnapshot can't read pages that are about to become unreadable
+		self.resolve_cow(addr);
+		let pstart = (addr.start - self.addr.start) >> PAGESHIFT;
+		let pend = (addr.end() - self.addr.start) >> PAGESHIFT;
+		let mut range = PageRange {
+			start: addr.start,
+			pages: &mut self.pages[pstart..pend]
+		};
+		for (paddr, p) in range.iter_mut_with_addr() {
+			p.watch = self.watchpoints.iter()
+				.filter(|w| w.addr.start < paddr.end() && paddr.start < w.addr.end())
+				.fold(0, |acc, w| acc | w.kind)
+				| if self.breakpoints.iter().any(|&b| paddr.contains(b)) { WATCH_EXEC } else { 0 }
+				| if cheats::has_write_cheat(&self.cheats[..], paddr) || audit::audited(&self.audits[..], paddr) { WATCH_WRITE } else { 0 };
+		}
+		self.refresh_protections(addr);
+	}
+	/// Add or remove a debugger breakpoint.  Returns false if there was nothing to remove.
+	pub(super) fn set_breakpoint(&mut self, addr: usize, enable: bool) -> bool {
+		if enable && self.sanitizer {
+			return false
+		}
+		match self.breakpoints.iter().position(|&b| b == addr) {
+			Some(_) if enable => return true,
+			Some(index) => { self.breakpoints.remove(index); },
+			None if enable => self.breakpoints.push(addr),
+			None => return false,
+		}
+		self.update_watch(AddressRange { start: addr, size: 1 });
+		true
+	}
+	pub(super) fn has_breakpoint(&self, addr: usize) -> bool {
+		self.breakpoints.contains(&addr)
+	}
+	/// Call the callbacks of all watchpoints hit by an access
+	pub(super) fn report_watch(&self, addr: usize, write: bool, rip: usize) {
+		let kind = if write { WATCH_WRITE } else { WATCH_READ };
+		for w in self.watchpoints.iter() {
+			if w.kind & kind != 0 && w.addr.contains(addr) {
+				(w.callback)(w.userdata, w.id, addr, write, rip);
+			}
+		}
+	}
+}
+
+impl<'block> ActivatedMemoryBlock<'block> {
+	/// Watch an address range for reads, writes, or both (`kind` is a combination of WATCH_READ and WATCH_WRITE.)
+	/// Returns an id for remove_watchpoint().  Accesses from any thread, including the host's own, are caught.
+	pub fn add_watchpoint(&mut self, addr: AddressRange, kind: u8, callback: WatchCallback, userdata: usize) -> anyhow::Result<u32> {
+		self.b.check_watch_supported("Watchpoints")?;
+		if kind == 0 || kind & !(WATCH_READ | WATCH_WRITE) != 0 {
+			return Err(coded(ErrorCode::InvalidArgument, format!("Bad watchpoint kind {}", kind)))
+		}
+		if addr.size == 0 || addr.start < self.b.addr.start || addr.end() > self.b.addr.end() {
+			return Err(coded(ErrorCode::UnmappedAddress, "Watchpoint must be inside the MemoryBlock"))
+		}
+		let id = self.b.next_watch_id;
+		self.b.next_watch_id += 1;
+		self.b.watchpoints.push(Watchpoint {
+			id,
+			addr,
+			kind,
+			callback,
+			userdata,
+		});
+		self.b.update_watch(addr);
+		Ok(id)
+	}
+	pub fn remove_watchpoint(&mut self, id: u32) -> anyhow::Result<()> {
+		match self.b.watchpoints.iter().position(|w| w.id == id) {
+			Some(index) => {
+				let w = self.b.watchpoints.remove(index);
+				self.b.update_watch(w.addr);
+				Ok(())
+			},
+			None => Err(coded(ErrorCode::NotFound, format!("No watchpoint with id {}", id)))
+		}
+	}
+}
@@ -0,0 +1,211 @@
+// Named regions of guest memory that a core registers so the frontend can find them, like main RAM or VRAM.  With
+// these, tools such as the hex editor and RAM search work on any waterbox core without knowing where it keeps things.
+// Domains flagged as battery backed are the core's SaveRAM, which the frontend gets and puts back apart from states.
+// Cores with banked or mirrored memory can give a domain a translator, a guest function that says where each of its
+// bytes is right now, so that tools reading values out of the domain see what the emulated machine would.
+use crate::*;
+use syscall_defs::*;
+use std::{os::raw::c_char, ffi::CString};
+
+/// Multi byte words in the domain are big endian
+pub const DOMAIN_BIG_ENDIAN: usize = 1;
+/// The frontend may write to the domain, not just read it
+pub const DOMAIN_WRITABLE: usize = 2;
+/// The domain is battery backed, like cartridge SRAM, and is part of the core's SaveRAM
+pub const DOMAIN_SAVERAM: usize = 4;
+const DOMAIN_ALL_FLAGS: usize = DOMAIN_BIG_ENDIAN | DOMAIN_WRITABLE | DOMAIN_SAVERAM;
+
+/// Types of values for wbx_read_value and wbx_write_value.  Floats are passed to and from the frontend as the bits of
+/// an f64, whichever size they are in guest memory.
+pub const VALUE_U8: u32 = 0;
+pub const VALUE_U16: u32 = 1;
+pub const VALUE_U32: u32 = 2;
+pub const VALUE_U64: u32 = 3;
+pub const VALUE_F32: u32 = 4;
+pub const VALUE_F64: u32 = 5;
+/// Along with one of the above, for values stored big endian
+pub const VALUE_BIG_ENDIAN: u32 = 0x100;
+
+/// How many bytes a value of type `ty` is, if it's a type
+pub fn value_width(ty: u32) -> Option<usize> {
+	match ty & !VALUE_BIG_ENDIAN {
+		VALUE_U8 => Some(1),
+		VALUE_U16 => Some(2),
+		VALUE_U32 | VALUE_F32 => Some(4),
+		VALUE_U64 | VALUE_F64 => Some(8),
+		_ => None,
+	}
+}
+/// The value of type `ty` stored in `bytes`, which are as many as value_width() says
+pub fn decode_value(ty: u32, bytes: &[u8]) -> u64 {
+	let mut word = [0u8; 8];
+	if ty & VALUE_BIG_ENDIAN != 0 {
+		word[8 - bytes.len()..].copy_from_slice(bytes);
+		word.reverse();
+	} else {
+		word[..bytes.len()].copy_from_slice(bytes);
+	}
+	let raw = u64::from_le_bytes(word);
+	match ty & !VALUE_BIG_ENDIAN {
+		VALUE_F32 => (f32::from_bits(raw as u32) as f64).to_bits(),
+		_ => raw,
+	}
+}
+/// `value` as type `ty`, stored in `bytes`, the other way
+pub fn encode_value(ty: u32, value: u64, bytes: &mut [u8]) {
+	let raw = match ty & !VALUE_BIG_ENDIAN {
+		VALUE_F32 => (f64::from_bits(value) as f32).to_bits() as u64,
+		_ => value,
+	};
+	let word = raw.to_le_bytes();
+	let n = bytes.len();
+	bytes.copy_from_slice(&word[..n]);
+	if ty & VALUE_BIG_ENDIAN != 0 {
+		bytes.reverse();
+	}
+}
+
+struct MemoryDomain {
+	name: CString,
+	addr: AddressRange,
+	word_size: usize,
+	flags: usize,
+	/// A guest function that takes an offset in the domain and returns its address now, or 0 if it's not mapped
+	translator: usize,
+}
+
+/// A registered domain, as the frontend sees it
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryDomainInfo {
+	/// Valid until the guest registers another domain, or the host is destroyed
+	pub name: *const c_char,
+	pub start: usize,
+	pub size: usize,
+	/// 1, 2, 4 or 8
+	pub word_size: usize,
+	/// DOMAIN_*
+	pub flags: usize,
+}
+
+/// Every domain the guest has registered, in the order it first registered them.  Not in savestates; cores register
+/// the same domains every time they start.
+#[derive(Default)]
+pub struct MemoryDomains {
+	domains: Vec<MemoryDomain>,
+}
+impl MemoryDomains {
+	/// Add a domain, or replace the one already registered with the same name.  `guest` is all of guest memory.
+	pub fn register(&mut self, name: &str, addr: AddressRange, word_size: usize, flags: usize, guest: AddressRange) -> SyscallResult {
+		if !matches!(word_size, 1 | 2 | 4 | 8) || flags & !DOMAIN_ALL_FLAGS != 0 {
+			return Err(EINVAL)
+		}
+		if name.is_empty() || addr.size == 0 || !addr.size.is_multiple_of(word_size) {
+			return Err(EINVAL)
+		}
+		if addr.start < guest.start || addr.start > guest.end() || addr.size > guest.end() - addr.start {
+			return Err(EINVAL)
+		}
+		let domain = MemoryDomain {
+			name: CString::new(name).map_err(|_| EINVAL)?,
+			addr,
+			word_size,
+			flags,
+			translator: 0,
+		};
+		match self.domains.iter_mut().find(|d| d.name == domain.name) {
+			Some(d) => *d = domain,
+			None => self.domains.push(domain),
+		}
+		Ok(())
+	}
+	/// Have the domain called `name` translated by the guest function at `func`, or not if it's 0.  Registering the
+	/// domain again drops its translator.
+	pub fn set_translator(&mut self, name: &str, func: usize, guest: AddressRange) -> SyscallResult {
+		if func != 0 && !guest.contains(func) {
+			return Err(EINVAL)
+		}
+		let d = self.domains.iter_mut().find(|d| d.name.as_bytes() == name.as_bytes()).ok_or(ENOENT)?;
+		d.translator = func;
+		Ok(())
+	}
+	/// Domain `index`'s translator, if it has one
+	pub fn translator(&self, index: usize) -> Option<usize> {
+		self.domains.get(index).map(|d| d.translator).filter(|&t| t != 0)
+	}
+	pub fn count(&self) -> usize {
+		self.domains.len()
+	}
+	pub fn get(&self, index: usize) -> Option<MemoryDomainInfo> {
+		self.domains.get(index).map(|d| MemoryDomainInfo {
+			name: d.name.as_ptr(),
+			start: d.addr.start,
+			size: d.addr.size,
+			word_size: d.word_size,
+			flags: d.flags,
+		})
+	}
+	/// Where the battery backed domains are, in the order they were first registered
+	pub fn saveram(&self) -> Vec<AddressRange> {
+		self.domains.iter().filter(|d| d.flags & DOMAIN_SAVERAM != 0).map(|d| d.addr).collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::ffi::CStr;
+
+	#[test]
+	fn test_register() {
+		let guest = AddressRange { start: 0x36f00000000, size: 0x100000 };
+		let mut d = MemoryDomains::default();
+		assert_eq!(d.register("WRAM", AddressRange { start: 0x36f00001000, size: 0x2000 }, 1, DOMAIN_WRITABLE, guest), Ok(()));
+		assert_eq!(d.register("VRAM", AddressRange { start: 0x36f00010000, size: 0x800 }, 2, DOMAIN_BIG_ENDIAN, guest), Ok(()));
+		assert_eq!(d.register("SRAM", AddressRange { start: 0x36f00020000, size: 0x2000 }, 1, DOMAIN_SAVERAM | DOMAIN_WRITABLE, guest), Ok(()));
+		assert_eq!(d.register("WRAM", AddressRange { start: 0x36f00004000, size: 0x4000 }, 4, 0, guest), Ok(()));
+		assert_eq!(d.count(), 3);
+		assert_eq!(d.saveram(), [AddressRange { start: 0x36f00020000, size: 0x2000 }]);
+		let wram = d.get(0).unwrap();
+		assert_eq!(unsafe { CStr::from_ptr(wram.name) }.to_str(), Ok("WRAM"));
+		assert_eq!((wram.start, wram.size, wram.word_size, wram.flags), (0x36f00004000, 0x4000, 4, 0));
+		assert_eq!(d.get(1).unwrap().word_size, 2);
+		assert!(d.get(3).is_none());
+
+		let bad = |d: &mut MemoryDomains, name, start, size, word_size, flags| {
+			d.register(name, AddressRange { start, size }, word_size, flags, guest)
+		};
+		assert_eq!(bad(&mut d, "", 0x36f00000000, 0x1000, 1, 0), Err(EINVAL));
+		assert_eq!(bad(&mut d, "x", 0x36f00000000, 0, 1, 0), Err(EINVAL));
+		assert_eq!(bad(&mut d, "x", 0x36f000ff000, 0x2000, 1, 0), Err(EINVAL));
+		assert_eq!(bad(&mut d, "x", 0x36f00000000, 0x1000, 3, 0), Err(EINVAL));
+		assert_eq!(bad(&mut d, "x", 0x36f00000000, 0x1001, 2, 0), Err(EINVAL));
+		assert_eq!(bad(&mut d, "x", 0x36f00000000, 0x1000, 1, 8), Err(EINVAL));
+		assert_eq!(d.count(), 3);
+
+		assert_eq!(d.set_translator("VRAM", 0x36f00000100, guest), Ok(()));
+		assert_eq!(d.translator(1), Some(0x36f00000100));
+		assert_eq!(d.set_translator("VRAM", 0x100, guest), Err(EINVAL));
+		assert_eq!(d.set_translator("CRAM", 0x36f00000100, guest), Err(ENOENT));
+		assert_eq!(d.register("VRAM", AddressRange { start: 0x36f00010000, size: 0x800 }, 2, 0, guest), Ok(()));
+		assert_eq!(d.translator(1), None);
+	}
+
+	#[test]
+	fn test_values() {
+		let mut bytes = [0u8; 4];
+		encode_value(VALUE_U32 | VALUE_BIG_ENDIAN, 0x12345678, &mut bytes);
+		assert_eq!(bytes, [0x12, 0x34, 0x56, 0x78]);
+		assert_eq!(decode_value(VALUE_U32, &bytes), 0x78563412);
+		assert_eq!(decode_value(VALUE_U16 | VALUE_BIG_ENDIAN, &bytes[..2]), 0x1234);
+		encode_value(VALUE_F32, 1.5f64.to_bits(), &mut bytes);
+		assert_eq!(bytes, 1.5f32.to_le_bytes());
+		assert_eq!(f64::from_bits(decode_value(VALUE_F32, &bytes)), 1.5);
+		let mut long = [0u8; 8];
+		encode_value(VALUE_F64 | VALUE_BIG_ENDIAN, (-2.25f64).to_bits(), &mut long);
+		assert_eq!(long, (-2.25f64).to_be_bytes());
+		assert_eq!(value_width(VALUE_U64 | VALUE_BIG_ENDIAN), Some(8));
+		assert_eq!(value_width(6), None);
+		assert_eq!(value_width(VALUE_U8 | 0x200), None);
+	}
+}
@@ -0,0 +1,183 @@
+// Runtime metrics for farms of headless hosts dumping movies, which need to see across all of them how often guests
+// fault, how big states get, what syscalls the guests make and how long frames take.  Once turned on, each host keeps
+// running totals that are cheap to add to, and writes them out on request in the Prometheus text format, which a
+// frontend can serve as is or turn into StatsD lines.  Everything only goes up, except the gauges, so rates are up to
+// whatever scrapes them.  None of it is in states.
+use crate::*;
+use syscall_defs::*;
+use memory_block::MemoryStats;
+use counters::PerfCounters;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+/// Upper bounds, in seconds, of the guest call histogram's buckets, which are mostly about frames at 60Hz
+const CALL_BUCKETS: [f64; 8] = [0.001, 0.002, 0.004, 0.008, 0.016, 0.033, 0.066, 0.25];
+
+#[derive(Default)]
+struct Timed {
+	count: u64,
+	total: Duration,
+}
+impl Timed {
+	fn add(&mut self, t: Duration) {
+		self.count += 1;
+		self.total += t;
+	}
+}
+
+#[derive(Default)]
+pub struct Metrics {
+	enabled: bool,
+	/// By syscall number, how many were made and how many of those failed
+	syscalls: BTreeMap<usize, (u64, u64)>,
+	/// Guest calls made by the frontend, not callbacks calling back in
+	calls: Timed,
+	/// Calls that fell into each of CALL_BUCKETS, and past all of them
+	call_buckets: [u64; CALL_BUCKETS.len() + 1],
+	call_failures: u64,
+	saves: Timed,
+	save_bytes: u64,
+	last_save_bytes: u64,
+	loads: Timed,
+	load_failures: u64,
+}
+impl Metrics {
+	/// Turn gathering on or off.  What's been gathered so far is kept either way.
+	pub fn set_enabled(&mut self, enabled: bool) {
+		self.enabled = enabled;
+	}
+	pub fn enabled(&self) -> bool {
+		self.enabled
+	}
+	/// When something being timed started, if metrics are on
+	pub fn start(&self) -> Option<Instant> {
+		if self.enabled { Some(Instant::now()) } else { None }
+	}
+	pub fn syscall(&mut self, nr: &SyscallNumber, ret: &SyscallReturn) {
+		let counts = self.syscalls.entry(nr.0).or_default();
+		counts.0 += 1;
+		if ret.0 > SyscallReturn::ERROR_THRESH {
+			counts.1 += 1;
+		}
+	}
+	pub fn guest_call(&mut self, started: Option<Instant>, ok: bool) {
+		let t = match started {
+			Some(s) => s.elapsed(),
+			None => return,
+		};
+		self.calls.add(t);
+		let bucket = CALL_BUCKETS.iter().position(|&b| t.as_secs_f64() <= b).unwrap_or(CALL_BUCKETS.len());
+		self.call_buckets[bucket] += 1;
+		if !ok {
+			self.call_failures += 1;
+		}
+	}
+	pub fn state_saved(&mut self, started: Option<Instant>, bytes: u64) {
+		if let Some(s) = started {
+			self.saves.add(s.elapsed());
+			self.save_bytes += bytes;
+			self.last_save_bytes = bytes;
+		}
+	}
+	pub fn state_loaded(&mut self, started: Option<Instant>, ok: bool) {
+		if let Some(s) = started {
+			self.loads.add(s.elapsed());
+			if !ok {
+				self.load_failures += 1;
+			}
+		}
+	}
+	/// Everything, for the core called `core`, along with the process's `perf` counters and the host's memory `stats`
+	pub fn render(&self, core: &str, perf: &PerfCounters, stats: &MemoryStats) -> String {
+		let core = core.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+		let mut out = String::new();
+		let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, String)]| {
+			let _ = writeln!(out, "# HELP waterbox_{} {}\n# TYPE waterbox_{} {}", name, help, name, kind);
+			for (suffix, value) in samples.iter() {
+				let (suffix, labels) = match suffix.find('{') {
+					Some(i) => (&suffix[..i], format!(",{}", &suffix[i + 1..suffix.len() - 1])),
+					None => (&suffix[..], String::new()),
+				};
+				let _ = writeln!(out, "waterbox_{}{}{{core=\"{}\"{}}} {}", name, suffix, core, labels, value);
+			}
+		};
+		let one = |v: u64| [(String::new(), v.to_string())];
+		let secs = |d: Duration| format!("{}", d.as_secs_f64());
+
+		metric("faults_total", "counter", "Faults taken on guest memory, by every host in the process", &one(perf.faults));
+		metric("dirty_faults_total", "counter", "Faults that were first writes to a clean page, by every host in the process", &one(perf.dirty_faults));
+		metric("protect_calls_total", "counter", "OS calls made to change guest memory protections, by every host in the process", &one(perf.protect_calls));
+		metric("committed_bytes", "gauge", "Host memory backing guest memory", &one(stats.committed_bytes as u64));
+		metric("dirty_bytes", "gauge", "Guest memory changed since sealing, which is in states", &one(stats.dirty_bytes as u64));
+
+		let by_name = |which: fn(&(u64, u64)) -> u64| self.syscalls.iter()
+			.map(|(&nr, counts)| {
+				let name = match lookup_syscall(&SyscallNumber(nr)) {
+					"????" => nr.to_string(),
+					name => name.trim_start_matches("NR_").to_lowercase(),
+				};
+				(format!("{{name=\"{}\"}}", name), which(counts).to_string())
+			})
+			.collect::<Vec<_>>();
+		metric("syscalls_total", "counter", "Syscalls the guest made", &by_name(|c| c.0));
+		metric("syscall_errors_total", "counter", "Syscalls the guest made that failed", &by_name(|c| c.1));
+
+		let mut calls = Vec::new();
+		let mut below = 0;
+		for (i, bound) in CALL_BUCKETS.iter().map(|b| b.to_string()).chain(std::iter::once("+Inf".to_string())).enumerate() {
+			below += self.call_buckets[i];
+			calls.push((format!("_bucket{{le=\"{}\"}}", bound), below.to_string()));
+		}
+		calls.push(("_sum".to_string(), secs(self.calls.total)));
+		calls.push(("_count".to_string(), self.calls.count.to_string()));
+		metric("guest_call_seconds", "histogram", "How long calls from the frontend into the guest took", &calls);
+		metric("guest_call_failures_total", "counter", "Calls into the guest that trapped, timed out, aborted or were cancelled", &one(self.call_failures));
+
+		metric("state_saves_total", "counter", "Savestates made", &one(self.saves.count));
+		metric("state_save_seconds_total", "counter", "Time spent making savestates", &[(String::new(), secs(self.saves.total))]);
+		metric("state_save_bytes_total", "counter", "Bytes of savestates written, after any compression", &one(self.save_bytes));
+		metric("state_bytes", "gauge", "How big the last savestate was, after any compression", &one(self.last_save_bytes));
+		metric("state_loads_total", "counter", "Savestates loaded, or tried", &one(self.loads.count));
+		metric("state_load_seconds_total", "counter", "Time spent loading savestates", &[(String::new(), secs(self.loads.total))]);
+		metric("state_load_failures_total", "counter", "Savestates that failed to load", &one(self.load_failures));
+		out
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_metrics() {
+		let mut m = Metrics::default();
+		assert_eq!(m.start(), None);
+		m.guest_call(m.start(), true);
+		m.set_enabled(true);
+		m.syscall(&NR_WRITE, &syscall_ok(3));
+		m.syscall(&NR_WRITE, &syscall_err(EBADF));
+		m.syscall(&SyscallNumber(0x9999), &syscall_ok(0));
+		m.guest_call(m.start(), true);
+		m.guest_call(Some(Instant::now() - Duration::from_secs(1)), false);
+		m.state_saved(m.start(), 1000);
+		m.state_loaded(m.start(), false);
+		let text = m.render("a \"core\"", &PerfCounters { faults: 12, ..Default::default() }, &MemoryStats::default());
+		let lines = text.lines().collect::<Vec<_>>();
+		for expected in [
+			"waterbox_faults_total{core=\"a \\\"core\\\"\"} 12",
+			"waterbox_syscalls_total{core=\"a \\\"core\\\"\",name=\"write\"} 2",
+			"waterbox_syscall_errors_total{core=\"a \\\"core\\\"\",name=\"write\"} 1",
+			"waterbox_syscalls_total{core=\"a \\\"core\\\"\",name=\"39321\"} 1",
+			"waterbox_guest_call_seconds_bucket{core=\"a \\\"core\\\"\",le=\"0.25\"} 1",
+			"waterbox_guest_call_seconds_bucket{core=\"a \\\"core\\\"\",le=\"+Inf\"} 2",
+			"waterbox_guest_call_seconds_count{core=\"a \\\"core\\\"\"} 2",
+			"waterbox_guest_call_failures_total{core=\"a \\\"core\\\"\"} 1",
+			"waterbox_state_bytes{core=\"a \\\"core\\\"\"} 1000",
+			"waterbox_state_load_failures_total{core=\"a \\\"core\\\"\"} 1",
+			"# TYPE waterbox_guest_call_seconds histogram",
+		].iter() {
+			assert!(lines.contains(expected), "{} isn't in\n{}", expected, text);
+		}
+	}
+}
@@ -0,0 +1,128 @@
+// Where the time goes in calls into the guest.  Each entry point gets running totals of how long calls to it took, how
+// much of that was spent handling syscalls, how many pages it dirtied, and how many protection changes it took, so that
+// a slow frame can be blamed on guest code, the syscall layer, or dirty page tracking.
+use crate::*;
+use std::time::{Duration, Instant};
+
+/// Entries that aren't guest functions
+pub const ENTRY_SAVE_STATE: usize = 1;
+pub const ENTRY_LOAD_STATE: usize = 2;
+
+/// Totals for every call to one entry point
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct EntryProfile {
+	/// The guest function that was called, or one of the ENTRY_*s
+	pub entry: usize,
+	pub calls: u64,
+	/// Nanoseconds spent in calls, all told
+	pub total_ns: u64,
+	/// Nanoseconds of total_ns spent handling syscalls
+	pub syscall_ns: u64,
+	pub syscalls: u64,
+	/// Pages that were dirtied, each of which took a fault to notice
+	pub dirty_faults: u64,
+	/// OS calls made to change guest memory protections
+	pub protect_calls: u64,
+}
+
+/// What the running totals were when a call started
+pub struct Span {
+	entry: usize,
+	start: Instant,
+	syscall_time: Duration,
+	syscalls: u64,
+	dirty_faults: u64,
+	protect_calls: u64,
+}
+
+#[derive(Default)]
+pub struct Profiler {
+	enabled: bool,
+	entries: Vec<EntryProfile>,
+	/// Running totals over everything, which calls take the difference of
+	syscall_time: Duration,
+	syscalls: u64,
+}
+impl Profiler {
+	/// Turn profiling on or off.  What's been gathered so far is kept either way.
+	pub fn set_enabled(&mut self, enabled: bool) {
+		self.enabled = enabled;
+	}
+	pub fn enabled(&self) -> bool {
+		self.enabled
+	}
+	pub fn reset(&mut self) {
+		self.entries.clear();
+	}
+	/// In the order each entry point was first called
+	pub fn entries(&self) -> &[EntryProfile] {
+		&self.entries[..]
+	}
+	/// Start timing a call to `entry`, if profiling is on
+	pub fn begin(&self, entry: usize) -> Option<Span> {
+		if !self.enabled {
+			return None
+		}
+		Some(Span {
+			entry,
+			start: Instant::now(),
+			syscall_time: self.syscall_time,
+			syscalls: self.syscalls,
+			dirty_faults: memory_block::dirty_fault_count(),
+			protect_calls: memory_block::protect_call_count(),
+		})
+	}
+	/// Finish timing a call started with begin()
+	pub fn end(&mut self, span: Option<Span>) {
+		let span = match span {
+			Some(s) => s,
+			None => return,
+		};
+		let index = match self.entries.iter().position(|e| e.entry == span.entry) {
+			Some(i) => i,
+			None => {
+				self.entries.push(EntryProfile { entry: span.entry, ..Default::default() });
+				self.entries.len() - 1
+			},
+		};
+		let e = &mut self.entries[index];
+		e.calls += 1;
+		e.total_ns += span.start.elapsed().as_nanos() as u64;
+		e.syscall_ns += (self.syscall_time - span.syscall_time).as_nanos() as u64;
+		e.syscalls += self.syscalls - span.syscalls;
+		e.dirty_faults += memory_block::dirty_fault_count() - span.dirty_faults;
+		e.protect_calls += memory_block::protect_call_count() - span.protect_calls;
+	}
+	/// Count a syscall that took `time` to handle
+	pub fn syscall(&mut self, time: Duration) {
+		self.syscall_time += time;
+		self.syscalls += 1;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_profiler() {
+		let mut p = Profiler::default();
+		assert!(p.begin(0x1000).is_none());
+		p.set_enabled(true);
+		for _ in 0..2 {
+			let span = p.begin(0x1000);
+			p.syscall(Duration::from_micros(3));
+			p.syscall(Duration::from_micros(4));
+			p.end(span);
+		}
+		let span = p.begin(ENTRY_SAVE_STATE);
+		p.end(span);
+		let e = p.entries();
+		assert_eq!(e.len(), 2);
+		assert_eq!((e[0].entry, e[0].calls, e[0].syscalls, e[0].syscall_ns), (0x1000, 2, 4, 14000));
+		assert_eq!((e[1].entry, e[1].calls, e[1].syscalls), (ENTRY_SAVE_STATE, 1, 0));
+		p.reset();
+		assert!(p.entries().is_empty());
+	}
+}
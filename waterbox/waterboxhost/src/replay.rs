@@ -0,0 +1,411 @@
+// Recording everything that goes into a guest from outside, and playing it back later to find where a run desyncs.
+// While recording, the host logs each call_guest() with what it returned, writes into guest memory, clock, seeds and
+// config changes, and every answer the frontend's socket callbacks give, and the frontend marks its frames to log a
+// hash of guest memory at each.  Nothing else a guest sees can differ between runs:  its clocks only move when told
+// to, its randomness is seeded, and files can't be mounted or states loaded mid-recording.  A log starts with the
+// full state hash of where it was made, and replays on a host loaded to that same state, answering socket callbacks
+// from the log.  Replay stops at the first frame whose hash comes out different, or the first call that returns
+// something else, or the first socket call the guest makes that wasn't in the log, and says which it was.
+use crate::*;
+use crate::syscall_defs::*;
+use crate::fs::SocketHost;
+use std::io::{Read, Write};
+use std::{cell::RefCell, rc::Rc};
+
+const MAGIC: &str = "WbxReplay1";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+	/// A call_guest() starting
+	Call { func: usize, args: [usize; 6] },
+	/// What the last Call returned, or the ErrorCode it failed with
+	Return(Result<usize, i32>),
+	WriteMemory { addr: usize, data: Vec<u8> },
+	AdvanceClock(u64),
+	SetClockRealtime(i64),
+	SetRandomSeed(u64),
+	SeedEntropy(Vec<u8>),
+	SetConfig { key: String, value: Option<String> },
+	/// Whether the guest has a socket host from now on
+	SocketHost(bool),
+	Connect(Result<u64, i32>),
+	Send(Result<usize, i32>),
+	Recv(Result<Vec<u8>, i32>),
+	Ready(bool),
+	/// A frame the frontend marked, and the hash of guest memory at it
+	Frame(u64),
+}
+
+fn write_bytes(stream: &mut dyn Write, data: &[u8]) -> anyhow::Result<()> {
+	bin::writeval(stream, data.len() as u64)?;
+	stream.write_all(data)?;
+	Ok(())
+}
+fn read_bytes(stream: &mut dyn Read) -> anyhow::Result<Vec<u8>> {
+	let len = bin::readval::<u64>(stream)? as usize;
+	let mut data = Vec::new();
+	// a bad length runs out of log, rather than memory
+	stream.take(len as u64).read_to_end(&mut data)?;
+	if data.len() != len {
+		return Err(coded(ErrorCode::BadStateData, "Replay log stops short"))
+	}
+	Ok(data)
+}
+fn read_string(stream: &mut dyn Read) -> anyhow::Result<String> {
+	String::from_utf8(read_bytes(stream)?).map_err(|_| coded(ErrorCode::BadStateData, "Replay log has a bad string"))
+}
+fn write_result<T>(stream: &mut dyn Write, res: &Result<T, i32>, ok: impl FnOnce(&mut dyn Write, &T) -> anyhow::Result<()>) -> anyhow::Result<()> {
+	match res {
+		Ok(v) => {
+			bin::writeval(stream, 0i32)?;
+			ok(stream, v)
+		},
+		Err(e) => bin::writeval(stream, *e).map_err(|e| e.into()),
+	}
+}
+fn read_result<T>(stream: &mut dyn Read, ok: impl FnOnce(&mut dyn Read) -> anyhow::Result<T>) -> anyhow::Result<Result<T, i32>> {
+	match bin::readval::<i32>(stream)? {
+		0 => Ok(Ok(ok(stream)?)),
+		e => Ok(Err(e)),
+	}
+}
+
+impl Event {
+	fn save(&self, stream: &mut dyn Write) -> anyhow::Result<()> {
+		match self {
+			Event::Call { func, args } => {
+				bin::writeval(stream, 1u8)?;
+				bin::write(stream, func)?;
+				bin::write(stream, args)?;
+			},
+			Event::Return(res) => {
+				bin::writeval(stream, 2u8)?;
+				write_result(stream, res, |s, v| bin::write(s, v).map_err(|e| e.into()))?;
+			},
+			Event::WriteMemory { addr, data } => {
+				bin::writeval(stream, 3u8)?;
+				bin::write(stream, addr)?;
+				write_bytes(stream, data)?;
+			},
+			Event::AdvanceClock(ns) => {
+				bin::writeval(stream, 4u8)?;
+				bin::write(stream, ns)?;
+			},
+			Event::SetClockRealtime(secs) => {
+				bin::writeval(stream, 5u8)?;
+				bin::write(stream, secs)?;
+			},
+			Event::SetRandomSeed(seed) => {
+				bin::writeval(stream, 6u8)?;
+				bin::write(stream, seed)?;
+			},
+			Event::SetConfig { key, value } => {
+				bin::writeval(stream, 7u8)?;
+				write_bytes(stream, key.as_bytes())?;
+				bin::writeval(stream, value.is_some())?;
+				if let Some(v) = value {
+					write_bytes(stream, v.as_bytes())?;
+				}
+			},
+			Event::SocketHost(has) => {
+				bin::writeval(stream, 8u8)?;
+				bin::write(stream, has)?;
+			},
+			Event::Connect(res) => {
+				bin::writeval(stream, 9u8)?;
+				write_result(stream, res, |s, v| bin::write(s, v).map_err(|e| e.into()))?;
+			},
+			Event::Send(res) => {
+				bin::writeval(stream, 10u8)?;
+				write_result(stream, res, |s, v| bin::write(s, v).map_err(|e| e.into()))?;
+			},
+			Event::Recv(res) => {
+				bin::writeval(stream, 11u8)?;
+				write_result(stream, res, |s, v| write_bytes(s, v))?;
+			},
+			Event::Ready(ready) => {
+				bin::writeval(stream, 12u8)?;
+				bin::write(stream, ready)?;
+			},
+			Event::Frame(hash) => {
+				bin::writeval(stream, 13u8)?;
+				bin::write(stream, hash)?;
+			},
+			Event::SeedEntropy(seed) => {
+				bin::writeval(stream, 14u8)?;
+				write_bytes(stream, seed)?;
+			},
+		}
+		Ok(())
+	}
+	fn load(stream: &mut dyn Read) -> anyhow::Result<Event> {
+		Ok(match bin::readval::<u8>(stream)? {
+			1 => Event::Call { func: bin::readval(stream)?, args: bin::readval(stream)? },
+			2 => Event::Return(read_result(stream, |s| Ok(bin::readval(s)?))?),
+			3 => Event::WriteMemory { addr: bin::readval(stream)?, data: read_bytes(stream)? },
+			4 => Event::AdvanceClock(bin::readval(stream)?),
+			5 => Event::SetClockRealtime(bin::readval(stream)?),
+			6 => Event::SetRandomSeed(bin::readval(stream)?),
+			7 => {
+				let key = read_string(stream)?;
+				let value = if bin::readval::<u8>(stream)? != 0 { Some(read_string(stream)?) } else { None };
+				Event::SetConfig { key, value }
+			},
+			8 => Event::SocketHost(bin::readval::<u8>(stream)? != 0),
+			9 => Event::Connect(read_result(stream, |s| Ok(bin::readval(s)?))?),
+			10 => Event::Send(read_result(stream, |s| Ok(bin::readval(s)?))?),
+			11 => Event::Recv(read_result(stream, |s| read_bytes(s))?),
+			12 => Event::Ready(bin::readval::<u8>(stream)? != 0),
+			13 => Event::Frame(bin::readval(stream)?),
+			14 => Event::SeedEntropy(read_bytes(stream)?),
+			_ => return Err(coded(ErrorCode::BadStateData, "Replay log has an unknown event")),
+		})
+	}
+}
+
+/// A whole recording
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Log {
+	/// Of the image the recording was made with
+	pub image_hash: Vec<u8>,
+	/// The full state hash where the recording starts
+	pub start_hash: u64,
+	pub events: Vec<Event>,
+}
+impl Log {
+	pub fn save(&self, stream: &mut dyn Write) -> anyhow::Result<()> {
+		bin::write_magic(stream, MAGIC)?;
+		write_bytes(stream, &self.image_hash)?;
+		bin::write(stream, &self.start_hash)?;
+		bin::writeval(stream, self.events.len() as u64)?;
+		for e in self.events.iter() {
+			e.save(stream)?;
+		}
+		Ok(())
+	}
+	pub fn load(stream: &mut dyn Read) -> anyhow::Result<Log> {
+		bin::verify_magic(stream, MAGIC)?;
+		let image_hash = read_bytes(stream)?;
+		let start_hash = bin::readval(stream)?;
+		let count = bin::readval::<u64>(stream)?;
+		let mut events = Vec::new();
+		for _ in 0..count {
+			events.push(Event::load(stream)?);
+		}
+		Ok(Log { image_hash, start_hash, events })
+	}
+}
+
+/// What a host is doing, shared with its socket host
+pub enum Session {
+	Off,
+	Recording(Log),
+	Replaying {
+		events: Vec<Event>,
+		next: usize,
+		/// The first socket call the guest made that the log didn't have next
+		desync: Option<String>,
+	},
+}
+impl Session {
+	pub fn recording(&self) -> bool {
+		matches!(self, Session::Recording(_))
+	}
+	pub fn active(&self) -> bool {
+		!matches!(self, Session::Off)
+	}
+	/// Log `e`, if recording
+	pub fn record(&mut self, e: Event) {
+		if let Session::Recording(log) = self {
+			log.events.push(e);
+		}
+	}
+	/// The next event in a replay, if there is one
+	pub fn next(&mut self) -> Option<Event> {
+		match self {
+			Session::Replaying { events, next, .. } if *next < events.len() => {
+				*next += 1;
+				Some(events[*next - 1].clone())
+			},
+			_ => None,
+		}
+	}
+	/// Which event a replay is up to
+	pub fn position(&self) -> usize {
+		match self {
+			Session::Replaying { next, .. } => *next,
+			_ => 0,
+		}
+	}
+	/// Take the socket call the guest just made, which should be next in the log, and have `answer` pull the recorded
+	/// result out of it.  A guest that went another way gets `otherwise`, and the replay fails after the call.
+	fn replay_socket<T>(&mut self, what: &str, answer: impl FnOnce(Event) -> Option<T>, otherwise: T) -> T {
+		if let Session::Replaying { desync, .. } = self {
+			if desync.is_some() {
+				return otherwise
+			}
+		}
+		let at = self.position();
+		let res = self.next().and_then(answer);
+		match (res, self) {
+			(Some(r), _) => r,
+			(None, Session::Replaying { desync, .. }) => {
+				*desync = Some(format!("Guest made a {} socket call that wasn't recorded, at event {}", what, at));
+				otherwise
+			},
+			(None, _) => otherwise,
+		}
+	}
+}
+
+/// How `res` goes in the log
+fn logged<T: Clone>(res: &Result<T, SyscallError>) -> Result<T, i32> {
+	match res {
+		Ok(v) => Ok(v.clone()),
+		Err(e) => Err(e.0),
+	}
+}
+
+/// Stands between the guest's sockets and the frontend's socket host, recording what the frontend answers, or
+/// answering from a replay instead.  Without a session, it just passes everything on.
+pub struct Sockets {
+	/// None only during a replay of a recording that had a socket host
+	inner: Option<Box<dyn SocketHost>>,
+	session: Rc<RefCell<Session>>,
+}
+impl Sockets {
+	pub fn new(inner: Option<Box<dyn SocketHost>>, session: Rc<RefCell<Session>>) -> Sockets {
+		Sockets { inner, session }
+	}
+	fn replaying(&self) -> bool {
+		let s = self.session.borrow();
+		!s.recording() && s.active()
+	}
+}
+impl SocketHost for Sockets {
+	fn connect(&mut self, addr: &str) -> Result<u64, SyscallError> {
+		if self.replaying() {
+			return self.session.borrow_mut().replay_socket("connect", |e| match e {
+				Event::Connect(r) => Some(r.map_err(SyscallError)),
+				_ => None,
+			}, Err(EIO))
+		}
+		let res = self.inner.as_mut().map_or(Err(ECONNREFUSED), |h| h.connect(addr));
+		self.session.borrow_mut().record(Event::Connect(logged(&res)));
+		res
+	}
+	fn send(&mut self, handle: u64, buf: &[u8]) -> Result<usize, SyscallError> {
+		if self.replaying() {
+			return self.session.borrow_mut().replay_socket("send", |e| match e {
+				Event::Send(r) => Some(r.map_err(SyscallError)),
+				_ => None,
+			}, Err(EIO))
+		}
+		let res = self.inner.as_mut().map_or(Err(ENOTCONN), |h| h.send(handle, buf));
+		self.session.borrow_mut().record(Event::Send(logged(&res)));
+		res
+	}
+	fn recv(&mut self, handle: u64, buf: &mut [u8]) -> Result<usize, SyscallError> {
+		if self.replaying() {
+			return self.session.borrow_mut().replay_socket("recv", |e| match e {
+				Event::Recv(Ok(data)) if data.len() <= buf.len() => {
+					buf[..data.len()].copy_from_slice(&data);
+					Some(Ok(data.len()))
+				},
+				Event::Recv(Err(e)) => Some(Err(SyscallError(e))),
+				_ => None,
+			}, Err(EIO))
+		}
+		let res = self.inner.as_mut().map_or(Err(ENOTCONN), |h| h.recv(handle, buf));
+		self.session.borrow_mut().record(Event::Recv(logged(&res).map(|n| buf[..n].to_vec())));
+		res
+	}
+	fn ready(&mut self, handle: u64) -> bool {
+		if self.replaying() {
+			return self.session.borrow_mut().replay_socket("poll", |e| match e {
+				Event::Ready(r) => Some(r),
+				_ => None,
+			}, false)
+		}
+		let res = self.inner.as_mut().map(|h| h.ready(handle)).unwrap_or(false);
+		self.session.borrow_mut().record(Event::Ready(res));
+		res
+	}
+	fn close(&mut self, handle: u64) {
+		// nothing comes back to the guest, so there's nothing to log
+		if !self.replaying() {
+			if let Some(h) = self.inner.as_mut() {
+				h.close(handle);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	struct Echo(Vec<u8>);
+	impl SocketHost for Echo {
+		fn connect(&mut self, _addr: &str) -> Result<u64, SyscallError> {
+			Ok(7)
+		}
+		fn send(&mut self, _handle: u64, buf: &[u8]) -> Result<usize, SyscallError> {
+			self.0.extend_from_slice(buf);
+			Ok(buf.len())
+		}
+		fn recv(&mut self, _handle: u64, buf: &mut [u8]) -> Result<usize, SyscallError> {
+			if self.0.is_empty() {
+				return Err(EAGAIN)
+			}
+			let n = std::cmp::min(buf.len(), self.0.len());
+			buf[..n].copy_from_slice(&self.0[..n]);
+			self.0.drain(..n);
+			Ok(n)
+		}
+		fn ready(&mut self, _handle: u64) -> bool {
+			!self.0.is_empty()
+		}
+		fn close(&mut self, _handle: u64) {}
+	}
+
+	#[test]
+	fn test_sockets() -> anyhow::Result<()> {
+		let session = Rc::new(RefCell::new(Session::Recording(Log { image_hash: vec![1, 2], start_hash: 3, events: Vec::new() })));
+		let mut s = Sockets::new(Some(Box::new(Echo(Vec::new()))), session.clone());
+		let mut buf = [0u8; 4];
+		let h = s.connect("inet:127.0.0.1:80")?;
+		assert_eq!(s.recv(h, &mut buf), Err(EAGAIN));
+		s.send(h, b"hello")?;
+		assert!(s.ready(h));
+		assert_eq!(s.recv(h, &mut buf)?, 4);
+		s.close(h);
+
+		let log = match std::mem::replace(&mut *session.borrow_mut(), Session::Off) {
+			Session::Recording(log) => log,
+			_ => unreachable!(),
+		};
+		assert_eq!(log.events.len(), 5);
+		let mut saved = Vec::new();
+		log.save(&mut saved)?;
+		let mut log = Log::load(&mut &saved[..])?;
+		assert!(Log::load(&mut &saved[..saved.len() - 1]).is_err());
+
+		// the same calls get the same answers, without anyone on the other end
+		log.events.push(Event::Frame(9));
+		*session.borrow_mut() = Session::Replaying { events: log.events, next: 0, desync: None };
+		let mut s = Sockets::new(None, session.clone());
+		let mut buf = [0u8; 4];
+		assert_eq!(s.connect("inet:127.0.0.1:80")?, 7);
+		assert_eq!(s.recv(h, &mut buf), Err(EAGAIN));
+		assert_eq!(s.send(h, b"other")?, 5);
+		assert!(s.ready(h));
+		assert_eq!(s.recv(h, &mut buf)?, 4);
+		assert_eq!(&buf, b"hell");
+		assert!(match &*session.borrow() { Session::Replaying { desync, .. } => desync.is_none(), _ => false });
+		// one more isn't in the log
+		assert_eq!(s.recv(h, &mut buf), Err(EIO));
+		assert!(match &*session.borrow() { Session::Replaying { desync, .. } => desync.is_some(), _ => false });
+		Ok(())
+	}
+}
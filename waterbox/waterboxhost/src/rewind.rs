@@ -0,0 +1,419 @@
+// The host's rewind buffer.  Frames are captured in groups:  A keyframe with the whole state, and then deltas against
+// it, until every `keyframe_interval`th capture starts a new group.  Consecutive states are nearly the same, so a delta
+// is usually a small fraction of a keyframe, and with everything LZ4 compressed on top, an hour of frames fits in a few
+// hundred MB.  When the buffer is full, the oldest group is thrown away as a whole, since its deltas are no use
+// without its keyframe.
+//
+// A delta is a list of copies out of the keyframe and literal bytes.  States don't line up byte for byte, since a page
+// that's newly dirty shifts everything after it along, so matches are found the way rsync finds them:  With a rolling
+// hash over the state, looked up in a table of the keyframe's blocks.
+use crate::*;
+use std::collections::VecDeque;
+use compress::{compress_block, decompress_block};
+
+/// Size of the blocks the keyframe is hashed in, and the shortest copy that's worth making
+const WINDOW: usize = 64;
+const HASH_BASE: u32 = 0x0100_0193;
+const OP_COPY: u8 = 0;
+const OP_LITERAL: u8 = 1;
+/// For frontends that only set a capacity
+pub const DEFAULT_KEYFRAME_INTERVAL: usize = 30;
+
+/// What's in the rewind buffer, for wbx_get_rewind_info
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct RewindInfo {
+	pub capacity: usize,
+	/// Including the uncompressed copy of the newest keyframe the buffer keeps for making deltas
+	pub used: usize,
+	pub frames: usize,
+	pub keyframes: usize,
+	pub keyframe_interval: usize,
+}
+
+struct Frame {
+	keyframe: bool,
+	/// Of the state this is, or is a delta to
+	state_size: usize,
+	/// Of the keyframe or delta, before it's compressed
+	raw_size: usize,
+	data: Vec<u8>,
+}
+impl Frame {
+	fn new(keyframe: bool, raw: &[u8], state_size: usize) -> Frame {
+		let mut data = Vec::new();
+		compress_block(raw, &mut data);
+		data.shrink_to_fit();
+		Frame { keyframe, state_size, raw_size: raw.len(), data }
+	}
+	fn decompress(&self) -> anyhow::Result<Vec<u8>> {
+		let mut res = Vec::with_capacity(self.raw_size);
+		decompress_block(&self.data[..], &mut res, self.raw_size)?;
+		Ok(res)
+	}
+}
+
+/// The newest keyframe, as it was captured, and where its blocks are, for making deltas against
+struct Base {
+	state: Vec<u8>,
+	/// Block offset + 1 for each hash slot, or 0 for none
+	table: Vec<u32>,
+	bits: u32,
+}
+impl Base {
+	fn new(state: Vec<u8>) -> Base {
+		let blocks = state.len() / WINDOW;
+		let bits = std::cmp::max(10, (blocks * 2).next_power_of_two().trailing_zeros());
+		let mut table = vec![0u32; 1 << bits];
+		for (index, block) in state.chunks_exact(WINDOW).enumerate() {
+			table[slot(window_hash(block), bits)] = index as u32 + 1;
+		}
+		Base { state, table, bits }
+	}
+	fn size(&self) -> usize {
+		self.state.len() + self.table.len() * std::mem::size_of::<u32>()
+	}
+	fn find(&self, window: &[u8], hash: u32) -> Option<usize> {
+		match self.table[slot(hash, self.bits)] {
+			0 => None,
+			index => {
+				let start = (index as usize - 1) * WINDOW;
+				if &self.state[start..start + WINDOW] == window { Some(start) } else { None }
+			}
+		}
+	}
+}
+
+fn window_hash(window: &[u8]) -> u32 {
+	window.iter().fold(0u32, |h, &b| h.wrapping_mul(HASH_BASE).wrapping_add(b as u32))
+}
+fn slot(hash: u32, bits: u32) -> usize {
+	(hash.wrapping_mul(2654435761) >> (32 - bits)) as usize
+}
+fn common_len(a: &[u8], b: &[u8]) -> usize {
+	let max = std::cmp::min(a.len(), b.len());
+	let mut n = 0;
+	while n + WINDOW <= max && a[n..n + WINDOW] == b[n..n + WINDOW] {
+		n += WINDOW;
+	}
+	while n < max && a[n] == b[n] {
+		n += 1;
+	}
+	n
+}
+fn write_op(dst: &mut Vec<u8>, op: u8, val: usize) {
+	dst.push(op);
+	dst.extend_from_slice(&(val as u64).to_le_bytes());
+}
+
+/// What `state` is, as copies out of `base` and bytes of its own
+fn make_delta(state: &[u8], base: &Base) -> Vec<u8> {
+	let key = &base.state[..];
+	let outgoing = HASH_BASE.wrapping_pow(WINDOW as u32 - 1);
+	let mut res = Vec::new();
+	let flush = |res: &mut Vec<u8>, literals: &[u8]| {
+		if !literals.is_empty() {
+			write_op(res, OP_LITERAL, literals.len());
+			res.extend_from_slice(literals);
+		}
+	};
+	let mut literal = 0;
+	let mut i = 0;
+	// where the keyframe is, relative to the state, at the last copy.  most changes are in place, so the state is
+	// looked for there first
+	let mut shift = 0isize;
+	let mut hash = None;
+	while i < state.len() {
+		let run = match i as isize + shift {
+			at if at >= 0 && (at as usize) < key.len() => common_len(&state[i..], &key[at as usize..]),
+			_ => 0,
+		};
+		if run >= WINDOW {
+			flush(&mut res, &state[literal..i]);
+			write_op(&mut res, OP_COPY, (i as isize + shift) as usize);
+			res.extend_from_slice(&(run as u64).to_le_bytes());
+			i += run;
+			literal = i;
+			hash = None;
+			continue
+		}
+		if i + WINDOW > state.len() {
+			break
+		}
+		let h = hash.unwrap_or_else(|| window_hash(&state[i..i + WINDOW]));
+		if let Some(at) = base.find(&state[i..i + WINDOW], h) {
+			// the next time around copies it
+			shift = at as isize - i as isize;
+			continue
+		}
+		hash = state.get(i + WINDOW).map(|&b| {
+			h.wrapping_sub((state[i] as u32).wrapping_mul(outgoing)).wrapping_mul(HASH_BASE).wrapping_add(b as u32)
+		});
+		i += 1;
+	}
+	flush(&mut res, &state[literal..]);
+	res
+}
+
+fn apply_delta(delta: &[u8], key: &[u8], size: usize) -> anyhow::Result<Vec<u8>> {
+	let bad = || coded(ErrorCode::BadStateData, "Corrupt rewind frame");
+	let mut res = Vec::with_capacity(size);
+	let mut i = 0;
+	let read = |i: &mut usize| -> anyhow::Result<usize> {
+		let bytes = delta.get(*i..*i + 8).ok_or_else(bad)?;
+		*i += 8;
+		let mut val = [0u8; 8];
+		val.copy_from_slice(bytes);
+		Ok(u64::from_le_bytes(val) as usize)
+	};
+	while i < delta.len() {
+		let op = delta[i];
+		i += 1;
+		let a = read(&mut i)?;
+		let src = match op {
+			OP_COPY => {
+				let len = read(&mut i)?;
+				key.get(a..a.checked_add(len).ok_or_else(bad)?).ok_or_else(bad)?
+			},
+			OP_LITERAL => {
+				let src = delta.get(i..i.checked_add(a).ok_or_else(bad)?).ok_or_else(bad)?;
+				i += a;
+				src
+			},
+			_ => return Err(bad())
+		};
+		if res.len() + src.len() > size {
+			return Err(bad())
+		}
+		res.extend_from_slice(src);
+	}
+	if res.len() != size {
+		return Err(bad())
+	}
+	Ok(res)
+}
+
+/// A buffer of captured states with a fixed memory budget.  When full, the oldest frames are thrown away to make room.
+pub struct RewindBuffer {
+	capacity: usize,
+	keyframe_interval: usize,
+	/// Of all of the frames
+	used: usize,
+	frames: VecDeque<Frame>,
+	/// For deltas, if the newest keyframe is still in the buffer
+	base: Option<Base>,
+	/// The keyframe peek() last decompressed, and its index, which truncate() goes on making deltas against
+	peeked: Option<(usize, Vec<u8>)>,
+}
+impl RewindBuffer {
+	/// `capacity` is the most memory the buffer will use, in bytes.  Every `keyframe_interval`th frame is a keyframe;
+	/// 1 makes them all keyframes.
+	pub fn new(capacity: usize, keyframe_interval: usize) -> RewindBuffer {
+		RewindBuffer {
+			capacity,
+			keyframe_interval: std::cmp::max(keyframe_interval, 1),
+			used: 0,
+			frames: VecDeque::new(),
+			base: None,
+			peeked: None,
+		}
+	}
+	pub fn capacity(&self) -> usize {
+		self.capacity
+	}
+	pub fn keyframe_interval(&self) -> usize {
+		self.keyframe_interval
+	}
+	/// Everything the buffer holds, in bytes, including the uncompressed copy of the newest keyframe
+	pub fn used(&self) -> usize {
+		self.used + self.base.as_ref().map_or(0, |b| b.size())
+	}
+	/// Number of frames currently available to rewind to
+	pub fn len(&self) -> usize {
+		self.frames.len()
+	}
+	pub fn keyframes(&self) -> usize {
+		self.frames.iter().filter(|f| f.keyframe).count()
+	}
+	pub fn clear(&mut self) {
+		self.frames.clear();
+		self.used = 0;
+		self.base = None;
+		self.peeked = None;
+	}
+	/// Throw away the oldest keyframe and its deltas
+	fn evict_group(&mut self) {
+		self.peeked = None;
+		while let Some(f) = self.frames.pop_front() {
+			self.used -= f.data.len();
+			if self.frames.front().map(|f| f.keyframe) != Some(false) {
+				break
+			}
+		}
+		if self.frames.is_empty() {
+			self.base = None;
+		}
+	}
+	/// Add a new frame, evicting old ones as needed to stay within capacity.  A frame that can't fit even on its own
+	/// empties the buffer and is not stored.
+	pub fn push(&mut self, state: &[u8]) {
+		let deltas = self.frames.iter().rev().position(|f| f.keyframe);
+		if let (Some(base), Some(deltas)) = (self.base.as_ref(), deltas) {
+			if deltas + 1 < self.keyframe_interval {
+				let frame = Frame::new(false, &make_delta(state, base)[..], state.len());
+				// any group but the last one, which this is a part of
+				while self.keyframes() > 1 && self.used() + frame.data.len() > self.capacity {
+					self.evict_group();
+				}
+				if self.used() + frame.data.len() <= self.capacity {
+					self.used += frame.data.len();
+					self.frames.push_back(frame);
+					return
+				}
+			}
+		}
+		let frame = Frame::new(true, state, state.len());
+		let base = Base::new(state.to_vec());
+		self.base = None;
+		while !self.frames.is_empty() && self.used + frame.data.len() + base.size() > self.capacity {
+			self.evict_group();
+		}
+		if self.used + frame.data.len() + base.size() > self.capacity {
+			self.clear();
+			return
+		}
+		self.used += frame.data.len();
+		self.frames.push_back(frame);
+		self.base = Some(base);
+	}
+	/// The frame `n` frames back, and the keyframe it goes with
+	fn target(&self, n: usize) -> anyhow::Result<(usize, usize)> {
+		if n == 0 || n > self.frames.len() {
+			return Err(coded(ErrorCode::InvalidArgument, format!("Only {} rewind frames are available", self.frames.len())))
+		}
+		let target = self.frames.len() - n;
+		Ok((target, (0..=target).rev().find(|&i| self.frames[i].keyframe).unwrap()))
+	}
+	/// Returns the state captured `n` frames ago, leaving every frame where it is, so that nothing is lost if the state
+	/// then fails to load.  Fails if there are not that many frames available.
+	pub fn peek(&mut self, n: usize) -> anyhow::Result<Vec<u8>> {
+		let (target, key) = self.target(n)?;
+		let key_state = self.frames[key].decompress()?;
+		if key == target {
+			return Ok(key_state)
+		}
+		let frame = &self.frames[target];
+		let res = apply_delta(&frame.decompress()?[..], &key_state[..], frame.state_size)?;
+		self.peeked = Some((key, key_state));
+		Ok(res)
+	}
+	/// Removes the `n` most recently captured frames, once the oldest of them has been loaded.  Fails and does nothing
+	/// if there are not that many frames available.
+	pub fn truncate(&mut self, n: usize) -> anyhow::Result<()> {
+		let (target, key) = self.target(n)?;
+		self.base = if key == target {
+			None
+		} else {
+			match self.peeked.take() {
+				Some((i, state)) if i == key => Some(Base::new(state)),
+				_ => Some(Base::new(self.frames[key].decompress()?)),
+			}
+		};
+		self.peeked = None;
+		for f in self.frames.drain(target..) {
+			self.used -= f.data.len();
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	impl RewindBuffer {
+		fn rewind(&mut self, n: usize) -> anyhow::Result<Vec<u8>> {
+			let res = self.peek(n)?;
+			self.truncate(n)?;
+			Ok(res)
+		}
+	}
+
+	fn state(seed: u8, len: usize) -> Vec<u8> {
+		(0..len).map(|i| (i as u32).wrapping_mul(2654435761).to_le_bytes()[3] ^ seed).collect()
+	}
+
+	#[test]
+	fn test_delta() -> anyhow::Result<()> {
+		let key = state(0, 0x10000);
+		let base = Base::new(key.clone());
+		// a few bytes changed in place, a page that wasn't there before, and the end cut off partway into a block
+		let mut next = key.clone();
+		next[100] ^= 1;
+		next[5000] ^= 1;
+		let mut page = state(7, 0x1000);
+		next.splice(0x8000..0x8000, page.drain(..));
+		next.truncate(0xf123);
+		let delta = make_delta(&next[..], &base);
+		assert!(delta.len() < 0x1200);
+		assert_eq!(apply_delta(&delta[..], &key[..], next.len())?, next);
+		assert_eq!(ErrorCode::of(&apply_delta(&delta[..], &key[..], next.len() + 1).unwrap_err()), ErrorCode::BadStateData);
+		let empty = make_delta(&[], &base);
+		assert_eq!(apply_delta(&empty[..], &key[..], 0)?, Vec::<u8>::new());
+		Ok(())
+	}
+
+	#[test]
+	fn test_rewind() -> anyhow::Result<()> {
+		let frames = (0..10u8).map(|i| {
+			let mut s = state(0, 0x4000);
+			s[i as usize * 100] = i;
+			s
+		}).collect::<Vec<_>>();
+		let mut r = RewindBuffer::new(1 << 20, 4);
+		for f in frames.iter() {
+			r.push(&f[..]);
+		}
+		assert_eq!(r.len(), 10);
+		assert_eq!(r.keyframes(), 3);
+		assert_eq!(ErrorCode::of(&r.rewind(11).unwrap_err()), ErrorCode::InvalidArgument);
+		assert!(r.rewind(0).is_err());
+		assert_eq!(r.rewind(1)?, frames[9]);
+		assert_eq!(r.rewind(2)?, frames[7]);
+		assert_eq!(r.len(), 7);
+		// groups go on from where they were
+		r.push(&frames[9][..]);
+		assert_eq!(r.keyframes(), 2);
+		assert_eq!(r.rewind(1)?, frames[9]);
+		// and rewinding to a keyframe starts a new one
+		assert_eq!(r.rewind(3)?, frames[4]);
+		r.push(&frames[5][..]);
+		assert_eq!(r.keyframes(), 2);
+		assert_eq!(r.rewind(1)?, frames[5]);
+		// peeking takes nothing out, and deltas are made against the right keyframe after truncating without one
+		assert_eq!(r.peek(2)?, frames[2]);
+		assert_eq!(r.peek(1)?, frames[3]);
+		assert_eq!(r.len(), 4);
+		r.truncate(1)?;
+		r.push(&frames[8][..]);
+		assert_eq!(r.rewind(1)?, frames[8]);
+		assert!(r.truncate(6).is_err());
+
+		// only whole groups are evicted, and everything counts against capacity
+		let mut r = RewindBuffer::new(1 << 20, 4);
+		r.push(&frames[0][..]);
+		let mut r = RewindBuffer::new(r.used() + 1000, 4);
+		for f in frames.iter() {
+			r.push(&f[..]);
+			assert!(r.used() <= r.capacity());
+			assert!(r.frames[0].keyframe);
+		}
+		let kept = r.len();
+		assert!(kept < 10);
+		assert_eq!(r.rewind(kept)?, frames[10 - kept]);
+		r.push(&vec![1; 1 << 20][..]);
+		assert_eq!(r.len(), 0);
+		assert_eq!(r.used(), 0);
+		Ok(())
+	}
+}
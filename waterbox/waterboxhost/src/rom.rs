@@ -0,0 +1,75 @@
+// ROM images the frontend has the host guard, once the core has loaded them into guest memory.  A core that scribbles
+// over its ROM by accident is hard to catch, since nothing reads back wrong until much later, so ROM regions are made
+// readonly for good:  The guest can't mprotect, remap or unmap them, and the frontend's writes stop short of them.
+// Each one has the SHA-256 it's meant to have, which the host checks when it's registered, when the host is activated,
+// and after a state is loaded, so a ROM that changed anyway is reported as Corrupted.  Not in savestates; frontends
+// register them again after the core loads its ROM.
+use crate::*;
+
+struct RomRegion {
+	addr: AddressRange,
+	hash: Vec<u8>,
+}
+
+#[derive(Default)]
+pub struct Roms {
+	regions: Vec<RomRegion>,
+}
+impl Roms {
+	/// Whether any page of `addr` is in a ROM region
+	pub fn overlaps(&self, addr: AddressRange) -> bool {
+		let end = match addr.checked_end() {
+			Some(end) => align_up(end),
+			None => usize::MAX,
+		};
+		let start = align_down(addr.start);
+		self.regions.iter().any(|r| r.addr.start < end && start < r.addr.end())
+	}
+	/// Add a region, which mustn't overlap another
+	pub fn add(&mut self, addr: AddressRange, hash: Vec<u8>) -> anyhow::Result<()> {
+		if self.overlaps(addr) {
+			return Err(coded(ErrorCode::InvalidArgument, format!("ROM at {:x} overlaps another one", addr.start)))
+		}
+		self.regions.push(RomRegion { addr, hash });
+		Ok(())
+	}
+	/// Forget the region that starts at `start`
+	pub fn remove(&mut self, start: usize) -> anyhow::Result<()> {
+		let before = self.regions.len();
+		self.regions.retain(|r| r.addr.start != start);
+		if self.regions.len() == before {
+			return Err(coded(ErrorCode::NotFound, format!("No ROM at {:x}", start)))
+		}
+		Ok(())
+	}
+	pub fn iter(&self) -> impl Iterator<Item = (AddressRange, &[u8])> {
+		self.regions.iter().map(|r| (r.addr, &r.hash[..]))
+	}
+	pub fn is_empty(&self) -> bool {
+		self.regions.is_empty()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_overlaps() -> anyhow::Result<()> {
+		let mut roms = Roms::default();
+		roms.add(AddressRange { start: 0x10000, size: 0x2000 }, vec![1])?;
+		assert!(roms.overlaps(AddressRange { start: 0x11fff, size: 1 }));
+		// a range that ends partway into a page covers all of it
+		assert!(roms.overlaps(AddressRange { start: 0xf000, size: 0x1001 }));
+		assert!(!roms.overlaps(AddressRange { start: 0xf000, size: 0x1000 }));
+		assert!(!roms.overlaps(AddressRange { start: 0x12000, size: 0x1000 }));
+		assert!(roms.overlaps(AddressRange { start: 0x11000, size: usize::MAX }));
+		assert_eq!(ErrorCode::of(&roms.add(AddressRange { start: 0x11000, size: 0x2000 }, vec![2]).unwrap_err()), ErrorCode::InvalidArgument);
+		roms.add(AddressRange { start: 0x12000, size: 0x1000 }, vec![2])?;
+		assert_eq!(roms.iter().map(|(a, h)| (a.start, h[0])).collect::<Vec<_>>(), vec![(0x10000, 1), (0x12000, 2)]);
+		roms.remove(0x10000)?;
+		assert_eq!(ErrorCode::of(&roms.remove(0x10000).unwrap_err()), ErrorCode::NotFound);
+		assert!(!roms.overlaps(AddressRange { start: 0x10000, size: 0x2000 }));
+		Ok(())
+	}
+}
@@ -0,0 +1,169 @@
+// Defense in depth against guest code that escapes the sandbox:  A seccomp filter restricting the thread a host runs on
+// to the syscalls the host itself makes, so that guest code making its own syscalls can't fork, exec, or open sockets
+// or anything else.  Filters belong to a thread and can never be removed, so this is opt-in; the frontend passes any
+// syscalls its own code on that thread needs.  Other syscalls fail with EPERM, and syscalls for a different ABI than
+// the host's, like x32 ones, kill the process.
+use crate::*;
+
+#[cfg(target_os = "linux")]
+mod filter {
+	use super::*;
+	use libc::{c_long, c_ulong};
+
+	#[repr(C)]
+	struct SockFilter {
+		code: u16,
+		jt: u8,
+		jf: u8,
+		k: u32,
+	}
+	#[repr(C)]
+	struct SockFprog {
+		len: u16,
+		filter: *const SockFilter,
+	}
+
+	const BPF_LD_W_ABS: u16 = 0x20;
+	const BPF_JEQ_K: u16 = 0x15;
+	const BPF_JGE_K: u16 = 0x35;
+	const BPF_JSET_K: u16 = 0x45;
+	const BPF_RET_K: u16 = 0x06;
+
+	const SECCOMP_RET_KILL_PROCESS: u32 = 0x80000000;
+	const SECCOMP_RET_ERRNO: u32 = 0x00050000;
+	const SECCOMP_RET_ALLOW: u32 = 0x7fff0000;
+
+	/// Offsets into struct seccomp_data
+	const DATA_NR: u32 = 0;
+	const DATA_ARCH: u32 = 4;
+	const DATA_ARG0: u32 = 16;
+
+	#[cfg(target_arch = "x86_64")]
+	const AUDIT_ARCH: u32 = 0xc000003e;
+	#[cfg(target_arch = "aarch64")]
+	const AUDIT_ARCH: u32 = 0xc00000b7;
+
+	#[cfg(target_arch = "x86_64")]
+	const SYS_RSEQ: c_long = 334;
+	#[cfg(target_arch = "aarch64")]
+	const SYS_RSEQ: c_long = 293;
+	const SYS_CLONE3: c_long = 435;
+
+	/// What the host calls, directly or through std and libc
+	const ALLOWED: &[c_long] = &[
+		libc::SYS_mmap, libc::SYS_munmap, libc::SYS_mprotect, libc::SYS_madvise, libc::SYS_mremap, libc::SYS_brk,
+		libc::SYS_memfd_create, libc::SYS_ftruncate, libc::SYS_fallocate, libc::SYS_userfaultfd, libc::SYS_mbind, libc::SYS_move_pages,
+		libc::SYS_futex, libc::SYS_set_robust_list, SYS_RSEQ, libc::SYS_sched_yield, libc::SYS_sched_getaffinity,
+		libc::SYS_exit, libc::SYS_exit_group, libc::SYS_gettid, libc::SYS_getpid, libc::SYS_tgkill,
+		libc::SYS_rt_sigaction, libc::SYS_rt_sigprocmask, libc::SYS_rt_sigreturn, libc::SYS_sigaltstack,
+		libc::SYS_clock_gettime, libc::SYS_clock_getres, libc::SYS_clock_nanosleep, libc::SYS_nanosleep, libc::SYS_gettimeofday,
+		libc::SYS_read, libc::SYS_write, libc::SYS_readv, libc::SYS_writev, libc::SYS_pread64, libc::SYS_pwrite64,
+		libc::SYS_openat, libc::SYS_close, libc::SYS_lseek, libc::SYS_fstat, libc::SYS_newfstatat, libc::SYS_statx,
+		libc::SYS_ioctl, libc::SYS_ppoll, libc::SYS_getrandom,
+		// on sockets that are already open
+		libc::SYS_recvfrom, libc::SYS_sendto, libc::SYS_shutdown,
+		#[cfg(target_arch = "x86_64")] libc::SYS_arch_prctl,
+		#[cfg(target_arch = "x86_64")] libc::SYS_open,
+		#[cfg(target_arch = "x86_64")] libc::SYS_poll,
+	];
+
+	fn stmt(code: u16, k: u32) -> SockFilter {
+		SockFilter { code, jt: 0, jf: 0, k }
+	}
+
+	/// The filter, allowing `extra` as well
+	fn program(extra: &[u32]) -> anyhow::Result<Vec<SockFilter>> {
+		let mut p = vec![
+			stmt(BPF_LD_W_ABS, DATA_ARCH),
+			SockFilter { code: BPF_JEQ_K, jt: 1, jf: 0, k: AUDIT_ARCH },
+			stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+			stmt(BPF_LD_W_ABS, DATA_NR),
+		];
+		// x32 syscalls are on the same arch
+		#[cfg(target_arch = "x86_64")]
+		{
+			p.push(SockFilter { code: BPF_JGE_K, jt: 0, jf: 1, k: 0x40000000 });
+			p.push(stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS));
+		}
+		// glibc only falls back to clone when clone3 doesn't exist
+		p.push(SockFilter { code: BPF_JEQ_K, jt: 0, jf: 1, k: SYS_CLONE3 as u32 });
+		p.push(stmt(BPF_RET_K, SECCOMP_RET_ERRNO | libc::ENOSYS as u32));
+		// every jump to the allow at the end, to be filled in
+		let mut allows = Vec::new();
+		for nr in ALLOWED.iter().map(|&n| n as u32).chain(extra.iter().cloned()) {
+			allows.push(p.len());
+			p.push(SockFilter { code: BPF_JEQ_K, jt: 0, jf: 0, k: nr });
+		}
+		// threads, but not processes
+		p.push(SockFilter { code: BPF_JEQ_K, jt: 0, jf: 2, k: libc::SYS_clone as u32 });
+		p.push(stmt(BPF_LD_W_ABS, DATA_ARG0));
+		allows.push(p.len());
+		p.push(SockFilter { code: BPF_JSET_K, jt: 0, jf: 0, k: libc::CLONE_THREAD as u32 });
+		p.push(stmt(BPF_RET_K, SECCOMP_RET_ERRNO | libc::EPERM as u32));
+		let allow = p.len();
+		p.push(stmt(BPF_RET_K, SECCOMP_RET_ALLOW));
+		for i in allows {
+			let distance = allow - i - 1;
+			if distance > u8::MAX as usize {
+				return Err(anyhow!("Too many syscalls to allow"))
+			}
+			p[i].jt = distance as u8;
+		}
+		Ok(p)
+	}
+
+	pub fn install(extra: &[u32]) -> anyhow::Result<()> {
+		let p = program(extra)?;
+		let prog = SockFprog { len: p.len() as u16, filter: p.as_ptr() };
+		unsafe {
+			if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1 as c_ulong, 0 as c_ulong, 0 as c_ulong, 0 as c_ulong) != 0 {
+				return Err(anyhow!("PR_SET_NO_NEW_PRIVS failed: {}", std::io::Error::last_os_error()))
+			}
+			if libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER as c_ulong, &prog as *const SockFprog as c_ulong, 0 as c_ulong, 0 as c_ulong) != 0 {
+				return Err(anyhow!("Installing the seccomp filter failed: {}", std::io::Error::last_os_error()))
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Restrict the current thread, and any threads it creates from now on, to the syscalls the host makes and `extra`.
+/// This can't be undone.
+#[cfg(target_os = "linux")]
+pub fn install(extra: &[u32]) -> anyhow::Result<()> {
+	filter::install(extra)
+}
+#[cfg(not(target_os = "linux"))]
+pub fn install(_extra: &[u32]) -> anyhow::Result<()> {
+	Err(coded(ErrorCode::Unsupported, "Syscall filtering is only available on Linux"))
+}
+
+#[cfg(test)]
+#[cfg(target_os = "linux")]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_filter() {
+		let res = std::thread::spawn(|| {
+			install(&[libc::SYS_getppid as u32]).unwrap();
+			unsafe {
+				let uname = libc::syscall(libc::SYS_uname, std::ptr::null_mut::<u8>());
+				let uname_err = *libc::__errno_location();
+				let ppid = libc::syscall(libc::SYS_getppid);
+				let fork = libc::syscall(libc::SYS_clone, libc::SIGCHLD as libc::c_ulong, 0usize, 0usize, 0usize, 0usize);
+				let fork_err = *libc::__errno_location();
+				// a thread can still be started
+				let thread = std::thread::spawn(|| libc::getpid()).join().unwrap();
+				(uname, uname_err, ppid, fork, fork_err, thread)
+			}
+		}).join().unwrap();
+		assert_eq!((res.0, res.1), (-1, libc::EPERM));
+		assert_eq!(res.2, unsafe { libc::getppid() } as libc::c_long);
+		assert_eq!((res.3, res.4), (-1, libc::EPERM));
+		assert_eq!(res.5, unsafe { libc::getpid() });
+		// the filter was only for that thread
+		let mut name = unsafe { std::mem::zeroed::<libc::utsname>() };
+		assert_eq!(unsafe { libc::uname(&mut name) }, 0);
+	}
+}
@@ -0,0 +1,250 @@
+// The registers a signal handler sees, which every platform keeps in different places.  Handlers get the context as
+// an untyped pointer, and everything that reads it or changes what the thread does after returning goes through here.
+use crate::*;
+use libc::siginfo_t;
+use std::ffi::c_void;
+use threading::GuestContext;
+
+/// Page fault error code bits, in x86's format whatever the platform
+pub const FAULT_WRITE: u64 = 2;
+pub const FAULT_EXECUTE: u64 = 0x10;
+/// In rflags
+#[cfg(target_arch = "x86_64")]
+const TRAP_FLAG: u64 = 0x100;
+
+/// The registers that handlers care about
+#[cfg(target_arch = "x86_64")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reg {
+	Rax, Rbx, Rcx, Rdx, Rsi, Rdi, Rbp, Rsp, R8, R9, R10, R11, R12, R13, R14, R15, Rip, Rflags,
+}
+
+/// The code-, fs- and gs- segment selectors
+#[cfg(target_arch = "x86_64")]
+pub struct Segments {
+	pub cs: u64,
+	pub fs: u64,
+	pub gs: u64,
+}
+
+pub use imp::*;
+#[cfg(target_arch = "x86_64")]
+pub use x86::*;
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+	use super::*;
+
+	/// unsafe: `ucontext` must be the one passed to a running handler
+	pub unsafe fn pc<'a>(ucontext: *mut c_void) -> &'a mut u64 {
+		reg(ucontext, Reg::Rip)
+	}
+	/// Have the thread continue from `ctx` when the handler returns, as if it had been switched to
+	pub unsafe fn resume(ucontext: *mut c_void, ctx: &GuestContext) {
+		for &(r, val) in [(Reg::Rbx, ctx.rbx), (Reg::Rbp, ctx.rbp), (Reg::R12, ctx.r12), (Reg::R13, ctx.r13),
+			(Reg::R14, ctx.r14), (Reg::R15, ctx.r15), (Reg::Rip, ctx.rip), (Reg::Rsp, ctx.rsp), (Reg::Rax, ctx.rax)].iter() {
+			*reg(ucontext, r) = val as u64;
+		}
+	}
+	pub unsafe fn user_regs(ucontext: *mut c_void) -> coredump::UserRegs {
+		let g = |r: Reg| *reg(ucontext, r);
+		let seg = segments(ucontext);
+		coredump::UserRegs {
+			r15: g(Reg::R15), r14: g(Reg::R14), r13: g(Reg::R13), r12: g(Reg::R12), rbp: g(Reg::Rbp), rbx: g(Reg::Rbx),
+			r11: g(Reg::R11), r10: g(Reg::R10), r9: g(Reg::R9), r8: g(Reg::R8), rax: g(Reg::Rax), rcx: g(Reg::Rcx),
+			rdx: g(Reg::Rdx), rsi: g(Reg::Rsi), rdi: g(Reg::Rdi), orig_rax: !0, rip: g(Reg::Rip), cs: seg.cs,
+			eflags: g(Reg::Rflags), rsp: g(Reg::Rsp), ss: 0, fs_base: 0, gs_base: 0, ds: 0, es: 0,
+			fs: seg.fs, gs: seg.gs,
+		}
+	}
+	/// Have the thread continue with the general purpose registers, rip and flags in `regs`.  The trap flag stays as
+	/// it was, since single stepping belongs to the host.
+	pub unsafe fn set_user_regs(ucontext: *mut c_void, regs: &coredump::UserRegs) {
+		for &(r, val) in [(Reg::Rax, regs.rax), (Reg::Rbx, regs.rbx), (Reg::Rcx, regs.rcx), (Reg::Rdx, regs.rdx),
+			(Reg::Rsi, regs.rsi), (Reg::Rdi, regs.rdi), (Reg::Rbp, regs.rbp), (Reg::Rsp, regs.rsp), (Reg::R8, regs.r8),
+			(Reg::R9, regs.r9), (Reg::R10, regs.r10), (Reg::R11, regs.r11), (Reg::R12, regs.r12), (Reg::R13, regs.r13),
+			(Reg::R14, regs.r14), (Reg::R15, regs.r15), (Reg::Rip, regs.rip)].iter() {
+			*reg(ucontext, r) = val;
+		}
+		let flags = reg(ucontext, Reg::Rflags);
+		*flags = (regs.eflags & !TRAP_FLAG) | (*flags & TRAP_FLAG);
+	}
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+mod imp {
+	use super::*;
+	use libc::*;
+
+	unsafe fn gregs<'a>(ucontext: *mut c_void) -> &'a mut [greg_t; 23] {
+		&mut (*(ucontext as *mut ucontext_t)).uc_mcontext.gregs
+	}
+
+	/// unsafe: `ucontext` must be the one passed to a running handler
+	pub unsafe fn reg<'a>(ucontext: *mut c_void, r: Reg) -> &'a mut u64 {
+		let index = match r {
+			Reg::Rax => REG_RAX, Reg::Rbx => REG_RBX, Reg::Rcx => REG_RCX, Reg::Rdx => REG_RDX,
+			Reg::Rsi => REG_RSI, Reg::Rdi => REG_RDI, Reg::Rbp => REG_RBP, Reg::Rsp => REG_RSP,
+			Reg::R8 => REG_R8, Reg::R9 => REG_R9, Reg::R10 => REG_R10, Reg::R11 => REG_R11,
+			Reg::R12 => REG_R12, Reg::R13 => REG_R13, Reg::R14 => REG_R14, Reg::R15 => REG_R15,
+			Reg::Rip => REG_RIP, Reg::Rflags => REG_EFL,
+		};
+		&mut *(&mut gregs(ucontext)[index as usize] as *mut greg_t as *mut u64)
+	}
+	pub unsafe fn segments(ucontext: *mut c_void) -> Segments {
+		let csgsfs = gregs(ucontext)[REG_CSGSFS as usize] as u64;
+		Segments { cs: csgsfs & 0xffff, gs: csgsfs >> 16 & 0xffff, fs: csgsfs >> 32 & 0xffff }
+	}
+	/// The page fault error code of a SIGSEGV
+	pub unsafe fn fault_error(ucontext: *mut c_void) -> u64 {
+		gregs(ucontext)[REG_ERR as usize] as u64
+	}
+	pub unsafe fn fault_address(info: *const siginfo_t) -> usize {
+		(*info).si_addr() as usize
+	}
+}
+
+#[cfg(target_os = "macos")]
+mod imp {
+	use super::*;
+
+	// <mach/i386/_structs.h> and <sys/_types/_ucontext.h>, for x86_64
+	#[repr(C)]
+	struct ExceptionState {
+		trapno: u16,
+		cpu: u16,
+		err: u32,
+		faultvaddr: u64,
+	}
+	#[repr(C)]
+	struct ThreadState {
+		rax: u64, rbx: u64, rcx: u64, rdx: u64, rdi: u64, rsi: u64, rbp: u64, rsp: u64,
+		r8: u64, r9: u64, r10: u64, r11: u64, r12: u64, r13: u64, r14: u64, r15: u64,
+		rip: u64, rflags: u64, cs: u64, fs: u64, gs: u64,
+	}
+	/// Followed by the float state, which nothing here touches
+	#[repr(C)]
+	struct MContext {
+		es: ExceptionState,
+		ss: ThreadState,
+	}
+	#[repr(C)]
+	struct UContext {
+		onstack: i32,
+		sigmask: u32,
+		stack: libc::stack_t,
+		link: *mut c_void,
+		mcsize: usize,
+		mcontext: *mut MContext,
+	}
+
+	unsafe fn mcontext<'a>(ucontext: *mut c_void) -> &'a mut MContext {
+		&mut *(*(ucontext as *mut UContext)).mcontext
+	}
+
+	/// unsafe: `ucontext` must be the one passed to a running handler
+	pub unsafe fn reg<'a>(ucontext: *mut c_void, r: Reg) -> &'a mut u64 {
+		let s = &mut mcontext(ucontext).ss;
+		match r {
+			Reg::Rax => &mut s.rax, Reg::Rbx => &mut s.rbx, Reg::Rcx => &mut s.rcx, Reg::Rdx => &mut s.rdx,
+			Reg::Rsi => &mut s.rsi, Reg::Rdi => &mut s.rdi, Reg::Rbp => &mut s.rbp, Reg::Rsp => &mut s.rsp,
+			Reg::R8 => &mut s.r8, Reg::R9 => &mut s.r9, Reg::R10 => &mut s.r10, Reg::R11 => &mut s.r11,
+			Reg::R12 => &mut s.r12, Reg::R13 => &mut s.r13, Reg::R14 => &mut s.r14, Reg::R15 => &mut s.r15,
+			Reg::Rip => &mut s.rip, Reg::Rflags => &mut s.rflags,
+		}
+	}
+	pub unsafe fn segments(ucontext: *mut c_void) -> Segments {
+		let s = &mcontext(ucontext).ss;
+		Segments { cs: s.cs, fs: s.fs, gs: s.gs }
+	}
+	/// The page fault error code of a SIGSEGV or SIGBUS
+	pub unsafe fn fault_error(ucontext: *mut c_void) -> u64 {
+		mcontext(ucontext).es.err as u64
+	}
+	pub unsafe fn fault_address(info: *const siginfo_t) -> usize {
+		(*info).si_addr as usize
+	}
+}
+
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+mod imp {
+	use super::*;
+
+	// <asm/sigcontext.h> and <asm/ucontext.h>
+	#[repr(C, align(16))]
+	struct SigContext {
+		fault_address: u64,
+		regs: [u64; 31],
+		sp: u64,
+		pc: u64,
+		pstate: u64,
+		/// A list of records, each starting with a magic number and a size, ending with a zero magic
+		reserved: [u8; 4096],
+	}
+	#[repr(C)]
+	struct UContext {
+		flags: u64,
+		link: *mut c_void,
+		stack: libc::stack_t,
+		/// The kernel's sigset_t, padded out for future expansion
+		sigmask: [u8; 128],
+		mcontext: SigContext,
+	}
+	const ESR_MAGIC: u32 = 0x45535201;
+
+	unsafe fn mcontext<'a>(ucontext: *mut c_void) -> &'a mut SigContext {
+		&mut (*(ucontext as *mut UContext)).mcontext
+	}
+
+	/// unsafe: `ucontext` must be the one passed to a running handler
+	pub unsafe fn pc<'a>(ucontext: *mut c_void) -> &'a mut u64 {
+		&mut mcontext(ucontext).pc
+	}
+	/// Have the thread continue from `ctx` when the handler returns, as if it had been switched to
+	pub unsafe fn resume(ucontext: *mut c_void, ctx: &GuestContext) {
+		let m = mcontext(ucontext);
+		for (i, &x) in ctx.x.iter().enumerate() {
+			m.regs[19 + i] = x as u64;
+		}
+		m.regs[29] = ctx.fp as u64;
+		m.regs[30] = ctx.pc as u64;
+		m.regs[0] = ctx.x0 as u64;
+		m.pc = ctx.pc as u64;
+		m.sp = ctx.sp as u64;
+	}
+	/// Core dumps are always laid out for x86_64, so this only fills in what crash reports use:  The pc, the stack
+	/// pointer, and the frame pointer, whose frame records chain the same way rbp's do.
+	pub unsafe fn user_regs(ucontext: *mut c_void) -> coredump::UserRegs {
+		let m = mcontext(ucontext);
+		coredump::UserRegs { rip: m.pc, rsp: m.sp, rbp: m.regs[29], rax: m.regs[0], orig_rax: !0, ..Default::default() }
+	}
+	/// The page fault error code of a SIGSEGV, made out of the exception syndrome the kernel passes along
+	pub unsafe fn fault_error(ucontext: *mut c_void) -> u64 {
+		let reserved = &mcontext(ucontext).reserved;
+		let mut i = 0;
+		while i + 16 <= reserved.len() {
+			let magic = u32::from_ne_bytes([reserved[i], reserved[i + 1], reserved[i + 2], reserved[i + 3]]);
+			let size = u32::from_ne_bytes([reserved[i + 4], reserved[i + 5], reserved[i + 6], reserved[i + 7]]) as usize;
+			if magic == 0 || size < 8 {
+				break
+			}
+			if magic == ESR_MAGIC {
+				let mut esr = [0u8; 8];
+				esr.copy_from_slice(&reserved[i + 8..i + 16]);
+				let esr = u64::from_ne_bytes(esr);
+				return match esr >> 26 {
+					// instruction aborts
+					0x20 | 0x21 => FAULT_EXECUTE,
+					// data aborts, with WnR set for writes
+					0x24 | 0x25 if esr & 0x40 != 0 => FAULT_WRITE,
+					_ => 0,
+				}
+			}
+			i += size;
+		}
+		0
+	}
+	pub unsafe fn fault_address(info: *const siginfo_t) -> usize {
+		(*info).si_addr() as usize
+	}
+}
@@ -0,0 +1,113 @@
+// What a native guest's _start is passed:  A pointer to a block laid out like the stack a Linux process starts with,
+// which is what musl's _start_c reads.  That's argc, the argv pointers and a null, the envp pointers and a null, and
+// then the auxiliary vector, with the strings after all that.  Every word is the guest's pointer width.  The arguments
+// and environment variables are whatever the frontend set before creating the host.  The block is mapped in the mmap
+// area before the guest runs, so it's guest memory like any other, and in states.
+use crate::*;
+use crate::abi::GuestAbi;
+use crate::memory_block::{ActivatedMemoryBlock, Protection};
+use lazy_static::lazy_static;
+use std::ffi::CString;
+use std::sync::Mutex;
+
+const AT_NULL: usize = 0;
+const AT_PAGESZ: usize = 6;
+
+/// The arguments and environment variables a guest starts with
+#[derive(Clone, Default)]
+pub struct StartInfo {
+	pub args: Vec<CString>,
+	pub env: Vec<CString>,
+}
+
+lazy_static! {
+	/// What hosts created from now on start their guests with
+	static ref START_INFO: Mutex<StartInfo> = Mutex::new(StartInfo::default());
+}
+
+/// Check that every one of `vars` looks like NAME=value
+fn check_environment(vars: &[CString]) -> anyhow::Result<()> {
+	for v in vars.iter() {
+		match v.as_bytes().iter().position(|&c| c == b'=') {
+			Some(i) if i > 0 => (),
+			_ => return Err(coded(ErrorCode::InvalidArgument, format!("Environment variable {:?} isn't NAME=value", v))),
+		}
+	}
+	Ok(())
+}
+/// Start the guests of hosts created from now on with environment variables `vars`
+pub fn set_environment(vars: Vec<CString>) -> anyhow::Result<()> {
+	check_environment(&vars)?;
+	START_INFO.lock().unwrap().env = vars;
+	Ok(())
+}
+/// Start the guests of hosts created from now on with arguments `args`, argv[0] included
+pub fn set_arguments(args: Vec<CString>) {
+	START_INFO.lock().unwrap().args = args;
+}
+pub fn start_info() -> StartInfo {
+	START_INFO.lock().unwrap().clone()
+}
+
+/// Map a start block for `info` somewhere in `arena`, and return its address
+pub fn write_start_block(info: &StartInfo, abi: GuestAbi, arena: AddressRange, b: &mut ActivatedMemoryBlock) -> anyhow::Result<usize> {
+	let word = abi.pointer_size();
+	// argc, argv and its null, envp and its null, and two auxv entries
+	let words = 1 + info.args.len() + 1 + info.env.len() + 1 + 4;
+	let strings = info.args.iter().chain(info.env.iter()).map(|v| v.as_bytes_with_nul().len()).sum::<usize>();
+	let size = align_up(words * word + strings);
+	let addr = b.mmap(AddressRange { start: 0, size }, Protection::RW, arena, false)?;
+	let mut w = addr;
+	let mut s = addr + words * word;
+	let mut push = |value: usize| {
+		unsafe { abi.write_pointer(w, value); }
+		w += word;
+	};
+	push(info.args.len());
+	for list in [&info.args, &info.env].iter() {
+		for v in list.iter() {
+			let bytes = v.as_bytes_with_nul();
+			unsafe { AddressRange { start: s, size: bytes.len() }.slice_mut().copy_from_slice(bytes); }
+			push(s);
+			s += bytes.len();
+		}
+		push(0);
+	}
+	push(AT_PAGESZ);
+	push(PAGESIZE);
+	push(AT_NULL);
+	push(0);
+	Ok(addr)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::memory_block::MemoryBlock;
+
+	#[test]
+	fn test_start_block() -> anyhow::Result<()> {
+		let info = StartInfo {
+			args: vec![CString::new("mame")?, CString::new("-verbose")?],
+			env: vec![CString::new("HOME=/")?, CString::new("CORE_DEBUG=1")?],
+		};
+		assert!(check_environment(&info.env).is_ok());
+		assert!(check_environment(&[CString::new("NOVALUE")?]).is_err());
+		assert!(check_environment(&[CString::new("=x")?]).is_err());
+
+		let addr = AddressRange { start: 0x38a00000000, size: 0x10000 };
+		let mut b = MemoryBlock::new(addr);
+		let mut g = b.enter();
+		let p = write_start_block(&info, GuestAbi::Lp64, addr, &mut g)?;
+		let words = unsafe { std::slice::from_raw_parts(p as *const usize, 11) };
+		let string = |i: usize| unsafe { std::ffi::CStr::from_ptr(words[i] as *const libc::c_char) }.to_bytes();
+		assert_eq!(words[0], 2);
+		assert_eq!(string(1), b"mame");
+		assert_eq!(string(2), b"-verbose");
+		assert_eq!(words[3], 0);
+		assert_eq!(string(4), b"HOME=/");
+		assert_eq!(string(5), b"CORE_DEBUG=1");
+		assert_eq!(words[6..], [0, AT_PAGESZ, PAGESIZE, AT_NULL, 0]);
+		Ok(())
+	}
+}
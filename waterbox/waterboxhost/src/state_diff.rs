@@ -0,0 +1,82 @@
+// Summaries of how two states for the same guest differ, so a desync can be narrowed down without diffing whole states
+// with a hex editor.  Guest memory is compared page by page, and what differs is totalled up for each area of the
+// memory layout.  Everything else in a state is the host's own, and only how many bytes of it differ is reported.
+use crate::*;
+use memory_block::PageDiff;
+
+/// How much two states differ in one area of guest memory
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RegionDiff {
+	pub pages: usize,
+	pub bytes: u64,
+}
+
+/// What wbx_compare_states() reports, along with a PageDiff for every page that differs
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StateDiffInfo {
+	/// Bytes that differ in the host's part of the states:  files, threads, the clock and so on
+	pub host_bytes: u64,
+	/// Pages that differ, in all
+	pub pages: usize,
+	pub elf: RegionDiff,
+	pub sbrk: RegionDiff,
+	pub sealed: RegionDiff,
+	pub invis: RegionDiff,
+	pub plain: RegionDiff,
+	pub mmap: RegionDiff,
+}
+
+/// Total up `pages` for each area of `layout`
+pub fn summarize(layout: &WbxSysLayout, host_bytes: usize, pages: &[PageDiff]) -> StateDiffInfo {
+	let mut res = StateDiffInfo { host_bytes: host_bytes as u64, pages: pages.len(), ..Default::default() };
+	for p in pages.iter() {
+		let region = if layout.elf.contains(p.addr) {
+			&mut res.elf
+		} else if layout.sbrk.contains(p.addr) {
+			&mut res.sbrk
+		} else if layout.sealed.contains(p.addr) {
+			&mut res.sealed
+		} else if layout.invis.contains(p.addr) {
+			&mut res.invis
+		} else if layout.plain.contains(p.addr) {
+			&mut res.plain
+		} else {
+			&mut res.mmap
+		};
+		region.pages += 1;
+		region.bytes += p.bytes as u64;
+	}
+	res
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_summarize() {
+		let range = |start: usize| AddressRange { start, size: 0x10000 };
+		let layout = WbxSysLayout {
+			elf: range(0x10000),
+			sbrk: range(0x20000),
+			sealed: range(0x30000),
+			invis: range(0x40000),
+			plain: range(0x50000),
+			mmap: range(0x60000),
+		};
+		let pages = [
+			PageDiff { addr: 0x10000, bytes: 3, status_changed: false },
+			PageDiff { addr: 0x21000, bytes: PAGESIZE as u32, status_changed: false },
+			PageDiff { addr: 0x22000, bytes: 1, status_changed: false },
+			PageDiff { addr: 0x6f000, bytes: 0, status_changed: true },
+		];
+		let res = summarize(&layout, 7, &pages);
+		assert_eq!((res.host_bytes, res.pages), (7, 4));
+		assert_eq!(res.elf, RegionDiff { pages: 1, bytes: 3 });
+		assert_eq!(res.sbrk, RegionDiff { pages: 2, bytes: PAGESIZE as u64 + 1 });
+		assert_eq!(res.sealed, RegionDiff::default());
+		assert_eq!(res.mmap, RegionDiff { pages: 1, bytes: 0 });
+	}
+}
@@ -0,0 +1,389 @@
+// The header at the start of every savestate, and what's done with states from older versions of the host.  The
+// header says which core made the state and how, so that a state that can't be loaded is turned away with a reason
+// before anything reads garbage from it.  Whenever what the host writes in states changes, VERSION goes up by one, and
+// a migration is added that rewrites the rest of a state from the old version into the new one.
+use crate::*;
+use std::fmt;
+use fpenv::FpEnv;
+
+const MAGIC: &str = "WaterboxState";
+/// What states started with before they had headers
+const LEGACY_MAGIC: &str = "ActivatedWaterboxHost_v1";
+pub const VERSION: u32 = 13;
+
+/// The state has mmap randomization's generator in it
+pub const FEATURE_MMAP_RANDOMIZATION: u32 = 1;
+
+/// Rewrites everything in a state after the header from one version into the next
+type Migration = fn(Vec<u8>) -> anyhow::Result<Vec<u8>>;
+/// MIGRATIONS[i] takes a state from version VERSION - MIGRATIONS.len() + i up to the next one
+const MIGRATIONS: &[Migration] = &[from_v2, from_v3, from_v4, from_v5, from_v6, from_v7, from_v8, from_v9, from_v10, from_v11, from_v12];
+
+/// Version 3 only added padding to the header
+fn from_v2(body: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+	Ok(body)
+}
+
+/// Version 4 put the guest's floating point environment first, which was always the default before
+fn from_v3(body: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+	let mut res = Vec::with_capacity(body.len() + std::mem::size_of::<FpEnv>());
+	bin::write(&mut res, &FpEnv::DEFAULT)?;
+	res.extend_from_slice(&body[..]);
+	Ok(res)
+}
+
+/// Version 5 added how many memory calls the guest had made, after the floating point environment.  Older states
+/// count from 0.
+fn from_v4(body: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+	let at = std::cmp::min(std::mem::size_of::<FpEnv>(), body.len());
+	let mut res = Vec::with_capacity(body.len() + 8);
+	res.extend_from_slice(&body[..at]);
+	bin::write(&mut res, &0u64)?;
+	res.extend_from_slice(&body[at..]);
+	Ok(res)
+}
+
+/// Version 6 only added the core's identity to the header
+fn from_v5(body: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+	Ok(body)
+}
+
+/// Version 7 only added how many times the host had been sealed to the header
+fn from_v6(body: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+	Ok(body)
+}
+
+/// Version 8 added the guest's descriptor table to the file system, which the file system makes up itself for older
+/// states
+fn from_v7(body: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+	Ok(body)
+}
+
+/// Version 9 only added the guest's scratch files in /tmp to the end of the file system
+fn from_v8(body: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+	Ok(body)
+}
+
+/// Version 10 added NR_WBX_ENTROPY's stream after the count of memory calls.  Older states get it as if unseeded.
+fn from_v9(body: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+	let at = std::cmp::min(std::mem::size_of::<FpEnv>() + 8, body.len());
+	let mut res = Vec::with_capacity(body.len() + 128);
+	res.extend_from_slice(&body[..at]);
+	entropy::Entropy::new().save_state(&mut res)?;
+	res.extend_from_slice(&body[at..]);
+	Ok(res)
+}
+
+/// Version 11 added the guest's arenas after NR_WBX_ENTROPY's stream, which older states have none of
+fn from_v10(body: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+	let mut entropy = Vec::new();
+	entropy::Entropy::new().save_state(&mut entropy)?;
+	let at = std::cmp::min(std::mem::size_of::<FpEnv>() + 8 + entropy.len(), body.len());
+	let mut res = Vec::with_capacity(body.len() + 16);
+	res.extend_from_slice(&body[..at]);
+	arenas::Arenas::default().save_state(&mut res)?;
+	res.extend_from_slice(&body[at..]);
+	Ok(res)
+}
+
+/// Version 12 added the guest's fibers after its arenas, which older states have none of
+fn from_v11(body: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+	let mut entropy = Vec::new();
+	entropy::Entropy::new().save_state(&mut entropy)?;
+	let mut at = std::cmp::min(std::mem::size_of::<FpEnv>() + 8 + entropy.len(), body.len());
+	let mut rest = &body[at..];
+	arenas::Arenas::default().load_state(&mut rest)?;
+	at = body.len() - rest.len();
+	let mut res = Vec::with_capacity(body.len() + 32);
+	res.extend_from_slice(&body[..at]);
+	fibers::Fibers::default().save_state(&mut res)?;
+	res.extend_from_slice(&body[at..]);
+	Ok(res)
+}
+
+/// Version 13 added the guest's /proc files, and where it's read up to in the open ones, to the end of the file
+/// system, which has them all closed for states without them
+fn from_v12(body: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+	Ok(body)
+}
+
+/// Which core made a state, and how the host had laid it out, for saying what's different about a state that can't be
+/// loaded.  Only the hash and the layout have to match; the rest is for people.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoreIdentity {
+	/// What the frontend called the core when it made the host
+	pub name: String,
+	/// As the frontend gave it with wbx_set_core_version, if it did
+	pub version: String,
+	/// bin::hash of the core's executable
+	pub image_hash: Vec<u8>,
+	/// The guest ABI, as GuestAbi's name
+	pub abi: String,
+	/// imports::IMPORTS_VERSION of the host that made the state
+	pub host_abi: u64,
+	pub layout: [AddressRange; 6],
+	/// How many times the host had been sealed, which is only more than 1 if it was unsealed to set up more
+	pub seals: u32,
+}
+impl fmt::Display for CoreIdentity {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "`{}`", self.name)?;
+		if !self.version.is_empty() {
+			write!(f, " {}", self.version)?;
+		}
+		let hash = self.image_hash.iter().take(4).map(|b| format!("{:02x}", b)).collect::<String>();
+		write!(f, " ({}, {}, waterbox ABI {})", hash, self.abi, self.host_abi)
+	}
+}
+
+/// The names of the areas in CoreIdentity::layout
+const LAYOUT_NAMES: [&str; 6] = ["elf", "sbrk", "sealed", "invis", "plain", "mmap"];
+
+pub fn layout_areas(layout: &WbxSysLayout) -> [AddressRange; 6] {
+	[layout.elf, layout.sbrk, layout.sealed, layout.invis, layout.plain, layout.mmap]
+}
+
+/// Why a state can't be loaded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateError {
+	NotAState,
+	/// From before states had versions
+	Unversioned,
+	TooNew { version: u32 },
+	/// Older than anything there's a migration from
+	TooOld { version: u32 },
+	/// With descriptions of the core that made the state and the one loading it, if the state says
+	WrongCore { state: Option<String>, host: String },
+	/// The same core, with one area of guest memory somewhere else
+	WrongLayout { area: &'static str, state: AddressRange, host: AddressRange },
+	/// The same core, sealed a different number of times
+	WrongSeal { state: u32, host: u32 },
+	WrongFeatures { state: u32, host: u32 },
+}
+impl fmt::Display for StateError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			StateError::NotAState => write!(f, "Not a waterbox savestate"),
+			StateError::Unversioned => write!(f, "Savestate is from an old version of waterbox that can't be loaded anymore"),
+			StateError::TooNew { version } => write!(f, "Savestate is version {}, but this waterbox only loads up to {}", version, VERSION),
+			StateError::TooOld { version } => write!(f, "Savestate version {} is too old to load", version),
+			StateError::WrongCore { state: Some(state), host } => write!(f, "Savestate was made by core {}, but this is core {}", state, host),
+			StateError::WrongCore { state: None, host } => write!(f, "Savestate was made by a different core than {}", host),
+			StateError::WrongLayout { area, state, host } => write!(f, "Savestate has the {} area at {:#x}+{:#x}, but this host has it at {:#x}+{:#x}",
+				area, state.start, state.size, host.start, host.size),
+			StateError::WrongSeal { state, host } => write!(f, "Savestate was made after the host was sealed {} times, but this host has been sealed {}", state, host),
+			StateError::WrongFeatures { state, host } => write!(f, "Savestate was made with features {:#x}, but this core has {:#x}", state, host),
+		}
+	}
+}
+impl std::error::Error for StateError {}
+
+fn write_string(stream: &mut dyn Write, s: &str) -> anyhow::Result<()> {
+	bin::writeval(stream, s.len() as u32)?;
+	stream.write_all(s.as_bytes())?;
+	Ok(())
+}
+fn read_string(stream: &mut dyn Read) -> anyhow::Result<String> {
+	let len = bin::readval::<u32>(stream)?;
+	let mut data = Vec::new();
+	stream.take(len as u64).read_to_end(&mut data)?;
+	if data.len() != len as usize {
+		return Err(StateError::NotAState.into())
+	}
+	Ok(String::from_utf8_lossy(&data[..]).into_owned())
+}
+
+/// What wbx_validate_state() found in a state this host can load
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StateCheck {
+	/// Of the state format; older ones are migrated when they're loaded
+	pub version: u32,
+	pub compressed: bool,
+	/// Uncompressed
+	pub size: u64,
+	/// Of guest memory in the state
+	pub pages: usize,
+}
+
+pub struct StateHeader {
+	pub version: u32,
+	/// bin::hash of the core's executable
+	pub image_hash: Vec<u8>,
+	pub features: u32,
+	/// Everything else about the core, in states from version 6 on
+	pub core: Option<CoreIdentity>,
+}
+impl StateHeader {
+	/// `following` is how many bytes will come between the header and the first page of guest memory.  The header is
+	/// padded so that page lands on a page boundary, which lets states be loaded by mapping them.
+	pub fn write(stream: &mut dyn Write, core: &CoreIdentity, features: u32, following: usize) -> anyhow::Result<()> {
+		let mut head = Vec::new();
+		bin::write_magic(&mut head, MAGIC)?;
+		bin::write(&mut head, &VERSION)?;
+		bin::write_hash(&mut head, &core.image_hash[..])?;
+		bin::write(&mut head, &features)?;
+		write_string(&mut head, &core.name)?;
+		write_string(&mut head, &core.version)?;
+		write_string(&mut head, &core.abi)?;
+		bin::write(&mut head, &core.host_abi)?;
+		for a in core.layout.iter() {
+			bin::writeval(&mut head, a.start as u64)?;
+			bin::writeval(&mut head, a.size as u64)?;
+		}
+		bin::write(&mut head, &core.seals)?;
+		let size = head.len() + std::mem::size_of::<u32>();
+		let padding = (PAGESIZE - (size + following) % PAGESIZE) % PAGESIZE;
+		bin::write(&mut head, &(padding as u32))?;
+		head.resize(head.len() + padding, 0);
+		stream.write_all(&head[..])?;
+		Ok(())
+	}
+	pub fn read(stream: &mut dyn Read) -> anyhow::Result<StateHeader> {
+		let mut magic = [0u8; MAGIC.len()];
+		stream.read_exact(&mut magic[..])?;
+		if &magic[..] != MAGIC.as_bytes() {
+			return Err(if LEGACY_MAGIC.as_bytes().starts_with(&magic[..]) {
+				StateError::Unversioned
+			} else {
+				StateError::NotAState
+			}.into())
+		}
+		let version = bin::readval(stream)?;
+		let mut image_hash = vec![0u8; 32];
+		stream.read_exact(&mut image_hash[..])?;
+		let features = bin::readval(stream)?;
+		let core = if version >= 6 {
+			let name = read_string(stream)?;
+			let core_version = read_string(stream)?;
+			let abi = read_string(stream)?;
+			let host_abi = bin::readval(stream)?;
+			let mut layout = [AddressRange { start: 0, size: 0 }; 6];
+			for a in layout.iter_mut() {
+				a.start = bin::readval::<u64>(stream)? as usize;
+				a.size = bin::readval::<u64>(stream)? as usize;
+			}
+			let seals = if version >= 7 { bin::readval(stream)? } else { 1 };
+			Some(CoreIdentity { name, version: core_version, image_hash: image_hash.clone(), abi, host_abi, layout, seals })
+		} else {
+			None
+		};
+		if version >= 3 {
+			let padding = bin::readval::<u32>(stream)?;
+			std::io::copy(&mut stream.take(padding as u64), &mut std::io::sink())?;
+		}
+		Ok(StateHeader { version, image_hash, features, core })
+	}
+	/// Make sure a state with this header can be loaded into a host running `core` with these features
+	pub fn check(&self, core: &CoreIdentity, features: u32) -> Result<(), StateError> {
+		if self.version > VERSION {
+			return Err(StateError::TooNew { version: self.version })
+		} else if VERSION - self.version > MIGRATIONS.len() as u32 {
+			return Err(StateError::TooOld { version: self.version })
+		} else if self.image_hash != core.image_hash {
+			return Err(StateError::WrongCore { state: self.core.as_ref().map(|c| c.to_string()), host: core.to_string() })
+		}
+		if let Some(c) = &self.core {
+			for (i, (&state, &host)) in c.layout.iter().zip(core.layout.iter()).enumerate() {
+				if state != host {
+					return Err(StateError::WrongLayout { area: LAYOUT_NAMES[i], state, host })
+				}
+			}
+			if c.seals != core.seals {
+				return Err(StateError::WrongSeal { state: c.seals, host: core.seals })
+			}
+		}
+		if self.features != features {
+			Err(StateError::WrongFeatures { state: self.features, host: features })
+		} else {
+			Ok(())
+		}
+	}
+}
+
+/// The rest of a state with this header, as the current version would have written it
+pub fn migrate<'a>(header: &StateHeader, stream: &'a mut dyn Read) -> anyhow::Result<Box<dyn Read + 'a>> {
+	migrate_with(header.version, stream, MIGRATIONS)
+}
+fn migrate_with<'a>(version: u32, stream: &'a mut dyn Read, migrations: &[Migration]) -> anyhow::Result<Box<dyn Read + 'a>> {
+	if version == VERSION {
+		return Ok(Box::new(stream))
+	}
+	let mut body = Vec::new();
+	stream.read_to_end(&mut body)?;
+	let first = migrations.len() - (VERSION - version) as usize;
+	for m in migrations[first..].iter() {
+		body = m(body)?;
+	}
+	Ok(Box::new(std::io::Cursor::new(body)))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_header() -> anyhow::Result<()> {
+		let range = |start: usize| AddressRange { start, size: 0x10000 };
+		let core = CoreIdentity {
+			name: "snes9x".to_string(),
+			version: "1.60".to_string(),
+			image_hash: bin::hash(b"core"),
+			abi: "Lp64".to_string(),
+			host_abi: 1,
+			layout: [range(0x10000), range(0x20000), range(0x30000), range(0x40000), range(0x50000), range(0x60000)],
+			seals: 1,
+		};
+		let mut state = Vec::new();
+		StateHeader::write(&mut state, &core, FEATURE_MMAP_RANDOMIZATION, 0x1234)?;
+		assert_eq!((state.len() + 0x1234) % PAGESIZE, 0);
+		state.push(1);
+		let mut rest = &state[..];
+		let header = StateHeader::read(&mut rest)?;
+		assert_eq!(rest, &[1]);
+		assert_eq!(header.version, VERSION);
+		assert_eq!(header.core.as_ref(), Some(&core));
+		assert_eq!(header.check(&core, FEATURE_MMAP_RANDOMIZATION), Ok(()));
+		let other = CoreIdentity { version: "1.61".to_string(), image_hash: bin::hash(b"other"), ..core.clone() };
+		let wrong = header.check(&other, FEATURE_MMAP_RANDOMIZATION).unwrap_err();
+		let short = |hash: &[u8]| hash[..4].iter().map(|b| format!("{:02x}", b)).collect::<String>();
+		assert_eq!(wrong.to_string(), format!("Savestate was made by core `snes9x` 1.60 ({}, Lp64, waterbox ABI 1), but this is core `snes9x` 1.61 ({}, Lp64, waterbox ABI 1)",
+			short(&core.image_hash), short(&other.image_hash)));
+		let mut moved = core.clone();
+		moved.layout[1].size = 0x20000;
+		assert_eq!(header.check(&moved, FEATURE_MMAP_RANDOMIZATION), Err(StateError::WrongLayout { area: "sbrk", state: range(0x20000), host: moved.layout[1] }));
+		let resealed = CoreIdentity { seals: 2, ..core.clone() };
+		assert_eq!(header.check(&resealed, FEATURE_MMAP_RANDOMIZATION), Err(StateError::WrongSeal { state: 1, host: 2 }));
+		assert_eq!(header.check(&core, 0), Err(StateError::WrongFeatures { state: 1, host: 0 }));
+		let old = StateHeader { version: VERSION - 1, core: None, ..header };
+		assert_eq!(old.check(&moved, 1), Ok(()));
+		assert_eq!(old.check(&other, 1), Err(StateError::WrongCore { state: None, host: other.to_string() }));
+		let old = StateHeader { version: VERSION - 12, ..old };
+		assert_eq!(old.check(&core, 1), Err(StateError::TooOld { version: VERSION - 12 }));
+		let new = StateHeader { version: VERSION + 1, ..old };
+		assert_eq!(new.check(&core, 1), Err(StateError::TooNew { version: VERSION + 1 }));
+
+		let err = |data: &[u8]| StateHeader::read(&mut &data[..]).err().unwrap().downcast::<StateError>().unwrap();
+		assert_eq!(err(b"ActivatedWaterboxHost_v1...."), StateError::Unversioned);
+		assert_eq!(err(b"something else entirely"), StateError::NotAState);
+		Ok(())
+	}
+
+	#[test]
+	fn test_migrate() -> anyhow::Result<()> {
+		let migrations: [Migration; 2] = [
+			|mut b| { b.push(1); Ok(b) },
+			|mut b| { b.push(2); Ok(b) },
+		];
+		let mut body = Vec::new();
+		migrate_with(VERSION - 2, &mut &b"x"[..], &migrations)?.read_to_end(&mut body)?;
+		assert_eq!(body, b"x\x01\x02");
+		body.clear();
+		migrate_with(VERSION - 1, &mut &b"x"[..], &migrations)?.read_to_end(&mut body)?;
+		assert_eq!(body, b"x\x02");
+		body.clear();
+		migrate_with(VERSION, &mut &b"x"[..], &migrations)?.read_to_end(&mut body)?;
+		assert_eq!(body, b"x");
+		Ok(())
+	}
+}
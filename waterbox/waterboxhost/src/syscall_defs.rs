@@ -277,6 +277,90 @@ pub struct KStat {
 	pub __unused2: i64,
 }
 
+#[repr(C)]
+#[derive(Default)]
+pub struct StatxTimestamp {
+	pub tv_sec: i64,
+	pub tv_nsec: u32,
+	pub __reserved: i32,
+}
+
+/// Kernel statx object
+#[repr(C)]
+#[derive(Default)]
+pub struct Statx {
+	pub stx_mask: u32,
+	pub stx_blksize: u32,
+	pub stx_attributes: u64,
+	pub stx_nlink: u32,
+	pub stx_uid: u32,
+	pub stx_gid: u32,
+	pub stx_mode: u16,
+	pub __spare0: u16,
+	pub stx_ino: u64,
+	pub stx_size: u64,
+	pub stx_blocks: u64,
+	pub stx_attributes_mask: u64,
+	pub stx_atime: StatxTimestamp,
+	pub stx_btime: StatxTimestamp,
+	pub stx_ctime: StatxTimestamp,
+	pub stx_mtime: StatxTimestamp,
+	pub stx_rdev_major: u32,
+	pub stx_rdev_minor: u32,
+	pub stx_dev_major: u32,
+	pub stx_dev_minor: u32,
+	pub __spare2: [u64; 14],
+}
+
+/// Everything in a KStat
+pub const STATX_BASIC_STATS: u32 = 0x7ff;
+
+pub const AT_FDCWD: i32 = -100;
+pub const RENAME_NOREPLACE: usize = 1;
+pub const AT_SYMLINK_NOFOLLOW: usize = 0x100;
+pub const AT_NO_AUTOMOUNT: usize = 0x800;
+pub const AT_EMPTY_PATH: usize = 0x1000;
+pub const AT_STATX_SYNC_TYPE: usize = 0x6000;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct PollFd {
+	pub fd: i32,
+	pub events: i16,
+	pub revents: i16,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Default)]
+pub struct EpollEvent {
+	pub events: u32,
+	pub data: u64,
+}
+
+pub const POLLIN: i16 = 0x1;
+pub const POLLPRI: i16 = 0x2;
+pub const POLLOUT: i16 = 0x4;
+pub const POLLERR: i16 = 0x8;
+pub const POLLHUP: i16 = 0x10;
+pub const POLLNVAL: i16 = 0x20;
+
+pub const EPOLL_CTL_ADD: i32 = 1;
+pub const EPOLL_CTL_DEL: i32 = 2;
+pub const EPOLL_CTL_MOD: i32 = 3;
+pub const EPOLL_CLOEXEC: usize = 0o2000000;
+pub const EPOLLET: u32 = 1 << 31;
+pub const EPOLLONESHOT: u32 = 1 << 30;
+
+pub const AF_UNIX: u16 = 1;
+pub const AF_INET: u16 = 2;
+pub const AF_INET6: u16 = 10;
+pub const SOCK_STREAM: usize = 1;
+pub const SOCK_DGRAM: usize = 2;
+pub const SOCK_NONBLOCK: usize = 0o4000;
+pub const SOCK_CLOEXEC: usize = 0o2000000;
+pub const SOL_SOCKET: usize = 1;
+pub const SO_ERROR: usize = 4;
+
 pub const SEEK_SET: i32 = 0;
 pub const SEEK_CUR: i32 = 1;
 pub const SEEK_END: i32 = 2;
@@ -286,6 +370,23 @@ pub const O_PATH: i32 = 0o010000000;
 pub const O_RDONLY: i32 = 0;
 pub const O_WRONLY: i32 = 1;
 pub const O_RDWR: i32 = 2;
+pub const O_CREAT: i32 = 0o100;
+pub const O_EXCL: i32 = 0o200;
+pub const O_TRUNC: i32 = 0o1000;
+pub const O_APPEND: i32 = 0o2000;
+pub const O_NONBLOCK: i32 = 0o4000;
+pub const O_CLOEXEC: i32 = 0o2000000;
+
+pub const F_DUPFD: i32 = 0;
+pub const F_GETFD: i32 = 1;
+pub const F_SETFD: i32 = 2;
+pub const F_GETFL: i32 = 3;
+pub const F_SETFL: i32 = 4;
+pub const F_DUPFD_CLOEXEC: i32 = 1030;
+pub const FD_CLOEXEC: i32 = 1;
+
+/// The most iovecs one call can have
+pub const IOV_MAX: usize = 1024;
 
 #[repr(C)]
 pub struct Iovec {
@@ -653,8 +754,28 @@ lookup! { lookup_syscall: SyscallNumber {
 	NR_FSPICK = 433;
 	NR_PIDFD_OPEN = 434;
 	NR_CLONE3 = 435;
+	// waterbox's own calls, well past anything linux will use
+	NR_WBX_REGISTER_MEMORY_DOMAIN = 0x10000;
+	NR_WBX_GET_CONFIG = 0x10001;
+	NR_WBX_ENTROPY = 0x10002;
+	NR_WBX_CREATE_ARENA = 0x10003;
+	NR_WBX_DESTROY_ARENA = 0x10004;
+	NR_WBX_SET_DOMAIN_TRANSLATOR = 0x10005;
+	NR_WBX_CREATE_FIBER = 0x10006;
+	NR_WBX_SWITCH_FIBER = 0x10007;
+	NR_WBX_DESTROY_FIBER = 0x10008;
+	NR_WBX_TRANSFER = 0x10009;
 }}
 
+pub const GRND_NONBLOCK: usize = 1;
+pub const GRND_RANDOM: usize = 2;
+pub const GRND_INSECURE: usize = 4;
+
+pub const ARCH_SET_GS: usize = 0x1001;
+pub const ARCH_SET_FS: usize = 0x1002;
+pub const ARCH_GET_FS: usize = 0x1003;
+pub const ARCH_GET_GS: usize = 0x1004;
+
 pub const MAP_FAILED: usize = 0xffffffffffffffff;
 
 pub const MAP_SHARED: usize = 0x01;
@@ -750,3 +871,120 @@ pub struct TimeSpec {
 	pub tv_sec: i64,
 	pub tv_nsec: i64,
 }
+
+#[repr(C)]
+#[derive(Default)]
+pub struct TimeVal {
+	pub tv_sec: i64,
+	pub tv_usec: i64,
+}
+
+pub const TIMER_ABSTIME: usize = 1;
+
+pub const RUSAGE_SELF: usize = 0;
+pub const RUSAGE_CHILDREN: usize = -1isize as usize;
+pub const RUSAGE_THREAD: usize = 1;
+
+/// Kernel rusage object
+#[repr(C)]
+#[derive(Default)]
+pub struct RUsage {
+	pub ru_utime: TimeVal,
+	pub ru_stime: TimeVal,
+	pub ru_maxrss: i64,
+	pub ru_ixrss: i64,
+	pub ru_idrss: i64,
+	pub ru_isrss: i64,
+	pub ru_minflt: i64,
+	pub ru_majflt: i64,
+	pub ru_nswap: i64,
+	pub ru_inblock: i64,
+	pub ru_oublock: i64,
+	pub ru_msgsnd: i64,
+	pub ru_msgrcv: i64,
+	pub ru_nsignals: i64,
+	pub ru_nvcsw: i64,
+	pub ru_nivcsw: i64,
+}
+
+/// Kernel sysinfo object
+#[repr(C)]
+#[derive(Default)]
+pub struct SysInfo {
+	pub uptime: i64,
+	pub loads: [u64; 3],
+	pub totalram: u64,
+	pub freeram: u64,
+	pub sharedram: u64,
+	pub bufferram: u64,
+	pub totalswap: u64,
+	pub freeswap: u64,
+	pub procs: u16,
+	pub __pad0: u16,
+	pub __pad1: u32,
+	pub totalhigh: u64,
+	pub freehigh: u64,
+	pub mem_unit: u32,
+	pub __pad2: u32,
+}
+
+// What the guest is told about the machine it runs on.  None of it comes from the real one, which would have cores
+// size arenas and thread pools differently from one host to the next.
+
+/// How many CPUs the guest has
+pub const GUEST_CPUS: usize = 1;
+/// How big a cpu_set_t the kernel would use for that many, which is as big as sched_getaffinity(2) writes
+pub const GUEST_CPU_SET_SIZE: usize = 8;
+
+/// What sysinfo(2) says, for a guest that has `total` bytes of memory, `free` of them for the heap, and has been
+/// running for `uptime` seconds according to its clock
+pub fn guest_sysinfo(total: usize, free: usize, uptime: i64) -> SysInfo {
+	SysInfo {
+		uptime,
+		totalram: total as u64,
+		freeram: free as u64,
+		procs: 1,
+		mem_unit: 1,
+		..Default::default()
+	}
+}
+
+/// What getrusage(2) says, for a guest that's been running for `elapsed`, which it's all spent in user time
+pub fn guest_rusage(who: usize, elapsed: &TimeSpec) -> Result<RUsage, SyscallError> {
+	match who {
+		RUSAGE_SELF | RUSAGE_THREAD => Ok(RUsage {
+			ru_utime: TimeVal { tv_sec: elapsed.tv_sec, tv_usec: elapsed.tv_nsec / 1000 },
+			..Default::default()
+		}),
+		// it never has any
+		RUSAGE_CHILDREN => Ok(RUsage::default()),
+		_ => Err(EINVAL),
+	}
+}
+
+/// What sched_getaffinity(2) returns for a buffer of `len` bytes, which is how much of it the mask should fill
+pub fn guest_affinity_len(len: usize) -> Result<usize, SyscallError> {
+	if len < GUEST_CPU_SET_SIZE || len & 7 != 0 {
+		Err(EINVAL)
+	} else {
+		Ok(GUEST_CPU_SET_SIZE)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_machine() -> Result<(), SyscallError> {
+		assert_eq!(std::mem::size_of::<RUsage>(), 144);
+		assert_eq!(std::mem::size_of::<SysInfo>(), 112);
+		assert_eq!(guest_rusage(RUSAGE_SELF, &TimeSpec { tv_sec: 3, tv_nsec: 250000000 })?.ru_utime.tv_usec, 250000);
+		assert_eq!(guest_rusage(RUSAGE_CHILDREN, &TimeSpec { tv_sec: 3, tv_nsec: 0 })?.ru_utime.tv_sec, 0);
+		assert!(guest_rusage(2, &TimeSpec { tv_sec: 0, tv_nsec: 0 }).is_err());
+		assert_eq!(guest_affinity_len(128), Ok(8));
+		assert_eq!(guest_affinity_len(4), Err(EINVAL));
+		assert_eq!(guest_affinity_len(12), Err(EINVAL));
+		Ok(())
+	}
+}
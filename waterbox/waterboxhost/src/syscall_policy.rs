@@ -0,0 +1,95 @@
+// Which guest syscalls the frontend lets through.  By default the host answers every one it knows, deterministically
+// as far as it can, and ENOSYS for the rest.  A strict build can instead have, say, everything to do with time or
+// randomness fail, so that a core that leans on them is caught, while a dev build can hand odd ones to the frontend to
+// answer however it likes.  Syscalls are numbered as on x86_64 whatever the guest's ABI, and the policy is checked
+// before anything else about the call, arguments included.
+use crate::*;
+use cinterface::SyscallTrapCallback;
+use std::collections::HashMap;
+use syscall_defs::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyscallAction {
+	/// Handled as the host would anyway
+	Allow,
+	/// Fails with this errno
+	Deny(i32),
+	/// The trap callback says what it returns
+	Trap,
+}
+impl SyscallAction {
+	/// From the C interface's numbering:  0 allow, 1 deny with `errno`, 2 trap
+	pub fn from_raw(action: u32, errno: i32) -> anyhow::Result<SyscallAction> {
+		match action {
+			0 => Ok(SyscallAction::Allow),
+			1 if errno > 0 && errno < 4096 => Ok(SyscallAction::Deny(errno)),
+			1 => Err(coded(ErrorCode::InvalidArgument, format!("{} is not an errno", errno))),
+			2 => Ok(SyscallAction::Trap),
+			_ => Err(coded(ErrorCode::InvalidArgument, format!("Unknown syscall action {}", action))),
+		}
+	}
+}
+
+#[derive(Default)]
+pub struct SyscallPolicy {
+	/// Anything not in here is allowed
+	actions: HashMap<usize, SyscallAction>,
+	trap: Option<(SyscallTrapCallback, usize)>,
+}
+impl SyscallPolicy {
+	pub fn set(&mut self, nr: usize, action: SyscallAction) {
+		if action == SyscallAction::Allow {
+			self.actions.remove(&nr);
+		} else {
+			self.actions.insert(nr, action);
+		}
+	}
+	pub fn get(&self, nr: usize) -> SyscallAction {
+		self.actions.get(&nr).copied().unwrap_or(SyscallAction::Allow)
+	}
+	pub fn set_trap_callback(&mut self, callback: Option<(SyscallTrapCallback, usize)>) {
+		self.trap = callback;
+	}
+	/// What syscall `nr` returns instead of what the host would have, if anything
+	pub fn apply(&self, nr: &SyscallNumber, args: &[usize; 6]) -> Option<SyscallReturn> {
+		match self.get(nr.0) {
+			SyscallAction::Allow => None,
+			SyscallAction::Deny(e) => Some(syscall_err(SyscallError(e))),
+			SyscallAction::Trap => match self.trap {
+				Some((callback, userdata)) => Some(SyscallReturn(callback(userdata, nr.0, args.as_ptr()))),
+				None => {
+					log!(Warn, "Guest syscall {} is trapped, but there's nothing to trap it", lookup_syscall(nr));
+					Some(syscall_err(ENOSYS))
+				},
+			},
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	extern fn answer(userdata: usize, nr: usize, args: *const usize) -> usize {
+		userdata + nr + unsafe { *args.add(1) }
+	}
+
+	#[test]
+	fn test_policy() -> anyhow::Result<()> {
+		let mut p = SyscallPolicy::default();
+		let args = [0, 5, 0, 0, 0, 0];
+		p.set(NR_GETRANDOM.0, SyscallAction::from_raw(1, 1)?);
+		p.set(NR_TIME.0, SyscallAction::from_raw(2, 0)?);
+		assert!(p.apply(&NR_READ, &args).is_none());
+		assert_eq!(p.apply(&NR_GETRANDOM, &args).unwrap().0, syscall_err(EPERM).0);
+		assert_eq!(p.apply(&NR_TIME, &args).unwrap().0, syscall_err(ENOSYS).0);
+		p.set_trap_callback(Some((answer, 1000)));
+		assert_eq!(p.apply(&NR_TIME, &args).unwrap().0, 1000 + NR_TIME.0 + 5);
+		p.set(NR_TIME.0, SyscallAction::Allow);
+		assert!(p.apply(&NR_TIME, &args).is_none());
+		assert_eq!(p.get(NR_TIME.0), SyscallAction::Allow);
+		assert!(SyscallAction::from_raw(1, 0).is_err());
+		assert!(SyscallAction::from_raw(3, 0).is_err());
+		Ok(())
+	}
+}
@@ -0,0 +1,1010 @@
+// Guest threads and their synchronization.  Nothing here ever blocks the host thread:  Guest threads are green
+// threads, all run by whichever host thread called into the guest, and they only switch at syscall boundaries, in
+// round robin order.  A guest thread that has to wait is parked, and if no guest thread could ever wake it, the wait
+// either times out or is reported as a deadlock.  Time doesn't pass while the guest waits, so this is all deterministic.
+// A parked thread is nothing but the guest registers that a syscall has to preserve, so savestates can hold them.
+// Guest TLS means the guest has its own idea of what fs points to, but host code uses fs for its own TLS, so the
+// guest's thread pointer is only installed while guest code runs, and host code that interrupts it swaps it out.
+use crate::*;
+use crate::syscall_defs::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+
+pub const FUTEX_WAIT: usize = 0;
+pub const FUTEX_WAKE: usize = 1;
+pub const FUTEX_REQUEUE: usize = 3;
+pub const FUTEX_CMP_REQUEUE: usize = 4;
+pub const FUTEX_WAKE_OP: usize = 5;
+pub const FUTEX_LOCK_PI: usize = 6;
+pub const FUTEX_UNLOCK_PI: usize = 7;
+pub const FUTEX_TRYLOCK_PI: usize = 8;
+pub const FUTEX_WAIT_BITSET: usize = 9;
+pub const FUTEX_WAKE_BITSET: usize = 10;
+pub const FUTEX_CMD_MASK: usize = 0x7f;
+pub const FUTEX_PRIVATE_FLAG: usize = 128;
+pub const FUTEX_CLOCK_REALTIME: usize = 256;
+pub const FUTEX_BITSET_MATCH_ANY: u32 = 0xffffffff;
+
+/// Id of a guest thread, as the guest sees it
+pub type ThreadId = u32;
+
+pub const MAIN_TID: ThreadId = 8675309;
+
+#[derive(Clone, Copy)]
+struct FutexWaiter {
+	thread: ThreadId,
+	addr: usize,
+	bitset: u32,
+}
+
+/// Threads waiting on futexes, in the order they started waiting
+#[derive(Default)]
+pub struct Futexes {
+	waiters: Vec<FutexWaiter>,
+}
+impl Futexes {
+	pub fn wait(&mut self, thread: ThreadId, addr: usize, bitset: u32) {
+		self.waiters.push(FutexWaiter { thread, addr, bitset });
+	}
+	/// Wake up to `count` threads waiting on addr whose bitsets intersect `bitset`, oldest first.  Returns the threads
+	/// that were woken.
+	pub fn wake(&mut self, addr: usize, count: usize, bitset: u32) -> Vec<ThreadId> {
+		let mut res = Vec::new();
+		let mut i = 0;
+		while i < self.waiters.len() && res.len() < count {
+			let w = &self.waiters[i];
+			if w.addr == addr && w.bitset & bitset != 0 {
+				res.push(self.waiters.remove(i).thread);
+			} else {
+				i += 1;
+			}
+		}
+		res
+	}
+	/// Wake up to `count` threads waiting on addr, and move up to `requeue` of the rest to addr2.  Returns the threads
+	/// that were woken, and the number that were moved.
+	pub fn requeue(&mut self, addr: usize, count: usize, addr2: usize, requeue: usize) -> (Vec<ThreadId>, usize) {
+		let woken = self.wake(addr, count, FUTEX_BITSET_MATCH_ANY);
+		let mut moved = 0;
+		for w in self.waiters.iter_mut() {
+			if moved == requeue {
+				break
+			}
+			if w.addr == addr {
+				w.addr = addr2;
+				moved += 1;
+			}
+		}
+		(woken, moved)
+	}
+	/// Stop a thread's wait without waking it normally.  Returns false if it wasn't waiting.
+	pub fn cancel(&mut self, thread: ThreadId) -> bool {
+		match self.waiters.iter().position(|w| w.thread == thread) {
+			Some(index) => {
+				self.waiters.remove(index);
+				true
+			},
+			None => false,
+		}
+	}
+}
+
+/// The registers that a guest syscall must preserve, and where it returns to, with what.  This is everything needed
+/// to resume a thread that's parked in a syscall.  The asm below depends on this layout.
+#[cfg(target_arch = "x86_64")]
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GuestContext {
+	pub rbx: usize,
+	pub rbp: usize,
+	pub r12: usize,
+	pub r13: usize,
+	pub r14: usize,
+	pub r15: usize,
+	pub rip: usize,
+	pub rsp: usize,
+	pub rax: usize,
+}
+#[cfg(target_arch = "x86_64")]
+impl GuestContext {
+	pub fn pc(&self) -> usize {
+		self.rip
+	}
+	pub fn frame_pointer(&self) -> usize {
+		self.rbp
+	}
+	pub fn stack_pointer(&self) -> usize {
+		self.rsp
+	}
+	/// Set what the syscall returns
+	pub fn set_return(&mut self, val: usize) {
+		self.rax = val;
+	}
+	/// A context that starts running at `pc` on the stack whose top is `sp`, with `func` and `arg` in rbx and r12
+	pub fn starting_at(pc: usize, sp: usize, func: usize, arg: usize) -> GuestContext {
+		GuestContext { rip: pc, rsp: sp, rbx: func, r12: arg, ..Default::default() }
+	}
+}
+
+/// The registers that a guest syscall must preserve, and where it returns to, with what.  This is everything needed
+/// to resume a thread that's parked in a syscall.  The asm below depends on this layout.
+#[cfg(target_arch = "aarch64")]
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GuestContext {
+	/// x19 through x28
+	pub x: [usize; 10],
+	pub fp: usize,
+	/// The link register at the time of the syscall
+	pub pc: usize,
+	pub sp: usize,
+	pub x0: usize,
+}
+#[cfg(target_arch = "aarch64")]
+impl GuestContext {
+	pub fn pc(&self) -> usize {
+		self.pc
+	}
+	pub fn frame_pointer(&self) -> usize {
+		self.fp
+	}
+	pub fn stack_pointer(&self) -> usize {
+		self.sp
+	}
+	/// Set what the syscall returns
+	pub fn set_return(&mut self, val: usize) {
+		self.x0 = val;
+	}
+	/// A context that starts running at `pc` on the stack whose top is `sp`, with `func` and `arg` in x19 and x20
+	pub fn starting_at(pc: usize, sp: usize, func: usize, arg: usize) -> GuestContext {
+		let mut x = [0; 10];
+		x[0] = func;
+		x[1] = arg;
+		GuestContext { x, pc, sp, ..Default::default() }
+	}
+}
+
+/// Filled in by the syscall entry stub on every guest syscall.  This has to be 8 bytes into whatever the syscall
+/// `ud` argument points to.
+#[repr(C)]
+pub struct SyscallEntry {
+	/// What the stub jumps to, with the syscall's arguments untouched
+	pub handler: usize,
+	/// The caller's context, as it will be when the syscall returns (except for the return value)
+	pub ctx: GuestContext,
+}
+
+#[cfg(target_arch = "x86_64")]
+global_asm!(r#"
+.intel_syntax noprefix
+.text
+.globl wbx_syscall_entry
+wbx_syscall_entry:
+	mov [rsi + 16], rbx
+	mov [rsi + 24], rbp
+	mov [rsi + 32], r12
+	mov [rsi + 40], r13
+	mov [rsi + 48], r14
+	mov [rsi + 56], r15
+	mov rax, [rsp]
+	mov [rsi + 64], rax
+	lea rax, [rsp + 8]
+	mov [rsi + 72], rax
+	jmp [rsi + 8]
+.globl wbx_switch_context
+wbx_switch_context:
+	mov rbx, [rdi]
+	mov rbp, [rdi + 8]
+	mov r12, [rdi + 16]
+	mov r13, [rdi + 24]
+	mov r14, [rdi + 32]
+	mov r15, [rdi + 40]
+	mov rcx, [rdi + 48]
+	mov rax, [rdi + 64]
+	mov rsp, [rdi + 56]
+	jmp rcx
+.globl wbx_call_saving_context
+wbx_call_saving_context:
+	mov [rdi], rbx
+	mov [rdi + 8], rbp
+	mov [rdi + 16], r12
+	mov [rdi + 24], r13
+	mov [rdi + 32], r14
+	mov [rdi + 40], r15
+	mov rax, [rsp]
+	mov [rdi + 48], rax
+	lea rax, [rsp + 8]
+	mov [rdi + 56], rax
+	mov rax, rsi
+	mov r10, rdx
+	mov rdi, [r10]
+	mov rsi, [r10 + 8]
+	mov rdx, [r10 + 16]
+	mov rcx, [r10 + 24]
+	mov r8, [r10 + 32]
+	mov r9, [r10 + 40]
+	jmp rax
+.att_syntax
+"#);
+
+// The same on aarch64, where the syscall's arguments are all in registers, ud is in x1, and nothing's on the stack
+#[cfg(target_arch = "aarch64")]
+global_asm!(r#"
+.text
+.globl wbx_syscall_entry
+wbx_syscall_entry:
+	stp x19, x20, [x1, #16]
+	stp x21, x22, [x1, #32]
+	stp x23, x24, [x1, #48]
+	stp x25, x26, [x1, #64]
+	stp x27, x28, [x1, #80]
+	stp x29, x30, [x1, #96]
+	mov x9, sp
+	str x9, [x1, #112]
+	ldr x9, [x1, #8]
+	br x9
+.globl wbx_switch_context
+wbx_switch_context:
+	ldp x19, x20, [x0]
+	ldp x21, x22, [x0, #16]
+	ldp x23, x24, [x0, #32]
+	ldp x25, x26, [x0, #48]
+	ldp x27, x28, [x0, #64]
+	ldp x29, x30, [x0, #80]
+	ldr x9, [x0, #96]
+	mov sp, x9
+	ldr x0, [x0, #104]
+	br x30
+.globl wbx_call_saving_context
+wbx_call_saving_context:
+	stp x19, x20, [x0]
+	stp x21, x22, [x0, #16]
+	stp x23, x24, [x0, #32]
+	stp x25, x26, [x0, #48]
+	stp x27, x28, [x0, #64]
+	stp x29, x30, [x0, #80]
+	mov x9, sp
+	str x9, [x0, #96]
+	mov x9, x1
+	mov x10, x2
+	ldp x0, x1, [x10]
+	ldp x2, x3, [x10, #16]
+	ldp x4, x5, [x10, #32]
+	br x9
+"#);
+
+guest_abi! {{
+	/// Goes in the guest's syscall pointer, in place of the real handler
+	pub fn wbx_syscall_entry();
+	fn wbx_switch_context(ctx: *const GuestContext) -> !;
+	fn wbx_call_saving_context(ctx: *mut GuestContext, func: usize, args: *const [usize; 6]) -> usize;
+}}
+
+/// Abandon the current stack and resume a parked guest thread
+/// unsafe: Nothing on the current stack will be cleaned up
+pub unsafe fn switch_to(ctx: &GuestContext) -> ! {
+	wbx_switch_context(ctx)
+}
+
+/// Call `func` with up to six integer arguments, after recording in `ctx` where it will return to.  Until it does,
+/// switch_to(ctx) abandons the call, and makes this return right away instead.
+/// unsafe: func must be callable like that, and ctx must stay put until this returns
+pub unsafe fn call_saving_context(ctx: *mut GuestContext, func: usize, args: &[usize; 6]) -> usize {
+	wbx_call_saving_context(ctx, func, args)
+}
+
+/// A host thread that's running guest code with a guest's thread pointer installed
+struct FsSlot {
+	/// The guest's thread pointer, or 0 while host code has interrupted it
+	guest: AtomicUsize,
+	/// What to put back when host code runs, or 0 if the slot is free
+	host: AtomicUsize,
+}
+
+/// Most host threads that can be running guest code with guest TLS at once
+const MAX_FS_SLOTS: usize = 64;
+
+lazy_static! {
+	/// Host code that interrupts guest code can't use host TLS to find out what to put back, since that's what's
+	/// broken, so each thread's is kept here, and found by the guest thread pointer that's installed.  Those are all
+	/// different, since each one is in its own guest's memory, and only one thread can be in a guest at a time.
+	static ref FS_SLOTS: Vec<FsSlot> = (0..MAX_FS_SLOTS).map(|_| FsSlot { guest: AtomicUsize::new(0), host: AtomicUsize::new(0) }).collect();
+}
+/// How many FsSlots are taken, so that threads can skip looking when none are
+static FS_INSTALLED: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+unsafe fn get_fs() -> usize {
+	let mut res = 0usize;
+	libc::syscall(libc::SYS_arch_prctl, ARCH_GET_FS, &mut res as *mut usize);
+	res
+}
+/// Must not touch host TLS, as it's whatever the old fs said
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+unsafe fn set_fs(fs: usize) {
+	if libc::syscall(libc::SYS_arch_prctl, ARCH_SET_FS, fs) != 0 {
+		std::process::abort();
+	}
+}
+#[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+unsafe fn get_fs() -> usize {
+	0
+}
+#[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+unsafe fn set_fs(_fs: usize) {
+	std::process::abort();
+}
+
+/// Whether guest TLS can work here.  macOS has no way to point fs somewhere else, and aarch64 guests would need
+/// their own TLS layout.
+pub fn tls_supported() -> bool {
+	cfg!(all(target_os = "linux", target_arch = "x86_64"))
+}
+
+/// Runs guest code with the guest's thread pointer installed, and puts the host's back afterwards.  Only guest code
+/// that the host calls into sees its thread pointer; guest code called by anything else has to do without TLS.
+pub struct GuestFs {
+	slot: Option<usize>,
+}
+impl GuestFs {
+	/// unsafe: host TLS can't be used until this is dropped
+	pub unsafe fn enter(fs: usize) -> GuestFs {
+		if fs == 0 {
+			return GuestFs { slot: None }
+		}
+		let host = get_fs();
+		let slot = match FS_SLOTS.iter().position(|s| s.host.compare_exchange(0, host, Ordering::SeqCst, Ordering::SeqCst).is_ok()) {
+			Some(i) => i,
+			None => panic!("More than {} threads are running guests with TLS", MAX_FS_SLOTS),
+		};
+		FS_INSTALLED.fetch_add(1, Ordering::SeqCst);
+		// marked before it's in, so a signal in between never sees a guest thread pointer it can't find
+		FS_SLOTS[slot].guest.store(fs, Ordering::SeqCst);
+		set_fs(fs);
+		GuestFs { slot: Some(slot) }
+	}
+}
+impl Drop for GuestFs {
+	fn drop(&mut self) {
+		if let Some(i) = self.slot {
+			let slot = &FS_SLOTS[i];
+			unsafe { set_fs(slot.host.load(Ordering::SeqCst)) }
+			slot.guest.store(0, Ordering::SeqCst);
+			FS_INSTALLED.fetch_sub(1, Ordering::SeqCst);
+			slot.host.store(0, Ordering::SeqCst);
+		}
+	}
+}
+
+/// Makes host TLS usable while guest code is interrupted, in a syscall or a signal handler.  When dropped, the guest's
+/// thread pointer goes back in, if it was in before.
+pub struct HostFs {
+	guest: usize,
+	slot: usize,
+}
+impl HostFs {
+	/// Must come before any use of host TLS
+	pub fn enter() -> HostFs {
+		if FS_INSTALLED.load(Ordering::SeqCst) == 0 {
+			return HostFs { guest: 0, slot: 0 }
+		}
+		let fs = unsafe { get_fs() };
+		if fs == 0 {
+			return HostFs { guest: 0, slot: 0 }
+		}
+		for (i, slot) in FS_SLOTS.iter().enumerate() {
+			if slot.guest.compare_exchange(fs, 0, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+				unsafe { set_fs(slot.host.load(Ordering::SeqCst)) }
+				return HostFs { guest: fs, slot: i }
+			}
+		}
+		// this thread has the host's in already
+		HostFs { guest: 0, slot: 0 }
+	}
+	/// Return to a different guest thread pointer than the one that was interrupted
+	pub fn set_guest(&mut self, fs: usize) {
+		if self.guest != 0 && fs != 0 {
+			self.guest = fs;
+		}
+	}
+}
+impl Drop for HostFs {
+	fn drop(&mut self) {
+		if self.guest != 0 {
+			FS_SLOTS[self.slot].guest.store(self.guest, Ordering::SeqCst);
+			unsafe { set_fs(self.guest) }
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ThreadState {
+	Runnable,
+	/// Waiting on a futex
+	Blocked { timed: bool },
+	/// Will be cleaned up as soon as it's switched away from
+	Exited,
+}
+
+#[derive(Clone, Copy)]
+struct Thread {
+	tid: ThreadId,
+	state: ThreadState,
+	/// Where to resume, if the thread isn't running
+	ctx: GuestContext,
+	/// Zeroed and woken when the thread exits
+	clear_child_tid: usize,
+	/// Thread pointer, if the guest uses TLS
+	tls: usize,
+}
+
+pub const CLONE_VM: usize = 0x00000100;
+pub const CLONE_FS: usize = 0x00000200;
+pub const CLONE_FILES: usize = 0x00000400;
+pub const CLONE_SIGHAND: usize = 0x00000800;
+pub const CLONE_SETTLS: usize = 0x00080000;
+pub const CLONE_PARENT_SETTID: usize = 0x00100000;
+pub const CLONE_CHILD_CLEARTID: usize = 0x00200000;
+pub const CLONE_DETACHED: usize = 0x00400000;
+pub const CLONE_CHILD_SETTID: usize = 0x01000000;
+pub const CLONE_THREAD: usize = 0x00010000;
+pub const CLONE_SYSVSEM: usize = 0x00040000;
+
+/// The guest's threads
+pub struct Threads {
+	threads: Vec<Thread>,
+	current: ThreadId,
+	next_tid: ThreadId,
+	futexes: Futexes,
+}
+impl Threads {
+	pub fn new() -> Threads {
+		Threads {
+			threads: vec![Thread {
+				tid: MAIN_TID,
+				state: ThreadState::Runnable,
+				ctx: GuestContext::default(),
+				clear_child_tid: 0,
+				tls: 0,
+			}],
+			current: MAIN_TID,
+			next_tid: MAIN_TID + 1,
+			futexes: Futexes::default(),
+		}
+	}
+	pub fn current(&self) -> ThreadId {
+		self.current
+	}
+	fn index(&self, tid: ThreadId) -> usize {
+		self.threads.iter().position(|t| t.tid == tid).unwrap()
+	}
+	fn current_mut(&mut self) -> &mut Thread {
+		let index = self.index(self.current);
+		&mut self.threads[index]
+	}
+	/// True if some thread other than the current one could ever run without the current one's help
+	pub fn others_can_run(&self) -> bool {
+		self.threads.iter().any(|t| t.tid != self.current
+			&& matches!(t.state, ThreadState::Runnable | ThreadState::Blocked { timed: true }))
+	}
+
+	/// Implements clone(2) for new threads.  `ctx` is where the calling syscall will return to.  The new thread starts
+	/// out returning from the same syscall with 0, on `stack`.
+	/// unsafe: ptid and ctid are guest pointers, and must be valid if the flags say they're used
+	pub unsafe fn clone(&mut self, ctx: &GuestContext, flags: usize, stack: usize, ptid: usize, ctid: usize, tls: usize) -> Result<usize, SyscallError> {
+		const REQUIRED: usize = CLONE_VM | CLONE_FS | CLONE_FILES | CLONE_SIGHAND | CLONE_THREAD;
+		const ALLOWED: usize = REQUIRED | CLONE_SYSVSEM | CLONE_SETTLS | CLONE_PARENT_SETTID | CLONE_CHILD_CLEARTID
+			| CLONE_DETACHED | CLONE_CHILD_SETTID;
+		if flags & REQUIRED != REQUIRED || flags & !ALLOWED != 0 {
+			// no processes, or exit signals
+			return Err(ENOSYS)
+		}
+		if stack == 0 {
+			return Err(EINVAL)
+		}
+		let tid = self.next_tid;
+		self.next_tid += 1;
+		if flags & CLONE_PARENT_SETTID != 0 {
+			*(ptid as *mut ThreadId) = tid;
+		}
+		if flags & CLONE_CHILD_SETTID != 0 {
+			*(ctid as *mut ThreadId) = tid;
+		}
+		self.threads.push(Thread {
+			tid,
+			state: ThreadState::Runnable,
+			ctx: GuestContext { rsp: stack, rax: 0, ..*ctx },
+			clear_child_tid: if flags & CLONE_CHILD_CLEARTID != 0 { ctid } else { 0 },
+			tls: if flags & CLONE_SETTLS != 0 { tls } else { self.tls() },
+		});
+		Ok(tid as usize)
+	}
+	/// The current thread's thread pointer
+	pub fn tls(&self) -> usize {
+		self.threads[self.index(self.current)].tls
+	}
+	/// Implements arch_prctl(ARCH_SET_FS), or sets up the main thread's thread pointer when called before anything runs
+	pub fn set_tls(&mut self, tls: usize) {
+		self.current_mut().tls = tls;
+	}
+	/// Implements set_tid_address(2)
+	pub fn set_tid_address(&mut self, addr: usize) -> usize {
+		self.current_mut().clear_child_tid = addr;
+		self.current as usize
+	}
+	/// Implements exit(2) for anything but the main thread, which can't exit on its own
+	/// unsafe: the thread's clear_child_tid must still be a valid guest pointer
+	pub unsafe fn exit(&mut self) -> SyscallResult {
+		let t = self.current_mut();
+		t.state = ThreadState::Exited;
+		let addr = t.clear_child_tid;
+		if addr != 0 {
+			*(addr as *mut ThreadId) = 0;
+			let woken = self.futexes.wake(addr, 1, FUTEX_BITSET_MATCH_ANY);
+			self.wake(woken);
+		}
+		Ok(())
+	}
+
+	/// Called at the end of every syscall to pick which thread runs next, whether the current thread can keep going
+	/// or not.  `ctx` is where the current thread would return to, and `ret` is what it would return.  Returns None
+	/// to simply return to the current thread, otherwise the caller must switch_to() the result.
+	pub fn reschedule(&mut self, ctx: &GuestContext, ret: usize) -> Option<GuestContext> {
+		if self.threads.len() == 1 {
+			return None
+		}
+		let cur = self.index(self.current);
+		let was_runnable = self.threads[cur].state == ThreadState::Runnable;
+		self.threads[cur].ctx = GuestContext { rax: ret, ..*ctx };
+		// round robin, starting after the current thread
+		let order = (1..=self.threads.len())
+			.map(|i| self.threads[(cur + i) % self.threads.len()].tid)
+			.collect::<Vec<_>>();
+		self.threads.retain(|t| t.state != ThreadState::Exited);
+		let mut next = self.pick(&order);
+		if next.is_none() {
+			// nobody can run, so timeouts are up
+			for t in self.threads.iter_mut() {
+				if t.state == (ThreadState::Blocked { timed: true }) {
+					self.futexes.cancel(t.tid);
+					t.state = ThreadState::Runnable;
+					t.ctx.set_return(syscall_err(ETIMEDOUT).0);
+				}
+			}
+			next = self.pick(&order);
+		}
+		let next = match next {
+			Some(n) => n,
+			None => {
+				log!(Warn, "All guest threads are waiting forever");
+				// give up on somebody's wait
+				let t = &mut self.threads[0];
+				self.futexes.cancel(t.tid);
+				t.state = ThreadState::Runnable;
+				t.ctx.set_return(syscall_err(EDEADLK).0);
+				0
+			}
+		};
+		let t = &self.threads[next];
+		if t.tid == self.current && was_runnable {
+			return None
+		}
+		self.current = t.tid;
+		Some(t.ctx)
+	}
+	fn pick(&self, order: &[ThreadId]) -> Option<usize> {
+		order.iter()
+			.filter_map(|&tid| self.threads.iter().position(|t| t.tid == tid))
+			.find(|&i| self.threads[i].state == ThreadState::Runnable)
+	}
+
+	/// Park the current thread until something wakes it.  `timed` is true if the wait has a timeout, which expires
+	/// when nothing else can run.
+	fn block(&mut self, timed: bool) -> SyscallResult {
+		if !self.others_can_run() {
+			// nobody could ever wake this one up, so don't bother switching away
+			self.futexes.cancel(self.current);
+			return if timed {
+				Err(ETIMEDOUT)
+			} else {
+				log!(Warn, "Guest thread {} waited forever on a futex", self.current);
+				Err(EDEADLK)
+			}
+		}
+		self.current_mut().state = ThreadState::Blocked { timed };
+		Ok(())
+	}
+	/// Threads that were woken by a futex operation can run again, and see their wait succeed
+	fn wake(&mut self, threads: Vec<ThreadId>) -> usize {
+		for &tid in threads.iter() {
+			let index = self.index(tid);
+			let t = &mut self.threads[index];
+			t.state = ThreadState::Runnable;
+			t.ctx.set_return(0);
+		}
+		threads.len()
+	}
+
+	fn read_thread(stream: &mut dyn Read) -> anyhow::Result<Thread> {
+		let tid = bin::readval(stream)?;
+		let state = match bin::readval::<u32>(stream)? {
+			0 => ThreadState::Runnable,
+			1 => ThreadState::Blocked { timed: false },
+			2 => ThreadState::Blocked { timed: true },
+			_ => return Err(anyhow!("Bad thread state")),
+		};
+		Ok(Thread {
+			tid,
+			state,
+			ctx: bin::readval(stream)?,
+			clear_child_tid: bin::readval(stream)?,
+			tls: bin::readval(stream)?,
+		})
+	}
+
+	/// Implements futex(2), except for the priority inheritance operations
+	/// unsafe: addresses are guest pointers, and must be valid if they're used by the operation
+	pub unsafe fn futex(&mut self, uaddr: usize, op: usize, val: usize, timeout: usize, uaddr2: usize, val3: usize) -> Result<usize, SyscallError> {
+		let cmd = op & FUTEX_CMD_MASK;
+		if op & !(FUTEX_CMD_MASK | FUTEX_PRIVATE_FLAG | FUTEX_CLOCK_REALTIME) != 0
+			|| op & FUTEX_CLOCK_REALTIME != 0 && cmd != FUTEX_WAIT_BITSET && cmd != FUTEX_WAIT {
+			return Err(ENOSYS)
+		}
+		let word = |addr: usize| -> Result<*mut u32, SyscallError> {
+			if addr == 0 {
+				Err(EFAULT)
+			} else if addr & 3 != 0 {
+				Err(EINVAL)
+			} else {
+				Ok(addr as *mut u32)
+			}
+		};
+		match cmd {
+			FUTEX_WAIT | FUTEX_WAIT_BITSET => {
+				let bitset = if cmd == FUTEX_WAIT { FUTEX_BITSET_MATCH_ANY } else { val3 as u32 };
+				if bitset == 0 {
+					return Err(EINVAL)
+				}
+				if std::ptr::read_volatile(word(uaddr)?) != val as u32 {
+					return Err(EAGAIN)
+				}
+				self.futexes.wait(self.current, uaddr, bitset);
+				self.block(timeout != 0)?;
+				Ok(0)
+			},
+			FUTEX_WAKE | FUTEX_WAKE_BITSET => {
+				let bitset = if cmd == FUTEX_WAKE { FUTEX_BITSET_MATCH_ANY } else { val3 as u32 };
+				if bitset == 0 {
+					return Err(EINVAL)
+				}
+				word(uaddr)?;
+				let woken = self.futexes.wake(uaddr, val, bitset);
+				Ok(self.wake(woken))
+			},
+			FUTEX_REQUEUE | FUTEX_CMP_REQUEUE => {
+				word(uaddr)?;
+				word(uaddr2)?;
+				if cmd == FUTEX_CMP_REQUEUE && std::ptr::read_volatile(word(uaddr)?) != val3 as u32 {
+					return Err(EAGAIN)
+				}
+				// the requeue count is passed in the timeout argument
+				let (woken, moved) = self.futexes.requeue(uaddr, val, uaddr2, timeout);
+				let woken = self.wake(woken);
+				Ok(if cmd == FUTEX_CMP_REQUEUE { woken + moved } else { woken })
+			},
+			FUTEX_WAKE_OP => {
+				word(uaddr)?;
+				let p2 = word(uaddr2)?;
+				let old = std::ptr::read_volatile(p2);
+				std::ptr::write_volatile(p2, futex_op(old, val3 as u32)?);
+				let mut woken = self.futexes.wake(uaddr, val, FUTEX_BITSET_MATCH_ANY);
+				if futex_cmp(old, val3 as u32)? {
+					// and so is the second wake count
+					woken.extend(self.futexes.wake(uaddr2, timeout, FUTEX_BITSET_MATCH_ANY));
+				}
+				Ok(self.wake(woken))
+			},
+			// including FUTEX_LOCK_PI and friends
+			_ => Err(ENOSYS),
+		}
+	}
+}
+
+/// Sign extend the 12 bit argument fields of FUTEX_WAKE_OP
+fn sext12(v: u32) -> u32 {
+	(((v & 0xfff) << 20) as i32 >> 20) as u32
+}
+/// The new value of the second futex word for FUTEX_WAKE_OP
+fn futex_op(old: u32, val3: u32) -> Result<u32, SyscallError> {
+	let op = val3 >> 28;
+	let mut arg = sext12(val3 >> 12);
+	if op & 8 != 0 {
+		if arg > 31 {
+			return Err(EINVAL)
+		}
+		arg = 1 << arg;
+	}
+	Ok(match op & 7 {
+		0 => arg,
+		1 => old.wrapping_add(arg),
+		2 => old | arg,
+		3 => old & !arg,
+		4 => old ^ arg,
+		_ => return Err(ENOSYS),
+	})
+}
+/// Whether FUTEX_WAKE_OP also wakes waiters on the second futex word
+fn futex_cmp(old: u32, val3: u32) -> Result<bool, SyscallError> {
+	let arg = sext12(val3) as i32;
+	let old = old as i32;
+	Ok(match (val3 >> 24) & 0xf {
+		0 => old == arg,
+		1 => old != arg,
+		2 => old < arg,
+		3 => old <= arg,
+		4 => old > arg,
+		5 => old >= arg,
+		_ => return Err(ENOSYS),
+	})
+}
+
+impl IStateable for Threads {
+	fn save_state(&mut self, stream: &mut dyn Write) -> anyhow::Result<()> {
+		bin::write_magic(stream, "Threads")?;
+		bin::write(stream, &self.current)?;
+		bin::write(stream, &self.next_tid)?;
+		// exited threads never outlive the syscall that exited them
+		bin::writeval(stream, self.threads.len())?;
+		for t in self.threads.iter() {
+			bin::write(stream, &t.tid)?;
+			bin::writeval::<u32>(stream, match t.state {
+				ThreadState::Blocked { timed: false } => 1,
+				ThreadState::Blocked { timed: true } => 2,
+				_ => 0,
+			})?;
+			bin::write(stream, &t.ctx)?;
+			bin::write(stream, &t.clear_child_tid)?;
+			bin::write(stream, &t.tls)?;
+		}
+		bin::writeval(stream, self.futexes.waiters.len())?;
+		for w in self.futexes.waiters.iter() {
+			bin::write(stream, &w.thread)?;
+			bin::write(stream, &w.addr)?;
+			bin::write(stream, &w.bitset)?;
+		}
+		Ok(())
+	}
+	fn load_state(&mut self, stream: &mut dyn Read) -> anyhow::Result<()> {
+		bin::verify_magic(stream, "Threads")?;
+		bin::read(stream, &mut self.current)?;
+		bin::read(stream, &mut self.next_tid)?;
+		let count: usize = bin::readval(stream)?;
+		self.threads = (0..count).map(|_| Threads::read_thread(stream)).collect::<anyhow::Result<_>>()?;
+		let count: usize = bin::readval(stream)?;
+		self.futexes.waiters = (0..count).map(|_| -> anyhow::Result<FutexWaiter> {
+			Ok(FutexWaiter {
+				thread: bin::readval(stream)?,
+				addr: bin::readval(stream)?,
+				bitset: bin::readval(stream)?,
+			})
+		}).collect::<anyhow::Result<_>>()?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_futex_queue() {
+		let mut f = Futexes::default();
+		f.wait(1, 0x1000, FUTEX_BITSET_MATCH_ANY);
+		f.wait(2, 0x2000, FUTEX_BITSET_MATCH_ANY);
+		f.wait(3, 0x1000, 2);
+		f.wait(4, 0x1000, FUTEX_BITSET_MATCH_ANY);
+		assert_eq!(f.wake(0x1000, 10, 1), vec![1, 4]);
+		assert_eq!(f.requeue(0x2000, 0, 0x1000, 1), (vec![], 1));
+		assert_eq!(f.wake(0x1000, 1, FUTEX_BITSET_MATCH_ANY), vec![2]);
+		assert!(f.cancel(3));
+		assert!(!f.cancel(3));
+	}
+
+	const THREAD_FLAGS: usize = CLONE_VM | CLONE_FS | CLONE_FILES | CLONE_SIGHAND | CLONE_THREAD | CLONE_SYSVSEM
+		| CLONE_SETTLS | CLONE_PARENT_SETTID | CLONE_CHILD_CLEARTID;
+
+	#[test]
+	fn test_scheduler() {
+		unsafe {
+			let mut t = Threads::new();
+			let main = GuestContext { rip: 0x1000, rsp: 0x2000, ..Default::default() };
+			let mut ptid = 0u32;
+			let mut ctid = 0u32;
+			let pctid = &mut ctid as *mut u32 as usize;
+			assert_eq!(t.clone(&main, CLONE_VM, 0x3000, 0, 0, 0), Err(ENOSYS));
+			let child = t.clone(&main, THREAD_FLAGS, 0x3000, &mut ptid as *mut u32 as usize, pctid, 0x4000).unwrap();
+			assert_eq!(child, MAIN_TID as usize + 1);
+			assert_eq!(ptid, child as u32);
+			// the parent returns from clone into the child
+			let c = t.reschedule(&main, child).unwrap();
+			assert_eq!(c, GuestContext { rip: 0x1000, rsp: 0x3000, rax: 0, ..Default::default() });
+			assert_eq!(t.current(), child as u32);
+			// the child waits on the tid, which wakes the parent
+			let wait = GuestContext { rip: 0x1100, rsp: 0x2f00, ..Default::default() };
+			ctid = 7;
+			assert_eq!(t.futex(pctid, FUTEX_WAIT, 7, 0, 0, 0), Ok(0));
+			let m = t.reschedule(&wait, 0).unwrap();
+			assert_eq!(m, GuestContext { rax: child, ..main });
+			assert_eq!(t.current(), MAIN_TID);
+			// the parent waits for the child to exit
+			assert_eq!(t.futex(pctid, FUTEX_WAKE, 1, 0, 0, 0), Ok(1));
+			assert_eq!(t.futex(pctid, FUTEX_WAIT, 7, 0, 0, 0), Ok(0));
+			let c = t.reschedule(&main, 0).unwrap();
+			assert_eq!(c, GuestContext { rax: 0, ..wait });
+			assert_eq!(t.exit(), Ok(()));
+			assert_eq!(ctid, 0);
+			assert_eq!(t.reschedule(&wait, 0).unwrap(), GuestContext { rax: 0, ..main });
+			assert_eq!(t.threads.len(), 1);
+			// and then there's nobody to switch to
+			assert_eq!(t.reschedule(&main, 0), None);
+		}
+	}
+
+	#[test]
+	fn test_timeout() {
+		unsafe {
+			let mut t = Threads::new();
+			let main = GuestContext::default();
+			let child = t.clone(&main, THREAD_FLAGS & !CLONE_CHILD_CLEARTID, 0x3000, &mut 0u32 as *mut u32 as usize, 0, 0).unwrap();
+			let mut word = 0u32;
+			let ts = TimeSpec { tv_sec: 1, tv_nsec: 0 };
+			// the child could still wake the parent, so the parent has to wait its turn
+			assert_eq!(t.futex(&mut word as *mut u32 as usize, FUTEX_WAIT, 0, &ts as *const TimeSpec as usize, 0, 0), Ok(0));
+			assert_eq!(t.reschedule(&main, 0).unwrap().rsp, 0x3000);
+			assert_eq!(t.current(), child as u32);
+			// but once it can't, the timeout is up
+			assert_eq!(t.futex(&mut word as *mut u32 as usize, FUTEX_WAIT, 0, 0, 0, 0), Ok(0));
+			let m = t.reschedule(&main, 0).unwrap();
+			assert_eq!(m.rax, syscall_err(ETIMEDOUT).0);
+			assert_eq!(t.current(), MAIN_TID);
+
+			let mut state = Vec::new();
+			t.save_state(&mut state).unwrap();
+			let mut t2 = Threads::new();
+			t2.load_state(&mut &state[..]).unwrap();
+			let mut state2 = Vec::new();
+			t2.save_state(&mut state2).unwrap();
+			assert_eq!(state, state2);
+			assert_eq!(t2.futexes.wake(&mut word as *mut u32 as usize, 1, FUTEX_BITSET_MATCH_ANY), vec![child as u32]);
+		}
+	}
+
+	#[repr(C)]
+	struct FakeHost {
+		tag: u64,
+		entry: SyscallEntry,
+	}
+	thread_local! {
+		static PARKED: std::cell::Cell<GuestContext> = Default::default();
+	}
+	/// As if `func` had just been called, with the stack at `top`
+	fn called(func: usize, top: usize) -> GuestContext {
+		#[cfg(target_arch = "x86_64")]
+		return GuestContext { rip: func, rsp: top - 8, ..Default::default() };
+		#[cfg(target_arch = "aarch64")]
+		return GuestContext { pc: func, sp: top, ..Default::default() };
+	}
+	guest_abi! { fn fake_syscall(_nr: usize, ud: usize) -> usize {
+		let h = unsafe { &*(ud as *const FakeHost) };
+		PARKED.with(|p| p.set(h.entry.ctx));
+		let mut stack = vec![0u8; 0x10000].into_boxed_slice();
+		let top = (stack.as_mut_ptr() as usize + stack.len()) & !15;
+		std::mem::forget(stack);
+		unsafe { switch_to(&called(fake_child as guest_abi!(fn() -> !) as usize, top)) }
+	}}
+	guest_abi! { fn fake_child() -> ! {
+		let mut ctx = PARKED.with(|p| p.get());
+		ctx.set_return(42);
+		unsafe { switch_to(&ctx) }
+	}}
+
+	#[test]
+	fn test_switch_context() {
+		let mut h = FakeHost {
+			tag: 0,
+			entry: SyscallEntry { handler: fake_syscall as guest_abi!(fn(usize, usize) -> usize) as usize, ctx: Default::default() },
+		};
+		let entry: guest_abi!(fn(usize, usize) -> usize) = unsafe {
+			std::mem::transmute(wbx_syscall_entry as guest_abi!(unsafe fn()))
+		};
+		let mut total = 0;
+		for i in 0..3 {
+			total += entry(i, &mut h as *mut FakeHost as usize) + i;
+		}
+		assert_eq!(total, 129);
+	}
+
+	#[test]
+	#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+	fn test_guest_fs() {
+		let mut tcb = Box::new([0usize; 4]);
+		let tp = tcb.as_mut_ptr() as usize;
+		tcb[0] = tp;
+		let host = unsafe { get_fs() };
+		let (inside, in_host) = unsafe {
+			let guest = GuestFs::enter(tp);
+			let inside = get_fs();
+			let host_fs = HostFs::enter();
+			let in_host = get_fs();
+			drop(host_fs);
+			drop(guest);
+			(inside, in_host)
+		};
+		assert_eq!(inside, tp);
+		assert_eq!(in_host, host);
+		assert_eq!(unsafe { get_fs() }, host);
+		// nothing to swap when no guest fs is in
+		drop(HostFs::enter());
+		assert_eq!(unsafe { get_fs() }, host);
+	}
+
+	#[test]
+	#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+	fn test_guest_fs_threads() {
+		use std::sync::Arc;
+		let ready = Arc::new(AtomicUsize::new(0));
+		let threads = (0..3).map(|_| {
+			let ready = ready.clone();
+			std::thread::spawn(move || {
+				let mut tcb = Box::new([0usize; 4]);
+				let tp = tcb.as_mut_ptr() as usize;
+				tcb[0] = tp;
+				let host = unsafe { get_fs() };
+				let (inside, in_host) = unsafe {
+					let guest = GuestFs::enter(tp);
+					// all of them in at once, without anything that could need host TLS
+					ready.fetch_add(1, Ordering::SeqCst);
+					while ready.load(Ordering::SeqCst) < 3 {
+						std::hint::spin_loop();
+					}
+					let host_fs = HostFs::enter();
+					let in_host = get_fs();
+					drop(host_fs);
+					let inside = get_fs();
+					drop(guest);
+					(inside, in_host)
+				};
+				assert_eq!((inside, in_host), (tp, host));
+				assert_eq!(unsafe { get_fs() }, host);
+			})
+		}).collect::<Vec<_>>();
+		for t in threads {
+			t.join().unwrap();
+		}
+	}
+
+	#[test]
+	fn test_futex_ops() {
+		unsafe {
+			let mut t = Threads::new();
+			let mut a = 5u32;
+			let mut b = 1u32;
+			let pa = &mut a as *mut u32 as usize;
+			let pb = &mut b as *mut u32 as usize;
+			assert_eq!(t.futex(pa, FUTEX_WAIT | FUTEX_PRIVATE_FLAG, 4, 0, 0, 0), Err(EAGAIN));
+			// nothing else could ever wake it
+			let ts = TimeSpec { tv_sec: 1, tv_nsec: 0 };
+			assert_eq!(t.futex(pa, FUTEX_WAIT, 5, &ts as *const TimeSpec as usize, 0, 0), Err(ETIMEDOUT));
+			assert_eq!(t.futex(pa, FUTEX_WAKE, 1, 0, 0, 0), Ok(0));
+			assert_eq!(t.futex(pa + 1, FUTEX_WAKE, 1, 0, 0, 0), Err(EINVAL));
+			assert_eq!(t.futex(pa, FUTEX_CMP_REQUEUE, 1, 1, pb, 4), Err(EAGAIN));
+			assert_eq!(t.futex(pa, FUTEX_LOCK_PI, 0, 0, 0, 0), Err(ENOSYS));
+			// b += 2, and compare the old value of b == 1
+			assert_eq!(t.futex(pa, FUTEX_WAKE_OP, 1, 1, pb, 1 << 28 | 2 << 12 | 1), Ok(0));
+			assert_eq!(b, 3);
+			// b |= 1 << 4
+			assert_eq!(t.futex(pa, FUTEX_WAKE_OP, 1, 1, pb, (8 | 2) << 28 | 4 << 12), Ok(0));
+			assert_eq!(b, 19);
+		}
+	}
+}
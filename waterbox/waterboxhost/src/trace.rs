@@ -0,0 +1,132 @@
+// strace-like descriptions of guest syscalls, for the syscall trace callback
+use crate::syscall_defs::*;
+use std::{os::raw::c_char, ffi::CStr};
+
+#[derive(Clone, Copy)]
+enum Arg {
+	Int,
+	Hex,
+	/// Pointer to a nul terminated string in guest memory
+	Str,
+}
+
+/// The arguments a syscall takes, if known.  Only the ones waterbox guests are likely to make are listed.
+fn signature(nr: &SyscallNumber) -> Option<&'static [Arg]> {
+	use Arg::*;
+	Some(match *nr {
+		NR_READ | NR_WRITE | NR_READV | NR_WRITEV => &[Int, Hex, Int],
+		NR_OPEN => &[Str, Hex, Hex],
+		NR_OPENAT => &[Int, Str, Hex, Hex],
+		NR_UNLINK => &[Str],
+		NR_UNLINKAT => &[Int, Str, Hex],
+		NR_RENAME => &[Str, Str],
+		NR_RENAMEAT => &[Int, Str, Int, Str],
+		NR_RENAMEAT2 => &[Int, Str, Int, Str, Hex],
+		NR_CLOSE | NR_DUP => &[Int],
+		NR_DUP2 => &[Int, Int],
+		NR_DUP3 => &[Int, Int, Hex],
+		NR_FCNTL => &[Int, Int, Hex],
+		NR_STAT | NR_LSTAT => &[Str, Hex],
+		NR_FSTAT => &[Int, Hex],
+		NR_NEWFSTATAT => &[Int, Str, Hex, Hex],
+		NR_STATX => &[Int, Str, Hex, Hex, Hex],
+		NR_LSEEK => &[Int, Int, Int],
+		NR_PREAD64 | NR_PWRITE64 | NR_PREADV | NR_PWRITEV => &[Int, Hex, Int, Int],
+		NR_PREADV2 | NR_PWRITEV2 => &[Int, Hex, Int, Int, Int, Hex],
+		NR_MMAP => &[Hex, Hex, Hex, Hex, Int, Hex],
+		NR_MPROTECT => &[Hex, Hex, Hex],
+		NR_MUNMAP => &[Hex, Hex],
+		NR_MREMAP => &[Hex, Hex, Hex, Hex, Hex],
+		NR_MADVISE => &[Hex, Hex, Int],
+		NR_BRK | NR_SET_TID_ADDRESS | NR_SET_THREAD_AREA => &[Hex],
+		NR_IOCTL => &[Int, Hex, Hex],
+		NR_TRUNCATE => &[Str, Int],
+		NR_FTRUNCATE => &[Int, Int],
+		NR_CLOCK_GETTIME | NR_CLOCK_GETRES => &[Int, Hex],
+		NR_GETTIMEOFDAY => &[Hex, Hex],
+		NR_TIME => &[Hex],
+		NR_CLOCK_SETTIME => &[Int, Hex],
+		NR_SETTIMEOFDAY => &[Hex, Hex],
+		NR_GETRUSAGE => &[Int, Hex],
+		NR_SYSINFO => &[Hex],
+		NR_SCHED_GETAFFINITY | NR_SCHED_SETAFFINITY => &[Int, Int, Hex],
+		NR_NANOSLEEP => &[Hex, Hex],
+		NR_CLOCK_NANOSLEEP => &[Int, Hex, Hex, Hex],
+		NR_GETRANDOM => &[Hex, Int, Hex],
+		NR_FUTEX => &[Hex, Int, Int, Hex, Hex, Hex],
+		NR_SOCKET => &[Int, Hex, Int],
+		NR_CONNECT => &[Int, Hex, Int],
+		NR_SENDTO | NR_RECVFROM => &[Int, Hex, Int, Hex, Hex, Hex],
+		NR_SHUTDOWN => &[Int, Int],
+		NR_SETSOCKOPT | NR_GETSOCKOPT => &[Int, Int, Int, Hex, Hex],
+		NR_POLL => &[Hex, Int, Int],
+		NR_PPOLL => &[Hex, Int, Hex, Hex, Int],
+		NR_EPOLL_CREATE | NR_EPOLL_CREATE1 => &[Hex],
+		NR_EPOLL_CTL => &[Int, Int, Int, Hex],
+		NR_EPOLL_WAIT => &[Int, Hex, Int, Int],
+		NR_EPOLL_PWAIT => &[Int, Hex, Int, Int, Hex],
+		NR_CLONE => &[Hex, Hex, Hex, Hex, Hex],
+		NR_EXIT => &[Int],
+		NR_ARCH_PRCTL => &[Hex, Hex],
+		NR_GETTID | NR_SCHED_YIELD => &[],
+		NR_WBX_REGISTER_MEMORY_DOMAIN => &[Str, Hex, Hex, Int, Hex],
+		NR_WBX_ENTROPY => &[Hex, Int],
+		NR_WBX_CREATE_ARENA => &[Str, Hex, Hex],
+		NR_WBX_DESTROY_ARENA => &[Str],
+		NR_WBX_SET_DOMAIN_TRANSLATOR => &[Str, Hex],
+		NR_WBX_CREATE_FIBER => &[Hex, Hex, Hex, Int],
+		NR_WBX_SWITCH_FIBER | NR_WBX_DESTROY_FIBER => &[Int],
+		NR_WBX_TRANSFER => &[Str, Hex, Int, Int],
+		_ => return None,
+	})
+}
+
+fn describe_arg(kind: Arg, val: usize) -> String {
+	match kind {
+		Arg::Int => format!("{}", val as isize),
+		Arg::Hex => format!("{:#x}", val),
+		Arg::Str if val == 0 => "NULL".to_string(),
+		Arg::Str => {
+			let s = unsafe { CStr::from_ptr(val as *const c_char) }.to_string_lossy();
+			if s.chars().count() > 64 {
+				format!("{:?}...", s.chars().take(64).collect::<String>())
+			} else {
+				format!("{:?}", s)
+			}
+		}
+	}
+}
+
+/// Describe a completed syscall, like `open("/foo", 0, 0) = ENOENT`.  Guest pointers to strings must still be valid.
+pub fn describe_syscall(nr: &SyscallNumber, args: &[usize; 6], ret: &SyscallReturn) -> String {
+	let args = match signature(nr) {
+		Some(sig) => sig.iter().zip(args.iter()).map(|(&k, &v)| describe_arg(k, v)).collect::<Vec<_>>(),
+		None => args.iter().map(|&v| describe_arg(Arg::Hex, v)).collect(),
+	};
+	let ret = if ret.0 > SyscallReturn::ERROR_THRESH {
+		lookup_errno(&SyscallError(-(ret.0 as isize) as i32)).to_string()
+	} else if ret.0 < 0x10000 {
+		format!("{}", ret.0)
+	} else {
+		format!("{:#x}", ret.0)
+	};
+	let name = lookup_syscall(nr);
+	format!("{}({}) = {}", name.strip_prefix("NR_").unwrap_or(name).to_lowercase(), args.join(", "), ret)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_describe() {
+		let path = std::ffi::CString::new("/dev/null").unwrap();
+		let args = [path.as_ptr() as usize, 0, 0, 0, 0, 0];
+		assert_eq!(describe_syscall(&NR_OPEN, &args, &syscall_ok(3)), "open(\"/dev/null\", 0x0, 0x0) = 3");
+		assert_eq!(describe_syscall(&NR_OPEN, &args, &syscall_err(ENOENT)), "open(\"/dev/null\", 0x0, 0x0) = ENOENT");
+		assert_eq!(describe_syscall(&NR_MUNMAP, &[0x36f00000000, 0x1000, 0, 0, 0, 0], &syscall_ok(0)),
+			"munmap(0x36f00000000, 0x1000) = 0");
+		assert_eq!(describe_syscall(&NR_GETPID, &[1, 2, 3, 4, 5, 6], &syscall_ok(0x20000)),
+			"getpid(0x1, 0x2, 0x3, 0x4, 0x5, 0x6) = 0x20000");
+	}
+}
@@ -0,0 +1,93 @@
+// Buffers in host memory that the guest fills directly, for what it hands the frontend every frame, like a video
+// frame or a batch of audio.  The frontend registers a buffer it has pinned under a name, and the guest copies into it
+// with NR_WBX_TRANSFER, which checks that what it sends fits, instead of the frontend copying the same data out of
+// guest memory through a call after every frame.  The buffers belong to the frontend, so none of this is in states.
+use crate::*;
+use syscall_defs::*;
+use std::ffi::CString;
+
+/// Longer names are turned down
+const MAX_NAME: usize = 255;
+
+/// How much of a buffer the guest has filled
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TransferInfo {
+	pub size: usize,
+	/// Where the guest's last transfer into it ended
+	pub filled: usize,
+	/// How many transfers it's had since it was registered
+	pub transfers: u64,
+}
+
+struct TransferBuffer {
+	name: CString,
+	data: *mut u8,
+	info: TransferInfo,
+}
+
+#[derive(Default)]
+pub struct Transfers {
+	buffers: Vec<TransferBuffer>,
+}
+impl Transfers {
+	/// Have the guest's transfers to `name` go to the `size` bytes at `data`, instead of wherever they went before
+	/// # Safety
+	/// `data` must stay valid for writes of `size` bytes until the buffer is unregistered or replaced
+	pub unsafe fn register(&mut self, name: &str, data: *mut u8, size: usize) -> anyhow::Result<()> {
+		if name.is_empty() || name.len() > MAX_NAME || data.is_null() || size == 0 {
+			return Err(coded(ErrorCode::InvalidArgument, "A transfer buffer needs a name and somewhere to go"))
+		}
+		let name = CString::new(name).map_err(|_| coded(ErrorCode::InvalidArgument, "Transfer buffer names can't have nuls in them"))?;
+		self.buffers.retain(|b| b.name != name);
+		self.buffers.push(TransferBuffer { name, data, info: TransferInfo { size, ..Default::default() } });
+		Ok(())
+	}
+	pub fn unregister(&mut self, name: &str) -> anyhow::Result<()> {
+		let before = self.buffers.len();
+		self.buffers.retain(|b| b.name.as_bytes() != name.as_bytes());
+		if self.buffers.len() == before {
+			return Err(coded(ErrorCode::NotFound, format!("No transfer buffer `{}`", name)))
+		}
+		Ok(())
+	}
+	pub fn info(&self, name: &str) -> anyhow::Result<TransferInfo> {
+		self.buffers.iter().find(|b| b.name.as_bytes() == name.as_bytes()).map(|b| b.info)
+			.ok_or_else(|| coded(ErrorCode::NotFound, format!("No transfer buffer `{}`", name)))
+	}
+	/// Copy `src` into buffer `name` at `offset`, for NR_WBX_TRANSFER
+	pub fn transfer(&mut self, name: &str, src: &[u8], offset: usize) -> SyscallResult {
+		let b = self.buffers.iter_mut().find(|b| b.name.as_bytes() == name.as_bytes()).ok_or(ENOENT)?;
+		let end = offset.checked_add(src.len()).ok_or(EMSGSIZE)?;
+		if end > b.info.size {
+			return Err(EMSGSIZE)
+		}
+		unsafe { std::slice::from_raw_parts_mut(b.data.add(offset), src.len()) }.copy_from_slice(src);
+		b.info.filled = end;
+		b.info.transfers += 1;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_transfers() -> anyhow::Result<()> {
+		let mut video = vec![0u8; 16];
+		let mut t = Transfers::default();
+		unsafe { t.register("video", video.as_mut_ptr(), video.len())? };
+		assert_eq!(ErrorCode::of(&unsafe { t.register("", video.as_mut_ptr(), 4) }.unwrap_err()), ErrorCode::InvalidArgument);
+		t.transfer("video", b"abcd", 0)?;
+		t.transfer("video", b"ef", 4)?;
+		assert_eq!(t.transfer("video", b"xyz", 14), Err(EMSGSIZE));
+		assert_eq!(t.transfer("video", b"x", usize::MAX), Err(EMSGSIZE));
+		assert_eq!(t.transfer("audio", b"x", 0), Err(ENOENT));
+		assert_eq!(t.info("video")?, TransferInfo { size: 16, filled: 6, transfers: 2 });
+		t.unregister("video")?;
+		assert_eq!(ErrorCode::of(&t.info("video").unwrap_err()), ErrorCode::NotFound);
+		assert_eq!(&video[..7], b"abcdef\0");
+		Ok(())
+	}
+}
@@ -0,0 +1,428 @@
+// Reading the wasm binary format.  Only what the interpreter runs is kept:  Custom sections are skipped, and so is
+// anything from proposals past the MVP other than sign extension, saturating truncation and bulk memory.
+use super::*;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValType {
+	I32,
+	I64,
+	F32,
+	F64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FuncType {
+	pub params: Vec<ValType>,
+	pub results: Vec<ValType>,
+}
+
+/// Where the block starting at some position ends, and its else, if it's an if that has one
+#[derive(Debug, Clone, Copy)]
+pub struct BlockEnd {
+	pub else_pos: Option<usize>,
+	pub end_pos: usize,
+}
+
+pub struct Function {
+	pub ty: u32,
+	/// Not counting the parameters
+	pub locals: Vec<ValType>,
+	/// Just the instructions, ending with the function's final `end`
+	pub code: Vec<u8>,
+	/// Keyed by the position of each block, loop and if instruction
+	pub blocks: HashMap<usize, BlockEnd>,
+}
+
+pub struct Import {
+	pub module: String,
+	pub name: String,
+	pub ty: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Export {
+	Func(u32),
+	Global(u32),
+	Memory,
+	Table,
+}
+
+pub struct Global {
+	pub ty: ValType,
+	pub mutable: bool,
+	pub init: u64,
+}
+
+pub struct DataSegment {
+	/// None for a passive segment, which is only copied by memory.init
+	pub offset: Option<u32>,
+	pub data: Vec<u8>,
+}
+
+pub struct ElementSegment {
+	pub offset: u32,
+	pub funcs: Vec<u32>,
+}
+
+pub struct Module {
+	pub types: Vec<FuncType>,
+	/// Imported functions, which come first in the function index space
+	pub imports: Vec<Import>,
+	pub functions: Vec<Function>,
+	/// The table's minimum size, if there is one
+	pub table: Option<u32>,
+	/// The linear memory's minimum size, in wasm pages, if there is one
+	pub memory: Option<u32>,
+	pub globals: Vec<Global>,
+	pub exports: HashMap<String, Export>,
+	pub start: Option<u32>,
+	pub elements: Vec<ElementSegment>,
+	pub data: Vec<DataSegment>,
+}
+
+pub struct Reader<'a> {
+	data: &'a [u8],
+	pub pos: usize,
+}
+impl<'a> Reader<'a> {
+	pub fn new(data: &'a [u8]) -> Reader<'a> {
+		Reader { data, pos: 0 }
+	}
+	pub fn done(&self) -> bool {
+		self.pos >= self.data.len()
+	}
+	pub fn u8(&mut self) -> anyhow::Result<u8> {
+		match self.data.get(self.pos) {
+			Some(&b) => {
+				self.pos += 1;
+				Ok(b)
+			},
+			None => Err(anyhow!("Unexpected end of wasm data")),
+		}
+	}
+	pub fn bytes(&mut self, n: usize) -> anyhow::Result<&'a [u8]> {
+		if self.data.len() - self.pos < n {
+			return Err(anyhow!("Unexpected end of wasm data"))
+		}
+		self.pos += n;
+		Ok(&self.data[self.pos - n..self.pos])
+	}
+	fn leb(&mut self, bits: u32, signed: bool) -> anyhow::Result<u64> {
+		let mut res = 0u64;
+		let mut shift = 0;
+		loop {
+			let b = self.u8()?;
+			if shift >= bits {
+				return Err(anyhow!("LEB128 number too long"))
+			}
+			res |= ((b & 0x7f) as u64) << shift;
+			shift += 7;
+			if b & 0x80 == 0 {
+				if signed && shift < 64 && b & 0x40 != 0 {
+					res |= !0 << shift;
+				}
+				return Ok(res)
+			}
+		}
+	}
+	pub fn u32(&mut self) -> anyhow::Result<u32> {
+		Ok(self.leb(32, false)? as u32)
+	}
+	pub fn i32(&mut self) -> anyhow::Result<i32> {
+		Ok(self.leb(32, true)? as i32)
+	}
+	pub fn i64(&mut self) -> anyhow::Result<i64> {
+		Ok(self.leb(64, true)? as i64)
+	}
+	/// The signed 33-bit number of a block type
+	pub fn i33(&mut self) -> anyhow::Result<i64> {
+		Ok(self.leb(33, true)? as i64)
+	}
+	pub fn f32(&mut self) -> anyhow::Result<u32> {
+		let mut b = [0u8; 4];
+		b.copy_from_slice(self.bytes(4)?);
+		Ok(u32::from_le_bytes(b))
+	}
+	pub fn f64(&mut self) -> anyhow::Result<u64> {
+		let mut b = [0u8; 8];
+		b.copy_from_slice(self.bytes(8)?);
+		Ok(u64::from_le_bytes(b))
+	}
+	fn name(&mut self) -> anyhow::Result<String> {
+		let len = self.u32()? as usize;
+		Ok(std::str::from_utf8(self.bytes(len)?)?.to_string())
+	}
+	fn valtype(&mut self) -> anyhow::Result<ValType> {
+		valtype(self.u8()?)
+	}
+	/// Minimum and maximum
+	fn limits(&mut self) -> anyhow::Result<(u32, Option<u32>)> {
+		match self.u8()? {
+			0 => Ok((self.u32()?, None)),
+			1 => Ok((self.u32()?, Some(self.u32()?))),
+			f => Err(anyhow!("Unsupported limits flags {}", f)),
+		}
+	}
+}
+
+fn valtype(b: u8) -> anyhow::Result<ValType> {
+	match b {
+		0x7f => Ok(ValType::I32),
+		0x7e => Ok(ValType::I64),
+		0x7d => Ok(ValType::F32),
+		0x7c => Ok(ValType::F64),
+		_ => Err(anyhow!("Unsupported value type {:#x}", b)),
+	}
+}
+
+/// A block's parameters and results
+#[derive(Debug, Clone, Copy)]
+pub struct BlockType {
+	pub params: usize,
+	pub results: usize,
+}
+pub fn block_type(r: &mut Reader, types: &[FuncType]) -> anyhow::Result<BlockType> {
+	let t = r.i33()?;
+	match t {
+		// the empty type, and the value types, in their signed forms
+		-0x40 => Ok(BlockType { params: 0, results: 0 }),
+		-4..=-1 => Ok(BlockType { params: 0, results: 1 }),
+		0..=0xffffffff => match types.get(t as usize) {
+			Some(ft) => Ok(BlockType { params: ft.params.len(), results: ft.results.len() }),
+			None => Err(anyhow!("Block type {} doesn't exist", t)),
+		},
+		_ => Err(anyhow!("Unsupported block type {}", t)),
+	}
+}
+
+/// A constant expression, as used for initializers and offsets
+fn const_expr(r: &mut Reader) -> anyhow::Result<u64> {
+	let res = match r.u8()? {
+		0x41 => r.i32()? as u32 as u64,
+		0x42 => r.i64()? as u64,
+		0x43 => r.f32()? as u64,
+		0x44 => r.f64()?,
+		op => return Err(anyhow!("Unsupported constant expression opcode {:#x}", op)),
+	};
+	if r.u8()? != 0x0b {
+		return Err(anyhow!("Constant expression has more than one instruction"))
+	}
+	Ok(res)
+}
+
+/// Skip the immediates of instruction `op`, failing if it's one the interpreter doesn't know
+fn skip_immediates(r: &mut Reader, op: u8, types: &[FuncType]) -> anyhow::Result<()> {
+	match op {
+		0x02..=0x04 => { block_type(r, types)?; },
+		0x0c | 0x0d | 0x10 | 0x20..=0x24 => { r.u32()?; },
+		0x0e => {
+			let n = r.u32()?;
+			for _ in 0..=n {
+				r.u32()?;
+			}
+		},
+		0x11 => {
+			r.u32()?;
+			r.u32()?;
+		},
+		0x1c => {
+			let n = r.u32()?;
+			for _ in 0..n {
+				r.valtype()?;
+			}
+		},
+		0x28..=0x3e => {
+			r.u32()?;
+			r.u32()?;
+		},
+		0x3f | 0x40 => { r.u8()?; },
+		0x41 => { r.i32()?; },
+		0x42 => { r.i64()?; },
+		0x43 => { r.f32()?; },
+		0x44 => { r.f64()?; },
+		0x00 | 0x01 | 0x05 | 0x0b | 0x0f | 0x1a | 0x1b | 0x45..=0xc4 => (),
+		0xfc => match r.u32()? {
+			0..=7 => (),
+			8 => {
+				r.u32()?;
+				r.u8()?;
+			},
+			9 => { r.u32()?; },
+			10 => {
+				r.u8()?;
+				r.u8()?;
+			},
+			11 => { r.u8()?; },
+			sub => return Err(anyhow!("Unsupported instruction 0xfc {}", sub)),
+		},
+		_ => return Err(anyhow!("Unsupported instruction {:#x}", op)),
+	}
+	Ok(())
+}
+
+/// Find where every block in `code` ends, checking that they nest properly and the last end is the function's
+fn find_blocks(code: &[u8], types: &[FuncType]) -> anyhow::Result<HashMap<usize, BlockEnd>> {
+	let mut res = HashMap::new();
+	// position and else position of each block still open
+	let mut open: Vec<(usize, Option<usize>)> = Vec::new();
+	let mut r = Reader::new(code);
+	while !r.done() {
+		let pos = r.pos;
+		let op = r.u8()?;
+		skip_immediates(&mut r, op, types)?;
+		match op {
+			0x02..=0x04 => open.push((pos, None)),
+			0x05 => match open.last_mut() {
+				Some((start, else_pos)) if code[*start] == 0x04 && else_pos.is_none() => *else_pos = Some(pos),
+				_ => return Err(anyhow!("else outside of an if")),
+			},
+			0x0b => match open.pop() {
+				Some((start, else_pos)) => { res.insert(start, BlockEnd { else_pos, end_pos: pos }); },
+				None if r.done() => return Ok(res),
+				None => return Err(anyhow!("Code continues after the function's end")),
+			},
+			_ => (),
+		}
+	}
+	Err(anyhow!("Function doesn't end"))
+}
+
+impl Module {
+	pub fn decode(data: &[u8]) -> anyhow::Result<Module> {
+		let mut r = Reader::new(data);
+		if r.bytes(8)? != [0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00] {
+			return Err(anyhow!("Not a version 1 wasm module"))
+		}
+		let mut m = Module {
+			types: Vec::new(),
+			imports: Vec::new(),
+			functions: Vec::new(),
+			table: None,
+			memory: None,
+			globals: Vec::new(),
+			exports: HashMap::new(),
+			start: None,
+			elements: Vec::new(),
+			data: Vec::new(),
+		};
+		let mut func_types = Vec::new();
+		while !r.done() {
+			let id = r.u8()?;
+			let size = r.u32()? as usize;
+			let mut s = Reader::new(r.bytes(size)?);
+			match id {
+				0 => continue,
+				1 => for _ in 0..s.u32()? {
+					if s.u8()? != 0x60 {
+						return Err(anyhow!("Malformed function type"))
+					}
+					let params = (0..s.u32()?).map(|_| s.valtype()).collect::<anyhow::Result<Vec<_>>>()?;
+					let results = (0..s.u32()?).map(|_| s.valtype()).collect::<anyhow::Result<Vec<_>>>()?;
+					m.types.push(FuncType { params, results });
+				},
+				2 => for _ in 0..s.u32()? {
+					let module = s.name()?;
+					let name = s.name()?;
+					match s.u8()? {
+						0 => m.imports.push(Import { module, name, ty: s.u32()? }),
+						// an imported memory is as good as one of our own, since it lives in guest memory either way
+						2 => m.memory = Some(s.limits()?.0),
+						_ => return Err(anyhow!("Import `{}.{}` isn't a function or memory, which isn't supported", module, name)),
+					}
+				},
+				3 => for _ in 0..s.u32()? {
+					func_types.push(s.u32()?);
+				},
+				4 => for _ in 0..s.u32()? {
+					if s.u8()? != 0x70 || m.table.is_some() {
+						return Err(anyhow!("Only one table of funcrefs is supported"))
+					}
+					m.table = Some(s.limits()?.0);
+				},
+				5 => for _ in 0..s.u32()? {
+					if m.memory.is_some() {
+						return Err(anyhow!("Only one memory is supported"))
+					}
+					m.memory = Some(s.limits()?.0);
+				},
+				6 => for _ in 0..s.u32()? {
+					let ty = s.valtype()?;
+					let mutable = s.u8()? != 0;
+					m.globals.push(Global { ty, mutable, init: const_expr(&mut s)? });
+				},
+				7 => for _ in 0..s.u32()? {
+					let name = s.name()?;
+					let export = match s.u8()? {
+						0 => Export::Func(s.u32()?),
+						1 => { s.u32()?; Export::Table },
+						2 => { s.u32()?; Export::Memory },
+						3 => Export::Global(s.u32()?),
+						k => return Err(anyhow!("Unknown export kind {}", k)),
+					};
+					m.exports.insert(name, export);
+				},
+				8 => m.start = Some(s.u32()?),
+				9 => for _ in 0..s.u32()? {
+					if s.u32()? != 0 {
+						return Err(anyhow!("Only active element segments of function indices are supported"))
+					}
+					let offset = const_expr(&mut s)? as u32;
+					let funcs = (0..s.u32()?).map(|_| s.u32()).collect::<anyhow::Result<Vec<_>>>()?;
+					m.elements.push(ElementSegment { offset, funcs });
+				},
+				10 => {
+					let count = s.u32()? as usize;
+					if count != func_types.len() {
+						return Err(anyhow!("Function and code sections don't match"))
+					}
+					for &ty in func_types.iter() {
+						let size = s.u32()? as usize;
+						let mut body = Reader::new(s.bytes(size)?);
+						let mut locals = Vec::new();
+						for _ in 0..body.u32()? {
+							let n = body.u32()?;
+							let t = body.valtype()?;
+							if locals.len() + n as usize > 50000 {
+								return Err(anyhow!("Function has too many locals"))
+							}
+							locals.resize(locals.len() + n as usize, t);
+						}
+						let code = body.bytes(size - body.pos)?.to_vec();
+						let blocks = find_blocks(&code, &m.types)?;
+						m.functions.push(Function { ty, locals, code, blocks });
+					}
+				},
+				11 => for _ in 0..s.u32()? {
+					let offset = match s.u32()? {
+						0 => Some(const_expr(&mut s)? as u32),
+						1 => None,
+						f => return Err(anyhow!("Unsupported data segment flags {}", f)),
+					};
+					let len = s.u32()? as usize;
+					m.data.push(DataSegment { offset, data: s.bytes(len)?.to_vec() });
+				},
+				// the data count, which only matters to validators
+				12 => (),
+				_ => return Err(anyhow!("Unknown section {}", id)),
+			}
+		}
+		if m.functions.len() != func_types.len() {
+			return Err(anyhow!("Function and code sections don't match"))
+		}
+		if m.imports.iter().map(|i| i.ty).chain(m.functions.iter().map(|f| f.ty)).any(|t| t as usize >= m.types.len()) {
+			return Err(anyhow!("Function type doesn't exist"))
+		}
+		Ok(m)
+	}
+	/// The type of function `index`, counting imports first
+	pub fn func_type(&self, index: u32) -> Option<&FuncType> {
+		let index = index as usize;
+		let ty = if index < self.imports.len() {
+			self.imports[index].ty
+		} else {
+			self.functions.get(index - self.imports.len())?.ty
+		};
+		self.types.get(ty as usize)
+	}
+}
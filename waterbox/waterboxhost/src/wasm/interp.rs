@@ -0,0 +1,656 @@
+// Running wasm code.  Modules aren't validated ahead of time, so everything that validation would have ruled out, like
+// popping an empty stack or using a local that doesn't exist, is checked as it happens and traps.  Values of every type
+// are kept as u64s:  i32s zero extended, and floats as their bits.  Guest calls don't recurse on the host stack, so
+// deep guest recursion only runs into MAX_FRAMES.
+use super::*;
+use super::decode::*;
+
+/// How deep guest calls can nest before trapping
+const MAX_FRAMES: usize = 100000;
+/// How many values the stack can hold, counting locals, before trapping
+const MAX_STACK: usize = 1 << 22;
+/// How many branches run between checks on the watchdog
+const BRANCHES_PER_CHECK: u32 = 1 << 16;
+
+/// What guest code can touch besides its stack
+pub struct Store {
+	pub globals: Vec<u64>,
+	pub table: Vec<Option<u32>>,
+	/// Where linear memory is valid.  Linear memory addresses are host addresses, so nothing below this is.
+	pub memory: AddressRange,
+	/// Passive data segments that have been dropped
+	pub dropped: Vec<bool>,
+}
+impl Store {
+	/// Where `size` bytes at linear memory address `ea` are
+	pub fn addr(&self, ea: u64, size: u64) -> anyhow::Result<usize> {
+		if ea < self.memory.start as u64 || ea + size > self.memory.end() as u64 {
+			return Err(anyhow!("Out of bounds memory access at {:#x}", ea))
+		}
+		Ok(ea as usize)
+	}
+}
+
+/// What guest code calls out to
+pub trait Host {
+	/// A call to imported function `index`, which can return at most one value
+	fn call(&mut self, store: &mut Store, index: usize, args: &[u64]) -> anyhow::Result<Option<u64>>;
+	/// How many 64KiB pages memory.size says there are
+	fn memory_size(&mut self) -> u32;
+	/// memory.grow by `pages`, returning whether there's that much more memory now
+	fn memory_grow(&mut self, pages: u32) -> bool;
+}
+
+#[derive(Clone, Copy)]
+struct Label {
+	/// The stack height the label's values go on top of
+	height: usize,
+	/// How many values a branch to it carries
+	arity: usize,
+	/// Where a branch to it goes:  The start of a loop, or the end of anything else
+	target: usize,
+}
+
+#[derive(Clone, Copy)]
+struct Frame {
+	/// Index into Module::functions
+	func: usize,
+	pc: usize,
+	/// Where the function's parameters and locals start on the stack
+	locals: usize,
+	nlocals: usize,
+	/// Where the function's own label is on the label stack
+	labels: usize,
+}
+
+fn enter(module: &Module, func: usize, stack: &mut Vec<u64>, labels: &mut Vec<Label>) -> anyhow::Result<Frame> {
+	let f = &module.functions[func];
+	let ty = &module.types[f.ty as usize];
+	if stack.len() < ty.params.len() {
+		return Err(anyhow!("Not enough values on the stack for a call"))
+	}
+	if stack.len() + f.locals.len() > MAX_STACK {
+		return Err(anyhow!("Guest stack exhausted"))
+	}
+	let locals = stack.len() - ty.params.len();
+	stack.resize(stack.len() + f.locals.len(), 0);
+	labels.push(Label { height: stack.len(), arity: ty.results.len(), target: f.code.len() - 1 });
+	Ok(Frame { func, pc: 0, locals, nlocals: ty.params.len() + f.locals.len(), labels: labels.len() - 1 })
+}
+
+macro_rules! minmax {
+	($t:ty, $min:ident, $max:ident, $nearest:ident) => {
+		fn $min(a: $t, b: $t) -> $t {
+			if a.is_nan() || b.is_nan() {
+				<$t>::NAN
+			} else if a == b {
+				// -0 is less than 0 here
+				if a.is_sign_negative() { a } else { b }
+			} else {
+				a.min(b)
+			}
+		}
+		fn $max(a: $t, b: $t) -> $t {
+			if a.is_nan() || b.is_nan() {
+				<$t>::NAN
+			} else if a == b {
+				if a.is_sign_positive() { a } else { b }
+			} else {
+				a.max(b)
+			}
+		}
+		/// Round to the nearest integer, with ties going to even
+		fn $nearest(a: $t) -> $t {
+			if (a - a.trunc()).abs() == 0.5 {
+				(a / 2.0).round() * 2.0
+			} else {
+				a.round()
+			}
+		}
+	};
+}
+minmax!(f32, f32_min, f32_max, f32_nearest);
+minmax!(f64, f64_min, f64_max, f64_nearest);
+
+/// Truncate for a conversion to an integer that traps when the result would be outside of [min, max)
+fn trunc(a: f64, min: f64, max: f64) -> anyhow::Result<f64> {
+	if a.is_nan() {
+		return Err(anyhow!("Invalid conversion to integer"))
+	}
+	let t = a.trunc();
+	if t < min || t >= max {
+		return Err(anyhow!("Integer overflow"))
+	}
+	Ok(t)
+}
+
+const I32_MIN: f64 = -2147483648.0;
+const I32_END: f64 = 2147483648.0;
+const U32_END: f64 = 4294967296.0;
+const I64_MIN: f64 = -9223372036854775808.0;
+const I64_END: f64 = 9223372036854775808.0;
+const U64_END: f64 = 18446744073709551616.0;
+
+/// Call function `func` of `module` with `args`, and return its results
+pub fn invoke(module: &Module, store: &mut Store, func: u32, args: &[u64], host: &mut dyn Host) -> anyhow::Result<Vec<u64>> {
+	let ty = match module.func_type(func) {
+		Some(t) => t,
+		None => return Err(anyhow!("Function {} doesn't exist", func)),
+	};
+	if args.len() != ty.params.len() {
+		return Err(anyhow!("Function {} takes {} arguments, not {}", func, ty.params.len(), args.len()))
+	}
+	let nimports = module.imports.len();
+	if (func as usize) < nimports {
+		return Ok(host.call(store, func as usize, args)?.into_iter().collect())
+	}
+
+	let mut stack = args.to_vec();
+	let mut labels = Vec::new();
+	let mut frames: Vec<Frame> = Vec::new();
+	let mut cur = enter(module, func as usize - nimports, &mut stack, &mut labels)?;
+	let mut r = Reader::new(&module.functions[cur.func].code);
+	let mut branches = 0u32;
+
+	macro_rules! pop {
+		() => {
+			match stack.pop() {
+				Some(v) => v,
+				None => return Err(anyhow!("Popped an empty stack")),
+			}
+		};
+	}
+	macro_rules! push {
+		($e:expr) => {{
+			let v = $e;
+			stack.push(v);
+		}};
+	}
+	macro_rules! trap {
+		($($t:tt)*) => {
+			return Err(anyhow!($($t)*))
+		};
+	}
+	macro_rules! i32_bin {
+		(|$a:ident, $b:ident| $e:expr) => {{
+			let $b = pop!() as u32;
+			let $a = pop!() as u32;
+			push!(($e) as u32 as u64);
+		}};
+	}
+	macro_rules! i64_bin {
+		(|$a:ident, $b:ident| $e:expr) => {{
+			let $b = pop!();
+			let $a = pop!();
+			push!(($e) as u64);
+		}};
+	}
+	macro_rules! i32_un {
+		(|$a:ident| $e:expr) => {{
+			let $a = pop!() as u32;
+			push!(($e) as u32 as u64);
+		}};
+	}
+	macro_rules! i64_un {
+		(|$a:ident| $e:expr) => {{
+			let $a = pop!();
+			push!(($e) as u64);
+		}};
+	}
+	macro_rules! f32_bin {
+		(|$a:ident, $b:ident| $e:expr) => {{
+			let $b = f32::from_bits(pop!() as u32);
+			let $a = f32::from_bits(pop!() as u32);
+			push!(f32::to_bits($e) as u64);
+		}};
+	}
+	macro_rules! f64_bin {
+		(|$a:ident, $b:ident| $e:expr) => {{
+			let $b = f64::from_bits(pop!());
+			let $a = f64::from_bits(pop!());
+			push!(f64::to_bits($e));
+		}};
+	}
+	macro_rules! f32_un {
+		(|$a:ident| $e:expr) => {{
+			let $a = f32::from_bits(pop!() as u32);
+			push!(f32::to_bits($e) as u64);
+		}};
+	}
+	macro_rules! f64_un {
+		(|$a:ident| $e:expr) => {{
+			let $a = f64::from_bits(pop!());
+			push!(f64::to_bits($e));
+		}};
+	}
+	macro_rules! f32_cmp {
+		(|$a:ident, $b:ident| $e:expr) => {{
+			let $b = f32::from_bits(pop!() as u32);
+			let $a = f32::from_bits(pop!() as u32);
+			push!(($e) as u64);
+		}};
+	}
+	macro_rules! f64_cmp {
+		(|$a:ident, $b:ident| $e:expr) => {{
+			let $b = f64::from_bits(pop!());
+			let $a = f64::from_bits(pop!());
+			push!(($e) as u64);
+		}};
+	}
+	macro_rules! load {
+		($t:ty, |$v:ident| $e:expr) => {{
+			let _align = r.u32()?;
+			let offset = r.u32()? as u64;
+			let ea = pop!() as u32 as u64 + offset;
+			let a = store.addr(ea, std::mem::size_of::<$t>() as u64)?;
+			let $v = unsafe { std::ptr::read_unaligned(a as *const $t) };
+			push!(($e) as u64);
+		}};
+	}
+	macro_rules! store {
+		($t:ty) => {{
+			let _align = r.u32()?;
+			let offset = r.u32()? as u64;
+			let v = pop!() as $t;
+			let ea = pop!() as u32 as u64 + offset;
+			let a = store.addr(ea, std::mem::size_of::<$t>() as u64)?;
+			unsafe { std::ptr::write_unaligned(a as *mut $t, v) };
+		}};
+	}
+	macro_rules! branch {
+		($depth:expr) => {{
+			let depth = $depth as usize;
+			let index = match labels.len().checked_sub(depth + 1) {
+				Some(i) if i >= cur.labels => i,
+				_ => trap!("Branch to label {}, which doesn't exist", depth),
+			};
+			let l = labels[index];
+			if stack.len() < l.height + l.arity {
+				trap!("Not enough values on the stack for a branch")
+			}
+			let values = stack.len() - l.arity;
+			stack.drain(l.height..values);
+			labels.truncate(index + 1);
+			r.pos = l.target;
+			branches = branches.wrapping_add(1);
+			if branches % BRANCHES_PER_CHECK == 0 {
+				unsafe { watchdog::check() }
+			}
+		}};
+	}
+	macro_rules! call {
+		($callee:expr) => {{
+			let callee = $callee as usize;
+			if callee < nimports {
+				let n = module.types[module.imports[callee].ty as usize].params.len();
+				if stack.len() < n {
+					trap!("Not enough values on the stack for a call")
+				}
+				let args = stack.split_off(stack.len() - n);
+				if let Some(v) = host.call(store, callee, &args)? {
+					push!(v);
+				}
+			} else {
+				if frames.len() >= MAX_FRAMES {
+					trap!("Guest call stack exhausted")
+				}
+				cur.pc = r.pos;
+				frames.push(cur);
+				cur = enter(module, callee - nimports, &mut stack, &mut labels)?;
+				r = Reader::new(&module.functions[cur.func].code);
+			}
+		}};
+	}
+
+	loop {
+		let op = r.u8()?;
+		match op {
+			0x00 => trap!("Reached unreachable code"),
+			0x01 => (),
+			0x02..=0x04 => {
+				let pos = r.pos - 1;
+				let bt = block_type(&mut r, &module.types)?;
+				let end = module.functions[cur.func].blocks[&pos];
+				if stack.len() < bt.params + if op == 0x04 { 1 } else { 0 } {
+					trap!("Not enough values on the stack for a block")
+				}
+				let cond = if op == 0x04 { pop!() as u32 } else { 1 };
+				let height = stack.len() - bt.params;
+				match op {
+					0x03 => labels.push(Label { height, arity: bt.params, target: r.pos }),
+					_ => labels.push(Label { height, arity: bt.results, target: end.end_pos }),
+				}
+				if cond == 0 {
+					r.pos = match end.else_pos {
+						Some(e) => e + 1,
+						None => end.end_pos,
+					};
+				}
+			},
+			// reached at the end of an if's first arm
+			0x05 => r.pos = labels.last().unwrap().target,
+			0x0b => {
+				labels.pop();
+				if labels.len() == cur.labels {
+					let arity = module.types[module.functions[cur.func].ty as usize].results.len();
+					if stack.len() < cur.locals + arity {
+						trap!("Not enough values on the stack for a return")
+					}
+					let results = stack.len() - arity;
+					stack.drain(cur.locals..results);
+					match frames.pop() {
+						Some(f) => {
+							cur = f;
+							r = Reader::new(&module.functions[cur.func].code);
+							r.pos = cur.pc;
+						},
+						None => return Ok(stack),
+					}
+				}
+			},
+			0x0c => branch!(r.u32()?),
+			0x0d => {
+				let depth = r.u32()?;
+				if pop!() as u32 != 0 {
+					branch!(depth);
+				}
+			},
+			0x0e => {
+				let n = r.u32()?;
+				let i = pop!() as u32;
+				let mut target = 0;
+				for j in 0..=n {
+					let t = r.u32()?;
+					if j == i || j == n {
+						target = t;
+						break
+					}
+				}
+				branch!(target);
+			},
+			0x0f => branch!(labels.len() - 1 - cur.labels),
+			0x10 => call!(r.u32()?),
+			0x11 => {
+				let ty = r.u32()? as usize;
+				let _table = r.u32()?;
+				let i = pop!() as u32 as usize;
+				let callee = match store.table.get(i) {
+					Some(&Some(f)) => f,
+					_ => trap!("Indirect call to undefined table element {}", i),
+				};
+				if module.types.get(ty) != module.func_type(callee) {
+					trap!("Indirect call through element {} has the wrong type", i)
+				}
+				call!(callee);
+			},
+			0x1a => { pop!(); },
+			0x1b | 0x1c => {
+				if op == 0x1c {
+					for _ in 0..r.u32()? {
+						r.u8()?;
+					}
+				}
+				let c = pop!() as u32;
+				let b = pop!();
+				let a = pop!();
+				push!(if c != 0 { a } else { b });
+			},
+			0x20..=0x22 => {
+				let i = r.u32()? as usize;
+				if i >= cur.nlocals {
+					trap!("Local {} doesn't exist", i)
+				}
+				let i = cur.locals + i;
+				match op {
+					0x20 => push!(stack[i]),
+					0x21 => stack[i] = pop!(),
+					_ => match stack.last() {
+						Some(&v) => stack[i] = v,
+						None => trap!("Popped an empty stack"),
+					},
+				}
+			},
+			0x23 => {
+				let i = r.u32()? as usize;
+				match store.globals.get(i) {
+					Some(&v) => push!(v),
+					None => trap!("Global {} doesn't exist", i),
+				}
+			},
+			0x24 => {
+				let i = r.u32()? as usize;
+				let v = pop!();
+				match (store.globals.get_mut(i), module.globals.get(i)) {
+					(Some(g), Some(info)) if info.mutable => *g = v,
+					_ => trap!("Global {} doesn't exist or isn't mutable", i),
+				}
+			},
+			0x28 => load!(u32, |v| v),
+			0x29 => load!(u64, |v| v),
+			0x2a => load!(u32, |v| v),
+			0x2b => load!(u64, |v| v),
+			0x2c => load!(i8, |v| v as i32 as u32),
+			0x2d => load!(u8, |v| v),
+			0x2e => load!(i16, |v| v as i32 as u32),
+			0x2f => load!(u16, |v| v),
+			0x30 => load!(i8, |v| v as i64),
+			0x31 => load!(u8, |v| v),
+			0x32 => load!(i16, |v| v as i64),
+			0x33 => load!(u16, |v| v),
+			0x34 => load!(i32, |v| v as i64),
+			0x35 => load!(u32, |v| v),
+			0x36 | 0x38 => store!(u32),
+			0x37 | 0x39 => store!(u64),
+			0x3a | 0x3c => store!(u8),
+			0x3b | 0x3d => store!(u16),
+			0x3e => store!(u32),
+			0x3f => {
+				r.u8()?;
+				push!(host.memory_size() as u64);
+			},
+			0x40 => {
+				r.u8()?;
+				let n = pop!() as u32;
+				let old = host.memory_size();
+				push!(if host.memory_grow(n) { old as u64 } else { 0xffffffff });
+			},
+			0x41 => push!(r.i32()? as u32 as u64),
+			0x42 => push!(r.i64()? as u64),
+			0x43 => push!(r.f32()? as u64),
+			0x44 => push!(r.f64()?),
+
+			0x45 => i32_un!(|a| a == 0),
+			0x46 => i32_bin!(|a, b| a == b),
+			0x47 => i32_bin!(|a, b| a != b),
+			0x48 => i32_bin!(|a, b| (a as i32) < b as i32),
+			0x49 => i32_bin!(|a, b| a < b),
+			0x4a => i32_bin!(|a, b| a as i32 > b as i32),
+			0x4b => i32_bin!(|a, b| a > b),
+			0x4c => i32_bin!(|a, b| a as i32 <= b as i32),
+			0x4d => i32_bin!(|a, b| a <= b),
+			0x4e => i32_bin!(|a, b| a as i32 >= b as i32),
+			0x4f => i32_bin!(|a, b| a >= b),
+			0x50 => i64_un!(|a| a == 0),
+			0x51 => i64_bin!(|a, b| a == b),
+			0x52 => i64_bin!(|a, b| a != b),
+			0x53 => i64_bin!(|a, b| (a as i64) < b as i64),
+			0x54 => i64_bin!(|a, b| a < b),
+			0x55 => i64_bin!(|a, b| a as i64 > b as i64),
+			0x56 => i64_bin!(|a, b| a > b),
+			0x57 => i64_bin!(|a, b| a as i64 <= b as i64),
+			0x58 => i64_bin!(|a, b| a <= b),
+			0x59 => i64_bin!(|a, b| a as i64 >= b as i64),
+			0x5a => i64_bin!(|a, b| a >= b),
+			0x5b => f32_cmp!(|a, b| a == b),
+			0x5c => f32_cmp!(|a, b| a != b),
+			0x5d => f32_cmp!(|a, b| a < b),
+			0x5e => f32_cmp!(|a, b| a > b),
+			0x5f => f32_cmp!(|a, b| a <= b),
+			0x60 => f32_cmp!(|a, b| a >= b),
+			0x61 => f64_cmp!(|a, b| a == b),
+			0x62 => f64_cmp!(|a, b| a != b),
+			0x63 => f64_cmp!(|a, b| a < b),
+			0x64 => f64_cmp!(|a, b| a > b),
+			0x65 => f64_cmp!(|a, b| a <= b),
+			0x66 => f64_cmp!(|a, b| a >= b),
+
+			0x67 => i32_un!(|a| a.leading_zeros()),
+			0x68 => i32_un!(|a| a.trailing_zeros()),
+			0x69 => i32_un!(|a| a.count_ones()),
+			0x6a => i32_bin!(|a, b| a.wrapping_add(b)),
+			0x6b => i32_bin!(|a, b| a.wrapping_sub(b)),
+			0x6c => i32_bin!(|a, b| a.wrapping_mul(b)),
+			0x6d => i32_bin!(|a, b| match (a as i32, b as i32) {
+				(_, 0) => trap!("Integer divide by zero"),
+				(std::i32::MIN, -1) => trap!("Integer overflow"),
+				(a, b) => a / b,
+			}),
+			0x6e => i32_bin!(|a, b| match a.checked_div(b) { Some(v) => v, None => trap!("Integer divide by zero") }),
+			0x6f => i32_bin!(|a, b| if b == 0 { trap!("Integer divide by zero") } else { (a as i32).wrapping_rem(b as i32) }),
+			0x70 => i32_bin!(|a, b| if b == 0 { trap!("Integer divide by zero") } else { a % b }),
+			0x71 => i32_bin!(|a, b| a & b),
+			0x72 => i32_bin!(|a, b| a | b),
+			0x73 => i32_bin!(|a, b| a ^ b),
+			0x74 => i32_bin!(|a, b| a.wrapping_shl(b)),
+			0x75 => i32_bin!(|a, b| (a as i32).wrapping_shr(b)),
+			0x76 => i32_bin!(|a, b| a.wrapping_shr(b)),
+			0x77 => i32_bin!(|a, b| a.rotate_left(b % 32)),
+			0x78 => i32_bin!(|a, b| a.rotate_right(b % 32)),
+			0x79 => i64_un!(|a| a.leading_zeros()),
+			0x7a => i64_un!(|a| a.trailing_zeros()),
+			0x7b => i64_un!(|a| a.count_ones()),
+			0x7c => i64_bin!(|a, b| a.wrapping_add(b)),
+			0x7d => i64_bin!(|a, b| a.wrapping_sub(b)),
+			0x7e => i64_bin!(|a, b| a.wrapping_mul(b)),
+			0x7f => i64_bin!(|a, b| match (a as i64, b as i64) {
+				(_, 0) => trap!("Integer divide by zero"),
+				(std::i64::MIN, -1) => trap!("Integer overflow"),
+				(a, b) => a / b,
+			}),
+			0x80 => i64_bin!(|a, b| match a.checked_div(b) { Some(v) => v, None => trap!("Integer divide by zero") }),
+			0x81 => i64_bin!(|a, b| if b == 0 { trap!("Integer divide by zero") } else { (a as i64).wrapping_rem(b as i64) }),
+			0x82 => i64_bin!(|a, b| if b == 0 { trap!("Integer divide by zero") } else { a % b }),
+			0x83 => i64_bin!(|a, b| a & b),
+			0x84 => i64_bin!(|a, b| a | b),
+			0x85 => i64_bin!(|a, b| a ^ b),
+			0x86 => i64_bin!(|a, b| a.wrapping_shl(b as u32)),
+			0x87 => i64_bin!(|a, b| (a as i64).wrapping_shr(b as u32)),
+			0x88 => i64_bin!(|a, b| a.wrapping_shr(b as u32)),
+			0x89 => i64_bin!(|a, b| a.rotate_left((b % 64) as u32)),
+			0x8a => i64_bin!(|a, b| a.rotate_right((b % 64) as u32)),
+
+			// abs, neg and copysign only touch the sign bit, even of NaNs
+			0x8b => i64_un!(|a| a & 0x7fffffff),
+			0x8c => i64_un!(|a| a ^ 0x80000000),
+			0x8d => f32_un!(|a| a.ceil()),
+			0x8e => f32_un!(|a| a.floor()),
+			0x8f => f32_un!(|a| a.trunc()),
+			0x90 => f32_un!(|a| f32_nearest(a)),
+			0x91 => f32_un!(|a| a.sqrt()),
+			0x92 => f32_bin!(|a, b| a + b),
+			0x93 => f32_bin!(|a, b| a - b),
+			0x94 => f32_bin!(|a, b| a * b),
+			0x95 => f32_bin!(|a, b| a / b),
+			0x96 => f32_bin!(|a, b| f32_min(a, b)),
+			0x97 => f32_bin!(|a, b| f32_max(a, b)),
+			0x98 => i64_bin!(|a, b| a & 0x7fffffff | b & 0x80000000),
+			0x99 => i64_un!(|a| a & 0x7fffffffffffffff),
+			0x9a => i64_un!(|a| a ^ 0x8000000000000000),
+			0x9b => f64_un!(|a| a.ceil()),
+			0x9c => f64_un!(|a| a.floor()),
+			0x9d => f64_un!(|a| a.trunc()),
+			0x9e => f64_un!(|a| f64_nearest(a)),
+			0x9f => f64_un!(|a| a.sqrt()),
+			0xa0 => f64_bin!(|a, b| a + b),
+			0xa1 => f64_bin!(|a, b| a - b),
+			0xa2 => f64_bin!(|a, b| a * b),
+			0xa3 => f64_bin!(|a, b| a / b),
+			0xa4 => f64_bin!(|a, b| f64_min(a, b)),
+			0xa5 => f64_bin!(|a, b| f64_max(a, b)),
+			0xa6 => i64_bin!(|a, b| a & 0x7fffffffffffffff | b & 0x8000000000000000),
+
+			0xa7 => i64_un!(|a| a as u32),
+			0xa8 => i32_un!(|a| trunc(f32::from_bits(a) as f64, I32_MIN, I32_END)? as i32),
+			0xa9 => i32_un!(|a| trunc(f32::from_bits(a) as f64, 0.0, U32_END)? as u32),
+			0xaa => i64_un!(|a| trunc(f64::from_bits(a), I32_MIN, I32_END)? as i32 as u32),
+			0xab => i64_un!(|a| trunc(f64::from_bits(a), 0.0, U32_END)? as u32),
+			0xac => i64_un!(|a| a as u32 as i32 as i64),
+			0xad => i64_un!(|a| a as u32),
+			0xae => i64_un!(|a| trunc(f32::from_bits(a as u32) as f64, I64_MIN, I64_END)? as i64),
+			0xaf => i64_un!(|a| trunc(f32::from_bits(a as u32) as f64, 0.0, U64_END)? as u64),
+			0xb0 => i64_un!(|a| trunc(f64::from_bits(a), I64_MIN, I64_END)? as i64),
+			0xb1 => i64_un!(|a| trunc(f64::from_bits(a), 0.0, U64_END)? as u64),
+			0xb2 => i64_un!(|a| (a as u32 as i32 as f32).to_bits()),
+			0xb3 => i64_un!(|a| (a as u32 as f32).to_bits()),
+			0xb4 => i64_un!(|a| (a as i64 as f32).to_bits()),
+			0xb5 => i64_un!(|a| (a as f32).to_bits()),
+			0xb6 => i64_un!(|a| (f64::from_bits(a) as f32).to_bits()),
+			0xb7 => i64_un!(|a| (a as u32 as i32 as f64).to_bits()),
+			0xb8 => i64_un!(|a| (a as u32 as f64).to_bits()),
+			0xb9 => i64_un!(|a| (a as i64 as f64).to_bits()),
+			0xba => i64_un!(|a| (a as f64).to_bits()),
+			0xbb => i64_un!(|a| (f32::from_bits(a as u32) as f64).to_bits()),
+			// the reinterpretations don't change any bits
+			0xbc..=0xbf => (),
+
+			0xc0 => i32_un!(|a| a as i8 as i32),
+			0xc1 => i32_un!(|a| a as i16 as i32),
+			0xc2 => i64_un!(|a| a as i8 as i64),
+			0xc3 => i64_un!(|a| a as i16 as i64),
+			0xc4 => i64_un!(|a| a as i32 as i64),
+
+			0xfc => match r.u32()? {
+				// float to int casts saturate, and turn NaN into 0, just as these want
+				0 => i64_un!(|a| f32::from_bits(a as u32) as i32 as u32),
+				1 => i64_un!(|a| f32::from_bits(a as u32) as u32),
+				2 => i64_un!(|a| f64::from_bits(a) as i32 as u32),
+				3 => i64_un!(|a| f64::from_bits(a) as u32),
+				4 => i64_un!(|a| f32::from_bits(a as u32) as i64),
+				5 => i64_un!(|a| f32::from_bits(a as u32) as u64),
+				6 => i64_un!(|a| f64::from_bits(a) as i64),
+				7 => i64_un!(|a| f64::from_bits(a) as u64),
+				8 => {
+					let seg = r.u32()? as usize;
+					r.u8()?;
+					let n = pop!() as u32 as usize;
+					let s = pop!() as u32 as usize;
+					let d = pop!() as u32 as u64;
+					let data = match module.data.get(seg) {
+						Some(ds) if !store.dropped[seg] => &ds.data[..],
+						Some(_) => &[][..],
+						None => trap!("Data segment {} doesn't exist", seg),
+					};
+					if s + n > data.len() {
+						trap!("Out of bounds data segment access")
+					}
+					let a = store.addr(d, n as u64)?;
+					unsafe { AddressRange { start: a, size: n }.slice_mut().copy_from_slice(&data[s..s + n]) }
+				},
+				9 => {
+					let seg = r.u32()? as usize;
+					match store.dropped.get_mut(seg) {
+						Some(d) => *d = true,
+						None => trap!("Data segment {} doesn't exist", seg),
+					}
+				},
+				10 => {
+					r.u8()?;
+					r.u8()?;
+					let n = pop!() as u32 as u64;
+					let s = store.addr(pop!() as u32 as u64, n)?;
+					let d = store.addr(pop!() as u32 as u64, n)?;
+					unsafe { std::ptr::copy(s as *const u8, d as *mut u8, n as usize) }
+				},
+				11 => {
+					r.u8()?;
+					let n = pop!() as u32 as u64;
+					let v = pop!() as u8;
+					let d = store.addr(pop!() as u32 as u64, n)?;
+					unsafe { std::ptr::write_bytes(d as *mut u8, v, n as usize) }
+				},
+				sub => trap!("Unsupported instruction 0xfc {}", sub),
+			},
+			_ => trap!("Unsupported instruction {:#x}", op),
+		}
+	}
+}
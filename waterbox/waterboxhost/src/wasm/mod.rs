@@ -0,0 +1,1387 @@
+// Guests compiled to wasm, for platforms where guest code can't run natively.  The module is interpreted, and its linear
+// memory is guest memory:  Linear memory addresses are host addresses, so the module has to be linked to live inside
+// the guest memory layout (wasm-ld's --global-base), and then everything the host does with guest memory, like dirty
+// tracking, savestates and syscall arguments, works on it unchanged.  memory.grow is brk, and linear memory ends at the
+// program break as far as memory.size is concerned, though mmap works too.
+// Modules either make syscalls through the import `env.__wbx_syscall`, and find the usual __wbxsysarea through an
+// exported global holding its address, or import WASI instead (see wasi.rs); which one is up to their imports.  The
+// frontend calls exports through host functions that get_proc_addr() hands out.
+use crate::*;
+use crate::memory_block::{ActivatedMemoryBlock, Protection};
+use crate::syscall_defs::SyscallNumber;
+use crate::startup::StartInfo;
+use std::sync::Mutex;
+use lazy_static::lazy_static;
+
+mod decode;
+mod interp;
+mod wasi;
+
+pub use decode::Module;
+use decode::{Export, ValType};
+use interp::Store;
+use syscall_defs::NR_BRK;
+
+/// If `data` looks like a wasm module rather than an ELF
+pub fn is_wasm(data: &[u8]) -> bool {
+	data.starts_with(b"\0asm")
+}
+
+impl Module {
+	/// Where the module's data goes:  From its lowest data segment to the end of its initial memory
+	pub fn image_addr(&self) -> anyhow::Result<AddressRange> {
+		let memory = match self.memory {
+			Some(m) => m as usize * 0x10000,
+			None => return Err(anyhow!("Module has no memory")),
+		};
+		let active = self.data.iter().filter_map(|d| d.offset.map(|o| (o as usize, o as usize + d.data.len())));
+		let start = match active.clone().map(|(s, _)| s).min() {
+			Some(s) => s,
+			None => return Err(anyhow!("Module has no data, so there's no telling where it was linked to go")),
+		};
+		let end = std::cmp::max(memory, active.map(|(_, e)| e).max().unwrap());
+		Ok(AddressRange { start, size: end - start })
+	}
+}
+
+/// Which imports a module makes syscalls through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Personality {
+	/// The waterbox sysroot's
+	Waterbox,
+	Wasi,
+}
+
+/// The functions a module can import from the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HostImport {
+	/// (nr: i32, a1..a6: i64) -> i64
+	Syscall,
+	Wasi(wasi::Function),
+}
+
+pub struct Instance {
+	module: Module,
+	store: Store,
+	personality: Personality,
+	imports: Vec<HostImport>,
+	/// The ActivatedWaterboxHost, while there is one
+	ud: usize,
+	/// What WASI guests get from args_get and environ_get
+	start_info: StartInfo,
+}
+impl Instance {
+	/// Put the module's data and table in place.  `layout` is where all of linear memory is.
+	pub fn new(module: Module, layout: &WbxSysLayout, b: &mut ActivatedMemoryBlock) -> anyhow::Result<Box<Instance>> {
+		let mut imports = Vec::new();
+		for import in module.imports.iter() {
+			let ty = &module.types[import.ty as usize];
+			let host = match (import.module.as_str(), import.name.as_str()) {
+				("env", "__wbx_syscall") => {
+					let params = [ValType::I32, ValType::I64, ValType::I64, ValType::I64, ValType::I64, ValType::I64, ValType::I64];
+					if ty.params != params || ty.results != [ValType::I64] {
+						return Err(anyhow!("Module imports `{}.{}` with the wrong type", import.module, import.name))
+					}
+					HostImport::Syscall
+				},
+				(wasi::MODULE, name) => HostImport::Wasi(wasi::lookup(name, ty)?),
+				_ => return Err(anyhow!("Module imports `{}.{}`, which the host doesn't provide", import.module, import.name)),
+			};
+			imports.push(host);
+		}
+		let personality = match (imports.contains(&HostImport::Syscall), imports.iter().any(|i| *i != HostImport::Syscall)) {
+			(true, true) => return Err(anyhow!("Module imports both WASI and waterbox syscalls")),
+			(false, true) => Personality::Wasi,
+			_ => Personality::Waterbox,
+		};
+
+		let image = module.image_addr()?;
+		b.mmap_fixed(image.align_expand(), Protection::RW, true)?;
+		let mut store = Store {
+			globals: module.globals.iter().map(|g| g.init).collect(),
+			table: vec![None; module.table.unwrap_or(0) as usize],
+			memory: layout.all(),
+			dropped: vec![false; module.data.len()],
+		};
+		for d in module.data.iter() {
+			if let Some(offset) = d.offset {
+				let addr = AddressRange { start: offset as usize, size: d.data.len() };
+				if addr.start < image.start || addr.end() > image.end() {
+					return Err(anyhow!("Data segment at {:#x} is outside of the module's memory", offset))
+				}
+				unsafe { addr.slice_mut().copy_from_slice(&d.data[..]); }
+			}
+		}
+		for e in module.elements.iter() {
+			let start = e.offset as usize;
+			if start + e.funcs.len() > store.table.len() {
+				return Err(anyhow!("Element segment at {} is outside of the table", start))
+			}
+			for (i, &f) in e.funcs.iter().enumerate() {
+				store.table[start + i] = Some(f);
+			}
+		}
+		Ok(Box::new(Instance { module, store, personality, imports, ud: 0, start_info: StartInfo::default() }))
+	}
+	pub fn personality(&self) -> Personality {
+		self.personality
+	}
+	/// The value of an exported i32 global, which is how wasm-ld exports the address of data
+	pub fn export_address(&self, name: &str) -> Option<usize> {
+		match self.module.exports.get(name) {
+			Some(&Export::Global(i)) => self.store.globals.get(i as usize).map(|&v| v as u32 as usize),
+			_ => None,
+		}
+	}
+	/// Syscalls made from now on go to this ActivatedWaterboxHost
+	pub fn connect(&mut self, ud: usize) {
+		self.ud = ud;
+	}
+	/// Set the arguments and environment variables a WASI guest sees, before init()
+	pub fn set_start_info(&mut self, start_info: StartInfo) {
+		self.start_info = start_info;
+	}
+	fn invoke(&mut self, func: u32, args: &[u64]) -> anyhow::Result<Vec<u64>> {
+		let mut connection = Connection { imports: &self.imports, ud: self.ud, start_info: &self.start_info };
+		interp::invoke(&self.module, &mut self.store, func, args, &mut connection)
+	}
+	/// Run the start function, and then `_initialize` or `_start`, whichever is exported
+	pub fn init(&mut self) {
+		let start = self.module.start;
+		let entry = ["_initialize", "_start"].iter().find_map(|name| match self.module.exports.get(*name) {
+			Some(&Export::Func(f)) => Some(f),
+			_ => None,
+		});
+		for func in start.into_iter().chain(entry) {
+			if let Err(e) = self.invoke(func, &[]) {
+				trapped(e)
+			}
+		}
+	}
+	/// A host function that calls export `name`, or 0 if it isn't an exported function that the frontend can call.
+	/// Those take up to six integer arguments, and return nothing or an integer.
+	pub fn proc_addr(&self, name: &str) -> usize {
+		let func = match self.module.exports.get(name) {
+			Some(&Export::Func(f)) => f,
+			_ => return 0,
+		};
+		let ty = match self.module.func_type(func) {
+			Some(t) => t,
+			None => return 0,
+		};
+		let integer = |t: &ValType| *t == ValType::I32 || *t == ValType::I64;
+		if ty.params.len() > 6 || ty.results.len() > 1 || !ty.params.iter().chain(ty.results.iter()).all(integer) {
+			return 0
+		}
+		let me = self as *const Instance as *mut Instance;
+		let mut slots = SLOTS.lock().unwrap();
+		let index = match slots.0.iter().position(|s| s.map(|s| s.instance == me && s.func == func).unwrap_or(false)) {
+			Some(i) => i,
+			None => match slots.0.iter().position(|s| s.is_none()) {
+				Some(i) => {
+					slots.0[i] = Some(Slot { instance: me, func });
+					i
+				},
+				None => {
+					log!(Error, "Can't call `{}`:  All {} wasm exports that can be called are taken", name, TRAMPOLINES.len());
+					return 0
+				},
+			},
+		};
+		TRAMPOLINES[index] as usize
+	}
+	/// Called through a trampoline
+	fn call_export(&mut self, func: u32, args: &[usize; 6]) -> usize {
+		let ty = self.module.func_type(func).unwrap();
+		let args = ty.params.iter().zip(args.iter())
+			.map(|(t, &a)| if *t == ValType::I32 { a as u32 as u64 } else { a as u64 })
+			.collect::<Vec<_>>();
+		match self.invoke(func, &args) {
+			Ok(res) => res.first().map(|&v| v as usize).unwrap_or(0),
+			Err(e) => trapped(e),
+		}
+	}
+}
+impl Drop for Instance {
+	fn drop(&mut self) {
+		let me = self as *mut Instance;
+		for s in SLOTS.lock().unwrap().0.iter_mut() {
+			if s.map(|s| s.instance == me).unwrap_or(false) {
+				*s = None;
+			}
+		}
+	}
+}
+
+/// What guest code calls out to, which is the ActivatedWaterboxHost `ud`
+struct Connection<'a> {
+	imports: &'a [HostImport],
+	ud: usize,
+	start_info: &'a StartInfo,
+}
+impl interp::Host for Connection<'_> {
+	fn call(&mut self, store: &mut Store, index: usize, args: &[u64]) -> anyhow::Result<Option<u64>> {
+		match self.imports[index] {
+			HostImport::Syscall => {
+				let ret = host::syscall(SyscallNumber(args[0] as u32 as usize), self.ud,
+					args[1] as usize, args[2] as usize, args[3] as usize, args[4] as usize, args[5] as usize, args[6] as usize);
+				Ok(Some(ret.0 as u64))
+			},
+			HostImport::Wasi(f) => wasi::call(f, self.ud, store, args, self.start_info),
+		}
+	}
+	fn memory_size(&mut self) -> u32 {
+		((host::program_break(self.ud) + 0xffff) >> 16) as u32
+	}
+	fn memory_grow(&mut self, pages: u32) -> bool {
+		let end = (self.memory_size() as usize + pages as usize) << 16;
+		end <= 1 << 32 && host::syscall(NR_BRK, self.ud, end, 0, 0, 0, 0, 0).0 == end
+	}
+}
+
+const MAGIC: &str = "WasmInstance";
+
+impl IStateable for Instance {
+	fn save_state(&mut self, stream: &mut dyn Write) -> anyhow::Result<()> {
+		bin::write_magic(stream, MAGIC)?;
+		bin::writeval(stream, self.store.globals.len())?;
+		for g in self.store.globals.iter() {
+			bin::write(stream, g)?;
+		}
+		for &d in self.store.dropped.iter() {
+			bin::writeval(stream, d as u8)?;
+		}
+		Ok(())
+	}
+	fn load_state(&mut self, stream: &mut dyn Read) -> anyhow::Result<()> {
+		bin::verify_magic(stream, MAGIC)?;
+		if bin::readval::<usize>(stream)? != self.store.globals.len() {
+			return Err(anyhow!("Savestate has a different number of wasm globals"))
+		}
+		for g in self.store.globals.iter_mut() {
+			bin::read(stream, g)?;
+		}
+		for d in self.store.dropped.iter_mut() {
+			*d = bin::readval::<u8>(stream)? != 0;
+		}
+		Ok(())
+	}
+}
+
+/// Guest code can't go on, so abandon the call it's in if that's being watched, or bring everything down like a native
+/// guest fault would
+fn trapped(e: anyhow::Error) -> ! {
+	log!(Error, "Wasm guest trapped:  {}", e);
+	unsafe { watchdog::abandon(watchdog::Abandoned::Trapped) }
+	std::process::abort()
+}
+
+/// Which export of which instance a trampoline calls
+#[derive(Clone, Copy)]
+struct Slot {
+	instance: *mut Instance,
+	func: u32,
+}
+struct Slots(Vec<Option<Slot>>);
+unsafe impl Send for Slots {}
+
+lazy_static! {
+	static ref SLOTS: Mutex<Slots> = Mutex::new(Slots(vec![None; TRAMPOLINES.len()]));
+}
+
+fn call_slot(index: usize, args: [usize; 6]) -> usize {
+	let slot = SLOTS.lock().unwrap().0[index];
+	match slot {
+		// the instance is still there, as it clears its slots when dropped
+		Some(s) => unsafe { (*s.instance).call_export(s.func, &args) },
+		None => {
+			log!(Error, "A wasm export was called after its guest went away");
+			std::process::abort()
+		},
+	}
+}
+
+/// A host function standing in for an export
+type Trampoline = guest_abi!(fn(usize, usize, usize, usize, usize, usize) -> usize);
+
+macro_rules! trampolines {
+	($($n:expr),*) => {
+		[$({
+			guest_abi! { fn t(a1: usize, a2: usize, a3: usize, a4: usize, a5: usize, a6: usize) -> usize {
+				call_slot($n, [a1, a2, a3, a4, a5, a6])
+			}}
+			t as Trampoline
+		}),*]
+	};
+}
+static TRAMPOLINES: [Trampoline; 128] = trampolines!(
+	0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31,
+	32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47, 48, 49, 50, 51, 52, 53, 54, 55, 56, 57, 58, 59, 60,
+	61, 62, 63, 64, 65, 66, 67, 68, 69, 70, 71, 72, 73, 74, 75, 76, 77, 78, 79, 80, 81, 82, 83, 84, 85, 86, 87, 88, 89,
+	90, 91, 92, 93, 94, 95, 96, 97, 98, 99, 100, 101, 102, 103, 104, 105, 106, 107, 108, 109, 110, 111, 112, 113, 114,
+	115, 116, 117, 118, 119, 120, 121, 122, 123, 124, 125, 126, 127
+);
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::memory_block::MemoryBlock;
+	use std::ffi::CString;
+
+	fn leb(v: &mut Vec<u8>, mut n: u64) {
+		loop {
+			let b = (n & 0x7f) as u8;
+			n >>= 7;
+			if n == 0 {
+				v.push(b);
+				return
+			}
+			v.push(b | 0x80);
+		}
+	}
+	fn sleb(v: &mut Vec<u8>, mut n: i64) {
+		loop {
+			let b = (n & 0x7f) as u8;
+			n >>= 7;
+			if (n == 0 && b & 0x40 == 0) || (n == -1 && b & 0x40 != 0) {
+				v.push(b);
+				return
+			}
+			v.push(b | 0x80);
+		}
+	}
+	fn section(m: &mut Vec<u8>, id: u8, body: &[u8]) {
+		m.push(id);
+		leb(m, body.len() as u64);
+		m.extend_from_slice(body);
+	}
+	fn name(v: &mut Vec<u8>, s: &str) {
+		leb(v, s.len() as u64);
+		v.extend_from_slice(s.as_bytes());
+	}
+
+	/// A module with its data at `base`, and a few functions to call
+	fn test_module(base: usize) -> Vec<u8> {
+		let mut m = b"\0asm\x01\0\0\0".to_vec();
+		section(&mut m, 1, &[4,
+			0x60, 1, 0x7f, 1, 0x7f,
+			0x60, 2, 0x7f, 0x7f, 1, 0x7f,
+			0x60, 7, 0x7f, 0x7e, 0x7e, 0x7e, 0x7e, 0x7e, 0x7e, 1, 0x7e,
+			0x60, 0, 0,
+		]);
+		let mut imports = vec![1];
+		name(&mut imports, "env");
+		name(&mut imports, "__wbx_syscall");
+		imports.extend_from_slice(&[0, 2]);
+		section(&mut m, 2, &imports);
+		section(&mut m, 3, &[6, 0, 1, 1, 1, 0, 0]);
+		section(&mut m, 4, &[1, 0x70, 0, 1]);
+		let mut memory = vec![1, 0];
+		leb(&mut memory, (base as u64 + 0x10000) >> 16);
+		section(&mut m, 5, &memory);
+		// __wbxsysarea, and a counter
+		let mut globals = vec![2, 0x7f, 0, 0x41];
+		sleb(&mut globals, base as i32 as i64 + 0x100);
+		globals.extend_from_slice(&[0x0b, 0x7f, 1, 0x41, 0, 0x0b]);
+		section(&mut m, 6, &globals);
+		let mut exports = vec![7];
+		for (i, n) in ["fact", "div", "indirect", "store_load", "sys", "grow"].iter().enumerate() {
+			name(&mut exports, n);
+			exports.extend_from_slice(&[0, i as u8 + 1]);
+		}
+		name(&mut exports, "__wbxsysarea");
+		exports.extend_from_slice(&[3, 0]);
+		section(&mut m, 7, &exports);
+		section(&mut m, 9, &[1, 0, 0x41, 0, 0x0b, 1, 1]);
+		let bodies: [&[u8]; 6] = [
+			// fact(n):  acc = 1; while n != 0 { acc *= n; n -= 1; } acc
+			&[1, 1, 0x7f, 0x41, 1, 0x21, 1, 0x02, 0x40, 0x03, 0x40, 0x20, 0, 0x45, 0x0d, 1, 0x20, 1, 0x20, 0, 0x6c, 0x21, 1,
+				0x20, 0, 0x41, 1, 0x6b, 0x21, 0, 0x0c, 0, 0x0b, 0x0b, 0x20, 1, 0x0b],
+			// div(a, b):  a / b, unsigned
+			&[0, 0x20, 0, 0x20, 1, 0x6e, 0x0b],
+			// indirect(a, i):  table[i](a)
+			&[0, 0x20, 0, 0x20, 1, 0x11, 0, 0, 0x0b],
+			// store_load(addr, v):  *addr = v; ((u8*)addr)[1]
+			&[0, 0x20, 0, 0x20, 1, 0x36, 2, 0, 0x20, 0, 0x2d, 0, 1, 0x0b],
+			// sys(nr):  __wbx_syscall(nr, 5, 0, 0, 0, 0, 0)
+			&[0, 0x20, 0, 0x42, 5, 0x42, 0, 0x42, 0, 0x42, 0, 0x42, 0, 0x42, 0, 0x10, 0, 0xa7, 0x0b],
+			// grow(n):  memory.grow(n)
+			&[0, 0x20, 0, 0x40, 0, 0x0b],
+		];
+		let mut code = vec![bodies.len() as u8];
+		for b in bodies.iter() {
+			leb(&mut code, b.len() as u64);
+			code.extend_from_slice(b);
+		}
+		section(&mut m, 10, &code);
+		let mut data = vec![1, 0, 0x41];
+		sleb(&mut data, base as i32 as i64);
+		data.extend_from_slice(&[0x0b, 4, 1, 2, 3, 4]);
+		section(&mut m, 11, &data);
+		m
+	}
+
+	fn test_layout(base: usize) -> WbxSysLayout {
+		let at = |i: usize| AddressRange { start: base + i * 0x10000, size: 0x10000 };
+		WbxSysLayout { elf: at(0), sbrk: at(1), sealed: at(2), invis: at(3), plain: at(4), mmap: at(5) }
+	}
+
+	#[test]
+	fn test_decode() -> anyhow::Result<()> {
+		let data = test_module(0x58600000);
+		assert!(is_wasm(&data));
+		let m = Module::decode(&data)?;
+		assert_eq!(m.image_addr()?, AddressRange { start: 0x58600000, size: 0x10000 });
+		assert_eq!(m.functions.len(), 6);
+		assert!(Module::decode(&data[..data.len() - 1]).is_err());
+		Ok(())
+	}
+
+	struct FakeHost {
+		calls: Vec<(usize, Vec<u64>)>,
+		pages: u32,
+	}
+	impl interp::Host for FakeHost {
+		fn call(&mut self, _store: &mut Store, index: usize, args: &[u64]) -> anyhow::Result<Option<u64>> {
+			self.calls.push((index, args.to_vec()));
+			Ok(Some(args[0] + args[1]))
+		}
+		fn memory_size(&mut self) -> u32 {
+			self.pages
+		}
+		fn memory_grow(&mut self, pages: u32) -> bool {
+			self.pages += pages;
+			pages < 10
+		}
+	}
+
+	#[test]
+	fn test_interp() -> anyhow::Result<()> {
+		let base = 0x58700000;
+		let data = test_module(base);
+		let m = Module::decode(&data)?;
+		let mut b = MemoryBlock::new(test_layout(base).all());
+		let mut g = b.enter();
+		g.mmap_fixed(test_layout(base).all(), Protection::RW, true)?;
+		let mut store = Store {
+			globals: vec![0; 2],
+			table: vec![Some(1)],
+			memory: test_layout(base).all(),
+			dropped: vec![false],
+		};
+		let mut host = FakeHost { calls: Vec::new(), pages: 3 };
+		let mut call = |func: u32, args: &[u64]| interp::invoke(&m, &mut store, func, args, &mut host);
+		assert_eq!(call(1, &[5])?, vec![120]);
+		assert_eq!(call(2, &[7, 2])?, vec![3]);
+		assert!(call(2, &[7, 0]).is_err());
+		assert_eq!(call(3, &[6, 0])?, vec![720]);
+		assert!(call(3, &[6, 1]).is_err());
+		assert_eq!(call(4, &[base as u64 + 0x20, 0x11223344])?, vec![0x33]);
+		// outside of guest memory
+		assert!(call(4, &[0x1000, 0x11223344]).is_err());
+		assert_eq!(call(5, &[10])?, vec![15]);
+		assert_eq!(call(6, &[2])?, vec![3]);
+		assert_eq!(call(6, &[20])?, vec![0xffffffff]);
+		assert_eq!(host.calls, vec![(0, vec![10, 5, 0, 0, 0, 0, 0])]);
+		Ok(())
+	}
+
+	#[test]
+	fn test_instance() -> anyhow::Result<()> {
+		let base = 0x58800000;
+		let data = test_module(base);
+		let layout = test_layout(base);
+		let mut b = MemoryBlock::new(layout.all());
+		let mut g = b.enter();
+		let mut instance = Instance::new(Module::decode(&data)?, &layout, &mut g)?;
+		assert_eq!(unsafe { layout.elf.slice() }[..4], [1, 2, 3, 4]);
+		assert_eq!(instance.export_address("__wbxsysarea"), Some(base + 0x100));
+		assert_eq!(instance.export_address("fact"), None);
+
+		let fact = instance.proc_addr("fact");
+		assert_ne!(fact, 0);
+		assert_eq!(instance.proc_addr("fact"), fact);
+		assert_eq!(instance.proc_addr("nothing"), 0);
+		let fact: Trampoline = unsafe { std::mem::transmute(fact) };
+		assert_eq!(fact(6, 0, 0, 0, 0, 0), 720);
+		// arguments are truncated to their wasm types
+		assert_eq!(fact(0x100000004, 0, 0, 0, 0, 0), 24);
+
+		let mut state = Vec::new();
+		instance.save_state(&mut state)?;
+		instance.store.globals[1] = 7;
+		instance.load_state(&mut &state[..])?;
+		assert_eq!(instance.store.globals[1], 0);
+
+		let me = instance.as_ref() as *const Instance as *mut Instance;
+		drop(instance);
+		assert!(SLOTS.lock().unwrap().0.iter().all(|s| s.map(|s| s.instance != me).unwrap_or(true)));
+		Ok(())
+	}
+
+	/// A WASI module with its data at `base`:  run() makes every kind of WASI call there is here and returns the sum of
+	/// their errnos, and grow() is memory.grow(1)
+	fn wasi_module(base: usize) -> Vec<u8> {
+		let mut m = b"\0asm\x01\0\0\0".to_vec();
+		section(&mut m, 1, &[3,
+			0x60, 2, 0x7f, 0x7f, 1, 0x7f,
+			0x60, 4, 0x7f, 0x7f, 0x7f, 0x7f, 1, 0x7f,
+			0x60, 0, 1, 0x7f,
+		]);
+		let mut imports = vec![3];
+		for (n, ty) in [("args_sizes_get", 0), ("fd_write", 1), ("poll_oneoff", 1)].iter() {
+			name(&mut imports, wasi::MODULE);
+			name(&mut imports, n);
+			imports.extend_from_slice(&[0, *ty]);
+		}
+		section(&mut m, 2, &imports);
+		section(&mut m, 3, &[2, 2, 2]);
+		let mut memory = vec![1, 0];
+		leb(&mut memory, (base as u64 + 0x10000) >> 16);
+		section(&mut m, 5, &memory);
+		let mut exports = vec![2];
+		name(&mut exports, "run");
+		exports.extend_from_slice(&[0, 3]);
+		name(&mut exports, "grow");
+		exports.extend_from_slice(&[0, 4]);
+		section(&mut m, 7, &exports);
+		let c = |v: &mut Vec<u8>, n: usize| {
+			v.push(0x41);
+			sleb(v, n as i32 as i64);
+		};
+		let mut run = vec![0];
+		// args_sizes_get(base + 0x200, base + 0x204)
+		c(&mut run, base + 0x200);
+		c(&mut run, base + 0x204);
+		run.extend_from_slice(&[0x10, 0]);
+		// fd_write(1, base + 0x210, 1, base + 0x220)
+		c(&mut run, 1);
+		c(&mut run, base + 0x210);
+		c(&mut run, 1);
+		c(&mut run, base + 0x220);
+		run.extend_from_slice(&[0x10, 1, 0x6a]);
+		// poll_oneoff(0, 0, 0, 0)
+		run.extend_from_slice(&[0x41, 0, 0x41, 0, 0x41, 0, 0x41, 0, 0x10, 2, 0x6a, 0x0b]);
+		let grow = [0, 0x41, 1, 0x40, 0, 0x0b];
+		let mut code = vec![2];
+		leb(&mut code, run.len() as u64);
+		code.extend_from_slice(&run);
+		leb(&mut code, grow.len() as u64);
+		code.extend_from_slice(&grow);
+		section(&mut m, 10, &code);
+		// an iovec, and what it points to
+		let mut data = vec![1, 0];
+		c(&mut data, base + 0x210);
+		data.extend_from_slice(&[0x0b, 0x23]);
+		data.extend_from_slice(&(base as u32 + 0x230).to_le_bytes());
+		data.extend_from_slice(&[3, 0, 0, 0]);
+		data.extend_from_slice(&[0xff; 0x18]);
+		data.extend_from_slice(b"hi\n");
+		section(&mut m, 11, &data);
+		m
+	}
+
+	#[test]
+	fn test_wasi() -> anyhow::Result<()> {
+		let base = 0x58900000;
+		let template = cinterface::MemoryLayoutTemplate {
+			sbrk_size: 0x20000,
+			sealed_size: 0x10000,
+			invis_size: 0x10000,
+			plain_size: 0x10000,
+			mmap_size: 0x10000,
+		};
+		let mut host = host::WaterboxHost::new(wasi_module(base), "wasi", &template)?;
+		let mut a = host.activate();
+		let run = a.get_proc_addr("run");
+		assert_ne!(run, 0);
+		// everything succeeds but poll_oneoff, with ENOSYS
+		assert_eq!(a.call_guest(run, &[0; 6])?, 52);
+		let written = AddressRange { start: base + 0x200, size: 0x24 };
+		let written = unsafe { written.slice() };
+		assert_eq!(written[..8], [0; 8]);
+		assert_eq!(written[0x20..], [3, 0, 0, 0]);
+		let grow = a.get_proc_addr("grow");
+		assert_eq!(a.call_guest(grow, &[0; 6])?, (base + 0x10000) >> 16);
+		assert_eq!(a.call_guest(grow, &[0; 6])?, (base + 0x20000) >> 16);
+		// that's all of sbrk
+		assert_eq!(a.call_guest(grow, &[0; 6])?, 0xffffffff);
+		Ok(())
+	}
+
+	#[test]
+	fn test_threads() -> anyhow::Result<()> {
+		let base = 0x58e00000;
+		let template = cinterface::MemoryLayoutTemplate {
+			sbrk_size: 0x20000,
+			sealed_size: 0x10000,
+			invis_size: 0x10000,
+			plain_size: 0x10000,
+			mmap_size: 0x10000,
+		};
+		let mut host = host::WaterboxHost::new(wasi_module(base), "wasi", &template)?;
+		let h = host.as_mut() as *mut host::WaterboxHost as usize;
+		// activated on one thread, used on another, and deactivated on a third
+		let a = std::thread::spawn(move || {
+			let h = unsafe { &mut *(h as *mut host::WaterboxHost) };
+			assert!(h.claim());
+			assert!(!h.claim());
+			Box::into_raw(h.activate()) as usize
+		}).join().unwrap();
+		let grown = std::thread::spawn(move || {
+			let a = unsafe { &mut *(a as *mut host::ActivatedWaterboxHost) };
+			let grow = a.get_proc_addr("grow");
+			a.call_guest(grow, &[0; 6]).unwrap()
+		}).join().unwrap();
+		assert_eq!(grown, (base + 0x10000) >> 16);
+		std::thread::spawn(move || unsafe {
+			drop(Box::from_raw(a as *mut host::ActivatedWaterboxHost));
+		}).join().unwrap();
+		assert!(!host.active());
+		// the area was let go of by the thread that deactivated it
+		let mut a = host.activate();
+		let grow = a.get_proc_addr("grow");
+		assert_eq!(a.call_guest(grow, &[0; 6])?, (base + 0x20000) >> 16);
+		Ok(())
+	}
+
+	static NESTED: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+	extern fn nested_trace(userdata: usize, _nr: usize, _args: *const usize, _ret: usize, _text: *const std::os::raw::c_char) {
+		use std::sync::atomic::Ordering;
+		// only once, as the nested call makes syscalls too
+		if NESTED.compare_exchange(0, 1, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+			let a = unsafe { &mut *(userdata as *mut host::ActivatedWaterboxHost) };
+			let grow = a.get_proc_addr("grow");
+			NESTED.store(a.call_guest(grow, &[0; 6]).unwrap(), Ordering::SeqCst);
+		}
+	}
+
+	#[test]
+	fn test_nested_call() -> anyhow::Result<()> {
+		let base = 0x58f00000;
+		let template = cinterface::MemoryLayoutTemplate {
+			sbrk_size: 0x20000,
+			sealed_size: 0x10000,
+			invis_size: 0x10000,
+			plain_size: 0x10000,
+			mmap_size: 0x10000,
+		};
+		let mut host = host::WaterboxHost::new(wasi_module(base), "wasi", &template)?;
+		let mut a = host.activate();
+		let ud = a.as_mut() as *mut host::ActivatedWaterboxHost as usize;
+		a.set_syscall_trace(Some((nested_trace, ud)));
+		let run = a.get_proc_addr("run");
+		// the outer call goes on as if nothing had happened in its first syscall
+		assert_eq!(a.call_guest(run, &[0; 6])?, 52);
+		assert_eq!(NESTED.load(std::sync::atomic::Ordering::SeqCst), (base + 0x10000) >> 16);
+		a.set_syscall_trace(None);
+		let grow = a.get_proc_addr("grow");
+		assert_eq!(a.call_guest(grow, &[0; 6])?, (base + 0x20000) >> 16);
+		assert!(!a.calling());
+		Ok(())
+	}
+
+	#[test]
+	fn test_config() -> anyhow::Result<()> {
+		let base = 0x58b00000;
+		let template = cinterface::MemoryLayoutTemplate {
+			sbrk_size: 0x20000,
+			sealed_size: 0x10000,
+			invis_size: 0x10000,
+			plain_size: 0x10000,
+			mmap_size: 0x10000,
+		};
+		let mut host = host::WaterboxHost::new(wasi_module(base), "wasi", &template)?;
+		let mut a = host.activate();
+		a.set_config("region", Some("pal"));
+		let ud = a.as_mut() as *mut host::ActivatedWaterboxHost as usize;
+		// pointers have to be into guest memory
+		let key = base + 0x200;
+		unsafe { AddressRange { start: key, size: 7 }.slice_mut().copy_from_slice(b"region\0"); }
+		let buf = base + 0x300;
+		let get = |len: usize| host::syscall(syscall_defs::NR_WBX_GET_CONFIG, ud, key, buf, len, 0, 0, 0).0;
+		assert_eq!(get(16), 3);
+		assert_eq!(unsafe { AddressRange { start: buf, size: 4 }.slice() }, b"pal\0");
+		// cut short, but terminated
+		assert_eq!(get(3), 3);
+		assert_eq!(unsafe { AddressRange { start: buf, size: 3 }.slice() }, b"pa\0");
+		a.set_config("region", None);
+		assert_eq!(get(16), syscall_defs::SyscallReturn::from_error(syscall_defs::ENOENT).0);
+		let outside = CString::new("region")?;
+		assert_eq!(host::syscall(syscall_defs::NR_WBX_GET_CONFIG, ud, outside.as_ptr() as usize, buf, 16, 0, 0, 0).0,
+			syscall_defs::SyscallReturn::from_error(syscall_defs::EFAULT).0);
+		// a call from some later host
+		assert_eq!(host::syscall(syscall_defs::SyscallNumber(0x100ff), ud, 0, 0, 0, 0, 0, 0).0, syscall_defs::SyscallReturn::from_error(syscall_defs::ENOSYS).0);
+		assert_eq!(a.imports().guest_version, 0);
+		Ok(())
+	}
+
+	#[test]
+	fn test_saveram() -> anyhow::Result<()> {
+		let base = 0x59100000;
+		let template = cinterface::MemoryLayoutTemplate {
+			sbrk_size: 0x20000,
+			sealed_size: 0x10000,
+			invis_size: 0x10000,
+			plain_size: 0x10000,
+			mmap_size: 0x10000,
+		};
+		let mut host = host::WaterboxHost::new(wasi_module(base), "wasi", &template)?;
+		let mut a = host.activate();
+		let ud = a.as_mut() as *mut host::ActivatedWaterboxHost as usize;
+		let register = |name: &[u8], start: usize, flags: usize| {
+			unsafe { AddressRange { start: base + 0x200, size: name.len() }.slice_mut().copy_from_slice(name); }
+			host::syscall(syscall_defs::NR_WBX_REGISTER_MEMORY_DOMAIN, ud, base + 0x200, start, 4, 1, flags, 0).0
+		};
+		assert_eq!(register(b"SRAM\0", base + 0x300, memory_domains::DOMAIN_SAVERAM), 0);
+		assert_eq!(register(b"WRAM\0", base + 0x400, 0), 0);
+		assert_eq!(register(b"RTC\0", base + 0x500, memory_domains::DOMAIN_SAVERAM), 0);
+		a.write_memory(base + 0x300, b"save");
+		a.write_memory(base + 0x500, b"time");
+		assert_eq!(a.saveram_size(), 8);
+		assert_eq!(a.get_saveram()?, b"savetime");
+		a.put_saveram(b"loadzone")?;
+		let mut read = [0u8; 4];
+		a.read_memory(base + 0x500, &mut read);
+		assert_eq!(&read, b"zone");
+		assert_eq!(ErrorCode::of(&a.put_saveram(b"short").unwrap_err()), ErrorCode::InvalidArgument);
+		assert_eq!(a.get_saveram()?, b"loadzone");
+		Ok(())
+	}
+
+	#[test]
+	fn test_replay() -> anyhow::Result<()> {
+		let base = 0x58d00000;
+		let template = cinterface::MemoryLayoutTemplate {
+			sbrk_size: 0x20000,
+			sealed_size: 0x10000,
+			invis_size: 0x10000,
+			plain_size: 0x10000,
+			mmap_size: 0x10000,
+		};
+		let mut host = host::WaterboxHost::new(wasi_module(base), "wasi", &template)?;
+		let mut a = host.activate();
+		a.seal()?;
+		let grow = a.get_proc_addr("grow");
+		let mut start = Vec::new();
+		a.save_state(&mut start)?;
+
+		a.start_recording()?;
+		assert!(a.mount_overlay_file("late".to_string(), vec![1], false).is_err());
+		assert_eq!(a.write_memory(base + 0x300, b"abc"), 3);
+		a.call_guest(grow, &[0; 6])?;
+		a.mark_frame()?;
+		a.advance_clock(5);
+		a.call_guest(grow, &[0; 6])?;
+		a.mark_frame()?;
+		let log = a.stop_recording()?;
+		assert!(a.stop_recording().is_err());
+		assert_eq!(log.events.len(), 9);
+
+		a.load_state(&mut &start[..])?;
+		assert_eq!(a.replay(log.clone())?, 2);
+		assert_eq!(a.call_guest(grow, &[0; 6])?, 0xffffffff);
+
+		// something else written to memory shows up at the first frame
+		let mut changed = log.clone();
+		changed.events[1] = replay::Event::WriteMemory { addr: base + 0x300, data: b"abd".to_vec() };
+		a.load_state(&mut &start[..])?;
+		let err = a.replay(changed).unwrap_err();
+		assert_eq!(ErrorCode::of(&err), ErrorCode::Desync);
+		assert!(err.to_string().contains("after 0 frames"));
+		// and it has to start from the same state
+		assert_eq!(ErrorCode::of(&a.replay(log).unwrap_err()), ErrorCode::StateMismatch);
+		Ok(())
+	}
+
+	#[test]
+	fn test_journal() -> anyhow::Result<()> {
+		let base = 0x59000000;
+		let template = cinterface::MemoryLayoutTemplate {
+			sbrk_size: 0x20000,
+			sealed_size: 0x10000,
+			invis_size: 0x10000,
+			plain_size: 0x10000,
+			mmap_size: 0x10000,
+		};
+		let path = std::env::temp_dir().join(format!("wbx_host_journal_{}", std::process::id()));
+		let path = path.to_str().unwrap();
+		let _ = std::fs::remove_file(path);
+		let mut host = host::WaterboxHost::new(wasi_module(base), "wasi", &template)?;
+		let mut a = host.activate();
+		assert!(a.start_journal(path, 1, 2).is_err());
+		a.seal()?;
+		a.start_journal(path, 1, 2)?;
+		for (frame, data) in [b"a", b"b", b"c", b"d"].iter().enumerate() {
+			a.write_memory(base + 0x300, &data[..]);
+			assert!(a.journal_frame(frame as u64)?);
+		}
+		let mut read = [0u8];
+		assert_eq!(a.load_journal_frame(1)?, 1);
+		a.read_memory(base + 0x300, &mut read);
+		assert_eq!(&read, b"b");
+		// a new timeline, from a state that was loaded
+		a.write_memory(base + 0x300, b"e");
+		assert!(a.journal_frame(2)?);
+		assert_eq!(a.load_journal_frame(3)?, 2);
+		a.read_memory(base + 0x300, &mut read);
+		assert_eq!(&read, b"e");
+
+		// picking up after a crash, on a fresh host
+		a.stop_journal();
+		drop(a);
+		let mut host = host::WaterboxHost::new(wasi_module(base), "wasi", &template)?;
+		let mut a = host.activate();
+		a.seal()?;
+		a.start_journal(path, 1, 2)?;
+		assert_eq!(a.load_journal_frame(u64::MAX)?, 2);
+		a.read_memory(base + 0x300, &mut read);
+		assert_eq!(&read, b"e");
+		assert_eq!(a.load_journal_frame(0)?, 0);
+		a.read_memory(base + 0x300, &mut read);
+		assert_eq!(&read, b"a");
+		drop(a);
+		std::fs::remove_file(path)?;
+		Ok(())
+	}
+
+	#[test]
+	fn test_entropy() -> anyhow::Result<()> {
+		let base = 0x59300000;
+		let template = cinterface::MemoryLayoutTemplate {
+			sbrk_size: 0x20000,
+			sealed_size: 0x10000,
+			invis_size: 0x10000,
+			plain_size: 0x10000,
+			mmap_size: 0x10000,
+		};
+		let mut host = host::WaterboxHost::new(wasi_module(base), "wasi", &template)?;
+		let mut a = host.activate();
+		let ud = a.as_mut() as *mut host::ActivatedWaterboxHost as usize;
+		let draw = |a: &mut host::ActivatedWaterboxHost| {
+			assert_eq!(host::syscall(syscall_defs::NR_WBX_ENTROPY, ud, base + 0x300, 16, 0, 0, 0, 0).0, 0);
+			let mut read = [0u8; 16];
+			a.read_memory(base + 0x300, &mut read);
+			read
+		};
+		a.seal()?;
+		a.seed_entropy(b"frame 1");
+		let first = draw(&mut a);
+		let mut state = Vec::new();
+		a.save_state(&mut state)?;
+		let second = draw(&mut a);
+		assert_ne!(first, second);
+		a.load_state(&mut &state[..])?;
+		assert_eq!(draw(&mut a), second);
+		a.seed_entropy(b"frame 1");
+		assert_eq!(draw(&mut a), first);
+		// getrandom() is a different stream, and doesn't move this one
+		a.seed_entropy(b"frame 1");
+		host::syscall(syscall_defs::NR_GETRANDOM, ud, base + 0x300, 16, 0, 0, 0, 0);
+		assert_eq!(draw(&mut a), first);
+		Ok(())
+	}
+
+	#[test]
+	fn test_roms() -> anyhow::Result<()> {
+		let base = 0x59400000;
+		let template = cinterface::MemoryLayoutTemplate {
+			sbrk_size: 0x20000,
+			sealed_size: 0x10000,
+			invis_size: 0x10000,
+			plain_size: 0x10000,
+			mmap_size: 0x10000,
+		};
+		let mut host = host::WaterboxHost::new(wasi_module(base), "wasi", &template)?;
+		let mut a = host.activate();
+		let ud = a.as_mut() as *mut host::ActivatedWaterboxHost as usize;
+		let rom = AddressRange { start: base + 0x1000, size: 0x1000 };
+		let code = |r: anyhow::Result<()>| ErrorCode::of(&r.unwrap_err());
+		a.seal()?;
+		a.write_memory(rom.start, b"rom");
+		let mut page = vec![0u8; rom.size];
+		a.read_memory(rom.start, &mut page);
+		let hash = bin::hash(&page);
+		assert_eq!(code(a.register_rom(AddressRange { start: rom.start + 1, size: 0x1000 }, None)), ErrorCode::InvalidArgument);
+		assert_eq!(code(a.register_rom(rom, Some(&[0; 32]))), ErrorCode::Corrupted);
+		a.register_rom(rom, Some(&hash))?;
+		assert_eq!(code(a.register_rom(rom, None)), ErrorCode::InvalidArgument);
+		// neither the frontend nor the guest can change it
+		assert_eq!(a.write_memory(rom.start, b"bad"), 0);
+		let eperm = -1isize as usize;
+		assert_eq!(host::syscall(syscall_defs::NR_MPROTECT, ud, rom.start, 0x1000, 3, 0, 0, 0).0, eperm);
+		assert_eq!(host::syscall(syscall_defs::NR_MUNMAP, ud, rom.start - 0x1000, 0x2000, 0, 0, 0, 0).0, eperm);
+		let mut good = Vec::new();
+		a.save_state(&mut good)?;
+
+		a.unregister_rom(rom.start)?;
+		assert_eq!(code(a.unregister_rom(rom.start)), ErrorCode::NotFound);
+		assert_eq!(a.write_memory(rom.start, b"bad"), 3);
+		let mut bad = Vec::new();
+		a.save_state(&mut bad)?;
+		assert_eq!(code(a.register_rom(rom, Some(&hash))), ErrorCode::Corrupted);
+		a.write_memory(rom.start, b"rom");
+		a.register_rom(rom, Some(&hash))?;
+		// a state with a different ROM in it is turned down once it's loaded, and a good one puts things right
+		assert_eq!(code(a.load_state(&mut &bad[..])), ErrorCode::Corrupted);
+		a.load_state(&mut &good[..])?;
+		a.verify_roms()?;
+		assert_eq!(a.write_memory(rom.start, b"bad"), 0);
+		Ok(())
+	}
+
+	#[test]
+	fn test_arenas() -> anyhow::Result<()> {
+		let base = 0x59500000;
+		let template = cinterface::MemoryLayoutTemplate {
+			sbrk_size: 0x20000,
+			sealed_size: 0x10000,
+			invis_size: 0x10000,
+			plain_size: 0x10000,
+			mmap_size: 0x10000,
+		};
+		let mut host = host::WaterboxHost::new(wasi_module(base), "wasi", &template)?;
+		let mut a = host.activate();
+		let ud = a.as_mut() as *mut host::ActivatedWaterboxHost as usize;
+		a.write_memory(base + 0x300, b"vram\0audio\0");
+		let call = |nr, args: [usize; 3]| host::syscall(nr, ud, args[0], args[1], args[2], 0, 0, 0).0 as isize;
+		assert_eq!(call(syscall_defs::NR_WBX_CREATE_ARENA, [base + 0x300, base + 0x1000, 0x2800]), 0);
+		assert_eq!(call(syscall_defs::NR_WBX_CREATE_ARENA, [base + 0x305, base + 0x4000, 0x100]), 0);
+		assert_eq!(call(syscall_defs::NR_WBX_CREATE_ARENA, [base + 0x305, base + 0x5000, 0x100]), -17);
+		a.seal()?;
+		a.write_memory(base + 0x1000, b"x");
+		let vram = a.arena(0)?;
+		assert_eq!(unsafe { std::ffi::CStr::from_ptr(vram.name) }.to_str()?, "vram");
+		assert_eq!((vram.start, vram.size, vram.mapped_bytes, vram.state_bytes), (base + 0x1000, 0x2800, 0x3000, 0x1000));
+		let mut state = Vec::new();
+		a.save_state(&mut state)?;
+		assert_eq!(call(syscall_defs::NR_WBX_DESTROY_ARENA, [base + 0x300, 0, 0]), 0);
+		assert_eq!(call(syscall_defs::NR_WBX_DESTROY_ARENA, [base + 0x300, 0, 0]), -2);
+		assert_eq!(a.arena_count(), 1);
+		// the guest's memory goes back to having both
+		a.load_state(&mut &state[..])?;
+		assert_eq!(a.arena_count(), 2);
+		Ok(())
+	}
+
+	#[test]
+	fn test_validate_state() -> anyhow::Result<()> {
+		let base = 0x59600000;
+		let template = cinterface::MemoryLayoutTemplate {
+			sbrk_size: 0x20000,
+			sealed_size: 0x10000,
+			invis_size: 0x10000,
+			plain_size: 0x10000,
+			mmap_size: 0x10000,
+		};
+		let mut host = host::WaterboxHost::new(wasi_module(base), "wasi", &template)?;
+		let mut a = host.activate();
+		a.seal()?;
+		a.write_memory(base + 0x300, b"old");
+		let mut state = Vec::new();
+		a.save_state(&mut state)?;
+		a.set_compress_states(true);
+		let mut compressed = Vec::new();
+		a.save_state(&mut compressed)?;
+		a.write_memory(base + 0x300, b"new");
+
+		let check = a.validate_state(&mut &state[..])?;
+		assert_eq!((check.version, check.compressed, check.size), (state_format::VERSION, false, state.len() as u64));
+		assert!(check.pages > 0);
+		let packed = a.validate_state(&mut &compressed[..])?;
+		assert_eq!((packed.compressed, packed.size, packed.pages), (true, check.size, check.pages));
+		let code = |r: anyhow::Result<state_format::StateCheck>| ErrorCode::of(&r.unwrap_err());
+		assert_eq!(code(a.validate_state(&mut &state[..state.len() - 100])), ErrorCode::BadStateData);
+		assert_eq!(code(a.validate_state(&mut &b"not a state"[..])), ErrorCode::BadStateData);
+		// nothing was loaded
+		let mut mem = [0u8; 3];
+		a.read_memory(base + 0x300, &mut mem);
+		assert_eq!(&mem, b"new");
+		a.unseal()?;
+		a.seal()?;
+		assert_eq!(code(a.validate_state(&mut &state[..])), ErrorCode::StateMismatch);
+		Ok(())
+	}
+
+	#[cfg(all(unix, target_arch = "x86_64"))]
+	extern fn guest_trap(userdata: usize, kind: u32, addr: usize, regs: &mut coredump::UserRegs) -> u32 {
+		if userdata == 1 {
+			return cinterface::GUEST_TRAP_ABORT
+		}
+		if kind == cinterface::GUEST_TRAP_BREAKPOINT {
+			regs.rdi = 42;
+			cinterface::GUEST_TRAP_SKIP
+		} else {
+			// past the ud2 by hand
+			regs.rip = addr as u64 + 2;
+			cinterface::GUEST_TRAP_RESUME
+		}
+	}
+
+	#[test]
+	#[cfg(all(unix, target_arch = "x86_64"))]
+	fn test_guest_traps() -> anyhow::Result<()> {
+		use syscall_defs::*;
+		let base = 0x59700000;
+		let template = cinterface::MemoryLayoutTemplate {
+			sbrk_size: 0x20000,
+			sealed_size: 0x10000,
+			invis_size: 0x10000,
+			plain_size: 0x10000,
+			mmap_size: 0x10000,
+		};
+		let mut host = host::WaterboxHost::new(wasi_module(base), "wasi", &template)?;
+		let mut a = host.activate();
+		let ud = a.as_mut() as *mut host::ActivatedWaterboxHost as usize;
+		let code = host::syscall(NR_MMAP, ud, 0, 0x1000, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, usize::MAX, 0).0;
+		// int3; mov rax, rdi; ud2; ret
+		unsafe { std::slice::from_raw_parts_mut(code as *mut u8, 7) }.copy_from_slice(&[0xcc, 0x48, 0x89, 0xf8, 0x0f, 0x0b, 0xc3]);
+		assert_eq!(host::syscall(NR_MPROTECT, ud, code, 0x1000, PROT_READ | PROT_EXEC, 0, 0, 0).0, 0);
+		a.set_guest_trap_callback(Some((guest_trap, 0)));
+		assert_eq!(a.call_guest(code, &[7, 0, 0, 0, 0, 0])?, 42);
+		a.set_guest_trap_callback(Some((guest_trap, 1)));
+		assert_eq!(ErrorCode::of(&a.call_guest(code, &[0; 6]).unwrap_err()), ErrorCode::Aborted);
+		Ok(())
+	}
+
+	#[test]
+	#[cfg(all(unix, target_arch = "x86_64"))]
+	fn test_typed_values() -> anyhow::Result<()> {
+		use syscall_defs::*;
+		use memory_domains::*;
+		let base = 0x59800000;
+		let template = cinterface::MemoryLayoutTemplate {
+			sbrk_size: 0x20000,
+			sealed_size: 0x10000,
+			invis_size: 0x10000,
+			plain_size: 0x10000,
+			mmap_size: 0x10000,
+		};
+		let mut host = host::WaterboxHost::new(wasi_module(base), "wasi", &template)?;
+		let mut a = host.activate();
+		let ud = a.as_mut() as *mut host::ActivatedWaterboxHost as usize;
+		let call = |nr, args: [usize; 5]| host::syscall(nr, ud, args[0], args[1], args[2], args[3], args[4], 0).0 as isize;
+		a.write_memory(base + 0x300, b"wram\0bank\0rom\0");
+		a.write_memory(base + 0x400, &[0x78, 0x56, 0x34, 0x12]);
+		a.write_memory(base + 0x408, &1.5f32.to_le_bytes());
+		assert_eq!(call(NR_WBX_REGISTER_MEMORY_DOMAIN, [base + 0x300, base + 0x400, 0x100, 1, DOMAIN_WRITABLE]), 0);
+		assert_eq!(call(NR_WBX_REGISTER_MEMORY_DOMAIN, [base + 0x305, base + 0x500, 0x100, 1, 0]), 0);
+		assert_eq!(a.read_value(Some(0), 0, VALUE_U32)?, 0x12345678);
+		assert_eq!(a.read_value(Some(0), 0, VALUE_U32 | VALUE_BIG_ENDIAN)?, 0x78563412);
+		assert_eq!(a.read_value(Some(0), 2, VALUE_U16)?, 0x1234);
+		assert_eq!(f64::from_bits(a.read_value(Some(0), 8, VALUE_F32)?), 1.5);
+		assert_eq!(a.read_value(None, base + 0x401, VALUE_U8)?, 0x56);
+		assert_eq!(ErrorCode::of(&a.read_value(Some(0), 0xfe, VALUE_U32).unwrap_err()), ErrorCode::InvalidArgument);
+		assert_eq!(ErrorCode::of(&a.read_value(Some(2), 0, VALUE_U8).unwrap_err()), ErrorCode::NotFound);
+		assert_eq!(ErrorCode::of(&a.read_value(None, 0x1000, VALUE_U8).unwrap_err()), ErrorCode::UnmappedAddress);
+		a.write_value(Some(0), 0x10, VALUE_U16 | VALUE_BIG_ENDIAN, 0xabcd)?;
+		let mut raw = [0u8; 2];
+		a.read_memory(base + 0x410, &mut raw);
+		assert_eq!(raw, [0xab, 0xcd]);
+		assert_eq!(ErrorCode::of(&a.write_value(Some(1), 0, VALUE_U8, 1).unwrap_err()), ErrorCode::InvalidArgument);
+
+		// the bank is really wram:  lea rax, [rdi + base + 0x400]; ret
+		let code = host::syscall(NR_MMAP, ud, 0, 0x1000, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, usize::MAX, 0).0;
+		let disp = (base as u32 + 0x400).to_le_bytes();
+		let translate = [0x48, 0x8d, 0x87, disp[0], disp[1], disp[2], disp[3], 0xc3];
+		unsafe { std::slice::from_raw_parts_mut(code as *mut u8, translate.len()) }.copy_from_slice(&translate);
+		assert_eq!(host::syscall(NR_MPROTECT, ud, code, 0x1000, PROT_READ | PROT_EXEC, 0, 0, 0).0, 0);
+		assert_eq!(call(NR_WBX_SET_DOMAIN_TRANSLATOR, [base + 0x305, code, 0, 0, 0]), 0);
+		assert_eq!(call(NR_WBX_SET_DOMAIN_TRANSLATOR, [base + 0x30a, code, 0, 0, 0]), -2);
+		assert_eq!(a.read_value(Some(1), 0, VALUE_U32)?, 0x12345678);
+		assert_eq!(call(NR_WBX_SET_DOMAIN_TRANSLATOR, [base + 0x305, 0, 0, 0, 0]), 0);
+		assert_eq!(a.read_value(Some(1), 0, VALUE_U32)?, 0);
+		Ok(())
+	}
+
+	#[test]
+	fn test_history() -> anyhow::Result<()> {
+		use memory_domains::*;
+		let base = 0x59900000;
+		let template = cinterface::MemoryLayoutTemplate {
+			sbrk_size: 0x20000,
+			sealed_size: 0x10000,
+			invis_size: 0x10000,
+			plain_size: 0x10000,
+			mmap_size: 0x10000,
+		};
+		let mut host = host::WaterboxHost::new(wasi_module(base), "wasi", &template)?;
+		let mut a = host.activate();
+		let id = a.add_history_watch(None, base + 0x400, VALUE_U16, 4)?;
+		assert_eq!(ErrorCode::of(&a.add_history_watch(None, 0x1000, VALUE_U16, 4).unwrap_err()), ErrorCode::UnmappedAddress);
+		for frame in 0..6u64 {
+			a.write_memory(base + 0x400, &((frame / 2) as u16 * 0x101).to_le_bytes());
+			a.record_history(frame);
+		}
+		let mut dest = [history::HistorySample::default(); 8];
+		let n = a.history(id, 0, &mut dest)?;
+		assert_eq!(dest[..n].iter().map(|s| (s.frame, s.value)).collect::<Vec<_>>(), [(0, 0), (2, 0x101), (4, 0x202)]);
+		// back to frame 3, where it's different this time
+		a.write_memory(base + 0x400, &[7, 0]);
+		a.record_history(3);
+		let n = a.history(id, 1, &mut dest)?;
+		assert_eq!(dest[..n].iter().map(|s| (s.frame, s.value)).collect::<Vec<_>>(), [(2, 0x101), (3, 7)]);
+		a.remove_history_watch(id)?;
+		assert_eq!(ErrorCode::of(&a.history(id, 0, &mut dest).unwrap_err()), ErrorCode::NotFound);
+		Ok(())
+	}
+
+	#[test]
+	fn test_transfers() -> anyhow::Result<()> {
+		use syscall_defs::*;
+		let base = 0x59a00000;
+		let template = cinterface::MemoryLayoutTemplate {
+			sbrk_size: 0x20000,
+			sealed_size: 0x10000,
+			invis_size: 0x10000,
+			plain_size: 0x10000,
+			mmap_size: 0x10000,
+		};
+		let mut host = host::WaterboxHost::new(wasi_module(base), "wasi", &template)?;
+		let mut a = host.activate();
+		let ud = a.as_mut() as *mut host::ActivatedWaterboxHost as usize;
+		let call = |args: [usize; 4]| host::syscall(NR_WBX_TRANSFER, ud, args[0], args[1], args[2], args[3], 0, 0).0 as isize;
+		let mut frame = vec![0u8; 8];
+		unsafe { a.register_transfer_buffer("video", frame.as_mut_ptr(), frame.len())? };
+		a.write_memory(base + 0x300, b"video\0");
+		a.write_memory(base + 0x400, b"pixels");
+		assert_eq!(call([base + 0x300, base + 0x400, 6, 1]), 0);
+		assert_eq!(call([base + 0x300, base + 0x400, 6, 3]), -(EMSGSIZE.0 as isize));
+		// the host checks the guest's pointer too
+		assert_eq!(call([base + 0x300, 0x1000, 6, 0]), -(EFAULT.0 as isize));
+		assert_eq!(a.transfer_info("video")?, transfers::TransferInfo { size: 8, filled: 7, transfers: 1 });
+		a.unregister_transfer_buffer("video")?;
+		assert_eq!(call([base + 0x300, base + 0x400, 6, 0]), -(ENOENT.0 as isize));
+		assert_eq!(&frame[..], b"\0pixels\0");
+		Ok(())
+	}
+
+	#[test]
+	fn test_mmap_file() -> anyhow::Result<()> {
+		use syscall_defs::*;
+		let base = 0x59d00000;
+		let template = cinterface::MemoryLayoutTemplate {
+			sbrk_size: 0x20000,
+			sealed_size: 0x10000,
+			invis_size: 0x10000,
+			plain_size: 0x10000,
+			mmap_size: 0x10000,
+		};
+		let mut host = host::WaterboxHost::new(wasi_module(base), "wasi", &template)?;
+		let mut a = host.activate();
+		let ud = a.as_mut() as *mut host::ActivatedWaterboxHost as usize;
+		let call = |nr, args: [usize; 6]| host::syscall(nr, ud, args[0], args[1], args[2], args[3], args[4], args[5]).0 as isize;
+		let data = (0..0x1800).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+		a.mount_file("/rom.bin".to_string(), data.clone().into(), true)?;
+		a.write_memory(base + 0x300, b"/rom.bin\0");
+		let fd = call(NR_OPEN, [base + 0x300, O_RDONLY as usize, 0, 0, 0, 0]) as usize;
+		let mut buf = vec![0u8; 0x2000];
+
+		// a private copy, with zeros past the end of the file, that the guest can change without changing the file
+		let private = call(NR_MMAP, [0, 0x2000, PROT_READ | PROT_WRITE, MAP_PRIVATE, fd, 0]) as usize;
+		assert_eq!(a.read_memory(private, &mut buf[..]), 0x2000);
+		assert_eq!(&buf[..0x1800], &data[..]);
+		assert!(buf[0x1800..].iter().all(|&b| b == 0));
+		assert_eq!(a.write_memory(private, b"changed"), 7);
+
+		// a shared one has to be readonly, which it stays
+		let shared = call(NR_MMAP, [0, 0x1000, PROT_READ, MAP_SHARED, fd, 0x1000]) as usize;
+		assert_eq!(a.read_memory(shared, &mut buf[..0x1000]), 0x1000);
+		assert_eq!(&buf[..0x800], &data[0x1000..]);
+		assert!(buf[0x800..0x1000].iter().all(|&b| b == 0));
+		assert_eq!(a.write_memory(shared, b"changed"), 0);
+
+		let err = |e: SyscallError| -(e.0 as isize);
+		assert_eq!(call(NR_MMAP, [0, 0x1000, PROT_READ | PROT_WRITE, MAP_SHARED, fd, 0]), err(EOPNOTSUPP));
+		assert_eq!(call(NR_MMAP, [0, 0x1000, PROT_READ, MAP_PRIVATE, fd, 0x800]), err(EINVAL));
+		// stdin can't be read at an offset
+		assert_eq!(call(NR_MMAP, [0, 0x1000, PROT_READ, MAP_PRIVATE, 0, 0]), err(ENODEV));
+		assert_eq!(call(NR_CLOSE, [fd, 0, 0, 0, 0, 0]), 0);
+		// which is also what aarch64 guests open files with
+		let fd = call(NR_OPENAT, [AT_FDCWD as usize, base + 0x300, O_RDONLY as usize, 0, 0, 0]);
+		assert!(fd >= 0);
+		assert_eq!(call(NR_CLOSE, [fd as usize, 0, 0, 0, 0, 0]), 0);
+		assert_eq!(a.unmount_file("/rom.bin")?, data);
+		Ok(())
+	}
+
+	#[test]
+	fn test_rewind_failed_load() -> anyhow::Result<()> {
+		let base = 0x59e00000;
+		let template = cinterface::MemoryLayoutTemplate {
+			sbrk_size: 0x20000,
+			sealed_size: 0x10000,
+			invis_size: 0x10000,
+			plain_size: 0x10000,
+			mmap_size: 0x10000,
+		};
+		let mut host = host::WaterboxHost::new(wasi_module(base), "wasi", &template)?;
+		let mut a = host.activate();
+		a.seal()?;
+		a.set_rewind_capacity(1 << 20);
+		a.capture_rewind_frame()?;
+		a.capture_rewind_frame()?;
+		// a file the frames don't have makes them unloadable, which mustn't cost any of them
+		a.mount_file("/extra.bin".to_string(), vec![1u8; 16].into(), false)?;
+		assert!(a.rewind(1).is_err());
+		assert_eq!(a.rewind_frame_count(), 2);
+		a.unmount_file("/extra.bin")?;
+		a.rewind(1)?;
+		assert_eq!(a.rewind_frame_count(), 1);
+		Ok(())
+	}
+
+	#[test]
+	fn test_save_with_progress() -> anyhow::Result<()> {
+		let base = 0x59c00000;
+		let template = cinterface::MemoryLayoutTemplate {
+			sbrk_size: 0x20000,
+			sealed_size: 0x10000,
+			invis_size: 0x10000,
+			plain_size: 0x10000,
+			mmap_size: 0x10000,
+		};
+		let mut host = host::WaterboxHost::new(wasi_module(base), "wasi", &template)?;
+		let mut a = host.activate();
+		a.seal()?;
+		let mut plain = Vec::new();
+		a.save_state(&mut plain)?;
+		let mut with_progress = Vec::new();
+		let mut last = (0, 0);
+		a.save_state_with_progress(&mut with_progress, &mut |done, total| last = (done, total))?;
+		// the same state either way, and the last report is all of it
+		assert_eq!(with_progress, plain);
+		assert_eq!(last, (plain.len() as u64, plain.len() as u64));
+
+		// a save that fails part way is still timed
+		struct Full;
+		impl std::io::Write for Full {
+			fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+				Err(std::io::Error::other("full"))
+			}
+			fn flush(&mut self) -> std::io::Result<()> {
+				Ok(())
+			}
+		}
+		a.set_compress_states(true);
+		a.set_profiling(true);
+		assert!(a.save_state_with_progress(&mut Full, &mut |_, _| {}).is_err());
+		assert!(a.save_state(&mut Full).is_err());
+		let saves = a.profile().iter().find(|e| e.entry == profile::ENTRY_SAVE_STATE).map(|e| e.calls);
+		assert_eq!(saves, Some(2));
+		Ok(())
+	}
+
+	#[test]
+	fn test_shutdown() -> anyhow::Result<()> {
+		use crate::api::Waterbox;
+		let base = 0x59b00000;
+		let mut wbx = Waterbox::builder("wasi")
+			.sbrk_size(0x20000)
+			.sealed_size(0x10000)
+			.invis_size(0x10000)
+			.plain_size(0x10000)
+			.mmap_size(0x10000)
+			.build(wasi_module(base))?;
+		wbx.activate()?.seal()?;
+		let before = leaks::audit();
+		assert!(before.hosts >= 1 && before.blocks >= 1);
+		wbx.shutdown()?;
+		assert!(leaks::audit().shut_down_hosts >= 1);
+		assert_eq!(wbx.activate().err().unwrap().code(), ErrorCode::BadState);
+		wbx.shutdown()?;
+		drop(wbx);
+		Ok(())
+	}
+
+	#[test]
+	fn test_api() -> anyhow::Result<()> {
+		use crate::api::Waterbox;
+		let base = 0x59200000;
+		let mut wbx = Waterbox::builder("wasi")
+			.sbrk_size(0x20000)
+			.sealed_size(0x10000)
+			.invis_size(0x10000)
+			.plain_size(0x10000)
+			.mmap_size(0x10000)
+			.build(wasi_module(base))?;
+		let mut a = wbx.activate()?;
+		assert_eq!(a.call("run", &[])?, 52);
+		assert_eq!(a.call("nothing", &[]).unwrap_err().code(), ErrorCode::NotFound);
+		let grow = a.proc_addr("grow").unwrap();
+		assert_eq!(unsafe { a.call_addr(grow, &[]) }?, (base + 0x10000) >> 16);
+		assert_eq!(a.call("grow", &[0; 7]).unwrap_err().code(), ErrorCode::InvalidArgument);
+		assert_eq!(a.save_state(&mut Vec::new()).unwrap_err().code(), ErrorCode::BadState);
+		a.seal()?;
+		a.write_memory(base + 0x300, b"old");
+		let mut state = Vec::new();
+		a.save_state(&mut state)?;
+		a.write_memory(base + 0x300, b"new");
+		drop(a);
+		// the state outlives the activation it was made in
+		let mut a = wbx.activate()?;
+		a.load_state(&mut &state[..])?;
+		let mut read = [0u8; 3];
+		assert_eq!(a.read_memory(base + 0x300, &mut read), 3);
+		assert_eq!(&read, b"old");
+		assert_eq!(a.load_state(&mut &b"junk"[..]).unwrap_err().code(), ErrorCode::BadStateData);
+		Ok(())
+	}
+
+	#[test]
+	#[cfg(feature = "fuzz")]
+	fn test_fuzz_syscalls() -> anyhow::Result<()> {
+		let template = cinterface::MemoryLayoutTemplate {
+			sbrk_size: 0x20000,
+			sealed_size: 0x10000,
+			invis_size: 0x10000,
+			plain_size: 0x10000,
+			mmap_size: 0x100000,
+		};
+		// a fresh host each time, since whatever one run leaves mapped can fill up the next one's
+		for seed in 0..4 {
+			let mut host = host::WaterboxHost::new(wasi_module(0x58c00000), "wasi", &template)?;
+			crate::fuzz::fuzz_syscalls(&mut host.activate(), seed, 2000)?;
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn test_wasi_start_info() -> anyhow::Result<()> {
+		let base = 0x58a00000;
+		let layout = test_layout(base);
+		let mut b = MemoryBlock::new(layout.all());
+		let mut g = b.enter();
+		g.mmap_fixed(layout.all(), Protection::RW, true)?;
+		let mut store = Store { globals: Vec::new(), table: Vec::new(), memory: layout.all(), dropped: Vec::new() };
+		let info = StartInfo {
+			args: vec![CString::new("core")?],
+			env: vec![CString::new("A=1")?, CString::new("BB=22")?],
+		};
+		let sizes = [base as u64, base as u64 + 4];
+		assert_eq!(wasi::call(wasi::Function::EnvironSizesGet, 0, &mut store, &sizes, &info)?, Some(0));
+		let words = unsafe { std::slice::from_raw_parts(base as *const u32, 2) };
+		assert_eq!(words, [2, 10]);
+		let get = [base as u64 + 0x10, base as u64 + 0x20];
+		assert_eq!(wasi::call(wasi::Function::EnvironGet, 0, &mut store, &get, &info)?, Some(0));
+		let ptrs = unsafe { std::slice::from_raw_parts((base + 0x10) as *const u32, 2) };
+		assert_eq!(ptrs, [base as u32 + 0x20, base as u32 + 0x24]);
+		assert_eq!(unsafe { AddressRange { start: base + 0x20, size: 10 }.slice() }, b"A=1\0BB=22\0");
+		assert_eq!(wasi::call(wasi::Function::ArgsSizesGet, 0, &mut store, &sizes, &info)?, Some(0));
+		assert_eq!(words, [1, 5]);
+		assert_eq!(wasi::call(wasi::Function::ArgsGet, 0, &mut store, &get, &info)?, Some(0));
+		assert_eq!(ptrs[0], base as u32 + 0x20);
+		assert_eq!(unsafe { AddressRange { start: base + 0x20, size: 5 }.slice() }, b"core\0");
+		Ok(())
+	}
+
+	#[test]
+	fn test_wasi_imports() {
+		let ty = |params: Vec<ValType>, results: Vec<ValType>| decode::FuncType { params, results };
+		let i = ValType::I32;
+		assert_eq!(wasi::lookup("fd_write", &ty(vec![i, i, i, i], vec![i])).unwrap(), wasi::Function::FdWrite);
+		assert!(wasi::lookup("fd_write", &ty(vec![i, i, i], vec![i])).is_err());
+		assert_eq!(wasi::lookup("sock_accept", &ty(vec![i, i, i], vec![i])).unwrap(), wasi::Function::Unsupported);
+		assert!(wasi::lookup("sock_accept", &ty(vec![i], vec![])).is_err());
+	}
+}
@@ -0,0 +1,287 @@
+// The WASI personality, for modules built with a standard WASI toolchain instead of the waterbox sysroot.  Their
+// wasi_snapshot_preview1 imports are done with the same syscalls a native guest would make, so they see the same files
+// and clocks.  There's one preopened directory, "/", which every file the frontend adds is in:  WASI fd 3 is that
+// directory, and the host's fds from 3 on are WASI's from 4 on.  The arguments and environment variables are the ones
+// native guests get in their start block (see startup.rs), and WASI functions that aren't here are still importable, but fail with ENOSYS.
+use super::*;
+use super::decode::FuncType;
+use crate::syscall_defs::*;
+use crate::startup::StartInfo;
+use std::ffi::CString;
+
+/// The import module the WASI functions are in
+pub const MODULE: &str = "wasi_snapshot_preview1";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Function {
+	ArgsGet,
+	ArgsSizesGet,
+	EnvironGet,
+	EnvironSizesGet,
+	ClockResGet,
+	ClockTimeGet,
+	FdClose,
+	FdFdstatGet,
+	FdFilestatGet,
+	FdPrestatGet,
+	FdPrestatDirName,
+	FdRead,
+	FdSeek,
+	FdTell,
+	FdWrite,
+	PathFilestatGet,
+	PathOpen,
+	ProcExit,
+	RandomGet,
+	SchedYield,
+	/// Anything else, which fails
+	Unsupported,
+}
+
+/// Every function there is, with its params and results:  `i` for i32, `I` for i64
+const FUNCTIONS: &[(&str, Function, &str, &str)] = &[
+	("args_get", Function::ArgsGet, "ii", "i"),
+	("args_sizes_get", Function::ArgsSizesGet, "ii", "i"),
+	("environ_get", Function::EnvironGet, "ii", "i"),
+	("environ_sizes_get", Function::EnvironSizesGet, "ii", "i"),
+	("clock_res_get", Function::ClockResGet, "ii", "i"),
+	("clock_time_get", Function::ClockTimeGet, "iIi", "i"),
+	("fd_close", Function::FdClose, "i", "i"),
+	("fd_fdstat_get", Function::FdFdstatGet, "ii", "i"),
+	("fd_filestat_get", Function::FdFilestatGet, "ii", "i"),
+	("fd_prestat_get", Function::FdPrestatGet, "ii", "i"),
+	("fd_prestat_dir_name", Function::FdPrestatDirName, "iii", "i"),
+	("fd_read", Function::FdRead, "iiii", "i"),
+	("fd_seek", Function::FdSeek, "iIii", "i"),
+	("fd_tell", Function::FdTell, "ii", "i"),
+	("fd_write", Function::FdWrite, "iiii", "i"),
+	("path_filestat_get", Function::PathFilestatGet, "iiiii", "i"),
+	("path_open", Function::PathOpen, "iiiiiIIii", "i"),
+	("proc_exit", Function::ProcExit, "i", ""),
+	("random_get", Function::RandomGet, "ii", "i"),
+	("sched_yield", Function::SchedYield, "", "i"),
+];
+
+/// Find the function a module imports as `name` with type `ty`
+pub fn lookup(name: &str, ty: &FuncType) -> anyhow::Result<Function> {
+	let types = |s: &str| s.chars().map(|c| if c == 'I' { ValType::I64 } else { ValType::I32 }).collect::<Vec<_>>();
+	let ok = match FUNCTIONS.iter().find(|f| f.0 == name) {
+		Some(&(_, f, params, results)) => if types(params) == ty.params && types(results) == ty.results { Some(f) } else { None },
+		// all those do is return an errno, so they can take anything
+		None => if ty.results == [ValType::I32] { Some(Function::Unsupported) } else { None },
+	};
+	match ok {
+		Some(f) => Ok(f),
+		None => Err(anyhow!("Module imports `{}.{}` with the wrong type", MODULE, name)),
+	}
+}
+
+/// The one preopened directory
+const PREOPEN_FD: usize = 3;
+
+const FILETYPE_UNKNOWN: u8 = 0;
+const FILETYPE_CHARACTER_DEVICE: u8 = 2;
+const FILETYPE_DIRECTORY: u8 = 3;
+const FILETYPE_REGULAR_FILE: u8 = 4;
+
+const OFLAGS_DIRECTORY: usize = 2;
+const RIGHTS_FD_READ: u64 = 1 << 1;
+const RIGHTS_FD_WRITE: u64 = 1 << 6;
+/// Every right there is
+const RIGHTS_ALL: u64 = (1 << 29) - 1;
+
+/// WASI has its own numbers for errors
+fn errno(e: SyscallError) -> u64 {
+	match e {
+		EACCES => 2,
+		EAGAIN => 6,
+		EBADF => 8,
+		EEXIST => 20,
+		EFAULT => 21,
+		EINTR => 27,
+		EINVAL => 28,
+		EIO => 29,
+		EISDIR => 31,
+		EMFILE => 33,
+		ENAMETOOLONG => 37,
+		ENOENT => 44,
+		ENOMEM => 48,
+		ENOSPC => 51,
+		ENOSYS => 52,
+		ENOTDIR => 54,
+		EPERM => 63,
+		ERANGE => 68,
+		EROFS => 69,
+		ESPIPE => 70,
+		_ => 29,
+	}
+}
+
+fn host_fd(fd: usize) -> Result<usize, SyscallError> {
+	match fd {
+		PREOPEN_FD => Err(EBADF),
+		fd if fd > PREOPEN_FD => Ok(fd - 1),
+		fd => Ok(fd),
+	}
+}
+fn wasi_fd(fd: usize) -> usize {
+	if fd >= PREOPEN_FD { fd + 1 } else { fd }
+}
+
+/// Only names in the preopened directory can be looked up
+fn check_dir(fd: usize) -> Result<(), SyscallError> {
+	if fd == PREOPEN_FD { Ok(()) } else { Err(ENOTDIR) }
+}
+
+fn sys(ud: usize, nr: SyscallNumber, args: [usize; 6]) -> Result<usize, SyscallError> {
+	let ret = host::syscall(nr, ud, args[0], args[1], args[2], args[3], args[4], args[5]);
+	if ret.0 <= SyscallReturn::ERROR_THRESH {
+		Ok(ret.0)
+	} else {
+		Err(SyscallError(-(ret.0 as isize) as i32))
+	}
+}
+
+/// Write an out parameter
+fn out<T: Copy>(store: &Store, addr: usize, value: T) -> Result<(), SyscallError> {
+	let addr = store.addr(addr as u64, std::mem::size_of::<T>() as u64).map_err(|_| EFAULT)?;
+	unsafe { std::ptr::write_unaligned(addr as *mut T, value); }
+	Ok(())
+}
+
+/// A name in the preopened directory, to open(2) or stat(2)
+fn path(store: &Store, addr: usize, len: usize) -> Result<CString, SyscallError> {
+	let addr = store.addr(addr as u64, len as u64).map_err(|_| EFAULT)?;
+	let name = unsafe { std::slice::from_raw_parts(addr as *const u8, len) };
+	let name = std::str::from_utf8(name).map_err(|_| EINVAL)?.trim_start_matches('/');
+	CString::new(name).map_err(|_| EINVAL)
+}
+
+fn filetype(st: &KStat) -> u8 {
+	match st.st_mode & S_IFMT {
+		S_IFCHR => FILETYPE_CHARACTER_DEVICE,
+		S_IFDIR => FILETYPE_DIRECTORY,
+		S_IFREG => FILETYPE_REGULAR_FILE,
+		_ => FILETYPE_UNKNOWN,
+	}
+}
+
+/// A WASI filestat
+fn filestat(st: &KStat) -> [u64; 8] {
+	let nanos = |sec: i64, nsec: i64| (sec * 1000000000 + nsec) as u64;
+	[
+		st.st_dev,
+		st.st_ino,
+		filetype(st) as u64,
+		st.st_nlink,
+		st.st_size as u64,
+		nanos(st.st_atime_sec, st.st_atime_nsec),
+		nanos(st.st_mtime_sec, st.st_mtime_nsec),
+		nanos(st.st_ctime_sec, st.st_ctime_nsec),
+	]
+}
+
+fn clock(ud: usize, nr: SyscallNumber, id: usize) -> Result<u64, SyscallError> {
+	let mut ts = TimeSpec { tv_sec: 0, tv_nsec: 0 };
+	sys(ud, nr, [id, &mut ts as *mut TimeSpec as usize, 0, 0, 0, 0])?;
+	Ok((ts.tv_sec * 1000000000 + ts.tv_nsec) as u64)
+}
+
+/// args_get and environ_get:  Fill in `ptrs` with where each of `strings` is, and copy them all to `buf`
+fn strings_get(store: &Store, ptrs: usize, buf: usize, strings: &[CString]) -> Result<(), SyscallError> {
+	let mut at = buf;
+	for (i, v) in strings.iter().enumerate() {
+		let bytes = v.as_bytes_with_nul();
+		let dest = store.addr(at as u64, bytes.len() as u64).map_err(|_| EFAULT)?;
+		unsafe { std::slice::from_raw_parts_mut(dest as *mut u8, bytes.len()).copy_from_slice(bytes); }
+		out(store, ptrs + i * 4, at as u32)?;
+		at += bytes.len();
+	}
+	Ok(())
+}
+/// args_sizes_get and environ_sizes_get:  How many of `strings` there are, and how many bytes they take up
+fn strings_sizes_get(store: &Store, count: usize, size: usize, strings: &[CString]) -> Result<(), SyscallError> {
+	out(store, count, strings.len() as u32)?;
+	out(store, size, strings.iter().map(|v| v.as_bytes_with_nul().len()).sum::<usize>() as u32)
+}
+
+/// Call `f` for the ActivatedWaterboxHost `ud`.  Returns the function's errno, except for proc_exit, which always traps.
+pub fn call(f: Function, ud: usize, store: &mut Store, args: &[u64], start_info: &StartInfo) -> anyhow::Result<Option<u64>> {
+	use Function::*;
+	let a = |i: usize| args[i] as u32 as usize;
+	let res = match f {
+		ProcExit => return Err(anyhow!("Guest exited with code {}", args[0] as u32)),
+		ArgsGet => strings_get(store, a(0), a(1), &start_info.args),
+		ArgsSizesGet => strings_sizes_get(store, a(0), a(1), &start_info.args),
+		EnvironGet => strings_get(store, a(0), a(1), &start_info.env),
+		EnvironSizesGet => strings_sizes_get(store, a(0), a(1), &start_info.env),
+		ClockResGet => clock(ud, NR_CLOCK_GETRES, a(0)).and_then(|t| out(store, a(1), t)),
+		ClockTimeGet => clock(ud, NR_CLOCK_GETTIME, a(0)).and_then(|t| out(store, a(2), t)),
+		FdClose => host_fd(a(0)).and_then(|fd| sys(ud, NR_CLOSE, [fd, 0, 0, 0, 0, 0])).map(drop),
+		FdFdstatGet => (|| {
+			let filetype = if a(0) == PREOPEN_FD {
+				FILETYPE_DIRECTORY
+			} else {
+				let mut st = KStat::default();
+				sys(ud, NR_FSTAT, [host_fd(a(0))?, &mut st as *mut KStat as usize, 0, 0, 0, 0])?;
+				filetype(&st)
+			};
+			// filetype, flags and rights
+			out(store, a(1), [filetype as u64, RIGHTS_ALL, RIGHTS_ALL])
+		})(),
+		FdFilestatGet => (|| {
+			let mut st = KStat::default();
+			sys(ud, NR_FSTAT, [host_fd(a(0))?, &mut st as *mut KStat as usize, 0, 0, 0, 0])?;
+			out(store, a(1), filestat(&st))
+		})(),
+		// a directory whose name is one byte long
+		FdPrestatGet => check_dir(a(0)).map_err(|_| EBADF).and_then(|_| out(store, a(1), [0u32, 1u32])),
+		FdPrestatDirName => check_dir(a(0)).map_err(|_| EBADF).and_then(|_| if a(2) < 1 { Err(EINVAL) } else { out(store, a(1), b'/') }),
+		FdRead | FdWrite => (|| {
+			let nr = if f == FdRead { NR_READV } else { NR_WRITEV };
+			let n = sys(ud, nr, [host_fd(a(0))?, a(1), a(2), 0, 0, 0])?;
+			out(store, a(3), n as u32)
+		})(),
+		FdSeek => (|| {
+			if a(2) > SEEK_END as usize {
+				return Err(EINVAL)
+			}
+			let pos = sys(ud, NR_LSEEK, [host_fd(a(0))?, args[1] as usize, a(2), 0, 0, 0])?;
+			out(store, a(3), pos as u64)
+		})(),
+		FdTell => (|| {
+			let pos = sys(ud, NR_LSEEK, [host_fd(a(0))?, 0, SEEK_CUR as usize, 0, 0, 0])?;
+			out(store, a(1), pos as u64)
+		})(),
+		PathFilestatGet => (|| {
+			check_dir(a(0))?;
+			let name = path(store, a(2), a(3))?;
+			let mut st = KStat::default();
+			sys(ud, NR_STAT, [name.as_ptr() as usize, &mut st as *mut KStat as usize, 0, 0, 0, 0])?;
+			out(store, a(4), filestat(&st))
+		})(),
+		PathOpen => (|| {
+			check_dir(a(0))?;
+			if a(4) & OFLAGS_DIRECTORY != 0 {
+				return Err(ENOTDIR)
+			}
+			let name = path(store, a(2), a(3))?;
+			// the filesystem only cares about the access mode
+			let flags = match (args[5] & RIGHTS_FD_READ != 0, args[5] & RIGHTS_FD_WRITE != 0) {
+				(true, true) => O_RDWR,
+				(false, true) => O_WRONLY,
+				_ => O_RDONLY,
+			};
+			let fd = sys(ud, NR_OPEN, [name.as_ptr() as usize, flags as usize, 0, 0, 0, 0])?;
+			out(store, a(8), wasi_fd(fd) as u32)
+		})(),
+		RandomGet => store.addr(a(0) as u64, a(1) as u64).map_err(|_| EFAULT)
+			.and_then(|_| sys(ud, NR_GETRANDOM, [a(0), a(1), 0, 0, 0, 0])).map(drop),
+		SchedYield => sys(ud, NR_SCHED_YIELD, [0; 6]).map(drop),
+		Unsupported => Err(ENOSYS),
+	};
+	Ok(Some(match res {
+		Ok(()) => 0,
+		Err(e) => errno(e),
+	}))
+}
@@ -0,0 +1,311 @@
+// Giving up on guest calls that run too long.  Calls the frontend wants watched go through call(), which remembers
+// where the call would return to.  Once a call's budget runs out, a watchdog thread keeps signalling the thread making
+// it, and the call is abandoned by switching straight back to that return address, either from the signal handler if
+// guest code was running, or from the guest's next syscall if host code was.  Nothing in the guest gets to clean up,
+// so after that the guest can't be trusted until a savestate is loaded.  Calls can also be cancelled, which is only
+// noticed at syscalls.  A guest that aborts, by raising a signal or hitting a trap instruction, is abandoned the same
+// way, instead of taking the whole process down with it.
+use crate::*;
+use threading::GuestContext;
+use std::cell::Cell;
+use std::ffi::c_void;
+use std::sync::{Mutex, Condvar, Once};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use lazy_static::lazy_static;
+
+/// How soon an expired call is signalled again, in case host code was running the last time
+const RESIGNAL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Why a call didn't finish
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Abandoned {
+	TimedOut,
+	Cancelled,
+	/// Interpreted guest code hit something it couldn't go on from
+	Trapped,
+	/// Native guest code called abort(), or otherwise gave up
+	Aborted,
+}
+
+/// A watched call in progress
+struct Call {
+	/// Where the call returns to, filled in as it starts
+	ctx: GuestContext,
+	/// Only code running here is ever interrupted
+	guest: AddressRange,
+	deadline: Option<Instant>,
+	expired: AtomicBool,
+	/// Set from anywhere to have the next syscall abandon the call
+	cancel: *const AtomicBool,
+	/// Set right before switching back to ctx
+	abandoned: Cell<Option<Abandoned>>,
+	#[cfg(unix)]
+	thread: libc::pthread_t,
+}
+
+/// A call on some thread's stack, which stays there for as long as it's in CALLS
+struct CallPtr(*const Call);
+unsafe impl Send for CallPtr {}
+
+lazy_static! {
+	/// Every call with a deadline, for the watchdog thread
+	static ref CALLS: Mutex<Vec<CallPtr>> = Mutex::new(Vec::new());
+	static ref CHANGED: Condvar = Condvar::new();
+}
+static STARTED: Once = Once::new();
+
+/// The innermost watched call on this thread
+#[thread_local]
+static CURRENT: Cell<*const Call> = Cell::new(std::ptr::null());
+
+/// Call guest code at `func` with up to six integer arguments, and abandon it if it's still running after `budget`, or
+/// if `cancel` is set when it makes a syscall.  `guest` is where guest code lives.
+pub fn call(func: usize, args: &[usize; 6], guest: AddressRange, budget: Option<Duration>, cancel: &AtomicBool) -> Result<usize, Abandoned> {
+	let mut call = Call {
+		ctx: GuestContext::default(),
+		guest,
+		deadline: budget.map(|b| Instant::now() + b),
+		expired: AtomicBool::new(false),
+		cancel,
+		abandoned: Cell::new(None),
+		#[cfg(unix)]
+		thread: unsafe { libc::pthread_self() },
+	};
+	let p = &mut call as *mut Call;
+	let outer = CURRENT.replace(p);
+	let watched = call.deadline.is_some();
+	if watched {
+		start();
+		CALLS.lock().unwrap().push(CallPtr(p));
+		CHANGED.notify_all();
+	}
+	let res = unsafe { threading::call_saving_context(&mut (*p).ctx, func, args) };
+	if watched {
+		CALLS.lock().unwrap().retain(|c| !std::ptr::eq(c.0, p));
+	}
+	CURRENT.set(outer);
+	match unsafe { (*p).abandoned.get() } {
+		Some(why) => Err(why),
+		None => Ok(res),
+	}
+}
+
+/// Where the stack pointer was when the innermost call into the guest on this thread was made
+pub fn entry_stack() -> Option<usize> {
+	let call = CURRENT.get();
+	if call.is_null() {
+		None
+	} else {
+		Some(unsafe { (*call).ctx.stack_pointer() })
+	}
+}
+
+/// Called at the start of every syscall.  If the call the guest is in has run out of time or been cancelled, this
+/// returns to whoever made it instead.
+/// unsafe: nothing on the current stack will be cleaned up
+pub unsafe fn check() {
+	let call = CURRENT.get();
+	if call.is_null() {
+		return
+	}
+	let why = if (*call).expired.load(Ordering::SeqCst) {
+		Abandoned::TimedOut
+	} else if (*(*call).cancel).load(Ordering::SeqCst) {
+		Abandoned::Cancelled
+	} else {
+		return
+	};
+	abandon(why)
+}
+
+/// Return to whoever made the call the guest is in, if that's being watched
+/// unsafe: nothing on the current stack will be cleaned up
+pub unsafe fn abandon(why: Abandoned) {
+	let call = CURRENT.get();
+	if call.is_null() {
+		return
+	}
+	(*call).abandoned.set(Some(why));
+	threading::switch_to(&(*call).ctx)
+}
+
+/// From a signal handler for guest code that can't go on:  If this thread is in a call into the guest code the signal
+/// came from, abandon it, by having the handler return to where the call would have.  Returns false if it isn't.
+/// unsafe: ucontext must be the handler's
+pub unsafe fn abandon_from_signal(ucontext: *mut c_void, why: Abandoned) -> bool {
+	let call = CURRENT.get();
+	if call.is_null() || !(*call).guest.contains(*signal_context::pc(ucontext) as usize) {
+		return false
+	}
+	signal_context::resume(ucontext, &(*call).ctx);
+	(*call).abandoned.set(Some(why));
+	true
+}
+
+fn start() {
+	STARTED.call_once(|| {
+		pal::initialize();
+		std::thread::Builder::new()
+			.name("waterbox watchdog".to_string())
+			.spawn(watch)
+			.unwrap();
+	});
+}
+
+fn watch() {
+	let mut calls = CALLS.lock().unwrap();
+	loop {
+		let now = Instant::now();
+		let mut next = None;
+		for c in calls.iter() {
+			let deadline = unsafe { (*c.0).deadline.unwrap() };
+			let wake = if deadline <= now {
+				unsafe {
+					(*c.0).expired.store(true, Ordering::SeqCst);
+					pal::interrupt(c.0);
+				}
+				now + RESIGNAL_INTERVAL
+			} else {
+				deadline
+			};
+			next = Some(next.map_or(wake, |n: Instant| n.min(wake)));
+		}
+		calls = match next {
+			Some(n) => CHANGED.wait_timeout(calls, n - now).unwrap().0,
+			None => CHANGED.wait(calls).unwrap(),
+		};
+	}
+}
+
+#[cfg(unix)]
+mod pal {
+	use libc::*;
+	use super::*;
+
+	type SaHandler = unsafe extern fn(i32) -> ();
+	type SaSigaction = unsafe extern fn(i32, *const siginfo_t, *mut c_void) -> ();
+	static mut SA_OLD: Option<Box<sigaction>> = None;
+
+	/// Left alone by the runtimes that frontends run on
+	const SIGNAL: i32 = SIGVTALRM;
+
+	pub fn initialize() {
+		use std::mem::{transmute, zeroed};
+
+		unsafe extern fn handler(sig: i32, info: *const siginfo_t, ucontext: *mut c_void) {
+			let fs = threading::HostFs::enter();
+			let call = CURRENT.get();
+			if !call.is_null() && (*call).expired.load(Ordering::SeqCst) {
+				if (*call).guest.contains(*signal_context::pc(ucontext) as usize) {
+					signal_context::resume(ucontext, &(*call).ctx);
+					(*call).abandoned.set(Some(Abandoned::TimedOut));
+					// going back to host code, so keep the host's thread pointer in
+					std::mem::forget(fs);
+				}
+				// otherwise host code is running, and the next syscall or signal will catch it
+				return
+			}
+			let sa_old = SA_OLD.as_ref().unwrap();
+			if sa_old.sa_sigaction == SIG_DFL || sa_old.sa_sigaction == SIG_IGN {
+				// a late signal for a call that already finished
+			} else if sa_old.sa_flags & SA_SIGINFO != 0 {
+				transmute::<usize, SaSigaction>(sa_old.sa_sigaction)(sig, info, ucontext);
+			} else {
+				transmute::<usize, SaHandler>(sa_old.sa_sigaction)(sig);
+			}
+		}
+		unsafe {
+			SA_OLD = Some(Box::new(zeroed()));
+			let mut sa: sigaction = zeroed();
+			sa.sa_sigaction = handler as SaSigaction as usize;
+			sa.sa_flags = SA_ONSTACK | SA_SIGINFO | SA_RESTART;
+			sigfillset(&mut sa.sa_mask);
+			assert!(sigaction(SIGNAL, &sa, &mut **SA_OLD.as_mut().unwrap() as *mut sigaction) == 0, "sigaction failed");
+		}
+	}
+
+	/// unsafe: call must be in CALLS, and CALLS must be locked
+	pub unsafe fn interrupt(call: *const Call) {
+		pthread_kill((*call).thread, SIGNAL);
+	}
+}
+
+#[cfg(not(unix))]
+mod pal {
+	use super::*;
+
+	pub fn initialize() {}
+
+	/// Nothing interrupts guest code here, so calls are only abandoned at syscalls
+	pub unsafe fn interrupt(_call: *const Call) {}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use memory_block::{MemoryBlock, Protection};
+
+	static CANCEL: AtomicBool = AtomicBool::new(false);
+
+	#[cfg(target_arch = "x86_64")]
+	extern "sysv64" fn fake_syscall() {
+		unsafe { check() }
+	}
+
+	#[test]
+	#[cfg(target_arch = "x86_64")]
+	fn test_watchdog() -> anyhow::Result<()> {
+		let addr = AddressRange { start: 0x37f00000000, size: 0x1000 };
+		let mut b = MemoryBlock::new(addr);
+		let mut g = b.enter();
+		g.mmap_fixed(addr, Protection::RW, true)?;
+		let code = unsafe { addr.slice_mut() };
+		// mov rax, rdi; ret
+		code[0..4].copy_from_slice(&[0x48, 0x89, 0xf8, 0xc3]);
+		// jmp $
+		code[0x10..0x12].copy_from_slice(&[0xeb, 0xfe]);
+		// sub rsp, 8; mov rax, fake_syscall; call rax; jmp back to the mov
+		code[0x20..0x26].copy_from_slice(&[0x48, 0x83, 0xec, 0x08, 0x48, 0xb8]);
+		code[0x26..0x2e].copy_from_slice(&(fake_syscall as extern "sysv64" fn() as usize).to_le_bytes());
+		code[0x2e..0x32].copy_from_slice(&[0xff, 0xd0, 0xeb, 0xf2]);
+		g.mprotect(addr, Protection::RX)?;
+
+		let budget = Some(Duration::from_millis(50));
+		let cancel = AtomicBool::new(false);
+		assert_eq!(call(addr.start, &[42, 0, 0, 0, 0, 0], addr, budget, &cancel), Ok(42));
+		assert_eq!(call(addr.start, &[7, 0, 0, 0, 0, 0], addr, None, &cancel), Ok(7));
+		// stuck in guest code
+		assert_eq!(call(addr.start + 0x10, &[0; 6], addr, budget, &cancel), Err(Abandoned::TimedOut));
+		// stuck making syscalls
+		assert_eq!(call(addr.start + 0x20, &[0; 6], addr, budget, &cancel), Err(Abandoned::TimedOut));
+		assert_eq!(call(addr.start, &[5, 0, 0, 0, 0, 0], addr, budget, &cancel), Ok(5));
+
+		let canceller = std::thread::spawn(|| {
+			std::thread::sleep(Duration::from_millis(20));
+			CANCEL.store(true, Ordering::SeqCst);
+		});
+		assert_eq!(call(addr.start + 0x20, &[0; 6], addr, None, &CANCEL), Err(Abandoned::Cancelled));
+		canceller.join().unwrap();
+		Ok(())
+	}
+	#[test]
+	#[cfg(all(unix, target_arch = "x86_64"))]
+	fn test_abort() -> anyhow::Result<()> {
+		let addr = AddressRange { start: 0x38c00000000, size: 0x1000 };
+		let mut b = MemoryBlock::new(addr);
+		let mut g = b.enter();
+		g.mmap_fixed(addr, Protection::RW, true)?;
+		let code = unsafe { addr.slice_mut() };
+		// int3
+		code[0] = 0xcc;
+		// ud2
+		code[0x10..0x12].copy_from_slice(&[0x0f, 0x0b]);
+		g.mprotect(addr, Protection::RX)?;
+
+		let cancel = AtomicBool::new(false);
+		assert_eq!(call(addr.start, &[0; 6], addr, None, &cancel), Err(Abandoned::Aborted));
+		assert_eq!(call(addr.start + 0x10, &[0; 6], addr, None, &cancel), Err(Abandoned::Aborted));
+		Ok(())
+	}
+}
@@ -0,0 +1,167 @@
+// A few worker threads for spreading independent pieces of savestate work, like compressing chunks and hashing pages,
+// over more than one core.  Results always come back in the order the work was given in, so nothing that's written
+// out depends on how the work happened to be split up.
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, Condvar, mpsc};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+
+/// The most threads that will ever work on one batch, counting the one that asked
+const MAX_THREADS: usize = 8;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+struct Pool {
+	jobs: Mutex<mpsc::Sender<Job>>,
+	threads: usize,
+}
+
+fn cpu_count() -> usize {
+	#[cfg(unix)]
+	let n = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) } as usize;
+	#[cfg(windows)]
+	let n = std::env::var("NUMBER_OF_PROCESSORS").ok().and_then(|s| s.parse().ok()).unwrap_or(1);
+	n
+}
+
+impl Pool {
+	fn new() -> Pool {
+		let threads = cpu_count().clamp(1, MAX_THREADS);
+		let (tx, rx) = mpsc::channel::<Job>();
+		let rx = Arc::new(Mutex::new(rx));
+		for i in 1..threads {
+			let rx = rx.clone();
+			std::thread::Builder::new()
+				.name(format!("waterbox worker {}", i))
+				.spawn(move || loop {
+					let job = rx.lock().unwrap().recv();
+					match job {
+						Ok(job) => job(),
+						Err(_) => break,
+					}
+				})
+				.unwrap();
+		}
+		Pool { jobs: Mutex::new(tx), threads }
+	}
+}
+
+lazy_static! {
+	static ref POOL: Pool = Pool::new();
+}
+
+/// How many threads work on a batch, which is how many pieces of work it takes to keep them all busy
+pub fn threads() -> usize {
+	POOL.threads
+}
+
+/// The work of a batch in progress, on the stack of the thread that started it
+struct WorkPtr(*const (dyn Fn() + Sync));
+unsafe impl Send for WorkPtr {}
+
+#[derive(Default)]
+struct Helpers {
+	/// Set once the batch is finished, after which helpers that start late don't touch it
+	closed: bool,
+	running: usize,
+}
+#[derive(Default)]
+struct Batch {
+	helpers: Mutex<Helpers>,
+	done: Condvar,
+	panicked: AtomicBool,
+}
+
+/// Calls `f` on every item, on as many threads as are useful, and returns the results in the same order as `items`.
+/// Must not be called from inside `f`.
+pub fn map<T: Sync, R: Send>(items: &[T], f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+	let helpers = std::cmp::min(threads(), items.len()).saturating_sub(1);
+	if helpers == 0 {
+		return items.iter().map(f).collect()
+	}
+	struct Results<R>(*mut Option<R>);
+	unsafe impl<R: Send> Sync for Results<R> {}
+
+	let mut results = (0..items.len()).map(|_| None).collect::<Vec<Option<R>>>();
+	let out = Results(results.as_mut_ptr());
+	let next = AtomicUsize::new(0);
+	let work = || {
+		let out = &out;
+		loop {
+			let i = next.fetch_add(1, Ordering::Relaxed);
+			if i >= items.len() {
+				break
+			}
+			let res = f(&items[i]);
+			// every index is handed out exactly once
+			unsafe { *out.0.add(i) = Some(res); }
+		}
+	};
+
+	let batch = Arc::new(Batch::default());
+	{
+		let work: &(dyn Fn() + Sync) = &work;
+		// helpers are never left holding this once map returns
+		let work: &'static (dyn Fn() + Sync) = unsafe { std::mem::transmute(work) };
+		let jobs = POOL.jobs.lock().unwrap();
+		for _ in 0..helpers {
+			let batch = batch.clone();
+			let work = WorkPtr(work);
+			jobs.send(Box::new(move || {
+				{
+					let mut h = batch.helpers.lock().unwrap();
+					if h.closed {
+						return
+					}
+					h.running += 1;
+				}
+				// the batch isn't closed until every helper that started has stopped, so `work` is still there
+				let work = unsafe { &*work.0 };
+				if panic::catch_unwind(AssertUnwindSafe(work)).is_err() {
+					batch.panicked.store(true, Ordering::Relaxed);
+				}
+				batch.helpers.lock().unwrap().running -= 1;
+				batch.done.notify_all();
+			})).unwrap();
+		}
+	}
+	let mine = panic::catch_unwind(AssertUnwindSafe(&work));
+	{
+		let mut h = batch.helpers.lock().unwrap();
+		h.closed = true;
+		while h.running > 0 {
+			h = batch.done.wait(h).unwrap();
+		}
+	}
+	if let Err(e) = mine {
+		panic::resume_unwind(e)
+	}
+	if batch.panicked.load(Ordering::Relaxed) {
+		panic!("A worker thread panicked")
+	}
+	results.into_iter().map(|r| r.unwrap()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_map() {
+		let items = (0..1000u64).collect::<Vec<_>>();
+		let res = map(&items[..], |&i| {
+			// uneven amounts of work, so that pieces finish out of order
+			let mut x = i;
+			for _ in 0..(i % 7) * 1000 {
+				x = x.wrapping_mul(6364136223846793005).wrapping_add(1);
+			}
+			(i, x)
+		});
+		assert_eq!(res.len(), items.len());
+		assert!(res.iter().enumerate().all(|(n, &(i, _))| n as u64 == i));
+		assert!(map(&[] as &[u8], |_| 0).is_empty());
+		assert!(panic::catch_unwind(|| map(&items[..], |&i| if i == 500 { panic!() } else { i })).is_err());
+		// still works after a panic
+		assert_eq!(map(&items[..3], |&i| i * 2), vec![0, 2, 4]);
+	}
+}
@@ -0,0 +1,171 @@
+// XXH3, the 64 bit variant with the default secret and no seed, for hashing guest memory quickly.  It isn't
+// cryptographic, but it only has to tell apart states that should have been the same, not resist anyone.
+
+const SECRET: [u8; 192] = [
+	0xb8, 0xfe, 0x6c, 0x39, 0x23, 0xa4, 0x4b, 0xbe, 0x7c, 0x01, 0x81, 0x2c, 0xf7, 0x21, 0xad, 0x1c,
+	0xde, 0xd4, 0x6d, 0xe9, 0x83, 0x90, 0x97, 0xdb, 0x72, 0x40, 0xa4, 0xa4, 0xb7, 0xb3, 0x67, 0x1f,
+	0xcb, 0x79, 0xe6, 0x4e, 0xcc, 0xc0, 0xe5, 0x78, 0x82, 0x5a, 0xd0, 0x7d, 0xcc, 0xff, 0x72, 0x21,
+	0xb8, 0x08, 0x46, 0x74, 0xf7, 0x43, 0x24, 0x8e, 0xe0, 0x35, 0x90, 0xe6, 0x81, 0x3a, 0x26, 0x4c,
+	0x3c, 0x28, 0x52, 0xbb, 0x91, 0xc3, 0x00, 0xcb, 0x88, 0xd0, 0x65, 0x8b, 0x1b, 0x53, 0x2e, 0xa3,
+	0x71, 0x64, 0x48, 0x97, 0xa2, 0x0d, 0xf9, 0x4e, 0x38, 0x19, 0xef, 0x46, 0xa9, 0xde, 0xac, 0xd8,
+	0xa8, 0xfa, 0x76, 0x3f, 0xe3, 0x9c, 0x34, 0x3f, 0xf9, 0xdc, 0xbb, 0xc7, 0xc7, 0x0b, 0x4f, 0x1d,
+	0x8a, 0x51, 0xe0, 0x4b, 0xcd, 0xb4, 0x59, 0x31, 0xc8, 0x9f, 0x7e, 0xc9, 0xd9, 0x78, 0x73, 0x64,
+	0xea, 0xc5, 0xac, 0x83, 0x34, 0xd3, 0xeb, 0xc3, 0xc5, 0x81, 0xa0, 0xff, 0xfa, 0x13, 0x63, 0xeb,
+	0x17, 0x0d, 0xdd, 0x51, 0xb7, 0xf0, 0xda, 0x49, 0xd3, 0x16, 0x55, 0x26, 0x29, 0xd4, 0x68, 0x9e,
+	0x2b, 0x16, 0xbe, 0x58, 0x7d, 0x47, 0xa1, 0xfc, 0x8f, 0xf8, 0xb8, 0xd1, 0x7a, 0xd0, 0x31, 0xce,
+	0x45, 0xcb, 0x3a, 0x8f, 0x95, 0x16, 0x04, 0x28, 0xaf, 0xd7, 0xfb, 0xca, 0xbb, 0x4b, 0x40, 0x7e,
+];
+
+const PRIME32_1: u64 = 0x9e3779b1;
+const PRIME32_2: u64 = 0x85ebca77;
+const PRIME32_3: u64 = 0xc2b2ae3d;
+const PRIME64_1: u64 = 0x9e3779b185ebca87;
+const PRIME64_2: u64 = 0xc2b2ae3d27d4eb4f;
+const PRIME64_3: u64 = 0x165667b19e3779f9;
+const PRIME64_4: u64 = 0x85ebca77c2b2ae63;
+const PRIME64_5: u64 = 0x27d4eb2f165667c5;
+const PRIME_MX1: u64 = 0x165667919e3779f9;
+const PRIME_MX2: u64 = 0x9fb21c651e98df25;
+
+const STRIPE_LEN: usize = 64;
+const STRIPES_PER_BLOCK: usize = (SECRET.len() - STRIPE_LEN) / 8;
+const BLOCK_LEN: usize = STRIPE_LEN * STRIPES_PER_BLOCK;
+
+fn read32(src: &[u8], i: usize) -> u64 {
+	u32::from_le_bytes([src[i], src[i + 1], src[i + 2], src[i + 3]]) as u64
+}
+fn read64(src: &[u8], i: usize) -> u64 {
+	let mut b = [0u8; 8];
+	b.copy_from_slice(&src[i..i + 8]);
+	u64::from_le_bytes(b)
+}
+
+fn mul_fold(a: u64, b: u64) -> u64 {
+	let p = a as u128 * b as u128;
+	p as u64 ^ (p >> 64) as u64
+}
+fn avalanche(mut h: u64) -> u64 {
+	h ^= h >> 37;
+	h = h.wrapping_mul(PRIME_MX1);
+	h ^ h >> 32
+}
+fn xxh64_avalanche(mut h: u64) -> u64 {
+	h ^= h >> 33;
+	h = h.wrapping_mul(PRIME64_2);
+	h ^= h >> 29;
+	h = h.wrapping_mul(PRIME64_3);
+	h ^ h >> 32
+}
+fn rrmxmx(mut h: u64, len: u64) -> u64 {
+	h ^= h.rotate_left(49) ^ h.rotate_left(24);
+	h = h.wrapping_mul(PRIME_MX2);
+	h ^= (h >> 35).wrapping_add(len);
+	h = h.wrapping_mul(PRIME_MX2);
+	h ^ h >> 28
+}
+fn mix16(src: &[u8], i: usize, secret: usize) -> u64 {
+	mul_fold(read64(src, i) ^ read64(&SECRET, secret), read64(src, i + 8) ^ read64(&SECRET, secret + 8))
+}
+
+pub fn xxh3_64(src: &[u8]) -> u64 {
+	let len = src.len();
+	let len64 = len as u64;
+	match len {
+		0 => xxh64_avalanche(read64(&SECRET, 56) ^ read64(&SECRET, 64)),
+		1..=3 => {
+			let combined = (src[0] as u64) << 16 | (src[len >> 1] as u64) << 24 | src[len - 1] as u64 | len64 << 8;
+			xxh64_avalanche(combined ^ (read32(&SECRET, 0) ^ read32(&SECRET, 4)))
+		},
+		4..=8 => {
+			let input = read32(src, len - 4).wrapping_add(read32(src, 0) << 32);
+			rrmxmx(input ^ (read64(&SECRET, 8) ^ read64(&SECRET, 16)), len64)
+		},
+		9..=16 => {
+			let lo = read64(src, 0) ^ (read64(&SECRET, 24) ^ read64(&SECRET, 32));
+			let hi = read64(src, len - 8) ^ (read64(&SECRET, 40) ^ read64(&SECRET, 48));
+			avalanche(len64.wrapping_add(lo.swap_bytes()).wrapping_add(hi).wrapping_add(mul_fold(lo, hi)))
+		},
+		17..=128 => {
+			let mut acc = len64.wrapping_mul(PRIME64_1);
+			// pairs of 16 byte chunks from each end, working in
+			let pairs = (len - 1) / 32 + 1;
+			for i in 0..pairs {
+				acc = acc.wrapping_add(mix16(src, 16 * i, 32 * i));
+				acc = acc.wrapping_add(mix16(src, len - 16 * (i + 1), 32 * i + 16));
+			}
+			avalanche(acc)
+		},
+		129..=240 => {
+			let mut acc = len64.wrapping_mul(PRIME64_1);
+			for i in 0..8 {
+				acc = acc.wrapping_add(mix16(src, 16 * i, 16 * i));
+			}
+			acc = avalanche(acc);
+			for i in 8..len / 16 {
+				acc = acc.wrapping_add(mix16(src, 16 * i, 16 * (i - 8) + 3));
+			}
+			acc = acc.wrapping_add(mix16(src, len - 16, 136 - 17));
+			avalanche(acc)
+		},
+		_ => long(src),
+	}
+}
+
+fn accumulate(acc: &mut [u64; 8], src: &[u8], i: usize, secret: usize) {
+	for lane in 0..8 {
+		let val = read64(src, i + 8 * lane);
+		let key = val ^ read64(&SECRET, secret + 8 * lane);
+		acc[lane ^ 1] = acc[lane ^ 1].wrapping_add(val);
+		acc[lane] = acc[lane].wrapping_add((key & 0xffffffff).wrapping_mul(key >> 32));
+	}
+}
+fn scramble(acc: &mut [u64; 8]) {
+	for (lane, a) in acc.iter_mut().enumerate() {
+		let key = read64(&SECRET, SECRET.len() - STRIPE_LEN + 8 * lane);
+		*a = (*a ^ *a >> 47 ^ key).wrapping_mul(PRIME32_1);
+	}
+}
+fn long(src: &[u8]) -> u64 {
+	let len = src.len();
+	let mut acc = [PRIME32_3, PRIME64_1, PRIME64_2, PRIME64_3, PRIME64_4, PRIME32_2, PRIME64_5, PRIME32_1];
+	let blocks = (len - 1) / BLOCK_LEN;
+	for b in 0..blocks {
+		for s in 0..STRIPES_PER_BLOCK {
+			accumulate(&mut acc, src, b * BLOCK_LEN + s * STRIPE_LEN, s * 8);
+		}
+		scramble(&mut acc);
+	}
+	let stripes = (len - 1 - blocks * BLOCK_LEN) / STRIPE_LEN;
+	for s in 0..stripes {
+		accumulate(&mut acc, src, blocks * BLOCK_LEN + s * STRIPE_LEN, s * 8);
+	}
+	// the last stripe always goes in, even if it overlaps what came before
+	accumulate(&mut acc, src, len - STRIPE_LEN, SECRET.len() - STRIPE_LEN - 7);
+	let mut res = (len as u64).wrapping_mul(PRIME64_1);
+	for i in 0..4 {
+		res = res.wrapping_add(mul_fold(acc[2 * i] ^ read64(&SECRET, 11 + 16 * i), acc[2 * i + 1] ^ read64(&SECRET, 19 + 16 * i)));
+	}
+	avalanche(res)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_xxh3() {
+		let data = (0..5000usize).map(|i| ((i * i * 13 + i * 7 + 3) % 256) as u8).collect::<Vec<_>>();
+		// from the reference implementation
+		let expected = [
+			(0, 0x2d06800538d394c2), (1, 0x13e608bc156defed), (2, 0x4d5df8e175754fd1), (3, 0x389847d162d596fb),
+			(4, 0xa3ce7741dd0a3285), (8, 0x87b394c28641c068), (9, 0x070d703115c5ef3c), (16, 0xbfcd509bc3acde21),
+			(17, 0x874e4ceb71fc2c70), (33, 0xb11864574b3d2bae), (65, 0xc2eb4d59b472927e), (100, 0x559e6e59dfa7de03),
+			(128, 0x24260de946326e8d), (129, 0xea185212be10719a), (200, 0xfb0b33f7c6f3fe5f), (240, 0x70583fec19d36f6e),
+			(241, 0x17a6e741d9b38490), (1024, 0xeb9b83c2d6a6e44a), (1025, 0xe2c38a7ca90fa683),
+			(4096, 0x2851ec02a053241c), (5000, 0x2add8cf3a3b7477f),
+		];
+		for &(len, hash) in expected.iter() {
+			assert_eq!(xxh3_64(&data[..len]), hash, "length {}", len);
+		}
+	}
+}